@@ -1,9 +1,13 @@
-use crate::{ClientError, KanidmClientBuilder, APPLICATION_JSON, KOPID, KSESSIONID};
+use crate::{
+    ClientError, KanidmClientBuilder, SpnAccount, APPLICATION_JSON, KOPID, KSESSIONID,
+    READY_POLL_INTERVAL,
+};
 use reqwest::header::CONTENT_TYPE;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet as Set;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
@@ -22,6 +26,8 @@ pub struct KanidmAsyncClient {
     pub(crate) builder: KanidmClientBuilder,
     pub(crate) bearer_token: RwLock<Option<String>>,
     pub(crate) auth_session_id: RwLock<Option<String>>,
+    // The domain name, fetched when first needed and kept until refreshed.
+    pub(crate) domain_name: RwLock<Option<String>>,
 }
 
 impl KanidmAsyncClient {
@@ -651,9 +657,34 @@ impl KanidmAsyncClient {
 
     // Raw DB actions
     pub async fn search(&self, filter: Filter) -> Result<Vec<Entry>, ClientError> {
-        let sr = SearchRequest { filter };
-        let r: Result<SearchResponse, _> = self.perform_post_request("/v1/raw/search", sr).await;
-        r.map(|v| v.entries)
+        self.search_limited(filter, None).await.map(|v| v.entries)
+    }
+
+    /// Search, failing if more than `limit` entries match. The server's
+    /// search_result_limit applies when `limit` is higher or None.
+    pub async fn search_limited(
+        &self,
+        filter: Filter,
+        limit: Option<usize>,
+    ) -> Result<SearchResponse, ClientError> {
+        self.search_projected(filter, limit, None).await
+    }
+
+    /// As search_limited, but only return the attributes in `attrs` of each entry.
+    /// The server's default_search_attrs applies when `attrs` is None, and an
+    /// attribute that isn't in the schema is an error.
+    pub async fn search_projected(
+        &self,
+        filter: Filter,
+        limit: Option<usize>,
+        attrs: Option<Vec<String>>,
+    ) -> Result<SearchResponse, ClientError> {
+        let sr = SearchRequest {
+            filter,
+            limit,
+            attrs,
+        };
+        self.perform_post_request("/v1/raw/search", sr).await
     }
 
     pub async fn create(&self, entries: Vec<Entry>) -> Result<bool, ClientError> {
@@ -1031,6 +1062,11 @@ impl KanidmAsyncClient {
         })
     }
 
+    pub async fn idm_account_get_auth_policy(&self, id: &str) -> Result<AuthPolicy, ClientError> {
+        self.perform_get_request(format!("/v1/account/{}/_auth_policy", id).as_str())
+            .await
+    }
+
     pub async fn idm_account_radius_credential_get(
         &self,
         id: &str,
@@ -1097,6 +1133,30 @@ impl KanidmAsyncClient {
             .await
     }
 
+    pub async fn idm_account_spn_lock(&self, id: &str, spn: &str) -> Result<bool, ClientError> {
+        let req = SingleStringRequest {
+            value: spn.to_string(),
+        };
+        self.perform_put_request(["/v1/account/", id, "/_spn"].concat().as_str(), req)
+            .await
+    }
+
+    pub async fn idm_account_spn_unlock(&self, id: &str) -> Result<bool, ClientError> {
+        self.perform_delete_request(["/v1/account/", id, "/_spn"].concat().as_str())
+            .await
+    }
+
+    pub async fn idm_account_spn_regenerate(
+        &self,
+        id: &str,
+    ) -> Result<SpnRegenerateResult, ClientError> {
+        self.perform_post_request(
+            ["/v1/account/", id, "/_spn/_regenerate"].concat().as_str(),
+            (),
+        )
+        .await
+    }
+
     pub async fn idm_account_unix_cred_verify(
         &self,
         id: &str,
@@ -1158,6 +1218,142 @@ impl KanidmAsyncClient {
             .await
     }
 
+    pub async fn idm_domain_get_name(&self) -> Result<String, ClientError> {
+        self.perform_get_request("/v1/domain/domain_local/_attr/domain_name")
+            .await
+            .and_then(|r: Option<Vec<String>>| {
+                r.and_then(|mut v| v.pop())
+                    .ok_or(ClientError::EmptyResponse)
+            })
+    }
+
+    /// The domain name of the server, which is fetched once and then cached. Use
+    /// `refresh_domain_info` after the domain is renamed.
+    pub async fn get_domain_name(&self) -> Result<String, ClientError> {
+        if let Some(domain_name) = self.domain_name.read().await.as_ref() {
+            return Ok(domain_name.clone());
+        }
+        self.refresh_domain_info().await
+    }
+
+    /// Fetch the domain name again, replacing the cached one, and return it.
+    pub async fn refresh_domain_info(&self) -> Result<String, ClientError> {
+        let domain_name = self.idm_domain_get_name().await?;
+        let mut dguard = self.domain_name.write().await;
+        if let Some(old) = dguard.as_ref() {
+            if old != &domain_name {
+                debug!("domain name changed from {} to {}", old, domain_name);
+            }
+        }
+        *dguard = Some(domain_name.clone());
+        Ok(domain_name)
+    }
+
+    /// Poll the server status until it reports that it is ready, or `timeout`
+    /// elapses. This is for scripts that start kanidm alongside the services that
+    /// depend on it, so a server that can't be reached yet is not an error.
+    pub async fn wait_for_ready(&self, timeout: Duration) -> Result<(), ClientError> {
+        let start = Instant::now();
+        loop {
+            let reason = match self.perform_get_request::<bool>("/status").await {
+                Ok(true) => return Ok(()),
+                Ok(false) => "the server reported it is not ready".to_string(),
+                Err(e) => format!("{:?}", e),
+            };
+            debug!("server not ready -> {}", reason);
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(ClientError::NotReady(elapsed, reason));
+            }
+            tokio::time::sleep(std::cmp::min(READY_POLL_INTERVAL, timeout - elapsed)).await;
+        }
+    }
+
+    /// Find the account with this spn. The realm of the spn is checked against the
+    /// domain of the server first, so that an spn from another domain is an error
+    /// rather than simply not found. The cached domain name is used, see
+    /// `refresh_domain_info`.
+    pub async fn account_from_spn(&self, spn: &str) -> Result<Option<SpnAccount>, ClientError> {
+        let realm = match spn.rfind('@') {
+            Some(idx) if idx > 0 && idx + 1 < spn.len() => &spn[idx + 1..],
+            _ => return Err(ClientError::InvalidSpn(spn.to_string())),
+        };
+
+        let domain_name = self.get_domain_name().await?;
+        if !realm.eq_ignore_ascii_case(domain_name.as_str()) {
+            return Err(ClientError::SpnRealmMismatch(
+                realm.to_string(),
+                domain_name,
+            ));
+        }
+
+        let filter = Filter::And(vec![
+            Filter::Eq("class".to_string(), "account".to_string()),
+            Filter::Eq("spn".to_string(), spn.to_lowercase()),
+        ]);
+        // spn is unique, so there is at most one.
+        match self.search(filter).await?.pop() {
+            Some(e) => SpnAccount::try_from_entry(e).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The spns of the members of a group, sorted, or None if there is no such
+    /// group. When `transitive` is set, the members of nested groups are included
+    /// in place of the groups themselves, so that only the spns of the accounts
+    /// (and other non-group members) are returned. Each group is only expanded
+    /// once, so a cycle of nested groups terminates.
+    pub async fn group_member_spns(
+        &self,
+        id: &str,
+        transitive: bool,
+    ) -> Result<Option<Vec<String>>, ClientError> {
+        let mut group = match self.idm_group_get(id).await? {
+            Some(g) => g,
+            None => return Ok(None),
+        };
+        let mut pending: Vec<String> = group.attrs.remove("member").unwrap_or_default();
+        if !transitive {
+            pending.sort();
+            pending.dedup();
+            return Ok(Some(pending));
+        }
+
+        let mut expanded: Set<String> = group.attrs.remove("spn").into_iter().flatten().collect();
+        let mut spns: Set<String> = Set::new();
+        while !pending.is_empty() {
+            let level: Vec<String> = pending
+                .drain(..)
+                .filter(|m| !expanded.contains(m) && !spns.contains(m))
+                .collect::<Set<_>>()
+                .into_iter()
+                .collect();
+            if level.is_empty() {
+                break;
+            }
+            // Find which of this level are groups, and their members, in one search.
+            let filter = Filter::And(vec![
+                Filter::Eq("class".to_string(), "group".to_string()),
+                Filter::Or(
+                    level
+                        .iter()
+                        .map(|m| Filter::Eq("spn".to_string(), m.clone()))
+                        .collect(),
+                ),
+            ]);
+            let attrs = Some(vec!["spn".to_string(), "member".to_string()]);
+            let groups = self.search_projected(filter, None, attrs).await?;
+            for mut g in groups.entries {
+                if let Some(spn) = g.attrs.remove("spn").and_then(|mut v| v.pop()) {
+                    expanded.insert(spn);
+                }
+                pending.extend(g.attrs.remove("member").unwrap_or_default());
+            }
+            spns.extend(level.into_iter().filter(|m| !expanded.contains(m)));
+        }
+        Ok(Some(spns.into_iter().collect()))
+    }
+
     // pub fn idm_domain_get_attr
     pub async fn idm_domain_get_ssid(&self, id: &str) -> Result<String, ClientError> {
         self.perform_get_request(format!("/v1/domain/{}/_attr/domain_ssid", id).as_str())
@@ -1179,6 +1375,18 @@ impl KanidmAsyncClient {
         .await
     }
 
+    pub async fn idm_domain_spn_config_export(&self) -> Result<SpnConfig, ClientError> {
+        self.perform_get_request("/v1/domain/_spn_config").await
+    }
+
+    pub async fn idm_domain_spn_config_import(
+        &self,
+        config: &SpnConfig,
+    ) -> Result<(), ClientError> {
+        self.perform_post_request("/v1/domain/_spn_config", config)
+            .await
+    }
+
     // ==== schema
     pub async fn idm_schema_list(&self) -> Result<Vec<Entry>, ClientError> {
         self.perform_get_request("/v1/schema").await
@@ -1219,4 +1427,65 @@ impl KanidmAsyncClient {
         self.perform_post_request(format!("/v1/recycle_bin/{}/_revive", id).as_str(), ())
             .await
     }
+
+    // ==== system
+    pub async fn auth_capabilities(&self) -> Result<AuthCapabilities, ClientError> {
+        self.perform_get_request("/v1/auth/capabilities").await
+    }
+
+    pub async fn system_spn_fsck(&self) -> Result<Vec<SpnFsckEntry>, ClientError> {
+        self.perform_get_request("/v1/system/_spn_fsck").await
+    }
+
+    pub async fn system_spn_fsck_repair(&self) -> Result<Vec<SpnFsckEntry>, ClientError> {
+        self.perform_post_request("/v1/system/_spn_fsck", ()).await
+    }
+
+    pub async fn system_spn_unlock(&self, filter: Filter) -> Result<usize, ClientError> {
+        self.perform_post_request("/v1/system/_spn_unlock", filter)
+            .await
+    }
+
+    pub async fn system_config(&self) -> Result<SystemConfig, ClientError> {
+        self.perform_get_request("/v1/system/config").await
+    }
+
+    pub async fn system_auth_events(
+        &self,
+        query: AuthEventQuery,
+    ) -> Result<Vec<AuthEventRecord>, ClientError> {
+        self.perform_post_request("/v1/system/_auth_events", query)
+            .await
+    }
+
+    pub async fn system_auth_trace_start(
+        &self,
+        account: &str,
+        duration: u64,
+    ) -> Result<(), ClientError> {
+        let req = AuthTraceRequest {
+            account: account.to_string(),
+            duration,
+        };
+        self.perform_post_request("/v1/system/_auth_trace", req)
+            .await
+    }
+
+    pub async fn system_auth_trace(&self, account: &str) -> Result<AuthTrace, ClientError> {
+        self.perform_get_request(format!("/v1/system/_auth_trace/{}", account).as_str())
+            .await
+    }
+
+    pub async fn system_spn_bench(&self, count: usize) -> Result<SpnBenchResult, ClientError> {
+        self.perform_post_request("/v1/system/_spn_bench", count)
+            .await
+    }
+
+    pub async fn system_stats_entry_sizes(
+        &self,
+        top: usize,
+    ) -> Result<Vec<EntrySize>, ClientError> {
+        self.perform_post_request("/v1/system/_stats/entry_sizes", top)
+            .await
+    }
 }
@@ -41,6 +41,8 @@ use crate::asynchronous::KanidmAsyncClient;
 pub const APPLICATION_JSON: &str = "application/json";
 pub const KOPID: &str = "X-KANIDM-OPID";
 pub const KSESSIONID: &str = "X-KANIDM-AUTH-SESSION-ID";
+// How often wait_for_ready polls the server.
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Debug)]
 pub enum ClientError {
@@ -53,6 +55,39 @@ pub enum ClientError {
     JsonDecode(reqwest::Error, String),
     JsonEncode(SerdeJsonError),
     SystemError,
+    InvalidSpn(String),
+    // The realm of the spn, and the domain name of the server.
+    SpnRealmMismatch(String, String),
+    // How long was waited, and why the server was last not ready.
+    NotReady(Duration, String),
+}
+
+/// An account resolved from its spn by `account_from_spn`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpnAccount {
+    pub uuid: String,
+    pub name: String,
+    pub spn: String,
+    pub displayname: Option<String>,
+    pub memberof: Vec<String>,
+}
+
+impl SpnAccount {
+    fn try_from_entry(mut e: Entry) -> Result<Self, ClientError> {
+        let mut single = |attr: &str| e.attrs.remove(attr).and_then(|mut v| v.pop());
+        let uuid = single("uuid").ok_or(ClientError::EmptyResponse)?;
+        let name = single("name").ok_or(ClientError::EmptyResponse)?;
+        let spn = single("spn").ok_or(ClientError::EmptyResponse)?;
+        let displayname = single("displayname");
+        let memberof = e.attrs.remove("memberof").unwrap_or_default();
+        Ok(SpnAccount {
+            uuid,
+            name,
+            spn,
+            displayname,
+            memberof,
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -339,6 +374,7 @@ impl KanidmClientBuilder {
             bearer_token: RwLock::new(None),
             origin,
             auth_session_id: RwLock::new(None),
+            domain_name: RwLock::new(None),
         })
     }
 }
@@ -451,6 +487,23 @@ impl KanidmClient {
         tokio_block_on(self.asclient.search(filter))
     }
 
+    pub fn search_limited(
+        &self,
+        filter: Filter,
+        limit: Option<usize>,
+    ) -> Result<SearchResponse, ClientError> {
+        tokio_block_on(self.asclient.search_limited(filter, limit))
+    }
+
+    pub fn search_projected(
+        &self,
+        filter: Filter,
+        limit: Option<usize>,
+        attrs: Option<Vec<String>>,
+    ) -> Result<SearchResponse, ClientError> {
+        tokio_block_on(self.asclient.search_projected(filter, limit, attrs))
+    }
+
     // create
     pub fn create(&self, entries: Vec<Entry>) -> Result<bool, ClientError> {
         tokio_block_on(self.asclient.create(entries))
@@ -674,6 +727,10 @@ impl KanidmClient {
         tokio_block_on(self.asclient.idm_account_get_credential_status(id))
     }
 
+    pub fn idm_account_get_auth_policy(&self, id: &str) -> Result<AuthPolicy, ClientError> {
+        tokio_block_on(self.asclient.idm_account_get_auth_policy(id))
+    }
+
     pub fn idm_account_radius_credential_get(
         &self,
         id: &str,
@@ -717,6 +774,18 @@ impl KanidmClient {
         tokio_block_on(self.asclient.idm_account_unix_cred_delete(id))
     }
 
+    pub fn idm_account_spn_lock(&self, id: &str, spn: &str) -> Result<bool, ClientError> {
+        tokio_block_on(self.asclient.idm_account_spn_lock(id, spn))
+    }
+
+    pub fn idm_account_spn_unlock(&self, id: &str) -> Result<bool, ClientError> {
+        tokio_block_on(self.asclient.idm_account_spn_unlock(id))
+    }
+
+    pub fn idm_account_spn_regenerate(&self, id: &str) -> Result<SpnRegenerateResult, ClientError> {
+        tokio_block_on(self.asclient.idm_account_spn_regenerate(id))
+    }
+
     pub fn idm_account_unix_cred_verify(
         &self,
         id: &str,
@@ -770,11 +839,47 @@ impl KanidmClient {
     }
 
     // pub fn idm_domain_get_attr
+    pub fn idm_domain_get_name(&self) -> Result<String, ClientError> {
+        tokio_block_on(self.asclient.idm_domain_get_name())
+    }
+
+    pub fn get_domain_name(&self) -> Result<String, ClientError> {
+        tokio_block_on(self.asclient.get_domain_name())
+    }
+
+    pub fn refresh_domain_info(&self) -> Result<String, ClientError> {
+        tokio_block_on(self.asclient.refresh_domain_info())
+    }
+
+    pub fn account_from_spn(&self, spn: &str) -> Result<Option<SpnAccount>, ClientError> {
+        tokio_block_on(self.asclient.account_from_spn(spn))
+    }
+
+    pub fn group_member_spns(
+        &self,
+        id: &str,
+        transitive: bool,
+    ) -> Result<Option<Vec<String>>, ClientError> {
+        tokio_block_on(self.asclient.group_member_spns(id, transitive))
+    }
+
+    pub fn wait_for_ready(&self, timeout: Duration) -> Result<(), ClientError> {
+        tokio_block_on(self.asclient.wait_for_ready(timeout))
+    }
+
     pub fn idm_domain_get_ssid(&self, id: &str) -> Result<String, ClientError> {
         tokio_block_on(self.asclient.idm_domain_get_ssid(id))
     }
 
     // pub fn idm_domain_put_attr
+
+    pub fn idm_domain_spn_config_export(&self) -> Result<SpnConfig, ClientError> {
+        tokio_block_on(self.asclient.idm_domain_spn_config_export())
+    }
+
+    pub fn idm_domain_spn_config_import(&self, config: &SpnConfig) -> Result<(), ClientError> {
+        tokio_block_on(self.asclient.idm_domain_spn_config_import(config))
+    }
     pub fn idm_domain_set_ssid(&self, id: &str, ssid: &str) -> Result<bool, ClientError> {
         tokio_block_on(self.asclient.idm_domain_set_ssid(id, ssid))
     }
@@ -812,4 +917,48 @@ impl KanidmClient {
     pub fn recycle_bin_revive(&self, id: &str) -> Result<bool, ClientError> {
         tokio_block_on(self.asclient.recycle_bin_revive(id))
     }
+
+    // ==== system
+    pub fn auth_capabilities(&self) -> Result<AuthCapabilities, ClientError> {
+        tokio_block_on(self.asclient.auth_capabilities())
+    }
+
+    pub fn system_spn_fsck(&self) -> Result<Vec<SpnFsckEntry>, ClientError> {
+        tokio_block_on(self.asclient.system_spn_fsck())
+    }
+
+    pub fn system_spn_fsck_repair(&self) -> Result<Vec<SpnFsckEntry>, ClientError> {
+        tokio_block_on(self.asclient.system_spn_fsck_repair())
+    }
+
+    pub fn system_spn_unlock(&self, filter: Filter) -> Result<usize, ClientError> {
+        tokio_block_on(self.asclient.system_spn_unlock(filter))
+    }
+
+    pub fn system_config(&self) -> Result<SystemConfig, ClientError> {
+        tokio_block_on(self.asclient.system_config())
+    }
+
+    pub fn system_auth_events(
+        &self,
+        query: AuthEventQuery,
+    ) -> Result<Vec<AuthEventRecord>, ClientError> {
+        tokio_block_on(self.asclient.system_auth_events(query))
+    }
+
+    pub fn system_auth_trace_start(&self, account: &str, duration: u64) -> Result<(), ClientError> {
+        tokio_block_on(self.asclient.system_auth_trace_start(account, duration))
+    }
+
+    pub fn system_auth_trace(&self, account: &str) -> Result<AuthTrace, ClientError> {
+        tokio_block_on(self.asclient.system_auth_trace(account))
+    }
+
+    pub fn system_spn_bench(&self, count: usize) -> Result<SpnBenchResult, ClientError> {
+        tokio_block_on(self.asclient.system_spn_bench(count))
+    }
+
+    pub fn system_stats_entry_sizes(&self, top: usize) -> Result<Vec<EntrySize>, ClientError> {
+        tokio_block_on(self.asclient.system_stats_entry_sizes(top))
+    }
 }
@@ -24,6 +24,15 @@ fn is_free_port(port: u16) -> bool {
 // Test external behaviours of the service.
 
 pub fn run_test(test_fn: fn(KanidmClient) -> ()) {
+    run_test_with_config(|_config: &mut Configuration| {}, test_fn)
+}
+
+// As run_test, but allows the server configuration to be altered before the
+// server is started.
+pub fn run_test_with_config(
+    config_fn: fn(&mut Configuration) -> (),
+    test_fn: fn(KanidmClient) -> (),
+) {
     // ::std::env::set_var("RUST_LOG", "tide=debug,kanidm=debug");
     let _ = env_logger::builder()
         .format_timestamp(None)
@@ -61,6 +70,7 @@ pub fn run_test(test_fn: fn(KanidmClient) -> ()) {
     // config.log_level = Some(LogLevel::Verbose as u32);
     // config.log_level = Some(LogLevel::FullTrace as u32);
     config.threads = 1;
+    config_fn(&mut config);
 
     let t_handle = thread::spawn(move || {
         // Spawn a thread for the test runner, this should have a unique
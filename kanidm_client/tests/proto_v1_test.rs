@@ -1,14 +1,23 @@
 #![deny(warnings)]
-use std::time::SystemTime;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 use log::debug;
 
+use kanidm::config::{AnonymousReadScope, Configuration, ReauthOperation, ServerRole};
+use kanidm::core::admin::{AdminRequest, AdminResponse};
 use kanidm::credential::totp::Totp;
-use kanidm_client::KanidmClient;
-use kanidm_proto::v1::{CredentialDetailType, Entry, Filter, Modify, ModifyList};
+use kanidm_client::{ClientError, KanidmClient, KanidmClientBuilder, StatusCode};
+use kanidm_proto::v1::{
+    AuthEventQuery, AuthEventResult, AuthMech, CredentialDetailType, Entry, Filter, Modify,
+    ModifyList, OperationError,
+};
 
 mod common;
-use crate::common::{run_test, ADMIN_TEST_PASSWORD};
+use crate::common::{run_test, run_test_with_config, ADMIN_TEST_PASSWORD};
 
 use webauthn_authenticator_rs::{softtok::U2FSoft, WebauthnAuthenticator};
 
@@ -981,3 +990,802 @@ fn test_server_rest_webauthn_mfa_auth_lifecycle() {
 // Test the self version of the radius path.
 
 // Test hitting all auth-required endpoints and assert they give unauthorized.
+
+#[test]
+fn test_server_role_no_ui_message() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.role = ServerRole::WriteReplicaNoUI;
+            config.no_ui_message = Some("No ui here, use idm.example.com".to_string());
+        },
+        |rsclient: KanidmClient| {
+            let res = reqwest::blocking::get(format!("{}/", rsclient.get_origin()))
+                .expect("Failed to make request");
+            assert!(res.status() == StatusCode::NOT_FOUND);
+            assert!(res.text().unwrap() == "No ui here, use idm.example.com");
+
+            // The api is still served.
+            let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+            assert!(a_res.is_ok());
+        },
+    );
+}
+
+#[test]
+fn test_server_role_read_only_message() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.role = ServerRole::ReadOnlyReplica;
+            // Only a standby refuses writes. Nothing listens on the primary, and it
+            // is never promoted automatically.
+            config.standby_primary = Some("127.0.0.1:1".to_string());
+            config.read_only_message = Some("Read only, use idm.example.com".to_string());
+        },
+        |rsclient: KanidmClient| {
+            // Auth and reads are allowed.
+            let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+            assert!(a_res.is_ok());
+            assert!(rsclient.idm_account_list().is_ok());
+
+            let res = reqwest::blocking::Client::new()
+                .post(format!("{}/v1/raw/create", rsclient.get_origin()))
+                .body("{}")
+                .send()
+                .expect("Failed to make request");
+            assert!(res.status() == StatusCode::METHOD_NOT_ALLOWED);
+            assert!(res.text().unwrap() == "Read only, use idm.example.com");
+
+            // The web ui is still available on read only replicas.
+            let res = reqwest::blocking::get(format!("{}/", rsclient.get_origin()))
+                .expect("Failed to make request");
+            assert!(res.status() == StatusCode::OK);
+        },
+    );
+}
+
+#[test]
+fn test_server_http_request_read_timeout() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.http_request_read_timeout = Some(1);
+        },
+        |rsclient: KanidmClient| {
+            let addr = rsclient.get_origin().trim_start_matches("http://");
+            let mut stream = TcpStream::connect(addr).expect("Failed to connect");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .expect("Failed to set read timeout");
+            // Promise a body, but only send part of it.
+            stream
+                .write_all(
+                    b"POST /v1/auth HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: 64\r\n\r\n{",
+                )
+                .expect("Failed to write");
+            thread::sleep(Duration::from_secs(3));
+
+            let mut buf = [0; 1024];
+            let len = stream.read(&mut buf).expect("Failed to read");
+            let res = String::from_utf8_lossy(&buf[..len]);
+            assert!(res.starts_with("HTTP/1.1 408"));
+
+            // Headers that arrive too slowly have the connection closed.
+            let mut stream = TcpStream::connect(addr).expect("Failed to connect");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .expect("Failed to set read timeout");
+            stream
+                .write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\n")
+                .expect("Failed to write");
+            thread::sleep(Duration::from_secs(3));
+            let len = stream.read(&mut buf).unwrap_or(0);
+            assert!(len == 0);
+
+            // Requests that are not slow are unaffected.
+            let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+            assert!(a_res.is_ok());
+        },
+    );
+}
+
+#[test]
+fn test_server_http_max_body_size() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.http_max_body_size = 16;
+        },
+        |rsclient: KanidmClient| {
+            let addr = rsclient.get_origin().trim_start_matches("http://");
+            let mut buf = [0; 1024];
+
+            // Refused from the content-length alone.
+            let mut stream = TcpStream::connect(addr).expect("Failed to connect");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .expect("Failed to set read timeout");
+            stream
+                .write_all(
+                    b"POST /v1/auth HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: 64\r\n\r\n{",
+                )
+                .expect("Failed to write");
+            let len = stream.read(&mut buf).expect("Failed to read");
+            let res = String::from_utf8_lossy(&buf[..len]);
+            assert!(res.starts_with("HTTP/1.1 413"));
+
+            // Refused while reading a chunked body with no content-length.
+            let mut stream = TcpStream::connect(addr).expect("Failed to connect");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .expect("Failed to set read timeout");
+            stream
+                .write_all(
+                    b"POST /v1/auth HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n20\r\n{\"aaaaaaaaaaaaaaaaaaaaaaaaaaaaa}\r\n0\r\n\r\n",
+                )
+                .expect("Failed to write");
+            let len = stream.read(&mut buf).expect("Failed to read");
+            let res = String::from_utf8_lossy(&buf[..len]);
+            assert!(res.starts_with("HTTP/1.1 413"));
+        },
+    );
+}
+
+#[test]
+fn test_server_max_connections_per_ip() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.max_connections_per_ip = Some(2);
+        },
+        |rsclient: KanidmClient| {
+            let addr = rsclient.get_origin().trim_start_matches("http://");
+            let mut buf = [0; 1024];
+
+            // Idle connections count towards the limit, not just requests.
+            let held: Vec<TcpStream> = (0..2)
+                .map(|_| TcpStream::connect(addr).expect("Failed to connect"))
+                .collect();
+
+            let mut stream = TcpStream::connect(addr).expect("Failed to connect");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .expect("Failed to set read timeout");
+            let len = stream.read(&mut buf).expect("Failed to read");
+            let res = String::from_utf8_lossy(&buf[..len]);
+            assert!(res.starts_with("HTTP/1.1 429"));
+
+            // Once they close, new connections are accepted again.
+            std::mem::drop(held);
+            thread::sleep(Duration::from_secs(1));
+            let mut stream = TcpStream::connect(addr).expect("Failed to connect");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .expect("Failed to set read timeout");
+            stream
+                .write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .expect("Failed to write");
+            let len = stream.read(&mut buf).expect("Failed to read");
+            let res = String::from_utf8_lossy(&buf[..len]);
+            assert!(res.starts_with("HTTP/1.1 200"));
+        },
+    );
+}
+
+#[test]
+fn test_server_security_headers() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            // Enabled explicitly, as the test server doesn't use TLS.
+            config.security_headers = Some(true);
+            config.hsts_max_age = Some(600);
+        },
+        |rsclient: KanidmClient| {
+            for path in &["/", "/status", "/v1/self"] {
+                let res = reqwest::blocking::get(format!("{}{}", rsclient.get_origin(), path))
+                    .expect("Failed to make request");
+                let headers = res.headers();
+                assert!(headers["Strict-Transport-Security"] == "max-age=600");
+                assert!(headers["X-Content-Type-Options"] == "nosniff");
+                assert!(headers.contains_key("Content-Security-Policy"));
+            }
+        },
+    );
+}
+
+#[test]
+fn test_server_search_attrs() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.update_default_search_attrs(&["name".to_string(), "uuid".to_string()]);
+        },
+        |rsclient: KanidmClient| {
+            let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+            assert!(res.is_ok());
+            let filter = Filter::Eq("name".to_string(), "admin".to_string());
+
+            // Only the requested attributes are returned.
+            let r = rsclient
+                .search_projected(
+                    filter.clone(),
+                    None,
+                    Some(vec!["Name".to_string(), "spn".to_string()]),
+                )
+                .expect("Failed to search");
+            assert!(r.entries.len() == 1);
+            let keys: Vec<_> = r.entries[0].attrs.keys().map(|k| k.as_str()).collect();
+            assert!(keys == vec!["name", "spn"]);
+
+            // Otherwise the configured default applies.
+            let rset = rsclient.search(filter.clone()).expect("Failed to search");
+            let keys: Vec<_> = rset[0].attrs.keys().map(|k| k.as_str()).collect();
+            assert!(keys == vec!["name", "uuid"]);
+
+            // An attribute that isn't in the schema is rejected.
+            let r = rsclient.search_projected(filter, None, Some(vec!["nonexistent".to_string()]));
+            assert!(matches!(
+                r,
+                Err(ClientError::Http(
+                    _,
+                    Some(OperationError::InvalidAttributeName(_)),
+                    _
+                ))
+            ));
+        },
+    );
+}
+
+#[test]
+fn test_server_anonymous_auth_limit_and_read_scope() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.update_anonymous_auth_rate_limit(Some(2));
+            config.update_anonymous_read_scope(AnonymousReadScope::DomainInfo);
+        },
+        |rsclient: KanidmClient| {
+            assert!(rsclient.auth_anonymous().is_ok());
+            // Anonymous can only see itself and the domain info.
+            let r = rsclient
+                .search(Filter::Pres("class".to_string()))
+                .expect("Failed to search");
+            assert!(r.len() == 2);
+
+            let anon = rsclient.new_session().expect("Failed to create session");
+            assert!(anon.auth_anonymous().is_ok());
+            // The third anonymous auth from this address is over the limit.
+            let anon = rsclient.new_session().expect("Failed to create session");
+            assert!(anon.auth_anonymous().is_err());
+
+            // Other accounts are not limited or scoped.
+            let admin = rsclient.new_session().expect("Failed to create session");
+            assert!(admin
+                .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+                .is_ok());
+            let r = admin
+                .search(Filter::Pres("class".to_string()))
+                .expect("Failed to search");
+            assert!(r.len() > 2);
+        },
+    );
+}
+
+#[test]
+fn test_server_auth_capabilities() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.update_disabled_auth_mechs(&[AuthMech::Anonymous, AuthMech::Webauthn]);
+        },
+        |rsclient: KanidmClient| {
+            // No authentication is needed to check capabilities.
+            let caps = rsclient
+                .auth_capabilities()
+                .expect("Failed to get capabilities");
+            assert!(caps.mechs == vec![AuthMech::Password, AuthMech::PasswordMfa]);
+
+            // And the disabled mechanisms can't be used.
+            let anon = rsclient.new_session().expect("Failed to create session");
+            assert!(anon.auth_anonymous().is_err());
+            assert!(rsclient
+                .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+                .is_ok());
+        },
+    );
+}
+
+#[test]
+fn test_server_rest_account_auth_policy() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.update_disabled_auth_mechs(&[AuthMech::Webauthn]);
+        },
+        |rsclient: KanidmClient| {
+            let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+            assert!(res.is_ok());
+            rsclient
+                .idm_group_add_members("idm_admins", &["admin"])
+                .unwrap();
+            rsclient
+                .idm_account_create("demo_account", "Deeeeemo")
+                .unwrap();
+
+            // Anonymous needs no credentials.
+            let policy = rsclient
+                .idm_account_get_auth_policy("anonymous")
+                .expect("Failed to get auth policy");
+            assert!(policy.usable == vec![AuthMech::Anonymous]);
+            assert!(policy.credentials.is_none());
+
+            // An account without credentials can't authenticate at all.
+            let policy = rsclient
+                .idm_account_get_auth_policy("demo_account")
+                .expect("Failed to get auth policy");
+            assert!(policy.enrolled.is_empty());
+            assert!(policy.usable.is_empty());
+            assert!(policy.credentials.is_none());
+
+            // A webauthn token alone is enrolled, but disabled on this server.
+            let mut wa_softtok = WebauthnAuthenticator::new(U2FSoft::new());
+            let (sessionid, regchal) = rsclient
+                .idm_account_primary_credential_register_webauthn("demo_account", "softtok")
+                .unwrap();
+            let rego = wa_softtok
+                .do_registration("https://idm.example.com", regchal)
+                .expect("Failed to register to softtoken");
+            rsclient
+                .idm_account_primary_credential_complete_webuthn_registration(
+                    "demo_account",
+                    rego,
+                    sessionid,
+                )
+                .unwrap();
+            let policy = rsclient
+                .idm_account_get_auth_policy("demo_account")
+                .expect("Failed to get auth policy");
+            assert!(policy.enrolled == vec![AuthMech::Webauthn]);
+            assert!(policy.disabled == vec![AuthMech::Webauthn]);
+            assert!(policy.usable.is_empty());
+
+            // With a password as well, the token is a second factor and can be used.
+            assert!(rsclient
+                .idm_account_primary_credential_set_password("demo_account", "sohdi3iuHo6mai7noh0a")
+                .is_ok());
+            let policy = rsclient
+                .idm_account_get_auth_policy("demo_account")
+                .expect("Failed to get auth policy");
+            assert!(policy.enrolled == vec![AuthMech::PasswordMfa]);
+            assert!(policy.disabled.is_empty());
+            assert!(policy.usable == vec![AuthMech::PasswordMfa]);
+            assert!(policy.credentials.is_some());
+            assert!(policy.to_string().contains("as a second factor"));
+        },
+    );
+}
+
+#[test]
+fn test_server_rest_spn_fsck() {
+    run_test(|rsclient: KanidmClient| {
+        // Only system administrators may check or repair spns.
+        let anon = rsclient.new_session().expect("Failed to create session");
+        assert!(anon.auth_anonymous().is_ok());
+        assert!(anon.system_spn_fsck().is_err());
+        assert!(anon.system_spn_fsck_repair().is_err());
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+        let found = rsclient.system_spn_fsck().expect("Failed to check spns");
+        assert!(found.is_empty());
+        let repaired = rsclient
+            .system_spn_fsck_repair()
+            .expect("Failed to repair spns");
+        assert!(repaired.is_empty());
+    });
+}
+
+#[test]
+fn test_server_rest_system_config() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.update_max_entries(Some(10000));
+        },
+        |rsclient: KanidmClient| {
+            // Only system administrators may read the configuration.
+            let anon = rsclient.new_session().expect("Failed to create session");
+            assert!(anon.auth_anonymous().is_ok());
+            assert!(anon.system_config().is_err());
+
+            let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+            assert!(res.is_ok());
+            let sc = rsclient.system_config().expect("Failed to get config");
+            assert!(sc.values.get("max_entries").map(String::as_str) == Some("10000"));
+            assert!(sc.values.contains_key("bindaddress"));
+            assert!(sc.secrets.get("tls_key") == Some(&false));
+        },
+    );
+}
+
+#[test]
+fn test_server_rest_auth_events() {
+    run_test(|rsclient: KanidmClient| {
+        let other = rsclient.new_session().expect("Failed to create session");
+        assert!(other
+            .auth_simple_password("admin", "not the admin password")
+            .is_err());
+
+        // Only system administrators may read the auth events.
+        assert!(other.auth_anonymous().is_ok());
+        let query = AuthEventQuery {
+            since: 0,
+            account: Some("admin".to_string()),
+            result: Some(AuthEventResult::Failure),
+        };
+        assert!(other.system_auth_events(query.clone()).is_err());
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+        let events = rsclient
+            .system_auth_events(query)
+            .expect("Failed to get auth events");
+        assert!(events.len() == 1);
+        assert!(events[0].account == "admin");
+        assert!(events[0].reason.is_some());
+
+        // Successful authentications are recorded too.
+        let events = rsclient
+            .system_auth_events(AuthEventQuery {
+                since: 0,
+                account: None,
+                result: None,
+            })
+            .expect("Failed to get auth events");
+        assert!(
+            events
+                .iter()
+                .filter(|e| e.account == "admin" && e.result == AuthEventResult::Success)
+                .count()
+                == 1
+        );
+    });
+}
+
+#[test]
+fn test_server_rest_spn_bench() {
+    run_test(|rsclient: KanidmClient| {
+        let anon = rsclient.new_session().expect("Failed to create session");
+        assert!(anon.auth_anonymous().is_ok());
+        assert!(anon.system_spn_bench(100).is_err());
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+        assert!(rsclient.system_spn_bench(0).is_err());
+        let r = rsclient
+            .system_spn_bench(100)
+            .expect("Failed to bench spns");
+        assert!(r.count == 100);
+        assert!(r.generate_per_sec > 0.0);
+        assert!(r.directory_size > 0);
+    });
+}
+
+#[test]
+fn test_server_rest_stats_entry_sizes() {
+    run_test(|rsclient: KanidmClient| {
+        let anon = rsclient.new_session().expect("Failed to create session");
+        assert!(anon.auth_anonymous().is_ok());
+        assert!(anon.system_stats_entry_sizes(5).is_err());
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+        assert!(rsclient.system_stats_entry_sizes(0).is_err());
+        let sizes = rsclient
+            .system_stats_entry_sizes(5)
+            .expect("Failed to get entry sizes");
+        assert!(sizes.len() == 5);
+        assert!(sizes.windows(2).all(|w| w[0].values >= w[1].values));
+    });
+}
+
+#[test]
+fn test_server_rest_account_from_spn() {
+    run_test(|rsclient: KanidmClient| {
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+        let domain_name = rsclient
+            .idm_domain_get_name()
+            .expect("Failed to get domain name");
+
+        let account = rsclient
+            .account_from_spn(format!("admin@{}", domain_name).as_str())
+            .expect("Failed to resolve spn")
+            .expect("No account found");
+        assert!(account.name == "admin");
+        assert!(account.spn == format!("admin@{}", domain_name));
+
+        // No such account in this domain.
+        let account = rsclient
+            .account_from_spn(format!("nobody@{}", domain_name).as_str())
+            .expect("Failed to resolve spn");
+        assert!(account.is_none());
+
+        // An spn from another realm is refused before searching.
+        assert!(matches!(
+            rsclient.account_from_spn("admin@other.example.com"),
+            Err(ClientError::SpnRealmMismatch(_, _))
+        ));
+        assert!(matches!(
+            rsclient.account_from_spn("admin"),
+            Err(ClientError::InvalidSpn(_))
+        ));
+    });
+}
+
+#[test]
+fn test_server_rest_group_member_spns() {
+    run_test(|rsclient: KanidmClient| {
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+        let domain_name = rsclient
+            .idm_domain_get_name()
+            .expect("Failed to get domain name");
+        let spn = |name: &str| format!("{}@{}", name, domain_name);
+
+        // svc_outer -> (svc_a, svc_inner -> (svc_b, svc_outer))
+        rsclient
+            .idm_account_create("svc_a", "Service A")
+            .expect("Failed to create account");
+        rsclient
+            .idm_account_create("svc_b", "Service B")
+            .expect("Failed to create account");
+        rsclient.idm_group_create("svc_outer").unwrap();
+        rsclient.idm_group_create("svc_inner").unwrap();
+        rsclient
+            .idm_group_add_members("svc_outer", &["svc_a", "svc_inner"])
+            .unwrap();
+        rsclient
+            .idm_group_add_members("svc_inner", &["svc_b", "svc_outer"])
+            .unwrap();
+
+        // Only the direct members, including the nested group.
+        let direct = rsclient
+            .group_member_spns("svc_outer", false)
+            .expect("Failed to get member spns");
+        assert!(direct == Some(vec![spn("svc_a"), spn("svc_inner")]));
+
+        // The accounts of the nested groups, despite the cycle.
+        let all = rsclient
+            .group_member_spns("svc_outer", true)
+            .expect("Failed to get member spns");
+        assert!(all == Some(vec![spn("svc_a"), spn("svc_b")]));
+        let all = rsclient
+            .group_member_spns("svc_inner", true)
+            .expect("Failed to get member spns");
+        assert!(all == Some(vec![spn("svc_a"), spn("svc_b")]));
+
+        assert!(rsclient
+            .group_member_spns("no_such_group", true)
+            .expect("Failed to get member spns")
+            .is_none());
+    });
+}
+
+#[test]
+fn test_server_rest_refresh_domain_info() {
+    run_test(|rsclient: KanidmClient| {
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+        let domain_name = rsclient
+            .idm_domain_get_name()
+            .expect("Failed to get domain name");
+
+        // The cached name matches the server, and is used for realm checks.
+        assert!(
+            rsclient
+                .get_domain_name()
+                .expect("Failed to get domain name")
+                == domain_name
+        );
+        let refreshed = rsclient
+            .refresh_domain_info()
+            .expect("Failed to refresh domain info");
+        assert!(refreshed == domain_name);
+        assert!(
+            rsclient
+                .get_domain_name()
+                .expect("Failed to get domain name")
+                == refreshed
+        );
+
+        let account = rsclient
+            .account_from_spn(format!("admin@{}", refreshed).as_str())
+            .expect("Failed to resolve spn")
+            .expect("No account found");
+        assert!(account.spn == format!("admin@{}", refreshed));
+        assert!(matches!(
+            rsclient.account_from_spn("admin@other.example.com"),
+            Err(ClientError::SpnRealmMismatch(_, d)) if d == refreshed
+        ));
+
+        // A new session starts without the cache, and fetches it again.
+        let session = rsclient.new_session().expect("Failed to create session");
+        let res = session.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+        assert!(
+            session
+                .get_domain_name()
+                .expect("Failed to get domain name")
+                == domain_name
+        );
+    });
+}
+
+#[test]
+fn test_server_rest_account_spn_lock() {
+    run_test(|rsclient: KanidmClient| {
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+        let domain_name = rsclient
+            .idm_domain_get_name()
+            .expect("Failed to get domain name");
+        rsclient
+            .idm_account_create("spn_account", "Spn Account")
+            .expect("Failed to create account");
+
+        rsclient
+            .idm_account_spn_lock("spn_account", "host@legacy.example.com")
+            .expect("Failed to lock spn");
+        let spn = rsclient
+            .idm_account_get_attr("spn_account", "spn")
+            .expect("Failed to get spn");
+        assert!(spn == Some(vec!["host@legacy.example.com".to_string()]));
+
+        // The locked spn isn't regenerated when the account changes.
+        rsclient
+            .idm_account_set_attr("spn_account", "displayname", &["Renamed"])
+            .expect("Failed to set displayname");
+        let spn = rsclient
+            .idm_account_get_attr("spn_account", "spn")
+            .expect("Failed to get spn");
+        assert!(spn == Some(vec!["host@legacy.example.com".to_string()]));
+
+        // Invalid spns are refused by the server.
+        assert!(rsclient
+            .idm_account_spn_lock("spn_account", "not an spn")
+            .is_err());
+
+        rsclient
+            .idm_account_spn_unlock("spn_account")
+            .expect("Failed to unlock spn");
+        let spn = rsclient
+            .idm_account_get_attr("spn_account", "spn")
+            .expect("Failed to get spn");
+        assert!(spn == Some(vec![format!("spn_account@{}", domain_name)]));
+    });
+}
+
+// The config and test fns can't capture, so they agree on the path this way.
+fn admin_socket_path() -> String {
+    std::env::temp_dir()
+        .join(format!("kanidm_admin_test_{}.sock", std::process::id()))
+        .to_str()
+        .expect("Invalid temp dir")
+        .to_string()
+}
+
+#[test]
+fn test_server_admin_socket_recover_account() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.update_admin_socket_path(&Some(admin_socket_path()));
+        },
+        |rsclient: KanidmClient| {
+            let mut stream = UnixStream::connect(admin_socket_path()).expect("Failed to connect");
+            let req = serde_json::to_string(&AdminRequest::RecoverAccount {
+                name: "admin".to_string(),
+                password: "a recovered admin password".to_string(),
+            })
+            .expect("Failed to serialise");
+            stream
+                .write_all(format!("{}\n", req).as_bytes())
+                .expect("Failed to send");
+            let mut line = String::new();
+            BufReader::new(stream)
+                .read_line(&mut line)
+                .expect("Failed to read");
+            let resp: AdminResponse = serde_json::from_str(&line).expect("Invalid response");
+            assert!(resp == AdminResponse::Success);
+
+            // The old password no longer works, and the recovered one does.
+            assert!(rsclient
+                .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+                .is_err());
+            let rsclient = rsclient.new_session().expect("Failed to create session");
+            assert!(rsclient
+                .auth_simple_password("admin", "a recovered admin password")
+                .is_ok());
+        },
+    );
+}
+
+#[test]
+fn test_server_reauth_sensitive_operation() {
+    run_test_with_config(
+        |config: &mut Configuration| {
+            config.update_reauth(&[ReauthOperation::Credential], Some(2));
+        },
+        |rsclient: KanidmClient| {
+            let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+            assert!(res.is_ok());
+
+            // Wait for the session to be older than the reauth_window.
+            thread::sleep(Duration::from_secs(4));
+
+            // The session is still valid for other operations ...
+            assert!(rsclient.whoami().unwrap().is_some());
+            // ... but not to change a credential.
+            let res = rsclient.idm_account_set_password(ADMIN_TEST_PASSWORD_CHANGE.to_string());
+            assert!(matches!(
+                res,
+                Err(ClientError::Http(sc, Some(OperationError::ReauthRequired), _))
+                    if sc == StatusCode::UNAUTHORIZED
+            ));
+
+            // Once authenticated again, it is allowed.
+            let _ = rsclient.logout();
+            let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+            assert!(res.is_ok());
+            assert!(rsclient
+                .idm_account_set_password(ADMIN_TEST_PASSWORD_CHANGE.to_string())
+                .is_ok());
+        },
+    );
+}
+
+// A stand in for a server that is starting up. It refuses the first `not_ready`
+// status requests, and then reports that it is ready.
+fn stub_status_server(not_ready: usize) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let addr = listener.local_addr().expect("Failed to get address");
+    thread::spawn(move || {
+        for (i, stream) in listener.incoming().enumerate() {
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(_) => return,
+            };
+            let mut request = String::new();
+            let mut reader = BufReader::new(stream.try_clone().expect("Failed to clone"));
+            while reader
+                .read_line(&mut request)
+                .map(|n| n > 2)
+                .unwrap_or(false)
+            {
+                request.clear();
+            }
+            let response = if i < not_ready {
+                "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+            } else {
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 4\r\nconnection: close\r\n\r\ntrue"
+            };
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    format!("http://{}", addr)
+}
+
+#[test]
+fn test_client_wait_for_ready() {
+    let rsclient = KanidmClientBuilder::new()
+        .address(stub_status_server(3))
+        .no_proxy()
+        .build()
+        .expect("Failed to build client");
+    assert!(rsclient.wait_for_ready(Duration::from_secs(30)).is_ok());
+
+    // A server that never becomes ready is a clear error once the timeout elapses.
+    let rsclient = KanidmClientBuilder::new()
+        .address(stub_status_server(usize::MAX))
+        .no_proxy()
+        .build()
+        .expect("Failed to build client");
+    match rsclient.wait_for_ready(Duration::from_secs(1)) {
+        Err(ClientError::NotReady(waited, reason)) => {
+            assert!(waited >= Duration::from_secs(1));
+            assert!(reason.contains("503"));
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+}
@@ -32,6 +32,8 @@ pub enum PluginError {
     Base(String),
     ReferentialIntegrity(String),
     PasswordImport(String),
+    SpnReserved(String),
+    SpnInvalidCharacters(String),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -49,12 +51,352 @@ pub enum ConsistencyError {
     MemberOfInvalid(u64),
     InvalidAttributeType(String),
     DuplicateUniqueAttribute(String),
-    InvalidSpn(u64),
+    InvalidSpn(u64, SpnInconsistency),
+    // The entry's spn is from a domain that is not trusted, with that domain.
+    UntrustedSpn(u64, String),
+    OrphanedSpn(u64, SpnOrphan),
+    InvalidSpnIndex(u64),
     SqliteIntegrityFailure,
     BackendAllIdsSync,
     BackendIndexSync,
 }
 
+impl ConsistencyError {
+    /// A suggestion for the administrator on how this inconsistency can be
+    /// resolved, if one is known.
+    pub fn remediation(&self) -> Option<&'static str> {
+        match self {
+            ConsistencyError::InvalidSpn(_, kind) => Some(kind.remediation()),
+            ConsistencyError::UntrustedSpn(_, _) => Some(SpnInconsistency::Untrusted.remediation()),
+            ConsistencyError::OrphanedSpn(_, kind) => Some(kind.remediation()),
+            ConsistencyError::InvalidSpnIndex(_) => {
+                Some("Apply any modification to the entry to regenerate its spn_index.")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Why an entry's spn was found to be invalid.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum SpnInconsistency {
+    /// The entry has no name, so no spn can be generated.
+    MissingName,
+    /// The entry has no spn.
+    Missing,
+    /// The spn does not match the one generated from the current domain name.
+    Mismatch,
+    /// The spn is in the server's reserved spn list.
+    Reserved,
+    /// The spn is from another domain, which is not one of the server's trusted domains.
+    Untrusted,
+    /// The spn has characters kerberos doesn't allow in a principal.
+    InvalidCharacters,
+}
+
+impl SpnInconsistency {
+    pub fn remediation(&self) -> &'static str {
+        match self {
+            SpnInconsistency::MissingName => {
+                "The entry has no name to generate an spn from. Restore the entry's name attribute."
+            }
+            SpnInconsistency::Missing => {
+                "Apply any modification to the entry to regenerate its spn."
+            }
+            SpnInconsistency::Mismatch => {
+                "The spn does not match the current domain name, so a domain rename may be incomplete. Apply any modification to the entry to regenerate its spn."
+            }
+            SpnInconsistency::Reserved => {
+                "The spn is reserved by the server configuration. Rename the entry, or remove the spn from reserved_spns."
+            }
+            SpnInconsistency::Untrusted => {
+                "The spn is from a domain that is not in trusted_domains. If the domain is trusted add it to trusted_domains, otherwise apply any modification to the entry to regenerate its spn."
+            }
+            SpnInconsistency::InvalidCharacters => {
+                "The spn has characters kerberos doesn't allow in a principal. Rename the entry, or set spn_charset_policy to sanitize to replace them."
+            }
+        }
+    }
+}
+
+/// Why an entry was found to hold an spn it should not have at all.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum SpnOrphan {
+    /// The entry is no longer an account or group.
+    Ineligible,
+    /// The entry is a tombstone.
+    Tombstone,
+}
+
+impl SpnOrphan {
+    pub fn remediation(&self) -> &'static str {
+        match self {
+            SpnOrphan::Ineligible => {
+                "The entry is not an account or group, so it should not have an spn. Remove the spn attribute from the entry."
+            }
+            SpnOrphan::Tombstone => {
+                "The entry was deleted, but its tombstone still holds an spn. Tombstones are removed by the server's periodic purge, so check that it is running, or restore the database from a backup."
+            }
+        }
+    }
+}
+
+/// An account or group found by an spn fsck to have an inconsistent spn.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpnFsckEntry {
+    pub uuid: String,
+    pub name: Option<String>,
+    pub spn: Option<String>,
+    pub expected_spn: Option<String>,
+    pub inconsistency: SpnInconsistency,
+}
+
+impl SpnFsckEntry {
+    /// Entries without a name can't have their spn regenerated, and regenerating
+    /// a reserved spn, or one with invalid characters, would only produce it again.
+    pub fn is_repairable(&self) -> bool {
+        !matches!(
+            self.inconsistency,
+            SpnInconsistency::MissingName
+                | SpnInconsistency::Reserved
+                | SpnInconsistency::InvalidCharacters
+        )
+    }
+}
+
+/// How the spn of an account or group is formed from its name. The server
+/// generates spns with this, so a tool can produce the same spns without one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpnFormat {
+    domain_name: String,
+    delimiter: char,
+    case_fold: bool,
+    suffix: Option<String>,
+    sanitize: bool,
+}
+
+impl SpnFormat {
+    pub fn new(domain_name: &str) -> Self {
+        SpnFormat {
+            domain_name: domain_name.to_string(),
+            delimiter: '@',
+            case_fold: false,
+            suffix: None,
+            sanitize: false,
+        }
+    }
+
+    /// The delimiter between name and realm in the string form of an spn.
+    pub fn delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Use the ASCII compatible encoding (punycode) of an internationalised
+    /// domain name in the realm. A domain name that is already ASCII is unchanged.
+    pub fn punycode(mut self, punycode: bool) -> Self {
+        if punycode {
+            // A name that isn't a valid IDN is left as it is, rather than
+            // generating spns in some other realm.
+            if let Ok(url::Host::Domain(ascii)) = url::Host::parse(self.domain_name.as_str()) {
+                self.domain_name = ascii;
+            }
+        }
+        self
+    }
+
+    /// Lowercase the name and realm.
+    pub fn case_fold(mut self, case_fold: bool) -> Self {
+        self.case_fold = case_fold;
+        self
+    }
+
+    /// Append this to the domain name to form the realm.
+    pub fn suffix(mut self, suffix: Option<&str>) -> Self {
+        self.suffix = suffix.map(str::to_string);
+        self
+    }
+
+    /// Replace each character of the name that kerberos doesn't allow in a
+    /// principal with '_'. The prefix is left as it is.
+    pub fn sanitize(mut self, sanitize: bool) -> Self {
+        self.sanitize = sanitize;
+        self
+    }
+
+    /// If kerberos allows this character in the name of a principal. Kerberos
+    /// itself is permissive, but this is what other implementations accept.
+    pub fn is_kerberos_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_'
+    }
+
+    pub fn realm(&self) -> String {
+        let realm = match &self.suffix {
+            Some(suffix) => format!("{}{}", self.domain_name, suffix),
+            None => self.domain_name.clone(),
+        };
+        if self.case_fold {
+            realm.to_lowercase()
+        } else {
+            realm
+        }
+    }
+
+    /// The name and realm of the spn of an entry with this name. With a prefix,
+    /// the kerberos service of the entry's class, the name is "prefix/name".
+    pub fn generate(&self, name: &str, prefix: Option<&str>) -> (String, String) {
+        let name = if self.case_fold {
+            name.to_lowercase()
+        } else {
+            name.to_string()
+        };
+        let name = if self.sanitize {
+            name.chars()
+                .map(|c| {
+                    if SpnFormat::is_kerberos_char(c) {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect()
+        } else {
+            name
+        };
+        // The prefix is the kerberos service, which is case sensitive.
+        let name = match prefix {
+            Some(prefix) => format!("{}/{}", prefix, name),
+            None => name,
+        };
+        (name, self.realm())
+    }
+
+    /// The string form of an spn, using the configured delimiter.
+    pub fn to_spn_string(&self, name: &str, realm: &str) -> String {
+        format!("{}{}{}", name, self.delimiter, realm)
+    }
+}
+
+/// The spn of an entry before and after it was regenerated.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpnRegenerateResult {
+    pub uuid: String,
+    pub spn_before: Option<String>,
+    pub spn_after: Option<String>,
+}
+
+/// The spn settings of a domain, so that they can be exported from one server and
+/// imported into another.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SpnConfig {
+    /// The service spn prefix of each class, each in the form "class=prefix".
+    #[serde(default)]
+    pub spn_prefixes: Vec<String>,
+    /// Only the accounts and groups matching this filter are given an spn.
+    #[serde(default)]
+    pub spn_scope: Option<Filter>,
+    /// If accounts past their account_expire keep their spn when spns are regenerated.
+    #[serde(default)]
+    pub spn_skip_expired: bool,
+}
+
+/// The throughput of spn generation and verification measured by the server, using
+/// synthetic entries that are never written.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpnBenchResult {
+    pub count: usize,
+    pub generate_per_sec: f64,
+    pub verify_per_sec: f64,
+    /// The number of accounts and groups, which a domain rename regenerates the spn of.
+    pub directory_size: usize,
+}
+
+/// One of the largest entries in the directory, as found by the entry size
+/// statistics.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EntrySize {
+    pub uuid: String,
+    pub name: Option<String>,
+    /// The number of attributes the entry has.
+    pub attrs: usize,
+    /// The number of values over all of its attributes, such as group members.
+    pub values: usize,
+}
+
+/// The effective configuration of a server, keyed by the option names of its
+/// config file, so that it can be compared with the file that was deployed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SystemConfig {
+    /// The options that are set, each rendered as a toml value.
+    pub values: BTreeMap<String, String>,
+    /// Secret options are only reported by whether they are set.
+    pub secrets: BTreeMap<String, bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthEventResult {
+    Success,
+    Failure,
+}
+
+/// An authentication that succeeded or failed, as recorded by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuthEventRecord {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    /// The name of the account, or the name given by the client if there is no
+    /// such account.
+    pub account: String,
+    pub result: AuthEventResult,
+    /// Why a failed authentication was denied.
+    pub reason: Option<String>,
+    pub sessionid: Uuid,
+}
+
+/// Select the recorded authentication events at or after `since` (in seconds
+/// since the unix epoch), optionally for a single account or result.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthEventQuery {
+    pub since: u64,
+    pub account: Option<String>,
+    pub result: Option<AuthEventResult>,
+}
+
+/// Trace the authentication steps of an account for the next `duration`
+/// seconds, replacing any earlier trace of it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthTraceRequest {
+    pub account: String,
+    pub duration: u64,
+}
+
+/// A single request of a traced authentication, and the server's response. Only
+/// the type of a credential is recorded, never its value, and the token of a
+/// successful authentication is not recorded either.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuthTraceStep {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    /// Absent if the server failed before a session was started.
+    pub sessionid: Option<Uuid>,
+    pub request: String,
+    pub response: String,
+}
+
+/// The authentication steps of an account, recorded from `started` until
+/// `until`, in seconds since the unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuthTrace {
+    pub account: String,
+    pub started: u64,
+    pub until: u64,
+    pub steps: Vec<AuthTraceStep>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum OperationError {
@@ -96,12 +438,22 @@ pub enum OperationError {
     SystemProtectedAttribute,
     PasswordTooWeak,
     PasswordTooShort(usize),
+    PasswordTooLong(usize),
+    CredentialTooLarge(usize),
     PasswordEmpty,
     PasswordBadListed,
     CryptographyError,
     ResourceLimit,
     QueueDisconnected,
     Webauthn,
+    MissingDomainInfo,
+    MaxEntriesExceeded(u64),
+    MaxModifyBatchExceeded(usize),
+    SearchSizeLimitExceeded(usize),
+    ReauthRequired,
+    ServerBusy,
+    AuthTraceDisabled,
+    AuthTraceTooLong(u64),
 }
 
 impl PartialEq for OperationError {
@@ -291,11 +643,50 @@ pub enum CredentialDetailType {
     PasswordMfa(bool, Vec<String>),
 }
 
+/// The backup flags an authenticator reported for a webauthn credential. A
+/// credential that is backup eligible is a multi-device passkey that may be
+/// synced, otherwise it's bound to the authenticator.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct WebauthnBackupState {
+    pub eligible: bool,
+    pub backed_up: bool,
+}
+
+impl fmt::Display for WebauthnBackupState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.eligible, self.backed_up) {
+            (false, _) => write!(f, "single device"),
+            (true, false) => write!(f, "multi device, not backed up"),
+            (true, true) => write!(f, "multi device, backed up"),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CredentialDetail {
     pub uuid: Uuid,
     pub claims: Vec<String>,
     pub type_: CredentialDetailType,
+    /// The backup state of each webauthn label, once it is known.
+    #[serde(default)]
+    pub webauthn_backup: BTreeMap<String, WebauthnBackupState>,
+}
+
+impl CredentialDetail {
+    fn fmt_webauthn(&self, f: &mut fmt::Formatter<'_>, labels: &[String]) -> fmt::Result {
+        if labels.is_empty() {
+            writeln!(f, "webauthn: no authenticators")
+        } else {
+            writeln!(f, "webauthn:")?;
+            for label in labels {
+                match self.webauthn_backup.get(label) {
+                    Some(backup) => writeln!(f, " * {} ({})", label, backup)?,
+                    None => writeln!(f, " * {}", label)?,
+                }
+            }
+            write!(f, "")
+        }
+    }
 }
 
 impl fmt::Display for CredentialDetail {
@@ -308,17 +699,7 @@ impl fmt::Display for CredentialDetail {
         match &self.type_ {
             CredentialDetailType::Password => writeln!(f, "password: set"),
             CredentialDetailType::GeneratedPassword => writeln!(f, "generated password: set"),
-            CredentialDetailType::Webauthn(labels) => {
-                if labels.is_empty() {
-                    writeln!(f, "webauthn: no authenticators")
-                } else {
-                    writeln!(f, "webauthn:")?;
-                    for label in labels {
-                        writeln!(f, " * {}", label)?;
-                    }
-                    write!(f, "")
-                }
-            }
+            CredentialDetailType::Webauthn(labels) => self.fmt_webauthn(f, labels),
             CredentialDetailType::PasswordMfa(totp, labels) => {
                 writeln!(f, "password: set")?;
                 if *totp {
@@ -326,15 +707,7 @@ impl fmt::Display for CredentialDetail {
                 } else {
                     writeln!(f, "totp: disabled")?;
                 }
-                if labels.is_empty() {
-                    writeln!(f, "webauthn: no authenticators")
-                } else {
-                    writeln!(f, "webauthn:")?;
-                    for label in labels {
-                        writeln!(f, " * {}", label)?;
-                    }
-                    write!(f, "")
-                }
+                self.fmt_webauthn(f, labels)
             }
         }
     }
@@ -427,11 +800,23 @@ impl OperationResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SearchRequest {
     pub filter: Filter,
+    /// Refuse the search if more than this many entries match. The server's
+    /// search_result_limit applies when this is higher or unset.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Only return these attributes of each entry. The server's
+    /// default_search_attrs applies when this is unset.
+    #[serde(default)]
+    pub attrs: Option<Vec<String>>,
 }
 
 impl SearchRequest {
     pub fn new(filter: Filter) -> Self {
-        SearchRequest { filter }
+        SearchRequest {
+            filter,
+            limit: None,
+            attrs: None,
+        }
     }
 }
 
@@ -530,6 +915,13 @@ impl PartialEq for AuthMech {
     }
 }
 
+/// The authentication mechanisms this server offers. These are not specific to
+/// an account - an account can only use those it has credentials for.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuthCapabilities {
+    pub mechs: Vec<AuthMech>,
+}
+
 impl fmt::Display for AuthMech {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -541,6 +933,62 @@ impl fmt::Display for AuthMech {
     }
 }
 
+/// The authentication mechanisms an account could use, given the credentials it
+/// has enrolled and the mechanisms this server offers.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuthPolicy {
+    /// The mechanisms the account has credentials for.
+    pub enrolled: Vec<AuthMech>,
+    /// The enrolled mechanisms that this server has disabled.
+    pub disabled: Vec<AuthMech>,
+    /// The mechanisms the account can authenticate with.
+    pub usable: Vec<AuthMech>,
+    /// The credentials of the account, if it has any.
+    pub credentials: Option<CredentialStatus>,
+}
+
+impl fmt::Display for AuthPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.enrolled.is_empty() {
+            writeln!(
+                f,
+                "no credentials are enrolled - this account can't authenticate"
+            )?;
+        } else {
+            writeln!(f, "mechanisms:")?;
+            for mech in &self.enrolled {
+                if self.disabled.contains(mech) {
+                    writeln!(f, " * {}: disabled on this server", mech)?;
+                } else {
+                    writeln!(f, " * {}: usable", mech)?;
+                }
+            }
+            if self.usable.is_empty() {
+                writeln!(
+                    f,
+                    "all of the enrolled mechanisms are disabled - this account can't authenticate"
+                )?;
+            }
+        }
+
+        // Webauthn tokens enrolled alongside a password are only a second factor.
+        let mfa_tokens = self.credentials.iter().flat_map(|c| c.creds.iter()).any(|c| {
+            matches!(&c.type_, CredentialDetailType::PasswordMfa(_, labels) if !labels.is_empty())
+        });
+        if mfa_tokens {
+            writeln!(
+                f,
+                "webauthn tokens are used with the password, as a second factor"
+            )?;
+        }
+
+        match &self.credentials {
+            Some(status) => status.fmt(f),
+            None => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum AuthStep {
@@ -679,6 +1127,12 @@ pub struct TotpSecret {
     pub secret: Vec<u8>,
     pub algo: TotpAlgo,
     pub step: u64,
+    #[serde(default = "default_totp_digits")]
+    pub digits: u8,
+}
+
+fn default_totp_digits() -> u8 {
+    6
 }
 
 impl TotpSecret {
@@ -700,9 +1154,10 @@ impl TotpSecret {
         let algo = self.algo.to_string();
         let secret = self.get_secret();
         let period = self.step;
+        let digits = self.digits;
         format!(
-            "otpauth://totp/{}?secret={}&issuer={}&algorithm={}&digits=6&period={}",
-            label, secret, issuer, algo, period
+            "otpauth://totp/{}?secret={}&issuer={}&algorithm={}&digits={}&period={}",
+            label, secret, issuer, algo, digits, period
         )
     }
 
@@ -791,7 +1246,45 @@ impl SingleStringRequest {
 #[cfg(test)]
 mod tests {
     use crate::v1::Filter as ProtoFilter;
-    use crate::v1::{TotpAlgo, TotpSecret};
+    use crate::v1::{SpnFormat, TotpAlgo, TotpSecret};
+
+    #[test]
+    fn test_spn_format() {
+        let generate = |f: &SpnFormat, prefix| {
+            let (name, realm) = f.generate("TestPerson", prefix);
+            f.to_spn_string(name.as_str(), realm.as_str())
+        };
+
+        let f = SpnFormat::new("example.com");
+        assert!(generate(&f, None) == "TestPerson@example.com");
+        // The prefix keeps its case, even when the rest is folded.
+        assert!(generate(&f, Some("HTTP")) == "HTTP/TestPerson@example.com");
+        let f = f.case_fold(true);
+        assert!(generate(&f, Some("HTTP")) == "HTTP/testperson@example.com");
+
+        let f = SpnFormat::new("Example.COM")
+            .case_fold(true)
+            .suffix(Some(".AU"))
+            .delimiter('/');
+        assert!(f.realm() == "example.com.au");
+        assert!(generate(&f, None) == "testperson/example.com.au");
+
+        let f = SpnFormat::new("bücher.example");
+        assert!(generate(&f, None) == "TestPerson@bücher.example");
+        let f = f.punycode(true);
+        assert!(generate(&f, None) == "TestPerson@xn--bcher-kva.example");
+        // ASCII domain names are unchanged.
+        assert!(SpnFormat::new("example.com").punycode(true).realm() == "example.com");
+
+        // Only the name is sanitized, not the prefix or realm.
+        let f = SpnFormat::new("bücher.example").sanitize(true);
+        let (name, realm) = f.generate("jöhn:smith", Some("HTTP"));
+        assert!(f.to_spn_string(name.as_str(), realm.as_str()) == "HTTP/j_hn_smith@bücher.example");
+        assert!(SpnFormat::is_kerberos_char('a'));
+        assert!(SpnFormat::is_kerberos_char('-'));
+        assert!(!SpnFormat::is_kerberos_char('ö'));
+        assert!(!SpnFormat::is_kerberos_char('#'));
+    }
 
     #[test]
     fn test_protofilter_simple() {
@@ -807,6 +1300,7 @@ mod tests {
             issuer: "blackhats".to_string(),
             secret: vec![0xaa, 0xbb, 0xcc, 0xdd],
             step: 30,
+            digits: 6,
             algo: TotpAlgo::Sha256,
         };
         let s = totp.to_uri();
@@ -818,6 +1312,7 @@ mod tests {
             issuer: "blackhats australia".to_string(),
             secret: vec![0xaa, 0xbb, 0xcc, 0xdd],
             step: 30,
+            digits: 6,
             algo: TotpAlgo::Sha256,
         };
         let s = totp.to_uri();
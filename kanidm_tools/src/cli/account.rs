@@ -1,6 +1,8 @@
-use crate::password_prompt;
+use crate::util::spn_parse;
+use crate::{password_prompt, totp_parse};
 use crate::{
-    AccountCredential, AccountOpt, AccountPosix, AccountRadius, AccountSsh, AccountValidity,
+    AccountCredential, AccountOpt, AccountPosix, AccountRadius, AccountSpn, AccountSsh,
+    AccountValidity,
 };
 use qrcode::render::unicode;
 use qrcode::QrCode;
@@ -40,6 +42,13 @@ impl AccountOpt {
             AccountOpt::Get(aopt) => aopt.copt.debug,
             AccountOpt::Delete(aopt) => aopt.copt.debug,
             AccountOpt::Create(aopt) => aopt.copt.debug,
+            AccountOpt::SpnFor(aopt) => aopt.copt.debug,
+            AccountOpt::AuthPolicy(aopt) => aopt.copt.debug,
+            AccountOpt::Spn(asopt) => match asopt {
+                AccountSpn::Set(aso) => aso.copt.debug,
+                AccountSpn::Unset(aso) => aso.copt.debug,
+                AccountSpn::Regenerate(aso) => aso.copt.debug,
+            },
             AccountOpt::Validity(avopt) => match avopt {
                 AccountValidity::Show(ano) => ano.copt.debug,
                 AccountValidity::ExpireAt(ano) => ano.copt.debug,
@@ -53,7 +62,7 @@ impl AccountOpt {
             // id/cred/primary/set
             AccountOpt::Credential(acopt) => match acopt {
                 AccountCredential::SetPassword(acsopt) => {
-                    let client = acsopt.copt.to_client();
+                    let mut client = acsopt.copt.to_client();
                     let password = match password_prompt(
                         format!("Enter new password for {}: ", acsopt.aopts.account_id).as_str(),
                     ) {
@@ -64,19 +73,23 @@ impl AccountOpt {
                         }
                     };
 
-                    if let Err(e) = client.idm_account_primary_credential_set_password(
-                        acsopt.aopts.account_id.as_str(),
-                        password.as_str(),
-                    ) {
+                    if let Err(e) = acsopt.copt.with_reauth(&mut client, |client| {
+                        client.idm_account_primary_credential_set_password(
+                            acsopt.aopts.account_id.as_str(),
+                            password.as_str(),
+                        )
+                    }) {
                         eprintln!("Error -> {:?}", e);
                     }
                 }
                 AccountCredential::GeneratePassword(acsopt) => {
-                    let client = acsopt.copt.to_client();
+                    let mut client = acsopt.copt.to_client();
 
-                    match client.idm_account_primary_credential_set_generated(
-                        acsopt.aopts.account_id.as_str(),
-                    ) {
+                    match acsopt.copt.with_reauth(&mut client, |client| {
+                        client.idm_account_primary_credential_set_generated(
+                            acsopt.aopts.account_id.as_str(),
+                        )
+                    }) {
                         Ok(npw) => {
                             println!(
                                 "Generated password for {}: {}",
@@ -89,13 +102,14 @@ impl AccountOpt {
                     }
                 }
                 AccountCredential::RegisterWebauthn(acsopt) => {
-                    let client = acsopt.copt.to_client();
+                    let mut client = acsopt.copt.to_client();
 
-                    let (session, chal) = match client
-                        .idm_account_primary_credential_register_webauthn(
+                    let (session, chal) = match acsopt.copt.with_reauth(&mut client, |client| {
+                        client.idm_account_primary_credential_register_webauthn(
                             acsopt.aopts.account_id.as_str(),
                             acsopt.tag.as_str(),
-                        ) {
+                        )
+                    }) {
                         Ok(v) => v,
                         Err(e) => {
                             eprintln!("Error Starting Registration -> {:?}", e);
@@ -129,11 +143,13 @@ impl AccountOpt {
                     }
                 }
                 AccountCredential::RemoveWebauthn(acsopt) => {
-                    let client = acsopt.copt.to_client();
-                    match client.idm_account_primary_credential_remove_webauthn(
-                        acsopt.aopts.account_id.as_str(),
-                        acsopt.tag.as_str(),
-                    ) {
+                    let mut client = acsopt.copt.to_client();
+                    match acsopt.copt.with_reauth(&mut client, |client| {
+                        client.idm_account_primary_credential_remove_webauthn(
+                            acsopt.aopts.account_id.as_str(),
+                            acsopt.tag.as_str(),
+                        )
+                    }) {
                         Ok(_) => {
                             println!("Webauthn removal success.");
                         }
@@ -143,11 +159,13 @@ impl AccountOpt {
                     }
                 }
                 AccountCredential::RegisterTotp(acsopt) => {
-                    let client = acsopt.copt.to_client();
-                    let (session, tok) = match client.idm_account_primary_credential_generate_totp(
-                        acsopt.aopts.account_id.as_str(),
-                        acsopt.tag.as_str(),
-                    ) {
+                    let mut client = acsopt.copt.to_client();
+                    let (session, tok) = match acsopt.copt.with_reauth(&mut client, |client| {
+                        client.idm_account_primary_credential_generate_totp(
+                            acsopt.aopts.account_id.as_str(),
+                            acsopt.tag.as_str(),
+                        )
+                    }) {
                         Ok(v) => v,
                         Err(e) => {
                             eprintln!("Error Starting Registration -> {:?}", e);
@@ -192,10 +210,10 @@ impl AccountOpt {
                     };
 
                     // Convert to a u32.
-                    let totp = match u32::from_str_radix(totp_input.trim(), 10) {
+                    let totp = match totp_parse(&totp_input) {
                         Ok(v) => v,
                         Err(e) => {
-                            eprintln!("Invalid TOTP -> {:?}", e);
+                            eprintln!("Invalid TOTP -> {}", e);
                             return;
                         }
                     };
@@ -214,10 +232,12 @@ impl AccountOpt {
                     }
                 }
                 AccountCredential::RemoveTotp(acsopt) => {
-                    let client = acsopt.copt.to_client();
-                    match client.idm_account_primary_credential_remove_totp(
-                        acsopt.aopts.account_id.as_str(),
-                    ) {
+                    let mut client = acsopt.copt.to_client();
+                    match acsopt.copt.with_reauth(&mut client, |client| {
+                        client.idm_account_primary_credential_remove_totp(
+                            acsopt.aopts.account_id.as_str(),
+                        )
+                    }) {
                         Ok(_) => {
                             println!("TOTP removal success.");
                         }
@@ -255,18 +275,20 @@ impl AccountOpt {
                     }
                 }
                 AccountRadius::Generate(aopt) => {
-                    let client = aopt.copt.to_client();
-                    if let Err(e) = client
-                        .idm_account_radius_credential_regenerate(aopt.aopts.account_id.as_str())
-                    {
+                    let mut client = aopt.copt.to_client();
+                    if let Err(e) = aopt.copt.with_reauth(&mut client, |client| {
+                        client.idm_account_radius_credential_regenerate(
+                            aopt.aopts.account_id.as_str(),
+                        )
+                    }) {
                         eprintln!("Error -> {:?}", e);
                     }
                 }
                 AccountRadius::Delete(aopt) => {
-                    let client = aopt.copt.to_client();
-                    if let Err(e) =
+                    let mut client = aopt.copt.to_client();
+                    if let Err(e) = aopt.copt.with_reauth(&mut client, |client| {
                         client.idm_account_radius_credential_delete(aopt.aopts.account_id.as_str())
-                    {
+                    }) {
                         eprintln!("Error -> {:?}", e);
                     }
                 }
@@ -292,7 +314,7 @@ impl AccountOpt {
                     }
                 }
                 AccountPosix::SetPassword(aopt) => {
-                    let client = aopt.copt.to_client();
+                    let mut client = aopt.copt.to_client();
                     let password = match password_prompt("Enter new unit (sudo) password: ") {
                         Some(v) => v,
                         None => {
@@ -301,10 +323,12 @@ impl AccountOpt {
                         }
                     };
 
-                    if let Err(e) = client.idm_account_unix_cred_put(
-                        aopt.aopts.account_id.as_str(),
-                        password.as_str(),
-                    ) {
+                    if let Err(e) = aopt.copt.with_reauth(&mut client, |client| {
+                        client.idm_account_unix_cred_put(
+                            aopt.aopts.account_id.as_str(),
+                            password.as_str(),
+                        )
+                    }) {
                         eprintln!("Error -> {:?}", e);
                     }
                 }
@@ -488,6 +512,108 @@ impl AccountOpt {
                     }
                 }
             }, // end AccountOpt::Validity
+            AccountOpt::AuthPolicy(aopt) => {
+                let client = aopt.copt.to_client();
+                match client.idm_account_get_auth_policy(aopt.aopts.account_id.as_str()) {
+                    Ok(policy) => print!("{}", policy),
+                    Err(e) => eprintln!("Error displaying auth policy -> {:?}", e),
+                }
+            }
+            AccountOpt::SpnFor(aopt) => {
+                let client = aopt.copt.to_client();
+                let domain_name = match client.idm_domain_get_name() {
+                    Ok(d) => d,
+                    Err(e) => {
+                        eprintln!("Error -> {:?}", e);
+                        return;
+                    }
+                };
+                match spn_preview(aopt.aopts.account_id.as_str(), domain_name.as_str()) {
+                    Ok(spn) => println!("{}", spn),
+                    Err(msg) => eprintln!("Error -> {}", msg),
+                }
+            }
+            AccountOpt::Spn(asopt) => match asopt {
+                AccountSpn::Set(aso) => {
+                    if !aso.lock {
+                        eprintln!(
+                            "Error -> An spn that isn't locked would be regenerated, use --lock"
+                        );
+                        return;
+                    }
+                    if let Err(e) = spn_parse(aso.spn.as_str()) {
+                        eprintln!("Error -> \"{}\" is not a valid spn: {}", aso.spn, e);
+                        return;
+                    }
+                    let client = aso.copt.to_client();
+                    match client
+                        .idm_account_spn_lock(aso.aopts.account_id.as_str(), aso.spn.as_str())
+                    {
+                        Ok(_) => println!("Success"),
+                        Err(e) => eprintln!("Error -> {:?}", e),
+                    }
+                }
+                AccountSpn::Unset(aso) => {
+                    let client = aso.copt.to_client();
+                    match client.idm_account_spn_unlock(aso.aopts.account_id.as_str()) {
+                        Ok(_) => println!("Success"),
+                        Err(e) => eprintln!("Error -> {:?}", e),
+                    }
+                }
+                AccountSpn::Regenerate(aso) => {
+                    let client = aso.copt.to_client();
+                    match client.idm_account_spn_regenerate(aso.aopts.account_id.as_str()) {
+                        Ok(r) => println!(
+                            "{} -> {}",
+                            r.spn_before.as_deref().unwrap_or("<no spn>"),
+                            r.spn_after.as_deref().unwrap_or("<no spn>")
+                        ),
+                        Err(e) => eprintln!("Error -> {:?}", e),
+                    }
+                }
+            }, // end AccountOpt::Spn
         }
     }
 }
+
+// This mirrors the server's rules for names, so that the preview is what the
+// account would actually be given.
+fn spn_preview(name: &str, domain_name: &str) -> Result<String, String> {
+    const RESERVED: &[&str] = &["root", "nobody", "nogroup", "wheel", "sshd", "shadow"];
+
+    // Names are case insensitive, and stored lowercased.
+    let name = name.to_lowercase();
+    let is_uuid = name.len() == 36
+        && name.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+
+    if name.is_empty() {
+        Err("An account name must not be empty".to_string())
+    } else if name.starts_with('_') || name.starts_with('.') {
+        Err(format!(
+            "{} is not a valid name, it must not start with _ or .",
+            name
+        ))
+    } else if name.contains(|c: char| c.is_whitespace() || "@,/\\=".contains(c)) {
+        Err(format!(
+            "{} is not a valid name, it must not contain whitespace, @ , / \\ or =",
+            name
+        ))
+    } else if name.chars().all(|c| c.is_ascii_digit()) {
+        Err(format!(
+            "{} is not a valid name, it must not be only digits",
+            name
+        ))
+    } else if is_uuid {
+        Err(format!(
+            "{} is not a valid name, it must not be a uuid",
+            name
+        ))
+    } else if RESERVED.contains(&name.as_str()) || name.starts_with("systemd") {
+        Err(format!("{} is a reserved name", name))
+    } else {
+        Ok(format!("{}@{}", name, domain_name))
+    }
+}
@@ -1,13 +1,129 @@
-use crate::login::read_tokens;
-use crate::CommonOpt;
-use kanidm_client::{KanidmClient, KanidmClientBuilder};
+use crate::login::{read_tokens, split_legacy_token_key, split_token_key};
+use crate::{CommonOpt, LoginOpt};
+use kanidm_client::{ClientError, KanidmClient, KanidmClientBuilder};
+use kanidm_proto::v1::OperationError;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use structopt::StructOpt;
+
+static SYSTEM_CONFIG_PATH: &str = "/etc/kanidm/config";
+static USER_CONFIG_PATH: &str = "~/.config/kanidm";
+
+/// A named set of connection options, defined in the configuration as
+/// a `[profiles.<name>]` table.
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ProfileConfig {
+    uri: Option<String>,
+    ca_path: Option<String>,
+    username: Option<String>,
+    login_prefer: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ProfilesConfig {
+    /// The authentication mechanisms login chooses automatically, most preferred first.
+    login_prefer: Option<Vec<String>>,
+    #[serde(default)]
+    profiles: BTreeMap<String, ProfileConfig>,
+}
+
+fn read_profiles(config_path: &str) -> BTreeMap<String, ProfileConfig> {
+    read_cli_config(config_path).profiles
+}
+
+fn read_cli_config(config_path: &str) -> ProfilesConfig {
+    let mut f = match File::open(config_path) {
+        Ok(f) => f,
+        Err(e) => {
+            if e.kind() != ErrorKind::NotFound {
+                debug!(
+                    "Unable to open config file {} [{:?}], skipping ...",
+                    config_path, e
+                );
+            }
+            return ProfilesConfig::default();
+        }
+    };
+
+    let mut contents = String::new();
+    if let Err(e) = f.read_to_string(&mut contents) {
+        error!("Failed to read config {} -- {:?}", config_path, e);
+        std::process::exit(1);
+    }
+
+    match toml::from_str::<ProfilesConfig>(contents.as_str()) {
+        Ok(pc) => pc,
+        Err(e) => {
+            error!(
+                "Failed to parse profiles in config {} -- {:?}",
+                config_path, e
+            );
+            std::process::exit(1);
+        }
+    }
+}
 
 impl CommonOpt {
+    // The selected profile, if any. The user configuration takes precedence
+    // over the system configuration.
+    fn read_profile(&self) -> Option<(&str, ProfileConfig)> {
+        let name = self.profile.as_deref()?;
+        let user_config_path: String = shellexpand::tilde(USER_CONFIG_PATH).into_owned();
+        let profile = read_profiles(&user_config_path)
+            .remove(name)
+            .or_else(|| read_profiles(SYSTEM_CONFIG_PATH).remove(name));
+        match profile {
+            Some(p) => Some((name, p)),
+            None => {
+                error!(
+                    "Profile {} not found in {} or {}",
+                    name, SYSTEM_CONFIG_PATH, USER_CONFIG_PATH
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// The username given on the command line, or from the selected profile.
+    pub fn resolve_username(&self) -> Option<String> {
+        self.username.clone().or_else(|| {
+            self.read_profile()
+                .and_then(|(_, profile)| profile.username)
+        })
+    }
+
+    /// The mechanism preference for login from the configuration. A profile's
+    /// preference overrides the user's, which overrides the system's.
+    pub fn login_prefer(&self) -> Vec<String> {
+        if let Some(prefer) = self
+            .read_profile()
+            .and_then(|(_, profile)| profile.login_prefer)
+        {
+            return prefer;
+        }
+        let user_config_path: String = shellexpand::tilde(USER_CONFIG_PATH).into_owned();
+        read_cli_config(&user_config_path)
+            .login_prefer
+            .or_else(|| read_cli_config(SYSTEM_CONFIG_PATH).login_prefer)
+            .unwrap_or_default()
+    }
+
+    /// The key older versions stored this username's session under in the token
+    /// store, which is prefixed by the profile rather than the server.
+    pub fn legacy_token_key(&self, username: &str) -> String {
+        match &self.profile {
+            Some(profile) => format!("{}/{}", profile, username),
+            None => username.to_string(),
+        }
+    }
+
     pub fn to_unauth_client(&self) -> KanidmClient {
-        let config_path: String = shellexpand::tilde("~/.config/kanidm").into_owned();
+        let config_path: String = shellexpand::tilde(USER_CONFIG_PATH).into_owned();
 
         let client_builder = match KanidmClientBuilder::new()
-            .read_options_from_optional_config("/etc/kanidm/config")
+            .read_options_from_optional_config(SYSTEM_CONFIG_PATH)
             .and_then(|cb| cb.read_options_from_optional_config(&config_path))
         {
             Ok(c) => {
@@ -20,6 +136,29 @@ impl CommonOpt {
             }
         };
 
+        // The profile overrides the defaults from the configuration, and the
+        // command line overrides the profile.
+        let client_builder = match self.read_profile() {
+            Some((name, profile)) => {
+                debug!("Using profile {}", name);
+                let client_builder = match profile.uri {
+                    Some(uri) => client_builder.address(uri),
+                    None => client_builder,
+                };
+                match profile.ca_path {
+                    Some(p) => match client_builder.add_root_certificate_filepath(&p) {
+                        Ok(cb) => cb,
+                        Err(e) => {
+                            error!("Failed to add profile ca certificate -- {:?}", e);
+                            std::process::exit(1);
+                        }
+                    },
+                    None => client_builder,
+                }
+            }
+            None => client_builder,
+        };
+
         let client_builder = match &self.addr {
             Some(a) => client_builder.address(a.to_string()),
             None => client_builder,
@@ -48,6 +187,15 @@ impl CommonOpt {
 
     pub fn to_client(&self) -> KanidmClient {
         let client = self.to_unauth_client();
+
+        // A token from the environment (see login --export-env) takes precedence
+        // over the token store.
+        if let Ok(token) = std::env::var("KANIDM_TOKEN") {
+            debug!("Using token from KANIDM_TOKEN");
+            client.set_token(token);
+            return client;
+        }
+
         // Read the token file.
         let tokens = match read_tokens() {
             Ok(t) => t,
@@ -57,15 +205,39 @@ impl CommonOpt {
             }
         };
 
+        // Only consider the sessions with the selected server. Those stored by
+        // older versions without their server are matched by the profile, and
+        // only used when there's no session with the server for that name.
+        let origin = client.get_origin();
+        let mut legacy: BTreeMap<String, String> = BTreeMap::new();
+        let mut tokens: BTreeMap<String, String> = tokens
+            .into_iter()
+            .filter_map(|(k, v)| match split_token_key(&k) {
+                Some((o, uname)) if o == origin => Some((uname.to_string(), v.token)),
+                Some(_) => None,
+                None => {
+                    let (profile, uname) = split_legacy_token_key(&k);
+                    if profile == self.profile.as_deref() {
+                        legacy.insert(uname.to_string(), v.token);
+                    }
+                    None
+                }
+            })
+            .collect();
+        for (uname, token) in legacy {
+            tokens.entry(uname).or_insert(token);
+        }
+
         if tokens.is_empty() {
             error!(
-                "No valid authentication tokens found. Please login with the 'login' subcommand."
+                "No valid authentication tokens found for {}. Please login with the 'login' subcommand.",
+                origin
             );
             std::process::exit(1);
         }
 
         // If we have a username, use that to select tokens
-        let token = match &self.username {
+        let token = match &self.resolve_username() {
             Some(username) => {
                 // Is it in the store?
                 match tokens.get(username) {
@@ -96,4 +268,52 @@ impl CommonOpt {
 
         client
     }
+
+    /// Run an operation that the server may require a recent authentication for.
+    /// If the session is too old, login again as the same user and retry it once.
+    /// The client is replaced by one using the new session.
+    pub fn with_reauth<T, F>(&self, client: &mut KanidmClient, f: F) -> Result<T, ClientError>
+    where
+        F: Fn(&KanidmClient) -> Result<T, ClientError>,
+    {
+        let res = f(client);
+        if !matches!(
+            res,
+            Err(ClientError::Http(
+                _,
+                Some(OperationError::ReauthRequired),
+                _
+            ))
+        ) {
+            return res;
+        }
+        if std::env::var("KANIDM_TOKEN").is_ok() {
+            error!("This operation requires a recent authentication. Please login again and update KANIDM_TOKEN.");
+            return res;
+        }
+
+        // The session is still valid for other operations, so it tells us who to
+        // login as.
+        let username = match client.whoami() {
+            Ok(Some((_, uat))) => uat.name,
+            _ => return res,
+        };
+        eprintln!(
+            "This operation requires a recent authentication, please login again as {}.",
+            username
+        );
+
+        let mut lopt = LoginOpt::from_iter(&["login", "--remember"]);
+        lopt.copt = CommonOpt {
+            debug: self.debug,
+            addr: self.addr.clone(),
+            username: Some(username),
+            ca_path: self.ca_path.clone(),
+            profile: self.profile.clone(),
+        };
+        lopt.exec();
+
+        *client = lopt.copt.to_client();
+        f(client)
+    }
 }
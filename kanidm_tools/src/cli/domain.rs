@@ -0,0 +1,79 @@
+use crate::{DomainOpt, SpnConfigFileOpt, SpnConfigOpt};
+use kanidm_proto::v1::SpnConfig;
+use std::fs;
+
+impl DomainOpt {
+    pub fn debug(&self) -> bool {
+        match self {
+            DomainOpt::SpnConfig(sopt) => sopt.debug(),
+        }
+    }
+
+    pub fn exec(&self) {
+        match self {
+            DomainOpt::SpnConfig(sopt) => sopt.exec(),
+        }
+    }
+}
+
+impl SpnConfigOpt {
+    pub fn debug(&self) -> bool {
+        match self {
+            SpnConfigOpt::Export(fopt) | SpnConfigOpt::Import(fopt) => fopt.copt.debug,
+        }
+    }
+
+    pub fn exec(&self) {
+        match self {
+            SpnConfigOpt::Export(fopt) => fopt.export(),
+            SpnConfigOpt::Import(fopt) => fopt.import(),
+        }
+    }
+}
+
+impl SpnConfigFileOpt {
+    fn export(&self) {
+        let client = self.copt.to_client();
+        let config = match client.idm_domain_spn_config_export() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Error -> {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = serde_json::to_string_pretty(&config)
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|s| fs::write(&self.path, s).map_err(|e| format!("{:?}", e)))
+        {
+            eprintln!("Unable to write {} -> {}", self.path.display(), e);
+            std::process::exit(1);
+        }
+        println!("Exported the spn settings to {}", self.path.display());
+    }
+
+    fn import(&self) {
+        // Unknown fields are refused, so a file from a newer server isn't
+        // partially applied.
+        let config: SpnConfig = match fs::read_to_string(&self.path)
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|s| serde_json::from_str(&s).map_err(|e| format!("{}", e)))
+        {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Unable to read {} -> {}", self.path.display(), e);
+                std::process::exit(1);
+            }
+        };
+
+        let mut client = self.copt.to_client();
+        match self.copt.with_reauth(&mut client, |client| {
+            client.idm_domain_spn_config_import(&config)
+        }) {
+            Ok(()) => println!("Imported the spn settings from {}", self.path.display()),
+            Err(e) => {
+                eprintln!("Error -> {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
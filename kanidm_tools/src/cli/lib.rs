@@ -17,10 +17,15 @@ include!("../opt/kanidm.rs");
 
 pub mod account;
 pub mod common;
+pub mod domain;
 pub mod group;
 pub mod login;
+pub mod progress;
 pub mod raw;
 pub mod recycle;
+pub mod session;
+pub mod system;
+pub mod util;
 
 impl SelfOpt {
     pub fn debug(&self) -> bool {
@@ -53,7 +58,7 @@ impl SelfOpt {
             }
 
             SelfOpt::SetPassword(copt) => {
-                let client = copt.to_client();
+                let mut client = copt.to_client();
 
                 let password = match rpassword::prompt_password_stderr("Enter new password: ") {
                     Ok(p) => p,
@@ -63,7 +68,9 @@ impl SelfOpt {
                     }
                 };
 
-                if let Err(e) = client.idm_account_set_password(password) {
+                if let Err(e) = copt.with_reauth(&mut client, |client| {
+                    client.idm_account_set_password(password.clone())
+                }) {
                     error!("Error -> {:?}", e);
                 }
             }
@@ -80,6 +87,10 @@ impl KanidmClientOpt {
             KanidmClientOpt::Account(aopt) => aopt.debug(),
             KanidmClientOpt::Group(gopt) => gopt.debug(),
             KanidmClientOpt::Recycle(ropt) => ropt.debug(),
+            KanidmClientOpt::System(sopt) => sopt.debug(),
+            KanidmClientOpt::Domain(dopt) => dopt.debug(),
+            KanidmClientOpt::Session(sopt) => sopt.debug(),
+            KanidmClientOpt::Util(uopt) => uopt.debug(),
         }
     }
 
@@ -91,10 +102,31 @@ impl KanidmClientOpt {
             KanidmClientOpt::Account(aopt) => aopt.exec(),
             KanidmClientOpt::Group(gopt) => gopt.exec(),
             KanidmClientOpt::Recycle(ropt) => ropt.exec(),
+            KanidmClientOpt::System(sopt) => sopt.exec(),
+            KanidmClientOpt::Domain(dopt) => dopt.exec(),
+            KanidmClientOpt::Session(sopt) => sopt.exec(),
+            KanidmClientOpt::Util(uopt) => uopt.exec(),
         }
     }
 }
 
+/// Parse a TOTP code as entered by a user. The server holds the digit length
+/// of the account's token, so we accept any of the lengths authenticators use
+/// and let the server verify it. Leading zeros are significant in the length.
+pub(crate) fn totp_parse(input: &str) -> Result<u32, String> {
+    let input = input.trim();
+    if !input.chars().all(|c| c.is_ascii_digit()) {
+        return Err("TOTP must only contain digits".to_string());
+    }
+    if input.len() != 6 && input.len() != 8 {
+        return Err(format!(
+            "TOTP must be 6 or 8 digits, not {} digits",
+            input.len()
+        ));
+    }
+    u32::from_str_radix(input, 10).map_err(|e| format!("{:?}", e))
+}
+
 pub(crate) fn password_prompt(prompt: &str) -> Option<String> {
     for _ in 0..3 {
         let password = match rpassword::prompt_password_stderr(prompt) {
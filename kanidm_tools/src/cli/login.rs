@@ -1,30 +1,256 @@
-use crate::LoginOpt;
+use crate::{totp_parse, LoginOpt};
 use kanidm_client::{ClientError, KanidmClient};
-use kanidm_proto::v1::{AuthAllowed, AuthResponse, AuthState};
-use libc::umask;
+use kanidm_proto::v1::{AuthAllowed, AuthMech, AuthResponse, AuthState};
+use libc::{fcntl, isatty, tcgetattr, tcsetattr, termios, umask, F_GETFD, STDIN_FILENO, TCSANOW};
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde_derive::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::fs::{create_dir, File};
+use std::fs::{create_dir, remove_file, rename, File};
 use std::io::ErrorKind;
-use std::io::{self, BufReader, BufWriter};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::io::FromRawFd;
 use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use webauthn_authenticator_rs::{u2fhid::U2FHid, RequestChallengeResponse, WebauthnAuthenticator};
 
+// The exit code of a non-interactive login when the server requires a credential
+// that wasn't given, so that a script can tell it apart from a failed login.
+const EXIT_CREDENTIAL_REQUIRED: i32 = 2;
+
+// Why an authentication step failed.
+enum StepError {
+    Client(ClientError),
+    // No TOTP could be read within the allowed attempts. This has already been
+    // reported, and nothing was sent to the server.
+    TotpRetriesExhausted,
+}
+
+impl From<ClientError> for StepError {
+    fn from(e: ClientError) -> Self {
+        StepError::Client(e)
+    }
+}
+
 static TOKEN_DIR: &str = "~/.cache";
 static TOKEN_PATH: &str = "~/.cache/kanidm_tokens";
 
-pub fn read_tokens() -> Result<BTreeMap<String, String>, ()> {
+// When set, the token store is encrypted with a key derived from this passphrase.
+static TOKEN_KEY_ENV: &str = "KANIDM_TOKEN_KEY";
+// An encrypted token store starts with this, followed by the salt, nonce and tag,
+// then the encrypted json. Json can't start with this, so a plaintext store from
+// an older version (or without a key) is still read.
+const TOKEN_STORE_MAGIC: &[u8] = b"KANIDM_TOKENS_AEAD_V1\n";
+const TOKEN_STORE_SALT_LEN: usize = 16;
+const TOKEN_STORE_NONCE_LEN: usize = 12;
+const TOKEN_STORE_TAG_LEN: usize = 16;
+const TOKEN_STORE_KDF_ROUNDS: usize = 100_000;
+
+/// How the token store is written. Both are json, so either can be read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenFormat {
+    Compact,
+    Pretty,
+}
+
+impl TokenFormat {
+    /// The format named by --token-format, which is limited to the known formats.
+    pub fn from_opt(s: &str) -> Self {
+        if s == "compact" {
+            TokenFormat::Compact
+        } else {
+            TokenFormat::Pretty
+        }
+    }
+}
+
+/// A session in the token store, with what was known about it when it was stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub token: String,
+    /// Given with --label at login, to tell sessions apart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// When the session was stored, in seconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// The origin of the server the session was created with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+}
+
+impl StoredSession {
+    pub fn new(token: String) -> Self {
+        StoredSession {
+            token,
+            label: None,
+            created_at: None,
+            server: None,
+        }
+    }
+}
+
+// Older versions stored only the token for each session. These are read as a
+// session without metadata, and written back in the current form.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredValue {
+    Token(String),
+    Session(StoredSession),
+}
+
+impl From<StoredValue> for StoredSession {
+    fn from(v: StoredValue) -> Self {
+        match v {
+            StoredValue::Token(token) => StoredSession::new(token),
+            StoredValue::Session(s) => s,
+        }
+    }
+}
+
+fn token_store_key() -> Option<String> {
+    std::env::var(TOKEN_KEY_ENV).ok().filter(|k| !k.is_empty())
+}
+
+fn token_store_derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32], ()> {
+    let mut key = [0; 32];
+    pbkdf2_hmac(
+        passphrase.as_bytes(),
+        salt,
+        TOKEN_STORE_KDF_ROUNDS,
+        MessageDigest::sha256(),
+        &mut key,
+    )
+    .map(|_| key)
+    .map_err(|e| {
+        error!("Unable to derive the token store key -> {:?}", e);
+    })
+}
+
+fn token_store_encrypt(passphrase: &str, plaintext: &[u8]) -> Result<Vec<u8>, ()> {
+    let mut salt = [0; TOKEN_STORE_SALT_LEN];
+    let mut nonce = [0; TOKEN_STORE_NONCE_LEN];
+    rand_bytes(&mut salt)
+        .and_then(|_| rand_bytes(&mut nonce))
+        .map_err(|e| {
+            error!("Unable to generate the token store nonce -> {:?}", e);
+        })?;
+    let key = token_store_derive_key(passphrase, &salt)?;
+
+    let mut tag = [0; TOKEN_STORE_TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(&nonce),
+        TOKEN_STORE_MAGIC,
+        plaintext,
+        &mut tag,
+    )
+    .map_err(|e| {
+        error!("Unable to encrypt the token store -> {:?}", e);
+    })?;
+
+    Ok([
+        TOKEN_STORE_MAGIC,
+        &salt,
+        &nonce,
+        &tag,
+        ciphertext.as_slice(),
+    ]
+    .concat())
+}
+
+fn token_store_decrypt(passphrase: &str, data: &[u8]) -> Result<Vec<u8>, ()> {
+    let data = &data[TOKEN_STORE_MAGIC.len()..];
+    if data.len() < TOKEN_STORE_SALT_LEN + TOKEN_STORE_NONCE_LEN + TOKEN_STORE_TAG_LEN {
+        error!("The encrypted token store {} is truncated", TOKEN_PATH);
+        return Err(());
+    }
+    let (salt, data) = data.split_at(TOKEN_STORE_SALT_LEN);
+    let (nonce, data) = data.split_at(TOKEN_STORE_NONCE_LEN);
+    let (tag, ciphertext) = data.split_at(TOKEN_STORE_TAG_LEN);
+    let key = token_store_derive_key(passphrase, salt)?;
+
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(nonce),
+        TOKEN_STORE_MAGIC,
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| {
+        error!(
+            "Unable to decrypt the token store {}, is {} correct?",
+            TOKEN_PATH, TOKEN_KEY_ENV
+        );
+    })
+}
+
+/// The key of a session in the token store. Sessions are keyed by the origin of
+/// the server as well as the username, so that logging in with the same name to
+/// another server doesn't replace the session.
+pub fn token_key(origin: &str, username: &str) -> String {
+    format!("{}#{}", origin, username)
+}
+
+/// The origin and username of a key in the token store, or None if it was stored
+/// by an older version, see `split_legacy_token_key`.
+pub fn split_token_key(key: &str) -> Option<(&str, &str)> {
+    // An origin can't contain '#', so the first one ends it.
+    let i = key.find('#')?;
+    let origin = &key[..i];
+    if origin.contains("://") {
+        Some((origin, &key[i + 1..]))
+    } else {
+        None
+    }
+}
+
+/// The profile and username of a key stored by an older version, which keyed
+/// sessions by "profile/username", or the username alone without a profile.
+pub fn split_legacy_token_key(key: &str) -> (Option<&str>, &str) {
+    match key.find('/') {
+        Some(i) => (Some(&key[..i]), &key[i + 1..]),
+        None => (None, key),
+    }
+}
+
+// Move the sessions stored by older versions to the key of their server. Those
+// stored before the server was recorded keep their key, and are found by their
+// profile instead. When both exist, the session under the new key is newer.
+fn migrate_token_keys(tokens: BTreeMap<String, StoredSession>) -> BTreeMap<String, StoredSession> {
+    let (mut migrated, legacy): (BTreeMap<_, _>, Vec<_>) = tokens
+        .into_iter()
+        .partition(|(k, _)| split_token_key(k).is_some());
+    for (k, session) in legacy {
+        let key = match &session.server {
+            Some(origin) => token_key(origin, split_legacy_token_key(&k).1),
+            None => k,
+        };
+        migrated.entry(key).or_insert(session);
+    }
+    migrated
+}
+
+// Read the token store as it is on disk, or None if there isn't one to read.
+fn read_token_store() -> Result<Option<Vec<u8>>, ()> {
     let token_path = PathBuf::from(shellexpand::tilde(TOKEN_PATH).into_owned());
     if !token_path.exists() {
         debug!(
             "Token cache file path {:?} does not exist, returning an empty token store.",
             TOKEN_PATH
         );
-        return Ok(BTreeMap::new());
+        return Ok(None);
     }
 
     debug!("Attempting to read tokens from {:?}", &token_path);
     // If the file does not exist, return Ok<map>
-    let file = match File::open(&token_path) {
+    let mut file = match File::open(&token_path) {
         Ok(f) => f,
         Err(e) => {
             match e.kind() {
@@ -42,25 +268,66 @@ pub fn read_tokens() -> Result<BTreeMap<String, String>, ()> {
                         "Cannot read tokens from {} due to error: {:?} ... continuing.",
                         TOKEN_PATH, e
                     );
-                    return Ok(BTreeMap::new());
+                    return Ok(None);
                 }
             };
         }
     };
-    let reader = BufReader::new(file);
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).map_err(|e| {
+        error!("IO error reading tokens from {:?} -> {:?}", &token_path, e);
+    })?;
+    Ok(Some(data))
+}
 
-    // Else try to read
-    serde_json::from_reader(reader).map_err(|e| {
+// The json of the token store, decrypting it with the passphrase if it's encrypted.
+fn token_store_open(passphrase: Option<&str>, data: Vec<u8>) -> Result<Vec<u8>, ()> {
+    if !data.starts_with(TOKEN_STORE_MAGIC) {
+        return Ok(data);
+    }
+    let passphrase = passphrase.ok_or_else(|| {
         error!(
-            "JSON/IO error reading tokens from {:?} -> {:?}",
-            &token_path, e
+            "The token store {} is encrypted, set {} to read it",
+            TOKEN_PATH, TOKEN_KEY_ENV
         );
-    })
+    })?;
+    token_store_decrypt(passphrase, &data)
+}
+
+// Decrypt the token store with the old passphrase, or read it as plaintext if it
+// isn't encrypted, and encrypt it with the new one. The sessions are not parsed,
+// so they're kept exactly as they were.
+fn token_store_rekey(old: Option<&str>, new: &str, data: Vec<u8>) -> Result<Vec<u8>, ()> {
+    let plaintext = token_store_open(old, data)?;
+    token_store_encrypt(new, &plaintext)
+}
+
+pub fn read_tokens() -> Result<BTreeMap<String, StoredSession>, ()> {
+    let data = match read_token_store()? {
+        Some(data) => token_store_open(token_store_key().as_deref(), data)?,
+        None => return Ok(BTreeMap::new()),
+    };
+
+    // Else try to read. Whitespace is insignificant in json, so this reads both
+    // the compact and pretty formats.
+    serde_json::from_slice(&data)
+        .map(|tokens: BTreeMap<String, StoredValue>| {
+            migrate_token_keys(tokens.into_iter().map(|(k, v)| (k, v.into())).collect())
+        })
+        .map_err(|e| {
+            error!(
+                "JSON/IO error reading tokens from {:?} -> {:?}",
+                TOKEN_PATH, e
+            );
+        })
 }
 
-pub fn write_tokens(tokens: &BTreeMap<String, String>) -> Result<(), ()> {
+// Replace the token store. The data is written to a temporary file that is then
+// renamed over the store, so an interrupted write never leaves it half written.
+fn write_token_store(data: &[u8]) -> Result<(), ()> {
     let token_dir = PathBuf::from(shellexpand::tilde(TOKEN_DIR).into_owned());
     let token_path = PathBuf::from(shellexpand::tilde(TOKEN_PATH).into_owned());
+    let tmp_path = token_path.with_extension("tmp");
 
     token_dir
         .parent()
@@ -88,20 +355,58 @@ pub fn write_tokens(tokens: &BTreeMap<String, String>) -> Result<(), ()> {
     // Take away group/everyone read/write
     let before = unsafe { umask(0o177) };
 
-    let file = File::create(&token_path).map_err(|e| {
+    let file = File::create(&tmp_path).map_err(|e| {
         let _ = unsafe { umask(before) };
-        error!("Can not write to {} -> {:?}", TOKEN_PATH, e);
+        error!("Can not write to {:?} -> {:?}", &tmp_path, e);
     })?;
 
     let _ = unsafe { umask(before) };
 
-    let writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(writer, tokens).map_err(|e| {
-        error!(
-            "JSON/IO error writing tokens to file {:?} -> {:?}",
-            &token_path, e
-        );
-    })
+    let mut writer = BufWriter::new(file);
+    writer
+        .write_all(data)
+        .and_then(|_| writer.flush())
+        .and_then(|_| writer.get_ref().sync_all())
+        .and_then(|_| rename(&tmp_path, &token_path))
+        .map_err(|e| {
+            let _ = remove_file(&tmp_path);
+            error!(
+                "IO error writing tokens to file {:?} -> {:?}",
+                &token_path, e
+            );
+        })
+}
+
+pub fn write_tokens(
+    tokens: &BTreeMap<String, StoredSession>,
+    format: TokenFormat,
+) -> Result<(), ()> {
+    let data = match format {
+        TokenFormat::Compact => serde_json::to_vec(tokens),
+        TokenFormat::Pretty => serde_json::to_vec_pretty(tokens),
+    }
+    .map_err(|e| {
+        error!("JSON error serialising tokens -> {:?}", e);
+    })?;
+    let data = match token_store_key() {
+        Some(passphrase) => token_store_encrypt(&passphrase, &data)?,
+        None => data,
+    };
+    write_token_store(&data)
+}
+
+/// Encrypt the token store with a new passphrase, keeping its sessions. The
+/// current passphrase is read from KANIDM_TOKEN_KEY, and a plaintext store is
+/// encrypted. The store is unchanged if it can't be decrypted.
+pub fn rekey_tokens(new_passphrase: &str) -> Result<(), ()> {
+    let data = read_token_store()?.ok_or_else(|| {
+        error!("There is no token store at {} to rekey", TOKEN_PATH);
+    })?;
+    let data =
+        token_store_rekey(token_store_key().as_deref(), new_passphrase, data).map_err(|_| {
+            error!("The token store {} was not changed", TOKEN_PATH);
+        })?;
+    write_token_store(&data)
 }
 
 fn get_index_choice(len: usize) -> Result<u8, ClientError> {
@@ -125,13 +430,340 @@ fn get_index_choice(len: usize) -> Result<u8, ClientError> {
     }
 }
 
+// Run a blocking interaction with the user on another thread, so that we can give
+// up if they walk away. A timeout of 0 waits forever. On timeout we exit, so the
+// abandoned thread doesn't matter, but the terminal settings it may have changed
+// (such as disabling echo for a password) must be restored first.
+fn with_timeout<T, F>(timeout_secs: u64, what: &str, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    if timeout_secs == 0 {
+        return f();
+    }
+
+    let mut term: termios = unsafe { std::mem::zeroed() };
+    let have_term = unsafe { tcgetattr(STDIN_FILENO, &mut term) } == 0;
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        // The receiver is gone if we timed out, so there is no one to tell.
+        let _ = tx.send(f());
+    });
+
+    match rx.recv_timeout(Duration::from_secs(timeout_secs)) {
+        Ok(v) => v,
+        Err(_) => {
+            if have_term {
+                unsafe { tcsetattr(STDIN_FILENO, TCSANOW, &term) };
+            }
+            eprintln!();
+            error!(
+                "Timed out after {} seconds waiting for {}",
+                timeout_secs, what
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+// The HID usage page of FIDO authenticators, as it appears in a report descriptor
+// (a two byte Usage Page item, little endian).
+#[cfg(target_os = "linux")]
+const FIDO_USAGE_PAGE: [u8; 3] = [0x06, 0xd0, 0xf1];
+
+// Check for a connected FIDO authenticator, so we can ask for one to be connected
+// rather than waiting for a device that isn't there. On platforms where we can't
+// tell, assume one is present and let the authenticator library handle it.
+#[cfg(target_os = "linux")]
+fn authenticator_present() -> bool {
+    let dir = match std::fs::read_dir("/sys/class/hidraw") {
+        Ok(d) => d,
+        Err(e) => {
+            debug!(
+                "Unable to list hidraw devices, assuming an authenticator is present -> {:?}",
+                e
+            );
+            return true;
+        }
+    };
+    dir.filter_map(|e| e.ok()).any(|e| {
+        std::fs::read(e.path().join("device/report_descriptor"))
+            .map(|desc| {
+                desc.windows(FIDO_USAGE_PAGE.len())
+                    .any(|w| w == FIDO_USAGE_PAGE)
+            })
+            .unwrap_or(false)
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn authenticator_present() -> bool {
+    true
+}
+
+/// Progress of a login, for programs (such as a gui) that drive the cli and render
+/// their own prompts.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum LoginEvent<'a> {
+    MechanismSelected {
+        mech: &'a str,
+    },
+    /// A response is needed on stdin. When there are choices, the response is the
+    /// index of one of them.
+    PromptNeeded {
+        prompt: &'a str,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        choices: Vec<&'a str>,
+    },
+    StepCompleted {
+        next: Vec<&'a str>,
+    },
+    Success {
+        username: &'a str,
+        stored: bool,
+    },
+    Denied {
+        reason: &'a str,
+    },
+}
+
+// Events use stable names rather than the display text shown to people.
+fn mech_name(mech: &AuthMech) -> &'static str {
+    match mech {
+        AuthMech::Anonymous => "anonymous",
+        AuthMech::Password => "password",
+        AuthMech::PasswordMfa => "passwordmfa",
+        AuthMech::Webauthn => "webauthn",
+    }
+}
+
+// Turn a list of mechanism names into mechanisms, ignoring (with a warning) any
+// that we don't know, so that a preference written for a newer version still works.
+fn parse_mech_preference(names: &[String]) -> Vec<AuthMech> {
+    const ALL_MECHS: [AuthMech; 4] = [
+        AuthMech::Anonymous,
+        AuthMech::Password,
+        AuthMech::PasswordMfa,
+        AuthMech::Webauthn,
+    ];
+    names
+        .iter()
+        .filter_map(|name| {
+            let name = name.trim().to_lowercase();
+            let mech = ALL_MECHS.iter().find(|m| mech_name(m) == name).cloned();
+            if mech.is_none() {
+                warn!(
+                    "Ignoring unknown authentication mechanism preference {}",
+                    name
+                );
+            }
+            mech
+        })
+        .collect()
+}
+
+// The most preferred mechanism that was offered, if any.
+fn preferred_mech<'a>(prefer: &[AuthMech], offered: &'a [AuthMech]) -> Option<&'a AuthMech> {
+    prefer.iter().find_map(|p| offered.iter().find(|m| *m == p))
+}
+
+fn allowed_name(allowed: &AuthAllowed) -> &'static str {
+    match allowed {
+        AuthAllowed::Anonymous => "anonymous",
+        AuthAllowed::Password => "password",
+        AuthAllowed::Totp => "totp",
+        AuthAllowed::Webauthn(_) => "webauthn",
+    }
+}
+
+struct LoginEvents {
+    out: Option<File>,
+}
+
+impl LoginEvents {
+    fn new(fd: Option<i32>) -> Self {
+        let out = fd.map(|fd| {
+            if unsafe { fcntl(fd, F_GETFD) } == -1 {
+                error!("--events-fd {} is not an open file descriptor", fd);
+                std::process::exit(1);
+            }
+            unsafe { File::from_raw_fd(fd) }
+        });
+        LoginEvents { out }
+    }
+
+    fn send(&mut self, event: &LoginEvent) {
+        if let Some(out) = self.out.as_mut() {
+            let res = serde_json::to_string(event)
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|line| {
+                    writeln!(out, "{}", line)
+                        .and_then(|_| out.flush())
+                        .map_err(|e| format!("{:?}", e))
+                });
+            if let Err(e) = res {
+                error!("Failed to write login event -> {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// The credentials given on stdin with --credential-pipe, so that they can come
+/// from a secret manager rather than the command line.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CredentialDocument {
+    username: String,
+    password: String,
+    // A string, as a number would lose any leading zeros.
+    #[serde(default)]
+    totp: Option<String>,
+}
+
+struct PipedCredentials {
+    username: String,
+    password: String,
+    totp: Option<u32>,
+}
+
+/// The credentials of a login that doesn't prompt, from the credential document or
+/// the environment.
+struct SuppliedCredentials {
+    password: Option<String>,
+    totp: Option<u32>,
+}
+
+impl From<&PipedCredentials> for SuppliedCredentials {
+    fn from(c: &PipedCredentials) -> Self {
+        SuppliedCredentials {
+            password: Some(c.password.clone()),
+            totp: c.totp,
+        }
+    }
+}
+
+// A credential from the environment variable named with --password-from-env or
+// --totp-from-env. A missing one is a credential that wasn't given.
+fn read_env_credential(var: &str) -> String {
+    match std::env::var(var) {
+        Ok(v) if !v.is_empty() => v,
+        _ => {
+            error!("The environment variable {} is not set", var);
+            std::process::exit(EXIT_CREDENTIAL_REQUIRED);
+        }
+    }
+}
+
+fn read_credential_document<R: Read>(r: R) -> Result<PipedCredentials, String> {
+    let doc: CredentialDocument =
+        serde_json::from_reader(r).map_err(|e| format!("Invalid credential document -> {}", e))?;
+    if doc.username.trim().is_empty() {
+        return Err("The credential document must have a username".to_string());
+    }
+    if doc.password.is_empty() {
+        return Err("The credential document must have a password".to_string());
+    }
+    let totp = doc
+        .totp
+        .as_deref()
+        .map(totp_parse)
+        .transpose()
+        .map_err(|e| format!("Invalid totp in the credential document -> {}", e))?;
+    Ok(PipedCredentials {
+        username: doc.username,
+        password: doc.password,
+        totp,
+    })
+}
+
+fn prompt_remember_session() -> Result<bool, ClientError> {
+    eprint!("Remember this session? [y/N] ");
+    let mut buffer = String::new();
+    if let Err(e) = io::stdin().read_line(&mut buffer) {
+        eprintln!("Failed to read from stdin -> {:?}", e);
+        return Err(ClientError::SystemError);
+    };
+    let response = buffer.trim().to_lowercase();
+    Ok(response == "y" || response == "yes")
+}
+
 impl LoginOpt {
     pub fn debug(&self) -> bool {
         self.copt.debug
     }
 
-    fn do_password(&self, client: &mut KanidmClient) -> Result<AuthResponse, ClientError> {
-        let password = match rpassword::prompt_password_stderr("Enter password: ") {
+    // When progress is sent as events, whatever is driving us renders the prompts.
+    fn quiet(&self) -> bool {
+        self.events_fd.is_some()
+    }
+
+    fn non_interactive(&self) -> bool {
+        self.non_interactive
+            || self.credential_pipe
+            || self.password_from_env.is_some()
+            || self.totp_from_env.is_some()
+    }
+
+    // The credentials from the environment, when the login is non-interactive.
+    fn env_credentials(&self) -> Option<SuppliedCredentials> {
+        if !self.non_interactive() {
+            return None;
+        }
+        // A TOTP is only ever asked for after a password, so without one the
+        // login would silently fall back to anonymous.
+        if self.totp_from_env.is_some() && self.password_from_env.is_none() {
+            error!("--totp-from-env requires --password-from-env");
+            std::process::exit(EXIT_CREDENTIAL_REQUIRED);
+        }
+        let password = self.password_from_env.as_deref().map(read_env_credential);
+        let totp = self.totp_from_env.as_deref().map(|var| {
+            totp_parse(&read_env_credential(var)).unwrap_or_else(|e| {
+                error!("Invalid TOTP in {} -> {}", var, e);
+                std::process::exit(1);
+            })
+        });
+        Some(SuppliedCredentials { password, totp })
+    }
+
+    fn should_store_token(&self) -> bool {
+        if self.no_cache {
+            return false;
+        }
+        // Only ask when a person is at the terminal - scripts keep the
+        // existing behaviour of always storing the token.
+        let interactive = unsafe { isatty(STDIN_FILENO) } == 1;
+        if self.remember || !interactive || self.quiet() || self.non_interactive() {
+            return true;
+        }
+        match prompt_remember_session() {
+            Ok(b) => b,
+            Err(e) => {
+                error!("Error getting session choice -> {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn do_password(
+        &self,
+        client: &mut KanidmClient,
+        supplied: Option<&str>,
+    ) -> Result<AuthResponse, ClientError> {
+        if let Some(password) = supplied {
+            return client.auth_step_password(password);
+        }
+        let quiet = self.quiet();
+        let password = match with_timeout(self.password_timeout, "a password", move || {
+            if quiet {
+                rpassword::read_password()
+            } else {
+                rpassword::prompt_password_stderr("Enter password: ")
+            }
+        }) {
             Ok(p) => p,
             Err(e) => {
                 error!("Failed to create password prompt -- {:?}", e);
@@ -141,32 +773,129 @@ impl LoginOpt {
         client.auth_step_password(password.as_str())
     }
 
-    fn do_totp(&self, client: &mut KanidmClient) -> Result<AuthResponse, ClientError> {
+    fn do_totp(
+        &self,
+        client: &mut KanidmClient,
+        supplied: Option<u32>,
+    ) -> Result<AuthResponse, StepError> {
+        if let Some(totp) = supplied {
+            return client.auth_step_totp(totp).map_err(StepError::from);
+        }
+        // A mistyped TOTP is caught here rather than sent to the server, which
+        // would count it as a failed authentication.
+        let mut malformed = 0;
         let totp = loop {
-            println!("Enter TOTP: ");
-            let mut buffer = String::new();
-            if let Err(e) = io::stdin().read_line(&mut buffer) {
-                eprintln!("Failed to read from stdin -> {:?}", e);
-                return Err(ClientError::SystemError);
+            if !self.quiet() {
+                eprintln!("Enter TOTP: ");
+            }
+            let read = with_timeout(self.totp_timeout, "a TOTP", || {
+                let mut buffer = String::new();
+                io::stdin().read_line(&mut buffer).map(|n| (n, buffer))
+            });
+            let buffer = match read {
+                Ok((0, _)) => {
+                    eprintln!("Failed to read a TOTP, stdin was closed");
+                    return Err(ClientError::SystemError.into());
+                }
+                Ok((_, b)) => b,
+                Err(e) => {
+                    eprintln!("Failed to read from stdin -> {:?}", e);
+                    return Err(ClientError::SystemError.into());
+                }
             };
 
-            let response = buffer.trim();
-            match u32::from_str_radix(response, 10) {
+            match totp_parse(&buffer) {
                 Ok(i) => break i,
-                Err(_) => eprintln!("Invalid Number"),
+                Err(e) => {
+                    malformed += 1;
+                    if malformed >= self.totp_retries {
+                        eprintln!(
+                            "Couldn't read your TOTP -> {}. Giving up after {} attempts, nothing was sent to the server.",
+                            e, malformed
+                        );
+                        return Err(StepError::TotpRetriesExhausted);
+                    }
+                    eprintln!(
+                        "Couldn't read your TOTP -> {}. Nothing was sent to the server, {} attempts left.",
+                        e,
+                        self.totp_retries - malformed
+                    );
+                }
             };
         };
-        client.auth_step_totp(totp)
+
+        let res = client.auth_step_totp(totp)?;
+        // The session ends with a denial, so it's up to the caller to report it
+        // and decide what to do. This only makes clear the code was read, but wrong.
+        if let AuthState::Denied(reason) = &res.state {
+            if !self.quiet() {
+                eprintln!("The server rejected the TOTP -> {}", reason);
+            }
+        }
+        Ok(res)
+    }
+
+    // Give the user a chance to connect their security key, rather than failing
+    // while the authenticator library waits for a device that isn't there.
+    fn wait_for_authenticator(&self, events: &mut LoginEvents) {
+        let mut retries = 0;
+        while !authenticator_present() {
+            if retries >= self.webauthn_retries {
+                error!("No security key was found. Connect your security key, or choose another credential.");
+                std::process::exit(1);
+            }
+            retries += 1;
+
+            if self.quiet() {
+                events.send(&LoginEvent::PromptNeeded {
+                    prompt: "connect_authenticator",
+                    choices: Vec::new(),
+                });
+            } else {
+                eprintln!(
+                    "No security key was found. Please connect your security key and press enter."
+                );
+            }
+            let read = with_timeout(self.webauthn_timeout, "a security key", || {
+                let mut buffer = String::new();
+                io::stdin().read_line(&mut buffer)
+            });
+            match read {
+                Ok(0) => {
+                    error!("No security key was found, and stdin was closed");
+                    std::process::exit(1);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Failed to read from stdin -> {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+
+            // A key that was just connected can take a moment to be found.
+            let mut waited = 0;
+            while waited < self.webauthn_retry_delay && !authenticator_present() {
+                thread::sleep(Duration::from_secs(1));
+                waited += 1;
+            }
+        }
     }
 
     fn do_webauthn(
         &self,
         client: &mut KanidmClient,
+        events: &mut LoginEvents,
         pkr: RequestChallengeResponse,
     ) -> Result<AuthResponse, ClientError> {
-        let mut wa = WebauthnAuthenticator::new(U2FHid::new());
-        println!("Your authenticator will now flash for you to interact with it.");
-        let auth = match wa.do_authentication(client.get_origin(), pkr) {
+        self.wait_for_authenticator(events);
+        if !self.quiet() {
+            eprintln!("Your authenticator will now flash for you to interact with it.");
+        }
+        let origin = client.get_origin().to_owned();
+        let auth = match with_timeout(self.webauthn_timeout, "the authenticator", move || {
+            let mut wa = WebauthnAuthenticator::new(U2FHid::new());
+            wa.do_authentication(origin.as_str(), pkr)
+        }) {
             Ok(a) => a,
             Err(e) => {
                 error!("Failed to interact with webauthn device. -- {:?}", e);
@@ -178,20 +907,85 @@ impl LoginOpt {
     }
 
     pub fn exec(&self) {
+        let mut events = LoginEvents::new(self.events_fd);
         let mut client = self.copt.to_unauth_client();
 
-        let username = self.copt.username.as_deref().unwrap_or("anonymous");
+        let piped = if self.credential_pipe {
+            match read_credential_document(io::stdin()) {
+                Ok(c) => Some(c),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            None
+        };
+
+        let supplied = match &piped {
+            Some(creds) => Some(SuppliedCredentials::from(creds)),
+            None => self.env_credentials(),
+        };
+
+        let username = match &piped {
+            Some(creds) => creds.username.clone(),
+            None => self
+                .copt
+                .resolve_username()
+                .unwrap_or_else(|| "anonymous".to_string()),
+        };
+        let username = username.as_str();
 
         // What auth mechanisms exist?
         let mechs: Vec<_> = match client.auth_step_init(username) {
             Ok(s) => s.into_iter().collect(),
+            // The server may deny an unknown account here, without saying why.
+            Err(ClientError::AuthenticationFailed) => {
+                events.send(&LoginEvent::Denied {
+                    reason: "authentication denied",
+                });
+                error!("Authentication Denied");
+                std::process::exit(1);
+            }
             Err(e) => {
                 error!("Error during authentication init phase: {:?}", e);
                 std::process::exit(1);
             }
         };
 
+        // The command line preference replaces the configured one. With supplied
+        // credentials, only the mechanisms they can satisfy may be chosen.
+        let prefer = if let Some(name) = &self.mech {
+            parse_mech_preference(std::slice::from_ref(name))
+        } else if let Some(creds) = &supplied {
+            match (&creds.password, creds.totp) {
+                (Some(_), Some(_)) => vec![AuthMech::PasswordMfa, AuthMech::Password],
+                (Some(_), None) => vec![AuthMech::Password],
+                (None, None) => vec![AuthMech::Anonymous],
+                (None, Some(_)) => {
+                    error!("A TOTP was given without a password");
+                    std::process::exit(EXIT_CREDENTIAL_REQUIRED);
+                }
+            }
+        } else if self.prefer.is_empty() {
+            parse_mech_preference(&self.copt.login_prefer())
+        } else {
+            parse_mech_preference(&self.prefer)
+        };
+
         let mech = match mechs.len() {
+            _ if self.mech.is_some() => match preferred_mech(&prefer, &mechs) {
+                Some(mech) => mech,
+                None => {
+                    error!(
+                        "The server doesn't offer {} for {}, only {}",
+                        self.mech.as_deref().unwrap_or_default(),
+                        username,
+                        mechs.iter().map(mech_name).collect::<Vec<_>>().join(", ")
+                    );
+                    std::process::exit(1);
+                }
+            },
             0 => {
                 error!("Error during authentication init phase: Server offered no authentication mechanisms");
                 std::process::exit(1);
@@ -203,25 +997,49 @@ impl LoginOpt {
                     .get(0)
                     .expect("can not fail - bounds already checked.")
             }
-            len => {
-                println!("Please choose how you want to authenticate:");
-                for (i, val) in mechs.iter().enumerate() {
-                    println!("{}: {}", i, val)
+            len => match preferred_mech(&prefer, &mechs) {
+                Some(mech) => {
+                    debug!("Choosing {} from the mechanism preference", mech_name(mech));
+                    mech
                 }
-                let mech_idx = match get_index_choice(len) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        error!("Error getting index choice -> {:?}", e);
-                        std::process::exit(1);
+                None if supplied.is_some() => {
+                    error!(
+                        "None of the offered authentication mechanisms ({}) can be used with the credentials given",
+                        mechs.iter().map(mech_name).collect::<Vec<_>>().join(", ")
+                    );
+                    std::process::exit(EXIT_CREDENTIAL_REQUIRED);
+                }
+                None => {
+                    if self.quiet() {
+                        events.send(&LoginEvent::PromptNeeded {
+                            prompt: "mechanism",
+                            choices: mechs.iter().map(mech_name).collect(),
+                        });
+                    } else {
+                        eprintln!("Please choose how you want to authenticate:");
+                        for (i, val) in mechs.iter().enumerate() {
+                            eprintln!("{}: {}", i, val)
+                        }
                     }
-                };
-                #[allow(clippy::expect_used)]
-                mechs
-                    .get(mech_idx as usize)
-                    .expect("can not fail - bounds already checked.")
-            }
+                    let mech_idx = match get_index_choice(len) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            error!("Error getting index choice -> {:?}", e);
+                            std::process::exit(1);
+                        }
+                    };
+                    #[allow(clippy::expect_used)]
+                    mechs
+                        .get(mech_idx as usize)
+                        .expect("can not fail - bounds already checked.")
+                }
+            },
         };
 
+        events.send(&LoginEvent::MechanismSelected {
+            mech: mech_name(mech),
+        });
+
         let mut allowed = match client.auth_step_begin((*mech).clone()) {
             Ok(s) => s,
             Err(e) => {
@@ -234,50 +1052,98 @@ impl LoginOpt {
         loop {
             debug!("Allowed mechanisms -> {:?}", allowed);
             // What auth can proceed?
-            let choice = match allowed.len() {
-                0 => {
-                    error!(
+            let choice = if let Some(creds) = &supplied {
+                let usable = allowed.iter().find(|a| match a {
+                    AuthAllowed::Anonymous => true,
+                    AuthAllowed::Password => creds.password.is_some(),
+                    AuthAllowed::Totp => creds.totp.is_some(),
+                    _ => false,
+                });
+                match usable {
+                    Some(a) => a,
+                    None => {
+                        error!(
+                            "The server requires {}, which was not given",
+                            allowed
+                                .iter()
+                                .map(allowed_name)
+                                .collect::<Vec<_>>()
+                                .join(" or ")
+                        );
+                        std::process::exit(EXIT_CREDENTIAL_REQUIRED);
+                    }
+                }
+            } else {
+                match allowed.len() {
+                    0 => {
+                        error!(
                         "Error during authentication phase: Server offered no method to proceed"
                     );
-                    std::process::exit(1);
-                }
-                1 =>
-                {
-                    #[allow(clippy::expect_used)]
-                    allowed
-                        .get(0)
-                        .expect("can not fail - bounds already checked.")
-                }
-                len => {
-                    println!("Please choose what credential to provide:");
-                    for (i, val) in allowed.iter().enumerate() {
-                        println!("{}: {}", i, val)
+                        std::process::exit(1);
                     }
-                    let idx = match get_index_choice(len) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            error!("Error getting index choice -> {:?}", e);
-                            std::process::exit(1);
+                    1 =>
+                    {
+                        #[allow(clippy::expect_used)]
+                        allowed
+                            .get(0)
+                            .expect("can not fail - bounds already checked.")
+                    }
+                    len => {
+                        if self.quiet() {
+                            events.send(&LoginEvent::PromptNeeded {
+                                prompt: "credential",
+                                choices: allowed.iter().map(allowed_name).collect(),
+                            });
+                        } else {
+                            eprintln!("Please choose what credential to provide:");
+                            for (i, val) in allowed.iter().enumerate() {
+                                eprintln!("{}: {}", i, val)
+                            }
                         }
-                    };
-                    #[allow(clippy::expect_used)]
-                    allowed
-                        .get(idx as usize)
-                        .expect("can not fail - bounds already checked.")
+                        let idx = match get_index_choice(len) {
+                            Ok(v) => v,
+                            Err(e) => {
+                                error!("Error getting index choice -> {:?}", e);
+                                std::process::exit(1);
+                            }
+                        };
+                        #[allow(clippy::expect_used)]
+                        allowed
+                            .get(idx as usize)
+                            .expect("can not fail - bounds already checked.")
+                    }
                 }
             };
 
+            if !matches!(choice, AuthAllowed::Anonymous) {
+                events.send(&LoginEvent::PromptNeeded {
+                    prompt: allowed_name(choice),
+                    choices: Vec::new(),
+                });
+            }
+
             let res = match choice {
-                AuthAllowed::Anonymous => client.auth_step_anonymous(),
-                AuthAllowed::Password => self.do_password(&mut client),
-                AuthAllowed::Totp => self.do_totp(&mut client),
-                AuthAllowed::Webauthn(chal) => self.do_webauthn(&mut client, chal.clone()),
+                AuthAllowed::Anonymous => client.auth_step_anonymous().map_err(StepError::from),
+                AuthAllowed::Password => self
+                    .do_password(
+                        &mut client,
+                        supplied.as_ref().and_then(|c| c.password.as_deref()),
+                    )
+                    .map_err(StepError::from),
+                AuthAllowed::Totp => {
+                    self.do_totp(&mut client, supplied.as_ref().and_then(|c| c.totp))
+                }
+                AuthAllowed::Webauthn(chal) => self
+                    .do_webauthn(&mut client, &mut events, chal.clone())
+                    .map_err(StepError::from),
             };
 
             // Now update state.
             let state = match res {
                 Ok(s) => s.state,
-                Err(e) => {
+                // The step has already said what was wrong with the input.
+                Err(StepError::TotpRetriesExhausted) => std::process::exit(1),
+                Err(StepError::Client(e)) => {
                     error!("Error in authentication phase: {:?}", e);
                     std::process::exit(1);
                 }
@@ -285,10 +1151,18 @@ impl LoginOpt {
 
             // What auth state are we in?
             allowed = match &state {
-                AuthState::Continue(allowed) => allowed.to_vec(),
+                AuthState::Continue(allowed) => {
+                    events.send(&LoginEvent::StepCompleted {
+                        next: allowed.iter().map(allowed_name).collect(),
+                    });
+                    allowed.to_vec()
+                }
                 AuthState::Success(_token) => break,
                 AuthState::Denied(reason) => {
-                    error!("Authentication Denied: {:?}", reason);
+                    events.send(&LoginEvent::Denied {
+                        reason: reason.as_str(),
+                    });
+                    error!("Authentication Denied: {}", reason);
                     std::process::exit(1);
                 }
                 _ => {
@@ -299,30 +1173,91 @@ impl LoginOpt {
             // Loop again.
         }
 
-        // Read the current tokens
-        let mut tokens = match read_tokens() {
-            Ok(t) => t,
-            Err(_e) => {
-                error!("Error retrieving authentication token store");
-                std::process::exit(1);
-            }
-        };
-        // Add our new one
-        match client.get_token() {
-            Some(t) => tokens.insert(username.to_string(), t.to_string()),
+        let token = match client.get_token() {
+            Some(t) => t,
             None => {
                 error!("Error retrieving client session");
                 std::process::exit(1);
             }
         };
 
-        // write them out.
-        if let Err(_e) = write_tokens(&tokens) {
-            error!("Error persisting authentication token store");
-            std::process::exit(1);
-        };
+        if self.export_env {
+            // This must be the only thing on stdout so that it can be eval'd.
+            println!("export KANIDM_TOKEN='{}'", token);
+        }
+
+        let stored = self.should_store_token();
+        if stored {
+            // Read the current tokens
+            let mut tokens = match read_tokens() {
+                Ok(t) => t,
+                Err(_e) => {
+                    error!("Error retrieving authentication token store");
+                    std::process::exit(1);
+                }
+            };
+            // Add our new one
+            let created_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .ok();
+            // A session from an older version, that didn't record its server,
+            // would otherwise be kept alongside this one.
+            tokens.remove(&self.copt.legacy_token_key(username));
+            tokens.insert(
+                token_key(client.get_origin(), username),
+                StoredSession {
+                    token,
+                    label: self.label.clone(),
+                    created_at,
+                    server: Some(client.get_origin().to_string()),
+                },
+            );
+
+            // write them out.
+            if let Err(_e) = write_tokens(&tokens, TokenFormat::from_opt(&self.token_format)) {
+                error!("Error persisting authentication token store");
+                std::process::exit(1);
+            };
+        }
 
         // Success!
-        println!("Login Success for {}", username);
+        events.send(&LoginEvent::Success { username, stored });
+        if self.quiet() {
+            return;
+        }
+        let msg = if stored {
+            format!("Login Success for {}", username)
+        } else {
+            format!("Login Success for {} - session not stored", username)
+        };
+        if self.export_env {
+            eprintln!("{}", msg);
+        } else {
+            println!("{}", msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{token_store_decrypt, token_store_encrypt, token_store_rekey};
+
+    #[test]
+    fn test_token_store_rekey() {
+        let json = b"{\"https://idm.example.com#admin\":{\"token\":\"abc\"}}".to_vec();
+        let data = token_store_encrypt("old", &json).expect("Unable to encrypt");
+
+        // The wrong old passphrase is refused.
+        assert!(token_store_rekey(Some("wrong"), "new", data.clone()).is_err());
+        assert!(token_store_rekey(None, "new", data.clone()).is_err());
+
+        let rekeyed = token_store_rekey(Some("old"), "new", data).expect("Unable to rekey");
+        assert!(token_store_decrypt("old", &rekeyed).is_err());
+        assert!(token_store_decrypt("new", &rekeyed).expect("Unable to decrypt") == json);
+
+        // A plaintext store is encrypted.
+        let rekeyed = token_store_rekey(None, "new", json.clone()).expect("Unable to rekey");
+        assert!(token_store_decrypt("new", &rekeyed).expect("Unable to decrypt") == json);
     }
 }
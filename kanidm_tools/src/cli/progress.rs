@@ -0,0 +1,91 @@
+// A progress indicator for cli operations that make many requests, showing the
+// percentage done and an estimate of the time remaining.
+//
+// It's only drawn when stderr is a terminal, and always to stderr, so that the
+// output of a command can still be piped or parsed. Redrawing is throttled so a
+// fast operation isn't slowed down by writing to the terminal.
+use libc::{isatty, STDERR_FILENO};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+// The minimum time between redraws.
+const PROGRESS_REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+pub struct Progress {
+    total: usize,
+    done: usize,
+    started: Instant,
+    last_draw: Option<Instant>,
+    enabled: bool,
+}
+
+impl Progress {
+    pub fn new(total: usize, quiet: bool) -> Self {
+        let enabled = !quiet && total > 0 && unsafe { isatty(STDERR_FILENO) } == 1;
+        Progress {
+            total,
+            done: 0,
+            started: Instant::now(),
+            last_draw: None,
+            enabled,
+        }
+    }
+
+    /// Record that n more items are done, redrawing if it's been long enough.
+    pub fn inc(&mut self, n: usize) {
+        self.done = (self.done + n).min(self.total);
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let due = self
+            .last_draw
+            .map(|last| now.duration_since(last) >= PROGRESS_REDRAW_INTERVAL)
+            .unwrap_or(true);
+        if due || self.done == self.total {
+            self.last_draw = Some(now);
+            eprint!("\r\x1b[K{}", self.line(now.duration_since(self.started)));
+            let _ = io::stderr().flush();
+        }
+    }
+
+    /// Remove the indicator so a line can be printed, it's drawn again on the
+    /// next inc.
+    pub fn clear(&mut self) {
+        if self.enabled && self.last_draw.is_some() {
+            eprint!("\r\x1b[K");
+            let _ = io::stderr().flush();
+            self.last_draw = None;
+        }
+    }
+
+    /// Remove the indicator once the operation is complete.
+    pub fn finish(mut self) {
+        self.clear();
+    }
+
+    fn line(&self, elapsed: Duration) -> String {
+        let percent = self.done * 100 / self.total;
+        if self.done == 0 || self.done == self.total {
+            return format!("{:3}% ({}/{})", percent, self.done, self.total);
+        }
+        let remaining = elapsed.as_secs_f64() * (self.total - self.done) as f64 / self.done as f64;
+        format!(
+            "{:3}% ({}/{}) ETA {}",
+            percent,
+            self.done,
+            self.total,
+            format_eta(remaining as u64)
+        )
+    }
+}
+
+fn format_eta(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
@@ -40,8 +40,13 @@ impl RawOpt {
                     }
                 };
 
-                match client.search(filter) {
-                    Ok(rset) => rset.iter().for_each(|e| println!("{}", e)),
+                let attrs = if sopt.attrs.is_empty() {
+                    None
+                } else {
+                    Some(sopt.attrs.clone())
+                };
+                match client.search_projected(filter, None, attrs) {
+                    Ok(r) => r.entries.iter().for_each(|e| println!("{}", e)),
                     Err(e) => {
                         eprintln!("Error -> {:?}", e);
                     }
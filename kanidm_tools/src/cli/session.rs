@@ -0,0 +1,226 @@
+use crate::login::{
+    read_tokens, rekey_tokens, split_legacy_token_key, split_token_key, write_tokens,
+    StoredSession, TokenFormat,
+};
+use crate::progress::Progress;
+use crate::{password_prompt, CommonOpt, SessionOpt, SessionValidateOpt};
+use kanidm_client::ClientError;
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::OffsetDateTime;
+
+// How long the server accepts a session token for after it was issued.
+const SESSION_TTL: u64 = 3600;
+
+// The passphrase a rekey encrypts the token store with, when not prompted for.
+static NEW_TOKEN_KEY_ENV: &str = "KANIDM_NEW_TOKEN_KEY";
+
+enum SessionStatus {
+    Valid(String),
+    Expired,
+    Rejected,
+    // The server could not be reached, so we don't know if the session is valid.
+    Unreachable(String),
+    Error(String),
+}
+
+impl SessionStatus {
+    fn is_invalid(&self) -> bool {
+        matches!(self, SessionStatus::Expired | SessionStatus::Rejected)
+    }
+}
+
+// Session tokens are fernet tokens, which start with a version byte and then the
+// time they were issued as a big endian u64. These are readable without the
+// server's key, so we can tell an expired session from one the server rejected.
+fn token_issued_at(token: &str) -> Option<u64> {
+    let mut bytes = Vec::with_capacity(9);
+    let mut acc: u32 = 0;
+    for (i, c) in token.bytes().take(12).enumerate() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | v as u32;
+        if i % 4 == 3 {
+            bytes.extend_from_slice(&acc.to_be_bytes()[1..]);
+            acc = 0;
+        }
+    }
+    if bytes.len() != 9 || bytes[0] != 0x80 {
+        return None;
+    }
+    let mut ts = [0; 8];
+    ts.copy_from_slice(&bytes[1..]);
+    Some(u64::from_be_bytes(ts))
+}
+
+fn is_expired(token: &str) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    token_issued_at(token)
+        .map(|issued| now.saturating_sub(issued) > SESSION_TTL)
+        .unwrap_or(false)
+}
+
+fn print_session(key: &str, session: &StoredSession) {
+    // Sessions stored by older versions have no metadata.
+    let created = session
+        .created_at
+        .map(|t| OffsetDateTime::from_unix_timestamp(t as i64).format(time::Format::Rfc3339))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("{}", key);
+    if let Some(label) = &session.label {
+        println!("  label: {}", label);
+    }
+    println!("  stored: {}", created);
+    println!(
+        "  server: {}",
+        session.server.as_deref().unwrap_or("unknown")
+    );
+}
+
+impl SessionOpt {
+    pub fn debug(&self) -> bool {
+        match self {
+            SessionOpt::List(copt) => copt.debug,
+            SessionOpt::Validate(vopt) => vopt.copt.debug,
+            SessionOpt::Rekey(copt) => copt.debug,
+        }
+    }
+
+    pub fn exec(&self) {
+        match self {
+            SessionOpt::List(_) => {
+                let tokens = match read_tokens() {
+                    Ok(t) => t,
+                    Err(_e) => {
+                        error!("Error retrieving authentication token store");
+                        std::process::exit(1);
+                    }
+                };
+                if tokens.is_empty() {
+                    println!("No sessions are stored.");
+                }
+                tokens.iter().for_each(|(k, s)| print_session(k, s));
+            }
+            SessionOpt::Validate(vopt) => vopt.exec(),
+            SessionOpt::Rekey(_) => {
+                let passphrase = match std::env::var(NEW_TOKEN_KEY_ENV) {
+                    Ok(p) => Some(p),
+                    Err(_) => password_prompt("Enter the new token store passphrase: "),
+                };
+                let passphrase = match passphrase.filter(|p| !p.is_empty()) {
+                    Some(p) => p,
+                    None => {
+                        error!("A new passphrase is required to rekey the token store");
+                        std::process::exit(1);
+                    }
+                };
+                if let Err(_e) = rekey_tokens(&passphrase) {
+                    error!("Error rekeying authentication token store");
+                    std::process::exit(1);
+                }
+                println!("The token store is now encrypted with the new passphrase. Set KANIDM_TOKEN_KEY to it to use the stored sessions.");
+            }
+        }
+    }
+}
+
+impl SessionValidateOpt {
+    // Each session is checked against the server it was created with, or for a
+    // session stored by an older version the server of its profile, so sessions
+    // for different servers can be validated together.
+    fn validate(&self, key: &str, token: &str) -> SessionStatus {
+        let copt = match split_token_key(key) {
+            Some((origin, _)) => CommonOpt {
+                debug: self.copt.debug,
+                addr: Some(origin.to_string()),
+                username: None,
+                ca_path: self.copt.ca_path.clone(),
+                profile: None,
+            },
+            None => {
+                let profile = split_legacy_token_key(key).0.map(str::to_string);
+                CommonOpt {
+                    debug: self.copt.debug,
+                    addr: self.copt.addr.clone().filter(|_| profile.is_none()),
+                    username: None,
+                    ca_path: self.copt.ca_path.clone().filter(|_| profile.is_none()),
+                    profile,
+                }
+            }
+        };
+        let client = copt.to_unauth_client();
+        client.set_token(token.to_string());
+
+        match client.whoami() {
+            Ok(Some((_, uat))) => SessionStatus::Valid(uat.spn),
+            Ok(None) if is_expired(token) => SessionStatus::Expired,
+            Ok(None) => SessionStatus::Rejected,
+            Err(ClientError::Transport(e)) => SessionStatus::Unreachable(e.to_string()),
+            Err(e) => SessionStatus::Error(format!("{:?}", e)),
+        }
+    }
+
+    fn exec(&self) {
+        let mut tokens = match read_tokens() {
+            Ok(t) => t,
+            Err(_e) => {
+                error!("Error retrieving authentication token store");
+                std::process::exit(1);
+            }
+        };
+
+        if tokens.is_empty() {
+            println!("No sessions are stored.");
+            return;
+        }
+
+        // Each session is a request to its server, which may be slow to respond.
+        let mut progress = Progress::new(tokens.len(), self.quiet);
+        let mut invalid = Vec::new();
+        for (key, session) in tokens.iter() {
+            let status = self.validate(key, &session.token);
+            progress.clear();
+            match &status {
+                SessionStatus::Valid(spn) => println!("{}: valid ({})", key, spn),
+                SessionStatus::Expired => println!("{}: expired", key),
+                SessionStatus::Rejected => println!("{}: rejected", key),
+                SessionStatus::Unreachable(e) => println!("{}: server unreachable - {}", key, e),
+                SessionStatus::Error(e) => println!("{}: error - {}", key, e),
+            }
+            if status.is_invalid() {
+                invalid.push(key.clone());
+            }
+            progress.inc(1);
+        }
+        progress.finish();
+
+        if invalid.is_empty() {
+            return;
+        }
+
+        if !self.prune {
+            eprintln!(
+                "{} sessions are no longer valid. Use --prune to remove them.",
+                invalid.len()
+            );
+            std::process::exit(1);
+        }
+
+        for key in invalid.iter() {
+            tokens.remove(key);
+        }
+        if let Err(_e) = write_tokens(&tokens, TokenFormat::from_opt(&self.token_format)) {
+            error!("Error persisting authentication token store");
+            std::process::exit(1);
+        }
+        println!("Removed {} sessions.", invalid.len());
+    }
+}
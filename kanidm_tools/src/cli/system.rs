@@ -0,0 +1,502 @@
+use crate::{
+    AuthEventsExportOpt, AuthEventsOpt, AuthTraceOpt, ConfigDiffOpt, ConfigOpt, EntrySizesOpt,
+    SpnBenchOpt, SpnFsckOpt, SpnOpt, SpnUnlockOpt, SpnWatchOpt, StatsOpt, SystemOpt,
+};
+use kanidm_client::{ClientError, KanidmClient};
+use kanidm_proto::v1::{AuthEventQuery, AuthEventResult, Filter, SpnFsckEntry, SystemConfig};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::thread;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+// Fetch the current uuid -> spn mapping of all accounts and groups.
+fn get_spns(client: &KanidmClient) -> Result<BTreeMap<String, String>, ClientError> {
+    let filter = Filter::Or(vec![
+        Filter::Eq("class".to_string(), "account".to_string()),
+        Filter::Eq("class".to_string(), "group".to_string()),
+    ]);
+    // Only the uuid and spn are needed, which keeps the response small in a large
+    // directory.
+    let attrs = vec!["uuid".to_string(), "spn".to_string()];
+    client.search_projected(filter, None, Some(attrs)).map(|r| {
+        r.entries
+            .into_iter()
+            .filter_map(|mut e| {
+                let uuid = e.attrs.remove("uuid").and_then(|mut v| v.pop());
+                let spn = e.attrs.remove("spn").and_then(|mut v| v.pop());
+                uuid.zip(spn)
+            })
+            .collect()
+    })
+}
+
+impl SpnOpt {
+    pub fn debug(&self) -> bool {
+        match self {
+            SpnOpt::Watch(wopt) => wopt.copt.debug,
+            SpnOpt::Fsck(fopt) => fopt.copt.debug,
+            SpnOpt::Bench(bopt) => bopt.copt.debug,
+            SpnOpt::Unlock(uopt) => uopt.copt.debug,
+        }
+    }
+
+    pub fn exec(&self) {
+        match self {
+            SpnOpt::Watch(wopt) => wopt.exec(),
+            SpnOpt::Fsck(fopt) => fopt.exec(),
+            SpnOpt::Bench(bopt) => bopt.exec(),
+            SpnOpt::Unlock(uopt) => uopt.exec(),
+        }
+    }
+}
+
+impl SpnWatchOpt {
+    fn exec(&self) {
+        let client = self.copt.to_client();
+        // There is no change stream from the server, so we poll and diff.
+        let interval = Duration::from_secs(self.interval.max(1));
+
+        let mut current = match get_spns(&client) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Error -> {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        eprintln!(
+            "Watching {} spns, checking every {} seconds. Press Ctrl-C to stop.",
+            current.len(),
+            interval.as_secs()
+        );
+
+        loop {
+            thread::sleep(interval);
+            let next = match get_spns(&client) {
+                Ok(n) => n,
+                Err(e) => {
+                    // Don't give up, the server may be restarting.
+                    warn!("Error checking spns, will retry -> {:?}", e);
+                    continue;
+                }
+            };
+
+            for (uuid, spn) in next.iter() {
+                match current.get(uuid) {
+                    None => println!("+ {} {}", uuid, spn),
+                    Some(old_spn) if old_spn != spn => {
+                        println!("~ {} {} -> {}", uuid, old_spn, spn)
+                    }
+                    Some(_) => {}
+                }
+            }
+            for (uuid, spn) in current.iter() {
+                if !next.contains_key(uuid) {
+                    println!("- {} {}", uuid, spn);
+                }
+            }
+
+            current = next;
+        }
+    }
+}
+
+fn print_spn_fsck_entry(f: &SpnFsckEntry) {
+    println!(
+        "{} {} {:?}: {} -> {}",
+        f.uuid,
+        f.name.as_deref().unwrap_or("<no name>"),
+        f.inconsistency,
+        f.spn.as_deref().unwrap_or("<no spn>"),
+        f.expected_spn.as_deref().unwrap_or("<cannot generate>")
+    );
+}
+
+fn prompt_repair(count: usize) -> bool {
+    eprint!("Regenerate {} spns? [y/N] ", count);
+    let mut buffer = String::new();
+    if let Err(e) = io::stdin().read_line(&mut buffer) {
+        eprintln!("Failed to read from stdin -> {:?}", e);
+        return false;
+    };
+    let response = buffer.trim().to_lowercase();
+    response == "y" || response == "yes"
+}
+
+impl SpnFsckOpt {
+    fn exec(&self) {
+        let mut client = self.copt.to_client();
+
+        let found = match client.system_spn_fsck() {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Error -> {:?}", e);
+                std::process::exit(1);
+            }
+        };
+
+        if found.is_empty() {
+            println!("All spns are consistent.");
+            return;
+        }
+
+        println!("Found {} inconsistent spns:", found.len());
+        found.iter().for_each(print_spn_fsck_entry);
+
+        let repairable = found.iter().filter(|f| f.is_repairable()).count();
+        let unrepairable = found.len() - repairable;
+        if unrepairable > 0 {
+            eprintln!(
+                "{} entries have no name, so their spn can't be regenerated. Restore their name attribute first.",
+                unrepairable
+            );
+        }
+
+        if self.report_only || repairable == 0 {
+            std::process::exit(1);
+        }
+
+        if !self.yes && !prompt_repair(repairable) {
+            eprintln!("No changes made.");
+            std::process::exit(1);
+        }
+
+        // The server finds the inconsistencies again, so this reports what was
+        // actually repaired rather than what was found above.
+        match self
+            .copt
+            .with_reauth(&mut client, |client| client.system_spn_fsck_repair())
+        {
+            Ok(repaired) => {
+                println!("Regenerated {} spns:", repaired.len());
+                repaired.iter().for_each(print_spn_fsck_entry);
+                if unrepairable > 0 {
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                error!("Error -> {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+impl SpnUnlockOpt {
+    fn exec(&self) {
+        let mut client = self.copt.to_client();
+
+        let filter: Filter = match serde_json::from_str(self.filter.as_str()) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Error -> {:?}", e);
+                std::process::exit(1);
+            }
+        };
+
+        match self.copt.with_reauth(&mut client, |client| {
+            client.system_spn_unlock(filter.clone())
+        }) {
+            Ok(count) => println!("Unlocked the spns of {} entries.", count),
+            Err(e) => {
+                error!("Error -> {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+impl SpnBenchOpt {
+    fn exec(&self) {
+        let client = self.copt.to_client();
+
+        let r = match client.system_spn_bench(self.count) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Error -> {:?}", e);
+                std::process::exit(1);
+            }
+        };
+
+        println!("Benchmarked {} synthetic entries:", r.count);
+        println!("  generate: {:.0} entries/sec", r.generate_per_sec);
+        println!("  verify:   {:.0} entries/sec", r.verify_per_sec);
+
+        // A rename regenerates the spn of every account and group, and then verifies them.
+        let projected = r.directory_size as f64 / r.generate_per_sec
+            + r.directory_size as f64 / r.verify_per_sec;
+        println!(
+            "A domain rename of {} accounts and groups needs at least {:.3} seconds.",
+            r.directory_size, projected
+        );
+        println!("This excludes writing the changes to the database, which is usually far slower.");
+    }
+}
+
+impl StatsOpt {
+    pub fn debug(&self) -> bool {
+        match self {
+            StatsOpt::EntrySizes(eopt) => eopt.copt.debug,
+        }
+    }
+
+    pub fn exec(&self) {
+        match self {
+            StatsOpt::EntrySizes(eopt) => eopt.exec(),
+        }
+    }
+}
+
+impl EntrySizesOpt {
+    fn exec(&self) {
+        let client = self.copt.to_client();
+
+        let sizes = match client.system_stats_entry_sizes(self.top) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error -> {:?}", e);
+                std::process::exit(1);
+            }
+        };
+
+        println!("{:<36}  {:>8}  {:>6}  name", "uuid", "values", "attrs");
+        for s in sizes {
+            println!(
+                "{:<36}  {:>8}  {:>6}  {}",
+                s.uuid,
+                s.values,
+                s.attrs,
+                s.name.as_deref().unwrap_or("-")
+            );
+        }
+    }
+}
+
+impl ConfigOpt {
+    pub fn debug(&self) -> bool {
+        match self {
+            ConfigOpt::Diff(dopt) => dopt.copt.debug,
+        }
+    }
+
+    pub fn exec(&self) {
+        match self {
+            ConfigOpt::Diff(dopt) => dopt.exec(),
+        }
+    }
+}
+
+// Compare each option of the local config file with the server. Options that are
+// not in the file are not compared, as the server can't tell us whether its value
+// is a default or was configured.
+fn diff_config(local: &toml::value::Table, server: &SystemConfig) -> Vec<String> {
+    let mut discrepancies = Vec::new();
+    for (option, value) in local.iter() {
+        if let Some(set) = server.secrets.get(option) {
+            if *set {
+                println!("  {}: <secret> (set)", option);
+            } else {
+                println!("! {}: set locally, not set on the server", option);
+                discrepancies.push(option.clone());
+            }
+            continue;
+        }
+        let value = value.to_string();
+        match server.values.get(option) {
+            Some(server_value) if *server_value == value => println!("  {}: {}", option, value),
+            Some(server_value) => {
+                println!(
+                    "! {}: {} locally, {} on the server",
+                    option, value, server_value
+                );
+                discrepancies.push(option.clone());
+            }
+            None => {
+                println!("! {}: {} locally, not set on the server", option, value);
+                discrepancies.push(option.clone());
+            }
+        }
+    }
+    // A secret can be reported even when the file doesn't set it.
+    for (option, set) in server.secrets.iter() {
+        if *set && !local.contains_key(option) {
+            println!("! {}: not set locally, <secret> on the server", option);
+            discrepancies.push(option.clone());
+        }
+    }
+    discrepancies
+}
+
+impl ConfigDiffOpt {
+    fn exec(&self) {
+        let local: toml::value::Table = match fs::read_to_string(&self.path)
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|s| toml::from_str(&s).map_err(|e| format!("{}", e)))
+        {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("Unable to read {} -> {}", self.path.display(), e);
+                std::process::exit(1);
+            }
+        };
+
+        let client = self.copt.to_client();
+        let server = match client.system_config() {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error -> {:?}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let discrepancies = diff_config(&local, &server);
+        if discrepancies.is_empty() {
+            println!(
+                "The server is running with the config in {}",
+                self.path.display()
+            );
+        } else {
+            println!(
+                "{} options differ from {}: {}",
+                discrepancies.len(),
+                self.path.display(),
+                discrepancies.join(", ")
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+impl SystemOpt {
+    pub fn debug(&self) -> bool {
+        match self {
+            SystemOpt::Spn(sopt) => sopt.debug(),
+            SystemOpt::Config(copt) => copt.debug(),
+            SystemOpt::AuthCapabilities(copt) => copt.debug,
+            SystemOpt::AuthEvents(aopt) => aopt.debug(),
+            SystemOpt::AuthTrace(topt) => topt.debug(),
+            SystemOpt::Stats(sopt) => sopt.debug(),
+        }
+    }
+
+    pub fn exec(&self) {
+        match self {
+            SystemOpt::Spn(sopt) => sopt.exec(),
+            SystemOpt::Config(copt) => copt.exec(),
+            SystemOpt::AuthCapabilities(copt) => {
+                let client = copt.to_client();
+                match client.auth_capabilities() {
+                    Ok(caps) => caps.mechs.iter().for_each(|m| println!("{}", m)),
+                    Err(e) => {
+                        eprintln!("Error -> {:?}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            SystemOpt::AuthEvents(aopt) => aopt.exec(),
+            SystemOpt::AuthTrace(topt) => topt.exec(),
+            SystemOpt::Stats(sopt) => sopt.exec(),
+        }
+    }
+}
+
+impl AuthEventsOpt {
+    pub fn debug(&self) -> bool {
+        match self {
+            AuthEventsOpt::Export(eopt) => eopt.copt.debug,
+        }
+    }
+
+    pub fn exec(&self) {
+        match self {
+            AuthEventsOpt::Export(eopt) => eopt.exec(),
+        }
+    }
+}
+
+impl AuthTraceOpt {
+    pub fn debug(&self) -> bool {
+        match self {
+            AuthTraceOpt::Start(sopt) => sopt.copt.debug,
+            AuthTraceOpt::Show(sopt) => sopt.copt.debug,
+        }
+    }
+
+    pub fn exec(&self) {
+        match self {
+            AuthTraceOpt::Start(sopt) => {
+                let client = sopt.copt.to_client();
+                match client.system_auth_trace_start(sopt.account.as_str(), sopt.duration) {
+                    Ok(()) => println!(
+                        "Tracing the authentications of {} for {}s",
+                        sopt.account, sopt.duration
+                    ),
+                    Err(e) => {
+                        eprintln!("Error -> {:?}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            AuthTraceOpt::Show(sopt) => {
+                let client = sopt.copt.to_client();
+                let trace = match client.system_auth_trace(sopt.account.as_str()) {
+                    Ok(trace) => trace,
+                    Err(e) => {
+                        eprintln!("Error -> {:?}", e);
+                        std::process::exit(1);
+                    }
+                };
+                match serde_json::to_string_pretty(&trace) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => {
+                        eprintln!("Failed to serialise trace -> {:?}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AuthEventsExportOpt {
+    fn exec(&self) {
+        let since = match OffsetDateTime::parse(self.since.as_str(), time::Format::Rfc3339) {
+            // Events before the epoch can't exist.
+            Ok(t) => t.unix_timestamp().max(0) as u64,
+            Err(e) => {
+                eprintln!(
+                    "Invalid --since {}, expected rfc3339 -> {:?}",
+                    self.since, e
+                );
+                std::process::exit(1);
+            }
+        };
+        let result = self.result.as_deref().map(|r| match r {
+            "success" => AuthEventResult::Success,
+            _ => AuthEventResult::Failure,
+        });
+
+        let client = self.copt.to_client();
+        let events = match client.system_auth_events(AuthEventQuery {
+            since,
+            account: self.account.clone(),
+            result,
+        }) {
+            Ok(events) => events,
+            Err(e) => {
+                eprintln!("Error -> {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        for event in events {
+            match serde_json::to_string(&event) {
+                Ok(line) => println!("{}", line),
+                Err(e) => {
+                    eprintln!("Failed to serialise event -> {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{GenerateSpnOpt, SpnValidateOpt, UtilOpt};
+use kanidm_proto::v1::SpnFormat;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum SpnParseError {
+    Empty,
+    MissingSeparator,
+    MultipleSeparators,
+    EmptyName,
+    EmptyRealm,
+    Whitespace,
+}
+
+impl fmt::Display for SpnParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpnParseError::Empty => write!(f, "the spn is empty"),
+            SpnParseError::MissingSeparator => {
+                write!(f, "there is no '@' separating the name and realm")
+            }
+            SpnParseError::MultipleSeparators => write!(f, "there is more than one '@'"),
+            SpnParseError::EmptyName => write!(f, "the name before the '@' is empty"),
+            SpnParseError::EmptyRealm => write!(f, "the realm after the '@' is empty"),
+            SpnParseError::Whitespace => write!(f, "spns may not contain whitespace"),
+        }
+    }
+}
+
+/// Split an spn into its name and realm. This is the server's spn syntax - a
+/// non-empty name and realm either side of a single '@' - but stricter, as the
+/// server would silently ignore anything after a second '@'.
+pub(crate) fn spn_parse(spn: &str) -> Result<(&str, &str), SpnParseError> {
+    if spn.is_empty() {
+        return Err(SpnParseError::Empty);
+    }
+    if spn.contains(char::is_whitespace) {
+        return Err(SpnParseError::Whitespace);
+    }
+    let mut parts = spn.split('@');
+    match (parts.next(), parts.next(), parts.next()) {
+        (_, _, Some(_)) => Err(SpnParseError::MultipleSeparators),
+        (Some(_), None, _) | (None, _, _) => Err(SpnParseError::MissingSeparator),
+        (Some(""), Some(_), None) => Err(SpnParseError::EmptyName),
+        (Some(_), Some(""), None) => Err(SpnParseError::EmptyRealm),
+        (Some(name), Some(realm), None) => Ok((name, realm)),
+    }
+}
+
+impl UtilOpt {
+    pub fn debug(&self) -> bool {
+        match self {
+            UtilOpt::SpnValidate(sopt) => sopt.copt.debug,
+            UtilOpt::GenerateSpn(gopt) => gopt.copt.debug,
+        }
+    }
+
+    pub fn exec(&self) {
+        match self {
+            UtilOpt::SpnValidate(sopt) => sopt.exec(),
+            UtilOpt::GenerateSpn(gopt) => gopt.exec(),
+        }
+    }
+}
+
+impl SpnValidateOpt {
+    fn exec(&self) {
+        let (name, realm) = match spn_parse(self.spn.as_str()) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("Error -> \"{}\" is not a valid spn: {}", self.spn, e);
+                std::process::exit(1);
+            }
+        };
+        println!("name: {}", name);
+        println!("realm: {}", realm);
+
+        if self.offline {
+            println!("The spn is valid.");
+            return;
+        }
+
+        let client = self.copt.to_client();
+        let domain_name = match client.idm_domain_get_name() {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!(
+                    "Error -> The spn is valid, but the server's domain could not be checked: {:?}",
+                    e
+                );
+                std::process::exit(1);
+            }
+        };
+
+        // Spns are generated from the lowercased domain name.
+        if realm.to_lowercase() == domain_name {
+            println!("The spn is valid, and its realm is the server's domain.");
+        } else {
+            eprintln!(
+                "Error -> The spn is valid, but its realm {} is not the server's domain {}",
+                realm, domain_name
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+impl GenerateSpnOpt {
+    fn exec(&self) {
+        // Names are stored lowercased, as is the domain name.
+        let format = SpnFormat::new(self.domain.to_lowercase().as_str())
+            .punycode(self.punycode)
+            .case_fold(self.case_fold)
+            .suffix(self.suffix.as_deref())
+            .sanitize(self.sanitize)
+            .delimiter(self.delimiter);
+        let (name, realm) =
+            format.generate(self.name.to_lowercase().as_str(), self.prefix.as_deref());
+        println!("{}", format.to_spn_string(name.as_str(), realm.as_str()));
+    }
+}
@@ -16,6 +16,9 @@ pub struct CommonOpt {
     pub username: Option<String>,
     #[structopt(parse(from_os_str), short = "C", long = "ca", env = "KANIDM_CA_PATH")]
     pub ca_path: Option<PathBuf>,
+    /// Use the server, ca and username of this named profile from the configuration.
+    #[structopt(short = "P", long = "profile", env = "KANIDM_PROFILE")]
+    pub profile: Option<String>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -214,6 +217,34 @@ pub enum AccountSsh {
     Delete(AccountNamedTagOpt),
 }
 
+#[derive(Debug, StructOpt)]
+pub struct AccountSpnSetOpt {
+    #[structopt(flatten)]
+    aopts: AccountCommonOpt,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+    #[structopt(name = "spn")]
+    /// The spn to give the account, of the form "name@domain".
+    spn: String,
+    #[structopt(long = "lock")]
+    /// Lock the spn so that it's never regenerated, such as by a domain rename. This is
+    /// required, as an spn that isn't locked would immediately be regenerated.
+    lock: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum AccountSpn {
+    #[structopt(name = "set")]
+    /// Set a custom spn for the account.
+    Set(AccountSpnSetOpt),
+    #[structopt(name = "unset")]
+    /// Remove a custom spn, so the account's spn is generated again.
+    Unset(AccountNamedOpt),
+    #[structopt(name = "regenerate")]
+    /// Regenerate the spn of only this account, such as to fix one that has drifted.
+    Regenerate(AccountNamedOpt),
+}
+
 #[derive(Debug, StructOpt)]
 pub enum AccountValidity {
     #[structopt(name = "show")]
@@ -244,6 +275,15 @@ pub enum AccountOpt {
     Delete(AccountNamedOpt),
     #[structopt(name = "validity")]
     Validity(AccountValidity),
+    #[structopt(name = "spn-for")]
+    /// Show the spn an account with this name would have, without creating it.
+    SpnFor(AccountNamedOpt),
+    #[structopt(name = "spn")]
+    Spn(AccountSpn),
+    #[structopt(name = "auth-policy")]
+    /// Show the mechanisms an account can authenticate with, given its credentials
+    /// and the mechanisms the server has disabled.
+    AuthPolicy(AccountNamedOpt),
 }
 
 #[derive(Debug, StructOpt)]
@@ -265,6 +305,112 @@ pub struct LoginOpt {
     pub copt: CommonOpt,
     #[structopt(short = "w", long = "webauthn")]
     pub webauthn: bool,
+    #[structopt(short = "y", long = "remember")]
+    /// Store the session token without prompting.
+    pub remember: bool,
+    #[structopt(long = "no-cache")]
+    /// Do not store the session token after a successful login.
+    pub no_cache: bool,
+    #[structopt(long = "export-env")]
+    /// Print the session token as a shell export of KANIDM_TOKEN, suitable for eval.
+    pub export_env: bool,
+    #[structopt(long = "label")]
+    /// A label stored with the session token, shown by session list.
+    pub label: Option<String>,
+    #[structopt(
+        long = "token-format",
+        default_value = "pretty",
+        possible_values = &["compact", "pretty"],
+        env = "KANIDM_TOKEN_FORMAT"
+    )]
+    /// How to write the token store. Either format can always be read.
+    pub token_format: String,
+    #[structopt(
+        long = "password-timeout",
+        default_value = "60",
+        env = "KANIDM_PASSWORD_TIMEOUT"
+    )]
+    /// Seconds to wait for a password to be entered, or 0 to wait forever.
+    pub password_timeout: u64,
+    #[structopt(
+        long = "totp-timeout",
+        default_value = "60",
+        env = "KANIDM_TOTP_TIMEOUT"
+    )]
+    /// Seconds to wait for a TOTP to be entered, or 0 to wait forever.
+    pub totp_timeout: u64,
+    #[structopt(
+        long = "totp-retries",
+        default_value = "3",
+        env = "KANIDM_TOTP_RETRIES"
+    )]
+    /// How many TOTPs that aren't 6 or 8 digits may be entered before giving up.
+    pub totp_retries: u32,
+    #[structopt(
+        long = "webauthn-timeout",
+        default_value = "300",
+        env = "KANIDM_WEBAUTHN_TIMEOUT"
+    )]
+    /// Seconds to wait for the authenticator to be used, or 0 to wait forever.
+    pub webauthn_timeout: u64,
+    #[structopt(
+        long = "webauthn-retries",
+        default_value = "3",
+        env = "KANIDM_WEBAUTHN_RETRIES"
+    )]
+    /// How many times to ask for a security key to be connected if none is found.
+    pub webauthn_retries: u32,
+    #[structopt(
+        long = "webauthn-retry-delay",
+        default_value = "2",
+        env = "KANIDM_WEBAUTHN_RETRY_DELAY"
+    )]
+    /// Seconds to wait for a newly connected security key to be found before asking again.
+    pub webauthn_retry_delay: u64,
+    #[structopt(long = "prefer", use_delimiter = true)]
+    /// When several authentication mechanisms are offered, choose the first of these
+    /// rather than asking, such as "webauthn,passwordmfa". This overrides login_prefer
+    /// in the configuration.
+    pub prefer: Vec<String>,
+    #[structopt(long = "events-fd")]
+    /// Write login progress to this file descriptor as newline delimited json, and
+    /// suppress the interactive prompts. Responses are still read from stdin.
+    pub events_fd: Option<i32>,
+    #[structopt(long = "credential-pipe", conflicts_with = "events-fd")]
+    /// Read the credentials from stdin as a json document, such as
+    /// {"username": "demo", "password": "...", "totp": "123456"}, and log in without
+    /// prompting. The totp is optional, and must be a string.
+    pub credential_pipe: bool,
+    #[structopt(
+        long = "mech",
+        possible_values = &["anonymous", "password", "passwordmfa", "webauthn"]
+    )]
+    /// Authenticate with this mechanism, and fail if the server doesn't offer it,
+    /// rather than choosing from the mechanisms offered.
+    pub mech: Option<String>,
+    #[structopt(long = "non-interactive")]
+    /// Never prompt. If the server requires a credential that wasn't given, the login
+    /// fails with exit code 2.
+    pub non_interactive: bool,
+    #[structopt(long = "password-from-env", conflicts_with = "credential-pipe")]
+    /// Read the password from this environment variable. Implies --non-interactive.
+    pub password_from_env: Option<String>,
+    #[structopt(long = "totp-from-env", conflicts_with = "credential-pipe")]
+    /// Read the TOTP from this environment variable. Implies --non-interactive, and requires
+    /// --password-from-env.
+    pub totp_from_env: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SearchOpt {
+    #[structopt()]
+    filter: String,
+    #[structopt(long = "attrs", use_delimiter = true)]
+    /// Only show these attributes of each entry, such as --attrs name,spn. The
+    /// server's default_search_attrs applies when this is not given.
+    attrs: Vec<String>,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
 }
 
 #[derive(Debug, StructOpt)]
@@ -296,7 +442,7 @@ pub struct ModifyOpt {
 #[derive(Debug, StructOpt)]
 pub enum RawOpt {
     #[structopt(name = "search")]
-    Search(FilterOpt),
+    Search(SearchOpt),
     #[structopt(name = "create")]
     Create(CreateOpt),
     #[structopt(name = "modify")]
@@ -315,6 +461,280 @@ pub enum SelfOpt {
     SetPassword(CommonOpt),
 }
 
+#[derive(Debug, StructOpt)]
+pub struct SpnWatchOpt {
+    #[structopt(short = "i", long = "interval", default_value = "5")]
+    /// Seconds to wait between checks for spn changes.
+    interval: u64,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SpnFsckOpt {
+    #[structopt(short = "y", long = "yes")]
+    /// Repair inconsistent spns without asking for confirmation.
+    yes: bool,
+    #[structopt(long = "report-only", conflicts_with = "yes")]
+    /// Only report inconsistent spns, don't repair them.
+    report_only: bool,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SpnBenchOpt {
+    #[structopt(short = "n", long = "count", default_value = "10000")]
+    /// The number of synthetic entries to generate and verify spns for.
+    count: usize,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SpnUnlockOpt {
+    #[structopt(long = "filter")]
+    /// Only unlock the entries matching this json filter, such as
+    /// '{"eq": ["class", "account"]}'.
+    filter: String,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SpnOpt {
+    #[structopt(name = "watch")]
+    /// Watch for spn additions, changes and removals as they happen
+    Watch(SpnWatchOpt),
+    #[structopt(name = "fsck")]
+    /// Check all spns are consistent with the domain, and repair those that aren't
+    Fsck(SpnFsckOpt),
+    #[structopt(name = "bench")]
+    /// Measure spn generation speed, to estimate how long a domain rename will take
+    Bench(SpnBenchOpt),
+    #[structopt(name = "unlock")]
+    /// Remove spn_locked from the matching entries, so their spns are generated again
+    Unlock(SpnUnlockOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct EntrySizesOpt {
+    #[structopt(short = "n", long = "top", default_value = "10")]
+    /// The number of entries to report, largest first.
+    top: usize,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum StatsOpt {
+    #[structopt(name = "entry-sizes")]
+    /// Show the entries with the most values, such as groups with large memberships
+    EntrySizes(EntrySizesOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ConfigDiffOpt {
+    #[structopt(parse(from_os_str))]
+    /// The server config file, such as server.toml, to compare with the server.
+    path: PathBuf,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ConfigOpt {
+    #[structopt(name = "diff")]
+    /// Compare a server config file with the effective config of the running server
+    Diff(ConfigDiffOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AuthEventsExportOpt {
+    #[structopt(long = "since")]
+    /// Only export events at or after this time, in rfc3339 format such as
+    /// 2021-07-01T00:00:00Z
+    since: String,
+    #[structopt(long = "account")]
+    /// Only export events for this account name
+    account: Option<String>,
+    #[structopt(long = "result", possible_values = &["success", "failure"])]
+    /// Only export events with this result
+    result: Option<String>,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum AuthEventsOpt {
+    #[structopt(name = "export")]
+    /// Export the recorded authentication events as json lines
+    Export(AuthEventsExportOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AuthTraceStartOpt {
+    #[structopt(long = "account")]
+    /// The name of the account to trace, as given when authenticating
+    account: String,
+    #[structopt(long = "duration", default_value = "3600")]
+    /// How long to trace for, in seconds
+    duration: u64,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AuthTraceShowOpt {
+    #[structopt(long = "account")]
+    /// The name of the traced account
+    account: String,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum AuthTraceOpt {
+    #[structopt(name = "start")]
+    /// Record the authentication steps of an account, replacing any earlier trace of it
+    Start(AuthTraceStartOpt),
+    #[structopt(name = "show")]
+    /// Show the recorded authentication steps of an account as json
+    Show(AuthTraceShowOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SpnConfigFileOpt {
+    #[structopt(parse(from_os_str))]
+    /// The file the spn settings are written to, or read from.
+    path: PathBuf,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SpnConfigOpt {
+    #[structopt(name = "export")]
+    /// Write the spn settings of the domain to a file
+    Export(SpnConfigFileOpt),
+    #[structopt(name = "import")]
+    /// Replace the spn settings of the domain with those in a file, regenerating spns
+    Import(SpnConfigFileOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub enum DomainOpt {
+    #[structopt(name = "spn-config")]
+    /// Export and import the spn settings of the domain
+    SpnConfig(SpnConfigOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SystemOpt {
+    #[structopt(name = "spn")]
+    /// Service principal name operations
+    Spn(SpnOpt),
+    #[structopt(name = "config")]
+    /// Server configuration operations
+    Config(ConfigOpt),
+    #[structopt(name = "auth-capabilities")]
+    /// Show the authentication mechanisms the server offers
+    AuthCapabilities(CommonOpt),
+    #[structopt(name = "auth-events")]
+    /// Authentication events recorded by the server
+    AuthEvents(AuthEventsOpt),
+    #[structopt(name = "auth-trace")]
+    /// Trace the authentications of an account to debug them
+    AuthTrace(AuthTraceOpt),
+    #[structopt(name = "stats")]
+    /// Statistics to help diagnose performance problems
+    Stats(StatsOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SessionValidateOpt {
+    #[structopt(long = "prune")]
+    /// Remove the sessions that were expired or rejected from the token store.
+    prune: bool,
+    #[structopt(
+        long = "token-format",
+        default_value = "pretty",
+        possible_values = &["compact", "pretty"],
+        env = "KANIDM_TOKEN_FORMAT"
+    )]
+    /// How to write the token store when pruning. Either format can always be read.
+    token_format: String,
+    #[structopt(short = "q", long = "quiet")]
+    /// Don't show the progress of the validation.
+    quiet: bool,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SessionOpt {
+    #[structopt(name = "list")]
+    /// Show the sessions in the token store, with their label, server and when they were stored
+    List(CommonOpt),
+    #[structopt(name = "validate")]
+    /// Check whether each session in the token store still authenticates
+    Validate(SessionValidateOpt),
+    #[structopt(name = "rekey")]
+    /// Encrypt the token store with a new passphrase, keeping its sessions
+    Rekey(CommonOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SpnValidateOpt {
+    #[structopt(name = "spn")]
+    spn: String,
+    #[structopt(long = "offline")]
+    /// Only check the syntax, don't compare the realm to the server's domain.
+    offline: bool,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct GenerateSpnOpt {
+    #[structopt(long = "name")]
+    /// The name of the account or group.
+    name: String,
+    #[structopt(long = "domain")]
+    /// The domain name of the server.
+    domain: String,
+    #[structopt(long = "prefix")]
+    /// The service prefix given to the entry's class by domain_spn_prefix, such as HTTP.
+    prefix: Option<String>,
+    #[structopt(long = "punycode")]
+    /// Use the punycode of an internationalised domain name, as with spn_idn_mode = "punycode".
+    punycode: bool,
+    #[structopt(long = "case-fold")]
+    /// Lowercase the name and realm.
+    case_fold: bool,
+    #[structopt(long = "suffix")]
+    /// Append this to the domain name to form the realm.
+    suffix: Option<String>,
+    #[structopt(long = "sanitize")]
+    /// Replace the characters kerberos doesn't allow, as with spn_charset_policy = "sanitize".
+    sanitize: bool,
+    #[structopt(long = "delimiter", default_value = "@")]
+    /// The delimiter between the name and realm.
+    delimiter: char,
+    #[structopt(flatten)]
+    copt: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum UtilOpt {
+    #[structopt(name = "spn-validate")]
+    /// Check an spn is well formed, and that its realm is the server's domain
+    SpnValidate(SpnValidateOpt),
+    #[structopt(name = "generate-spn")]
+    /// Show the spn the server would generate for a name, without connecting to it
+    GenerateSpn(GenerateSpnOpt),
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Kanidm Client Utility")]
 pub enum KanidmClientOpt {
@@ -333,6 +753,18 @@ pub enum KanidmClientOpt {
     #[structopt(name = "recycle_bin")]
     /// Recycle Bin operations
     Recycle(RecycleOpt),
+    #[structopt(name = "system")]
+    /// System administration operations
+    System(SystemOpt),
+    #[structopt(name = "domain")]
+    /// Domain administration operations
+    Domain(DomainOpt),
+    #[structopt(name = "session")]
+    /// Manage the sessions stored by login
+    Session(SessionOpt),
+    #[structopt(name = "util")]
+    /// Utilities that don't change anything on the server
+    Util(UtilOpt),
     #[structopt(name = "raw")]
     /// Unsafe - low level, raw database operations.
     Raw(RawOpt),
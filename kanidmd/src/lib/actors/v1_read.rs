@@ -4,7 +4,12 @@ use std::sync::Arc;
 
 use crate::prelude::*;
 
-use crate::event::{AuthEvent, AuthResult, SearchEvent, SearchResult, WhoamiResult};
+use crate::audit::LogLevelHandle;
+
+use crate::be::BackendTransaction;
+use crate::event::{
+    AuthEvent, AuthResult, Event, OnlineBackupEvent, SearchEvent, SearchResult, WhoamiResult,
+};
 use crate::idm::event::{
     CredentialStatusEvent, RadiusAuthTokenEvent, UnixGroupTokenEvent, UnixUserAuthEvent,
     UnixUserTokenEvent,
@@ -15,14 +20,18 @@ use kanidm_proto::v1::{OperationError, RadiusAuthToken};
 use crate::filter::{Filter, FilterInvalid};
 use crate::idm::server::IdmServer;
 use crate::ldap::{LdapBoundToken, LdapResponseState, LdapServer};
+use crate::utils::duration_from_epoch_now;
 
 use kanidm_proto::v1::Entry as ProtoEntry;
 use kanidm_proto::v1::{
-    AuthRequest, CredentialStatus, SearchRequest, SearchResponse, UnixGroupToken, UnixUserToken,
-    UserAuthToken, WhoamiResponse,
+    AuthCapabilities, AuthEventQuery, AuthEventRecord, AuthPolicy, AuthRequest, AuthTrace,
+    AuthTraceRequest, CredentialStatus, EntrySize, SearchRequest, SearchResponse, SpnBenchResult,
+    SpnConfig, SpnFsckEntry, SystemConfig, UnixGroupToken, UnixUserToken, UserAuthToken,
+    WhoamiResponse,
 };
 
-use std::time::SystemTime;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
 use ldap3_server::simple::*;
@@ -32,7 +41,7 @@ use std::convert::TryFrom;
 
 pub struct QueryServerReadV1 {
     log: Sender<AuditScope>,
-    log_level: Option<u32>,
+    log_level: LogLevelHandle,
     idms: Arc<IdmServer>,
     ldap: Arc<LdapServer>,
 }
@@ -40,7 +49,7 @@ pub struct QueryServerReadV1 {
 impl QueryServerReadV1 {
     pub fn new(
         log: Sender<AuditScope>,
-        log_level: Option<u32>,
+        log_level: LogLevelHandle,
         idms: Arc<IdmServer>,
         ldap: Arc<LdapServer>,
     ) -> Self {
@@ -55,7 +64,7 @@ impl QueryServerReadV1 {
 
     pub fn start_static(
         log: Sender<AuditScope>,
-        log_level: Option<u32>,
+        log_level: LogLevelHandle,
         idms: Arc<IdmServer>,
         ldap: Arc<LdapServer>,
     ) -> &'static Self {
@@ -76,7 +85,7 @@ impl QueryServerReadV1 {
         req: SearchRequest,
         eventid: Uuid,
     ) -> Result<SearchResponse, OperationError> {
-        let mut audit = AuditScope::new("search", eventid, self.log_level);
+        let mut audit = AuditScope::new("search", eventid, self.log_level.get());
         // Begin a read
         let idms_prox_read = self.idms.proxy_read_async().await;
         let res = lperf_op_segment!(&mut audit, "actors::v1_read::handle<SearchMessage>", || {
@@ -96,14 +105,17 @@ impl QueryServerReadV1 {
 
             ltrace!(audit, "Begin event {:?}", srch);
 
-            match idms_prox_read.qs_read.search_ext(&mut audit, &srch) {
+            match idms_prox_read
+                .qs_read
+                .search_ext_limited(&mut audit, &srch, req.limit)
+            {
                 Ok(entries) => SearchResult::new(&mut audit, &idms_prox_read.qs_read, &entries)
                     .map(|ok_sr| ok_sr.response()),
                 Err(e) => Err(e),
             }
         });
         // At the end of the event we send it for logging.
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -114,13 +126,14 @@ impl QueryServerReadV1 {
         &self,
         sessionid: Option<Uuid>,
         req: AuthRequest,
+        source: Option<IpAddr>,
         eventid: Uuid,
     ) -> Result<AuthResult, OperationError> {
         // This is probably the first function that really implements logic
         // "on top" of the db server concept. In this case we check if
         // the credentials provided is sufficient to say if someone is
         // "authenticated" or not.
-        let mut audit = AuditScope::new("auth", eventid, self.log_level);
+        let mut audit = AuditScope::new("auth", eventid, self.log_level.get());
         let mut idm_auth = self.idms.auth_async().await;
         // let res = lperf_op_segment!(&mut audit, "actors::v1_read::handle<AuthMessage>", || {
         lsecurity!(audit, "Begin auth event {:?} {:?}", sessionid, req);
@@ -128,7 +141,7 @@ impl QueryServerReadV1 {
         // Destructure it.
         // Convert the AuthRequest to an AuthEvent that the idm server
         // can use.
-        let ae = AuthEvent::from_message(sessionid, req).map_err(|e| {
+        let ae = AuthEvent::from_message(sessionid, req, source).map_err(|e| {
             ladmin_error!(audit, "Failed to parse AuthEvent -> {:?}", e);
             e
         })?;
@@ -158,7 +171,7 @@ impl QueryServerReadV1 {
         // r
         // });
         // At the end of the event we send it for logging.
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -170,7 +183,7 @@ impl QueryServerReadV1 {
         uat: Option<UserAuthToken>,
         eventid: Uuid,
     ) -> Result<WhoamiResponse, OperationError> {
-        let mut audit = AuditScope::new("whoami", eventid, self.log_level);
+        let mut audit = AuditScope::new("whoami", eventid, self.log_level.get());
         // TODO #62: Move this to IdmServer!!!
         // Begin a read
         let idms_prox_read = self.idms.proxy_read_async().await;
@@ -220,21 +233,303 @@ impl QueryServerReadV1 {
         });
         // Should we log the final result?
         // At the end of the event we send it for logging.
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    pub(crate) async fn handle_online_backup(
+        &self,
+        msg: OnlineBackupEvent,
+    ) -> Result<(), OperationError> {
+        let mut audit = AuditScope::new("online backup", msg.eventid, self.log_level.get());
+
+        ltrace!(audit, "Begin online backup event {:?}", msg);
+        let idms_prox_read = self.idms.proxy_read_async().await;
+
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_read::handle<OnlineBackupEvent>",
+            || {
+                let res = idms_prox_read
+                    .qs_read
+                    .get_be_txn()
+                    .backup(&mut audit, msg.dst_path.as_str());
+                ladmin_info!(audit, "Online backup to {} result: {:?}", msg.dst_path, res);
+                res
+            }
+        );
+        self.log.send(audit.finish()).unwrap_or_else(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+        });
+        res
+    }
+
+    pub async fn handle_spnfsck(
+        &self,
+        uat: Option<UserAuthToken>,
+        eventid: Uuid,
+    ) -> Result<Vec<SpnFsckEntry>, OperationError> {
+        let mut audit = AuditScope::new("spn_fsck", eventid, self.log_level.get());
+        let idms_prox_read = self.idms.proxy_read_async().await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_read::handle<SpnFsckMessage>",
+            || {
+                let ev = Event::from_ro_uat(&mut audit, &idms_prox_read.qs_read, uat.as_ref())
+                    .map_err(|e| {
+                        ladmin_error!(audit, "Failed to begin spn fsck: {:?}", e);
+                        e
+                    })?;
+                idms_prox_read.qs_read.spn_fsck_report(&mut audit, &ev)
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    pub async fn handle_spnbench(
+        &self,
+        uat: Option<UserAuthToken>,
+        count: usize,
+        eventid: Uuid,
+    ) -> Result<SpnBenchResult, OperationError> {
+        let mut audit = AuditScope::new("spn_bench", eventid, self.log_level.get());
+        let idms_prox_read = self.idms.proxy_read_async().await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_read::handle<SpnBenchMessage>",
+            || {
+                let ev = Event::from_ro_uat(&mut audit, &idms_prox_read.qs_read, uat.as_ref())
+                    .map_err(|e| {
+                        ladmin_error!(audit, "Failed to begin spn bench: {:?}", e);
+                        e
+                    })?;
+                idms_prox_read.qs_read.spn_bench(&mut audit, &ev, count)
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    pub async fn handle_entrysizes(
+        &self,
+        uat: Option<UserAuthToken>,
+        top: usize,
+        eventid: Uuid,
+    ) -> Result<Vec<EntrySize>, OperationError> {
+        let mut audit = AuditScope::new("entry_sizes", eventid, self.log_level.get());
+        let idms_prox_read = self.idms.proxy_read_async().await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_read::handle<EntrySizesMessage>",
+            || {
+                let ev = Event::from_ro_uat(&mut audit, &idms_prox_read.qs_read, uat.as_ref())
+                    .map_err(|e| {
+                        ladmin_error!(audit, "Failed to begin entry sizes: {:?}", e);
+                        e
+                    })?;
+                idms_prox_read.qs_read.entry_sizes(&mut audit, &ev, top)
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    pub async fn handle_spnconfigexport(
+        &self,
+        uat: Option<UserAuthToken>,
+        eventid: Uuid,
+    ) -> Result<SpnConfig, OperationError> {
+        let mut audit = AuditScope::new("spn_config_export", eventid, self.log_level.get());
+        let idms_prox_read = self.idms.proxy_read_async().await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_read::handle<SpnConfigExportMessage>",
+            || {
+                let ev = Event::from_ro_uat(&mut audit, &idms_prox_read.qs_read, uat.as_ref())
+                    .map_err(|e| {
+                        ladmin_error!(audit, "Failed to begin spn config export: {:?}", e);
+                        e
+                    })?;
+                idms_prox_read.qs_read.spn_config_export(&mut audit, &ev)
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    pub async fn handle_systemconfig(
+        &self,
+        uat: Option<UserAuthToken>,
+        config: SystemConfig,
+        eventid: Uuid,
+    ) -> Result<SystemConfig, OperationError> {
+        let mut audit = AuditScope::new("system_config", eventid, self.log_level.get());
+        let idms_prox_read = self.idms.proxy_read_async().await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_read::handle<SystemConfigMessage>",
+            || {
+                let ev = Event::from_ro_uat(&mut audit, &idms_prox_read.qs_read, uat.as_ref())
+                    .map_err(|e| {
+                        ladmin_error!(audit, "Failed to begin system config: {:?}", e);
+                        e
+                    })?;
+                idms_prox_read
+                    .qs_read
+                    .check_system_admin_access(&mut audit, &ev, "system config")
+                    .map(|_| config)
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    pub async fn handle_authevents(
+        &self,
+        uat: Option<UserAuthToken>,
+        query: AuthEventQuery,
+        eventid: Uuid,
+    ) -> Result<Vec<AuthEventRecord>, OperationError> {
+        let mut audit = AuditScope::new("auth_events", eventid, self.log_level.get());
+        let idms_prox_read = self.idms.proxy_read_async().await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_read::handle<AuthEventsMessage>",
+            || {
+                let ev = Event::from_ro_uat(&mut audit, &idms_prox_read.qs_read, uat.as_ref())
+                    .map_err(|e| {
+                        ladmin_error!(audit, "Failed to begin auth events: {:?}", e);
+                        e
+                    })?;
+                idms_prox_read
+                    .qs_read
+                    .check_system_admin_access(&mut audit, &ev, "auth events")
+                    .map(|_| self.idms.auth_events(&query))
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    pub async fn handle_authtracestart(
+        &self,
+        uat: Option<UserAuthToken>,
+        req: AuthTraceRequest,
+        eventid: Uuid,
+    ) -> Result<(), OperationError> {
+        let mut audit = AuditScope::new("auth_trace_start", eventid, self.log_level.get());
+        let idms_prox_read = self.idms.proxy_read_async().await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_read::handle<AuthTraceStartMessage>",
+            || {
+                let ev = Event::from_ro_uat(&mut audit, &idms_prox_read.qs_read, uat.as_ref())
+                    .map_err(|e| {
+                        ladmin_error!(audit, "Failed to begin auth trace: {:?}", e);
+                        e
+                    })?;
+                idms_prox_read
+                    .qs_read
+                    .check_system_admin_access(&mut audit, &ev, "auth trace")?;
+                lsecurity!(
+                    audit,
+                    "Tracing the authentications of {} for {}s",
+                    req.account,
+                    req.duration
+                );
+                self.idms.start_auth_trace(
+                    req.account.as_str(),
+                    Duration::from_secs(req.duration),
+                    duration_from_epoch_now(),
+                )
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    pub async fn handle_authtrace(
+        &self,
+        uat: Option<UserAuthToken>,
+        account: String,
+        eventid: Uuid,
+    ) -> Result<AuthTrace, OperationError> {
+        let mut audit = AuditScope::new("auth_trace", eventid, self.log_level.get());
+        let idms_prox_read = self.idms.proxy_read_async().await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_read::handle<AuthTraceMessage>",
+            || {
+                let ev = Event::from_ro_uat(&mut audit, &idms_prox_read.qs_read, uat.as_ref())
+                    .map_err(|e| {
+                        ladmin_error!(audit, "Failed to begin auth trace: {:?}", e);
+                        e
+                    })?;
+                idms_prox_read
+                    .qs_read
+                    .check_system_admin_access(&mut audit, &ev, "auth trace")?;
+                self.idms
+                    .auth_trace(account.as_str())
+                    .ok_or(OperationError::NoMatchingEntries)
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
         res
     }
 
+    pub async fn handle_authcapabilities(
+        &self,
+        eventid: Uuid,
+    ) -> Result<AuthCapabilities, OperationError> {
+        let mut audit = AuditScope::new("auth_capabilities", eventid, self.log_level.get());
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_read::handle<AuthCapabilitiesMessage>",
+            || self.idms.auth_capabilities()
+        );
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        Ok(res)
+    }
+
     pub async fn handle_internalsearch(
         &self,
         uat: Option<UserAuthToken>,
         filter: Filter<FilterInvalid>,
         attrs: Option<Vec<String>>,
         eventid: Uuid,
-    ) -> Result<Vec<ProtoEntry>, OperationError> {
-        let mut audit = AuditScope::new("internal_search_message", eventid, self.log_level);
+    ) -> Result<SearchResponse, OperationError> {
+        let mut audit = AuditScope::new("internal_search_message", eventid, self.log_level.get());
         let idms_prox_read = self.idms.proxy_read_async().await;
         let res = lperf_op_segment!(
             &mut audit,
@@ -257,14 +552,17 @@ impl QueryServerReadV1 {
 
                 ltrace!(audit, "Begin event {:?}", srch);
 
-                match idms_prox_read.qs_read.search_ext(&mut audit, &srch) {
+                match idms_prox_read
+                    .qs_read
+                    .search_ext_limited(&mut audit, &srch, None)
+                {
                     Ok(entries) => SearchResult::new(&mut audit, &idms_prox_read.qs_read, &entries)
-                        .map(|ok_sr| ok_sr.into_proto_array()),
+                        .map(|ok_sr| ok_sr.response()),
                     Err(e) => Err(e),
                 }
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -278,7 +576,11 @@ impl QueryServerReadV1 {
         attrs: Option<Vec<String>>,
         eventid: Uuid,
     ) -> Result<Vec<ProtoEntry>, OperationError> {
-        let mut audit = AuditScope::new("internal_search_recycle_message", eventid, self.log_level);
+        let mut audit = AuditScope::new(
+            "internal_search_recycle_message",
+            eventid,
+            self.log_level.get(),
+        );
         let idms_prox_read = self.idms.proxy_read_async().await;
 
         let res = lperf_op_segment!(
@@ -309,7 +611,7 @@ impl QueryServerReadV1 {
                 }
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -322,7 +624,11 @@ impl QueryServerReadV1 {
         uuid_or_name: String,
         eventid: Uuid,
     ) -> Result<Option<String>, OperationError> {
-        let mut audit = AuditScope::new("internal_radius_read_message", eventid, self.log_level);
+        let mut audit = AuditScope::new(
+            "internal_radius_read_message",
+            eventid,
+            self.log_level.get(),
+        );
         let idms_prox_read = self.idms.proxy_read_async().await;
         let res = lperf_op_segment!(
             &mut audit,
@@ -368,7 +674,7 @@ impl QueryServerReadV1 {
                 }
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -384,7 +690,7 @@ impl QueryServerReadV1 {
         let mut audit = AuditScope::new(
             "internal_radius_token_read_message",
             eventid,
-            self.log_level,
+            self.log_level.get(),
         );
         let mut idms_prox_read = self.idms.proxy_read_async().await;
 
@@ -426,7 +732,7 @@ impl QueryServerReadV1 {
                 idms_prox_read.get_radiusauthtoken(&mut audit, &rate, ct)
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -439,8 +745,11 @@ impl QueryServerReadV1 {
         uuid_or_name: String,
         eventid: Uuid,
     ) -> Result<UnixUserToken, OperationError> {
-        let mut audit =
-            AuditScope::new("internal_unix_token_read_message", eventid, self.log_level);
+        let mut audit = AuditScope::new(
+            "internal_unix_token_read_message",
+            eventid,
+            self.log_level.get(),
+        );
         let mut idms_prox_read = self.idms.proxy_read_async().await;
 
         let res = lperf_op_segment!(
@@ -486,7 +795,7 @@ impl QueryServerReadV1 {
                 idms_prox_read.get_unixusertoken(&mut audit, &rate, ct)
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -502,7 +811,7 @@ impl QueryServerReadV1 {
         let mut audit = AuditScope::new(
             "internal_unixgroup_token_read_message",
             eventid,
-            self.log_level,
+            self.log_level.get(),
         );
         let mut idms_prox_read = self.idms.proxy_read_async().await;
         let res = lperf_op_segment!(
@@ -536,7 +845,7 @@ impl QueryServerReadV1 {
                 idms_prox_read.get_unixgrouptoken(&mut audit, &rate)
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -549,7 +858,11 @@ impl QueryServerReadV1 {
         uuid_or_name: String,
         eventid: Uuid,
     ) -> Result<Vec<String>, OperationError> {
-        let mut audit = AuditScope::new("internal_sshkey_read_message", eventid, self.log_level);
+        let mut audit = AuditScope::new(
+            "internal_sshkey_read_message",
+            eventid,
+            self.log_level.get(),
+        );
         let idms_prox_read = self.idms.proxy_read_async().await;
         let res = lperf_op_segment!(
             &mut audit,
@@ -599,7 +912,7 @@ impl QueryServerReadV1 {
                 }
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -613,8 +926,11 @@ impl QueryServerReadV1 {
         tag: String,
         eventid: Uuid,
     ) -> Result<Option<String>, OperationError> {
-        let mut audit =
-            AuditScope::new("internal_sshkey_tag_read_message", eventid, self.log_level);
+        let mut audit = AuditScope::new(
+            "internal_sshkey_tag_read_message",
+            eventid,
+            self.log_level.get(),
+        );
         let idms_prox_read = self.idms.proxy_read_async().await;
         let res = lperf_op_segment!(
             &mut audit,
@@ -670,7 +986,7 @@ impl QueryServerReadV1 {
                 }
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -684,7 +1000,7 @@ impl QueryServerReadV1 {
         cred: String,
         eventid: Uuid,
     ) -> Result<Option<UnixUserToken>, OperationError> {
-        let mut audit = AuditScope::new("idm_account_unix_auth", eventid, self.log_level);
+        let mut audit = AuditScope::new("idm_account_unix_auth", eventid, self.log_level.get());
         let mut idm_auth = self.idms.auth_async().await;
         // let res = lperf_op_segment!(&mut audit, "actors::v1_read::handle<IdmAccountUnixAuthMessage>", || {
         // resolve the id
@@ -727,7 +1043,7 @@ impl QueryServerReadV1 {
         lsecurity!(audit, "Sending result -> {:?}", res);
         // res
         // });
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -740,7 +1056,11 @@ impl QueryServerReadV1 {
         uuid_or_name: String,
         eventid: Uuid,
     ) -> Result<CredentialStatus, OperationError> {
-        let mut audit = AuditScope::new("idm_credential_status_message", eventid, self.log_level);
+        let mut audit = AuditScope::new(
+            "idm_credential_status_message",
+            eventid,
+            self.log_level.get(),
+        );
         let mut idms_prox_read = self.idms.proxy_read_async().await;
 
         let res = lperf_op_segment!(
@@ -774,7 +1094,54 @@ impl QueryServerReadV1 {
                 idms_prox_read.get_credentialstatus(&mut audit, &cse)
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    pub async fn handle_idmauthpolicy(
+        &self,
+        uat: Option<UserAuthToken>,
+        uuid_or_name: String,
+        eventid: Uuid,
+    ) -> Result<AuthPolicy, OperationError> {
+        let mut audit = AuditScope::new("idm_auth_policy_message", eventid, self.log_level.get());
+        let mut idms_prox_read = self.idms.proxy_read_async().await;
+
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_read::handle<IdmAuthPolicyMessage>",
+            || {
+                let target_uuid = idms_prox_read
+                    .qs_read
+                    .name_to_uuid(&mut audit, uuid_or_name.as_str())
+                    .map_err(|e| {
+                        ladmin_error!(&mut audit, "Error resolving id to target");
+                        e
+                    })?;
+
+                // The policy reveals the same as the credential status.
+                let cse = match CredentialStatusEvent::from_parts(
+                    &mut audit,
+                    &idms_prox_read.qs_read,
+                    uat.as_ref(),
+                    target_uuid,
+                ) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        ladmin_error!(audit, "Failed to begin auth policy read: {:?}", e);
+                        return Err(e);
+                    }
+                };
+
+                ltrace!(audit, "Begin event {:?}", cse);
+
+                idms_prox_read.get_auth_policy(&mut audit, &cse)
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -787,7 +1154,7 @@ impl QueryServerReadV1 {
         protomsg: LdapMsg,
         uat: Option<LdapBoundToken>,
     ) -> Option<LdapResponseState> {
-        let mut audit = AuditScope::new("ldap_request_message", eventid, self.log_level);
+        let mut audit = AuditScope::new("ldap_request_message", eventid, self.log_level.get());
 
         /*
         let res = lperf_op_segment!(
@@ -816,7 +1183,7 @@ impl QueryServerReadV1 {
             }
         );
         */
-        if self.log.send(audit).is_err() {
+        if self.log.send(audit.finish()).is_err() {
             error!("Unable to commit log -> {:?}", &eventid);
             Some(LdapResponseState::Disconnect(DisconnectionNotice::gen(
                 LdapResultCode::Other,
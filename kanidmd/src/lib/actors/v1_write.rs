@@ -4,9 +4,11 @@ use tokio::sync::mpsc::UnboundedSender as Sender;
 
 use crate::prelude::*;
 
+use crate::audit::LogLevelHandle;
+
 use crate::event::{
-    CreateEvent, DeleteEvent, ModifyEvent, PurgeRecycledEvent, PurgeTombstoneEvent,
-    ReviveRecycledEvent,
+    CreateEvent, DbMaintenanceEvent, DeleteEvent, Event, ModifyEvent, PurgeRecycledEvent,
+    PurgeTombstoneEvent, ReviveRecycledEvent,
 };
 use crate::idm::event::{
     GeneratePasswordEvent, GenerateTotpEvent, PasswordChangeEvent, RegenerateRadiusSecretEvent,
@@ -22,23 +24,25 @@ use crate::idm::delayed::DelayedAction;
 use crate::idm::server::IdmServer;
 use crate::utils::duration_from_epoch_now;
 
+use kanidm_proto::v1::Filter as ProtoFilter;
 use kanidm_proto::v1::Modify as ProtoModify;
 use kanidm_proto::v1::ModifyList as ProtoModifyList;
 use kanidm_proto::v1::{
     AccountUnixExtend, CreateRequest, DeleteRequest, GroupUnixExtend, ModifyRequest,
-    OperationResponse, SetCredentialRequest, SetCredentialResponse, UserAuthToken,
+    OperationResponse, SetCredentialRequest, SetCredentialResponse, SpnConfig, SpnFsckEntry,
+    SpnRegenerateResult, UserAuthToken,
 };
 
 use uuid::Uuid;
 
 pub struct QueryServerWriteV1 {
     log: Sender<AuditScope>,
-    log_level: Option<u32>,
+    log_level: LogLevelHandle,
     idms: Arc<IdmServer>,
 }
 
 impl QueryServerWriteV1 {
-    pub fn new(log: Sender<AuditScope>, log_level: Option<u32>, idms: Arc<IdmServer>) -> Self {
+    pub fn new(log: Sender<AuditScope>, log_level: LogLevelHandle, idms: Arc<IdmServer>) -> Self {
         info!("Starting query server v1 worker ...");
         QueryServerWriteV1 {
             log,
@@ -49,7 +53,7 @@ impl QueryServerWriteV1 {
 
     pub fn start_static(
         log: Sender<AuditScope>,
-        log_level: Option<u32>,
+        log_level: LogLevelHandle,
         idms: Arc<IdmServer>,
     ) -> &'static QueryServerWriteV1 {
         let x = Box::new(QueryServerWriteV1::new(log, log_level, idms));
@@ -150,7 +154,7 @@ impl QueryServerWriteV1 {
         req: CreateRequest,
         eventid: Uuid,
     ) -> Result<OperationResponse, OperationError> {
-        let mut audit = AuditScope::new("create", eventid, self.log_level);
+        let mut audit = AuditScope::new("create", eventid, self.log_level.get());
         let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
         let res = lperf_op_segment!(
             &mut audit,
@@ -182,7 +186,7 @@ impl QueryServerWriteV1 {
             }
         );
         // At the end of the event we send it for logging.
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -195,7 +199,7 @@ impl QueryServerWriteV1 {
         req: ModifyRequest,
         eventid: Uuid,
     ) -> Result<OperationResponse, OperationError> {
-        let mut audit = AuditScope::new("modify", eventid, self.log_level);
+        let mut audit = AuditScope::new("modify", eventid, self.log_level.get());
         let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
         let res = lperf_segment!(
             &mut audit,
@@ -226,7 +230,7 @@ impl QueryServerWriteV1 {
                     })
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -239,7 +243,7 @@ impl QueryServerWriteV1 {
         req: DeleteRequest,
         eventid: Uuid,
     ) -> Result<OperationResponse, OperationError> {
-        let mut audit = AuditScope::new("delete", eventid, self.log_level);
+        let mut audit = AuditScope::new("delete", eventid, self.log_level.get());
         let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
         let res = lperf_op_segment!(
             &mut audit,
@@ -270,7 +274,7 @@ impl QueryServerWriteV1 {
                     })
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -283,7 +287,7 @@ impl QueryServerWriteV1 {
         filter: Filter<FilterInvalid>,
         eventid: Uuid,
     ) -> Result<(), OperationError> {
-        let mut audit = AuditScope::new("internal_delete", eventid, self.log_level);
+        let mut audit = AuditScope::new("internal_delete", eventid, self.log_level.get());
         let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
         let res = lperf_op_segment!(
             &mut audit,
@@ -310,7 +314,7 @@ impl QueryServerWriteV1 {
                     .and_then(|_| idms_prox_write.commit(&mut audit).map(|_| ()))
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -323,7 +327,7 @@ impl QueryServerWriteV1 {
         filter: Filter<FilterInvalid>,
         eventid: Uuid,
     ) -> Result<(), OperationError> {
-        let mut audit = AuditScope::new("revive", eventid, self.log_level);
+        let mut audit = AuditScope::new("revive", eventid, self.log_level.get());
         let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
         let res = lperf_op_segment!(
             &mut audit,
@@ -350,7 +354,7 @@ impl QueryServerWriteV1 {
                     .and_then(|_| idms_prox_write.commit(&mut audit).map(|_| ()))
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -366,7 +370,11 @@ impl QueryServerWriteV1 {
         sac: SetCredentialRequest,
         eventid: Uuid,
     ) -> Result<SetCredentialResponse, OperationError> {
-        let mut audit = AuditScope::new("internal_credential_set_message", eventid, self.log_level);
+        let mut audit = AuditScope::new(
+            "internal_credential_set_message",
+            eventid,
+            self.log_level.get(),
+        );
         let ct = duration_from_epoch_now();
         let mut idms_prox_write = self.idms.proxy_write_async(ct).await;
         let res = lperf_op_segment!(
@@ -560,7 +568,7 @@ impl QueryServerWriteV1 {
                 }
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -573,7 +581,7 @@ impl QueryServerWriteV1 {
         cleartext: String,
         eventid: Uuid,
     ) -> Result<OperationResponse, OperationError> {
-        let mut audit = AuditScope::new("idm_account_set_password", eventid, self.log_level);
+        let mut audit = AuditScope::new("idm_account_set_password", eventid, self.log_level.get());
         let ct = duration_from_epoch_now();
         let mut idms_prox_write = self.idms.proxy_write_async(ct).await;
         let res = lperf_op_segment!(
@@ -599,7 +607,34 @@ impl QueryServerWriteV1 {
                     .map(|_| OperationResponse::new(()))
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    // Only reachable through the admin socket, which has already limited who can
+    // connect, so there is no uat or access control check.
+    pub async fn handle_admin_recover_account(
+        &self,
+        name: String,
+        cleartext: String,
+        eventid: Uuid,
+    ) -> Result<(), OperationError> {
+        let mut audit = AuditScope::new("admin_recover_account", eventid, self.log_level.get());
+        let mut idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_write::handle<AdminRecoverAccount>",
+            || {
+                lsecurity!(audit, "Recovering account {} from the admin socket", name);
+                idms_prox_write
+                    .recover_account(&mut audit, &name, &cleartext)
+                    .and_then(|_| idms_prox_write.commit(&mut audit))
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -612,7 +647,11 @@ impl QueryServerWriteV1 {
         uuid_or_name: String,
         eventid: Uuid,
     ) -> Result<String, OperationError> {
-        let mut audit = AuditScope::new("idm_account_regenerate_radius", eventid, self.log_level);
+        let mut audit = AuditScope::new(
+            "idm_account_regenerate_radius",
+            eventid,
+            self.log_level.get(),
+        );
         let ct = duration_from_epoch_now();
         let mut idms_prox_write = self.idms.proxy_write_async(ct).await;
         let res = lperf_op_segment!(
@@ -649,7 +688,89 @@ impl QueryServerWriteV1 {
                     .and_then(|r| idms_prox_write.commit(&mut audit).map(|_| r))
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    pub async fn handle_spnfsckrepair(
+        &self,
+        uat: Option<UserAuthToken>,
+        eventid: Uuid,
+    ) -> Result<Vec<SpnFsckEntry>, OperationError> {
+        let mut audit = AuditScope::new("spn_fsck_repair", eventid, self.log_level.get());
+        // Find what to repair with the same classification the report uses.
+        let idms_prox_read = self.idms.proxy_read_async().await;
+        let found = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_write::handle<SpnFsckMessage>",
+            || {
+                let ev = Event::from_ro_uat(&mut audit, &idms_prox_read.qs_read, uat.as_ref())?;
+                idms_prox_read.qs_read.spn_fsck_report(&mut audit, &ev)
+            }
+        );
+        std::mem::drop(idms_prox_read);
+
+        let res = match found {
+            Ok(found) => {
+                let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
+                lperf_op_segment!(
+                    &mut audit,
+                    "actors::v1_write::handle<SpnFsckRepairMessage>",
+                    || {
+                        let repairable: Vec<_> =
+                            found.into_iter().filter(|f| f.is_repairable()).collect();
+                        let uuids = repairable
+                            .iter()
+                            .map(|f| Uuid::parse_str(f.uuid.as_str()))
+                            .collect::<Result<Vec<_>, _>>()
+                            .map_err(|_| OperationError::InvalidUuid)?;
+
+                        let ev = Event::from_rw_uat(
+                            &mut audit,
+                            &idms_prox_write.qs_write,
+                            uat.as_ref(),
+                        )?;
+                        idms_prox_write
+                            .qs_write
+                            .spn_fsck_repair(&mut audit, &ev, &uuids)
+                            .and_then(|_| idms_prox_write.commit(&mut audit))
+                            .map(|_| repairable)
+                    }
+                )
+            }
+            Err(e) => Err(e),
+        };
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    pub async fn handle_spnunlock(
+        &self,
+        uat: Option<UserAuthToken>,
+        filter: ProtoFilter,
+        eventid: Uuid,
+    ) -> Result<usize, OperationError> {
+        let mut audit = AuditScope::new("spn_unlock", eventid, self.log_level.get());
+        let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_write::handle<SpnUnlockMessage>",
+            || {
+                let ev = Event::from_rw_uat(&mut audit, &idms_prox_write.qs_write, uat.as_ref())?;
+                let filter = Filter::from_rw(&mut audit, &ev, &filter, &idms_prox_write.qs_write)?;
+                idms_prox_write
+                    .qs_write
+                    .spn_unlock(&mut audit, &ev, filter)
+                    .and_then(|count| idms_prox_write.commit(&mut audit).map(|_| count))
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -664,7 +785,7 @@ impl QueryServerWriteV1 {
         filter: Filter<FilterInvalid>,
         eventid: Uuid,
     ) -> Result<(), OperationError> {
-        let mut audit = AuditScope::new("purge_attribute", eventid, self.log_level);
+        let mut audit = AuditScope::new("purge_attribute", eventid, self.log_level.get());
         let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
         let res = lperf_op_segment!(
             &mut audit,
@@ -701,7 +822,7 @@ impl QueryServerWriteV1 {
                     .and_then(|_| idms_prox_write.commit(&mut audit).map(|_| ()))
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -717,7 +838,7 @@ impl QueryServerWriteV1 {
         filter: Filter<FilterInvalid>,
         eventid: Uuid,
     ) -> Result<(), OperationError> {
-        let mut audit = AuditScope::new("remove_attribute_values", eventid, self.log_level);
+        let mut audit = AuditScope::new("remove_attribute_values", eventid, self.log_level.get());
         let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
         let res = lperf_op_segment!(
             &mut audit,
@@ -761,7 +882,7 @@ impl QueryServerWriteV1 {
                     .and_then(|_| idms_prox_write.commit(&mut audit).map(|_| ()))
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -777,7 +898,7 @@ impl QueryServerWriteV1 {
         filter: Filter<FilterInvalid>,
         eventid: Uuid,
     ) -> Result<(), OperationError> {
-        let mut audit = AuditScope::new("append_attribute", eventid, self.log_level);
+        let mut audit = AuditScope::new("append_attribute", eventid, self.log_level.get());
         // We need to turn these into proto modlists so they can be converted
         // and validated.
         let proto_ml = ProtoModifyList::new_list(
@@ -796,7 +917,7 @@ impl QueryServerWriteV1 {
                 filter,
             )
             .await;
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -812,7 +933,7 @@ impl QueryServerWriteV1 {
         filter: Filter<FilterInvalid>,
         eventid: Uuid,
     ) -> Result<(), OperationError> {
-        let mut audit = AuditScope::new("set_attribute", eventid, self.log_level);
+        let mut audit = AuditScope::new("set_attribute", eventid, self.log_level.get());
         // We need to turn these into proto modlists so they can be converted
         // and validated.
         let proto_ml = ProtoModifyList::new_list(
@@ -834,7 +955,112 @@ impl QueryServerWriteV1 {
                 filter,
             )
             .await;
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    /// Set the spn of an account and lock it so that it's never regenerated, or
+    /// with `None` remove the lock so the spn is generated again.
+    pub async fn handle_accountspnlock(
+        &self,
+        uat: Option<UserAuthToken>,
+        uuid_or_name: String,
+        spn: Option<String>,
+        filter: Filter<FilterInvalid>,
+        eventid: Uuid,
+    ) -> Result<(), OperationError> {
+        let mut audit = AuditScope::new("account_spn_lock", eventid, self.log_level.get());
+        // As proto modifies, the spn is validated by schema. Purging the spn on
+        // unlock causes the spn plugin to generate it again.
+        let mods = match spn {
+            Some(spn) => vec![
+                ProtoModify::Purged("spn_locked".to_string()),
+                ProtoModify::Present("spn_locked".to_string(), "true".to_string()),
+                ProtoModify::Purged("spn".to_string()),
+                ProtoModify::Present("spn".to_string(), spn),
+            ],
+            None => vec![
+                ProtoModify::Purged("spn_locked".to_string()),
+                ProtoModify::Purged("spn".to_string()),
+            ],
+        };
+        let proto_ml = ProtoModifyList::new_list(mods);
+        let res = self
+            .modify_from_parts(
+                &mut audit,
+                "actors::v1_write::handle<AccountSpnLockMessage>",
+                uat,
+                &uuid_or_name,
+                &proto_ml,
+                filter,
+            )
+            .await;
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    /// Regenerate the spn of a single account, reporting it before and after.
+    pub async fn handle_accountspnregenerate(
+        &self,
+        uat: Option<UserAuthToken>,
+        uuid_or_name: String,
+        eventid: Uuid,
+    ) -> Result<SpnRegenerateResult, OperationError> {
+        let mut audit = AuditScope::new("account_spn_regenerate", eventid, self.log_level.get());
+        let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_write::handle<AccountSpnRegenerateMessage>",
+            || {
+                let target_uuid = idms_prox_write
+                    .qs_write
+                    .name_to_uuid(&mut audit, uuid_or_name.as_str())
+                    .map_err(|e| {
+                        ladmin_error!(audit, "Error resolving id to target");
+                        e
+                    })?;
+
+                let ev = Event::from_rw_uat(&mut audit, &idms_prox_write.qs_write, uat.as_ref())?;
+                let res = idms_prox_write
+                    .qs_write
+                    .spn_regenerate(&mut audit, &ev, &target_uuid)?;
+                idms_prox_write.commit(&mut audit).map(|_| res)
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+            OperationError::InvalidState
+        })?;
+        res
+    }
+
+    /// Replace the spn settings of the domain, regenerating spns as needed.
+    pub async fn handle_spnconfigimport(
+        &self,
+        uat: Option<UserAuthToken>,
+        config: SpnConfig,
+        eventid: Uuid,
+    ) -> Result<(), OperationError> {
+        let mut audit = AuditScope::new("spn_config_import", eventid, self.log_level.get());
+        let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
+        let res = lperf_op_segment!(
+            &mut audit,
+            "actors::v1_write::handle<SpnConfigImportMessage>",
+            || {
+                let ev = Event::from_rw_uat(&mut audit, &idms_prox_write.qs_write, uat.as_ref())?;
+                idms_prox_write
+                    .qs_write
+                    .spn_config_import(&mut audit, &ev, &config)?;
+                idms_prox_write.commit(&mut audit)
+            }
+        );
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -850,7 +1076,7 @@ impl QueryServerWriteV1 {
         filter: Filter<FilterInvalid>,
         eventid: Uuid,
     ) -> Result<(), OperationError> {
-        let mut audit = AuditScope::new("internal_sshkey_create", eventid, self.log_level);
+        let mut audit = AuditScope::new("internal_sshkey_create", eventid, self.log_level.get());
         // Because this is from internal, we can generate a real modlist, rather
         // than relying on the proto ones.
         let ml = ModifyList::new_append("ssh_publickey", Value::new_sshkey(tag, key));
@@ -865,7 +1091,7 @@ impl QueryServerWriteV1 {
                 filter,
             )
             .await;
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -878,7 +1104,7 @@ impl QueryServerWriteV1 {
         uuid_or_name: String,
         eventid: Uuid,
     ) -> Result<(), OperationError> {
-        let mut audit = AuditScope::new("idm_account_person_extend", eventid, self.log_level);
+        let mut audit = AuditScope::new("idm_account_person_extend", eventid, self.log_level.get());
         // The filter_map here means we only create the mods if the gidnumber or shell are set
         // in the actual request.
         // NOTE: This is an iter for future requirements to be added
@@ -903,7 +1129,7 @@ impl QueryServerWriteV1 {
                 filter,
             )
             .await;
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -918,7 +1144,7 @@ impl QueryServerWriteV1 {
         eventid: Uuid,
     ) -> Result<(), OperationError> {
         let AccountUnixExtend { gidnumber, shell } = ux;
-        let mut audit = AuditScope::new("idm_account_unix_extend", eventid, self.log_level);
+        let mut audit = AuditScope::new("idm_account_unix_extend", eventid, self.log_level.get());
         // The filter_map here means we only create the mods if the gidnumber or shell are set
         // in the actual request.
         let mods: Vec<_> = iter::once(Some(Modify::Present(
@@ -956,7 +1182,7 @@ impl QueryServerWriteV1 {
                 filter,
             )
             .await;
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -970,7 +1196,7 @@ impl QueryServerWriteV1 {
         gx: GroupUnixExtend,
         eventid: Uuid,
     ) -> Result<(), OperationError> {
-        let mut audit = AuditScope::new("idm_group_unix_extend", eventid, self.log_level);
+        let mut audit = AuditScope::new("idm_group_unix_extend", eventid, self.log_level.get());
         // The filter_map here means we only create the mods if the gidnumber or shell are set
         // in the actual request.
         let mods: Vec<_> = iter::once(Some(Modify::Present(
@@ -997,7 +1223,7 @@ impl QueryServerWriteV1 {
                 filter,
             )
             .await;
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -1011,7 +1237,7 @@ impl QueryServerWriteV1 {
         cred: String,
         eventid: Uuid,
     ) -> Result<(), OperationError> {
-        let mut audit = AuditScope::new("idm_account_unix_set_cred", eventid, self.log_level);
+        let mut audit = AuditScope::new("idm_account_unix_set_cred", eventid, self.log_level.get());
         let ct = duration_from_epoch_now();
         let mut idms_prox_write = self.idms.proxy_write_async(ct).await;
         let res = lperf_op_segment!(
@@ -1047,7 +1273,7 @@ impl QueryServerWriteV1 {
                     .map(|_| ())
             }
         );
-        self.log.send(audit).map_err(|_| {
+        self.log.send(audit.finish()).map_err(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
             OperationError::InvalidState
         })?;
@@ -1056,7 +1282,7 @@ impl QueryServerWriteV1 {
 
     // ===== These below are internal only event types. =====
     pub(crate) async fn handle_purgetombstoneevent(&self, msg: PurgeTombstoneEvent) {
-        let mut audit = AuditScope::new("purge tombstones", msg.eventid, self.log_level);
+        let mut audit = AuditScope::new("purge tombstones", msg.eventid, self.log_level.get());
 
         ltrace!(audit, "Begin purge tombstone event {:?}", msg);
         let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
@@ -1075,13 +1301,13 @@ impl QueryServerWriteV1 {
             }
         );
         // At the end of the event we send it for logging.
-        self.log.send(audit).unwrap_or_else(|_| {
+        self.log.send(audit.finish()).unwrap_or_else(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
         });
     }
 
     pub(crate) async fn handle_purgerecycledevent(&self, msg: PurgeRecycledEvent) {
-        let mut audit = AuditScope::new("purge recycled", msg.eventid, self.log_level);
+        let mut audit = AuditScope::new("purge recycled", msg.eventid, self.log_level.get());
         ltrace!(audit, "Begin purge recycled event {:?}", msg);
         let idms_prox_write = self.idms.proxy_write_async(duration_from_epoch_now()).await;
         lperf_op_segment!(
@@ -1098,14 +1324,49 @@ impl QueryServerWriteV1 {
             }
         );
         // At the end of the event we send it for logging.
-        self.log.send(audit).unwrap_or_else(|_| {
+        self.log.send(audit.finish()).unwrap_or_else(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+        });
+    }
+
+    pub(crate) async fn handle_db_maintenance(&self, msg: DbMaintenanceEvent) {
+        let mut audit = AuditScope::new("db maintenance", msg.eventid, self.log_level.get());
+        ladmin_info!(audit, "Begin db maintenance (vacuum: {})", msg.vacuum);
+        match self.idms.db_maintenance_async(&mut audit, msg.vacuum).await {
+            Ok(stats) => ladmin_info!(
+                audit,
+                "Db maintenance complete, reclaimed {} bytes. Pages {} -> {}, free pages {} -> {}",
+                stats.reclaimed_bytes(),
+                stats.pages_before,
+                stats.pages_after,
+                stats.free_pages_before,
+                stats.free_pages_after
+            ),
+            Err(e) => ladmin_error!(audit, "Db maintenance failed -> {:?}", e),
+        }
+        self.log.send(audit.finish()).unwrap_or_else(|_| {
+            error!("CRITICAL: UNABLE TO COMMIT LOGS");
+        });
+    }
+
+    pub(crate) async fn handle_spn_regen(&self) {
+        if !self.idms.spn_regen_pending() {
+            return;
+        }
+        let mut audit = AuditScope::new("spn regen", Uuid::new_v4(), self.log_level.get());
+        ladmin_info!(audit, "Begin regenerating spns after a domain rename");
+        // The spns still pending are tried again on the next interval.
+        if let Err(e) = self.idms.spn_regenerate_chunked_async(&mut audit).await {
+            ladmin_error!(audit, "spn regeneration failed -> {:?}", e);
+        }
+        self.log.send(audit.finish()).unwrap_or_else(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
         });
     }
 
     pub(crate) async fn handle_delayedaction(&self, da: DelayedAction) {
         let eventid = Uuid::new_v4();
-        let mut audit = AuditScope::new("delayed action", eventid, self.log_level);
+        let mut audit = AuditScope::new("delayed action", eventid, self.log_level.get());
         ltrace!(audit, "Begin delayed action ...");
         let ct = duration_from_epoch_now();
         let mut idms_prox_write = self.idms.proxy_write_async(ct).await;
@@ -1121,7 +1382,7 @@ impl QueryServerWriteV1 {
                 }
             }
         );
-        self.log.send(audit).unwrap_or_else(|_| {
+        self.log.send(audit.finish()).unwrap_or_else(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
         });
     }
@@ -1,9 +1,18 @@
 use crate::audit::AuditScope;
+use std::time::Duration;
 use tokio::sync::mpsc::UnboundedReceiver as Receiver;
 
-pub(crate) async fn run(mut rx: Receiver<AuditScope>) {
+pub(crate) async fn run(
+    mut rx: Receiver<AuditScope>,
+    slow_operation_threshold: Option<Duration>,
+    log_subsystems: Vec<String>,
+) {
     info!("Log task started ...");
-    while let Some(al) = rx.recv().await {
+    while let Some(mut al) = rx.recv().await {
+        if let Some(msg) = slow_operation_threshold.and_then(|t| al.slow_operation_message(t)) {
+            warn!("{}", msg);
+        }
+        al.retain_subsystems(&log_subsystems);
         al.write_log();
     }
     info!("Log task shutdown complete.");
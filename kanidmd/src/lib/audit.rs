@@ -1,7 +1,10 @@
 use std::fmt;
 // use std::ptr;
 use std::cmp::Ordering;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 
 use chrono::offset::Utc;
@@ -12,6 +15,47 @@ include!("./audit_loglevel.rs");
 
 pub const AUDIT_LINE_SIZE: usize = 512;
 
+// Stored in a LogLevelHandle when the configured log level is in effect.
+const LOG_LEVEL_NOT_RAISED: u64 = u64::MAX;
+
+/// The log level of the running server. This is shared by the actors so that the
+/// level can be raised temporarily, and later restored, without a restart.
+#[derive(Debug, Clone)]
+pub struct LogLevelHandle {
+    configured: Option<u32>,
+    raised: Arc<AtomicU64>,
+}
+
+impl LogLevelHandle {
+    pub fn new(configured: Option<u32>) -> Self {
+        LogLevelHandle {
+            configured,
+            raised: Arc::new(AtomicU64::new(LOG_LEVEL_NOT_RAISED)),
+        }
+    }
+
+    /// The log level that new audit scopes should use.
+    pub fn get(&self) -> Option<u32> {
+        match self.raised.load(AtomicOrdering::Relaxed) {
+            LOG_LEVEL_NOT_RAISED => self.configured,
+            level => Some(level as u32),
+        }
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.raised.load(AtomicOrdering::Relaxed) != LOG_LEVEL_NOT_RAISED
+    }
+
+    pub fn raise(&self, level: u32) {
+        self.raised.store(level as u64, AtomicOrdering::Relaxed);
+    }
+
+    pub fn restore(&self) {
+        self.raised
+            .store(LOG_LEVEL_NOT_RAISED, AtomicOrdering::Relaxed);
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[repr(u32)]
 pub enum LogTag {
@@ -158,6 +202,17 @@ macro_rules! lsecurity_access {
     })
 }
 
+// Tag the audit lines logged while $fun runs with $subsystem, such as the id of a
+// plugin, so that they can be filtered from a busy log.
+macro_rules! lsubsystem_segment {
+    ($audit:expr, $subsystem:expr, $fun:expr) => {{
+        let prev = $audit.enter_subsystem($subsystem);
+        let r = $fun();
+        $audit.exit_subsystem(prev);
+        r
+    }};
+}
+
 macro_rules! lperf_op_segment {
     ($audit:expr, $id:expr, $fun:expr) => {{
         use crate::audit::LogTag;
@@ -270,6 +325,8 @@ macro_rules! try_audit {
 #[derive(Debug, Serialize, Deserialize)]
 struct AuditLog {
     tag: LogTag,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subsystem: Option<String>,
     data: String,
 }
 
@@ -399,12 +456,24 @@ pub struct AuditScope {
     #[serde(skip_serializing)]
     pub level: u32,
     pub uuid: Uuid,
+    // The type of operation, and when it began.
+    #[serde(skip_serializing)]
+    name: String,
+    #[serde(skip_serializing)]
+    start: Instant,
+    // How long the operation took, recorded when it finished, so that the time
+    // spent waiting to be logged isn't counted.
+    #[serde(skip_serializing)]
+    duration: Option<Duration>,
     events: Vec<AuditLog>,
     #[allow(clippy::vec_box)]
     perf: Vec<Box<PerfEvent>>,
     // active perf event
     #[serde(skip_serializing)]
     active_perf: Option<&'static mut PerfEvent>,
+    // The subsystem that new events are tagged with.
+    #[serde(skip_serializing)]
+    subsystem: Option<&'static str>,
 }
 
 impl AuditScope {
@@ -437,6 +506,7 @@ impl AuditScope {
             let datetime: DateTime<Utc> = t_now.into();
             events.push(AuditLog {
                 tag: LogTag::AdminInfo,
+                subsystem: None,
                 data: format!("{} {}", name, datetime.to_rfc3339()),
             })
         }
@@ -444,17 +514,64 @@ impl AuditScope {
         AuditScope {
             level,
             uuid: eventid,
+            name: name.to_string(),
+            start: Instant::now(),
+            duration: None,
             events,
             perf: vec![],
             active_perf: None,
+            subsystem: None,
+        }
+    }
+
+    /// The time since this operation began.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Record that the operation has finished, before it is sent to be logged.
+    pub fn finish(mut self) -> Self {
+        if self.duration.is_none() {
+            self.duration = Some(self.elapsed());
         }
+        self
+    }
+
+    /// If this operation took longer than threshold, a message describing the
+    /// slow operation. An operation that wasn't finished is measured until now.
+    pub fn slow_operation_message(&self, threshold: Duration) -> Option<String> {
+        let elapsed = self.duration.unwrap_or_else(|| self.elapsed());
+        if elapsed > threshold {
+            Some(format!(
+                "[{}] slow operation {} took {:?} (threshold {:?})",
+                self.uuid.to_hyphenated_ref(),
+                self.name,
+                elapsed,
+                threshold
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Only keep the events tagged with one of these subsystems. An empty list
+    /// keeps every event.
+    pub fn retain_subsystems(&mut self, subsystems: &[String]) {
+        if subsystems.is_empty() {
+            return;
+        }
+        self.events.retain(|e| match &e.subsystem {
+            Some(s) => subsystems.iter().any(|f| f == s),
+            None => false,
+        });
     }
 
     pub fn write_log(self) {
         let uuid_ref = self.uuid.to_hyphenated_ref();
-        self.events
-            .iter()
-            .for_each(|e| eprintln!("[{} {}] {}", uuid_ref, e.tag, e.data));
+        self.events.iter().for_each(|e| match &e.subsystem {
+            Some(s) => eprintln!("[{} {} {}] {}", uuid_ref, e.tag, s, e.data),
+            None => eprintln!("[{} {}] {}", uuid_ref, e.tag, e.data),
+        });
 
         // First, we pre-process all the perf events to order them
         let mut proc_perf: Vec<_> = self.perf.iter().map(|pe| pe.process()).collect();
@@ -479,10 +596,21 @@ impl AuditScope {
         self.events.push(AuditLog {
             // time: datetime.to_rfc3339(),
             tag,
+            subsystem: self.subsystem.map(str::to_string),
             data,
         })
     }
 
+    /// Tag new events with this subsystem, returning the previous one to restore
+    /// with exit_subsystem.
+    pub(crate) fn enter_subsystem(&mut self, subsystem: &'static str) -> Option<&'static str> {
+        self.subsystem.replace(subsystem)
+    }
+
+    pub(crate) fn exit_subsystem(&mut self, prev: Option<&'static str>) {
+        self.subsystem = prev;
+    }
+
     #[allow(clippy::unreachable)]
     pub(crate) unsafe fn new_perfevent(&mut self, id: &str) -> &'static mut PerfEvent {
         // Does an active event currently exist?
@@ -547,7 +675,8 @@ impl AuditScope {
 
 #[cfg(test)]
 mod tests {
-    use crate::audit::AuditScope;
+    use crate::audit::{AuditScope, LogLevel, LogLevelHandle};
+    use std::time::Duration;
 
     // Create and remove. Perhaps add some core details?
     #[test]
@@ -556,4 +685,72 @@ mod tests {
         let d = serde_json::to_string_pretty(&au).expect("Json serialise failure");
         debug!("{}", d);
     }
+
+    #[test]
+    fn test_audit_subsystem_filter() {
+        let mut au = AuditScope::new("au", uuid::Uuid::new_v4(), None);
+        ladmin_info!(au, "untagged");
+        lsubsystem_segment!(au, "plugin_spn", || {
+            ladmin_info!(au, "spn");
+            lsubsystem_segment!(au, "plugin_base", || ladmin_info!(au, "base"));
+            ladmin_info!(au, "spn again");
+        });
+        ladmin_info!(au, "untagged again");
+
+        // The tag is part of the json export.
+        let d = serde_json::to_string(&au).expect("Json serialise failure");
+        assert!(d.contains("\"subsystem\":\"plugin_spn\""));
+
+        au.retain_subsystems(&["plugin_spn".to_string()]);
+        let data: Vec<&str> = au.events.iter().map(|e| e.data.as_str()).collect();
+        assert!(data == vec!["spn", "spn again"]);
+        assert!(au
+            .events
+            .iter()
+            .all(|e| e.subsystem.as_deref() == Some("plugin_spn")));
+    }
+
+    #[test]
+    fn test_audit_slow_operation() {
+        let au = AuditScope::new("slow_op", uuid::Uuid::new_v4(), None);
+        std::thread::sleep(Duration::from_millis(50));
+        let msg = au
+            .slow_operation_message(Duration::from_millis(10))
+            .expect("Slow operation not detected");
+        assert!(msg.contains("slow_op"));
+        assert!(msg.contains(au.uuid.to_hyphenated_ref().to_string().as_str()));
+        // Under the threshold, nothing is reported.
+        assert!(au
+            .slow_operation_message(Duration::from_secs(3600))
+            .is_none());
+
+        // The time spent waiting to be logged after finishing isn't counted.
+        let au = AuditScope::new("fast_op", uuid::Uuid::new_v4(), None).finish();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(au
+            .slow_operation_message(Duration::from_millis(10))
+            .is_none());
+    }
+    #[test]
+    fn test_audit_log_level_handle() {
+        let handle = LogLevelHandle::new(Some(LogLevel::Quiet as u32));
+        // Clones observe changes, as the actors each hold one.
+        let actor_handle = handle.clone();
+        assert!(actor_handle.get() == Some(LogLevel::Quiet as u32));
+        assert!(!actor_handle.is_raised());
+
+        handle.raise(LogLevel::Verbose as u32);
+        assert!(actor_handle.get() == Some(LogLevel::Verbose as u32));
+        assert!(actor_handle.is_raised());
+
+        handle.restore();
+        assert!(actor_handle.get() == Some(LogLevel::Quiet as u32));
+
+        // With no configured level, the default is restored.
+        let handle = LogLevelHandle::new(None);
+        handle.raise(LogLevel::FullTrace as u32);
+        assert!(handle.get() == Some(LogLevel::FullTrace as u32));
+        handle.restore();
+        assert!(handle.get().is_none());
+    }
 }
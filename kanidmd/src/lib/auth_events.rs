@@ -0,0 +1,133 @@
+// Record the outcome of authentications in memory, so that they can be
+// exported later for analysis, such as when investigating an incident.
+//
+// Only the most recent events are kept, bounded by both the configured
+// retention and AUTH_EVENT_LOG_MAX. Events are not persisted, so they are lost
+// when the server restarts.
+use crate::constants::AUTH_EVENT_LOG_MAX;
+use crate::idm::AuthState;
+
+use kanidm_proto::v1::{AuthEventQuery, AuthEventRecord, AuthEventResult};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub struct AuthEventLog {
+    retention: Duration,
+    events: Mutex<VecDeque<AuthEventRecord>>,
+}
+
+impl AuthEventLog {
+    /// A retention of zero disables recording.
+    pub fn new(retention: Duration) -> Self {
+        AuthEventLog {
+            retention,
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Record the state an authentication has reached, if it has finished.
+    pub(crate) fn record(&self, account: &str, sessionid: Uuid, state: &AuthState, ct: Duration) {
+        let (result, reason) = match state {
+            AuthState::Success(_) => (AuthEventResult::Success, None),
+            AuthState::Denied(reason) => (AuthEventResult::Failure, Some(reason.clone())),
+            AuthState::Choose(_) | AuthState::Continue(_) => return,
+        };
+        self.push(
+            AuthEventRecord {
+                time: ct.as_secs(),
+                account: account.to_string(),
+                result,
+                reason,
+                sessionid,
+            },
+            ct,
+        );
+    }
+
+    fn push(&self, record: AuthEventRecord, ct: Duration) {
+        if self.retention.as_secs() == 0 {
+            return;
+        }
+        #[allow(clippy::expect_used)]
+        let mut events = self.events.lock().expect("auth event log poisoned");
+        events.push_back(record);
+
+        // Events are recorded in time order, so the expired ones are at the front.
+        let expire = ct.as_secs().saturating_sub(self.retention.as_secs());
+        while events.front().map(|e| e.time < expire).unwrap_or(false) {
+            events.pop_front();
+        }
+        if events.len() > AUTH_EVENT_LOG_MAX {
+            let dropped = events.len() - AUTH_EVENT_LOG_MAX;
+            events.drain(..dropped);
+        }
+    }
+
+    pub(crate) fn query(&self, q: &AuthEventQuery) -> Vec<AuthEventRecord> {
+        #[allow(clippy::expect_used)]
+        let events = self.events.lock().expect("auth event log poisoned");
+        events
+            .iter()
+            .filter(|e| e.time >= q.since)
+            .filter(|e| q.account.as_ref().map(|a| a == &e.account).unwrap_or(true))
+            .filter(|e| q.result.map(|r| r == e.result).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AuthEventLog;
+    use crate::idm::AuthState;
+    use kanidm_proto::v1::{AuthEventQuery, AuthEventResult};
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    fn query(since: u64, account: Option<&str>, result: Option<AuthEventResult>) -> AuthEventQuery {
+        AuthEventQuery {
+            since,
+            account: account.map(|a| a.to_string()),
+            result,
+        }
+    }
+
+    #[test]
+    fn test_auth_event_log() {
+        let log = AuthEventLog::new(Duration::from_secs(100));
+        let denied = AuthState::Denied("incorrect password".to_string());
+
+        log.record("alice", Uuid::new_v4(), &denied, Duration::from_secs(10));
+        // Authentications that are still in progress are not recorded.
+        log.record(
+            "alice",
+            Uuid::new_v4(),
+            &AuthState::Choose(Vec::new()),
+            Duration::from_secs(20),
+        );
+        log.record("bob", Uuid::new_v4(), &denied, Duration::from_secs(30));
+
+        assert!(log.query(&query(0, None, None)).len() == 2);
+        let r = log.query(&query(0, Some("alice"), Some(AuthEventResult::Failure)));
+        assert!(r.len() == 1);
+        assert!(r[0].time == 10);
+        assert!(r[0].reason.as_deref() == Some("incorrect password"));
+        assert!(log
+            .query(&query(0, None, Some(AuthEventResult::Success)))
+            .is_empty());
+        assert!(log.query(&query(20, None, None)).len() == 1);
+
+        // Once the retention has passed, the older events are dropped.
+        log.record("bob", Uuid::new_v4(), &denied, Duration::from_secs(125));
+        let r = log.query(&query(0, None, None));
+        assert!(r.len() == 2);
+        assert!(r.iter().all(|e| e.account == "bob"));
+
+        // A retention of zero records nothing.
+        let disabled = AuthEventLog::new(Duration::from_secs(0));
+        disabled.record("alice", Uuid::new_v4(), &denied, Duration::from_secs(10));
+        assert!(disabled.query(&query(0, None, None)).is_empty());
+    }
+}
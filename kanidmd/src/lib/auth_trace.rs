@@ -0,0 +1,259 @@
+// Record every step of the authentications of an account, and the server's
+// response to each, so that a maintainer can see exactly what happened when a
+// user reports a login failure that can't be reproduced.
+//
+// A trace is started by an administrator for a single account, and only records
+// until it ends, which is at most auth_trace_max_duration after it started. Only
+// the type of a credential is recorded, never its value, and the token issued by
+// a successful authentication isn't recorded. Traces are only held in memory.
+use crate::constants::AUTH_TRACE_STEPS_MAX;
+use crate::event::{AuthEventStep, AuthResult};
+use crate::idm::AuthState;
+
+use kanidm_proto::v1::{AuthAllowed, AuthCredential, AuthTrace, AuthTraceStep, OperationError};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Default)]
+struct AuthTraces {
+    // By the account name given when the trace was started.
+    traces: BTreeMap<String, AuthTrace>,
+    // The sessions that are being traced, and the account each belongs to.
+    sessions: BTreeMap<Uuid, String>,
+}
+
+pub struct AuthTraceLog {
+    max_duration: Duration,
+    inner: Mutex<AuthTraces>,
+}
+
+impl AuthTraceLog {
+    /// A max duration of zero disables tracing.
+    pub fn new(max_duration: Duration) -> Self {
+        AuthTraceLog {
+            max_duration,
+            inner: Mutex::new(AuthTraces::default()),
+        }
+    }
+
+    /// Trace the account for duration, replacing any earlier trace of it.
+    pub(crate) fn start(
+        &self,
+        account: &str,
+        duration: Duration,
+        ct: Duration,
+    ) -> Result<(), OperationError> {
+        if self.max_duration.as_secs() == 0 {
+            return Err(OperationError::AuthTraceDisabled);
+        }
+        if duration > self.max_duration {
+            return Err(OperationError::AuthTraceTooLong(
+                self.max_duration.as_secs(),
+            ));
+        }
+        #[allow(clippy::expect_used)]
+        let mut inner = self.inner.lock().expect("auth trace log poisoned");
+        inner.sessions.retain(|_, a| a != account);
+        inner.traces.insert(
+            account.to_string(),
+            AuthTrace {
+                account: account.to_string(),
+                started: ct.as_secs(),
+                until: (ct + duration).as_secs(),
+                steps: Vec::new(),
+            },
+        );
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, account: &str) -> Option<AuthTrace> {
+        #[allow(clippy::expect_used)]
+        let inner = self.inner.lock().expect("auth trace log poisoned");
+        inner.traces.get(account).cloned()
+    }
+
+    /// Record a step of an authentication, if its account is being traced.
+    pub(crate) fn record(
+        &self,
+        step: &AuthEventStep,
+        r: &Result<AuthResult, OperationError>,
+        ct: Duration,
+    ) {
+        if self.max_duration.as_secs() == 0 {
+            return;
+        }
+        #[allow(clippy::expect_used)]
+        let mut inner = self.inner.lock().expect("auth trace log poisoned");
+        let AuthTraces { traces, sessions } = &mut *inner;
+
+        let sessionid = r.as_ref().ok().map(|ar| ar.sessionid);
+        let account = match step {
+            AuthEventStep::Init(init) => init.name.clone(),
+            AuthEventStep::Begin(mech) => match sessions.get(&mech.sessionid) {
+                Some(a) => a.clone(),
+                None => return,
+            },
+            AuthEventStep::Cred(creds) => match sessions.get(&creds.sessionid) {
+                Some(a) => a.clone(),
+                None => return,
+            },
+        };
+        let trace = match traces.get_mut(&account) {
+            Some(trace) if ct.as_secs() < trace.until => trace,
+            _ => {
+                if let Some(sessionid) = sessionid {
+                    sessions.remove(&sessionid);
+                }
+                return;
+            }
+        };
+        if trace.steps.len() >= AUTH_TRACE_STEPS_MAX {
+            return;
+        }
+
+        trace.steps.push(AuthTraceStep {
+            time: ct.as_secs(),
+            sessionid,
+            request: describe_step(step),
+            response: describe_result(r),
+        });
+
+        // Follow the session until it finishes.
+        if let Some(sessionid) = sessionid {
+            match r {
+                Ok(AuthResult {
+                    state: AuthState::Choose(_),
+                    ..
+                })
+                | Ok(AuthResult {
+                    state: AuthState::Continue(_),
+                    ..
+                }) => {
+                    sessions.insert(sessionid, account);
+                }
+                _ => {
+                    sessions.remove(&sessionid);
+                }
+            }
+        }
+    }
+}
+
+fn describe_step(step: &AuthEventStep) -> String {
+    match step {
+        AuthEventStep::Init(init) => match &init.appid {
+            Some(appid) => format!("init appid {}", appid),
+            None => "init".to_string(),
+        },
+        AuthEventStep::Begin(mech) => format!("begin {:?}", mech.mech),
+        AuthEventStep::Cred(creds) => match &creds.cred {
+            AuthCredential::Anonymous => "cred anonymous".to_string(),
+            AuthCredential::Password(_) => "cred password (redacted)".to_string(),
+            AuthCredential::Totp(_) => "cred totp (redacted)".to_string(),
+            AuthCredential::Webauthn(_) => "cred webauthn (redacted)".to_string(),
+        },
+    }
+}
+
+fn describe_result(r: &Result<AuthResult, OperationError>) -> String {
+    match r {
+        Ok(ar) => match &ar.state {
+            AuthState::Choose(mechs) => format!("choose {:?}", mechs),
+            AuthState::Continue(allowed) => {
+                // A webauthn challenge isn't useful for debugging, so it's left out.
+                let allowed: Vec<_> = allowed
+                    .iter()
+                    .map(|a| match a {
+                        AuthAllowed::Anonymous => "anonymous",
+                        AuthAllowed::Password => "password",
+                        AuthAllowed::Totp => "totp",
+                        AuthAllowed::Webauthn(_) => "webauthn",
+                    })
+                    .collect();
+                format!("continue [{}]", allowed.join(", "))
+            }
+            AuthState::Denied(reason) => format!("denied: {}", reason),
+            AuthState::Success(_) => "success".to_string(),
+        },
+        Err(e) => format!("error: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AuthTraceLog;
+    use crate::event::{AuthEventStep, AuthResult};
+    use crate::idm::AuthState;
+    use kanidm_proto::v1::{AuthMech, OperationError};
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    fn choose(sessionid: Uuid) -> Result<AuthResult, OperationError> {
+        Ok(AuthResult {
+            sessionid,
+            state: AuthState::Choose(vec![AuthMech::Password]),
+            delay: None,
+        })
+    }
+
+    #[test]
+    fn test_auth_trace_log() {
+        let log = AuthTraceLog::new(Duration::from_secs(100));
+        let sid = Uuid::new_v4();
+
+        // Nothing is recorded until a trace is started.
+        log.record(
+            &AuthEventStep::named_init("alice"),
+            &choose(sid),
+            Duration::from_secs(5),
+        );
+        assert!(log.get("alice").is_none());
+
+        assert!(matches!(
+            log.start("alice", Duration::from_secs(101), Duration::from_secs(10)),
+            Err(OperationError::AuthTraceTooLong(100))
+        ));
+        assert!(log
+            .start("alice", Duration::from_secs(50), Duration::from_secs(10))
+            .is_ok());
+
+        log.record(
+            &AuthEventStep::named_init("alice"),
+            &choose(sid),
+            Duration::from_secs(20),
+        );
+        // Other accounts, and sessions started before the trace, are not recorded.
+        log.record(
+            &AuthEventStep::named_init("bob"),
+            &choose(Uuid::new_v4()),
+            Duration::from_secs(20),
+        );
+        log.record(
+            &AuthEventStep::begin_mech(Uuid::new_v4(), AuthMech::Password),
+            &choose(Uuid::new_v4()),
+            Duration::from_secs(20),
+        );
+        // Nor is anything once the trace has ended.
+        log.record(
+            &AuthEventStep::begin_mech(sid, AuthMech::Password),
+            &choose(sid),
+            Duration::from_secs(60),
+        );
+
+        let trace = log.get("alice").expect("must be traced");
+        assert!(trace.started == 10 && trace.until == 60);
+        assert!(trace.steps.len() == 1);
+        assert!(trace.steps[0].request == "init");
+        assert!(trace.steps[0].response == "choose [Password]");
+        assert!(log.get("bob").is_none());
+
+        // A max duration of zero disables tracing.
+        let disabled = AuthTraceLog::new(Duration::from_secs(0));
+        assert!(matches!(
+            disabled.start("alice", Duration::from_secs(10), Duration::from_secs(10)),
+            Err(OperationError::AuthTraceDisabled)
+        ));
+    }
+}
@@ -27,9 +27,17 @@ pub struct DbTotpV1 {
     pub l: String,
     pub k: Vec<u8>,
     pub s: u64,
+    #[serde(default)]
+    pub d: Option<u8>,
     pub a: DbTotpAlgoV1,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbWebauthnBackupV1 {
+    pub e: bool,
+    pub b: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DbWebauthnV1 {
     pub l: String,
@@ -37,6 +45,10 @@ pub struct DbWebauthnV1 {
     pub c: COSEKey,
     pub t: u32,
     pub v: bool,
+    // Not known for credentials registered before it was recorded, until they
+    // are next used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub b: Option<DbWebauthnBackupV1>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
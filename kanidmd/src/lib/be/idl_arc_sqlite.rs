@@ -3,7 +3,7 @@ use crate::be::idl_sqlite::{
     IdlSqlite, IdlSqliteReadTransaction, IdlSqliteTransaction, IdlSqliteWriteTransaction,
 };
 use crate::be::idxkey::{IdlCacheKey, IdlCacheKeyRef, IdlCacheKeyToRef};
-use crate::be::{BackendConfig, IdList, IdRawEntry};
+use crate::be::{BackendConfig, DbMaintenanceStats, IdList, IdRawEntry};
 use crate::entry::{Entry, EntryCommitted, EntrySealed};
 use crate::value::IndexType;
 use crate::value::Value;
@@ -612,6 +612,12 @@ impl<'a> IdlArcSqliteWriteTransaction<'a> {
         *self.maxid = mid;
     }
 
+    /// The number of entries in the database, including those written in this
+    /// transaction.
+    pub fn get_allids_count(&self) -> u64 {
+        (&(*self.allids)).into_iter().count() as u64
+    }
+
     pub fn write_identries<'b, I>(
         &'b mut self,
         au: &mut AuditScope,
@@ -1002,6 +1008,15 @@ impl IdlArcSqlite {
         }
     }
 
+    pub fn maintenance(
+        &self,
+        audit: &mut AuditScope,
+        vacuum: bool,
+    ) -> Result<DbMaintenanceStats, OperationError> {
+        // The caches only hold committed data, so are unaffected.
+        self.db.maintenance(audit, vacuum)
+    }
+
     /*
     pub fn stats_audit(&self, audit: &mut AuditScope) {
         let entry_stats = self.entry_cache.view_stats();
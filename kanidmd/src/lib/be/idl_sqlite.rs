@@ -1,5 +1,5 @@
 use crate::audit::AuditScope;
-use crate::be::{BackendConfig, IdList, IdRawEntry};
+use crate::be::{BackendConfig, DbMaintenanceStats, IdList, IdRawEntry};
 use crate::entry::{Entry, EntryCommitted, EntrySealed};
 use crate::value::{IndexType, Value};
 use idlset::v2::IDLBitRange;
@@ -1355,6 +1355,71 @@ impl IdlSqlite {
         Ok(IdlSqlite { pool })
     }
 
+    // The page size, page count and free page count of the db.
+    fn page_stats(
+        audit: &mut AuditScope,
+        conn: &Connection,
+    ) -> Result<(u64, u64, u64), OperationError> {
+        let get = |audit: &mut AuditScope, pragma: &str| -> Result<u64, OperationError> {
+            conn.pragma_query_value(None, pragma, |row| row.get::<_, i64>(0))
+                .map(|v| v as u64)
+                .map_err(|e| {
+                    ladmin_error!(audit, "rusqlite {} error {:?}", pragma, e);
+                    OperationError::SqliteError
+                })
+        };
+        Ok((
+            get(audit, "page_size")?,
+            get(audit, "page_count")?,
+            get(audit, "freelist_count")?,
+        ))
+    }
+
+    /// Vacuum the db if requested to reclaim free pages, and then checkpoint the
+    /// WAL into the db and truncate it. The checkpoint can't complete while other
+    /// transactions are open.
+    pub fn maintenance(
+        &self,
+        audit: &mut AuditScope,
+        vacuum: bool,
+    ) -> Result<DbMaintenanceStats, OperationError> {
+        #[allow(clippy::expect_used)]
+        let conn = self
+            .pool
+            .try_get()
+            .expect("Unable to get connection from pool!!!");
+
+        let (page_size, pages_before, free_pages_before) = Self::page_stats(audit, &conn)?;
+
+        if vacuum {
+            conn.execute_batch("VACUUM").map_err(|e| {
+                ladmin_error!(audit, "rusqlite vacuum error {:?}", e);
+                OperationError::SqliteError
+            })?;
+        }
+
+        // The first column is set if the checkpoint was blocked by a reader.
+        let busy: i64 = conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))
+            .map_err(|e| {
+                ladmin_error!(audit, "rusqlite wal_checkpoint error {:?}", e);
+                OperationError::SqliteError
+            })?;
+        if busy != 0 {
+            ladmin_warning!(audit, "db checkpoint was blocked and did not complete");
+        }
+
+        let (_, pages_after, free_pages_after) = Self::page_stats(audit, &conn)?;
+
+        Ok(DbMaintenanceStats {
+            page_size,
+            pages_before,
+            pages_after,
+            free_pages_before,
+            free_pages_after,
+        })
+    }
+
     pub(crate) fn get_allids_count(&self, au: &mut AuditScope) -> Result<u64, OperationError> {
         ltrace!(au, "Counting allids...");
         #[allow(clippy::expect_used)]
@@ -1405,4 +1470,36 @@ mod tests {
         let r = be_w.verify();
         assert!(r.len() == 0);
     }
+
+    #[test]
+    fn test_idl_sqlite_maintenance() {
+        let mut audit = AuditScope::new("run_test", uuid::Uuid::new_v4(), None);
+        let cfg = BackendConfig::new_test();
+        let be = IdlSqlite::new(&mut audit, &cfg, false).unwrap();
+
+        // Grow the db, and then free most of what was added.
+        let be_w = be.write();
+        be_w.get_conn()
+            .execute_batch(
+                "CREATE TABLE maint_test (id INTEGER PRIMARY KEY, data BLOB);
+                WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 512)
+                INSERT INTO maint_test SELECT x, randomblob(4096) FROM c;
+                DELETE FROM maint_test;",
+            )
+            .unwrap();
+        be_w.commit(&mut audit).unwrap();
+
+        // Without a vacuum the free pages remain.
+        let stats = be.maintenance(&mut audit, false).unwrap();
+        assert!(stats.free_pages_before > 0);
+        assert!(stats.free_pages_after == stats.free_pages_before);
+        assert!(stats.reclaimed_bytes() == 0);
+
+        let stats = be.maintenance(&mut audit, true).unwrap();
+        assert!(stats.free_pages_after == 0);
+        assert!(stats.pages_after < stats.pages_before);
+        assert!(
+            stats.reclaimed_bytes() == (stats.pages_before - stats.pages_after) * stats.page_size
+        );
+    }
 }
@@ -64,6 +64,22 @@ impl IdxMeta {
     }
 }
 
+/// The size of the database before and after maintenance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbMaintenanceStats {
+    pub page_size: u64,
+    pub pages_before: u64,
+    pub pages_after: u64,
+    pub free_pages_before: u64,
+    pub free_pages_after: u64,
+}
+
+impl DbMaintenanceStats {
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.pages_before.saturating_sub(self.pages_after) * self.page_size
+    }
+}
+
 #[derive(Clone)]
 pub struct BackendConfig {
     path: String,
@@ -511,6 +527,19 @@ pub trait BackendTransaction {
         au: &mut AuditScope,
         erl: &EventLimits,
         filt: &Filter<FilterValidResolved>,
+    ) -> Result<Vec<Entry<EntrySealed, EntryCommitted>>, OperationError> {
+        self.search_size_limited(au, erl, filt, usize::MAX)
+    }
+
+    /// As search, but refuse with SearchSizeLimitExceeded if more than `size_limit`
+    /// entries match. When the filter is fully indexed this is known before any
+    /// entries are loaded.
+    fn search_size_limited(
+        &self,
+        au: &mut AuditScope,
+        erl: &EventLimits,
+        filt: &Filter<FilterValidResolved>,
+        size_limit: usize,
     ) -> Result<Vec<Entry<EntrySealed, EntryCommitted>>, OperationError> {
         // Unlike DS, even if we don't get the index back, we can just pass
         // to the in-memory filter test and be done.
@@ -560,6 +589,14 @@ pub trait BackendTransaction {
                         ladmin_error!(au, "filter (search) is indexed and greater than search_max_results allowed by resource limits");
                         return Err(OperationError::ResourceLimit);
                     }
+                    if !idl_br.below_threshold(size_limit.saturating_add(1)) {
+                        ladmin_warning!(
+                            au,
+                            "filter (search) is indexed and matches more than the size limit of {}",
+                            size_limit
+                        );
+                        return Err(OperationError::SearchSizeLimitExceeded(size_limit));
+                    }
                 }
             };
 
@@ -604,6 +641,15 @@ pub trait BackendTransaction {
                 ladmin_error!(au, "filter (search) is resolved and greater than search_max_results allowed by resource limits");
                 return Err(OperationError::ResourceLimit);
             }
+            if entries_filtered.len() > size_limit {
+                ladmin_warning!(
+                    au,
+                    "filter (search) matches {} entries, more than the size limit of {}",
+                    entries_filtered.len(),
+                    size_limit
+                );
+                return Err(OperationError::SearchSizeLimitExceeded(size_limit));
+            }
 
             Ok(entries_filtered)
         })
@@ -1405,6 +1451,12 @@ impl<'a> BackendWriteTransaction<'a> {
         })
     }
 
+    /// The number of entries stored, including recycled and tombstoned entries
+    /// that have not yet been purged.
+    pub fn get_entry_count(&self) -> u64 {
+        self.get_idlayer().get_allids_count()
+    }
+
     fn reset_db_s_uuid(&self) -> Result<Uuid, OperationError> {
         // The value is missing. Generate a new one and store it.
         let nsid = Uuid::new_v4();
@@ -1530,6 +1582,18 @@ impl Backend {
         }
     }
 
+    /// Vacuum (if requested) and checkpoint the database. No transactions may be
+    /// open while this runs, so the caller must prevent them.
+    pub(crate) fn maintenance(
+        &self,
+        audit: &mut AuditScope,
+        vacuum: bool,
+    ) -> Result<DbMaintenanceStats, OperationError> {
+        lperf_trace_segment!(audit, "be::maintenance", || {
+            self.idlayer.maintenance(audit, vacuum)
+        })
+    }
+
     // Should this actually call the idlayer directly?
     pub fn reset_db_s_uuid(&self, audit: &mut AuditScope) -> Uuid {
         let wr = self.write();
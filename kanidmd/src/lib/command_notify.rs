@@ -0,0 +1,222 @@
+// A queue of notifications that are each delivered by running an external
+// command, shared by the notifiers that tell other systems about events in
+// kanidm.
+//
+// Notifications are queued by the operation that caused them, and delivered by
+// the interval actor, so the command never delays the operation. The queue is
+// bounded, dropping the oldest notifications when it is full. A command that
+// doesn't finish within the timeout is killed, so a hung command can't stop the
+// interval actor.
+use std::collections::VecDeque;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often a running command is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A notification that can be given to the command as its arguments.
+pub(crate) trait CommandArgs {
+    fn command_args(&self) -> Vec<String>;
+}
+
+/// What is done with a notification that the command failed to deliver.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum OnFailure {
+    /// Keep it at the front of the queue, and stop until the next attempt, so
+    /// that notifications are never lost or reordered.
+    Retry,
+    /// Drop it and carry on with the rest.
+    Drop,
+}
+
+pub(crate) struct CommandNotifier<T> {
+    command: String,
+    timeout: Duration,
+    queue_max: usize,
+    on_failure: OnFailure,
+    queue: Mutex<VecDeque<T>>,
+}
+
+impl<T: CommandArgs + Clone + PartialEq> CommandNotifier<T> {
+    pub fn new(command: &str, timeout: Duration, queue_max: usize, on_failure: OnFailure) -> Self {
+        CommandNotifier {
+            command: command.to_string(),
+            timeout,
+            queue_max,
+            on_failure,
+            queue: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Queue the notifications, returning the number of the oldest that were
+    /// dropped because the queue was full.
+    pub fn enqueue<I: IntoIterator<Item = T>>(&self, notifications: I) -> usize {
+        #[allow(clippy::expect_used)]
+        let mut queue = self.queue.lock().expect("notify queue poisoned");
+        queue.extend(notifications);
+        if queue.len() > self.queue_max {
+            let dropped = queue.len() - self.queue_max;
+            queue.drain(..dropped);
+            dropped
+        } else {
+            0
+        }
+    }
+
+    pub fn pending(&self) -> Vec<T> {
+        #[allow(clippy::expect_used)]
+        let queue = self.queue.lock().expect("notify queue poisoned");
+        queue.iter().cloned().collect()
+    }
+
+    /// Deliver the queued notifications in order, calling `failed` with each that
+    /// couldn't be delivered. Returns the number that were delivered.
+    pub fn process<F: FnMut(&T, &str)>(&self, mut failed: F) -> usize {
+        let mut delivered = 0;
+        loop {
+            // Don't hold the lock while the command runs, so whatever queues the
+            // notifications isn't blocked.
+            let notification = {
+                #[allow(clippy::expect_used)]
+                let mut queue = self.queue.lock().expect("notify queue poisoned");
+                let next = match self.on_failure {
+                    OnFailure::Retry => queue.front().cloned(),
+                    OnFailure::Drop => queue.pop_front(),
+                };
+                match next {
+                    Some(n) => n,
+                    None => break,
+                }
+            };
+
+            if let Err(e) = self.run(&notification.command_args()) {
+                failed(&notification, &e);
+                match self.on_failure {
+                    OnFailure::Retry => break,
+                    OnFailure::Drop => continue,
+                }
+            }
+
+            if self.on_failure == OnFailure::Retry {
+                #[allow(clippy::expect_used)]
+                let mut queue = self.queue.lock().expect("notify queue poisoned");
+                // Only remove it if the queue didn't overflow while we were busy.
+                if queue.front() == Some(&notification) {
+                    queue.pop_front();
+                }
+            }
+            delivered += 1;
+        }
+        delivered
+    }
+
+    fn run(&self, args: &[String]) -> Result<(), String> {
+        let mut child = Command::new(&self.command)
+            .args(args)
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Failed to run {} -> {:?}", self.command, e))?;
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                Ok(None) => {
+                    // Reap it as well, so it isn't left as a zombie.
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(format!(
+                        "{} did not finish within {:?}, killed it",
+                        self.command, self.timeout
+                    ));
+                }
+                Err(e) => return Err(format!("Failed to wait for {} -> {:?}", self.command, e)),
+            }
+        };
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("{} exited with {}", self.command, status))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandArgs, CommandNotifier, OnFailure};
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+
+    impl CommandArgs for String {
+        fn command_args(&self) -> Vec<String> {
+            vec![self.clone()]
+        }
+    }
+
+    fn notifier(command: &str, on_failure: OnFailure) -> CommandNotifier<String> {
+        CommandNotifier::new(command, Duration::from_secs(30), 2, on_failure)
+    }
+
+    #[test]
+    fn test_command_notify_queue_max() {
+        let n = notifier("true", OnFailure::Retry);
+        assert!(n.enqueue(vec!["a".to_string(), "b".to_string()]) == 0);
+        // The oldest are dropped.
+        assert!(n.enqueue(vec!["c".to_string()]) == 1);
+        assert!(n.pending() == vec!["b".to_string(), "c".to_string()]);
+    }
+
+    #[test]
+    fn test_command_notify_on_failure() {
+        let items = vec!["a".to_string(), "b".to_string()];
+
+        let mut failures = 0;
+        let retry = notifier("false", OnFailure::Retry);
+        retry.enqueue(items.clone());
+        assert!(retry.process(|_, _| failures += 1) == 0);
+        // Delivery stops at the first failure, which is kept.
+        assert!(failures == 1);
+        assert!(retry.pending() == items);
+
+        let mut failures = 0;
+        let drop = notifier("false", OnFailure::Drop);
+        drop.enqueue(items.clone());
+        assert!(drop.process(|_, _| failures += 1) == 0);
+        assert!(failures == 2);
+        assert!(drop.pending().is_empty());
+
+        let working = notifier("true", OnFailure::Retry);
+        working.enqueue(items);
+        assert!(working.process(|_, _| panic!("must not fail")) == 2);
+        assert!(working.pending().is_empty());
+    }
+
+    #[test]
+    fn test_command_notify_timeout() {
+        // A command that hangs is killed, and counts as a failure.
+        let path =
+            std::env::temp_dir().join(format!("kanidm_command_notify_test_{}", Uuid::new_v4()));
+        std::fs::write(&path, "#!/bin/sh\nexec sleep 60\n").expect("Unable to write script");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .expect("Unable to set permissions");
+
+        let hanging = CommandNotifier::new(
+            path.to_str().expect("Invalid path"),
+            Duration::from_millis(200),
+            2,
+            OnFailure::Retry,
+        );
+        hanging.enqueue(vec!["a".to_string()]);
+        let start = Instant::now();
+        let delivered = hanging.process(|_, _| {});
+        std::fs::remove_file(&path).expect("Unable to remove test script");
+
+        assert!(delivered == 0);
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert!(hanging.pending() == vec!["a".to_string()]);
+    }
+}
@@ -1,6 +1,110 @@
+use crate::audit::LogLevel;
+use crate::constants::{
+    DEFAULT_MAX_CREDENTIAL_SIZE, DEFAULT_MAX_MODIFY_BATCH, DEFAULT_MAX_PASSWORD_SIZE,
+    DEFAULT_SEARCH_RESULT_LIMIT, DEFAULT_SPN_REGEN_CHUNK_SIZE, DEFAULT_WRITE_QUEUE_DEPTH,
+    PW_MIN_LENGTH, UUID_ANONYMOUS, UUID_DOMAIN_INFO,
+};
+use crate::plugins::Plugins;
+use crate::server::SpnSettings;
+use crate::standby::StandbyMonitor;
+use kanidm_proto::v1::{AuthMech, SystemConfig};
 use rand::prelude::*;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::fs::{self, File};
+use std::net::SocketAddr;
+use std::path::Path;
 use std::str::FromStr;
+use uuid::Uuid;
+
+// A rough estimate of the in memory size of a single cached entry. This is
+// used to sanity check db_arc_size against the memory of the system.
+const DB_ARC_ENTRY_SIZE_ESTIMATE: usize = 4096;
+// The db_arc_size should not consume more than 1/DIVISOR of system memory.
+const DB_ARC_MEMORY_DIVISOR: usize = 2;
+// Bounds (in seconds) for a sensible http_request_read_timeout.
+const HTTP_REQUEST_READ_TIMEOUT_MIN: u64 = 1;
+const HTTP_REQUEST_READ_TIMEOUT_MAX: u64 = 300;
+// The largest request body (in bytes) accepted when http_max_body_size is not set.
+const DEFAULT_HTTP_MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+// How many online backups are kept when backup_retention_count is not set.
+const DEFAULT_BACKUP_RETENTION_COUNT: usize = 7;
+// Bounds (in bytes) for a sensible worker_stack_size.
+const WORKER_STACK_SIZE_MIN: usize = 1024 * 1024;
+const WORKER_STACK_SIZE_MAX: usize = 256 * 1024 * 1024;
+// The longest (in seconds) an expired token may be accepted for.
+const TOKEN_EXPIRY_GRACE_MAX: u64 = 300;
+// The hsts max-age (in seconds) when TLS is enabled and hsts_max_age is not set. One year.
+const DEFAULT_HSTS_MAX_AGE: u64 = 31_536_000;
+// Browsers cap the hsts max-age, so longer values are a mistake. Two years.
+const HSTS_MAX_AGE_MAX: u64 = 63_072_000;
+// The reauth_window (in seconds) when reauth_operations is set but it is not.
+const DEFAULT_REAUTH_WINDOW: u64 = 300;
+// Sessions are only valid for an hour, so a longer reauth_window has no effect.
+const REAUTH_WINDOW_MAX: u64 = 3600;
+// The db_maintenance_interval (in seconds) when it is not set. One week.
+const DEFAULT_DB_MAINTENANCE_INTERVAL: u64 = 604_800;
+// Maintenance blocks all database access while it runs, so it should not run
+// more often than hourly. The longest interval is one year.
+const DB_MAINTENANCE_INTERVAL_MIN: u64 = 3600;
+const DB_MAINTENANCE_INTERVAL_MAX: u64 = 31_536_000;
+// How long (in seconds) authentication events are kept for export when
+// auth_event_retention is not set. One day.
+const DEFAULT_AUTH_EVENT_RETENTION: u64 = 86400;
+// Events are only held in memory, so keeping them longer than 30 days is a mistake.
+const AUTH_EVENT_RETENTION_MAX: u64 = 2_592_000;
+// Tracing is for debugging a specific problem, so a trace may last at most one day.
+const AUTH_TRACE_MAX_DURATION_MAX: u64 = 86400;
+// The weakest TLS keys that are accepted, in bits, unless tls_allow_weak_keys is set.
+// The minimums may be raised, but not lowered. These are the common compliance
+// baselines, an RSA key of 2048 bits or an elliptic curve of 256 bits.
+const DEFAULT_TLS_MIN_RSA_BITS: u32 = 2048;
+const DEFAULT_TLS_MIN_EC_BITS: u32 = 256;
+const TLS_MAX_RSA_BITS: u32 = 16384;
+const TLS_MAX_EC_BITS: u32 = 521;
+// The web ui is wasm, which needs 'unsafe-eval' until 'wasm-unsafe-eval' is widely supported.
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; script-src 'self' 'unsafe-eval'; style-src 'self' 'unsafe-inline'; img-src 'self' data:; object-src 'none'; base-uri 'self'; frame-ancestors 'none'";
+
+/// Every authentication mechanism the server implements, before any are disabled.
+pub const ALL_AUTH_MECHS: [AuthMech; 4] = [
+    AuthMech::Anonymous,
+    AuthMech::Password,
+    AuthMech::PasswordMfa,
+    AuthMech::Webauthn,
+];
+
+/// Return the amount of physical memory in this system in bytes, if it
+/// can be determined.
+pub fn system_memory_bytes() -> Option<usize> {
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pages <= 0 || page_size <= 0 {
+        None
+    } else {
+        Some((pages as usize).saturating_mul(page_size as usize))
+    }
+}
+
+// Show a log level by the name it is configured with, where it has one.
+fn log_level_value(level: u32) -> toml::Value {
+    [
+        "quiet",
+        "default",
+        "filter",
+        "verbose",
+        "perfbasic",
+        "perffull",
+        "fulltrace",
+    ]
+    .iter()
+    .find(|name| {
+        LogLevel::from_str(name)
+            .map(|l| l as u32 == level)
+            .unwrap_or(false)
+    })
+    .map(|name| toml::Value::String(name.to_string()))
+    .unwrap_or_else(|| toml::Value::Integer(level as i64))
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct IntegrationTestConfig {
@@ -21,12 +125,36 @@ pub enum ServerRole {
     ReadOnlyReplica,
 }
 
+impl ServerRole {
+    pub fn default_rejection_message(self) -> Option<&'static str> {
+        match self {
+            ServerRole::WriteReplica => None,
+            ServerRole::WriteReplicaNoUI => Some(
+                "This server does not serve the web user interface. Please use a different server.",
+            ),
+            ServerRole::ReadOnlyReplica => Some(
+                "This server is a standby and can not accept changes until it is promoted. Please use the primary.",
+            ),
+        }
+    }
+}
+
 impl Default for ServerRole {
     fn default() -> Self {
         ServerRole::WriteReplica
     }
 }
 
+impl fmt::Display for ServerRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerRole::WriteReplica => write!(f, "write_replica"),
+            ServerRole::WriteReplicaNoUI => write!(f, "write_replica_no_ui"),
+            ServerRole::ReadOnlyReplica => write!(f, "read_only_replica"),
+        }
+    }
+}
+
 impl FromStr for ServerRole {
     type Err = &'static str;
 
@@ -40,23 +168,392 @@ impl FromStr for ServerRole {
     }
 }
 
+/// What the anonymous account may read, in addition to what access controls
+/// permit. Anonymous can always read itself so that whoami continues to work.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnonymousReadScope {
+    All,
+    DomainInfo,
+    Nothing,
+}
+
+impl AnonymousReadScope {
+    /// Is the anonymous account permitted to read the entry with this uuid?
+    pub fn permits(self, uuid: &Uuid) -> bool {
+        match self {
+            AnonymousReadScope::All => true,
+            AnonymousReadScope::DomainInfo => {
+                uuid == &*UUID_DOMAIN_INFO || uuid == &*UUID_ANONYMOUS
+            }
+            AnonymousReadScope::Nothing => uuid == &*UUID_ANONYMOUS,
+        }
+    }
+}
+
+impl Default for AnonymousReadScope {
+    fn default() -> Self {
+        AnonymousReadScope::All
+    }
+}
+
+impl fmt::Display for AnonymousReadScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnonymousReadScope::All => write!(f, "all"),
+            AnonymousReadScope::DomainInfo => write!(f, "domain_info"),
+            AnonymousReadScope::Nothing => write!(f, "nothing"),
+        }
+    }
+}
+
+/// The SameSite attribute of the session cookie, which controls whether browsers
+/// send it with requests from other sites.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieSameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl Default for CookieSameSite {
+    fn default() -> Self {
+        CookieSameSite::Strict
+    }
+}
+
+impl fmt::Display for CookieSameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CookieSameSite::Strict => write!(f, "strict"),
+            CookieSameSite::Lax => write!(f, "lax"),
+            CookieSameSite::None => write!(f, "none"),
+        }
+    }
+}
+
+/// How a create is handled when more than one of the entries it creates has the
+/// same name, which would otherwise give them identical spns.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateNamePolicy {
+    /// Refuse the create.
+    Reject,
+    /// Give each later entry with the name a numbered suffix, such as name_2.
+    Disambiguate,
+}
+
+impl Default for DuplicateNamePolicy {
+    fn default() -> Self {
+        DuplicateNamePolicy::Reject
+    }
+}
+
+impl fmt::Display for DuplicateNamePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DuplicateNamePolicy::Reject => write!(f, "reject"),
+            DuplicateNamePolicy::Disambiguate => write!(f, "disambiguate"),
+        }
+    }
+}
+
+/// What to do when a modify sets the spn of an account or group directly, rather
+/// than leaving it to be generated.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpnDirectWrite {
+    /// Replace it with the generated spn, and log a warning.
+    Overwrite,
+    /// Refuse the modify.
+    Reject,
+}
+
+impl Default for SpnDirectWrite {
+    fn default() -> Self {
+        SpnDirectWrite::Overwrite
+    }
+}
+
+impl fmt::Display for SpnDirectWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpnDirectWrite::Overwrite => write!(f, "overwrite"),
+            SpnDirectWrite::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// How the spns of accounts and groups are regenerated when the domain is renamed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpnRegenMode {
+    /// Regenerate every spn in the same transaction as the rename, so that no
+    /// one ever sees a mix of old and new spns, but other writes wait until it's
+    /// done.
+    Strict,
+    /// Commit the rename, then regenerate the spns in chunks of
+    /// spn_regen_chunk_size, each in its own transaction. Other writes can run
+    /// between the chunks, but until the last one commits some spns are still in
+    /// the old domain.
+    Available,
+}
+
+impl Default for SpnRegenMode {
+    fn default() -> Self {
+        SpnRegenMode::Strict
+    }
+}
+
+impl fmt::Display for SpnRegenMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpnRegenMode::Strict => write!(f, "strict"),
+            SpnRegenMode::Available => write!(f, "available"),
+        }
+    }
+}
+
+/// What to do when the consistency checks run at startup find a problem.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyFailureAction {
+    /// Log the inconsistencies and start anyway.
+    Warn,
+    /// Log the inconsistencies and refuse to start.
+    Refuse,
+}
+
+impl Default for VerifyFailureAction {
+    fn default() -> Self {
+        VerifyFailureAction::Warn
+    }
+}
+
+impl fmt::Display for VerifyFailureAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyFailureAction::Warn => write!(f, "warn"),
+            VerifyFailureAction::Refuse => write!(f, "refuse"),
+        }
+    }
+}
+
+/// The level the spn plugin logs the spns it sets at.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpnLogLevel {
+    Trace,
+    /// Log changed spns at info, up to a limit in each transaction.
+    Info,
+}
+
+impl Default for SpnLogLevel {
+    fn default() -> Self {
+        SpnLogLevel::Trace
+    }
+}
+
+impl fmt::Display for SpnLogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpnLogLevel::Trace => write!(f, "trace"),
+            SpnLogLevel::Info => write!(f, "info"),
+        }
+    }
+}
+
+/// How an internationalised domain name is written in the realm of the spns the
+/// spn plugin generates.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpnIdnMode {
+    /// The domain name as it is, which may contain non-ASCII characters.
+    Unicode,
+    /// The ASCII compatible encoding (punycode) of the domain name, which kerberos
+    /// can handle.
+    Punycode,
+}
+
+impl Default for SpnIdnMode {
+    fn default() -> Self {
+        SpnIdnMode::Unicode
+    }
+}
+
+impl fmt::Display for SpnIdnMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpnIdnMode::Unicode => write!(f, "unicode"),
+            SpnIdnMode::Punycode => write!(f, "punycode"),
+        }
+    }
+}
+
+/// What the spn plugin does when the name of a generated spn has characters that
+/// kerberos doesn't allow in a principal.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpnCharsetPolicy {
+    /// Refuse the create or modify.
+    Reject,
+    /// Replace each of the characters with '_' in the spn. The name is unchanged.
+    Sanitize,
+    /// Generate the spn as it is, and log a warning.
+    Warn,
+}
+
+impl Default for SpnCharsetPolicy {
+    fn default() -> Self {
+        SpnCharsetPolicy::Warn
+    }
+}
+
+impl fmt::Display for SpnCharsetPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpnCharsetPolicy::Reject => write!(f, "reject"),
+            SpnCharsetPolicy::Sanitize => write!(f, "sanitize"),
+            SpnCharsetPolicy::Warn => write!(f, "warn"),
+        }
+    }
+}
+
+/// Sensitive operations that can be configured to need a recent authentication,
+/// even when the session is otherwise still valid.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReauthOperation {
+    /// Changing the credentials of any account, including its own.
+    Credential,
+    /// Changing the domain, or repairing the directory.
+    Admin,
+}
+
+impl fmt::Display for ReauthOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReauthOperation::Credential => write!(f, "credential"),
+            ReauthOperation::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// The spn of the anonymous account, which otherwise is generated like that of
+/// any other account.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnonymousSpn {
+    Generated,
+    /// The anonymous account has no spn.
+    Suppressed,
+    /// The anonymous account always has this name and domain as its spn.
+    Fixed(String, String),
+}
+
+impl Default for AnonymousSpn {
+    fn default() -> Self {
+        AnonymousSpn::Generated
+    }
+}
+
+impl FromStr for AnonymousSpn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "none" {
+            return Ok(AnonymousSpn::Suppressed);
+        }
+        match s
+            .to_lowercase()
+            .rsplitn(2, '@')
+            .collect::<Vec<_>>()
+            .as_slice()
+        {
+            [domain, name] if !name.is_empty() && !domain.is_empty() => {
+                Ok(AnonymousSpn::Fixed(name.to_string(), domain.to_string()))
+            }
+            _ => Err(format!(
+                "anonymous_spn must be \"none\" or in the form name@domain, not {}",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Configuration {
     pub address: String,
     pub ldapaddress: Option<String>,
+    pub ldap_basedn: Option<String>,
     pub threads: usize,
     // db type later
     pub db_path: String,
     pub db_fs_type: Option<String>,
     pub db_arc_size: Option<usize>,
     pub maximum_request: usize,
+    pub max_password_size: usize,
+    pub max_credential_size: usize,
     pub secure_cookies: bool,
+    pub cookie_domain: Option<String>,
+    pub cookie_samesite: CookieSameSite,
     pub tls_config: Option<TlsConfiguration>,
+    pub tls_min_rsa_bits: u32,
+    pub tls_min_ec_bits: u32,
+    pub tls_allow_weak_keys: bool,
     pub cookie_key: [u8; 32],
     pub integration_test_config: Option<Box<IntegrationTestConfig>>,
     pub log_level: Option<u32>,
+    pub log_level_debug: u32,
     pub origin: String,
     pub role: ServerRole,
+    pub no_ui_message: Option<String>,
+    pub read_only_message: Option<String>,
+    pub max_connections_per_ip: Option<usize>,
+    pub http_request_read_timeout: Option<u64>,
+    pub http_max_body_size: usize,
+    pub slow_operation_threshold: Option<u64>,
+    pub anonymous_auth_rate_limit: Option<u32>,
+    pub anonymous_read_scope: AnonymousReadScope,
+    pub backup_path: Option<String>,
+    pub backup_retention_count: usize,
+    pub disabled_auth_mechs: Vec<AuthMech>,
+    pub reserved_spns: Vec<String>,
+    pub trusted_domains: Vec<String>,
+    pub spn_strict_verify: bool,
+    pub duplicate_name_policy: DuplicateNamePolicy,
+    pub spn_direct_write: SpnDirectWrite,
+    pub spn_log_level: SpnLogLevel,
+    pub spn_idn_mode: SpnIdnMode,
+    pub spn_charset_policy: SpnCharsetPolicy,
+    pub spn_regen_mode: SpnRegenMode,
+    pub spn_regen_chunk_size: usize,
+    pub startup_verify: bool,
+    pub on_verify_failure: VerifyFailureAction,
+    pub worker_stack_size: Option<usize>,
+    pub admin_socket_path: Option<String>,
+    pub spn_notify_command: Option<String>,
+    pub token_expiry_grace: u64,
+    pub missing_domain_name: Option<String>,
+    pub security_headers: Option<bool>,
+    pub hsts_max_age: Option<u64>,
+    pub content_security_policy: Option<String>,
+    pub max_entries: Option<u64>,
+    pub reauth_operations: Vec<ReauthOperation>,
+    pub reauth_window: Option<u64>,
+    pub anonymous_spn: Option<String>,
+    pub db_maintenance_interval: u64,
+    pub db_maintenance_vacuum: bool,
+    pub auth_event_retention: u64,
+    pub auth_trace_max_duration: u64,
+    pub detailed_auth_errors: bool,
+    pub lockout_notify_command: Option<String>,
+    pub standby_primary: Option<String>,
+    pub log_subsystems: Vec<String>,
+    pub search_result_limit: usize,
+    pub default_search_attrs: Vec<String>,
+    pub max_modify_batch: usize,
+    pub write_queue_depth: usize,
 }
 
 impl fmt::Display for Configuration {
@@ -66,19 +563,209 @@ impl fmt::Display for Configuration {
                 Some(la) => write!(f, "ldap address: {}, ", la),
                 None => write!(f, "ldap address: disabled, "),
             })
+            .and_then(|_| match &self.ldap_basedn {
+                Some(b) => write!(f, "ldap basedn: {}, ", b),
+                None => write!(f, "ldap basedn: from domain name, "),
+            })
             .and_then(|_| write!(f, "thread count: {}, ", self.threads))
+            .and_then(|_| match &self.admin_socket_path {
+                Some(p) => write!(f, "admin socket: {}, ", p),
+                None => write!(f, "admin socket: disabled, "),
+            })
+            .and_then(|_| match self.worker_stack_size {
+                Some(v) => write!(f, "worker stack size: {}b, ", v),
+                None => write!(f, "worker stack size: system default, "),
+            })
             .and_then(|_| write!(f, "dbpath: {}, ", self.db_path))
             .and_then(|_| match self.db_arc_size {
                 Some(v) => write!(f, "arcsize: {}, ", v),
                 None => write!(f, "arcsize: AUTO, "),
             })
+            .and_then(|_| match self.db_maintenance_interval() {
+                Some(v) => write!(
+                    f,
+                    "db maintenance: every {}s (vacuum: {}), ",
+                    v, self.db_maintenance_vacuum
+                ),
+                None => write!(f, "db maintenance: disabled, "),
+            })
             .and_then(|_| write!(f, "max request size: {}b, ", self.maximum_request))
+            .and_then(|_| write!(f, "max password size: {}b, ", self.max_password_size))
+            .and_then(|_| write!(f, "max credential size: {}b, ", self.max_credential_size))
             .and_then(|_| write!(f, "secure cookies: {}, ", self.secure_cookies))
+            .and_then(|_| match &self.cookie_domain {
+                Some(d) => write!(f, "cookie domain: {}, ", d),
+                None => write!(f, "cookie domain: default, "),
+            })
+            .and_then(|_| write!(f, "cookie samesite: {}, ", self.cookie_samesite))
+            .and_then(|_| write!(f, "token expiry grace: {}s, ", self.token_expiry_grace))
+            .and_then(|_| {
+                if self.reauth_operations.is_empty() {
+                    write!(f, "reauth: disabled, ")
+                } else {
+                    let ops: Vec<String> = self
+                        .reauth_operations
+                        .iter()
+                        .map(|op| op.to_string())
+                        .collect();
+                    write!(
+                        f,
+                        "reauth: {} within {}s, ",
+                        ops.join(" "),
+                        self.reauth_window()
+                    )
+                }
+            })
+            .and_then(|_| match self.auth_event_retention {
+                0 => write!(f, "auth event retention: disabled, "),
+                v => write!(f, "auth event retention: {}s, ", v),
+            })
+            .and_then(|_| match self.auth_trace_max_duration {
+                0 => write!(f, "auth trace: disabled, "),
+                v => write!(f, "auth trace max duration: {}s, ", v),
+            })
+            .and_then(|_| write!(f, "detailed auth errors: {}, ", self.detailed_auth_errors))
+            .and_then(|_| match &self.lockout_notify_command {
+                Some(c) => write!(f, "lockout notify command: {}, ", c),
+                None => write!(f, "lockout notify command: disabled, "),
+            })
+            .and_then(|_| match &self.standby_primary {
+                Some(p) => write!(f, "standby of: {} (manual promotion only), ", p),
+                None => write!(f, "standby: disabled, "),
+            })
+            .and_then(|_| {
+                if self.log_subsystems.is_empty() {
+                    write!(f, "log subsystems: all, ")
+                } else {
+                    write!(f, "log subsystems: {}, ", self.log_subsystems.join(" "))
+                }
+            })
             .and_then(|_| write!(f, "with TLS: {}, ", self.tls_config.is_some()))
+            .and_then(|_| {
+                write!(
+                    f,
+                    "TLS minimum key bits: RSA {} EC {}{}, ",
+                    self.tls_min_rsa_bits,
+                    self.tls_min_ec_bits,
+                    if self.tls_allow_weak_keys {
+                        " (weak keys allowed)"
+                    } else {
+                        ""
+                    }
+                )
+            })
+            .and_then(|_| {
+                let headers = self.http_security_headers();
+                if headers.is_empty() {
+                    write!(f, "security headers: disabled, ")
+                } else {
+                    write!(
+                        f,
+                        "security headers: [{}], ",
+                        headers
+                            .iter()
+                            .map(|(n, v)| format!("{}: {}", n, v))
+                            .collect::<Vec<_>>()
+                            .join("; ")
+                    )
+                }
+            })
+            .and_then(|_| match self.max_connections_per_ip {
+                Some(v) => write!(f, "max connections per ip: {}, ", v),
+                None => write!(f, "max connections per ip: unlimited, "),
+            })
+            .and_then(|_| match self.http_request_read_timeout {
+                Some(v) => write!(f, "http request read timeout: {}s, ", v),
+                None => write!(f, "http request read timeout: disabled, "),
+            })
+            .and_then(|_| write!(f, "http max body size: {}, ", self.http_max_body_size))
+            .and_then(|_| match self.slow_operation_threshold {
+                Some(v) => write!(f, "slow operation threshold: {}ms, ", v),
+                None => write!(f, "slow operation threshold: disabled, "),
+            })
+            .and_then(|_| match self.anonymous_auth_rate_limit {
+                Some(v) => write!(f, "anonymous auth rate limit: {}/min, ", v),
+                None => write!(f, "anonymous auth rate limit: unlimited, "),
+            })
+            .and_then(|_| write!(f, "anonymous read scope: {}, ", self.anonymous_read_scope))
+            .and_then(|_| match &self.backup_path {
+                Some(p) => write!(
+                    f,
+                    "online backups: {} (keeping {}), ",
+                    p, self.backup_retention_count
+                ),
+                None => write!(f, "online backups: disabled, "),
+            })
+            .and_then(|_| {
+                if self.disabled_auth_mechs.is_empty() {
+                    write!(f, "disabled auth mechanisms: none, ")
+                } else {
+                    write!(
+                        f,
+                        "disabled auth mechanisms: {:?}, ",
+                        self.disabled_auth_mechs
+                    )
+                }
+            })
+            .and_then(|_| match &self.missing_domain_name {
+                Some(n) => write!(f, "missing domain info: recreate as {}, ", n),
+                None => write!(f, "missing domain info: refuse to start, "),
+            })
+            .and_then(|_| match self.max_entries {
+                Some(v) => write!(f, "max entries: {}, ", v),
+                None => write!(f, "max entries: unlimited, "),
+            })
+            .and_then(|_| write!(f, "search result limit: {}, ", self.search_result_limit))
+            .and_then(|_| write!(f, "max modify batch: {}, ", self.max_modify_batch))
+            .and_then(|_| write!(f, "write queue depth: {}, ", self.write_queue_depth))
+            .and_then(|_| {
+                if self.default_search_attrs.is_empty() {
+                    write!(f, "default search attrs: all, ")
+                } else {
+                    write!(
+                        f,
+                        "default search attrs: {}, ",
+                        self.default_search_attrs.join(",")
+                    )
+                }
+            })
+            .and_then(|_| match self.anonymous_spn() {
+                AnonymousSpn::Generated => write!(f, "anonymous spn: generated, "),
+                AnonymousSpn::Suppressed => write!(f, "anonymous spn: none, "),
+                AnonymousSpn::Fixed(n, d) => write!(f, "anonymous spn: {}@{}, ", n, d),
+            })
+            .and_then(|_| write!(f, "reserved spns: {}, ", self.reserved_spns.len()))
+            .and_then(|_| write!(f, "trusted domains: {}, ", self.trusted_domains.len()))
+            .and_then(|_| write!(f, "spn strict verify: {}, ", self.spn_strict_verify))
+            .and_then(|_| write!(f, "duplicate name policy: {}, ", self.duplicate_name_policy))
+            .and_then(|_| write!(f, "spn direct write: {}, ", self.spn_direct_write))
+            .and_then(|_| write!(f, "spn log level: {}, ", self.spn_log_level))
+            .and_then(|_| write!(f, "spn idn mode: {}, ", self.spn_idn_mode))
+            .and_then(|_| write!(f, "spn charset policy: {}, ", self.spn_charset_policy))
+            .and_then(|_| match self.spn_regen_mode {
+                SpnRegenMode::Strict => write!(f, "spn regen mode: strict, "),
+                SpnRegenMode::Available => write!(
+                    f,
+                    "spn regen mode: available in chunks of {}, ",
+                    self.spn_regen_chunk_size
+                ),
+            })
+            .and_then(|_| {
+                if self.startup_verify {
+                    write!(f, "startup verify: {}, ", self.on_verify_failure)
+                } else {
+                    write!(f, "startup verify: disabled, ")
+                }
+            })
+            .and_then(|_| match &self.spn_notify_command {
+                Some(c) => write!(f, "spn notify command: {}, ", c),
+                None => write!(f, "spn notify command: disabled, "),
+            })
             .and_then(|_| match self.log_level {
                 Some(u) => write!(f, "with log_level: {:x}, ", u),
                 None => write!(f, "with log_level: default, "),
             })
+            .and_then(|_| write!(f, "debug log_level: {:x}, ", self.log_level_debug))
             .and_then(|_| {
                 write!(
                     f,
@@ -91,26 +778,91 @@ impl fmt::Display for Configuration {
 
 impl Configuration {
     pub fn new() -> Self {
+        Self::new_with_rng(StdRng::from_entropy())
+    }
+
+    /// Create a configuration with a cookie_key derived from seed, so that tests
+    /// depending on it are reproducible. Never use this outside of tests.
+    pub fn new_with_seed(seed: u64) -> Self {
+        Self::new_with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn new_with_rng(mut rng: StdRng) -> Self {
         let mut c = Configuration {
             address: String::from("127.0.0.1:8080"),
             ldapaddress: None,
+            ldap_basedn: None,
             threads: num_cpus::get(),
             db_path: String::from(""),
             db_fs_type: None,
             db_arc_size: None,
             maximum_request: 262_144, // 256k
+            max_password_size: DEFAULT_MAX_PASSWORD_SIZE,
+            max_credential_size: DEFAULT_MAX_CREDENTIAL_SIZE,
             // log type
             // log path
             // TODO #63: default true in prd
             secure_cookies: !cfg!(test),
+            cookie_domain: None,
+            cookie_samesite: CookieSameSite::Strict,
             tls_config: None,
+            tls_min_rsa_bits: DEFAULT_TLS_MIN_RSA_BITS,
+            tls_min_ec_bits: DEFAULT_TLS_MIN_EC_BITS,
+            tls_allow_weak_keys: false,
             cookie_key: [0; 32],
             integration_test_config: None,
             log_level: None,
+            log_level_debug: LogLevel::Verbose as u32,
             origin: "https://idm.example.com".to_string(),
             role: ServerRole::WriteReplica,
+            no_ui_message: None,
+            read_only_message: None,
+            max_connections_per_ip: None,
+            http_request_read_timeout: None,
+            http_max_body_size: DEFAULT_HTTP_MAX_BODY_SIZE,
+            slow_operation_threshold: None,
+            anonymous_auth_rate_limit: None,
+            anonymous_read_scope: AnonymousReadScope::All,
+            backup_path: None,
+            backup_retention_count: DEFAULT_BACKUP_RETENTION_COUNT,
+            disabled_auth_mechs: Vec::new(),
+            reserved_spns: Vec::new(),
+            trusted_domains: Vec::new(),
+            spn_strict_verify: false,
+            duplicate_name_policy: DuplicateNamePolicy::Reject,
+            spn_direct_write: SpnDirectWrite::Overwrite,
+            spn_log_level: SpnLogLevel::Trace,
+            spn_idn_mode: SpnIdnMode::default(),
+            spn_charset_policy: SpnCharsetPolicy::default(),
+            spn_regen_mode: SpnRegenMode::default(),
+            spn_regen_chunk_size: DEFAULT_SPN_REGEN_CHUNK_SIZE,
+            startup_verify: false,
+            on_verify_failure: VerifyFailureAction::Warn,
+            worker_stack_size: None,
+            admin_socket_path: None,
+            spn_notify_command: None,
+            token_expiry_grace: 0,
+            missing_domain_name: None,
+            security_headers: None,
+            hsts_max_age: None,
+            content_security_policy: None,
+            max_entries: None,
+            reauth_operations: Vec::new(),
+            reauth_window: None,
+            anonymous_spn: None,
+            db_maintenance_interval: DEFAULT_DB_MAINTENANCE_INTERVAL,
+            db_maintenance_vacuum: true,
+            auth_event_retention: DEFAULT_AUTH_EVENT_RETENTION,
+            auth_trace_max_duration: 0,
+            detailed_auth_errors: false,
+            lockout_notify_command: None,
+            standby_primary: None,
+            log_subsystems: Vec::new(),
+            search_result_limit: DEFAULT_SEARCH_RESULT_LIMIT,
+            default_search_attrs: Vec::new(),
+            max_modify_batch: DEFAULT_MAX_MODIFY_BATCH,
+            write_queue_depth: DEFAULT_WRITE_QUEUE_DEPTH,
         };
-        let mut rng = StdRng::from_entropy();
         rng.fill(&mut c.cookie_key);
         c
     }
@@ -119,6 +871,10 @@ impl Configuration {
         self.log_level = log_level;
     }
 
+    pub fn update_log_level_debug(&mut self, log_level: Option<u32>) {
+        self.log_level_debug = log_level.unwrap_or(LogLevel::Verbose as u32);
+    }
+
     pub fn update_db_path(&mut self, p: &str) {
         self.db_path = p.to_string();
     }
@@ -127,6 +883,25 @@ impl Configuration {
         self.db_arc_size = v
     }
 
+    /// Check that the configured db_arc_size is likely to fit within the
+    /// provided amount of memory (in bytes).
+    pub fn validate_db_arc_size(&self, system_memory: usize) -> Result<(), String> {
+        let arc_size = match self.db_arc_size {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let estimate = arc_size.saturating_mul(DB_ARC_ENTRY_SIZE_ESTIMATE);
+        let limit = system_memory / DB_ARC_MEMORY_DIVISOR;
+        if estimate > limit {
+            Err(format!(
+                "db_arc_size {} may require ~{} bytes of memory, which exceeds 1/{} of system memory ({} bytes). This may cause the server to thrash or be killed.",
+                arc_size, estimate, DB_ARC_MEMORY_DIVISOR, system_memory
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn update_db_fs_type(&mut self, p: &Option<String>) {
         self.db_fs_type = p.as_ref().map(|v| v.to_lowercase());
     }
@@ -142,6 +917,41 @@ impl Configuration {
         self.ldapaddress = l.clone();
     }
 
+    pub fn update_ldap_basedn(&mut self, b: &Option<String>) {
+        self.ldap_basedn = b.clone();
+    }
+
+    /// Check the ldap_basedn is a DN made of simple attr=value components,
+    /// such as dc=example,dc=com. Multi-valued and escaped rdns are not
+    /// supported by the ldap server, so they are rejected here.
+    pub fn validate_ldap_basedn(&self) -> Result<(), String> {
+        let basedn = match &self.ldap_basedn {
+            Some(b) => b,
+            None => return Ok(()),
+        };
+        let valid = basedn.split(',').all(|rdn| {
+            let mut parts = rdn.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(attr), Some(val)) => {
+                    attr.starts_with(|c: char| c.is_ascii_alphabetic())
+                        && attr.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                        && !val.is_empty()
+                        && val.trim() == val
+                        && !val.contains(|c| "=+,\"<>;#\\".contains(c))
+                }
+                _ => false,
+            }
+        });
+        if valid {
+            Ok(())
+        } else {
+            Err(format!(
+                "ldap_basedn {} is not a valid DN, it should be in the form dc=example,dc=com",
+                basedn
+            ))
+        }
+    }
+
     pub fn update_origin(&mut self, o: &str) {
         self.origin = o.to_string();
     }
@@ -150,18 +960,1509 @@ impl Configuration {
         self.role = r;
     }
 
-    pub fn update_tls(&mut self, chain: &Option<String>, key: &Option<String>) {
-        match (chain, key) {
-            (None, None) => {}
-            (Some(chainp), Some(keyp)) => {
-                let chain = chainp.to_string();
-                let key = keyp.to_string();
-                self.tls_config = Some(TlsConfiguration { chain, key })
+    pub fn update_role_messages(
+        &mut self,
+        no_ui_message: &Option<String>,
+        read_only_message: &Option<String>,
+    ) {
+        self.no_ui_message = no_ui_message.clone();
+        self.read_only_message = read_only_message.clone();
+    }
+
+    /// The message returned to clients when a request is refused due to the
+    /// role of this server, or None if this role refuses nothing. A read only
+    /// replica only refuses writes while it is a standby awaiting promotion.
+    pub fn role_rejection_message(&self) -> Option<String> {
+        let configured = match self.role {
+            ServerRole::WriteReplica => None,
+            ServerRole::WriteReplicaNoUI => self.no_ui_message.as_ref(),
+            ServerRole::ReadOnlyReplica => self.read_only_message.as_ref(),
+        };
+        configured
+            .cloned()
+            .or_else(|| self.role.default_rejection_message().map(str::to_string))
+    }
+
+    pub fn update_max_connections_per_ip(&mut self, v: Option<usize>) {
+        self.max_connections_per_ip = v;
+    }
+
+    pub fn update_http_request_read_timeout(&mut self, v: Option<u64>) {
+        self.http_request_read_timeout = v;
+    }
+
+    /// Check the http request read timeout is long enough that legitimate
+    /// clients can complete requests, but short enough to be useful.
+    pub fn validate_http_request_read_timeout(&self) -> Result<(), String> {
+        match self.http_request_read_timeout {
+            Some(v) if v < HTTP_REQUEST_READ_TIMEOUT_MIN || v > HTTP_REQUEST_READ_TIMEOUT_MAX => {
+                Err(format!(
+                    "http_request_read_timeout {} must be between {} and {} seconds",
+                    v, HTTP_REQUEST_READ_TIMEOUT_MIN, HTTP_REQUEST_READ_TIMEOUT_MAX
+                ))
             }
-            _ => {
-                eprintln!("ERROR: Invalid TLS configuration - must provide chain and key!");
-                std::process::exit(1);
+            _ => Ok(()),
+        }
+    }
+
+    pub fn update_http_max_body_size(&mut self, v: Option<usize>) {
+        self.http_max_body_size = v.unwrap_or(DEFAULT_HTTP_MAX_BODY_SIZE);
+    }
+
+    pub fn validate_http_max_body_size(&self) -> Result<(), String> {
+        if self.http_max_body_size == 0 {
+            return Err("http_max_body_size must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn update_slow_operation_threshold(&mut self, v: Option<u64>) {
+        self.slow_operation_threshold = v;
+    }
+
+    pub fn update_anonymous_auth_rate_limit(&mut self, v: Option<u32>) {
+        self.anonymous_auth_rate_limit = v;
+    }
+
+    pub fn update_anonymous_read_scope(&mut self, s: AnonymousReadScope) {
+        self.anonymous_read_scope = s;
+    }
+
+    pub fn update_cookie(&mut self, domain: &Option<String>, samesite: Option<CookieSameSite>) {
+        self.cookie_domain = domain.as_ref().map(|d| d.trim().to_lowercase());
+        self.cookie_samesite = samesite.unwrap_or_default();
+    }
+
+    pub fn validate_cookie(&self) -> Result<(), String> {
+        if let Some(d) = &self.cookie_domain {
+            if d.is_empty() || d.contains(|c: char| c.is_whitespace() || c == '/' || c == ':') {
+                return Err(format!("cookie_domain \"{}\" must be a domain name", d));
             }
         }
+        // Browsers refuse a SameSite=None cookie unless it is only sent over https.
+        if self.cookie_samesite == CookieSameSite::None
+            && (!self.secure_cookies || self.tls_config.is_none())
+        {
+            return Err(
+                "cookie_samesite \"none\" requires secure cookies, so tls_chain and tls_key must be set"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn update_backup_path(&mut self, p: &Option<String>) {
+        self.backup_path = p.clone();
+    }
+
+    pub fn update_backup_retention_count(&mut self, v: Option<usize>) {
+        self.backup_retention_count = v.unwrap_or(DEFAULT_BACKUP_RETENTION_COUNT);
+    }
+
+    /// Check the backup_path is a directory we can write to, so that a
+    /// misconfiguration is found at startup rather than when the first backup
+    /// is due.
+    pub fn validate_backup_path(&self) -> Result<(), String> {
+        let path = match &self.backup_path {
+            Some(p) => Path::new(p),
+            None => return Ok(()),
+        };
+        if !path.is_dir() {
+            return Err(format!(
+                "backup_path {} does not exist or is not a directory",
+                path.display()
+            ));
+        }
+        if self.backup_retention_count == 0 {
+            return Err("backup_retention_count must be greater than 0".to_string());
+        }
+        // Permission bits don't account for acls or read only mounts, so the only
+        // reliable check is to try.
+        let probe = path.join(".kanidm_backup_write_test");
+        File::create(&probe)
+            .and_then(|_| fs::remove_file(&probe))
+            .map_err(|e| format!("backup_path {} is not writable -> {:?}", path.display(), e))
+    }
+
+    pub fn update_credential_size_limits(
+        &mut self,
+        max_password: Option<usize>,
+        max_credential: Option<usize>,
+    ) {
+        self.max_password_size = max_password.unwrap_or(DEFAULT_MAX_PASSWORD_SIZE);
+        self.max_credential_size = max_credential.unwrap_or(DEFAULT_MAX_CREDENTIAL_SIZE);
+    }
+
+    /// A limit must allow the shortest acceptable password, and a limit beyond the
+    /// maximum request size would never be reached.
+    pub fn validate_credential_size_limits(&self) -> Result<(), String> {
+        if self.max_password_size < PW_MIN_LENGTH {
+            Err(format!(
+                "max_password_size must be at least {}, the minimum password length",
+                PW_MIN_LENGTH
+            ))
+        } else if self.max_credential_size == 0 {
+            Err("max_credential_size must be greater than 0".to_string())
+        } else if self.max_password_size > self.maximum_request
+            || self.max_credential_size > self.maximum_request
+        {
+            Err(format!(
+                "max_password_size and max_credential_size must not exceed the maximum request size of {} bytes",
+                self.maximum_request
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn update_disabled_auth_mechs(&mut self, v: &[AuthMech]) {
+        self.disabled_auth_mechs = v.to_vec();
+    }
+
+    /// Check that at least one authentication mechanism remains enabled, else
+    /// no one could ever authenticate.
+    pub fn validate_disabled_auth_mechs(&self) -> Result<(), String> {
+        if ALL_AUTH_MECHS
+            .iter()
+            .all(|m| self.disabled_auth_mechs.contains(m))
+        {
+            Err("disabled_auth_mechs must not disable every authentication mechanism".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn update_reserved_spns(&mut self, v: &[String]) {
+        // Spns are generated from the lowercased name, so compare them the same way.
+        self.reserved_spns = v.iter().map(|s| s.trim().to_lowercase()).collect();
+    }
+
+    pub fn validate_reserved_spns(&self) -> Result<(), String> {
+        match self.reserved_spns.iter().find(|s| {
+            let mut parts = s.splitn(2, '@');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(realm)) => {
+                    name.is_empty() || realm.is_empty() || realm.contains('@')
+                }
+                _ => true,
+            }
+        }) {
+            Some(s) => Err(format!(
+                "reserved_spns entry \"{}\" must be of the form name@domain",
+                s
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn update_trusted_domains(&mut self, v: &[String]) {
+        self.trusted_domains = v.iter().map(|s| s.trim().to_lowercase()).collect();
+    }
+
+    pub fn validate_trusted_domains(&self) -> Result<(), String> {
+        match self
+            .trusted_domains
+            .iter()
+            .find(|s| s.is_empty() || s.contains('@') || s.contains(char::is_whitespace))
+        {
+            Some(s) => Err(format!(
+                "trusted_domains entry \"{}\" must be a domain name",
+                s
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn update_spn_strict_verify(&mut self, v: bool) {
+        self.spn_strict_verify = v;
+    }
+
+    pub fn update_duplicate_name_policy(&mut self, p: DuplicateNamePolicy) {
+        self.duplicate_name_policy = p;
+    }
+
+    pub fn update_spn_direct_write(&mut self, p: SpnDirectWrite) {
+        self.spn_direct_write = p;
+    }
+
+    pub fn update_spn_log_level(&mut self, l: SpnLogLevel) {
+        self.spn_log_level = l;
+    }
+
+    pub fn update_spn_idn_mode(&mut self, m: SpnIdnMode) {
+        self.spn_idn_mode = m;
+    }
+
+    pub fn update_spn_charset_policy(&mut self, p: SpnCharsetPolicy) {
+        self.spn_charset_policy = p;
+    }
+
+    pub fn update_spn_regen_mode(&mut self, m: SpnRegenMode, chunk_size: Option<usize>) {
+        self.spn_regen_mode = m;
+        self.spn_regen_chunk_size = chunk_size.unwrap_or(DEFAULT_SPN_REGEN_CHUNK_SIZE);
+    }
+
+    pub fn validate_spn_regen_mode(&self) -> Result<(), String> {
+        if self.spn_regen_chunk_size == 0 {
+            Err("spn_regen_chunk_size must be greater than 0".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn update_startup_verify(&mut self, v: bool, action: VerifyFailureAction) {
+        self.startup_verify = v;
+        self.on_verify_failure = action;
+    }
+
+    pub fn update_worker_stack_size(&mut self, v: Option<usize>) {
+        self.worker_stack_size = v;
+    }
+
+    pub fn validate_worker_stack_size(&self) -> Result<(), String> {
+        match self.worker_stack_size {
+            Some(v) if v < WORKER_STACK_SIZE_MIN || v > WORKER_STACK_SIZE_MAX => Err(format!(
+                "worker_stack_size {} must be between {} and {} bytes",
+                v, WORKER_STACK_SIZE_MIN, WORKER_STACK_SIZE_MAX
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn update_admin_socket_path(&mut self, p: &Option<String>) {
+        self.admin_socket_path = p.clone();
+    }
+
+    pub fn update_spn_notify_command(&mut self, c: &Option<String>) {
+        self.spn_notify_command = c.clone();
+    }
+
+    pub fn validate_spn_notify_command(&self) -> Result<(), String> {
+        match &self.spn_notify_command {
+            Some(c) if !std::path::Path::new(c).is_absolute() => Err(format!(
+                "spn_notify_command \"{}\" must be an absolute path",
+                c
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn update_token_expiry_grace(&mut self, v: Option<u64>) {
+        self.token_expiry_grace = v.unwrap_or(0);
+    }
+
+    pub fn validate_token_expiry_grace(&self) -> Result<(), String> {
+        if self.token_expiry_grace > TOKEN_EXPIRY_GRACE_MAX {
+            Err(format!(
+                "token_expiry_grace {} must be at most {} seconds",
+                self.token_expiry_grace, TOKEN_EXPIRY_GRACE_MAX
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn update_missing_domain_name(&mut self, n: &Option<String>) {
+        self.missing_domain_name = n.as_ref().map(|n| n.trim().to_lowercase());
+    }
+
+    pub fn validate_missing_domain_name(&self) -> Result<(), String> {
+        match &self.missing_domain_name {
+            Some(n) if n.is_empty() || n.contains('@') || n.contains(char::is_whitespace) => Err(
+                format!("missing_domain_name \"{}\" is not a valid domain name", n),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn update_reauth(&mut self, operations: &[ReauthOperation], window: Option<u64>) {
+        self.reauth_operations = operations.to_vec();
+        self.reauth_window = window;
+    }
+
+    pub fn validate_reauth(&self) -> Result<(), String> {
+        match self.reauth_window {
+            Some(_) if self.reauth_operations.is_empty() => {
+                Err("reauth_window has no effect unless reauth_operations is set".to_string())
+            }
+            Some(v) if v == 0 || v > REAUTH_WINDOW_MAX => Err(format!(
+                "reauth_window {} must be between 1 and {} seconds",
+                v, REAUTH_WINDOW_MAX
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// How recently (in seconds) a session must have authenticated to perform one
+    /// of the reauth_operations.
+    pub fn reauth_window(&self) -> u64 {
+        self.reauth_window.unwrap_or(DEFAULT_REAUTH_WINDOW)
+    }
+
+    pub fn update_anonymous_spn(&mut self, v: &Option<String>) {
+        self.anonymous_spn = v.clone();
+    }
+
+    pub fn validate_anonymous_spn(&self) -> Result<(), String> {
+        match &self.anonymous_spn {
+            Some(s) => AnonymousSpn::from_str(s).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// The spn policy of the anonymous account. This is only meaningful once
+    /// validate_anonymous_spn has passed.
+    pub fn anonymous_spn(&self) -> AnonymousSpn {
+        self.anonymous_spn
+            .as_deref()
+            .and_then(|s| AnonymousSpn::from_str(s).ok())
+            .unwrap_or_default()
+    }
+
+    /// The spn settings the query server is given.
+    pub fn spn_settings(&self) -> SpnSettings {
+        SpnSettings {
+            reserved_spns: self.reserved_spns.iter().cloned().collect(),
+            trusted_domains: self.trusted_domains.iter().cloned().collect(),
+            anonymous_spn: self.anonymous_spn(),
+            idn_mode: self.spn_idn_mode,
+            charset_policy: self.spn_charset_policy,
+            strict_verify: self.spn_strict_verify,
+            duplicate_name_policy: self.duplicate_name_policy,
+            direct_write: self.spn_direct_write,
+            log_level: self.spn_log_level,
+            regen_mode: self.spn_regen_mode,
+            regen_chunk_size: self.spn_regen_chunk_size,
+        }
+    }
+
+    pub fn update_db_maintenance(&mut self, interval: Option<u64>, vacuum: Option<bool>) {
+        self.db_maintenance_interval = interval.unwrap_or(DEFAULT_DB_MAINTENANCE_INTERVAL);
+        self.db_maintenance_vacuum = vacuum.unwrap_or(true);
+    }
+
+    pub fn validate_db_maintenance(&self) -> Result<(), String> {
+        match self.db_maintenance_interval {
+            0 => Ok(()),
+            v if v < DB_MAINTENANCE_INTERVAL_MIN || v > DB_MAINTENANCE_INTERVAL_MAX => {
+                Err(format!(
+                    "db_maintenance_interval {} must be 0 (disabled) or between {} and {} seconds",
+                    v, DB_MAINTENANCE_INTERVAL_MIN, DB_MAINTENANCE_INTERVAL_MAX
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// How often (in seconds) database maintenance runs, or `None` if it is disabled.
+    pub fn db_maintenance_interval(&self) -> Option<u64> {
+        match self.db_maintenance_interval {
+            0 => None,
+            v => Some(v),
+        }
+    }
+
+    pub fn update_auth_event_retention(&mut self, v: Option<u64>) {
+        self.auth_event_retention = v.unwrap_or(DEFAULT_AUTH_EVENT_RETENTION);
+    }
+
+    pub fn validate_auth_event_retention(&self) -> Result<(), String> {
+        if self.auth_event_retention > AUTH_EVENT_RETENTION_MAX {
+            Err(format!(
+                "auth_event_retention {} must be at most {} seconds",
+                self.auth_event_retention, AUTH_EVENT_RETENTION_MAX
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn update_auth_trace_max_duration(&mut self, v: Option<u64>) {
+        self.auth_trace_max_duration = v.unwrap_or(0);
+    }
+
+    pub fn validate_auth_trace_max_duration(&self) -> Result<(), String> {
+        if self.auth_trace_max_duration > AUTH_TRACE_MAX_DURATION_MAX {
+            Err(format!(
+                "auth_trace_max_duration {} must be at most {} seconds",
+                self.auth_trace_max_duration, AUTH_TRACE_MAX_DURATION_MAX
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn update_detailed_auth_errors(&mut self, v: bool) {
+        self.detailed_auth_errors = v;
+    }
+
+    pub fn update_lockout_notify_command(&mut self, c: &Option<String>) {
+        self.lockout_notify_command = c.clone();
+    }
+
+    pub fn validate_lockout_notify_command(&self) -> Result<(), String> {
+        match &self.lockout_notify_command {
+            Some(c) if !std::path::Path::new(c).is_absolute() => Err(format!(
+                "lockout_notify_command \"{}\" must be an absolute path",
+                c
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn update_standby(&mut self, primary: &Option<String>) {
+        self.standby_primary = primary.clone();
+    }
+
+    /// The http and ldap listeners can only share a port when they're bound to
+    /// different addresses. A wildcard address such as [::] overlaps every other
+    /// address on the port.
+    pub fn validate_listeners(&self) -> Result<(), String> {
+        let ldapaddress = match &self.ldapaddress {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        let overlap = if &self.address == ldapaddress {
+            true
+        } else {
+            match (
+                self.address.parse::<SocketAddr>(),
+                ldapaddress.parse::<SocketAddr>(),
+            ) {
+                (Ok(http), Ok(ldap)) => {
+                    http.port() == ldap.port()
+                        && (http.ip() == ldap.ip()
+                            || http.ip().is_unspecified()
+                            || ldap.ip().is_unspecified())
+                }
+                // A hostname is only resolved when it's bound.
+                _ => false,
+            }
+        };
+        if overlap {
+            Err(format!(
+                "bindaddress {} and ldapbindaddress {} overlap, they must use different ports or addresses",
+                self.address, ldapaddress
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Only a read only replica can be a standby, and its primary must be given as
+    /// host:port so that it can be probed.
+    pub fn validate_standby(&self) -> Result<(), String> {
+        let primary = match &self.standby_primary {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if !matches!(self.role, ServerRole::ReadOnlyReplica) {
+            return Err(format!(
+                "standby_primary requires the role read_only_replica, not {}",
+                self.role
+            ));
+        }
+        match primary.rsplitn(2, ':').collect::<Vec<_>>().as_slice() {
+            [port, host] if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => {
+                return Err(format!(
+                    "standby_primary \"{}\" must be in the form host:port",
+                    primary
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// The standby monitor of this server, if it is a standby. Only valid once
+    /// validate_standby has passed.
+    pub fn standby_monitor(&self) -> Option<StandbyMonitor> {
+        self.standby_primary
+            .as_ref()
+            .map(|p| StandbyMonitor::new(p))
+    }
+
+    pub fn update_log_subsystems(&mut self, subsystems: &Option<Vec<String>>) {
+        self.log_subsystems = subsystems
+            .as_ref()
+            .map(|s| s.iter().map(|n| n.trim().to_string()).collect())
+            .unwrap_or_else(Vec::new);
+    }
+
+    /// Only the subsystems that tag their audit events can be selected, so that a
+    /// misspelt name doesn't silently filter out every event.
+    pub fn validate_log_subsystems(&self) -> Result<(), String> {
+        let known = Plugins::subsystems();
+        match self
+            .log_subsystems
+            .iter()
+            .find(|s| !known.contains(&s.as_str()))
+        {
+            Some(s) => Err(format!(
+                "log_subsystems \"{}\" is not a known subsystem, expected one of {}",
+                s,
+                known.join(", ")
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn update_max_entries(&mut self, v: Option<u64>) {
+        self.max_entries = v;
+    }
+
+    pub fn validate_max_entries(&self) -> Result<(), String> {
+        match self.max_entries {
+            Some(0) => Err("max_entries must be greater than 0".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn update_search_result_limit(&mut self, v: Option<usize>) {
+        self.search_result_limit = v.unwrap_or(DEFAULT_SEARCH_RESULT_LIMIT);
+    }
+
+    pub fn validate_search_result_limit(&self) -> Result<(), String> {
+        if self.search_result_limit == 0 {
+            Err("search_result_limit must be greater than 0".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn update_max_modify_batch(&mut self, v: Option<usize>) {
+        self.max_modify_batch = v.unwrap_or(DEFAULT_MAX_MODIFY_BATCH);
+    }
+
+    pub fn validate_max_modify_batch(&self) -> Result<(), String> {
+        if self.max_modify_batch == 0 {
+            Err("max_modify_batch must be greater than 0".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn update_write_queue_depth(&mut self, v: Option<usize>) {
+        self.write_queue_depth = v.unwrap_or(DEFAULT_WRITE_QUEUE_DEPTH);
+    }
+
+    pub fn validate_write_queue_depth(&self) -> Result<(), String> {
+        if self.write_queue_depth == 0 {
+            Err("write_queue_depth must be greater than 0".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn update_default_search_attrs(&mut self, v: &[String]) {
+        self.default_search_attrs = v.iter().map(|s| s.trim().to_lowercase()).collect();
+    }
+
+    // The attributes can only be checked against the schema once the server has
+    // loaded it, so this only checks they could be attribute names.
+    pub fn validate_default_search_attrs(&self) -> Result<(), String> {
+        match self
+            .default_search_attrs
+            .iter()
+            .find(|s| s.is_empty() || s.contains(char::is_whitespace))
+        {
+            Some(s) => Err(format!(
+                "default_search_attrs entry \"{}\" must be an attribute name",
+                s
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn update_security_headers(
+        &mut self,
+        enabled: Option<bool>,
+        hsts_max_age: Option<u64>,
+        content_security_policy: &Option<String>,
+    ) {
+        self.security_headers = enabled;
+        self.hsts_max_age = hsts_max_age;
+        self.content_security_policy = content_security_policy
+            .as_ref()
+            .map(|s| s.trim().to_string());
+    }
+
+    pub fn validate_security_headers(&self) -> Result<(), String> {
+        if self.security_headers == Some(false)
+            && (self.hsts_max_age.is_some() || self.content_security_policy.is_some())
+        {
+            return Err(
+                "hsts_max_age and content_security_policy have no effect when security_headers is false"
+                    .to_string(),
+            );
+        }
+        if let Some(v) = self.hsts_max_age {
+            if v > HSTS_MAX_AGE_MAX {
+                return Err(format!(
+                    "hsts_max_age {} must be at most {} seconds",
+                    v, HSTS_MAX_AGE_MAX
+                ));
+            }
+        }
+        if let Some(csp) = &self.content_security_policy {
+            // Each directive is a name followed by its (possibly empty) source list. Only
+            // visible ascii is allowed, so that the policy can't alter the header itself.
+            let valid = !csp.is_empty()
+                && csp.chars().all(|c| c == ' ' || c.is_ascii_graphic())
+                && csp
+                    .trim_end_matches(';')
+                    .split(';')
+                    .map(str::trim)
+                    .all(|d| {
+                        d.split(' ')
+                            .next()
+                            .map(|name| {
+                                !name.is_empty()
+                                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                            })
+                            .unwrap_or(false)
+                    });
+            if !valid {
+                return Err(format!(
+                    "content_security_policy \"{}\" is not a valid policy, it should be in the form \"default-src 'self'; frame-ancestors 'none'\"",
+                    csp
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The security headers added to every http response. These are enabled by
+    /// default when TLS is, since hsts is ignored by browsers over plain http.
+    pub fn http_security_headers(&self) -> Vec<(&'static str, String)> {
+        let tls = self.tls_config.is_some();
+        if !self.security_headers.unwrap_or(tls) {
+            return Vec::new();
+        }
+        let mut headers = vec![("X-Content-Type-Options", "nosniff".to_string())];
+        // When not set, hsts is only sent if we are serving TLS ourselves. Behind a TLS
+        // proxy it can be set explicitly.
+        if let Some(max_age) = self.hsts_max_age.or_else(|| {
+            if tls {
+                Some(DEFAULT_HSTS_MAX_AGE)
+            } else {
+                None
+            }
+        }) {
+            headers.push(("Strict-Transport-Security", format!("max-age={}", max_age)));
+        }
+        headers.push((
+            "Content-Security-Policy",
+            self.content_security_policy
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CONTENT_SECURITY_POLICY.to_string()),
+        ));
+        headers
+    }
+
+    /// The effective configuration, keyed by the option names of server.toml so
+    /// that it can be compared with a config file. Options that are only set
+    /// internally are left out, and tls_key is only reported by whether it is set.
+    pub fn effective_config(&self) -> Result<SystemConfig, String> {
+        let mut table = match toml::Value::try_from(self) {
+            Ok(toml::Value::Table(t)) => t,
+            Ok(_) => return Err("Configuration is not a table".to_string()),
+            Err(e) => return Err(format!("Unable to serialise configuration -> {:?}", e)),
+        };
+        // These are not options of server.toml, or are re-added below as they are
+        // represented differently in it.
+        for k in &[
+            "threads",
+            "maximum_request",
+            "secure_cookies",
+            "cookie_key",
+            "integration_test_config",
+            "tls_config",
+            "role",
+            "log_level",
+            "log_level_debug",
+        ] {
+            table.remove(*k);
+        }
+        for (field, option) in &[
+            ("address", "bindaddress"),
+            ("ldapaddress", "ldapbindaddress"),
+        ] {
+            if let Some(v) = table.remove(*field) {
+                table.insert(option.to_string(), v);
+            }
+        }
+        if let Some(tls) = &self.tls_config {
+            table.insert(
+                "tls_chain".to_string(),
+                toml::Value::String(tls.chain.clone()),
+            );
+        }
+        table.insert(
+            "role".to_string(),
+            toml::Value::String(self.role.to_string()),
+        );
+        if let Some(ll) = self.log_level {
+            table.insert("log_level".to_string(), log_level_value(ll));
+        }
+        table.insert(
+            "log_level_debug".to_string(),
+            log_level_value(self.log_level_debug),
+        );
+
+        let mut secrets = BTreeMap::new();
+        secrets.insert("tls_key".to_string(), self.tls_config.is_some());
+
+        Ok(SystemConfig {
+            values: table.into_iter().map(|(k, v)| (k, v.to_string())).collect(),
+            secrets,
+        })
+    }
+
+    pub fn update_tls_key_strength(
+        &mut self,
+        min_rsa_bits: Option<u32>,
+        min_ec_bits: Option<u32>,
+        allow_weak_keys: Option<bool>,
+    ) {
+        self.tls_min_rsa_bits = min_rsa_bits.unwrap_or(DEFAULT_TLS_MIN_RSA_BITS);
+        self.tls_min_ec_bits = min_ec_bits.unwrap_or(DEFAULT_TLS_MIN_EC_BITS);
+        self.tls_allow_weak_keys = allow_weak_keys.unwrap_or(false);
+    }
+
+    /// Weaker keys are accepted with tls_allow_weak_keys, rather than by lowering
+    /// the minimums, so that the override is explicit.
+    pub fn validate_tls_key_strength(&self) -> Result<(), String> {
+        if !(DEFAULT_TLS_MIN_RSA_BITS..=TLS_MAX_RSA_BITS).contains(&self.tls_min_rsa_bits) {
+            return Err(format!(
+                "tls_min_rsa_bits {} must be between {} and {}",
+                self.tls_min_rsa_bits, DEFAULT_TLS_MIN_RSA_BITS, TLS_MAX_RSA_BITS
+            ));
+        }
+        if !(DEFAULT_TLS_MIN_EC_BITS..=TLS_MAX_EC_BITS).contains(&self.tls_min_ec_bits) {
+            return Err(format!(
+                "tls_min_ec_bits {} must be between {} and {}",
+                self.tls_min_ec_bits, DEFAULT_TLS_MIN_EC_BITS, TLS_MAX_EC_BITS
+            ));
+        }
+        Ok(())
+    }
+
+    /// Run all of the validation of the configuration that is done at startup,
+    /// returning every problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.max_connections_per_ip == Some(0) {
+            errors.push("max_connections_per_ip must be greater than 0".to_string());
+        }
+        if self.anonymous_auth_rate_limit == Some(0) {
+            errors.push("anonymous_auth_rate_limit must be greater than 0".to_string());
+        }
+        let results = vec![
+            self.validate_listeners(),
+            self.validate_tls_key_strength(),
+            self.validate_ldap_basedn(),
+            self.validate_cookie(),
+            self.validate_http_request_read_timeout(),
+            self.validate_http_max_body_size(),
+            self.validate_backup_path(),
+            self.validate_disabled_auth_mechs(),
+            self.validate_credential_size_limits(),
+            self.validate_reserved_spns(),
+            self.validate_trusted_domains(),
+            self.validate_worker_stack_size(),
+            self.validate_spn_notify_command(),
+            self.validate_token_expiry_grace(),
+            self.validate_missing_domain_name(),
+            self.validate_security_headers(),
+            self.validate_reauth(),
+            self.validate_anonymous_spn(),
+            self.validate_db_maintenance(),
+            self.validate_auth_event_retention(),
+            self.validate_auth_trace_max_duration(),
+            self.validate_lockout_notify_command(),
+            self.validate_standby(),
+            self.validate_log_subsystems(),
+            self.validate_max_entries(),
+            self.validate_search_result_limit(),
+            self.validate_max_modify_batch(),
+            self.validate_spn_regen_mode(),
+            self.validate_write_queue_depth(),
+            self.validate_default_search_attrs(),
+        ];
+        errors.extend(results.into_iter().filter_map(|r| r.err()));
+        errors
+    }
+
+    pub fn update_tls(&mut self, chain: &Option<String>, key: &Option<String>) {
+        match (chain, key) {
+            (None, None) => {}
+            (Some(chainp), Some(keyp)) => {
+                let chain = chainp.to_string();
+                let key = keyp.to_string();
+                self.tls_config = Some(TlsConfiguration { chain, key })
+            }
+            _ => {
+                eprintln!("ERROR: Invalid TLS configuration - must provide chain and key!");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::LogLevel;
+    use crate::config::{
+        AnonymousReadScope, AnonymousSpn, Configuration, CookieSameSite, ReauthOperation,
+        ServerRole, SpnRegenMode, TlsConfiguration, ALL_AUTH_MECHS,
+    };
+    use crate::constants::{
+        DEFAULT_MAX_MODIFY_BATCH, DEFAULT_SEARCH_RESULT_LIMIT, UUID_ADMIN, UUID_ANONYMOUS,
+        UUID_DOMAIN_INFO,
+    };
+    use kanidm_proto::v1::AuthMech;
+
+    #[test]
+    fn test_config_seeded_cookie_key() {
+        let a = Configuration::new_with_seed(42);
+        let b = Configuration::new_with_seed(42);
+        assert!(a.cookie_key == b.cookie_key);
+        assert!(a.cookie_key != [0; 32]);
+        assert!(Configuration::new_with_seed(43).cookie_key != a.cookie_key);
+    }
+
+    #[test]
+    fn test_config_validate_db_arc_size() {
+        let mut config = Configuration::new();
+        // 1GB of memory.
+        let mem = 1024 * 1024 * 1024;
+        // Auto is always okay.
+        assert!(config.validate_db_arc_size(mem).is_ok());
+        config.update_db_arc_size(Some(2048));
+        assert!(config.validate_db_arc_size(mem).is_ok());
+        config.update_db_arc_size(Some(usize::MAX));
+        assert!(config.validate_db_arc_size(mem).is_err());
+        config.update_db_arc_size(Some(1_000_000));
+        assert!(config.validate_db_arc_size(mem).is_err());
+    }
+
+    #[test]
+    fn test_config_validate_http_request_read_timeout() {
+        let mut config = Configuration::new();
+        assert!(config.validate_http_request_read_timeout().is_ok());
+        config.update_http_request_read_timeout(Some(30));
+        assert!(config.validate_http_request_read_timeout().is_ok());
+        assert!(config
+            .to_string()
+            .contains("http request read timeout: 30s"));
+        config.update_http_request_read_timeout(Some(0));
+        assert!(config.validate_http_request_read_timeout().is_err());
+        config.update_http_request_read_timeout(Some(86400));
+        assert!(config.validate_http_request_read_timeout().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_http_max_body_size() {
+        let mut config = Configuration::new();
+        assert!(config.validate_http_max_body_size().is_ok());
+        assert!(config.to_string().contains(&format!(
+            "http max body size: {}",
+            DEFAULT_HTTP_MAX_BODY_SIZE
+        )));
+        config.update_http_max_body_size(Some(1024));
+        assert!(config.validate_http_max_body_size().is_ok());
+        config.update_http_max_body_size(Some(0));
+        assert!(config.validate_http_max_body_size().is_err());
+        config.update_http_max_body_size(None);
+        assert_eq!(config.http_max_body_size, DEFAULT_HTTP_MAX_BODY_SIZE);
+    }
+
+    #[test]
+    fn test_config_display_slow_operation_threshold() {
+        let mut config = Configuration::new();
+        assert!(config
+            .to_string()
+            .contains("slow operation threshold: disabled"));
+        config.update_slow_operation_threshold(Some(500));
+        assert!(config
+            .to_string()
+            .contains("slow operation threshold: 500ms"));
+    }
+
+    #[test]
+    fn test_config_anonymous_read_scope() {
+        let mut config = Configuration::new();
+        assert!(config.to_string().contains("anonymous read scope: all"));
+        assert!(config.anonymous_read_scope.permits(&UUID_ADMIN));
+
+        config.update_anonymous_read_scope(AnonymousReadScope::DomainInfo);
+        assert!(config
+            .to_string()
+            .contains("anonymous read scope: domain_info"));
+        assert!(config.anonymous_read_scope.permits(&UUID_DOMAIN_INFO));
+        assert!(config.anonymous_read_scope.permits(&UUID_ANONYMOUS));
+        assert!(!config.anonymous_read_scope.permits(&UUID_ADMIN));
+
+        config.update_anonymous_read_scope(AnonymousReadScope::Nothing);
+        assert!(!config.anonymous_read_scope.permits(&UUID_DOMAIN_INFO));
+        assert!(config.anonymous_read_scope.permits(&UUID_ANONYMOUS));
+    }
+
+    #[test]
+    fn test_config_validate_credential_size_limits() {
+        let mut config = Configuration::new();
+        assert!(config.validate_credential_size_limits().is_ok());
+        assert!(config.to_string().contains("max password size: 1024b"));
+
+        config.update_credential_size_limits(Some(256), Some(8192));
+        assert!(config.validate_credential_size_limits().is_ok());
+        assert!(config.max_password_size == 256);
+        assert!(config.max_credential_size == 8192);
+
+        config.update_credential_size_limits(Some(4), None);
+        assert!(config.validate_credential_size_limits().is_err());
+        config.update_credential_size_limits(None, Some(0));
+        assert!(config.validate_credential_size_limits().is_err());
+        config.update_credential_size_limits(None, Some(config.maximum_request + 1));
+        assert!(config.validate_credential_size_limits().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_disabled_auth_mechs() {
+        let mut config = Configuration::new();
+        assert!(config.validate_disabled_auth_mechs().is_ok());
+        assert!(config
+            .to_string()
+            .contains("disabled auth mechanisms: none"));
+
+        config.update_disabled_auth_mechs(&[AuthMech::Anonymous]);
+        assert!(config.validate_disabled_auth_mechs().is_ok());
+        assert!(config
+            .to_string()
+            .contains("disabled auth mechanisms: [Anonymous]"));
+
+        config.update_disabled_auth_mechs(&ALL_AUTH_MECHS);
+        assert!(config.validate_disabled_auth_mechs().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_worker_stack_size() {
+        let mut config = Configuration::new();
+        assert!(config.validate_worker_stack_size().is_ok());
+        assert!(config
+            .to_string()
+            .contains("worker stack size: system default"));
+        config.update_worker_stack_size(Some(8 * 1024 * 1024));
+        assert!(config.validate_worker_stack_size().is_ok());
+        assert!(config.to_string().contains("worker stack size: 8388608b"));
+        config.update_worker_stack_size(Some(4096));
+        assert!(config.validate_worker_stack_size().is_err());
+        config.update_worker_stack_size(Some(usize::MAX));
+        assert!(config.validate_worker_stack_size().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_missing_domain_name() {
+        let mut config = Configuration::new();
+        assert!(config.validate_missing_domain_name().is_ok());
+        assert!(config
+            .to_string()
+            .contains("missing domain info: refuse to start"));
+        config.update_missing_domain_name(&Some(" IDM.example.com ".to_string()));
+        assert!(config.validate_missing_domain_name().is_ok());
+        assert!(config.missing_domain_name.as_deref() == Some("idm.example.com"));
+        config.update_missing_domain_name(&Some("admin@example.com".to_string()));
+        assert!(config.validate_missing_domain_name().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_token_expiry_grace() {
+        let mut config = Configuration::new();
+        assert!(config.validate_token_expiry_grace().is_ok());
+        assert!(config.to_string().contains("token expiry grace: 0s"));
+        config.update_token_expiry_grace(Some(30));
+        assert!(config.validate_token_expiry_grace().is_ok());
+        config.update_token_expiry_grace(Some(3600));
+        assert!(config.validate_token_expiry_grace().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_reauth() {
+        let mut config = Configuration::new();
+        assert!(config.validate_reauth().is_ok());
+        assert!(config.to_string().contains("reauth: disabled"));
+        config.update_reauth(&[ReauthOperation::Credential], None);
+        assert!(config.validate_reauth().is_ok());
+        assert!(config
+            .to_string()
+            .contains("reauth: credential within 300s"));
+        config.update_reauth(&[ReauthOperation::Credential], Some(7200));
+        assert!(config.validate_reauth().is_err());
+        config.update_reauth(&[], Some(60));
+        assert!(config.validate_reauth().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_db_maintenance() {
+        let mut config = Configuration::new();
+        assert!(config.validate_db_maintenance().is_ok());
+        assert!(config.db_maintenance_interval() == Some(604_800));
+        assert!(config
+            .to_string()
+            .contains("db maintenance: every 604800s (vacuum: true)"));
+        config.update_db_maintenance(Some(0), None);
+        assert!(config.validate_db_maintenance().is_ok());
+        assert!(config.db_maintenance_interval() == None);
+        assert!(config.to_string().contains("db maintenance: disabled"));
+        config.update_db_maintenance(Some(86400), Some(false));
+        assert!(config.validate_db_maintenance().is_ok());
+        assert!(config
+            .to_string()
+            .contains("db maintenance: every 86400s (vacuum: false)"));
+        config.update_db_maintenance(Some(60), None);
+        assert!(config.validate_db_maintenance().is_err());
+        config.update_db_maintenance(Some(63_072_000), None);
+        assert!(config.validate_db_maintenance().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_auth_event_retention() {
+        let mut config = Configuration::new();
+        assert!(config.validate_auth_event_retention().is_ok());
+        assert!(config.to_string().contains("auth event retention: 86400s"));
+        config.update_auth_event_retention(Some(0));
+        assert!(config.validate_auth_event_retention().is_ok());
+        assert!(config
+            .to_string()
+            .contains("auth event retention: disabled"));
+        config.update_auth_event_retention(Some(31_536_000));
+        assert!(config.validate_auth_event_retention().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_auth_trace_max_duration() {
+        let mut config = Configuration::new();
+        assert!(config.validate_auth_trace_max_duration().is_ok());
+        assert!(config.to_string().contains("auth trace: disabled"));
+        config.update_auth_trace_max_duration(Some(3600));
+        assert!(config.validate_auth_trace_max_duration().is_ok());
+        assert!(config
+            .to_string()
+            .contains("auth trace max duration: 3600s"));
+        config.update_auth_trace_max_duration(Some(86401));
+        assert!(config.validate_auth_trace_max_duration().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_listeners() {
+        let mut config = Configuration::new();
+        config.update_bind(&Some("[::]:8443".to_string()));
+        assert!(config.validate_listeners().is_ok());
+
+        for overlapping in &["[::]:8443", "127.0.0.1:8443", "0.0.0.0:8443"] {
+            config.update_ldapbind(&Some(overlapping.to_string()));
+            let err = config.validate_listeners().expect_err("must overlap");
+            assert!(err.contains("[::]:8443") && err.contains(overlapping));
+            assert!(config.validate().contains(&err));
+        }
+
+        for separate in &["[::]:3636", "127.0.0.1:3636"] {
+            config.update_ldapbind(&Some(separate.to_string()));
+            assert!(config.validate_listeners().is_ok());
+        }
+
+        // Distinct addresses may share a port.
+        config.update_bind(&Some("192.0.2.1:8443".to_string()));
+        config.update_ldapbind(&Some("192.0.2.2:8443".to_string()));
+        assert!(config.validate_listeners().is_ok());
+
+        config.update_bind(&Some("idm.example.com:8443".to_string()));
+        config.update_ldapbind(&Some("idm.example.com:8443".to_string()));
+        assert!(config.validate_listeners().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_standby() {
+        let mut config = Configuration::new();
+        assert!(config.validate_standby().is_ok());
+        assert!(config.standby_monitor().is_none());
+
+        config.update_standby(&Some("idm1.example.com:8443".to_string()));
+        // Only a read only replica can be a standby.
+        assert!(config.validate_standby().is_err());
+        config.update_role(ServerRole::ReadOnlyReplica);
+        assert!(config.validate_standby().is_ok());
+        assert!(config.standby_monitor().is_some());
+
+        for invalid in &["idm1.example.com", ":8443", "idm1.example.com:https"] {
+            config.update_standby(&Some(invalid.to_string()));
+            assert!(config.validate_standby().is_err());
+        }
+
+        config.update_standby(&Some("[2001:db8::1]:8443".to_string()));
+        assert!(config.validate_standby().is_ok());
+        assert!(config.to_string().contains("manual promotion only"));
+    }
+
+    #[test]
+    fn test_config_role_rejection_message() {
+        let mut config = Configuration::new();
+        assert!(config.role_rejection_message().is_none());
+
+        config.update_role_messages(
+            &Some("No ui here".to_string()),
+            &Some("Read only here".to_string()),
+        );
+        // Each role returns its own message.
+        assert!(config.role_rejection_message().is_none());
+        config.update_role(ServerRole::WriteReplicaNoUI);
+        assert!(config.role_rejection_message() == Some("No ui here".to_string()));
+        config.update_role(ServerRole::ReadOnlyReplica);
+        assert!(config.role_rejection_message() == Some("Read only here".to_string()));
+
+        // Or a generic message when it isn't configured.
+        config.update_role_messages(&None, &None);
+        assert!(config.role_rejection_message().is_some());
+    }
+
+    #[test]
+    fn test_config_validate_tls_key_strength() {
+        let mut config = Configuration::new();
+        assert!(config.validate_tls_key_strength().is_ok());
+        assert!(config.tls_min_rsa_bits == 2048 && config.tls_min_ec_bits == 256);
+
+        config.update_tls_key_strength(Some(3072), Some(384), Some(true));
+        assert!(config.validate_tls_key_strength().is_ok());
+        assert!(config.to_string().contains("weak keys allowed"));
+
+        // The minimums can't be lowered, only overridden.
+        config.update_tls_key_strength(Some(1024), None, None);
+        assert!(config.validate_tls_key_strength().is_err());
+        config.update_tls_key_strength(None, Some(192), None);
+        assert!(config.validate_tls_key_strength().is_err());
+        config.update_tls_key_strength(None, Some(1024), None);
+        assert!(config.validate_tls_key_strength().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_log_subsystems() {
+        let mut config = Configuration::new();
+        assert!(config.validate_log_subsystems().is_ok());
+        assert!(config.to_string().contains("log subsystems: all"));
+
+        config.update_log_subsystems(&Some(vec![
+            "plugin_spn".to_string(),
+            " memberof ".to_string(),
+        ]));
+        assert!(config.validate_log_subsystems().is_ok());
+        assert!(config.log_subsystems == vec!["plugin_spn", "memberof"]);
+
+        config.update_log_subsystems(&Some(vec!["plugin_spm".to_string()]));
+        assert!(config.validate_log_subsystems().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_lockout_notify_command() {
+        let mut config = Configuration::new();
+        assert!(config.validate_lockout_notify_command().is_ok());
+        assert!(config
+            .to_string()
+            .contains("lockout notify command: disabled"));
+        config.update_lockout_notify_command(&Some("/usr/local/bin/notify-admin".to_string()));
+        assert!(config.validate_lockout_notify_command().is_ok());
+        config.update_lockout_notify_command(&Some("notify-admin".to_string()));
+        assert!(config.validate_lockout_notify_command().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_anonymous_spn() {
+        let mut config = Configuration::new();
+        assert!(config.validate_anonymous_spn().is_ok());
+        assert!(config.anonymous_spn() == AnonymousSpn::Generated);
+        config.update_anonymous_spn(&Some("none".to_string()));
+        assert!(config.validate_anonymous_spn().is_ok());
+        assert!(config.anonymous_spn() == AnonymousSpn::Suppressed);
+        assert!(config.to_string().contains("anonymous spn: none"));
+        config.update_anonymous_spn(&Some("Guest@IDM.example.com".to_string()));
+        assert!(config.validate_anonymous_spn().is_ok());
+        assert!(
+            config.anonymous_spn()
+                == AnonymousSpn::Fixed("guest".to_string(), "idm.example.com".to_string())
+        );
+        for invalid in &["guest", "guest@", "@idm.example.com", ""] {
+            config.update_anonymous_spn(&Some(invalid.to_string()));
+            assert!(config.validate_anonymous_spn().is_err());
+        }
+    }
+
+    #[test]
+    fn test_config_validate_max_entries() {
+        let mut config = Configuration::new();
+        assert!(config.validate_max_entries().is_ok());
+        assert!(config.to_string().contains("max entries: unlimited"));
+        config.update_max_entries(Some(10000));
+        assert!(config.validate_max_entries().is_ok());
+        assert!(config.to_string().contains("max entries: 10000"));
+        config.update_max_entries(Some(0));
+        assert!(config.validate_max_entries().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_search_result_limit() {
+        let mut config = Configuration::new();
+        assert!(config.validate_search_result_limit().is_ok());
+        assert!(config.search_result_limit == DEFAULT_SEARCH_RESULT_LIMIT);
+        config.update_search_result_limit(Some(500));
+        assert!(config.validate_search_result_limit().is_ok());
+        assert!(config.to_string().contains("search result limit: 500"));
+        config.update_search_result_limit(Some(0));
+        assert!(config.validate_search_result_limit().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_security_headers() {
+        let mut config = Configuration::new();
+        assert!(config.validate_security_headers().is_ok());
+        // Without TLS, no headers are sent unless they are enabled.
+        assert!(config.http_security_headers().is_empty());
+        assert!(config.to_string().contains("security headers: disabled"));
+
+        config.update_tls(&Some("chain.pem".to_string()), &Some("key.pem".to_string()));
+        let headers = config.http_security_headers();
+        assert!(headers.contains(&("Strict-Transport-Security", "max-age=31536000".to_string())));
+        assert!(headers.contains(&("X-Content-Type-Options", "nosniff".to_string())));
+        assert!(headers.iter().any(|(n, _)| *n == "Content-Security-Policy"));
+        assert!(config.to_string().contains("max-age=31536000"));
+
+        config.update_security_headers(
+            None,
+            Some(600),
+            &Some("default-src 'self'; frame-ancestors 'none';".to_string()),
+        );
+        assert!(config.validate_security_headers().is_ok());
+        let headers = config.http_security_headers();
+        assert!(headers.contains(&("Strict-Transport-Security", "max-age=600".to_string())));
+        assert!(headers.contains(&(
+            "Content-Security-Policy",
+            "default-src 'self'; frame-ancestors 'none';".to_string()
+        )));
+
+        config.update_security_headers(Some(false), None, &None);
+        assert!(config.validate_security_headers().is_ok());
+        assert!(config.http_security_headers().is_empty());
+        config.update_security_headers(Some(false), Some(600), &None);
+        assert!(config.validate_security_headers().is_err());
+
+        config.update_security_headers(None, Some(100_000_000), &None);
+        assert!(config.validate_security_headers().is_err());
+        for csp in &[
+            "",
+            "default-src 'self'\r\nX-Injected: 1",
+            "'self'",
+            "default-src 'self';; img-src *",
+        ] {
+            config.update_security_headers(None, None, &Some(csp.to_string()));
+            assert!(config.validate_security_headers().is_err());
+        }
+    }
+
+    #[test]
+    fn test_config_validate_spn_notify_command() {
+        let mut config = Configuration::new();
+        assert!(config.validate_spn_notify_command().is_ok());
+        assert!(config.to_string().contains("spn notify command: disabled"));
+        config.update_spn_notify_command(&Some("/usr/local/bin/kdc-sync".to_string()));
+        assert!(config.validate_spn_notify_command().is_ok());
+        config.update_spn_notify_command(&Some("kdc-sync".to_string()));
+        assert!(config.validate_spn_notify_command().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_reserved_spns() {
+        let mut config = Configuration::new();
+        assert!(config.validate_reserved_spns().is_ok());
+
+        config.update_reserved_spns(&["HOST@IDM.example.com".to_string()]);
+        assert!(config.validate_reserved_spns().is_ok());
+        assert!(config.reserved_spns == vec!["host@idm.example.com".to_string()]);
+        assert!(config.to_string().contains("reserved spns: 1"));
+
+        for invalid in &["host", "@idm.example.com", "host@", "host@idm@example.com"] {
+            config.update_reserved_spns(&[invalid.to_string()]);
+            assert!(config.validate_reserved_spns().is_err());
+        }
+    }
+
+    #[test]
+    fn test_config_validate_cookie() {
+        let mut config = Configuration::new();
+        assert!(config.validate_cookie().is_ok());
+        assert!(config.to_string().contains("cookie domain: default"));
+        assert!(config.to_string().contains("cookie samesite: strict"));
+
+        config.update_cookie(&Some("Example.com".to_string()), Some(CookieSameSite::Lax));
+        assert!(config.validate_cookie().is_ok());
+        assert!(config.to_string().contains("cookie domain: example.com"));
+        assert!(config.to_string().contains("cookie samesite: lax"));
+
+        for invalid in &["", "example.com:8443", "https://example.com"] {
+            config.update_cookie(&Some(invalid.to_string()), None);
+            assert!(config.validate_cookie().is_err());
+        }
+
+        // SameSite=None needs cookies that are only sent over https.
+        config.update_cookie(&None, Some(CookieSameSite::None));
+        assert!(config.validate_cookie().is_err());
+        config.secure_cookies = true;
+        assert!(config.validate_cookie().is_err());
+        config.tls_config = Some(TlsConfiguration {
+            chain: "/data/chain.pem".to_string(),
+            key: "/data/key.pem".to_string(),
+        });
+        assert!(config.validate_cookie().is_ok());
+    }
+
+    #[test]
+    fn test_config_validate_spn_regen_mode() {
+        let mut config = Configuration::new();
+        assert!(config.validate_spn_regen_mode().is_ok());
+        assert!(config.to_string().contains("spn regen mode: strict"));
+        config.update_spn_regen_mode(SpnRegenMode::Available, None);
+        assert!(config.validate_spn_regen_mode().is_ok());
+        assert!(config
+            .to_string()
+            .contains("spn regen mode: available in chunks of 1000"));
+        config.update_spn_regen_mode(SpnRegenMode::Available, Some(0));
+        assert!(config.validate_spn_regen_mode().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_max_modify_batch() {
+        let mut config = Configuration::new();
+        assert!(config.validate_max_modify_batch().is_ok());
+        assert!(config.max_modify_batch == DEFAULT_MAX_MODIFY_BATCH);
+        config.update_max_modify_batch(Some(1000));
+        assert!(config.validate_max_modify_batch().is_ok());
+        assert!(config.to_string().contains("max modify batch: 1000"));
+        config.update_max_modify_batch(Some(0));
+        assert!(config.validate_max_modify_batch().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_write_queue_depth() {
+        let mut config = Configuration::new();
+        assert!(config.validate_write_queue_depth().is_ok());
+        assert!(config.to_string().contains("write queue depth: 1024"));
+        config.update_write_queue_depth(Some(16));
+        assert!(config.validate_write_queue_depth().is_ok());
+        assert!(config.to_string().contains("write queue depth: 16"));
+        config.update_write_queue_depth(Some(0));
+        assert!(config.validate_write_queue_depth().is_err());
+    }
+
+    #[test]
+    fn test_config_validate_default_search_attrs() {
+        let mut config = Configuration::new();
+        assert!(config.validate_default_search_attrs().is_ok());
+        assert!(config.default_search_attrs.is_empty());
+        config.update_default_search_attrs(&["Name".to_string(), " spn".to_string()]);
+        assert!(config.validate_default_search_attrs().is_ok());
+        assert!(config.default_search_attrs == vec!["name".to_string(), "spn".to_string()]);
+        for invalid in &["", "display name"] {
+            config.update_default_search_attrs(&[invalid.to_string()]);
+            assert!(config.validate_default_search_attrs().is_err());
+        }
+    }
+
+    #[test]
+    fn test_config_validate_trusted_domains() {
+        let mut config = Configuration::new();
+        assert!(config.validate_trusted_domains().is_ok());
+
+        config.update_trusted_domains(&[" Example.COM".to_string()]);
+        assert!(config.validate_trusted_domains().is_ok());
+        assert!(config.trusted_domains == vec!["example.com".to_string()]);
+        assert!(config.to_string().contains("trusted domains: 1"));
+
+        for invalid in &["", "host@example.com", "example com"] {
+            config.update_trusted_domains(&[invalid.to_string()]);
+            assert!(config.validate_trusted_domains().is_err());
+        }
+    }
+
+    #[test]
+    fn test_config_validate_ldap_basedn() {
+        let mut config = Configuration::new();
+        assert!(config.validate_ldap_basedn().is_ok());
+        assert!(config.to_string().contains("ldap basedn: from domain name"));
+
+        config.update_ldap_basedn(&Some("o=kanidm,dc=example,dc=com".to_string()));
+        assert!(config.validate_ldap_basedn().is_ok());
+        assert!(config
+            .to_string()
+            .contains("ldap basedn: o=kanidm,dc=example,dc=com"));
+
+        for invalid in &[
+            "",
+            "dc=example,",
+            "example.com",
+            "dc=,dc=com",
+            "dc=example, dc=com",
+            "1dc=example",
+            "dc=a+cn=b",
+        ] {
+            config.update_ldap_basedn(&Some(invalid.to_string()));
+            assert!(config.validate_ldap_basedn().is_err());
+        }
+    }
+
+    #[test]
+    fn test_config_validate_backup_path() {
+        let mut config = Configuration::new();
+        assert!(config.validate_backup_path().is_ok());
+        assert!(config.to_string().contains("online backups: disabled"));
+
+        let dir = std::env::temp_dir();
+        config.update_backup_path(&Some(dir.to_string_lossy().to_string()));
+        config.update_backup_retention_count(Some(3));
+        assert!(config.validate_backup_path().is_ok());
+        assert!(config.to_string().contains("(keeping 3)"));
+
+        config.update_backup_retention_count(Some(0));
+        assert!(config.validate_backup_path().is_err());
+
+        config.update_backup_retention_count(None);
+        config.update_backup_path(&Some(
+            dir.join("kanidm_does_not_exist")
+                .to_string_lossy()
+                .to_string(),
+        ));
+        assert!(config.validate_backup_path().is_err());
+    }
+
+    #[test]
+    fn test_config_effective_config() {
+        let mut config = Configuration::new();
+        config.update_bind(&Some("[::]:443".to_string()));
+        config.update_log_level(Some(LogLevel::PerfBasic as u32));
+        config.update_tls(
+            &Some("/chain.pem".to_string()),
+            &Some("/key.pem".to_string()),
+        );
+        let effective = config.effective_config().expect("must not fail");
+
+        // Keyed and rendered as they would be in server.toml.
+        assert!(effective.values.get("bindaddress").map(String::as_str) == Some("\"[::]:443\""));
+        assert!(effective.values.get("log_level").map(String::as_str) == Some("\"perfbasic\""));
+        assert!(effective.values.get("role").map(String::as_str) == Some("\"write_replica\""));
+        assert!(effective.values.get("tls_chain").map(String::as_str) == Some("\"/chain.pem\""));
+        // Secrets and internal options are never included.
+        assert!(effective.secrets.get("tls_key") == Some(&true));
+        assert!(!effective.values.contains_key("tls_key"));
+        assert!(!effective.values.contains_key("cookie_key"));
+        assert!(!effective.values.contains_key("address"));
+        // Options that are not set are left out.
+        assert!(!effective.values.contains_key("ldapbindaddress"));
     }
 }
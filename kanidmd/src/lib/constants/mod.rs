@@ -20,6 +20,48 @@ pub const PURGE_FREQUENCY: u64 = 60;
 // For production, 10 minutes.
 #[cfg(not(test))]
 pub const PURGE_FREQUENCY: u64 = 600;
+// When backup_path is configured, take an online backup daily.
+pub const ONLINE_BACKUP_FREQUENCY: u64 = 86400;
+// The most synthetic entries an spn bench may generate, as they are all held in memory.
+pub const SPN_BENCH_COUNT_MAX: usize = 1_000_000;
+// The number of changed spns a simulated domain rename shows.
+pub const DOMAIN_RENAME_SIMULATE_SAMPLES: usize = 10;
+// The most entries the entry size statistics report.
+pub const ENTRY_SIZES_TOP_MAX: usize = 1000;
+// With spn_log_level info, the most spn changes logged at info in one transaction.
+pub const SPN_LOG_INFO_MAX: usize = 100;
+// How often queued spn changes are sent to the kdc, and failed ones retried.
+pub const SPN_NOTIFY_FREQUENCY: u64 = 10;
+// How often to check for spns that are waiting to be regenerated after a domain rename.
+pub const SPN_REGEN_FREQUENCY: u64 = 10;
+// The most spn changes to hold for the kdc before the oldest are dropped.
+pub const SPN_NOTIFY_QUEUE_MAX: usize = 65536;
+// How long the kdc notify command may run before it is killed, in seconds.
+pub const SPN_NOTIFY_TIMEOUT: u64 = 30;
+// The most lockout notifications to hold before the oldest are dropped.
+pub const LOCKOUT_NOTIFY_QUEUE_MAX: usize = 1024;
+// How long the lockout notify command may run before it is killed, in seconds.
+pub const LOCKOUT_NOTIFY_TIMEOUT: u64 = 30;
+// How often queued lockout notifications are sent.
+pub const LOCKOUT_NOTIFY_FREQUENCY: u64 = 10;
+// How often a standby probes its primary, and how long each probe may take.
+pub const STANDBY_PROBE_FREQUENCY: u64 = 10;
+pub const STANDBY_PROBE_TIMEOUT: u64 = 5;
+// The most authentication events to hold for export before the oldest are dropped.
+pub const AUTH_EVENT_LOG_MAX: usize = 65536;
+// The most steps recorded by a single auth trace, later steps are not recorded.
+pub const AUTH_TRACE_STEPS_MAX: usize = 1024;
+// The most entries an external search returns when search_result_limit isn't set.
+pub const DEFAULT_SEARCH_RESULT_LIMIT: usize = 100_000;
+// The most entries a single modify may change when max_modify_batch isn't set.
+pub const DEFAULT_MAX_MODIFY_BATCH: usize = 500_000;
+// The most spns regenerated in each transaction when spn_regen_mode is available
+// and spn_regen_chunk_size isn't set.
+pub const DEFAULT_SPN_REGEN_CHUNK_SIZE: usize = 1000;
+// The most client write requests in progress when write_queue_depth isn't set.
+pub const DEFAULT_WRITE_QUEUE_DEPTH: usize = 1024;
+// How long the log level stays raised by SIGUSR1 before it is restored.
+pub const LOG_LEVEL_DEBUG_TIMEOUT: u64 = 600;
 
 #[cfg(test)]
 /// In test, we limit the changelog to 10 minutes.
@@ -40,3 +82,7 @@ pub const AUTH_SESSION_TIMEOUT: u64 = 300;
 // 5 minute mfa reg window
 pub const MFAREG_SESSION_TIMEOUT: u64 = 300;
 pub const PW_MIN_LENGTH: usize = 10;
+// The largest password (in bytes) that is hashed or verified.
+pub const DEFAULT_MAX_PASSWORD_SIZE: usize = 1024;
+// The largest other credential, such as a serialised webauthn response, in bytes.
+pub const DEFAULT_MAX_CREDENTIAL_SIZE: usize = 65_536;
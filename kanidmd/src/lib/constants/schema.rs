@@ -484,6 +484,205 @@ pub const JSON_SCHEMA_ATTR_ACCOUNT_VALID_FROM: &str = r#"{
     }
 }"#;
 
+pub const JSON_SCHEMA_ATTR_SPN_INDEX: &str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "A normalised copy of the spn, maintained by the server for direct lookup."
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "true"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "spn_index"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000074"
+      ]
+    }
+}"#;
+
+pub const JSON_SCHEMA_ATTR_SPN_SCOPE: &str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "A filter limiting which accounts and groups are given an spn. When absent, all accounts and groups are."
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "spn_scope"
+      ],
+      "syntax": [
+        "JSON_FILTER"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000075"
+      ]
+    }
+}"#;
+
+pub const JSON_SCHEMA_ATTR_SPN_SKIP_EXPIRED: &str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "If true, accounts past their account_expire keep their existing spn when spns are regenerated, such as during a domain rename."
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "spn_skip_expired"
+      ],
+      "syntax": [
+        "BOOLEAN"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000076"
+      ]
+    }
+}"#;
+
+pub const JSON_SCHEMA_ATTR_SPN_LOCKED: &str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "If true, the spn of this account was set by an administrator and is never regenerated, such as during a domain rename."
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "spn_locked"
+      ],
+      "syntax": [
+        "BOOLEAN"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000077"
+      ]
+    }
+}"#;
+
+pub const JSON_SCHEMA_ATTR_DOMAIN_SPN_PREFIX: &str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "A class and the kerberos service prefix given to the spn of entries with that class, in the form class=prefix."
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "domain_spn_prefix"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000078"
+      ]
+    }
+}"#;
+
+pub const JSON_SCHEMA_ATTR_SPN_OPTOUT_GROUP: &str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The name of a group whose members, including those of nested groups, are not given an spn."
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "spn_optout_group"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000079"
+      ]
+    }
+}"#;
+
+pub const JSON_SCHEMA_ATTR_ORIGIN_DOMAIN: &str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The domain an account or group was replicated from. When it is a trusted domain, the spn given by that domain is kept rather than generated."
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "origin_domain"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000080"
+      ]
+    }
+}"#;
+
 // === classes ===
 
 pub const JSON_SCHEMA_CLASS_PERSON: &str = r#"
@@ -530,7 +729,9 @@ pub const JSON_SCHEMA_CLASS_GROUP: &str = r#"
         "group"
       ],
       "systemmay": [
-        "member"
+        "member",
+        "spn_index",
+        "origin_domain"
       ],
       "systemmust": [
         "name",
@@ -561,7 +762,10 @@ pub const JSON_SCHEMA_CLASS_ACCOUNT: &str = r#"
         "ssh_publickey",
         "radius_secret",
         "account_expire",
-        "account_valid_from"
+        "account_valid_from",
+        "spn_index",
+        "spn_locked",
+        "origin_domain"
       ],
       "systemmust": [
         "displayname",
@@ -595,7 +799,8 @@ pub const JSON_SCHEMA_CLASS_DOMAIN_INFO: &str = r#"
         "domain_info"
       ],
       "systemmay": [
-        "domain_ssid"
+        "domain_ssid",
+        "domain_spn_prefix"
       ],
       "systemmust": [
         "name",
@@ -661,6 +866,30 @@ pub const JSON_SCHEMA_CLASS_POSIXACCOUNT: &str = r#"
   }
 "#;
 
+// An account or group that deliberately has no spn, because it is outside of the
+// spn_scope (or is the anonymous account with its spn suppressed). This is
+// managed by the spn plugin, and exempts the entry from the spn systemmust.
+pub const JSON_SCHEMA_CLASS_SPN_EXEMPT: &str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "An account or group that is not given an spn, managed by the spn plugin."
+      ],
+      "classname": [
+        "spn_exempt"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000081"
+      ]
+    }
+  }
+"#;
+
 pub const JSON_SCHEMA_CLASS_SYSTEM_CONFIG: &str = r#"
   {
     "attrs": {
@@ -677,7 +906,10 @@ pub const JSON_SCHEMA_CLASS_SYSTEM_CONFIG: &str = r#"
       ],
       "systemmay": [
         "description",
-        "badlist_password"
+        "badlist_password",
+        "spn_scope",
+        "spn_skip_expired",
+        "spn_optout_group"
       ],
       "uuid": [
         "00000000-0000-0000-0000-ffff00000060"
@@ -22,7 +22,7 @@ pub const _STR_UUID_IDM_GROUP_MANAGE_PRIV: &str = "00000000-0000-0000-0000-00000
 pub const _STR_UUID_IDM_HP_ACCOUNT_MANAGE_PRIV: &str = "00000000-0000-0000-0000-000000000016";
 pub const _STR_UUID_IDM_HP_GROUP_MANAGE_PRIV: &str = "00000000-0000-0000-0000-000000000017";
 pub const _STR_UUID_IDM_ADMIN_V1: &str = "00000000-0000-0000-0000-000000000018";
-pub const _STR_UUID_SYSTEM_ADMINS: &str = "00000000-0000-0000-0000-000000000019";
+pub const STR_UUID_SYSTEM_ADMINS: &str = "00000000-0000-0000-0000-000000000019";
 pub const STR_UUID_DOMAIN_ADMINS: &str = "00000000-0000-0000-0000-000000000020";
 pub const _STR_UUID_IDM_ACCOUNT_UNIX_EXTEND_PRIV: &str = "00000000-0000-0000-0000-000000000021";
 pub const _STR_UUID_IDM_GROUP_UNIX_EXTEND_PRIV: &str = "00000000-0000-0000-0000-000000000022";
@@ -122,6 +122,14 @@ pub const STR_UUID_SCHEMA_ATTR_OBJECTCLASS: &str = "00000000-0000-0000-0000-ffff
 
 pub const _STR_UUID_SCHEMA_ATTR_ACCOUNT_EXPIRE: &str = "00000000-0000-0000-0000-ffff00000072";
 pub const _STR_UUID_SCHEMA_ATTR_ACCOUNT_VALID_FROM: &str = "00000000-0000-0000-0000-ffff00000073";
+pub const _STR_UUID_SCHEMA_ATTR_SPN_INDEX: &str = "00000000-0000-0000-0000-ffff00000074";
+pub const _STR_UUID_SCHEMA_ATTR_SPN_SCOPE: &str = "00000000-0000-0000-0000-ffff00000075";
+pub const _STR_UUID_SCHEMA_ATTR_SPN_SKIP_EXPIRED: &str = "00000000-0000-0000-0000-ffff00000076";
+pub const _STR_UUID_SCHEMA_ATTR_SPN_LOCKED: &str = "00000000-0000-0000-0000-ffff00000077";
+pub const _STR_UUID_SCHEMA_ATTR_DOMAIN_SPN_PREFIX: &str = "00000000-0000-0000-0000-ffff00000078";
+pub const _STR_UUID_SCHEMA_ATTR_SPN_OPTOUT_GROUP: &str = "00000000-0000-0000-0000-ffff00000079";
+pub const _STR_UUID_SCHEMA_ATTR_ORIGIN_DOMAIN: &str = "00000000-0000-0000-0000-ffff00000080";
+pub const _STR_UUID_SCHEMA_CLASS_SPN_EXEMPT: &str = "00000000-0000-0000-0000-ffff00000081";
 
 // System and domain infos
 // I'd like to strongly criticise william of the past for making poor choices about these allocations.
@@ -171,6 +179,7 @@ pub const _STR_UUID_IDM_HP_ACP_ACCOUNT_UNIX_EXTEND_PRIV_V1: &str =
     "00000000-0000-0000-0000-ffffff000033";
 pub const _STR_UUID_IDM_HP_ACP_GROUP_UNIX_EXTEND_PRIV_V1: &str =
     "00000000-0000-0000-0000-ffffff000034";
+pub const _STR_UUID_IDM_ACP_SYSTEM_ADMIN_OPS_PRIV_V1: &str = "00000000-0000-0000-0000-ffffff000035";
 
 // End of system ranges
 pub const STR_UUID_DOES_NOT_EXIST: &str = "00000000-0000-0000-0000-fffffffffffe";
@@ -180,6 +189,7 @@ lazy_static! {
     pub static ref UUID_ADMIN: Uuid = Uuid::parse_str(STR_UUID_ADMIN).unwrap();
     pub static ref UUID_DOES_NOT_EXIST: Uuid = Uuid::parse_str(STR_UUID_DOES_NOT_EXIST).unwrap();
     pub static ref UUID_ANONYMOUS: Uuid = Uuid::parse_str(STR_UUID_ANONYMOUS).unwrap();
+    pub static ref UUID_SYSTEM_ADMINS: Uuid = Uuid::parse_str(STR_UUID_SYSTEM_ADMINS).unwrap();
     pub static ref UUID_SYSTEM_CONFIG: Uuid = Uuid::parse_str(STR_UUID_SYSTEM_CONFIG).unwrap();
     pub static ref UUID_SYSTEM_INFO: Uuid = Uuid::parse_str(STR_UUID_SYSTEM_INFO).unwrap();
     pub static ref UUID_DOMAIN_INFO: Uuid = Uuid::parse_str(STR_UUID_DOMAIN_INFO).unwrap();
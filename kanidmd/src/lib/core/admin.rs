@@ -0,0 +1,199 @@
+//! A unix domain socket for local recovery, such as resetting the admin
+//! credential when authentication is broken. It only exists when
+//! admin_socket_path is configured. Access is limited by the permissions of the
+//! socket, and connections are only accepted from root or the server's own user.
+use crate::actors::v1_write::QueryServerWriteV1;
+use crate::standby::StandbyMonitor;
+use libc::geteuid;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use uuid::Uuid;
+
+// A request is a single line of json, and none should be anywhere near this.
+const ADMIN_REQUEST_MAX: u64 = 64 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRequest {
+    RecoverAccount { name: String, password: String },
+    Promote,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminResponse {
+    Success,
+    Error(String),
+}
+
+async fn handle_request(
+    req: AdminRequest,
+    qe_w_ref: &'static QueryServerWriteV1,
+    standby: Option<&StandbyMonitor>,
+) -> AdminResponse {
+    match req {
+        AdminRequest::RecoverAccount { name, password } => {
+            let eventid = Uuid::new_v4();
+            match qe_w_ref
+                .handle_admin_recover_account(name.clone(), password, eventid)
+                .await
+            {
+                Ok(()) => {
+                    info!("Admin socket recovered account {}", name);
+                    AdminResponse::Success
+                }
+                Err(e) => AdminResponse::Error(format!("{:?}", e)),
+            }
+        }
+        AdminRequest::Promote => match standby {
+            Some(s) => {
+                if !s.promote("manually promoted through the admin socket") {
+                    info!("Admin socket promotion requested, but already promoted");
+                }
+                AdminResponse::Success
+            }
+            None => AdminResponse::Error("This server is not a standby".to_string()),
+        },
+    }
+}
+
+async fn client_process(
+    stream: UnixStream,
+    qe_w_ref: &'static QueryServerWriteV1,
+    standby: Option<Arc<StandbyMonitor>>,
+) {
+    // The socket permissions should already prevent this, but they can be changed.
+    let server_uid = unsafe { geteuid() };
+    match stream.peer_cred() {
+        Ok(cred) if cred.uid() == 0 || cred.uid() == server_uid => {}
+        Ok(cred) => {
+            error!("Admin socket connection refused for uid {}", cred.uid());
+            return;
+        }
+        Err(e) => {
+            error!("Unable to determine admin socket peer -> {:?}", e);
+            return;
+        }
+    }
+
+    let (r, mut w) = stream.into_split();
+    let mut line = String::new();
+    let resp = match BufReader::new(r.take(ADMIN_REQUEST_MAX))
+        .read_line(&mut line)
+        .await
+    {
+        Ok(_) => match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(req) => handle_request(req, qe_w_ref, standby.as_deref()).await,
+            Err(e) => AdminResponse::Error(format!("Invalid request -> {}", e)),
+        },
+        Err(e) => AdminResponse::Error(format!("Unable to read request -> {:?}", e)),
+    };
+
+    let res = match serde_json::to_string(&resp) {
+        Ok(mut s) => {
+            s.push('\n');
+            w.write_all(s.as_bytes())
+                .await
+                .map_err(|e| format!("{:?}", e))
+        }
+        Err(e) => Err(format!("{:?}", e)),
+    };
+    if let Err(e) = res {
+        error!("Unable to send admin socket response -> {}", e);
+    }
+}
+
+async fn acceptor(
+    listener: UnixListener,
+    qe_w_ref: &'static QueryServerWriteV1,
+    standby: Option<Arc<StandbyMonitor>>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _addr)) => {
+                tokio::spawn(client_process(stream, qe_w_ref, standby.clone()));
+            }
+            Err(e) => {
+                error!("Admin socket acceptor error, continuing -> {:?}", e);
+            }
+        }
+    }
+}
+
+pub(crate) async fn create_admin_server(
+    path: &str,
+    qe_w_ref: &'static QueryServerWriteV1,
+    standby: Option<Arc<StandbyMonitor>>,
+) -> Result<(), ()> {
+    // A socket left by a previous run must be removed before we can bind, but
+    // never remove something else that happens to be at the path.
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            error!("Admin socket path {} exists and is not a socket", path);
+            return Err(());
+        }
+        fs::remove_file(path).map_err(|e| {
+            error!("Unable to remove stale admin socket {} -> {:?}", path, e);
+        })?;
+    }
+
+    let listener = UnixListener::bind(Path::new(path)).map_err(|e| {
+        error!("Could not bind to admin socket {} -> {:?}", path, e);
+    })?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(|e| {
+        error!(
+            "Unable to set permissions of admin socket {} -> {:?}",
+            path, e
+        );
+    })?;
+
+    info!("Starting admin socket {} ...", path);
+    tokio::spawn(acceptor(listener, qe_w_ref, standby));
+    Ok(())
+}
+
+/// Recover an account through the admin socket of a running server, rather than
+/// by opening the database directly.
+pub async fn admin_recover_account(path: &str, name: &str, password: &str) -> Result<(), String> {
+    admin_request(
+        path,
+        &AdminRequest::RecoverAccount {
+            name: name.to_string(),
+            password: password.to_string(),
+        },
+    )
+    .await
+}
+
+/// Promote a running standby so that it accepts writes.
+pub async fn admin_promote(path: &str) -> Result<(), String> {
+    admin_request(path, &AdminRequest::Promote).await
+}
+
+async fn admin_request(path: &str, req: &AdminRequest) -> Result<(), String> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| format!("Unable to connect to admin socket {} -> {:?}", path, e))?;
+    let (r, mut w) = stream.into_split();
+
+    let mut req = serde_json::to_string(req).map_err(|e| format!("{:?}", e))?;
+    req.push('\n');
+    w.write_all(req.as_bytes())
+        .await
+        .map_err(|e| format!("Unable to send request -> {:?}", e))?;
+
+    let mut line = String::new();
+    BufReader::new(r)
+        .read_line(&mut line)
+        .await
+        .map_err(|e| format!("Unable to read response -> {:?}", e))?;
+    match serde_json::from_str::<AdminResponse>(&line) {
+        Ok(AdminResponse::Success) => Ok(()),
+        Ok(AdminResponse::Error(e)) => Err(e),
+        Err(e) => Err(format!("Invalid response -> {}", e)),
+    }
+}
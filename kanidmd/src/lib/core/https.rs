@@ -1,32 +1,48 @@
 use crate::actors::v1_read::QueryServerReadV1;
 use crate::actors::v1_write::QueryServerWriteV1;
-use crate::config::{ServerRole, TlsConfiguration};
+use crate::config::{Configuration, CookieSameSite, ReauthOperation, ServerRole};
+use crate::constants::UUID_ANONYMOUS;
 use crate::event::AuthResult;
 use crate::filter::{Filter, FilterInvalid};
 use crate::idm::AuthState;
+use crate::standby::StandbyMonitor;
 use crate::status::{StatusActor, StatusRequestEvent};
+use crate::utils::duration_from_epoch_now;
 use crate::value::PartialValue;
+use crate::write_queue::WriteQueue;
 
 use kanidm_proto::v1::Entry as ProtoEntry;
+use kanidm_proto::v1::Filter as ProtoFilter;
 use kanidm_proto::v1::{
-    AccountUnixExtend, AuthRequest, AuthResponse, AuthState as ProtoAuthState, CreateRequest,
-    DeleteRequest, GroupUnixExtend, ModifyRequest, OperationError, SearchRequest,
-    SetCredentialRequest, SingleStringRequest, UserAuthToken,
+    AccountUnixExtend, AuthEventQuery, AuthRequest, AuthResponse, AuthState as ProtoAuthState,
+    AuthStep, AuthTraceRequest, CreateRequest, DeleteRequest, GroupUnixExtend, ModifyRequest,
+    OperationError, SearchRequest, SetCredentialRequest, SingleStringRequest, SpnConfig,
+    SystemConfig, UserAuthToken,
 };
 
 use serde::Serialize;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-// Temporary
-use tide_rustls::TlsListener;
+use super::listener::{load_tls_config, HttpListener};
 // use openssl::ssl::{SslAcceptor, SslAcceptorBuilder};
 // use tokio::net::TcpListener;
 // use async_std::io;
+use async_std::io::ReadExt;
 use async_std::task;
 // use std::net;
 // use std::str::FromStr;
 
+// The window over which anonymous_auth_rate_limit is counted.
+const ANONYMOUS_AUTH_RATE_WINDOW: Duration = Duration::from_secs(60);
+// How many source addresses to track before forgetting expired windows.
+const ANONYMOUS_AUTH_RATE_TRACKED_MAX: usize = 4096;
+// How long (in seconds) a user auth token is valid for once issued.
+const UAT_TTL: u64 = 3600;
+
 #[derive(Clone)]
 pub struct AppState {
     pub status_ref: &'static StatusActor,
@@ -34,6 +50,45 @@ pub struct AppState {
     pub qe_r_ref: &'static QueryServerReadV1,
     // Store the token management parts.
     pub fernet_handle: fernet::Fernet,
+    anonymous_auth_limiter: Option<AnonymousAuthLimiter>,
+    token_expiry_grace: u64,
+    reauth_window: u64,
+    reauth_operations: Vec<ReauthOperation>,
+    system_config: SystemConfig,
+}
+
+// Decrypt a token at time `ct`. A token that expired less than `grace` seconds ago
+// is still accepted, so that small clock differences with the client don't
+// cause spurious re-authentication.
+fn decrypt_token_with_grace(
+    kref: &fernet::Fernet,
+    token: &str,
+    grace: u64,
+    ct: u64,
+) -> Option<Vec<u8>> {
+    match kref.decrypt_at_time(token, Some(UAT_TTL), ct) {
+        Ok(b) => Some(b),
+        Err(_) if grace > 0 => kref
+            .decrypt_at_time(token, Some(UAT_TTL + grace), ct)
+            .ok()
+            .map(|b| {
+                warn!("Accepting an expired token within the token_expiry_grace window");
+                b
+            }),
+        Err(_) => None,
+    }
+}
+
+fn bearer_token(req: &tide::Request<AppState>) -> Option<String> {
+    req.header(tide::http::headers::AUTHORIZATION)
+        .and_then(|hv| {
+            // Get the first header value.
+            hv.get(0)
+        })
+        .and_then(|h| {
+            // Turn it to a &str, and then check the prefix
+            h.as_str().strip_prefix("Bearer ").map(|s| s.to_string())
+        })
 }
 
 pub trait RequestExtensions {
@@ -48,25 +103,16 @@ impl RequestExtensions for tide::Request<AppState> {
     fn get_current_uat(&self) -> Option<UserAuthToken> {
         // Contact the QS to get it to validate wtf is up.
         let kref = &self.state().fernet_handle;
+        let grace = self.state().token_expiry_grace;
         // self.session().get::<UserAuthToken>("uat")
-        self.header(tide::http::headers::AUTHORIZATION)
-            .and_then(|hv| {
-                // Get the first header value.
-                hv.get(0)
-            })
-            .and_then(|h| {
-                // Turn it to a &str, and then check the prefix
-                h.as_str().strip_prefix("Bearer ")
-            })
-            .and_then(|ts| {
-                // Take the token str and attempt to decrypt
-                // Attempt to re-inflate a UAT from bytes.
-                let uat: Option<UserAuthToken> = kref
-                    .decrypt_with_ttl(ts, 3600)
-                    .ok()
-                    .and_then(|b| serde_json::from_slice(&b).ok());
-                uat
-            })
+        bearer_token(self).and_then(|ts| {
+            // Take the token str and attempt to decrypt
+            // Attempt to re-inflate a UAT from bytes.
+            let ct = duration_from_epoch_now().as_secs();
+            let uat: Option<UserAuthToken> = decrypt_token_with_grace(kref, &ts, grace, ct)
+                .and_then(|b| serde_json::from_slice(&b).ok());
+            uat
+        })
     }
 
     fn get_current_auth_session_id(&self) -> Option<Uuid> {
@@ -111,14 +157,22 @@ pub fn to_tide_response<T: Serialize>(
         }
         Err(e) => {
             let sc = match &e {
-                OperationError::NotAuthenticated => tide::StatusCode::Unauthorized,
+                OperationError::NotAuthenticated | OperationError::ReauthRequired => {
+                    tide::StatusCode::Unauthorized
+                }
                 OperationError::SystemProtectedObject | OperationError::AccessDenied => {
                     tide::StatusCode::Forbidden
                 }
                 OperationError::NoMatchingEntries => tide::StatusCode::NotFound,
-                OperationError::EmptyRequest | OperationError::SchemaViolation(_) => {
-                    tide::StatusCode::BadRequest
+                OperationError::EmptyRequest
+                | OperationError::SchemaViolation(_)
+                | OperationError::AuthTraceDisabled
+                | OperationError::AuthTraceTooLong(_)
+                | OperationError::SearchSizeLimitExceeded(_) => tide::StatusCode::BadRequest,
+                OperationError::PasswordTooLong(_) | OperationError::CredentialTooLarge(_) => {
+                    tide::StatusCode::PayloadTooLarge
                 }
+                OperationError::ServerBusy => tide::StatusCode::ServiceUnavailable,
                 _ => tide::StatusCode::InternalServerError,
             };
             let mut res = tide::Response::new(sc);
@@ -142,6 +196,36 @@ macro_rules! new_eventid {
     }};
 }
 
+// Operations in reauth_operations need a session that authenticated within the
+// reauth_window, even though it is otherwise still valid. Returns the response
+// refusing the request if the session is too old.
+fn reauth_refusal(req: &tide::Request<AppState>, op: ReauthOperation) -> Option<tide::Result> {
+    let state = req.state();
+    if !state.reauth_operations.contains(&op) {
+        return None;
+    }
+    // Requests without a valid session are refused by the operation itself.
+    let token = bearer_token(req)?;
+    let ct = duration_from_epoch_now().as_secs();
+    decrypt_token_with_grace(&state.fernet_handle, &token, state.token_expiry_grace, ct)?;
+    if state
+        .fernet_handle
+        .decrypt_at_time(&token, Some(state.reauth_window), ct)
+        .is_ok()
+    {
+        return None;
+    }
+    info!(
+        "Refusing {} operation, the session did not authenticate within the reauth_window",
+        op
+    );
+    let (_, hvalue) = new_eventid!();
+    Some(to_tide_response::<()>(
+        Err(OperationError::ReauthRequired),
+        hvalue,
+    ))
+}
+
 // Handle the various end points we need to expose
 async fn index_view(_req: tide::Request<AppState>) -> tide::Result {
     let mut res = tide::Response::new(200);
@@ -230,7 +314,7 @@ pub async fn json_rest_event_get(
         .qe_r_ref
         .handle_internalsearch(uat, filter, attrs, eventid)
         .await;
-    to_tide_response(res, hvalue)
+    to_tide_response(res.map(|sr| sr.entries), hvalue)
 }
 
 async fn json_rest_event_get_id(
@@ -250,7 +334,7 @@ async fn json_rest_event_get_id(
         .qe_r_ref
         .handle_internalsearch(uat, filter, attrs, eventid)
         .await
-        .map(|mut r| r.pop());
+        .map(|mut r| r.entries.pop());
     to_tide_response(res, hvalue)
 }
 
@@ -291,7 +375,12 @@ async fn json_rest_event_get_id_attr(
         .qe_r_ref
         .handle_internalsearch(uat, filter, attrs, eventid)
         .await
-        .map(|mut event_result| event_result.pop().and_then(|mut e| e.attrs.remove(&attr)));
+        .map(|mut event_result| {
+            event_result
+                .entries
+                .pop()
+                .and_then(|mut e| e.attrs.remove(&attr))
+        });
     to_tide_response(res, hvalue)
 }
 
@@ -391,6 +480,9 @@ async fn json_rest_event_credential_put(
     mut req: tide::Request<AppState>,
     appid: Option<String>,
 ) -> tide::Result {
+    if let Some(res) = reauth_refusal(&req, ReauthOperation::Credential) {
+        return res;
+    }
     let uat = req.get_current_uat();
     let uuid_or_name = req.get_url_param("id")?;
     let sac: SetCredentialRequest = req.body_json().await?;
@@ -449,7 +541,7 @@ pub async fn schema_attributetype_get_id(req: tide::Request<AppState>) -> tide::
         .qe_r_ref
         .handle_internalsearch(uat, filter, None, eventid)
         .await
-        .map(|mut r| r.pop());
+        .map(|mut r| r.entries.pop());
     to_tide_response(res, hvalue)
 }
 
@@ -475,7 +567,7 @@ pub async fn schema_classtype_get_id(req: tide::Request<AppState>) -> tide::Resu
         .qe_r_ref
         .handle_internalsearch(uat, filter, None, eventid)
         .await
-        .map(|mut r| r.pop());
+        .map(|mut r| r.entries.pop());
     to_tide_response(res, hvalue)
 }
 
@@ -557,6 +649,20 @@ pub async fn account_get_id_credential_status(req: tide::Request<AppState>) -> t
     to_tide_response(res, hvalue)
 }
 
+pub async fn account_get_id_auth_policy(req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+    let uuid_or_name = req.get_url_param("id")?;
+
+    let (eventid, hvalue) = new_eventid!();
+
+    let res = req
+        .state()
+        .qe_r_ref
+        .handle_idmauthpolicy(uat, uuid_or_name, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
 // Return a vec of str
 pub async fn account_get_id_ssh_pubkeys(req: tide::Request<AppState>) -> tide::Result {
     let uat = req.get_current_uat();
@@ -639,6 +745,9 @@ pub async fn account_get_id_radius(req: tide::Request<AppState>) -> tide::Result
 }
 
 pub async fn account_post_id_radius_regenerate(req: tide::Request<AppState>) -> tide::Result {
+    if let Some(res) = reauth_refusal(&req, ReauthOperation::Credential) {
+        return res;
+    }
     // Need to to send the regen msg
     let uat = req.get_current_uat();
     let uuid_or_name = req.get_url_param("id")?;
@@ -654,6 +763,9 @@ pub async fn account_post_id_radius_regenerate(req: tide::Request<AppState>) ->
 }
 
 pub async fn account_delete_id_radius(req: tide::Request<AppState>) -> tide::Result {
+    if let Some(res) = reauth_refusal(&req, ReauthOperation::Credential) {
+        return res;
+    }
     let attr = "radius_secret".to_string();
     let filter = filter_all!(f_eq("class", PartialValue::new_class("account")));
     json_rest_event_delete_id_attr(req, filter, attr).await
@@ -729,6 +841,9 @@ pub async fn account_post_id_unix_auth(mut req: tide::Request<AppState>) -> tide
 }
 
 pub async fn account_put_id_unix_credential(mut req: tide::Request<AppState>) -> tide::Result {
+    if let Some(res) = reauth_refusal(&req, ReauthOperation::Credential) {
+        return res;
+    }
     let uat = req.get_current_uat();
     let uuid_or_name = req.get_url_param("id")?;
     let obj: SingleStringRequest = req.body_json().await?;
@@ -744,6 +859,9 @@ pub async fn account_put_id_unix_credential(mut req: tide::Request<AppState>) ->
 }
 
 pub async fn account_delete_id_unix_credential(req: tide::Request<AppState>) -> tide::Result {
+    if let Some(res) = reauth_refusal(&req, ReauthOperation::Credential) {
+        return res;
+    }
     let uat = req.get_current_uat();
     let uuid_or_name = req.get_url_param("id")?;
     let attr = "unix_password".to_string();
@@ -760,6 +878,47 @@ pub async fn account_delete_id_unix_credential(req: tide::Request<AppState>) ->
     to_tide_response(res, hvalue)
 }
 
+pub async fn account_put_id_spn(mut req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+    let uuid_or_name = req.get_url_param("id")?;
+    let obj: SingleStringRequest = req.body_json().await?;
+    let filter = filter_all!(f_eq("class", PartialValue::new_class("account")));
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_w_ref
+        .handle_accountspnlock(uat, uuid_or_name, Some(obj.value), filter, eventid)
+        .await
+        .map(|()| true);
+    to_tide_response(res, hvalue)
+}
+
+pub async fn account_delete_id_spn(req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+    let uuid_or_name = req.get_url_param("id")?;
+    let filter = filter_all!(f_eq("class", PartialValue::new_class("account")));
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_w_ref
+        .handle_accountspnlock(uat, uuid_or_name, None, filter, eventid)
+        .await
+        .map(|()| true);
+    to_tide_response(res, hvalue)
+}
+
+pub async fn account_post_id_spn_regenerate(req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+    let uuid_or_name = req.get_url_param("id")?;
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_w_ref
+        .handle_accountspnregenerate(uat, uuid_or_name, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
 pub async fn group_get(req: tide::Request<AppState>) -> tide::Result {
     let filter = filter_all!(f_eq("class", PartialValue::new_class("group")));
     json_rest_event_get(req, filter, None).await
@@ -848,10 +1007,41 @@ pub async fn domain_id_get_attr(
 }
 
 pub async fn domain_id_put_attr(req: tide::Request<AppState>) -> tide::Result {
+    if let Some(res) = reauth_refusal(&req, ReauthOperation::Admin) {
+        return res;
+    }
     let filter = filter_all!(f_eq("class", PartialValue::new_class("domain_info")));
     json_rest_event_put_id_attr(req, filter).await
 }
 
+pub async fn domain_spn_config_get(req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_r_ref
+        .handle_spnconfigexport(uat, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
+pub async fn domain_spn_config_post(mut req: tide::Request<AppState>) -> tide::Result {
+    if let Some(res) = reauth_refusal(&req, ReauthOperation::Admin) {
+        return res;
+    }
+    let uat = req.get_current_uat();
+    let config: SpnConfig = req.body_json().await?;
+
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_w_ref
+        .handle_spnconfigimport(uat, config, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
 pub async fn recycle_bin_get(req: tide::Request<AppState>) -> tide::Result {
     let filter = filter_all!(f_pres("class"));
     let uat = req.get_current_uat();
@@ -899,6 +1089,123 @@ pub async fn recycle_bin_revive_id_post(req: tide::Request<AppState>) -> tide::R
     to_tide_response(res, hvalue)
 }
 
+pub async fn system_spn_fsck_get(req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+
+    let (eventid, hvalue) = new_eventid!();
+    let res = req.state().qe_r_ref.handle_spnfsck(uat, eventid).await;
+    to_tide_response(res, hvalue)
+}
+
+pub async fn system_spn_fsck_post(req: tide::Request<AppState>) -> tide::Result {
+    if let Some(res) = reauth_refusal(&req, ReauthOperation::Admin) {
+        return res;
+    }
+    let uat = req.get_current_uat();
+
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_w_ref
+        .handle_spnfsckrepair(uat, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
+pub async fn system_spn_unlock_post(mut req: tide::Request<AppState>) -> tide::Result {
+    if let Some(res) = reauth_refusal(&req, ReauthOperation::Admin) {
+        return res;
+    }
+    let uat = req.get_current_uat();
+    let filter: ProtoFilter = req.body_json().await?;
+
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_w_ref
+        .handle_spnunlock(uat, filter, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
+pub async fn system_config_get(req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+    let config = req.state().system_config.clone();
+
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_r_ref
+        .handle_systemconfig(uat, config, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
+pub async fn system_spn_bench_post(mut req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+    let count: usize = req.body_json().await?;
+
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_r_ref
+        .handle_spnbench(uat, count, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
+pub async fn system_stats_entry_sizes_post(mut req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+    let top: usize = req.body_json().await?;
+
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_r_ref
+        .handle_entrysizes(uat, top, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
+pub async fn system_auth_events_post(mut req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+    let query: AuthEventQuery = req.body_json().await?;
+
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_r_ref
+        .handle_authevents(uat, query, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
+pub async fn system_auth_trace_post(mut req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+    let obj: AuthTraceRequest = req.body_json().await?;
+
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_r_ref
+        .handle_authtracestart(uat, obj, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
+pub async fn system_auth_trace_id_get(req: tide::Request<AppState>) -> tide::Result {
+    let uat = req.get_current_uat();
+    let id = req.get_url_param("id")?;
+
+    let (eventid, hvalue) = new_eventid!();
+    let res = req
+        .state()
+        .qe_r_ref
+        .handle_authtrace(uat, id, eventid)
+        .await;
+    to_tide_response(res, hvalue)
+}
+
 pub async fn do_nothing(_req: tide::Request<AppState>) -> tide::Result {
     let mut res = tide::Response::new(200);
     res.set_body("did nothing");
@@ -914,11 +1221,38 @@ pub async fn auth(mut req: tide::Request<AppState>) -> tide::Result {
     let maybe_sessionid = req.get_current_auth_session_id();
     debug!("🍿 {:?}", maybe_sessionid);
 
+    let peer_ip = req
+        .peer_addr()
+        .and_then(|a| a.parse::<SocketAddr>().ok())
+        .map(|a| a.ip());
+
     let obj: AuthRequest = req.body_json().await.map_err(|e| {
         debug!("wat? {:?}", e);
         e
     })?;
 
+    // Anonymous needs no credentials, so limit how often each source may begin
+    // an anonymous auth. This is refused before any auth session is created.
+    let limited = match (&req.state().anonymous_auth_limiter, peer_ip, &obj.step) {
+        (Some(limiter), Some(ip), AuthStep::Init(name)) if is_anonymous_name(name) => {
+            !limiter.check(ip, Instant::now())
+        }
+        _ => false,
+    };
+    if limited {
+        debug!(
+            "Denying anonymous auth, rate limit exceeded -> {:?}",
+            peer_ip
+        );
+        let res: Result<AuthResponse, OperationError> = Ok(AuthResponse {
+            state: ProtoAuthState::Denied(
+                "Too many anonymous authentications from this address".to_string(),
+            ),
+            sessionid: Uuid::new_v4(),
+        });
+        return to_tide_response(res, hvalue);
+    }
+
     let mut auth_session_id_tok = None;
 
     // We probably need to know if we allocate the cookie, that this is a
@@ -928,7 +1262,7 @@ pub async fn auth(mut req: tide::Request<AppState>) -> tide::Result {
         .state()
         // This may change in the future ...
         .qe_r_ref
-        .handle_auth(maybe_sessionid, obj, eventid)
+        .handle_auth(maybe_sessionid, obj, peer_ip, eventid)
         .await
     {
         // .and_then(|ar| {
@@ -1021,6 +1355,9 @@ pub async fn auth(mut req: tide::Request<AppState>) -> tide::Result {
 }
 
 pub async fn idm_account_set_password(mut req: tide::Request<AppState>) -> tide::Result {
+    if let Some(res) = reauth_refusal(&req, ReauthOperation::Credential) {
+        return res;
+    }
     let uat = req.get_current_uat();
     let obj: SingleStringRequest = req.body_json().await?;
     let cleartext = obj.value;
@@ -1035,6 +1372,12 @@ pub async fn idm_account_set_password(mut req: tide::Request<AppState>) -> tide:
 
 // == Status
 
+pub async fn auth_capabilities(req: tide::Request<AppState>) -> tide::Result {
+    let (eventid, hvalue) = new_eventid!();
+    let res = req.state().qe_r_ref.handle_authcapabilities(eventid).await;
+    to_tide_response(res, hvalue)
+}
+
 pub async fn status(req: tide::Request<AppState>) -> tide::Result {
     // We ignore the body in this req
     let (eventid, hvalue) = new_eventid!();
@@ -1148,19 +1491,257 @@ impl<State: Clone + Send + Sync + 'static> tide::listener::Listener<State> for T
 }
 */
 
-// TODO: Add request limits.
-pub fn create_https_server(
-    address: String,
-    // opt_tls_params: Option<SslAcceptorBuilder>,
-    opt_tls_params: Option<&TlsConfiguration>,
-    role: ServerRole,
+fn role_rejected_response(status: tide::StatusCode, message: &str) -> tide::Response {
+    let mut res = tide::Response::new(status);
+    res.set_content_type("text/plain;charset=utf-8");
+    res.set_body(message);
+    res
+}
+
+// Requests that may change state, and so are refused by a standby. Some requests
+// are a POST, but only read state so a standby can still serve them.
+fn is_write_request(method: tide::http::Method, path: &str) -> bool {
+    match method {
+        tide::http::Method::Get | tide::http::Method::Head | tide::http::Method::Options => false,
+        tide::http::Method::Post => {
+            !(path == "/v1/auth" || path == "/v1/raw/search" || path.ends_with("/_unix/_auth"))
+        }
+        _ => true,
+    }
+}
+
+// A standby refuses writes until it has been promoted.
+struct StandbyMiddleware {
+    message: String,
+    standby: Arc<StandbyMonitor>,
+}
+
+#[async_trait::async_trait]
+impl tide::Middleware<AppState> for StandbyMiddleware {
+    async fn handle(
+        &self,
+        req: tide::Request<AppState>,
+        next: tide::Next<'_, AppState>,
+    ) -> tide::Result {
+        if is_write_request(req.method(), req.url().path()) && !self.standby.is_promoted() {
+            debug!("Rejecting write to standby -> {}", req.url().path());
+            return Ok(role_rejected_response(
+                tide::StatusCode::MethodNotAllowed,
+                self.message.as_str(),
+            ));
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+// Takes a place in the write queue for each write request, held until it completes.
+struct WriteQueueMiddleware {
+    queue: WriteQueue,
+}
+
+#[async_trait::async_trait]
+impl tide::Middleware<AppState> for WriteQueueMiddleware {
+    async fn handle(
+        &self,
+        req: tide::Request<AppState>,
+        next: tide::Next<'_, AppState>,
+    ) -> tide::Result {
+        if !is_write_request(req.method(), req.url().path()) {
+            return Ok(next.run(req).await);
+        }
+        match self.queue.enter() {
+            Ok(_ticket) => Ok(next.run(req).await),
+            Err(e) => {
+                let (_, hvalue) = new_eventid!();
+                to_tide_response::<()>(Err(e), hvalue)
+            }
+        }
+    }
+}
+
+// Counts the anonymous auths begun by each source address within a fixed window,
+// so that anonymous auth can't be used to flood the server with sessions.
+#[derive(Clone)]
+struct AnonymousAuthLimiter {
+    limit: u32,
+    window: Duration,
+    granted: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>,
+}
+
+impl AnonymousAuthLimiter {
+    fn new(limit: u32) -> Self {
+        AnonymousAuthLimiter {
+            limit,
+            window: ANONYMOUS_AUTH_RATE_WINDOW,
+            granted: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Record an anonymous auth from this address, returning false if it exceeds
+    // the limit for the current window.
+    fn check(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut granted = self.granted.lock().unwrap_or_else(|e| e.into_inner());
+        if granted.len() >= ANONYMOUS_AUTH_RATE_TRACKED_MAX {
+            // Forget addresses whose window has passed, so this can't grow unbounded.
+            let window = self.window;
+            granted.retain(|_, (start, _)| now.duration_since(*start) < window);
+        }
+        let (start, count) = granted.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.limit {
+            false
+        } else {
+            *count += 1;
+            true
+        }
+    }
+}
+
+// Does this auth init name the anonymous account? The server accepts its uuid,
+// name or spn.
+fn is_anonymous_name(name: &str) -> bool {
+    match Uuid::parse_str(name) {
+        Ok(u) => u == *UUID_ANONYMOUS,
+        Err(_) => {
+            let lname = name.to_lowercase();
+            lname == "anonymous" || lname.starts_with("anonymous@")
+        }
+    }
+}
+
+fn request_body_rejected(status: tide::StatusCode, message: &str) -> tide::Response {
+    let mut res = tide::Response::new(status);
+    res.insert_header("Connection", "close");
+    res.set_body(message);
+    res
+}
+
+// Read the body of the request up front, bounding both its size and how long we
+// wait for it, so that clients can't hold connections open or exhaust memory.
+// The headers are bounded where the connection is accepted, see listener.rs.
+struct RequestBodyMiddleware {
+    timeout: Option<Duration>,
+    max_size: usize,
+}
+
+#[async_trait::async_trait]
+impl tide::Middleware<AppState> for RequestBodyMiddleware {
+    async fn handle(
+        &self,
+        mut req: tide::Request<AppState>,
+        next: tide::Next<'_, AppState>,
+    ) -> tide::Result {
+        if req.len().map(|l| l > self.max_size).unwrap_or(false) {
+            debug!(
+                "Dropping request, content-length exceeds the maximum body size -> {}",
+                req.url().path()
+            );
+            return Ok(request_body_rejected(
+                tide::StatusCode::PayloadTooLarge,
+                "The request body exceeds the maximum size",
+            ));
+        }
+
+        // Read at most one byte past the limit, so a body without a content-length
+        // that is too large is still detected without being buffered.
+        let body = req.take_body();
+        let limit = self.max_size as u64 + 1;
+        let read = async move {
+            let mut buf = Vec::new();
+            body.take(limit).read_to_end(&mut buf).await.map(|_| buf)
+        };
+        let bytes = match self.timeout {
+            Some(timeout) => match async_std::future::timeout(timeout, read).await {
+                Ok(bytes) => bytes?,
+                Err(_) => {
+                    debug!(
+                        "Dropping request, not read within timeout -> {}",
+                        req.url().path()
+                    );
+                    return Ok(request_body_rejected(
+                        tide::StatusCode::RequestTimeout,
+                        "The request was not received within the read timeout",
+                    ));
+                }
+            },
+            None => read.await?,
+        };
+
+        if bytes.len() > self.max_size {
+            debug!(
+                "Dropping request, body exceeds the maximum body size -> {}",
+                req.url().path()
+            );
+            return Ok(request_body_rejected(
+                tide::StatusCode::PayloadTooLarge,
+                "The request body exceeds the maximum size",
+            ));
+        }
+
+        req.set_body(bytes);
+        Ok(next.run(req).await)
+    }
+}
+
+// Add the configured security headers (hsts, csp and so on) to every response.
+struct SecurityHeadersMiddleware {
+    headers: Vec<(&'static str, String)>,
+}
+
+#[async_trait::async_trait]
+impl tide::Middleware<AppState> for SecurityHeadersMiddleware {
+    async fn handle(
+        &self,
+        req: tide::Request<AppState>,
+        next: tide::Next<'_, AppState>,
+    ) -> tide::Result {
+        let mut res = next.run(req).await;
+        for (name, value) in self.headers.iter() {
+            res.insert_header(*name, value.as_str());
+        }
+        Ok(res)
+    }
+}
+
+// The session cookie is only marked secure when the request was made over https.
+fn session_middleware(
     cookie_key: &[u8; 32],
+    cookie_domain: Option<&str>,
+    cookie_samesite: CookieSameSite,
+) -> tide::sessions::SessionMiddleware<tide::sessions::MemoryStore> {
+    let same_site = match cookie_samesite {
+        CookieSameSite::Strict => tide::http::cookies::SameSite::Strict,
+        CookieSameSite::Lax => tide::http::cookies::SameSite::Lax,
+        CookieSameSite::None => tide::http::cookies::SameSite::None,
+    };
+    let middleware =
+        tide::sessions::SessionMiddleware::new(tide::sessions::MemoryStore::new(), cookie_key)
+            .with_cookie_name("kanidm-session")
+            .with_same_site_policy(same_site)
+            .with_session_ttl(Some(Duration::from_secs(3600)));
+    match cookie_domain {
+        Some(domain) => middleware.with_cookie_domain(domain),
+        None => middleware,
+    }
+}
+
+pub fn create_https_server(
+    config: &Configuration,
+    standby: Option<Arc<StandbyMonitor>>,
     status_ref: &'static StatusActor,
     qe_w_ref: &'static QueryServerWriteV1,
     qe_r_ref: &'static QueryServerReadV1,
 ) -> Result<(), ()> {
     info!("WEB_UI_PKG_PATH -> {}", env!("KANIDM_WEB_UI_PKG_PATH"));
 
+    let system_config = config.effective_config().map_err(|e| {
+        error!("Unable to determine the effective configuration -> {}", e);
+    })?;
+    let http_request_read_timeout = config.http_request_read_timeout.map(Duration::from_secs);
+
     // Create the in memory fernet key
     let fernet_handle = fernet::Fernet::new(&fernet::Fernet::generate_key()).ok_or_else(|| {
         error!("Failed to generate fernet key");
@@ -1171,20 +1752,76 @@ pub fn create_https_server(
         qe_w_ref,
         qe_r_ref,
         fernet_handle,
+        anonymous_auth_limiter: config
+            .anonymous_auth_rate_limit
+            .map(AnonymousAuthLimiter::new),
+        token_expiry_grace: config.token_expiry_grace,
+        reauth_window: config.reauth_window(),
+        reauth_operations: config.reauth_operations.clone(),
+        system_config,
     });
 
     // Add middleware?
-    tserver.with(tide::log::LogMiddleware::new()).with(
-        tide::sessions::SessionMiddleware::new(tide::sessions::MemoryStore::new(), cookie_key)
-            .with_cookie_name("kanidm-session")
-            .with_same_site_policy(tide::http::cookies::SameSite::Strict)
-            .with_session_ttl(Some(Duration::from_secs(3600))),
-    );
+    // This is first so that every response has the headers, even those refused below.
+    let security_headers = config.http_security_headers();
+    if !security_headers.is_empty() {
+        tserver.with(SecurityHeadersMiddleware {
+            headers: security_headers,
+        });
+    }
+
+    tserver.with(RequestBodyMiddleware {
+        timeout: http_request_read_timeout,
+        max_size: config.http_max_body_size,
+    });
+
+    tserver
+        .with(tide::log::LogMiddleware::new())
+        .with(session_middleware(
+            &config.cookie_key,
+            config.cookie_domain.as_deref(),
+            config.cookie_samesite,
+        ));
+
+    if let Some(standby) = standby {
+        tserver.with(StandbyMiddleware {
+            message: config.role_rejection_message().unwrap_or_default(),
+            standby,
+        });
+    }
+
+    tserver.with(WriteQueueMiddleware {
+        queue: WriteQueue::new(config.write_queue_depth),
+    });
 
     // Add routes
 
     // If we are no-ui, we remove this.
-    if !matches!(role, ServerRole::WriteReplicaNoUI) {
+    if matches!(config.role, ServerRole::WriteReplicaNoUI) {
+        // Rather than an unhelpful 404, tell the user why there is no ui here.
+        let message = Arc::new(config.role_rejection_message().unwrap_or_default());
+        let ui_message = message.clone();
+        tserver.at("/").get(move |_req: tide::Request<AppState>| {
+            let message = ui_message.clone();
+            async move {
+                Ok(role_rejected_response(
+                    tide::StatusCode::NotFound,
+                    message.as_str(),
+                ))
+            }
+        });
+        tserver
+            .at("/pkg/*")
+            .get(move |_req: tide::Request<AppState>| {
+                let message = message.clone();
+                async move {
+                    Ok(role_rejected_response(
+                        tide::StatusCode::NotFound,
+                        message.as_str(),
+                    ))
+                }
+            });
+    } else {
         tserver.at("/").get(index_view);
         tserver
             .at("/pkg")
@@ -1207,6 +1844,7 @@ pub fn create_https_server(
     raw_route.at("/search").post(search);
 
     tserver.at("/v1/auth").post(auth);
+    tserver.at("/v1/auth/capabilities").get(auth_capabilities);
 
     let mut schema_route = tserver.at("/v1/schema");
     schema_route.at("/").get(schema_get);
@@ -1279,6 +1917,9 @@ pub fn create_https_server(
     account_route
         .at("/:id/_credential/_status")
         .get(account_get_id_credential_status);
+    account_route
+        .at("/:id/_auth_policy")
+        .get(account_get_id_auth_policy);
     account_route
         .at("/:id/_credential/primary")
         .put(account_put_id_credential_primary);
@@ -1318,6 +1959,15 @@ pub fn create_https_server(
         .put(account_put_id_unix_credential)
         .delete(account_delete_id_unix_credential);
 
+    account_route
+        .at("/:id/_spn")
+        .put(account_put_id_spn)
+        .delete(account_delete_id_spn);
+
+    account_route
+        .at("/:id/_spn/_regenerate")
+        .post(account_post_id_spn_regenerate);
+
     let mut group_route = tserver.at("/v1/group");
     group_route.at("/").get(group_get).post(group_post);
     group_route
@@ -1337,6 +1987,10 @@ pub fn create_https_server(
 
     let mut domain_route = tserver.at("/v1/domain");
     domain_route.at("/").get(domain_get);
+    domain_route
+        .at("/_spn_config")
+        .get(domain_spn_config_get)
+        .post(domain_spn_config_post);
     domain_route.at("/:id").get(domain_id_get);
     domain_route
         .at("/:id/_attr/:attr")
@@ -1350,48 +2004,155 @@ pub fn create_https_server(
         .at("/:id/_revive")
         .post(recycle_bin_revive_id_post);
 
+    let mut system_route = tserver.at("/v1/system");
+    system_route
+        .at("/_spn_fsck")
+        .get(system_spn_fsck_get)
+        .post(system_spn_fsck_post);
+    system_route.at("/_spn_bench").post(system_spn_bench_post);
+    system_route.at("/_spn_unlock").post(system_spn_unlock_post);
+    system_route
+        .at("/_stats/entry_sizes")
+        .post(system_stats_entry_sizes_post);
+    system_route.at("/config").get(system_config_get);
+    system_route
+        .at("/_auth_events")
+        .post(system_auth_events_post);
+    system_route.at("/_auth_trace").post(system_auth_trace_post);
+    system_route
+        .at("/_auth_trace/:id")
+        .get(system_auth_trace_id_get);
+
     let mut accessprof_route = tserver.at("/v1/access_profile");
     accessprof_route.at("/").get(do_nothing);
     accessprof_route.at("/:id").get(do_nothing);
     accessprof_route.at("/:id/_attr/:attr").get(do_nothing);
 
     // Create listener?
-    match opt_tls_params {
-        Some(tls_param) => {
-            let tlsl = TlsListener::build()
-                .addrs(&address)
-                .cert(&tls_param.chain)
-                .key(&tls_param.key)
-                .finish()
-                .map_err(|e| {
-                    error!("Failed to build TLS Listener -> {:?}", e);
-                })?;
-            /*
-            let x = Box::new(tls_param.build());
-            let x_ref = Box::leak(x);
-            let tlsl = TlsListener::new(address, x_ref);
-            */
-
-            tokio::spawn(async move {
-                if let Err(e) = tserver.listen(tlsl).await {
-                    error!(
-                        "Failed to start server listener on address {:?} -> {:?}",
-                        &address, e
-                    );
-                }
-            });
-        }
-        None => {
-            // Create without https
-            tokio::spawn(async move {
-                if let Err(e) = tserver.listen(&address).await {
-                    error!(
-                        "Failed to start server listener on address {:?} -> {:?}",
-                        &address, e,
-                    );
-                }
-            });
-        }
+    let tls_config = match config.tls_config.as_ref() {
+        Some(tls_param) => Some(load_tls_config(tls_param).map_err(|e| {
+            error!("Failed to build TLS Listener -> {:?}", e);
+        })?),
+        // Create without https
+        None => None,
     };
+    let address = config.address.clone();
+    let listener = HttpListener::new(
+        address.clone(),
+        tls_config,
+        http_request_read_timeout,
+        config.max_connections_per_ip,
+    );
+
+    tokio::spawn(async move {
+        if let Err(e) = tserver.listen(listener).await {
+            error!(
+                "Failed to start server listener on address {:?} -> {:?}",
+                &address, e
+            );
+        }
+    });
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decrypt_token_with_grace, is_anonymous_name, is_write_request, session_middleware,
+        AnonymousAuthLimiter, UAT_TTL,
+    };
+    use crate::config::CookieSameSite;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_standby_write_requests() {
+        use tide::http::Method;
+        assert!(!is_write_request(Method::Get, "/v1/account"));
+        assert!(!is_write_request(Method::Post, "/v1/auth"));
+        assert!(!is_write_request(Method::Post, "/v1/raw/search"));
+        assert!(!is_write_request(
+            Method::Post,
+            "/v1/account/testaccount/_unix/_auth"
+        ));
+        assert!(is_write_request(Method::Post, "/v1/raw/create"));
+        assert!(is_write_request(
+            Method::Put,
+            "/v1/self/_credential/primary"
+        ));
+        assert!(is_write_request(Method::Delete, "/v1/account/testaccount"));
+    }
+
+    #[test]
+    fn test_anonymous_auth_limit() {
+        let limiter = AnonymousAuthLimiter::new(2);
+        let ip_a: IpAddr = "192.0.2.1".parse().expect("Invalid ip");
+        let ip_b: IpAddr = "192.0.2.2".parse().expect("Invalid ip");
+        let now = Instant::now();
+
+        assert!(limiter.check(ip_a, now));
+        assert!(limiter.check(ip_a, now));
+        // Over the limit for this address, but not for others.
+        assert!(!limiter.check(ip_a, now));
+        assert!(limiter.check(ip_b, now));
+
+        // Once the window has passed, anonymous auth is allowed again.
+        let later = now + limiter.window + Duration::from_secs(1);
+        assert!(limiter.check(ip_a, later));
+    }
+
+    #[test]
+    fn test_anonymous_auth_name() {
+        assert!(is_anonymous_name("anonymous"));
+        assert!(is_anonymous_name("Anonymous"));
+        assert!(is_anonymous_name("anonymous@example.com"));
+        assert!(is_anonymous_name("00000000-0000-0000-0000-ffffffffffff"));
+        assert!(!is_anonymous_name("admin"));
+        assert!(!is_anonymous_name("anonymous_user"));
+        assert!(!is_anonymous_name("00000000-0000-0000-0000-000000000000"));
+    }
+
+    #[test]
+    fn test_token_expiry_grace() {
+        let kref = fernet::Fernet::new(&fernet::Fernet::generate_key()).expect("Invalid key");
+        let issued = 1_600_000_000;
+        let token = kref.encrypt_at_time(b"uat", issued as i64);
+
+        assert!(decrypt_token_with_grace(&kref, &token, 0, issued + UAT_TTL).is_some());
+        // Just past expiry is only accepted within the grace window.
+        let just_expired = issued + UAT_TTL + 5;
+        assert!(decrypt_token_with_grace(&kref, &token, 0, just_expired).is_none());
+        assert!(decrypt_token_with_grace(&kref, &token, 10, just_expired) == Some(b"uat".to_vec()));
+        assert!(decrypt_token_with_grace(&kref, &token, 10, issued + UAT_TTL + 20).is_none());
+    }
+
+    #[test]
+    fn test_session_cookie_attributes() {
+        let mut app = tide::new();
+        app.with(session_middleware(
+            &[0; 32],
+            Some("example.com"),
+            CookieSameSite::Lax,
+        ));
+        app.at("/").get(|mut req: tide::Request<()>| async move {
+            req.session_mut().insert("test", 1)?;
+            Ok("")
+        });
+
+        let req = tide::http::Request::new(
+            tide::http::Method::Get,
+            tide::http::Url::parse("https://idm.example.com/").expect("Invalid url"),
+        );
+        let res: tide::http::Response =
+            async_std::task::block_on(app.respond(req)).expect("Request failed");
+        let cookie = res
+            .header("set-cookie")
+            .map(|h| h.last().as_str().to_string())
+            .expect("No session cookie");
+
+        assert!(cookie.starts_with("kanidm-session="));
+        assert!(cookie.contains("Domain=example.com"));
+        assert!(cookie.contains("SameSite=Lax"));
+        assert!(cookie.contains("Secure"));
+    }
+}
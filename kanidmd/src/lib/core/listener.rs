@@ -0,0 +1,361 @@
+//! The http listener for the web server. This replaces the listeners provided by
+//! tide so that limits can be applied where connections are accepted, before any
+//! middleware runs.
+
+use async_rustls::server::TlsStream;
+use async_rustls::TlsAcceptor;
+use async_std::io::{Read, Write, WriteExt};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::config::TlsConfiguration;
+
+// The same as the default of async-h1, used when no read timeout is configured.
+const DEFAULT_HEADERS_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Sent to a connection over max_connections_per_ip before it is closed.
+const TOO_MANY_CONNECTIONS_RESPONSE: &[u8] = b"HTTP/1.1 429 Too Many Requests\r\ncontent-type: text/plain\r\ncontent-length: 49\r\nconnection: close\r\n\r\nToo many concurrent connections from this address";
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Load the certificate chain and key for the listener.
+pub fn load_tls_config(tls_param: &TlsConfiguration) -> io::Result<ServerConfig> {
+    let chain = certs(&mut BufReader::new(File::open(&tls_param.chain)?))
+        .map_err(|_| invalid_data(format!("Invalid certificate chain {}", tls_param.chain)))?;
+
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(&tls_param.key)?))
+        .map_err(|_| invalid_data(format!("Invalid private key {}", tls_param.key)))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(&tls_param.key)?))
+            .map_err(|_| invalid_data(format!("Invalid private key {}", tls_param.key)))?;
+    }
+    let key = keys
+        .pop()
+        .ok_or_else(|| invalid_data(format!("No private key found in {}", tls_param.key)))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(chain, key)
+        .map_err(|e| invalid_data(format!("Invalid certificate or key -> {:?}", e)))?;
+    Ok(config)
+}
+
+// async-h1 needs a stream it can clone for reading and writing, which the tls
+// stream is not, so share it between the halves.
+#[derive(Clone)]
+struct TlsStreamWrapper(Arc<Mutex<TlsStream<TcpStream>>>);
+
+impl Read for TlsStreamWrapper {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut stream = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        Pin::new(&mut *stream).poll_read(cx, buf)
+    }
+}
+
+impl Write for TlsStreamWrapper {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut stream = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        Pin::new(&mut *stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut stream = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        Pin::new(&mut *stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut stream = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        Pin::new(&mut *stream).poll_close(cx)
+    }
+}
+
+// Tracks the connections open from each source address, so that a single
+// misbehaving client can't monopolise the server.
+#[derive(Clone)]
+struct PeerConnectionTracker {
+    limit: usize,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+// Releases the slot held by a connection from this address when dropped.
+struct PeerConnectionGuard {
+    tracker: PeerConnectionTracker,
+    ip: IpAddr,
+}
+
+impl PeerConnectionTracker {
+    fn new(limit: usize) -> Self {
+        PeerConnectionTracker {
+            limit,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn try_acquire(&self, ip: IpAddr) -> Option<PeerConnectionGuard> {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        let count = active.entry(ip).or_insert(0);
+        if *count >= self.limit {
+            None
+        } else {
+            *count += 1;
+            Some(PeerConnectionGuard {
+                tracker: self.clone(),
+                ip,
+            })
+        }
+    }
+}
+
+impl Drop for PeerConnectionGuard {
+    fn drop(&mut self) {
+        let mut active = self
+            .tracker
+            .active
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Accepts http (or https) connections, requiring that the tls handshake and the
+/// request headers are received within the read timeout. This is what stops a
+/// client holding a connection open by sending its headers slowly. Connections
+/// from an address that already has max_connections_per_ip open are refused.
+pub struct HttpListener<State> {
+    address: String,
+    acceptor: Option<TlsAcceptor>,
+    headers_timeout: Duration,
+    tracker: Option<PeerConnectionTracker>,
+    listener: Option<TcpListener>,
+    server: Option<tide::Server<State>>,
+}
+
+impl<State> HttpListener<State> {
+    pub fn new(
+        address: String,
+        tls_config: Option<ServerConfig>,
+        read_timeout: Option<Duration>,
+        max_connections_per_ip: Option<usize>,
+    ) -> Self {
+        HttpListener {
+            address,
+            acceptor: tls_config.map(|c| TlsAcceptor::from(Arc::new(c))),
+            headers_timeout: read_timeout.unwrap_or(DEFAULT_HEADERS_TIMEOUT),
+            tracker: max_connections_per_ip.map(PeerConnectionTracker::new),
+            listener: None,
+            server: None,
+        }
+    }
+}
+
+impl<State> fmt::Debug for HttpListener<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpListener")
+            .field("address", &self.address)
+            .field("tls", &self.acceptor.is_some())
+            .field("headers_timeout", &self.headers_timeout)
+            .field(
+                "max_connections_per_ip",
+                &self.tracker.as_ref().map(|t| t.limit),
+            )
+            .finish()
+    }
+}
+
+impl<State> fmt::Display for HttpListener<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.acceptor.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        write!(f, "{}://{}", scheme, self.address)
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> tide::listener::ToListener<State>
+    for HttpListener<State>
+{
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self::Listener> {
+        Ok(self)
+    }
+}
+
+async fn handle_stream<State, RW>(
+    app: tide::Server<State>,
+    stream: RW,
+    local_addr: Option<SocketAddr>,
+    peer_addr: Option<SocketAddr>,
+    headers_timeout: Duration,
+) where
+    State: Clone + Send + Sync + 'static,
+    RW: Read + Write + Clone + Send + Sync + Unpin + 'static,
+{
+    let opts = async_h1::ServerOptions::new().with_headers_timeout(headers_timeout);
+    let fut = async_h1::accept_with_opts(
+        stream,
+        |mut req| async {
+            req.set_local_addr(local_addr);
+            req.set_peer_addr(peer_addr);
+            app.respond(req).await
+        },
+        opts,
+    );
+    if let Err(e) = fut.await {
+        debug!("http connection error -> {:?}", e);
+    }
+}
+
+// Tell the client why its connection is refused, without reading its request.
+async fn refuse_stream<RW: Write + Unpin>(mut stream: RW) {
+    if let Err(e) = stream.write_all(TOO_MANY_CONNECTIONS_RESPONSE).await {
+        debug!("Unable to refuse connection -> {:?}", e);
+    }
+    let _ = stream.close().await;
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::listener::Listener<State> for HttpListener<State> {
+    async fn bind(&mut self, app: tide::Server<State>) -> io::Result<()> {
+        self.listener = Some(TcpListener::bind(&self.address).await?);
+        self.server = Some(app);
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        let (listener, server) = match (self.listener.as_ref(), self.server.as_ref()) {
+            (Some(l), Some(s)) => (l, s),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Listener must be bound before accepting connections",
+                ))
+            }
+        };
+
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("acceptor error, continuing -> {:?}", e);
+                    continue;
+                }
+            };
+
+            let app = server.clone();
+            let acceptor = self.acceptor.clone();
+            let headers_timeout = self.headers_timeout;
+            let local_addr = stream.local_addr().ok();
+            let peer_addr = stream.peer_addr().ok();
+            // The slot is held until the connection closes, including while it's
+            // idle between requests. There is no trusted proxy support, so this is
+            // the address connecting to us, which is shared by clients behind a
+            // proxy or NAT. If we can't determine it, we have nothing to limit on.
+            let (guard, refused) = match (self.tracker.as_ref(), peer_addr) {
+                (Some(tracker), Some(addr)) => match tracker.try_acquire(addr.ip()) {
+                    Some(guard) => (Some(guard), false),
+                    None => {
+                        debug!("Refusing connection, too many from -> {}", addr.ip());
+                        (None, true)
+                    }
+                },
+                _ => (None, false),
+            };
+            task::spawn(async move {
+                let _guard = guard;
+                match acceptor {
+                    Some(acceptor) => {
+                        // The handshake gets the same bound as the headers, or it
+                        // could be used to hold the connection open instead.
+                        match async_std::future::timeout(headers_timeout, acceptor.accept(stream))
+                            .await
+                        {
+                            Ok(Ok(tls_stream)) => {
+                                let stream = TlsStreamWrapper(Arc::new(Mutex::new(tls_stream)));
+                                if refused {
+                                    refuse_stream(stream).await
+                                } else {
+                                    handle_stream(
+                                        app,
+                                        stream,
+                                        local_addr,
+                                        peer_addr,
+                                        headers_timeout,
+                                    )
+                                    .await
+                                }
+                            }
+                            Ok(Err(e)) => debug!("tls handshake error -> {:?}", e),
+                            Err(_) => debug!("tls handshake not completed within timeout"),
+                        }
+                    }
+                    None if refused => refuse_stream(stream).await,
+                    None => {
+                        handle_stream(app, stream, local_addr, peer_addr, headers_timeout).await
+                    }
+                }
+            });
+        }
+    }
+
+    fn info(&self) -> Vec<tide::listener::ListenInfo> {
+        vec![tide::listener::ListenInfo::new(
+            self.to_string(),
+            "tcp".to_string(),
+            self.acceptor.is_some(),
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerConnectionTracker;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_peer_connection_limit() {
+        let tracker = PeerConnectionTracker::new(2);
+        let ip_a: IpAddr = "192.0.2.1".parse().expect("Invalid ip");
+        let ip_b: IpAddr = "192.0.2.2".parse().expect("Invalid ip");
+
+        let a1 = tracker.try_acquire(ip_a).expect("must be allowed");
+        let _a2 = tracker.try_acquire(ip_a).expect("must be allowed");
+        // Over the limit for this address.
+        assert!(tracker.try_acquire(ip_a).is_none());
+        // Other addresses are unaffected.
+        let _b1 = tracker.try_acquire(ip_b).expect("must be allowed");
+
+        // Once a connection closes, its slot is available again.
+        std::mem::drop(a1);
+        let _a3 = tracker.try_acquire(ip_a).expect("must be allowed");
+        assert!(tracker.try_acquire(ip_a).is_none());
+    }
+}
@@ -1,35 +1,73 @@
+pub mod admin;
 mod https;
 mod ldaps;
+mod listener;
 use libc::umask;
 
 // use crossbeam::channel::unbounded;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc::unbounded_channel as unbounded;
 
 use crate::prelude::*;
 
-use crate::config::Configuration;
+use crate::config::{Configuration, SpnRegenMode, VerifyFailureAction};
 
 // SearchResult
 // use self::ctx::ServerCtx;
 use crate::actors::v1_read::QueryServerReadV1;
 use crate::actors::v1_write::QueryServerWriteV1;
 use crate::async_log;
+use crate::audit::LogLevelHandle;
 use crate::be::{Backend, BackendConfig, BackendTransaction, FsType};
-use crate::crypto::setup_tls;
+use crate::crypto::{check_tls, check_tls_key_strength, setup_tls};
 use crate::idm::server::{IdmServer, IdmServerDelayed};
 use crate::interval::IntervalActor;
 use crate::ldap::LdapServer;
+use crate::lockout_notify::LockoutNotifier;
+use crate::preflight::{preflight, PreflightOptions, PreflightSeverity};
 use crate::schema::Schema;
+use crate::spn_notify::SpnNotifier;
 use crate::status::StatusActor;
 use crate::utils::duration_from_epoch_now;
 
-use kanidm_proto::v1::OperationError;
+use kanidm_proto::v1::{Filter as ProtoFilter, OperationError};
 
 use async_std::task;
 
 // === internal setup helpers
 
+// Raise the log level on SIGUSR1, and restore it on the next SIGUSR1 or after
+// LOG_LEVEL_DEBUG_TIMEOUT. This allows a running server to be debugged without
+// a restart dropping its connections.
+async fn log_level_signal_handler(log_level: LogLevelHandle, debug_level: u32) {
+    let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!(
+                "Unable to listen for SIGUSR1, the log level can not be raised -> {:?}",
+                e
+            );
+            return;
+        }
+    };
+    while sigusr1.recv().await.is_some() {
+        log_level.raise(debug_level);
+        info!(
+            "SIGUSR1 received, log level raised to {:x} for up to {} seconds",
+            debug_level, LOG_LEVEL_DEBUG_TIMEOUT
+        );
+        tokio::select! {
+            _ = sigusr1.recv() => info!("SIGUSR1 received, restoring the log level"),
+            _ = tokio::time::sleep(Duration::from_secs(LOG_LEVEL_DEBUG_TIMEOUT)) => {
+                info!("Raised log level timed out, restoring the log level")
+            }
+        }
+        log_level.restore();
+    }
+}
+
 fn setup_backend(config: &Configuration, schema: &Schema) -> Result<Backend, OperationError> {
     setup_backend_vacuum(config, schema, false)
 }
@@ -70,6 +108,25 @@ fn setup_backend_vacuum(
     be
 }
 
+// Apply the configuration that changes how the query server behaves. Every command
+// that builds a query server uses this, so that they all see the same entries and
+// policies as the running server.
+fn apply_config(query_server: &mut QueryServer, config: &Configuration) {
+    query_server.set_anonymous_read_scope(config.anonymous_read_scope);
+    query_server.set_spn_settings(config.spn_settings());
+    query_server.set_spn_notifier(
+        config
+            .spn_notify_command
+            .as_deref()
+            .map(|c| Arc::new(SpnNotifier::new(c))),
+    );
+    query_server.set_missing_domain_name(config.missing_domain_name.clone());
+    query_server.set_max_entries(config.max_entries);
+    query_server.set_search_result_limit(config.search_result_limit);
+    query_server.set_default_search_attrs(&config.default_search_attrs);
+    query_server.set_max_modify_batch(config.max_modify_batch);
+}
+
 // TODO #54: We could move most of the be/schema/qs setup and startup
 // outside of this call, then pass in "what we need" in a cloneable
 // form, this way we could have seperate Idm vs Qs threads, and dedicated
@@ -81,7 +138,8 @@ fn setup_qs_idms(
     config: &Configuration,
 ) -> Result<(QueryServer, IdmServer, IdmServerDelayed), OperationError> {
     // Create a query_server implementation
-    let query_server = QueryServer::new(be, schema);
+    let mut query_server = QueryServer::new(be, schema);
+    apply_config(&mut query_server, config);
 
     // TODO #62: Should the IDM parts be broken out to the IdmServer?
     // What's important about this initial setup here is that it also triggers
@@ -92,10 +150,28 @@ fn setup_qs_idms(
     // in memory matches the BE on disk, and that it's syntactically correct.
     // Write it out if changes are needed.
     query_server.initialise_helper(audit, duration_from_epoch_now())?;
+    query_server.validate_default_search_attrs(audit)?;
+    // A regeneration in progress is only tracked in memory, so finish any that was
+    // interrupted by a restart.
+    if config.spn_regen_mode == SpnRegenMode::Available {
+        query_server.resume_spn_regen();
+    }
 
     // We generate a SINGLE idms only!
 
-    let (idms, idms_delayed) = IdmServer::new(audit, query_server.clone(), config.origin.clone())?;
+    let (mut idms, idms_delayed) =
+        IdmServer::new(audit, query_server.clone(), config.origin.clone())?;
+    idms.set_disabled_auth_mechs(config.disabled_auth_mechs.clone());
+    idms.set_credential_size_limits(config.max_password_size, config.max_credential_size);
+    idms.set_auth_event_retention(Duration::from_secs(config.auth_event_retention));
+    idms.set_auth_trace_max_duration(Duration::from_secs(config.auth_trace_max_duration));
+    idms.set_detailed_auth_errors(config.detailed_auth_errors);
+    idms.set_lockout_notifier(
+        config
+            .lockout_notify_command
+            .as_deref()
+            .map(|c| Arc::new(LockoutNotifier::new(c))),
+    );
 
     Ok((query_server, idms, idms_delayed))
 }
@@ -292,7 +368,7 @@ pub fn vacuum_server_core(config: &Configuration) {
     };
 }
 
-pub fn domain_rename_core(config: &Configuration, new_domain_name: &str) {
+pub fn domain_rename_core(config: &Configuration, new_domain_name: &str, class: Option<&str>) {
     let mut audit = AuditScope::new("domain_rename", uuid::Uuid::new_v4(), config.log_level);
 
     let schema = match Schema::new(&mut audit) {
@@ -322,14 +398,150 @@ pub fn domain_rename_core(config: &Configuration, new_domain_name: &str) {
     };
 
     let qs_write = task::block_on(qs.write_async(duration_from_epoch_now()));
-    let r = qs_write
-        .domain_rename(&mut audit, new_domain_name)
-        .and_then(|_| qs_write.commit(&mut audit));
+    let r = match class {
+        Some(class) => qs_write.domain_rename_scoped(&mut audit, new_domain_name, class),
+        None => qs_write.domain_rename(&mut audit, new_domain_name),
+    }
+    .and_then(|_| qs_write.commit(&mut audit));
+
+    if let Err(e) = r {
+        error!("Domain Rename Failed - Rollback has occured: {:?}", e);
+        std::process::exit(1);
+    }
+
+    // In spn_regen_mode available, the spns are regenerated once the rename is committed.
+    match task::block_on(qs.spn_regenerate_chunked(&mut audit)) {
+        Ok(0) => {}
+        Ok(n) => info!("Regenerated {} spns", n),
+        Err(e) => {
+            error!(
+                "spn regeneration failed, the remaining spns are regenerated when the server starts: {:?}",
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+    info!("Domain Rename Success!");
+}
+
+pub fn domain_rename_simulate_core(
+    config: &Configuration,
+    new_domain_name: &str,
+    class: Option<&str>,
+) {
+    let mut audit = AuditScope::new(
+        "domain_rename_simulate",
+        uuid::Uuid::new_v4(),
+        config.log_level,
+    );
+
+    let schema = match Schema::new(&mut audit) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Failed to setup in memory schema: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let be = match setup_backend(&config, &schema) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    // As the rename is rolled back, it runs against the db exactly as a real rename would.
+    let (qs, _idms, _idms_delayed) = match setup_qs_idms(&mut audit, be, schema, &config) {
+        Ok(t) => t,
+        Err(e) => {
+            audit.write_log();
+            error!("Unable to setup query server or idm server -> {:?}", e);
+            return;
+        }
+    };
+
+    let qs_write = task::block_on(qs.write_async(duration_from_epoch_now()));
+    let r = qs_write.domain_rename_simulate(
+        &mut audit,
+        new_domain_name,
+        class,
+        DOMAIN_RENAME_SIMULATE_SAMPLES,
+    );
+    audit.write_log();
 
     match r {
-        Ok(_) => info!("Domain Rename Success!"),
+        Ok(sim) => {
+            info!(
+                "Domain Rename Simulation: {} of {} spns regenerated in {:?}. Nothing was changed.",
+                sim.changed, sim.candidates, sim.elapsed
+            );
+            for (old, new) in sim.samples.iter() {
+                info!(
+                    "{}\t{}",
+                    old.as_deref().unwrap_or("-"),
+                    new.as_deref().unwrap_or("-")
+                );
+            }
+        }
         Err(e) => {
-            error!("Domain Rename Failed - Rollback has occured: {:?}", e);
+            error!("Domain Rename Simulation Failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+pub fn domain_rename_plan_core(
+    config: &Configuration,
+    new_domain_name: &str,
+    anonymize: bool,
+    output: Option<&std::path::Path>,
+) {
+    let mut audit = AuditScope::new("domain_rename_plan", uuid::Uuid::new_v4(), config.log_level);
+
+    let schema = match Schema::new(&mut audit) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Failed to setup in memory schema: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let be = match setup_backend(&config, &schema) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    // We never write, so don't init or migrate the db - plan against what is there.
+    let mut qs = QueryServer::new(be, schema);
+    apply_config(&mut qs, config);
+
+    let mut out: Box<dyn std::io::Write> = match output {
+        Some(p) => match std::fs::File::create(p) {
+            Ok(f) => Box::new(std::io::BufWriter::new(f)),
+            Err(e) => {
+                error!("Unable to create {:?} -> {:?}", p, e);
+                std::process::exit(1);
+            }
+        },
+        None => Box::new(std::io::BufWriter::new(std::io::stdout())),
+    };
+
+    let qs_read = task::block_on(qs.read_async());
+    let r = qs_read
+        .domain_rename_plan(&mut audit, new_domain_name, anonymize, &mut out)
+        .and_then(|count| {
+            out.flush()
+                .map(|_| count)
+                .map_err(|_| OperationError::FsError)
+        });
+    audit.write_log();
+
+    match r {
+        Ok(count) => info!("Domain Rename Plan generated for {} entries", count),
+        Err(e) => {
+            error!("Domain Rename Plan Failed: {:?}", e);
             std::process::exit(1);
         }
     };
@@ -352,7 +564,57 @@ pub fn reset_sid_core(config: Configuration) {
 }
 */
 
-pub fn verify_server_core(config: &Configuration) {
+// Run the consistency checks of "kanidmd verify" against the database as the
+// server starts. Inconsistencies are always logged, and with Refuse this returns
+// an error so the server doesn't serve an inconsistent directory.
+fn startup_verify(
+    audit: &mut AuditScope,
+    qs: &QueryServer,
+    action: VerifyFailureAction,
+) -> Result<(), ()> {
+    let r = qs.verify(audit);
+    if r.is_empty() {
+        info!("Startup verification passed");
+        return Ok(());
+    }
+    for er in r.iter() {
+        match action {
+            VerifyFailureAction::Warn => warn!("{:?}", er),
+            VerifyFailureAction::Refuse => error!("{:?}", er),
+        }
+        if let Some(hint) = er.as_ref().err().and_then(|ce| ce.remediation()) {
+            warn!("  remediation: {}", hint);
+        }
+    }
+    match action {
+        VerifyFailureAction::Warn => {
+            warn!(
+                "Startup verification found {} inconsistencies, starting anyway",
+                r.len()
+            );
+            Ok(())
+        }
+        VerifyFailureAction::Refuse => {
+            error!(
+                "Startup verification found {} inconsistencies, refusing to start",
+                r.len()
+            );
+            Err(())
+        }
+    }
+}
+
+/// Verify the database. When a filter (as json) is given, only the plugin checks of
+/// the entries matching it are run, which is much faster for a targeted check.
+pub fn verify_server_core(config: &Configuration, filter: Option<&str>) {
+    let scope: Option<ProtoFilter> = match filter.map(serde_json::from_str).transpose() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Invalid verify filter, it must be a json filter -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
     let mut audit = AuditScope::new("server_verify", uuid::Uuid::new_v4(), config.log_level);
     // setup the qs - without initialise!
     let schema_mem = match Schema::new(&mut audit) {
@@ -370,10 +632,21 @@ pub fn verify_server_core(config: &Configuration) {
             return;
         }
     };
-    let server = QueryServer::new(be, schema_mem);
+    let mut server = QueryServer::new(be, schema_mem);
+    apply_config(&mut server, config);
 
     // Run verifications.
-    let r = server.verify(&mut audit);
+    let r = match scope {
+        Some(scope) => match server.verify_scoped(&mut audit, &scope) {
+            Ok(r) => r,
+            Err(e) => {
+                audit.write_log();
+                error!("Unable to verify with filter -> {:?}", e);
+                std::process::exit(1);
+            }
+        },
+        None => server.verify(&mut audit),
+    };
 
     audit.write_log();
 
@@ -383,6 +656,9 @@ pub fn verify_server_core(config: &Configuration) {
     } else {
         for er in r {
             error!("{:?}", er);
+            if let Some(hint) = er.err().and_then(|ce| ce.remediation()) {
+                error!("  remediation: {}", hint);
+            }
         }
         std::process::exit(1);
     }
@@ -390,6 +666,56 @@ pub fn verify_server_core(config: &Configuration) {
     // Now add IDM server verifications?
 }
 
+pub fn tls_check_core(config: &Configuration, expiry_warn_days: u32) {
+    let report = match check_tls(config, expiry_warn_days) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("FAIL: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    eprintln!("subject: {}", report.subject);
+    eprintln!(
+        "expires: {} ({} days remaining)",
+        report.not_after, report.days_remaining
+    );
+    if report.problems.is_empty() {
+        eprintln!("TLS configuration OK");
+    } else {
+        for p in report.problems.iter() {
+            eprintln!("FAIL: {}", p);
+        }
+        std::process::exit(1);
+    }
+}
+
+/// Check the strength of the keys in the configured TLS chain, as is done at
+/// startup.
+pub fn tls_key_check_core(config: &Configuration) -> Result<(), String> {
+    check_tls_key_strength(config)
+}
+
+pub fn preflight_core(config: &Configuration, options: &PreflightOptions) {
+    let report = preflight(config, options);
+    for f in report.findings() {
+        eprintln!("{}: [{}] {}", f.severity, f.check, f.message);
+    }
+    eprintln!(
+        "{} errors, {} warnings, {} checks - readiness {}%",
+        report.count(PreflightSeverity::Error),
+        report.count(PreflightSeverity::Warning),
+        report.checks().len(),
+        report.score()
+    );
+    if report.passed() {
+        eprintln!("Preflight PASSED");
+    } else {
+        eprintln!("Preflight FAILED");
+        std::process::exit(1);
+    }
+}
+
 pub fn recover_account_core(config: &Configuration, name: &str, password: &str) {
     let mut audit = AuditScope::new("recover_account", uuid::Uuid::new_v4(), config.log_level);
 
@@ -443,6 +769,17 @@ pub fn recover_account_core(config: &Configuration, name: &str, password: &str)
     };
 }
 
+/// Build the runtime whose worker threads run the server, with the configured
+/// worker_stack_size.
+pub fn create_runtime(config: &Configuration) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(size) = config.worker_stack_size {
+        builder.thread_stack_size(size);
+    }
+    builder.build()
+}
+
 pub async fn create_server_core(config: Configuration) -> Result<(), ()> {
     // Until this point, we probably want to write to the log macro fns.
 
@@ -457,17 +794,30 @@ pub async fn create_server_core(config: Configuration) -> Result<(), ()> {
 
     // The log task is spawned. It will only consume a single thread at a time.
     let (log_tx, log_rx) = unbounded();
-    tokio::spawn(async_log::run(log_rx));
+    tokio::spawn(async_log::run(
+        log_rx,
+        config
+            .slow_operation_threshold
+            .map(std::time::Duration::from_millis),
+        config.log_subsystems.clone(),
+    ));
+
+    // The log level is shared by the actors so it can be changed while running.
+    let log_level = LogLevelHandle::new(config.log_level);
+    tokio::spawn(log_level_signal_handler(
+        log_level.clone(),
+        config.log_level_debug,
+    ));
 
     // Similar, create a stats task which aggregates statistics from the
     // server as they come in.
-    let status_ref = StatusActor::start(log_tx.clone(), config.log_level);
+    let status_ref = StatusActor::start(log_tx.clone(), log_level.clone());
 
     // Setup TLS (if any)
     let _opt_tls_params = match setup_tls(&config) {
         Ok(opt_tls_params) => opt_tls_params,
         Err(e) => {
-            error!("Failed to configure TLS parameters -> {:?}", e);
+            error!("Failed to configure TLS parameters -> {}", e);
             return Err(());
         }
     };
@@ -491,7 +841,7 @@ pub async fn create_server_core(config: Configuration) -> Result<(), ()> {
         }
     };
     // Start the IDM server.
-    let (_qs, idms, mut idms_delayed) = match setup_qs_idms(&mut audit, be, schema, &config) {
+    let (qs, idms, mut idms_delayed) = match setup_qs_idms(&mut audit, be, schema, &config) {
         Ok(t) => t,
         Err(e) => {
             audit.write_log();
@@ -500,6 +850,11 @@ pub async fn create_server_core(config: Configuration) -> Result<(), ()> {
         }
     };
 
+    if config.startup_verify && startup_verify(&mut audit, &qs, config.on_verify_failure).is_err() {
+        audit.write_log();
+        return Err(());
+    }
+
     // Any pre-start tasks here.
     match &config.integration_test_config {
         Some(itc) => {
@@ -528,7 +883,7 @@ pub async fn create_server_core(config: Configuration) -> Result<(), ()> {
         None => {}
     }
 
-    let ldap = match LdapServer::new(&mut audit, &idms) {
+    let ldap = match LdapServer::new(&mut audit, &idms, config.ldap_basedn.as_deref()) {
         Ok(l) => l,
         Err(e) => {
             audit.write_log();
@@ -537,7 +892,7 @@ pub async fn create_server_core(config: Configuration) -> Result<(), ()> {
         }
     };
 
-    log_tx.send(audit).unwrap_or_else(|_| {
+    log_tx.send(audit.finish()).unwrap_or_else(|_| {
         error!("CRITICAL: UNABLE TO COMMIT LOGS");
     });
 
@@ -549,14 +904,14 @@ pub async fn create_server_core(config: Configuration) -> Result<(), ()> {
     // Start the read query server with the given be path: future config
     let server_read_ref = QueryServerReadV1::start_static(
         log_tx.clone(),
-        config.log_level,
+        log_level.clone(),
         idms_arc.clone(),
         ldap_arc.clone(),
     );
 
     // Create the server async write entry point.
     let server_write_ref =
-        QueryServerWriteV1::start_static(log_tx.clone(), config.log_level, idms_arc.clone());
+        QueryServerWriteV1::start_static(log_tx.clone(), log_level, idms_arc.clone());
 
     tokio::spawn(async move {
         idms_delayed.process_all(server_write_ref).await;
@@ -564,6 +919,34 @@ pub async fn create_server_core(config: Configuration) -> Result<(), ()> {
 
     // Setup timed events associated to the write thread
     IntervalActor::start(server_write_ref);
+    if let Some(backup_path) = &config.backup_path {
+        IntervalActor::start_online_backup(
+            server_read_ref,
+            backup_path.clone(),
+            config.backup_retention_count,
+        );
+    }
+    if let Some(period) = config.db_maintenance_interval() {
+        IntervalActor::start_db_maintenance(server_write_ref, period, config.db_maintenance_vacuum);
+    }
+    if let Some(notifier) = qs.get_spn_notifier() {
+        IntervalActor::start_spn_notify(notifier);
+    }
+    if config.spn_regen_mode == SpnRegenMode::Available {
+        IntervalActor::start_spn_regen(server_write_ref);
+    }
+    if let Some(notifier) = idms_arc.get_lockout_notifier() {
+        IntervalActor::start_lockout_notify(notifier);
+    }
+
+    let standby = config.standby_monitor().map(Arc::new);
+    if let Some(monitor) = &standby {
+        IntervalActor::start_standby(monitor.clone());
+    }
+
+    if let Some(path) = &config.admin_socket_path {
+        self::admin::create_admin_server(path.as_str(), server_write_ref, standby.clone()).await?;
+    }
 
     // If we have been requested to init LDAP, configure it now.
     match &config.ldapaddress {
@@ -571,7 +954,7 @@ pub async fn create_server_core(config: Configuration) -> Result<(), ()> {
             let opt_ldap_tls_params = match setup_tls(&config) {
                 Ok(t) => t,
                 Err(e) => {
-                    error!("Failed to configure LDAP TLS parameters -> {:?}", e);
+                    error!("Failed to configure LDAP TLS parameters -> {}", e);
                     return Err(());
                 }
             };
@@ -585,15 +968,10 @@ pub async fn create_server_core(config: Configuration) -> Result<(), ()> {
     // TODO: Remove these when we go to auth bearer!
     // Copy the max size
     let _secure_cookies = config.secure_cookies;
-    // domain will come from the qs now!
-    let cookie_key: [u8; 32] = config.cookie_key;
 
     self::https::create_https_server(
-        config.address,
-        // opt_tls_params,
-        config.tls_config.as_ref(),
-        config.role,
-        &cookie_key,
+        &config,
+        standby,
         status_ref,
         server_write_ref,
         server_read_ref,
@@ -603,3 +981,69 @@ pub async fn create_server_core(config: Configuration) -> Result<(), ()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Configuration, VerifyFailureAction};
+    use crate::core::{create_runtime, startup_verify};
+    use crate::prelude::*;
+
+    // Each frame holds 64k, so this needs far more than the default 2MB stack.
+    fn use_stack(depth: usize) -> usize {
+        let buf = [depth as u8; 64 * 1024];
+        let v = unsafe { std::ptr::read_volatile(&buf[buf.len() - 1]) } as usize;
+        if depth == 0 {
+            v
+        } else {
+            v + use_stack(depth - 1)
+        }
+    }
+
+    #[test]
+    fn test_create_runtime_worker_stack_size() {
+        let mut config = Configuration::new();
+        config.update_worker_stack_size(Some(64 * 1024 * 1024));
+        let rt = create_runtime(&config).expect("must not fail");
+        // Spawned tasks run on the worker threads, which would overflow their
+        // stack here if the size was not applied.
+        let r = rt
+            .block_on(async { tokio::spawn(async { use_stack(256) }).await })
+            .expect("must not fail");
+        assert!(r == (0..=256_usize).map(|d| d as u8 as usize).sum::<usize>());
+    }
+
+    #[test]
+    fn test_startup_verify_failure_action() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            assert!(startup_verify(au, server, VerifyFailureAction::Refuse).is_ok());
+
+            // Bypass the plugins to remove the spn of admin.
+            let server_txn = server.write(duration_from_epoch_now());
+            let e_pre = server_txn
+                .internal_search_uuid(au, &UUID_ADMIN)
+                .expect("must not fail");
+            let mut e_broken = unsafe { e_pre.clone().into_invalid() };
+            e_broken.purge_ava("spn");
+            let e_broken = unsafe { e_broken.into_sealed_committed() };
+            server_txn
+                .get_be_txn()
+                .modify(au, &[e_pre], &[e_broken])
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+
+            assert!(startup_verify(au, server, VerifyFailureAction::Warn).is_ok());
+            assert!(startup_verify(au, server, VerifyFailureAction::Refuse).is_err());
+
+            // Repair it, as run_test verifies the database afterwards.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                    &modlist!([m_purge("spn")]),
+                )
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+        });
+    }
+}
@@ -1,6 +1,8 @@
-use crate::be::dbvalue::{DbCredTypeV1, DbCredV1, DbPasswordV1, DbWebauthnV1};
+use crate::be::dbvalue::{DbCredTypeV1, DbCredV1, DbPasswordV1, DbWebauthnBackupV1, DbWebauthnV1};
 use hashbrown::HashMap as Map;
-use kanidm_proto::v1::{CredentialDetail, CredentialDetailType, OperationError};
+use kanidm_proto::v1::{
+    CredentialDetail, CredentialDetailType, OperationError, WebauthnBackupState,
+};
 use openssl::hash::MessageDigest;
 use openssl::pkcs5::pbkdf2_hmac;
 use openssl::sha::Sha512;
@@ -230,6 +232,9 @@ pub struct Credential {
     // Uuid of Credential, used by auth session to lock this specific credential
     // if required.
     pub(crate) uuid: Uuid,
+    // The backup state reported for each webauthn label. This isn't part of the
+    // webauthn credential, so it's kept alongside.
+    pub(crate) webauthn_backup: Map<String, WebauthnBackupState>,
     // TODO #59: Add auth policy IE validUntil, lock state ...
     // locked: bool
 }
@@ -265,6 +270,11 @@ impl Into<CredentialDetail> for &Credential {
                     CredentialDetailType::PasswordMfa(totp.is_some(), labels)
                 }
             },
+            webauthn_backup: self
+                .webauthn_backup
+                .iter()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
         }
     }
 }
@@ -293,6 +303,22 @@ impl TryFrom<DbCredV1> for Credential {
             None => None,
         };
 
+        let webauthn_backup = webauthn
+            .iter()
+            .flatten()
+            .filter_map(|wc| {
+                wc.b.as_ref().map(|b| {
+                    (
+                        wc.l.clone(),
+                        WebauthnBackupState {
+                            eligible: b.e,
+                            backed_up: b.b,
+                        },
+                    )
+                })
+            })
+            .collect();
+
         let v_webauthn = match webauthn {
             Some(dbw) => Some(
                 dbw.into_iter()
@@ -329,6 +355,7 @@ impl TryFrom<DbCredV1> for Credential {
             type_,
             claims,
             uuid,
+            webauthn_backup,
         })
     }
 }
@@ -341,13 +368,22 @@ impl Credential {
         Password::new(policy, cleartext).map(Self::new_from_password)
     }
 
-    pub fn new_webauthn_only(label: String, cred: WebauthnCredential) -> Self {
+    pub fn new_webauthn_only(
+        label: String,
+        cred: WebauthnCredential,
+        backup: Option<WebauthnBackupState>,
+    ) -> Self {
+        let mut webauthn_backup = Map::new();
+        if let Some(backup) = backup {
+            webauthn_backup.insert(label.clone(), backup);
+        }
         let mut webauthn_map = Map::new();
         webauthn_map.insert(label, cred);
         Credential {
             type_: CredentialType::Webauthn(webauthn_map),
             claims: Vec::new(),
             uuid: Uuid::new_v4(),
+            webauthn_backup,
         }
     }
 
@@ -363,7 +399,12 @@ impl Credential {
         &self,
         label: String,
         cred: WebauthnCredential,
+        backup: Option<WebauthnBackupState>,
     ) -> Result<Self, OperationError> {
+        let mut webauthn_backup = self.webauthn_backup.clone();
+        if let Some(backup) = backup {
+            webauthn_backup.insert(label.clone(), backup);
+        }
         let type_ = match &self.type_ {
             CredentialType::Password(pw) | CredentialType::GeneratedPassword(pw) => {
                 let mut wan = Map::new();
@@ -397,6 +438,7 @@ impl Credential {
             type_,
             claims: self.claims.clone(),
             uuid: self.uuid,
+            webauthn_backup,
         })
     }
 
@@ -443,60 +485,67 @@ impl Credential {
             }
         };
 
+        let mut webauthn_backup = self.webauthn_backup.clone();
+        webauthn_backup.remove(label);
+
         // Check stuff
         Ok(Credential {
             type_,
             claims: self.claims.clone(),
             uuid: self.uuid,
+            webauthn_backup,
         })
     }
 
+    /// Record a use of a webauthn credential, updating its counter and the backup
+    /// state it reported. Returns None if neither has changed.
     #[allow(clippy::ptr_arg)]
     pub fn update_webauthn_counter(
         &self,
         cid: &CredentialID,
         counter: Counter,
+        backup: Option<WebauthnBackupState>,
     ) -> Result<Option<Self>, OperationError> {
-        let nmap = match &self.type_ {
+        let map = match &self.type_ {
             CredentialType::Password(_pw) | CredentialType::GeneratedPassword(_pw) => {
                 // No action required
                 return Ok(None);
             }
-            CredentialType::PasswordMfa(_, _, map) | CredentialType::Webauthn(map) => map
-                .iter()
-                .fold(None, |acc, (k, v)| {
-                    if acc.is_none() && &v.cred_id == cid && v.counter < counter {
-                        Some(k)
-                    } else {
-                        acc
-                    }
-                })
-                .map(|label| {
-                    let mut webauthn_map = map.clone();
-
-                    if let Some(cred) = webauthn_map.get_mut(label) {
-                        cred.counter = counter
-                    };
-                    webauthn_map
-                }),
+            CredentialType::PasswordMfa(_, _, map) | CredentialType::Webauthn(map) => map,
         };
 
-        let map = match nmap {
-            Some(map) => map,
+        let (label, cred) = match map.iter().find(|(_, v)| &v.cred_id == cid) {
+            Some(found) => found,
             None => {
                 // No action needed.
                 return Ok(None);
             }
         };
+        let counter_changed = cred.counter < counter;
+        let backup_changed = backup.is_some() && self.webauthn_backup.get(label) != backup.as_ref();
+        if !counter_changed && !backup_changed {
+            return Ok(None);
+        }
+
+        let mut webauthn_map = map.clone();
+        if counter_changed {
+            if let Some(cred) = webauthn_map.get_mut(label) {
+                cred.counter = counter
+            };
+        }
+        let mut webauthn_backup = self.webauthn_backup.clone();
+        if let Some(backup) = backup {
+            webauthn_backup.insert(label.clone(), backup);
+        }
 
         let type_ = match &self.type_ {
             CredentialType::Password(_pw) | CredentialType::GeneratedPassword(_pw) => {
                 // Should not be possible!
                 return Err(OperationError::InvalidState);
             }
-            CredentialType::Webauthn(_) => CredentialType::Webauthn(map),
+            CredentialType::Webauthn(_) => CredentialType::Webauthn(webauthn_map),
             CredentialType::PasswordMfa(pw, totp, _) => {
-                CredentialType::PasswordMfa(pw.clone(), totp.clone(), map)
+                CredentialType::PasswordMfa(pw.clone(), totp.clone(), webauthn_map)
             }
         };
 
@@ -504,6 +553,7 @@ impl Credential {
             type_,
             claims: self.claims.clone(),
             uuid: self.uuid,
+            webauthn_backup,
         }))
     }
 
@@ -563,6 +613,10 @@ impl Credential {
                             c: v.cred.clone(),
                             t: v.counter,
                             v: v.verified,
+                            b: self.webauthn_backup.get(k).map(|b| DbWebauthnBackupV1 {
+                                e: b.eligible,
+                                b: b.backed_up,
+                            }),
                         })
                         .collect(),
                 ),
@@ -581,6 +635,10 @@ impl Credential {
                             c: v.cred.clone(),
                             t: v.counter,
                             v: v.verified,
+                            b: self.webauthn_backup.get(k).map(|b| DbWebauthnBackupV1 {
+                                e: b.eligible,
+                                b: b.backed_up,
+                            }),
                         })
                         .collect(),
                 ),
@@ -604,6 +662,7 @@ impl Credential {
             type_,
             claims: self.claims.clone(),
             uuid: self.uuid,
+            webauthn_backup: self.webauthn_backup.clone(),
         }
     }
 
@@ -625,6 +684,7 @@ impl Credential {
             type_,
             claims: self.claims.clone(),
             uuid: self.uuid,
+            webauthn_backup: self.webauthn_backup.clone(),
         }
     }
 
@@ -643,6 +703,7 @@ impl Credential {
             type_,
             claims: self.claims.clone(),
             uuid: self.uuid,
+            webauthn_backup: self.webauthn_backup.clone(),
         }
     }
 
@@ -651,6 +712,7 @@ impl Credential {
             type_: CredentialType::Password(pw),
             claims: Vec::new(),
             uuid: Uuid::new_v4(),
+            webauthn_backup: Map::new(),
         }
     }
 
@@ -59,6 +59,10 @@ use std::time::Duration;
 //
 
 const ONEDAY: u64 = 86400;
+// After this many failures the credential is locked until the end of the cycle,
+// rather than for a short delay.
+const PASSWORD_LOCKOUT_COUNT: usize = 100;
+const TOTP_LOCKOUT_COUNT: usize = 3;
 
 #[derive(Debug, Clone)]
 pub enum CredSoftLockPolicy {
@@ -68,6 +72,16 @@ pub enum CredSoftLockPolicy {
 }
 
 impl CredSoftLockPolicy {
+    /// Is a credential with this many failures locked out until the end of the
+    /// cycle. Webauthn is only ever delayed, so it is never locked out.
+    fn is_lockout(&self, count: usize) -> bool {
+        match self {
+            CredSoftLockPolicy::Password => count >= PASSWORD_LOCKOUT_COUNT,
+            CredSoftLockPolicy::Totp(_) => count >= TOTP_LOCKOUT_COUNT,
+            CredSoftLockPolicy::Webauthn => false,
+        }
+    }
+
     /// Determine the next lock state after a failure based on this credentials
     /// policy.
     fn failure_next_state(&self, count: usize, ct: Duration) -> LockState {
@@ -83,7 +97,7 @@ impl CredSoftLockPolicy {
                     LockState::Locked(count, reset_at, ct + Duration::from_secs(3))
                 } else if count < 25 {
                     LockState::Locked(count, reset_at, ct + Duration::from_secs(5))
-                } else if count < 100 {
+                } else if count < PASSWORD_LOCKOUT_COUNT {
                     LockState::Locked(count, reset_at, ct + Duration::from_secs(10))
                 } else {
                     LockState::Locked(count, reset_at, reset_at)
@@ -96,7 +110,7 @@ impl CredSoftLockPolicy {
                 let reset_at = Duration::from_secs(next_window_end - rem);
                 // We delay for 1 second, unless count is > 3, then we set
                 // unlock at to reset_at.
-                if count >= 3 {
+                if count >= TOTP_LOCKOUT_COUNT {
                     LockState::Locked(count, reset_at, reset_at)
                 } else {
                     LockState::Locked(count, reset_at, ct + Duration::from_secs(1))
@@ -173,6 +187,17 @@ impl CredSoftLock {
         !matches!(self.state, LockState::Locked(_count, _reset_at, _unlock_at))
     }
 
+    /// If this credential is locked out by repeated failures, the number of
+    /// failures. The short delays after each failure are not a lockout.
+    pub fn locked_out(&self) -> Option<usize> {
+        match self.state {
+            LockState::Locked(count, _reset_at, _unlock_at) if self.policy.is_lockout(count) => {
+                Some(count)
+            }
+            _ => None,
+        }
+    }
+
     /// Document a failure of authentication at this time.
     pub fn record_failure(&mut self, ct: Duration) {
         let mut next_state = match self.state {
@@ -313,6 +338,9 @@ mod tests {
                     Duration::from_secs(ONEDAY)
                 )
         );
+
+        assert!(!policy.is_lockout(99));
+        assert!(policy.is_lockout(100));
     }
 
     #[test]
@@ -345,6 +373,9 @@ mod tests {
                     Duration::from_secs(TOTP_DEFAULT_STEP)
                 )
         );
+
+        assert!(!policy.is_lockout(2));
+        assert!(policy.is_lockout(3));
     }
 
     #[test]
@@ -361,5 +392,6 @@ mod tests {
             policy.failure_next_state(1000, Duration::from_secs(0))
                 == LockState::Locked(1000, Duration::from_secs(1), Duration::from_secs(1))
         );
+        assert!(!policy.is_lockout(1000));
     }
 }
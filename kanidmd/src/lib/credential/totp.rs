@@ -13,6 +13,9 @@ use kanidm_proto::v1::TotpSecret as ProtoTotp;
 // This is 64 bits of entropy, as the examples in https://tools.ietf.org/html/rfc6238 show.
 const SECRET_SIZE_BYTES: usize = 8;
 pub const TOTP_DEFAULT_STEP: u64 = 30;
+pub const TOTP_DEFAULT_DIGITS: u8 = 6;
+// Authenticators and policies in the wild only use 6 or 8 digit codes.
+const TOTP_VALID_DIGITS: [u8; 2] = [6, 8];
 
 #[derive(Debug, PartialEq)]
 pub enum TotpError {
@@ -63,6 +66,7 @@ pub struct Totp {
     label: String,
     secret: Vec<u8>,
     pub(crate) step: u64,
+    pub(crate) digits: u8,
     algo: TotpAlgo,
 }
 
@@ -75,10 +79,16 @@ impl TryFrom<DbTotpV1> for Totp {
             DbTotpAlgoV1::S256 => TotpAlgo::Sha256,
             DbTotpAlgoV1::S512 => TotpAlgo::Sha512,
         };
+        // Tokens stored before digits were configurable are always 6 digits.
+        let digits = value.d.unwrap_or(TOTP_DEFAULT_DIGITS);
+        if !TOTP_VALID_DIGITS.contains(&digits) || value.s == 0 {
+            return Err(());
+        }
         Ok(Totp {
             label: value.l,
             secret: value.k,
             step: value.s,
+            digits,
             algo,
         })
     }
@@ -95,16 +105,18 @@ impl From<ProtoTotp> for Totp {
                 ProtoTotpAlgo::Sha512 => TotpAlgo::Sha512,
             },
             step: value.step,
+            digits: value.digits,
         }
     }
 }
 
 impl Totp {
-    pub fn new(label: String, secret: Vec<u8>, step: u64, algo: TotpAlgo) -> Self {
+    pub fn new(label: String, secret: Vec<u8>, step: u64, digits: u8, algo: TotpAlgo) -> Self {
         Totp {
             label,
             secret,
             step,
+            digits,
             algo,
         }
     }
@@ -118,6 +130,7 @@ impl Totp {
             label,
             secret,
             step,
+            digits: TOTP_DEFAULT_DIGITS,
             algo,
         }
     }
@@ -127,6 +140,7 @@ impl Totp {
             l: self.label.clone(),
             k: self.secret.clone(),
             s: self.step,
+            d: Some(self.digits),
             a: match self.algo {
                 TotpAlgo::Sha1 => DbTotpAlgoV1::S1,
                 TotpAlgo::Sha256 => DbTotpAlgoV1::S256,
@@ -148,7 +162,7 @@ impl Totp {
             .map_err(|_| TotpError::HmacError)?;
 
         let otp = u32::from_be_bytes(bytes);
-        Ok((otp & 0x7fff_ffff) % 1_000_000)
+        Ok((otp & 0x7fff_ffff) % 10_u32.pow(self.digits as u32))
     }
 
     pub fn do_totp_duration_from_epoch(&self, time: &Duration) -> Result<u32, TotpError> {
@@ -168,6 +182,10 @@ impl Totp {
     pub fn verify(&self, chal: u32, time: &Duration) -> bool {
         let secs = time.as_secs();
         let counter = secs / self.step;
+        // A code can't be longer than our configured digits, so reject it early.
+        if chal >= 10_u32.pow(self.digits as u32) {
+            return false;
+        }
         // Any error becomes a failure.
         self.digest(counter).map(|v1| v1 == chal).unwrap_or(false)
             || self
@@ -188,6 +206,7 @@ impl Totp {
                 .replace(" ", "%20"),
             secret: self.secret.clone(),
             step: self.step,
+            digits: self.digits,
             algo: match self.algo {
                 TotpAlgo::Sha1 => ProtoTotpAlgo::Sha1,
                 TotpAlgo::Sha256 => ProtoTotpAlgo::Sha256,
@@ -199,21 +218,49 @@ impl Totp {
 
 #[cfg(test)]
 mod tests {
-    use crate::credential::totp::{Totp, TotpAlgo, TotpError, TOTP_DEFAULT_STEP};
+    use crate::be::dbvalue::{DbTotpAlgoV1, DbTotpV1};
+    use crate::credential::totp::{
+        Totp, TotpAlgo, TotpError, TOTP_DEFAULT_DIGITS, TOTP_DEFAULT_STEP,
+    };
+    use std::convert::TryFrom;
     use std::time::Duration;
 
     #[test]
     fn hotp_basic() {
-        let otp_sha1 = Totp::new("".to_string(), vec![0], 30, TotpAlgo::Sha1);
+        let otp_sha1 = Totp::new(
+            "".to_string(),
+            vec![0],
+            30,
+            TOTP_DEFAULT_DIGITS,
+            TotpAlgo::Sha1,
+        );
         assert!(otp_sha1.digest(0) == Ok(328482));
-        let otp_sha256 = Totp::new("".to_string(), vec![0], 30, TotpAlgo::Sha256);
+        let otp_sha256 = Totp::new(
+            "".to_string(),
+            vec![0],
+            30,
+            TOTP_DEFAULT_DIGITS,
+            TotpAlgo::Sha256,
+        );
         assert!(otp_sha256.digest(0) == Ok(356306));
-        let otp_sha512 = Totp::new("".to_string(), vec![0], 30, TotpAlgo::Sha512);
+        let otp_sha512 = Totp::new(
+            "".to_string(),
+            vec![0],
+            30,
+            TOTP_DEFAULT_DIGITS,
+            TotpAlgo::Sha512,
+        );
         assert!(otp_sha512.digest(0) == Ok(674061));
     }
 
     fn do_test(key: Vec<u8>, algo: TotpAlgo, secs: u64, step: u64, expect: Result<u32, TotpError>) {
-        let otp = Totp::new("".to_string(), key.clone(), step, algo.clone());
+        let otp = Totp::new(
+            "".to_string(),
+            key.clone(),
+            step,
+            TOTP_DEFAULT_DIGITS,
+            algo.clone(),
+        );
         let d = Duration::from_secs(secs);
         let r = otp.do_totp_duration_from_epoch(&d);
         debug!(
@@ -285,6 +332,7 @@ mod tests {
             "".to_string(),
             key.clone(),
             TOTP_DEFAULT_STEP,
+            TOTP_DEFAULT_DIGITS,
             TotpAlgo::Sha512,
         );
         let d = Duration::from_secs(secs);
@@ -297,4 +345,79 @@ mod tests {
         // This is step + 1
         assert!(!otp.verify(972806, &d));
     }
+
+    #[test]
+    fn totp_rfc6238_digit_lengths() {
+        // https://tools.ietf.org/html/rfc6238#appendix-B
+        let key = b"12345678901234567890".to_vec();
+        let d = Duration::from_secs(59);
+        let otp_6 = Totp::new(
+            "".to_string(),
+            key.clone(),
+            TOTP_DEFAULT_STEP,
+            6,
+            TotpAlgo::Sha1,
+        );
+        assert!(otp_6.do_totp_duration_from_epoch(&d) == Ok(287082));
+        assert!(otp_6.verify(287082, &d));
+        // An 8 digit code must not be accepted by a 6 digit token.
+        assert!(!otp_6.verify(94287082, &d));
+
+        let otp_8 = Totp::new(
+            "".to_string(),
+            key.clone(),
+            TOTP_DEFAULT_STEP,
+            8,
+            TotpAlgo::Sha1,
+        );
+        assert!(otp_8.do_totp_duration_from_epoch(&d) == Ok(94287082));
+        assert!(otp_8.verify(94287082, &d));
+        assert!(!otp_8.verify(287082, &d));
+        // Leading zeros are part of the code, but not of the u32.
+        let d = Duration::from_secs(1111111109);
+        assert!(otp_8.verify(7081804, &d));
+
+        let key = b"12345678901234567890123456789012".to_vec();
+        let otp_8 = Totp::new("".to_string(), key, TOTP_DEFAULT_STEP, 8, TotpAlgo::Sha256);
+        assert!(otp_8.verify(46119246, &Duration::from_secs(59)));
+    }
+
+    #[test]
+    fn totp_alternate_step() {
+        let key = b"12345678901234567890".to_vec();
+        let otp = Totp::new("".to_string(), key, 60, 8, TotpAlgo::Sha1);
+        let d = Duration::from_secs(1111111109);
+        // Step
+        assert!(otp.verify(19360094, &d));
+        // Step - 1
+        assert!(otp.verify(28471227, &d));
+        // Step - 2
+        assert!(!otp.verify(58871156, &d));
+        // The 30 second step value is not valid.
+        assert!(!otp.verify(7081804, &d));
+    }
+
+    #[test]
+    fn totp_dbtotpv1_digits() {
+        let dbtotp = DbTotpV1 {
+            l: "".to_string(),
+            k: vec![0],
+            s: TOTP_DEFAULT_STEP,
+            d: None,
+            a: DbTotpAlgoV1::S1,
+        };
+        // Legacy tokens without digits are 6 digit tokens.
+        let otp = Totp::try_from(dbtotp).expect("Failed to load totp");
+        assert!(otp.digits == 6);
+        assert!(otp.to_dbtotpv1().d == Some(6));
+
+        let dbtotp = DbTotpV1 {
+            l: "".to_string(),
+            k: vec![0],
+            s: TOTP_DEFAULT_STEP,
+            d: Some(7),
+            a: DbTotpAlgoV1::S1,
+        };
+        assert!(Totp::try_from(dbtotp).is_err());
+    }
 }
@@ -1,5 +1,13 @@
+use kanidm_proto::v1::WebauthnBackupState;
+use serde_cbor::Value as CborValue;
 use webauthn_rs::WebauthnConfig;
 
+// The flags byte of authenticator data follows the 32 byte rp id hash.
+// https://www.w3.org/TR/webauthn-3/#sctn-authenticator-data
+const AUTH_DATA_FLAGS_OFFSET: usize = 32;
+const AUTH_DATA_FLAG_BACKUP_ELIGIBLE: u8 = 0x08;
+const AUTH_DATA_FLAG_BACKED_UP: u8 = 0x10;
+
 pub struct WebauthnDomainConfig {
     pub rp_name: String,
     pub origin: String,
@@ -19,3 +27,103 @@ impl WebauthnConfig for WebauthnDomainConfig {
         self.rp_id.clone()
     }
 }
+
+/// Read the backup flags from the authenticator data of an assertion or a
+/// registration. Authenticators that predate these flags leave them unset, and
+/// so are reported as single device. Data that is too short, or that is backed
+/// up without being eligible, is invalid and gives None.
+pub fn backup_state_from_auth_data(auth_data: &[u8]) -> Option<WebauthnBackupState> {
+    let flags = *auth_data.get(AUTH_DATA_FLAGS_OFFSET)?;
+    let eligible = flags & AUTH_DATA_FLAG_BACKUP_ELIGIBLE != 0;
+    let backed_up = flags & AUTH_DATA_FLAG_BACKED_UP != 0;
+    if backed_up && !eligible {
+        None
+    } else {
+        Some(WebauthnBackupState {
+            eligible,
+            backed_up,
+        })
+    }
+}
+
+/// The authenticator data of a registration is within its cbor attestation
+/// object.
+pub fn backup_state_from_attestation(attestation_object: &[u8]) -> Option<WebauthnBackupState> {
+    let value: CborValue = serde_cbor::from_slice(attestation_object).ok()?;
+    match value {
+        CborValue::Map(map) => match map.get(&CborValue::Text("authData".to_string())) {
+            Some(CborValue::Bytes(auth_data)) => backup_state_from_auth_data(auth_data),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backup_state_from_attestation, backup_state_from_auth_data};
+    use kanidm_proto::v1::WebauthnBackupState;
+    use serde_cbor::Value as CborValue;
+    use std::collections::BTreeMap;
+
+    // An rp id hash, the flags and a counter.
+    fn auth_data(flags: u8) -> Vec<u8> {
+        let mut data = vec![0xab; 32];
+        data.push(flags);
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data
+    }
+
+    #[test]
+    fn test_webauthn_backup_state_from_assertion() {
+        // User present only, as from an authenticator that doesn't report backup state.
+        assert!(
+            backup_state_from_auth_data(&auth_data(0x01))
+                == Some(WebauthnBackupState {
+                    eligible: false,
+                    backed_up: false
+                })
+        );
+        assert!(
+            backup_state_from_auth_data(&auth_data(0x05 | 0x08))
+                == Some(WebauthnBackupState {
+                    eligible: true,
+                    backed_up: false
+                })
+        );
+        assert!(
+            backup_state_from_auth_data(&auth_data(0x05 | 0x08 | 0x10))
+                == Some(WebauthnBackupState {
+                    eligible: true,
+                    backed_up: true
+                })
+        );
+        // Backed up but not eligible is invalid.
+        assert!(backup_state_from_auth_data(&auth_data(0x01 | 0x10)).is_none());
+        // Truncated before the flags.
+        assert!(backup_state_from_auth_data(&[0xab; 32]).is_none());
+    }
+
+    #[test]
+    fn test_webauthn_backup_state_from_attestation() {
+        let mut map = BTreeMap::new();
+        map.insert(
+            CborValue::Text("fmt".to_string()),
+            CborValue::Text("none".to_string()),
+        );
+        map.insert(
+            CborValue::Text("authData".to_string()),
+            CborValue::Bytes(auth_data(0x45 | 0x08 | 0x10)),
+        );
+        let attestation =
+            serde_cbor::to_vec(&CborValue::Map(map)).expect("Failed to encode attestation");
+        assert!(
+            backup_state_from_attestation(&attestation)
+                == Some(WebauthnBackupState {
+                    eligible: true,
+                    backed_up: true
+                })
+        );
+        assert!(backup_state_from_attestation(&[0xff, 0x00]).is_none());
+    }
+}
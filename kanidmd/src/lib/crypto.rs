@@ -1,16 +1,367 @@
-use crate::config::Configuration;
+use crate::config::{Configuration, TlsConfiguration};
+use openssl::asn1::Asn1Time;
 use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkey::Id;
 use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+use openssl::x509::{X509NameRef, X509Ref, X509VerifyResult, X509};
+use std::net::IpAddr;
+use url::{Host, Url};
 
-pub fn setup_tls(config: &Configuration) -> Result<Option<SslAcceptorBuilder>, ErrorStack> {
+/// Load the configured TLS chain and key. A chain with a key below the configured
+/// minimum strength is refused, unless tls_allow_weak_keys is set.
+pub fn setup_tls(config: &Configuration) -> Result<Option<SslAcceptorBuilder>, String> {
     match &config.tls_config {
         Some(tls_config) => {
-            let mut ssl_builder = SslAcceptor::mozilla_modern(SslMethod::tls())?;
-            ssl_builder.set_certificate_chain_file(&tls_config.chain)?;
-            ssl_builder.set_private_key_file(&tls_config.key, SslFiletype::PEM)?;
-            ssl_builder.check_private_key()?;
-            Ok(Some(ssl_builder))
+            check_tls_key_strength(config)?;
+            load_tls(tls_config)
+                .map(Some)
+                .map_err(|e| format!("Failed to load tls_chain and tls_key -> {:?}", e))
         }
         None => Ok(None),
     }
 }
+
+fn load_tls(tls_config: &TlsConfiguration) -> Result<SslAcceptorBuilder, ErrorStack> {
+    let mut ssl_builder = SslAcceptor::mozilla_modern(SslMethod::tls())?;
+    ssl_builder.set_certificate_chain_file(&tls_config.chain)?;
+    ssl_builder.set_private_key_file(&tls_config.key, SslFiletype::PEM)?;
+    ssl_builder.check_private_key()?;
+    Ok(ssl_builder)
+}
+
+fn load_chain(tls_config: &TlsConfiguration) -> Result<Vec<X509>, String> {
+    let chain_pem = std::fs::read(&tls_config.chain)
+        .map_err(|e| format!("Failed to read {} -> {:?}", tls_config.chain, e))?;
+    X509::stack_from_pem(&chain_pem)
+        .map_err(|e| format!("Failed to parse {} -> {:?}", tls_config.chain, e))
+}
+
+/// Check that every key in the configured TLS chain meets the configured minimum
+/// strength. The leaf key is the pair of tls_key, so this covers it too.
+pub fn check_tls_key_strength(config: &Configuration) -> Result<(), String> {
+    let tls_config = match &config.tls_config {
+        Some(tls_config) => tls_config,
+        None => return Ok(()),
+    };
+    let weak = weak_keys(
+        &load_chain(tls_config)?,
+        config.tls_min_rsa_bits,
+        config.tls_min_ec_bits,
+    );
+    if weak.is_empty() {
+        Ok(())
+    } else if config.tls_allow_weak_keys {
+        for w in weak.iter() {
+            warn!("{}, allowed by tls_allow_weak_keys", w);
+        }
+        Ok(())
+    } else {
+        Err(format!(
+            "{}. Replace the certificate, or set tls_allow_weak_keys = true",
+            weak.join(", ")
+        ))
+    }
+}
+
+// Finite field keys (RSA, DSA, DH) share a minimum, as their strength for a given
+// size is comparable. Edwards curves are always strong enough.
+fn weak_keys(chain: &[X509], min_rsa_bits: u32, min_ec_bits: u32) -> Vec<String> {
+    chain
+        .iter()
+        .filter_map(|cert| {
+            let subject = x509_name_to_string(cert.subject_name());
+            let pkey = match cert.public_key() {
+                Ok(pkey) => pkey,
+                Err(_) => return Some(format!("The key of {} can not be read", subject)),
+            };
+            let (kind, min_bits) = match pkey.id() {
+                Id::RSA => ("RSA", min_rsa_bits),
+                Id::DSA => ("DSA", min_rsa_bits),
+                Id::DH => ("DH", min_rsa_bits),
+                Id::EC => ("EC", min_ec_bits),
+                Id::ED25519 | Id::ED448 => return None,
+                _ => return Some(format!("The key of {} is of an unknown type", subject)),
+            };
+            if pkey.bits() < min_bits {
+                Some(format!(
+                    "The {} key of {} is {} bits, below the minimum of {}",
+                    kind,
+                    subject,
+                    pkey.bits(),
+                    min_bits
+                ))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The outcome of checking a TLS configuration. If there are any problems the
+/// configuration should not be deployed.
+#[derive(Debug)]
+pub struct TlsCheckReport {
+    pub subject: String,
+    pub not_after: String,
+    pub days_remaining: i32,
+    pub problems: Vec<String>,
+}
+
+/// Load the configured TLS chain and key in the same way as the server does at
+/// startup, and then check that the leaf certificate is valid now, will not expire
+/// within `expiry_warn_days`, and covers the host of the configured origin. Keys
+/// below the minimum strength are problems unless tls_allow_weak_keys is set.
+pub fn check_tls(config: &Configuration, expiry_warn_days: u32) -> Result<TlsCheckReport, String> {
+    let tls_config = config
+        .tls_config
+        .as_ref()
+        .ok_or_else(|| "tls_chain and tls_key are not configured".to_string())?;
+
+    // This is what proves the key matches the leaf of the chain.
+    load_tls(tls_config).map_err(|e| format!("Failed to load tls_chain and tls_key -> {:?}", e))?;
+
+    let chain = load_chain(tls_config)?;
+
+    let mut report = check_chain(&chain, config.origin.as_str(), expiry_warn_days)?;
+    if let Err(e) = check_tls_key_strength(config) {
+        report.problems.push(e);
+    }
+    Ok(report)
+}
+
+fn check_chain(
+    chain: &[X509],
+    origin: &str,
+    expiry_warn_days: u32,
+) -> Result<TlsCheckReport, String> {
+    let leaf = chain
+        .first()
+        .ok_or_else(|| "tls_chain contains no certificates".to_string())?;
+
+    let mut problems = Vec::new();
+
+    // Each certificate must be issued and signed by the one that follows it.
+    for pair in chain.windows(2) {
+        let signed = pair[1]
+            .public_key()
+            .and_then(|pk| pair[0].verify(&pk))
+            .unwrap_or(false);
+        if pair[1].issued(&pair[0]) != X509VerifyResult::OK || !signed {
+            problems.push(format!(
+                "{} is not issued by {}, the chain may be out of order or incomplete",
+                x509_name_to_string(pair[0].subject_name()),
+                x509_name_to_string(pair[1].subject_name())
+            ));
+        }
+    }
+
+    let now = Asn1Time::days_from_now(0).map_err(|e| format!("{:?}", e))?;
+
+    let since_valid = leaf
+        .not_before()
+        .diff(&now)
+        .map_err(|e| format!("{:?}", e))?;
+    if since_valid.days < 0 || since_valid.secs < 0 {
+        problems.push(format!(
+            "Certificate is not valid until {}",
+            leaf.not_before()
+        ));
+    }
+
+    let until_expiry = now.diff(leaf.not_after()).map_err(|e| format!("{:?}", e))?;
+    if until_expiry.days < 0 || until_expiry.secs < 0 {
+        problems.push(format!("Certificate expired at {}", leaf.not_after()));
+    } else if until_expiry.days < expiry_warn_days as i32 {
+        problems.push(format!(
+            "Certificate expires in {} days, at {}",
+            until_expiry.days,
+            leaf.not_after()
+        ));
+    }
+
+    match Url::parse(origin)
+        .ok()
+        .and_then(|u| u.host().map(|h| h.to_owned()))
+    {
+        Some(host) => {
+            if !certificate_covers_host(leaf, &host) {
+                problems.push(format!(
+                    "Certificate does not cover the origin host {}",
+                    host
+                ));
+            }
+        }
+        None => problems.push(format!("Unable to determine the host of origin {}", origin)),
+    }
+
+    Ok(TlsCheckReport {
+        subject: x509_name_to_string(leaf.subject_name()),
+        not_after: leaf.not_after().to_string(),
+        days_remaining: until_expiry.days,
+        problems,
+    })
+}
+
+fn certificate_covers_host(cert: &X509Ref, host: &Host<String>) -> bool {
+    match cert.subject_alt_names() {
+        Some(sans) => sans.iter().any(|san| match host {
+            Host::Domain(d) => san
+                .dnsname()
+                .map(|n| dns_name_matches(n, d))
+                .unwrap_or(false),
+            Host::Ipv4(ip) => san.ipaddress() == Some(&ip.octets()[..]),
+            Host::Ipv6(ip) => san.ipaddress() == Some(&ip.octets()[..]),
+        }),
+        // Only consider the common name when there are no subject alt names.
+        None => match host {
+            Host::Domain(d) => cert
+                .subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .filter_map(|e| e.data().as_utf8().ok())
+                .any(|cn| dns_name_matches(&cn, d)),
+            Host::Ipv4(_) | Host::Ipv6(_) => false,
+        },
+    }
+}
+
+// A wildcard may only replace the whole left-most label, and never matches an
+// ip address.
+fn dns_name_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let host = host.to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => match host.find('.') {
+            Some(idx) => host.parse::<IpAddr>().is_err() && idx > 0 && host[idx + 1..] == *suffix,
+            None => false,
+        },
+        None => pattern == host,
+    }
+}
+
+fn x509_name_to_string(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|e| {
+            format!(
+                "{}={}",
+                e.object().nid().short_name().unwrap_or("?"),
+                e.data()
+                    .as_utf8()
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|_| "?".to_string())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::{check_chain, dns_name_matches, weak_keys};
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Builder, X509NameBuilder, X509};
+
+    fn ec_key(curve: Nid) -> PKey<Private> {
+        let group = EcGroup::from_curve_name(curve).expect("must not fail");
+        PKey::from_ec_key(EcKey::generate(&group).expect("must not fail")).expect("must not fail")
+    }
+
+    fn self_signed(dns: &str, days: u32) -> X509 {
+        self_signed_with_key(dns, days, &ec_key(Nid::X9_62_PRIME256V1))
+    }
+
+    fn self_signed_with_key(dns: &str, days: u32, pkey: &PKey<Private>) -> X509 {
+        let mut name = X509NameBuilder::new().expect("must not fail");
+        name.append_entry_by_nid(Nid::COMMONNAME, dns)
+            .expect("must not fail");
+        let name = name.build();
+
+        let mut builder = X509Builder::new().expect("must not fail");
+        builder.set_version(2).expect("must not fail");
+        builder.set_subject_name(&name).expect("must not fail");
+        builder.set_issuer_name(&name).expect("must not fail");
+        builder.set_pubkey(pkey).expect("must not fail");
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).expect("must not fail"))
+            .expect("must not fail");
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).expect("must not fail"))
+            .expect("must not fail");
+        let san = SubjectAlternativeName::new()
+            .dns(dns)
+            .build(&builder.x509v3_context(None, None))
+            .expect("must not fail");
+        builder.append_extension(san).expect("must not fail");
+        builder
+            .sign(pkey, MessageDigest::sha256())
+            .expect("must not fail");
+        builder.build()
+    }
+
+    #[test]
+    fn test_tls_check_dns_name_matches() {
+        assert!(dns_name_matches("idm.example.com", "IDM.example.com"));
+        assert!(dns_name_matches("*.example.com", "idm.example.com"));
+        assert!(!dns_name_matches("*.example.com", "example.com"));
+        assert!(!dns_name_matches("*.example.com", "a.idm.example.com"));
+        assert!(!dns_name_matches("idm.example.com", "idm.example.net"));
+    }
+
+    #[test]
+    fn test_tls_check_chain() {
+        let cert = self_signed("idm.example.com", 90);
+        let report = check_chain(&[cert], "https://idm.example.com", 30).expect("must not fail");
+        assert!(report.problems.is_empty());
+        assert!(report.days_remaining >= 89);
+
+        // Soon to expire, and not covering the origin.
+        let cert = self_signed("idm.example.com", 10);
+        let report =
+            check_chain(&[cert], "https://other.example.com:8443", 30).expect("must not fail");
+        assert!(report.problems.len() == 2);
+
+        // A chain where the second certificate did not issue the first.
+        let chain = vec![
+            self_signed("idm.example.com", 90),
+            self_signed("ca.example.com", 90),
+        ];
+        let report = check_chain(&chain, "https://idm.example.com", 30).expect("must not fail");
+        assert!(report.problems.len() == 1);
+
+        assert!(check_chain(&[], "https://idm.example.com", 30).is_err());
+    }
+
+    #[test]
+    fn test_tls_check_weak_keys() {
+        let strong = vec![
+            self_signed("idm.example.com", 90),
+            self_signed_with_key(
+                "ca.example.com",
+                90,
+                &PKey::from_rsa(Rsa::generate(2048).expect("must not fail"))
+                    .expect("must not fail"),
+            ),
+        ];
+        assert!(weak_keys(&strong, 2048, 256).is_empty());
+
+        let weak_rsa =
+            PKey::from_rsa(Rsa::generate(1024).expect("must not fail")).expect("must not fail");
+        let weak = vec![
+            self_signed_with_key("idm.example.com", 90, &weak_rsa),
+            self_signed_with_key("ca.example.com", 90, &ec_key(Nid::SECP224R1)),
+        ];
+        let problems = weak_keys(&weak, 2048, 256);
+        assert!(problems.len() == 2);
+        assert!(problems[0].contains("RSA key of CN=idm.example.com is 1024 bits"));
+        assert!(problems[1].contains("EC key of CN=ca.example.com is 224 bits"));
+
+        // A higher minimum rejects keys that would otherwise be accepted.
+        assert!(weak_keys(&strong, 3072, 384).len() == 2);
+    }
+}
@@ -35,7 +35,7 @@ use crate::value::{IndexType, SyntaxType};
 use crate::value::{PartialValue, Value};
 use kanidm_proto::v1::Entry as ProtoEntry;
 use kanidm_proto::v1::Filter as ProtoFilter;
-use kanidm_proto::v1::{OperationError, SchemaError};
+use kanidm_proto::v1::{OperationError, SchemaError, SpnFormat};
 
 use crate::be::dbentry::{DbEntry, DbEntryV1, DbEntryVers};
 use crate::be::IdxKey;
@@ -82,6 +82,7 @@ use uuid::Uuid;
 
 lazy_static! {
     static ref CLASS_EXTENSIBLE: PartialValue = PartialValue::new_class("extensibleobject");
+    static ref CLASS_SPN_EXEMPT: PartialValue = PartialValue::new_class("spn_exempt");
     static ref PVCLASS_TOMBSTONE: PartialValue = PartialValue::new_class("tombstone");
     static ref PVCLASS_RECYCLED: PartialValue = PartialValue::new_class("recycled");
 }
@@ -599,10 +600,18 @@ impl<STATE> Entry<EntryInvalid, STATE> {
 
             let must = must?;
 
+            // The spn plugin marks the accounts and groups it deliberately gives
+            // no spn (such as those outside of the spn_scope), which is the only
+            // case a must attribute may be absent.
+            let spn_exempt = ne.attribute_value_pres("class", &CLASS_SPN_EXEMPT);
+
             // Check that all must are inplace
             //   for each attr in must, check it's present on our ent
             let mut missing_must = Vec::with_capacity(0);
             must.iter().for_each(|attr| {
+                if spn_exempt && attr.name.as_str() == "spn" {
+                    return;
+                }
                 let avas = ne.get_ava(&attr.name);
                 if avas.is_none() {
                     missing_must.push(attr.name.to_string());
@@ -1623,12 +1632,6 @@ impl<VALID, STATE> Entry<VALID, STATE> {
             .and_then(|v: &Value| v.as_json_filter())
     }
 
-    #[inline(always)]
-    pub(crate) fn generate_spn(&self, domain_name: &str) -> Option<Value> {
-        self.get_ava_single_str("name")
-            .map(|name| Value::new_spn_str(name, domain_name))
-    }
-
     #[inline(always)]
     pub fn attribute_pres(&self, attr: &str) -> bool {
         // Note, we don't normalise attr name, but I think that's not
@@ -1984,10 +1987,147 @@ impl From<&SchemaClass> for Entry<EntryInit, EntryNew> {
     }
 }
 
+/// Generates and validates the spn of accounts and groups. All spn generation
+/// and verification goes through this type, so that they can never drift apart.
+#[derive(Debug, Clone)]
+pub struct SpnGenerator {
+    format: SpnFormat,
+    prefixes: Vec<(PartialValue, String)>,
+}
+
+impl SpnGenerator {
+    pub fn new(domain_name: &str) -> Self {
+        SpnGenerator {
+            format: SpnFormat::new(domain_name),
+            prefixes: Vec::new(),
+        }
+    }
+
+    /// Parse a value of domain_spn_prefix, which is in the form "class=prefix".
+    /// The prefix may not contain '/', '@' or whitespace.
+    pub fn parse_prefix(s: &str) -> Option<(String, String)> {
+        let mut parts = s.splitn(2, '=');
+        let class = parts.next()?.trim();
+        let prefix = parts.next()?.trim();
+        if class.is_empty()
+            || prefix.is_empty()
+            || prefix
+                .chars()
+                .any(|c| c == '/' || c == '@' || c.is_whitespace())
+        {
+            None
+        } else {
+            Some((class.to_lowercase(), prefix.to_string()))
+        }
+    }
+
+    /// Give the entries with one of these classes a service spn, of the form
+    /// "prefix/name". When an entry has more than one of the classes, the first
+    /// that matches is used. Other entries are unaffected.
+    pub fn prefixes(mut self, prefixes: &[(String, String)]) -> Self {
+        self.prefixes = prefixes
+            .iter()
+            .map(|(class, prefix)| (PartialValue::new_class(class.as_str()), prefix.clone()))
+            .collect();
+        self
+    }
+
+    fn prefix<VALID, STATE>(&self, e: &Entry<VALID, STATE>) -> Option<&str> {
+        self.prefixes
+            .iter()
+            .find(|(class, _)| e.attribute_value_pres("class", class))
+            .map(|(_, prefix)| prefix.as_str())
+    }
+
+    /// The delimiter between name and realm in the string form of an spn.
+    pub fn delimiter(mut self, delimiter: char) -> Self {
+        self.format = self.format.delimiter(delimiter);
+        self
+    }
+
+    /// Use the ASCII compatible encoding (punycode) of an internationalised
+    /// domain name in the realm of generated spns. A domain name that is already
+    /// ASCII is unchanged.
+    pub fn punycode(mut self, punycode: bool) -> Self {
+        self.format = self.format.punycode(punycode);
+        self
+    }
+
+    /// Lowercase the name and realm of generated spns.
+    pub fn case_fold(mut self, case_fold: bool) -> Self {
+        self.format = self.format.case_fold(case_fold);
+        self
+    }
+
+    /// Append this to the domain name to form the realm of generated spns.
+    pub fn suffix(mut self, suffix: Option<&str>) -> Self {
+        self.format = self.format.suffix(suffix);
+        self
+    }
+
+    /// Replace the characters kerberos doesn't allow in the name of generated
+    /// spns with '_'.
+    pub fn sanitize(mut self, sanitize: bool) -> Self {
+        self.format = self.format.sanitize(sanitize);
+        self
+    }
+
+    /// The characters of the name of this spn that kerberos doesn't allow in a
+    /// principal. The prefix of a service spn is checked when it's configured.
+    pub fn invalid_kerberos_chars<VALID, STATE>(
+        &self,
+        e: &Entry<VALID, STATE>,
+        spn: &Value,
+    ) -> Vec<char> {
+        let name = match spn.to_spn() {
+            Some((name, _)) => name,
+            None => return Vec::new(),
+        };
+        let name = match self.prefix(e) {
+            Some(prefix) => name
+                .strip_prefix(prefix)
+                .and_then(|n| n.strip_prefix('/'))
+                .unwrap_or(name),
+            None => name,
+        };
+        let mut invalid: Vec<char> = name
+            .chars()
+            .filter(|c| !SpnFormat::is_kerberos_char(*c))
+            .collect();
+        invalid.sort_unstable();
+        invalid.dedup();
+        invalid
+    }
+
+    /// Is this the realm of the spns we generate.
+    pub fn is_local_realm(&self, realm: &str) -> bool {
+        realm.eq_ignore_ascii_case(self.format.realm().as_str())
+    }
+
+    /// Generate the spn for this entry, or None if it has no name.
+    pub fn generate<VALID, STATE>(&self, e: &Entry<VALID, STATE>) -> Option<Value> {
+        e.get_ava_single_str("name").map(|name| {
+            let (name, realm) = self.format.generate(name, self.prefix(e));
+            Value::new_spn_str(name.as_str(), realm.as_str())
+        })
+    }
+
+    /// Check that `stored` is the spn we would generate for this entry.
+    pub fn validate<VALID, STATE>(&self, e: &Entry<VALID, STATE>, stored: &Value) -> bool {
+        self.generate(e).map(|spn| spn == *stored).unwrap_or(false)
+    }
+
+    /// The string form of an spn, using the configured delimiter.
+    pub fn to_spn_string(&self, spn: &Value) -> Option<String> {
+        spn.to_spn()
+            .map(|(name, realm)| self.format.to_spn_string(name, realm))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::be::IdxKey;
-    use crate::entry::{Entry, EntryInit, EntryInvalid, EntryNew};
+    use crate::entry::{Entry, EntryInit, EntryInvalid, EntryNew, SpnGenerator};
     use crate::modify::{Modify, ModifyList};
     use crate::value::{IndexType, PartialValue, Value};
     use hashbrown::HashSet;
@@ -2451,4 +2591,106 @@ mod tests {
                 == Some(Ok("spn=renameperson@example.com".to_string()))
         );
     }
+
+    #[test]
+    fn test_spn_generator() {
+        let mut e: Entry<EntryInit, EntryNew> = Entry::new();
+        let spngen = SpnGenerator::new("example.com");
+        // No name, no spn.
+        assert!(spngen.generate(&e).is_none());
+
+        e.add_ava("name", Value::new_iname("testperson"));
+        let spn = spngen.generate(&e).expect("Failed to generate spn");
+        assert!(spn == Value::new_spn_str("testperson", "example.com"));
+        assert!(spngen.validate(&e, &spn));
+        assert!(!spngen.validate(&e, &Value::new_spn_str("testperson", "other.com")));
+        assert!(spngen.to_spn_string(&spn) == Some("testperson@example.com".to_string()));
+        assert!(spngen
+            .to_spn_string(&Value::new_iname("testperson"))
+            .is_none());
+
+        // Stored spns from another generator don't validate.
+        let spngen_other = SpnGenerator::new("Example.COM")
+            .case_fold(true)
+            .suffix(Some(".AU"))
+            .delimiter('/');
+        let spn_other = spngen_other.generate(&e).expect("Failed to generate spn");
+        assert!(spn_other == Value::new_spn_str("testperson", "example.com.au"));
+        assert!(spngen_other.validate(&e, &spn_other));
+        assert!(!spngen_other.validate(&e, &spn));
+        assert!(!spngen.validate(&e, &spn_other));
+        assert!(
+            spngen_other.to_spn_string(&spn_other) == Some("testperson/example.com.au".to_string())
+        );
+    }
+
+    #[test]
+    fn test_spn_generator_punycode() {
+        let mut e: Entry<EntryInit, EntryNew> = Entry::new();
+        e.add_ava("name", Value::new_iname("testperson"));
+
+        let spngen = SpnGenerator::new("bücher.example").punycode(true);
+        let spn = spngen.generate(&e).expect("Failed to generate spn");
+        assert!(spn == Value::new_spn_str("testperson", "xn--bcher-kva.example"));
+        assert!(spngen.validate(&e, &spn));
+        assert!(spngen.is_local_realm("xn--bcher-kva.example"));
+
+        // In unicode mode the realm is the domain name as it is, and the two don't
+        // validate each other's spns.
+        let spngen_unicode = SpnGenerator::new("bücher.example").punycode(false);
+        let spn_unicode = spngen_unicode.generate(&e).expect("Failed to generate spn");
+        assert!(spn_unicode == Value::new_spn_str("testperson", "bücher.example"));
+        assert!(spngen_unicode.validate(&e, &spn_unicode));
+        assert!(!spngen_unicode.validate(&e, &spn));
+        assert!(!spngen.validate(&e, &spn_unicode));
+
+        // ASCII domain names are the same in either mode.
+        let spngen = SpnGenerator::new("example.com").punycode(true);
+        assert!(
+            spngen.generate(&e).expect("Failed to generate spn")
+                == Value::new_spn_str("testperson", "example.com")
+        );
+    }
+
+    #[test]
+    fn test_spn_generator_prefixes() {
+        let prefixes: Vec<(String, String)> = ["posixaccount=host", "service=HTTP"]
+            .iter()
+            .map(|s| SpnGenerator::parse_prefix(s).expect("Invalid prefix"))
+            .collect();
+        let spngen = SpnGenerator::new("example.com").prefixes(&prefixes);
+
+        let mut e: Entry<EntryInit, EntryNew> = Entry::new();
+        e.add_ava("class", Value::new_class("account"));
+        e.add_ava("name", Value::new_iname("testperson"));
+        // Without a class in the table, the default spn.
+        let spn = spngen.generate(&e).expect("Failed to generate spn");
+        assert!(spn == Value::new_spn_str("testperson", "example.com"));
+
+        e.add_ava("class", Value::new_class("service"));
+        let spn = spngen.generate(&e).expect("Failed to generate spn");
+        assert!(spn == Value::new_spn_str("HTTP/testperson", "example.com"));
+        assert!(spngen.validate(&e, &spn));
+        assert!(!spngen.validate(&e, &Value::new_spn_str("testperson", "example.com")));
+
+        // With more than one, the first in the table is used.
+        e.add_ava("class", Value::new_class("posixaccount"));
+        let spn = spngen.generate(&e).expect("Failed to generate spn");
+        assert!(spngen.to_spn_string(&spn) == Some("host/testperson@example.com".to_string()));
+
+        assert!(
+            SpnGenerator::parse_prefix("Service=HTTP")
+                == Some(("service".to_string(), "HTTP".to_string()))
+        );
+        for invalid in &[
+            "service",
+            "=HTTP",
+            "service=",
+            "service=HTTP/x",
+            "a=b@c",
+            "a=b c",
+        ] {
+            assert!(SpnGenerator::parse_prefix(invalid).is_none());
+        }
+    }
 }
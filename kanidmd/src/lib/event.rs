@@ -21,6 +21,7 @@ use ldap3_server::simple::LdapFilter;
 use smartstring::alias::String as AttrString;
 use std::collections::BTreeSet;
 use std::hash::Hash;
+use std::net::IpAddr;
 use std::time::Duration;
 use uuid::Uuid;
 
@@ -283,13 +284,43 @@ impl SearchEvent {
             .validate(qs.get_schema())
             .map_err(OperationError::SchemaViolation)?;
         let filter = filter_orig.clone().into_ignore_hidden();
+
+        // Unlike an internal search, an attribute that isn't in the schema is an
+        // error rather than ignored, so a typo doesn't silently return nothing.
+        let attrs = req
+            .attrs
+            .as_deref()
+            .or_else(|| qs.get_default_search_attrs())
+            .map(|vs| {
+                vs.iter()
+                    .map(|a| {
+                        qs.get_schema()
+                            .normalise_attr_if_exists(a.to_lowercase().as_str())
+                            .ok_or_else(|| {
+                                lrequest_error!(
+                                    audit,
+                                    "Requested attribute {} is not in the schema",
+                                    a
+                                );
+                                OperationError::InvalidAttributeName(a.to_string())
+                            })
+                    })
+                    .collect::<Result<BTreeSet<AttrString>, _>>()
+            })
+            .transpose()?;
+
+        if let Some(s) = &attrs {
+            if s.is_empty() {
+                lrequest_error!(audit, "EmptyRequest for attributes");
+                return Err(OperationError::EmptyRequest);
+            }
+        }
+
         Ok(SearchEvent {
             event,
             filter,
             filter_orig,
-            // We can't get this from the SearchMessage because it's annoying with the
-            // current macro design.
-            attrs: None,
+            attrs,
         })
     }
 
@@ -978,14 +1009,21 @@ impl AuthEventStep {
 pub struct AuthEvent {
     pub event: Option<Event>,
     pub step: AuthEventStep,
+    // The address the request came from, if known.
+    pub source: Option<IpAddr>,
     // pub sessionid: Option<Uuid>,
 }
 
 impl AuthEvent {
-    pub fn from_message(sessionid: Option<Uuid>, req: AuthRequest) -> Result<Self, OperationError> {
+    pub fn from_message(
+        sessionid: Option<Uuid>,
+        req: AuthRequest,
+        source: Option<IpAddr>,
+    ) -> Result<Self, OperationError> {
         Ok(AuthEvent {
             event: None,
             step: AuthEventStep::from_authstep(req.step, sessionid)?,
+            source,
         })
     }
 
@@ -994,6 +1032,7 @@ impl AuthEvent {
         AuthEvent {
             event: None,
             step: AuthEventStep::anonymous_init(),
+            source: None,
         }
     }
 
@@ -1002,6 +1041,7 @@ impl AuthEvent {
         AuthEvent {
             event: None,
             step: AuthEventStep::named_init(name),
+            source: None,
         }
     }
 
@@ -1010,6 +1050,7 @@ impl AuthEvent {
         AuthEvent {
             event: None,
             step: AuthEventStep::begin_mech(sessionid, mech),
+            source: None,
         }
     }
 
@@ -1018,6 +1059,7 @@ impl AuthEvent {
         AuthEvent {
             event: None,
             step: AuthEventStep::cred_step_anonymous(sid),
+            source: None,
         }
     }
 
@@ -1026,6 +1068,7 @@ impl AuthEvent {
         AuthEvent {
             event: None,
             step: AuthEventStep::cred_step_password(sid, pw),
+            source: None,
         }
     }
 }
@@ -1117,6 +1160,36 @@ impl PurgeRecycledEvent {
     }
 }
 
+#[derive(Debug)]
+pub struct OnlineBackupEvent {
+    pub dst_path: String,
+    pub eventid: Uuid,
+}
+
+impl OnlineBackupEvent {
+    pub fn new(dst_path: String) -> Self {
+        OnlineBackupEvent {
+            dst_path,
+            eventid: Uuid::new_v4(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DbMaintenanceEvent {
+    pub vacuum: bool,
+    pub eventid: Uuid,
+}
+
+impl DbMaintenanceEvent {
+    pub fn new(vacuum: bool) -> Self {
+        DbMaintenanceEvent {
+            vacuum,
+            eventid: Uuid::new_v4(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ReviveRecycledEvent {
     pub event: Event,
@@ -1,16 +1,17 @@
 use crate::entry::{Entry, EntryCommitted, EntryReduced, EntrySealed};
 use crate::prelude::*;
 
-use kanidm_proto::v1::CredentialStatus;
 use kanidm_proto::v1::OperationError;
 use kanidm_proto::v1::UserAuthToken;
+use kanidm_proto::v1::{AuthMech, CredentialStatus, WebauthnBackupState};
 
 use crate::constants::UUID_ANONYMOUS;
 use crate::credential::policy::CryptoPolicy;
 use crate::credential::totp::Totp;
-use crate::credential::{softlock::CredSoftLockPolicy, Credential};
+use crate::credential::{softlock::CredSoftLockPolicy, Credential, CredentialType};
 use crate::idm::claim::Claim;
 use crate::idm::group::Group;
+use crate::idm::missing_spn;
 use crate::modify::{ModifyInvalid, ModifyList};
 use crate::value::{PartialValue, Value};
 
@@ -52,15 +53,16 @@ macro_rules! try_from_entry {
             .get_ava_single_credential("primary_credential")
             .map(|v| v.clone());
 
-        let spn = $value
-            .get_ava_single("spn")
-            .map(|s| {
+        let spn = match $value.get_ava_single("spn") {
+            Some(s) => {
                 debug_assert!(s.is_spn());
                 s.to_proto_string_clone()
-            })
-            .ok_or(OperationError::InvalidAccountState(
-                "Missing attribute: spn".to_string(),
-            ))?;
+            }
+            // The anonymous account is configured to have no spn when
+            // anonymous_spn is "none", so it's known by its name alone.
+            None if *$value.get_uuid() == *UUID_ANONYMOUS => name.clone(),
+            None => return Err(missing_spn($value)),
+        };
 
         let valid_from = $value.get_ava_single_datetime("account_valid_from");
 
@@ -301,10 +303,11 @@ impl Account {
         &self,
         label: String,
         cred: WebauthnCredential,
+        backup: Option<WebauthnBackupState>,
     ) -> Result<ModifyList<ModifyInvalid>, OperationError> {
         let ncred = match &self.primary {
-            Some(primary) => primary.append_webauthn(label, cred)?,
-            None => Credential::new_webauthn_only(label, cred),
+            Some(primary) => primary.append_webauthn(label, cred, backup)?,
+            None => Credential::new_webauthn_only(label, cred, backup),
         };
         let vcred = Value::new_credential("primary", ncred);
         Ok(ModifyList::new_purge_and_set("primary_credential", vcred))
@@ -333,10 +336,11 @@ impl Account {
         &self,
         cid: &CredentialID,
         counter: Counter,
+        backup: Option<WebauthnBackupState>,
     ) -> Result<Option<ModifyList<ModifyInvalid>>, OperationError> {
         //
         let opt_ncred = match self.primary.as_ref() {
-            Some(primary) => primary.update_webauthn_counter(cid, counter)?,
+            Some(primary) => primary.update_webauthn_counter(cid, counter, backup)?,
             None => None,
         };
 
@@ -385,6 +389,24 @@ impl Account {
             })
             .ok_or(OperationError::NoMatchingAttributes)
     }
+
+    /// The mechanisms this account has credentials for, as an auth session for
+    /// it would offer them before any are disabled by the server.
+    pub(crate) fn enrolled_auth_mechs(&self) -> Vec<AuthMech> {
+        if self.is_anonymous() {
+            return vec![AuthMech::Anonymous];
+        }
+        self.primary
+            .iter()
+            .map(|cred| match &cred.type_ {
+                CredentialType::Password(_) | CredentialType::GeneratedPassword(_) => {
+                    AuthMech::Password
+                }
+                CredentialType::PasswordMfa(_, _, _) => AuthMech::PasswordMfa,
+                CredentialType::Webauthn(_) => AuthMech::Webauthn,
+            })
+            .collect()
+    }
 }
 
 // Need to also add a "to UserAuthToken" ...
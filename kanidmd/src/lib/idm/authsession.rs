@@ -12,7 +12,7 @@ use crate::idm::delayed::{DelayedAction, PasswordUpgrade, WebauthnCounterIncreme
 // use crossbeam::channel::Sender;
 use tokio::sync::mpsc::UnboundedSender as Sender;
 
-use crate::credential::webauthn::WebauthnDomainConfig;
+use crate::credential::webauthn::{backup_state_from_auth_data, WebauthnDomainConfig};
 use std::time::Duration;
 use uuid::Uuid;
 // use webauthn_rs::proto::Credential as WebauthnCredential;
@@ -238,13 +238,15 @@ impl CredHandler {
                                 .map(|(cid, auth_data)| {
                                     pw_mfa.mfa_state = CredVerifyState::Success;
                                     // Success. Determine if we need to update the counter
-                                    // async from r.
-                                    if auth_data.counter != 0 {
+                                    // or the backup state async from r.
+                                    let backup = backup_state_from_auth_data(&resp.response.authenticator_data.0);
+                                    if auth_data.counter != 0 || backup.is_some() {
                                         // Do async
                                         if let Err(_e) = async_tx.send(DelayedAction::WebauthnCounterIncrement(WebauthnCounterIncrement {
                                             target_uuid: who,
                                             cid,
                                             counter: auth_data.counter,
+                                            backup,
                                         })) {
                                             ladmin_warning!(au, "unable to queue delayed webauthn counter increment, continuing ... ");
                                         };
@@ -365,13 +367,15 @@ impl CredHandler {
                     .map(|(cid, auth_data)| {
                         wan_cred.state = CredVerifyState::Success;
                         // Success. Determine if we need to update the counter
-                        // async from r.
-                        if auth_data.counter != 0 {
+                        // or the backup state async from r.
+                        let backup = backup_state_from_auth_data(&resp.response.authenticator_data.0);
+                        if auth_data.counter != 0 || backup.is_some() {
                             // Do async
                             if let Err(_e) = async_tx.send(DelayedAction::WebauthnCounterIncrement(WebauthnCounterIncrement {
                                 target_uuid: who,
                                 cid,
                                 counter: auth_data.counter,
+                                backup,
                             })) {
                                 ladmin_warning!(au, "unable to queue delayed webauthn counter increment, continuing ... ");
                             };
@@ -1346,7 +1350,7 @@ mod tests {
         let (webauthn, mut wa, wan_cred) = setup_webauthn(account.name.as_str());
 
         // Now create the credential for the account.
-        let cred = Credential::new_webauthn_only("soft".to_string(), wan_cred);
+        let cred = Credential::new_webauthn_only("soft".to_string(), wan_cred, None);
         account.primary = Some(cred);
 
         // now check correct mech was offered.
@@ -1489,7 +1493,7 @@ mod tests {
         let p = CryptoPolicy::minimum();
         let cred = Credential::new_password_only(&p, pw_good)
             .unwrap()
-            .append_webauthn("soft".to_string(), wan_cred)
+            .append_webauthn("soft".to_string(), wan_cred, None)
             .unwrap();
 
         account.primary = Some(cred);
@@ -1674,7 +1678,7 @@ mod tests {
         let p = CryptoPolicy::minimum();
         let cred = Credential::new_password_only(&p, pw_good)
             .unwrap()
-            .append_webauthn("soft".to_string(), wan_cred)
+            .append_webauthn("soft".to_string(), wan_cred, None)
             .unwrap()
             .update_totp(totp);
 
@@ -1,3 +1,4 @@
+use kanidm_proto::v1::WebauthnBackupState;
 use uuid::Uuid;
 use webauthn_rs::proto::{Counter, CredentialID};
 
@@ -22,4 +23,5 @@ pub(crate) struct WebauthnCounterIncrement {
     pub target_uuid: Uuid,
     pub counter: Counter,
     pub cid: CredentialID,
+    pub backup: Option<WebauthnBackupState>,
 }
@@ -1,10 +1,10 @@
 use crate::audit::AuditScope;
 use crate::credential::totp::{Totp, TOTP_DEFAULT_STEP};
-use crate::credential::webauthn::WebauthnDomainConfig;
+use crate::credential::webauthn::{backup_state_from_attestation, WebauthnDomainConfig};
 use crate::event::EventOriginId;
 use crate::idm::account::Account;
-use kanidm_proto::v1::TotpSecret;
 use kanidm_proto::v1::{OperationError, SetCredentialResponse};
+use kanidm_proto::v1::{TotpSecret, WebauthnBackupState};
 use std::mem;
 use std::time::Duration;
 use uuid::Uuid;
@@ -16,7 +16,7 @@ use webauthn_rs::{proto::UserVerificationPolicy, Webauthn};
 
 pub(crate) enum MfaRegCred {
     Totp(Totp),
-    Webauthn(String, WebauthnCredential),
+    Webauthn(String, WebauthnCredential, Option<WebauthnBackupState>),
 }
 
 pub(crate) enum MfaRegNext {
@@ -166,7 +166,13 @@ impl MfaRegSession {
                     ladmin_error!(au, "Unable to register webauthn credential -> {:?}", e);
                     OperationError::Webauthn
                 })
-                .map(|cred| (MfaRegNext::Success, Some(MfaRegCred::Webauthn(label, cred)))),
+                .map(|cred| {
+                    let backup = backup_state_from_attestation(&chal.response.attestation_object.0);
+                    (
+                        MfaRegNext::Success,
+                        Some(MfaRegCred::Webauthn(label, cred, backup)),
+                    )
+                }),
             _ => Err(OperationError::InvalidRequestState),
         }
     }
@@ -10,7 +10,25 @@ pub mod server;
 pub(crate) mod unix;
 // mod identity;
 
-use kanidm_proto::v1::{AuthAllowed, AuthMech, UserAuthToken};
+use crate::entry::Entry;
+use crate::value::PartialValue;
+use kanidm_proto::v1::{AuthAllowed, AuthMech, OperationError, UserAuthToken};
+
+lazy_static! {
+    static ref PVCLASS_SPN_EXEMPT: PartialValue = PartialValue::new_class("spn_exempt");
+}
+
+// Accounts and groups outside of the spn_scope have no spn. They can't be used
+// where an spn is required, so say why rather than reporting a corrupt entry.
+pub(crate) fn missing_spn<VALID, STATE>(e: &Entry<VALID, STATE>) -> OperationError {
+    if e.attribute_value_pres("class", &PVCLASS_SPN_EXEMPT) {
+        OperationError::InvalidAccountState(
+            "No spn: the entry is outside of the spn_scope".to_string(),
+        )
+    } else {
+        OperationError::InvalidAccountState("Missing attribute: spn".to_string())
+    }
+}
 
 #[derive(Debug)]
 pub enum AuthState {
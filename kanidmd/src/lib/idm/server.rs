@@ -19,16 +19,25 @@ use crate::prelude::*;
 use crate::utils::{password_from_random, readable_password_from_random, uuid_from_duration, Sid};
 
 use crate::actors::v1_write::QueryServerWriteV1;
+use crate::auth_events::AuthEventLog;
+use crate::auth_trace::AuthTraceLog;
+use crate::be::DbMaintenanceStats;
 use crate::idm::delayed::{
     DelayedAction, PasswordUpgrade, UnixPasswordUpgrade, WebauthnCounterIncrement,
 };
+use crate::lockout_notify::{LockoutNotification, LockoutNotifier};
 
+use crate::config::ALL_AUTH_MECHS;
 use hashbrown::HashSet;
 use kanidm_proto::v1::CredentialStatus;
 use kanidm_proto::v1::RadiusAuthToken;
 use kanidm_proto::v1::SetCredentialResponse;
 use kanidm_proto::v1::UnixGroupToken;
 use kanidm_proto::v1::UnixUserToken;
+use kanidm_proto::v1::{
+    AuthCapabilities, AuthCredential, AuthEventQuery, AuthEventRecord, AuthMech, AuthPolicy,
+    AuthTrace,
+};
 
 use tokio::sync::mpsc::{
     unbounded_channel as unbounded, UnboundedReceiver as Receiver, UnboundedSender as Sender,
@@ -49,6 +58,7 @@ use concread::{
     hashmap::HashMap,
 };
 use rand::prelude::*;
+use serde::Serialize;
 use std::{sync::Arc, time::Duration};
 use url::Url;
 
@@ -75,8 +85,24 @@ pub struct IdmServer {
     // Our webauthn verifier/config
     webauthn: Webauthn<WebauthnDomainConfig>,
     pw_badlist_cache: Arc<CowCell<HashSet<String>>>,
+    // Mechanisms that are never offered or accepted.
+    disabled_auth_mechs: Vec<AuthMech>,
+    // Recent authentication outcomes, for export.
+    auth_events: AuthEventLog,
+    // The authentications being traced for debugging.
+    auth_traces: AuthTraceLog,
+    lockout_notifier: Option<Arc<LockoutNotifier>>,
+    // The largest passwords and other credentials that are accepted, in bytes.
+    max_password_size: usize,
+    max_credential_size: usize,
+    // When false, unauthenticated clients aren't told why they were denied.
+    detailed_auth_errors: bool,
 }
 
+const AUTH_MECH_DISABLED_MSG: &str = "authentication mechanism is disabled";
+const NO_AUTH_MECHS_MSG: &str = "no enabled authentication mechanisms";
+const GENERIC_AUTH_DENIED_MSG: &str = "authentication denied";
+
 pub struct IdmServerAuthTransaction<'a> {
     // Contains methods that require writes, but in the context of writing to
     // the idm in memory structures (maybe the query server too). This is
@@ -93,12 +119,20 @@ pub struct IdmServerAuthTransaction<'a> {
     async_tx: Sender<DelayedAction>,
     webauthn: &'a Webauthn<WebauthnDomainConfig>,
     pw_badlist_cache: CowCellReadTxn<HashSet<String>>,
+    disabled_auth_mechs: &'a [AuthMech],
+    auth_events: &'a AuthEventLog,
+    auth_traces: &'a AuthTraceLog,
+    lockout_notifier: Option<&'a LockoutNotifier>,
+    max_password_size: usize,
+    max_credential_size: usize,
+    detailed_auth_errors: bool,
 }
 
 pub struct IdmServerProxyReadTransaction<'a> {
     // This contains read-only methods, like getting users, groups
     // and other structured content.
     pub qs_read: QueryServerReadTransaction<'a>,
+    disabled_auth_mechs: &'a [AuthMech],
 }
 
 pub struct IdmServerProxyWriteTransaction<'a> {
@@ -111,12 +145,56 @@ pub struct IdmServerProxyWriteTransaction<'a> {
     crypto_policy: &'a CryptoPolicy,
     webauthn: &'a Webauthn<WebauthnDomainConfig>,
     pw_badlist_cache: CowCellWriteTxn<'a, HashSet<String>>,
+    max_password_size: usize,
+    max_credential_size: usize,
 }
 
 pub struct IdmServerDelayed {
     async_rx: Receiver<DelayedAction>,
 }
 
+// Oversized passwords and credentials are refused before they are hashed or
+// verified, so they can't be used to exhaust the server.
+fn check_password_size(
+    au: &mut AuditScope,
+    cleartext: &str,
+    max: usize,
+) -> Result<(), OperationError> {
+    if cleartext.len() > max {
+        lsecurity!(
+            au,
+            "password of {} bytes exceeds the maximum of {}",
+            cleartext.len(),
+            max
+        );
+        Err(OperationError::PasswordTooLong(max))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_credential_size<T: Serialize>(
+    au: &mut AuditScope,
+    cred: &T,
+    max: usize,
+) -> Result<(), OperationError> {
+    let size = serde_json::to_vec(cred).map(|v| v.len()).map_err(|e| {
+        ladmin_error!(au, "Unable to measure credential -> {:?}", e);
+        OperationError::SerdeJsonError
+    })?;
+    if size > max {
+        lsecurity!(
+            au,
+            "credential of {} bytes exceeds the maximum of {}",
+            size,
+            max
+        );
+        Err(OperationError::CredentialTooLarge(max))
+    } else {
+        Ok(())
+    }
+}
+
 impl IdmServer {
     // TODO: Make number of authsessions configurable!!!
     pub fn new(
@@ -184,11 +262,83 @@ impl IdmServer {
                 async_tx,
                 webauthn,
                 pw_badlist_cache: Arc::new(CowCell::new(pw_badlist_set)),
+                disabled_auth_mechs: Vec::new(),
+                auth_events: AuthEventLog::new(Duration::from_secs(0)),
+                auth_traces: AuthTraceLog::new(Duration::from_secs(0)),
+                lockout_notifier: None,
+                max_password_size: DEFAULT_MAX_PASSWORD_SIZE,
+                max_credential_size: DEFAULT_MAX_CREDENTIAL_SIZE,
+                detailed_auth_errors: true,
             },
             IdmServerDelayed { async_rx },
         ))
     }
 
+    pub fn set_disabled_auth_mechs(&mut self, mechs: Vec<AuthMech>) {
+        self.disabled_auth_mechs = mechs;
+    }
+
+    /// The largest password, and the largest other credential (such as a
+    /// webauthn response), in bytes, that will be processed.
+    pub fn set_credential_size_limits(&mut self, max_password: usize, max_credential: usize) {
+        self.max_password_size = max_password;
+        self.max_credential_size = max_credential;
+    }
+
+    /// Keep the outcome of each authentication for this long, so they can be
+    /// exported. Zero disables this.
+    pub fn set_auth_event_retention(&mut self, retention: Duration) {
+        self.auth_events = AuthEventLog::new(retention);
+    }
+
+    pub(crate) fn auth_events(&self, q: &AuthEventQuery) -> Vec<AuthEventRecord> {
+        self.auth_events.query(q)
+    }
+
+    /// The longest that the authentications of an account may be traced for.
+    /// Zero disables tracing.
+    pub fn set_auth_trace_max_duration(&mut self, max_duration: Duration) {
+        self.auth_traces = AuthTraceLog::new(max_duration);
+    }
+
+    pub(crate) fn start_auth_trace(
+        &self,
+        account: &str,
+        duration: Duration,
+        ct: Duration,
+    ) -> Result<(), OperationError> {
+        self.auth_traces.start(account, duration, ct)
+    }
+
+    pub(crate) fn auth_trace(&self, account: &str) -> Option<AuthTrace> {
+        self.auth_traces.get(account)
+    }
+
+    /// When false, unauthenticated clients are only told that authentication was
+    /// denied, and the reason is only logged.
+    pub fn set_detailed_auth_errors(&mut self, detailed: bool) {
+        self.detailed_auth_errors = detailed;
+    }
+
+    pub(crate) fn set_lockout_notifier(&mut self, notifier: Option<Arc<LockoutNotifier>>) {
+        self.lockout_notifier = notifier;
+    }
+
+    pub(crate) fn get_lockout_notifier(&self) -> Option<Arc<LockoutNotifier>> {
+        self.lockout_notifier.clone()
+    }
+
+    /// The authentication mechanisms that this server offers to accounts.
+    pub fn auth_capabilities(&self) -> AuthCapabilities {
+        AuthCapabilities {
+            mechs: ALL_AUTH_MECHS
+                .iter()
+                .filter(|m| !self.disabled_auth_mechs.contains(m))
+                .cloned()
+                .collect(),
+        }
+    }
+
     #[cfg(test)]
     pub fn auth(&self) -> IdmServerAuthTransaction {
         task::block_on(self.auth_async())
@@ -214,6 +364,13 @@ impl IdmServer {
             async_tx: self.async_tx.clone(),
             webauthn: &self.webauthn,
             pw_badlist_cache: self.pw_badlist_cache.read(),
+            disabled_auth_mechs: self.disabled_auth_mechs.as_slice(),
+            auth_events: &self.auth_events,
+            auth_traces: &self.auth_traces,
+            lockout_notifier: self.lockout_notifier.as_deref(),
+            max_password_size: self.max_password_size,
+            max_credential_size: self.max_credential_size,
+            detailed_auth_errors: self.detailed_auth_errors,
         }
     }
 
@@ -222,9 +379,29 @@ impl IdmServer {
         task::block_on(self.proxy_read_async())
     }
 
+    pub(crate) async fn db_maintenance_async(
+        &self,
+        audit: &mut AuditScope,
+        vacuum: bool,
+    ) -> Result<DbMaintenanceStats, OperationError> {
+        self.qs.db_maintenance_async(audit, vacuum).await
+    }
+
+    pub(crate) async fn spn_regenerate_chunked_async(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<usize, OperationError> {
+        self.qs.spn_regenerate_chunked(audit).await
+    }
+
+    pub(crate) fn spn_regen_pending(&self) -> bool {
+        self.qs.spn_regen_pending()
+    }
+
     pub async fn proxy_read_async(&self) -> IdmServerProxyReadTransaction<'_> {
         IdmServerProxyReadTransaction {
             qs_read: self.qs.read_async().await,
+            disabled_auth_mechs: self.disabled_auth_mechs.as_slice(),
         }
     }
 
@@ -246,6 +423,8 @@ impl IdmServer {
             crypto_policy: &self.crypto_policy,
             webauthn: &self.webauthn,
             pw_badlist_cache: self.pw_badlist_cache.write(),
+            max_password_size: self.max_password_size,
+            max_credential_size: self.max_credential_size,
         }
     }
 
@@ -320,6 +499,45 @@ impl<'a> IdmServerAuthTransaction<'a> {
         au: &mut AuditScope,
         ae: &AuthEvent,
         ct: Duration,
+    ) -> Result<AuthResult, OperationError> {
+        let r = self.auth_inner(au, ae, ct).await;
+        self.auth_traces.record(&ae.step, &r, ct);
+        if self.detailed_auth_errors {
+            return r;
+        }
+        // The reason is still in the audit log and the auth event log, but the
+        // client is only told that it was denied, so that it can't learn whether
+        // an account exists, or which part of a credential was wrong.
+        match r {
+            Ok(AuthResult {
+                sessionid,
+                state: AuthState::Denied(reason),
+                delay,
+            }) => {
+                lsecurity!(au, "Authentication denied -> {}", reason);
+                Ok(AuthResult {
+                    sessionid,
+                    state: AuthState::Denied(GENERIC_AUTH_DENIED_MSG.to_string()),
+                    delay,
+                })
+            }
+            Err(OperationError::NoMatchingEntries) if matches!(ae.step, AuthEventStep::Init(_)) => {
+                lsecurity!(au, "Authentication denied -> no such account");
+                Ok(AuthResult {
+                    sessionid: uuid_from_duration(ct, self.sid),
+                    state: AuthState::Denied(GENERIC_AUTH_DENIED_MSG.to_string()),
+                    delay: None,
+                })
+            }
+            r => r,
+        }
+    }
+
+    async fn auth_inner(
+        &mut self,
+        au: &mut AuditScope,
+        ae: &AuthEvent,
+        ct: Duration,
     ) -> Result<AuthResult, OperationError> {
         ltrace!(au, "Received -> {:?}", ae);
         // Match on the auth event, to see what we need to do.
@@ -345,7 +563,18 @@ impl<'a> IdmServerAuthTransaction<'a> {
                 //
                 // Check anything needed? Get the current auth-session-id from request
                 // because it associates to the nonce's etc which were all cached.
-                let euuid = self.qs_read.name_to_uuid(au, init.name.as_str())?;
+                let euuid = self
+                    .qs_read
+                    .name_to_uuid(au, init.name.as_str())
+                    .map_err(|e| {
+                        self.auth_events.record(
+                            init.name.as_str(),
+                            sessionid,
+                            &AuthState::Denied("no such account".to_string()),
+                            ct,
+                        );
+                        e
+                    })?;
 
                 // Get the first / single entry we expect here ....
                 let entry = self.qs_read.internal_search_uuid(au, &euuid)?;
@@ -362,6 +591,7 @@ impl<'a> IdmServerAuthTransaction<'a> {
                 // continue, and helps to keep non-needed entry specific data
                 // out of the session tree.
                 let account = Account::try_from_entry_ro(au, &entry, &mut self.qs_read)?;
+                let account_name = account.name.clone();
 
                 // Check the credential that the auth_session will attempt to
                 // use.
@@ -395,6 +625,23 @@ impl<'a> IdmServerAuthTransaction<'a> {
                     )
                 };
 
+                // Never offer the mechanisms that are disabled.
+                let (auth_session, state) = match state {
+                    AuthState::Choose(mechs) => {
+                        let mechs: Vec<_> = mechs
+                            .into_iter()
+                            .filter(|m| !self.disabled_auth_mechs.contains(m))
+                            .collect();
+                        if mechs.is_empty() {
+                            lsecurity!(au, "All mechanisms for this account are disabled.");
+                            (None, AuthState::Denied(NO_AUTH_MECHS_MSG.to_string()))
+                        } else {
+                            (auth_session, AuthState::Choose(mechs))
+                        }
+                    }
+                    state => (auth_session, state),
+                };
+
                 match auth_session {
                     Some(auth_session) => {
                         // Now acquire the session tree for writing.
@@ -417,6 +664,9 @@ impl<'a> IdmServerAuthTransaction<'a> {
                     }
                 };
 
+                self.auth_events
+                    .record(account_name.as_str(), sessionid, &state, ct);
+
                 // TODO: Change this william!
                 // For now ...
                 let delay = None;
@@ -450,6 +700,7 @@ impl<'a> IdmServerAuthTransaction<'a> {
                 let mut softlock_write = self.softlocks.write();
 
                 let cred_uuid = auth_session.get_account().primary_cred_uuid();
+                let account_name = auth_session.get_account().name.clone();
 
                 let is_valid = softlock_write
                     .get_mut(&cred_uuid)
@@ -461,7 +712,10 @@ impl<'a> IdmServerAuthTransaction<'a> {
                     })
                     .unwrap_or(true);
 
-                let r = if is_valid {
+                let r = if self.disabled_auth_mechs.contains(&mech.mech) {
+                    lsecurity!(au, "Requested mechanism {:?} is disabled.", mech.mech);
+                    auth_session.end_session(AUTH_MECH_DISABLED_MSG)
+                } else if is_valid {
                     // Indicate to the session which auth mech we now want to proceed with.
                     auth_session.start_session(au, &mech.mech)
                 } else {
@@ -478,10 +732,23 @@ impl<'a> IdmServerAuthTransaction<'a> {
                 });
                 softlock_write.commit();
                 session_write.commit();
+                if let Ok(ar) = &r {
+                    self.auth_events
+                        .record(account_name.as_str(), ar.sessionid, &ar.state, ct);
+                }
                 r
             } // End AuthEventStep::Mech
             AuthEventStep::Cred(creds) => {
                 // lperf_segment!(au, "idm::server::auth<Creds>", || {
+                match &creds.cred {
+                    AuthCredential::Password(cleartext) => {
+                        check_password_size(au, cleartext.as_str(), self.max_password_size)?
+                    }
+                    AuthCredential::Webauthn(resp) => {
+                        check_credential_size(au, resp, self.max_credential_size)?
+                    }
+                    AuthCredential::Anonymous | AuthCredential::Totp(_) => {}
+                }
                 let _session_ticket = self.session_ticket.acquire().await;
                 let _softlock_ticket = self.softlock_ticket.acquire().await;
 
@@ -500,6 +767,7 @@ impl<'a> IdmServerAuthTransaction<'a> {
                 let mut softlock_write = self.softlocks.write();
 
                 let cred_uuid = auth_session.get_account().primary_cred_uuid();
+                let account_name = auth_session.get_account().name.clone();
 
                 let is_valid = softlock_write
                     .get_mut(&cred_uuid)
@@ -529,9 +797,12 @@ impl<'a> IdmServerAuthTransaction<'a> {
                             // Inspect the result:
                             // if it was a failure, we need to inc the softlock.
                             if let AuthState::Denied(_) = &aus {
-                                if let Some(slock) = softlock_write.get_mut(&cred_uuid) {
+                                let locked_out = if let Some(slock) =
+                                    softlock_write.get_mut(&cred_uuid)
+                                {
                                     // Update it.
                                     slock.record_failure(ct);
+                                    slock.locked_out()
                                 } else {
                                     // Create if not exist, and the cred type supports softlocking.
                                     if let Some(policy) =
@@ -539,7 +810,28 @@ impl<'a> IdmServerAuthTransaction<'a> {
                                     {
                                         let mut slock = CredSoftLock::new(policy);
                                         slock.record_failure(ct);
+                                        let locked_out = slock.locked_out();
                                         softlock_write.insert(cred_uuid, slock);
+                                        locked_out
+                                    } else {
+                                        None
+                                    }
+                                };
+                                // A locked credential isn't checked again until the lockout
+                                // ends, so this is only reached once per lockout.
+                                if let Some(attempts) = locked_out {
+                                    lsecurity!(
+                                        au,
+                                        "Account {} is locked out after {} failed attempts",
+                                        account_name,
+                                        attempts
+                                    );
+                                    if let Some(notifier) = self.lockout_notifier {
+                                        notifier.enqueue(LockoutNotification {
+                                            account: account_name.clone(),
+                                            source: ae.source,
+                                            attempts,
+                                        });
                                     }
                                 }
                             };
@@ -562,6 +854,10 @@ impl<'a> IdmServerAuthTransaction<'a> {
                 });
                 softlock_write.commit();
                 session_write.commit();
+                if let Ok(ar) = &r {
+                    self.auth_events
+                        .record(account_name.as_str(), ar.sessionid, &ar.state, ct);
+                }
                 r
             } // End AuthEventStep::Cred
         }
@@ -573,6 +869,7 @@ impl<'a> IdmServerAuthTransaction<'a> {
         uae: &UnixUserAuthEvent,
         ct: Duration,
     ) -> Result<Option<UnixUserToken>, OperationError> {
+        check_password_size(au, uae.cleartext.as_str(), self.max_password_size)?;
         // Get the entry/target we are working on.
         let account = self
             .qs_read
@@ -648,6 +945,7 @@ impl<'a> IdmServerAuthTransaction<'a> {
         lae: &LdapAuthEvent,
         ct: Duration,
     ) -> Result<Option<LdapBoundToken>, OperationError> {
+        check_password_size(au, lae.cleartext.as_str(), self.max_password_size)?;
         let account_entry = self
             .qs_read
             .internal_search_uuid(au, &lae.target)
@@ -849,6 +1147,40 @@ impl<'a> IdmServerProxyReadTransaction<'a> {
 
         account.to_credentialstatus()
     }
+
+    pub fn get_auth_policy(
+        &mut self,
+        au: &mut AuditScope,
+        cse: &CredentialStatusEvent,
+    ) -> Result<AuthPolicy, OperationError> {
+        let account = self
+            .qs_read
+            .impersonate_search_ext_uuid(au, &cse.target, &cse.event)
+            .and_then(|account_entry| {
+                Account::try_from_entry_reduced(au, &account_entry, &mut self.qs_read)
+            })
+            .map_err(|e| {
+                ladmin_error!(au, "Failed to search account {:?}", e);
+                e
+            })?;
+
+        let enrolled = account.enrolled_auth_mechs();
+        let (disabled, usable) = enrolled
+            .iter()
+            .cloned()
+            .partition(|m| self.disabled_auth_mechs.contains(m));
+        let credentials = match account.to_credentialstatus() {
+            Ok(status) => Some(status),
+            Err(OperationError::NoMatchingAttributes) => None,
+            Err(e) => return Err(e),
+        };
+        Ok(AuthPolicy {
+            enrolled,
+            disabled,
+            usable,
+            credentials,
+        })
+    }
 }
 
 impl<'a> IdmServerProxyWriteTransaction<'a> {
@@ -946,6 +1278,7 @@ impl<'a> IdmServerProxyWriteTransaction<'a> {
         au: &mut AuditScope,
         pce: &PasswordChangeEvent,
     ) -> Result<(), OperationError> {
+        check_password_size(au, pce.cleartext.as_str(), self.max_password_size)?;
         let account = self.target_to_account(au, &pce.target)?;
 
         // Deny the change if the account is anonymous!
@@ -1026,6 +1359,7 @@ impl<'a> IdmServerProxyWriteTransaction<'a> {
         au: &mut AuditScope,
         pce: &UnixPasswordChangeEvent,
     ) -> Result<(), OperationError> {
+        check_password_size(au, pce.cleartext.as_str(), self.max_password_size)?;
         // Get the account
         let account = self
             .qs_write
@@ -1116,6 +1450,7 @@ impl<'a> IdmServerProxyWriteTransaction<'a> {
         name: &str,
         cleartext: &str,
     ) -> Result<(), OperationError> {
+        check_password_size(au, cleartext, self.max_password_size)?;
         // name to uuid
         let target = self.qs_write.name_to_uuid(au, name).map_err(|e| {
             ladmin_error!(au, "name to uuid failed {:?}", e);
@@ -1258,6 +1593,7 @@ impl<'a> IdmServerProxyWriteTransaction<'a> {
         au: &mut AuditScope,
         wre: &WebauthnDoRegisterEvent,
     ) -> Result<SetCredentialResponse, OperationError> {
+        check_credential_size(au, &wre.chal, self.max_credential_size)?;
         let sessionid = wre.session;
         let origin = (&wre.event.origin).into();
         let webauthn = self.webauthn;
@@ -1280,12 +1616,17 @@ impl<'a> IdmServerProxyWriteTransaction<'a> {
                 OperationError::Webauthn
             })?;
 
-        if let (MfaRegNext::Success, Some(MfaRegCred::Webauthn(label, cred))) = (&next, wan_cred) {
+        if let (MfaRegNext::Success, Some(MfaRegCred::Webauthn(label, cred, backup))) =
+            (&next, wan_cred)
+        {
             // Persist the credential
-            let modlist = session.account.gen_webauthn_mod(label, cred).map_err(|e| {
-                ladmin_error!(au, "Failed to gen webauthn mod {:?}", e);
-                e
-            })?;
+            let modlist = session
+                .account
+                .gen_webauthn_mod(label, cred, backup)
+                .map_err(|e| {
+                    ladmin_error!(au, "Failed to gen webauthn mod {:?}", e);
+                    e
+                })?;
             // Perform the mod
             self.qs_write
                 .impersonate_modify(
@@ -1537,7 +1878,7 @@ impl<'a> IdmServerProxyWriteTransaction<'a> {
 
         // Generate an optional mod and then attempt to apply it.
         let opt_modlist = account
-            .gen_webauthn_counter_mod(&wci.cid, wci.counter)
+            .gen_webauthn_counter_mod(&wci.cid, wci.counter, wci.backup)
             .map_err(|e| {
                 ladmin_error!(au, "Unable to generate webauthn counter mod {:?}", e);
                 e
@@ -1604,6 +1945,8 @@ impl<'a> IdmServerProxyWriteTransaction<'a> {
 
 #[cfg(test)]
 mod tests {
+    use crate::auth_events::AuthEventLog;
+    use crate::auth_trace::AuthTraceLog;
     use crate::credential::policy::CryptoPolicy;
     use crate::credential::totp::Totp;
     use crate::credential::{Credential, Password};
@@ -1615,12 +1958,14 @@ mod tests {
         UnixUserAuthEvent, UnixUserTokenEvent, VerifyTotpEvent, WebauthnDoRegisterEvent,
         WebauthnInitRegisterEvent,
     };
+    use crate::idm::server::GENERIC_AUTH_DENIED_MSG;
     use crate::idm::AuthState;
+    use crate::lockout_notify::{LockoutNotification, LockoutNotifier};
     use crate::modify::{Modify, ModifyList};
     use crate::prelude::*;
     use kanidm_proto::v1::OperationError;
     use kanidm_proto::v1::SetCredentialResponse;
-    use kanidm_proto::v1::{AuthAllowed, AuthMech};
+    use kanidm_proto::v1::{AuthAllowed, AuthEventQuery, AuthMech, WebauthnBackupState};
 
     use crate::idm::server::IdmServer;
     // , IdmServerDelayed;
@@ -1628,6 +1973,7 @@ mod tests {
     use async_std::task;
     use smartstring::alias::String as AttrString;
     use std::convert::TryFrom;
+    use std::net::IpAddr;
     use std::time::Duration;
     use uuid::Uuid;
     use webauthn_authenticator_rs::{softtok::U2FSoft, WebauthnAuthenticator};
@@ -2057,6 +2403,45 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_idm_password_too_long() {
+        run_idm_test!(|qs: &QueryServer,
+                       idms: &IdmServer,
+                       _idms_delayed: &IdmServerDelayed,
+                       au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let oversize = "a".repeat(DEFAULT_MAX_PASSWORD_SIZE + 1);
+
+            // An oversize password is refused before it's verified.
+            let sid = init_admin_authsession_sid(
+                idms,
+                au,
+                Duration::from_secs(TEST_CURRENT_TIME),
+                "admin",
+            );
+            let mut idms_auth = idms.auth();
+            let step = AuthEvent::cred_step_password(sid, oversize.as_str());
+            let r =
+                task::block_on(idms_auth.auth(au, &step, Duration::from_secs(TEST_CURRENT_TIME)));
+            assert!(
+                matches!(r, Err(OperationError::PasswordTooLong(max)) if max == DEFAULT_MAX_PASSWORD_SIZE)
+            );
+            idms_auth.commit(au).expect("Must not fail");
+
+            // Or set.
+            let pce = PasswordChangeEvent::new_internal(&UUID_ADMIN, oversize.as_str(), None);
+            let mut idms_prox_write = idms.proxy_write(duration_from_epoch_now());
+            assert!(matches!(
+                idms_prox_write.set_account_password(au, &pce),
+                Err(OperationError::PasswordTooLong(_))
+            ));
+            assert!(idms_prox_write.commit(au).is_ok());
+
+            // The existing password is unchanged.
+            check_admin_password(idms, au, TEST_PASSWORD);
+        })
+    }
+
     #[test]
     fn test_idm_simple_password_reset() {
         run_idm_test!(|_qs: &QueryServer,
@@ -3084,6 +3469,196 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_idm_account_lockout_notify() {
+        run_idm_test!(|qs: &QueryServer,
+                       idms: &IdmServer,
+                       _idms_delayed: &mut IdmServerDelayed,
+                       au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let notifier = LockoutNotifier::new("true");
+            let source: IpAddr = "192.0.2.1".parse().expect("Invalid ip");
+
+            // Each attempt is after the delay from the last failure, so it is checked.
+            // Only the failure that locks the account out is notified.
+            for i in 0..100 {
+                assert!(notifier.pending().is_empty());
+                let ct = Duration::from_secs(TEST_CURRENT_TIME + i * 11);
+                let sid = init_admin_authsession_sid(idms, au, ct, "admin");
+                let mut idms_auth = idms.auth();
+                idms_auth.lockout_notifier = Some(&notifier);
+                let mut cred_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD_INC);
+                cred_step.source = Some(source);
+                let r = task::block_on(idms_auth.auth(au, &cred_step, ct));
+                assert!(matches!(
+                    r,
+                    Ok(AuthResult {
+                        state: AuthState::Denied(_),
+                        ..
+                    })
+                ));
+                idms_auth.commit(au).expect("Must not fail");
+            }
+
+            assert!(
+                notifier.pending()
+                    == vec![LockoutNotification {
+                        account: "admin".to_string(),
+                        source: Some(source),
+                        attempts: 100,
+                    }]
+            );
+
+            // While locked out the credential isn't checked, so there is no
+            // further notification.
+            let mut idms_auth = idms.auth();
+            idms_auth.lockout_notifier = Some(&notifier);
+            let r = task::block_on(idms_auth.auth(
+                au,
+                &AuthEvent::named_init("admin"),
+                Duration::from_secs(TEST_CURRENT_TIME + 1100),
+            ));
+            assert!(matches!(
+                r,
+                Ok(AuthResult {
+                    state: AuthState::Denied(_),
+                    ..
+                })
+            ));
+            idms_auth.commit(au).expect("Must not fail");
+            assert!(notifier.pending().len() == 1);
+        })
+    }
+
+    #[test]
+    fn test_idm_generic_auth_errors() {
+        run_idm_test!(|qs: &QueryServer,
+                       idms: &IdmServer,
+                       _idms_delayed: &mut IdmServerDelayed,
+                       au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let auth_events = AuthEventLog::new(Duration::from_secs(3600));
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let is_generic = |r: &Result<AuthResult, OperationError>| match r {
+                Ok(AuthResult {
+                    state: AuthState::Denied(reason),
+                    ..
+                }) => reason == GENERIC_AUTH_DENIED_MSG,
+                _ => false,
+            };
+
+            // A wrong password.
+            let sid = init_admin_authsession_sid(idms, au, ct, "admin");
+            let mut idms_auth = idms.auth();
+            idms_auth.detailed_auth_errors = false;
+            idms_auth.auth_events = &auth_events;
+            let cred_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD_INC);
+            let r = task::block_on(idms_auth.auth(au, &cred_step, ct));
+            assert!(is_generic(&r));
+
+            // An account that doesn't exist is denied the same way, rather than
+            // being an error.
+            let r = task::block_on(idms_auth.auth(au, &AuthEvent::named_init("nosuchaccount"), ct));
+            assert!(is_generic(&r));
+            idms_auth.commit(au).expect("Must not fail");
+
+            // The reasons are still recorded on the server.
+            let reasons: Vec<_> = auth_events
+                .query(&AuthEventQuery {
+                    since: 0,
+                    account: None,
+                    result: None,
+                })
+                .into_iter()
+                .map(|e| (e.account, e.reason))
+                .collect();
+            assert!(
+                reasons
+                    == vec![
+                        ("admin".to_string(), Some("incorrect password".to_string())),
+                        (
+                            "nosuchaccount".to_string(),
+                            Some("no such account".to_string())
+                        ),
+                    ]
+            );
+
+            // With detailed errors, the client is told the reason.
+            let ct = ct + Duration::from_secs(60);
+            let sid = init_admin_authsession_sid(idms, au, ct, "admin");
+            let mut idms_auth = idms.auth();
+            let cred_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD_INC);
+            let r = task::block_on(idms_auth.auth(au, &cred_step, ct));
+            assert!(matches!(
+                r,
+                Ok(AuthResult {
+                    state: AuthState::Denied(ref reason),
+                    ..
+                }) if reason == "incorrect password"
+            ));
+            let r = task::block_on(idms_auth.auth(au, &AuthEvent::named_init("nosuchaccount"), ct));
+            assert!(matches!(r, Err(OperationError::NoMatchingEntries)));
+            idms_auth.commit(au).expect("Must not fail");
+        })
+    }
+
+    #[test]
+    fn test_idm_auth_trace() {
+        run_idm_test!(|qs: &QueryServer,
+                       idms: &IdmServer,
+                       _idms_delayed: &mut IdmServerDelayed,
+                       au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let auth_traces = AuthTraceLog::new(Duration::from_secs(3600));
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            auth_traces
+                .start("admin", Duration::from_secs(600), ct)
+                .expect("Failed to start trace");
+
+            let mut idms_auth = idms.auth();
+            idms_auth.auth_traces = &auth_traces;
+            let mut authenticate = |pw: &str| {
+                let sid = task::block_on(idms_auth.auth(au, &AuthEvent::named_init("admin"), ct))
+                    .expect("Failed to init")
+                    .sessionid;
+                let begin = AuthEvent::begin_mech(sid, AuthMech::Password);
+                task::block_on(idms_auth.auth(au, &begin, ct)).expect("Failed to begin");
+                let cred_step = AuthEvent::cred_step_password(sid, pw);
+                task::block_on(idms_auth.auth(au, &cred_step, ct)).expect("Failed to auth");
+                sid
+            };
+            let failed = authenticate(TEST_PASSWORD_INC);
+            let succeeded = authenticate(TEST_PASSWORD);
+            idms_auth.commit(au).expect("Must not fail");
+
+            let trace = auth_traces.get("admin").expect("Admin must be traced");
+            let steps: Vec<_> = trace
+                .steps
+                .iter()
+                .map(|s| (s.sessionid, s.request.as_str(), s.response.as_str()))
+                .collect();
+            assert!(
+                steps
+                    == vec![
+                        (Some(failed), "init", "choose [Password]"),
+                        (Some(failed), "begin Password", "continue [password]"),
+                        (
+                            Some(failed),
+                            "cred password (redacted)",
+                            "denied: incorrect password"
+                        ),
+                        (Some(succeeded), "init", "choose [Password]"),
+                        (Some(succeeded), "begin Password", "continue [password]"),
+                        (Some(succeeded), "cred password (redacted)", "success"),
+                    ]
+            );
+            // No credential is stored in the trace.
+            let json = serde_json::to_string(&trace).expect("Failed to serialise trace");
+            assert!(!json.contains(TEST_PASSWORD));
+            assert!(!json.contains(TEST_PASSWORD_INC));
+        })
+    }
+
     #[test]
     fn test_idm_account_unix_softlocking() {
         run_idm_test!(|qs: &QueryServer,
@@ -3204,6 +3779,13 @@ mod tests {
                 .map(|c| c.clone())
                 .expect("must have a webauthn credential");
 
+            // The softtoken doesn't report backup state, so it's single device.
+            let single_device = WebauthnBackupState {
+                eligible: false,
+                backed_up: false,
+            };
+            assert!(cred.webauthn_backup.get("softtoken") == Some(&single_device));
+
             assert!(idms_prox_write.commit(au).is_ok());
 
             // ===
@@ -3216,7 +3798,22 @@ mod tests {
             let da = DelayedAction::WebauthnCounterIncrement(WebauthnCounterIncrement {
                 target_uuid: UUID_ADMIN.clone(),
                 counter: wcred.counter + 1,
-                cid: wcred.cred_id,
+                cid: wcred.cred_id.clone(),
+                backup: None,
+            });
+            let r = task::block_on(idms.delayed_action(au, duration_from_epoch_now(), da));
+            assert!(Ok(true) == r);
+
+            // A change of backup state is recorded, even if the counter is unchanged.
+            let synced = WebauthnBackupState {
+                eligible: true,
+                backed_up: true,
+            };
+            let da = DelayedAction::WebauthnCounterIncrement(WebauthnCounterIncrement {
+                target_uuid: UUID_ADMIN.clone(),
+                counter: 0,
+                cid: wcred.cred_id.clone(),
+                backup: Some(synced),
             });
             let r = task::block_on(idms.delayed_action(au, duration_from_epoch_now(), da));
             assert!(Ok(true) == r);
@@ -3232,6 +3829,12 @@ mod tests {
                 }
                 _ => assert!(false),
             };
+            let account = idms_prox_write
+                .target_to_account(au, &UUID_ADMIN)
+                .expect("account must exist");
+            let cred = account.primary.expect("Must exist.");
+            assert!(cred.webauthn_backup.get("softtoken") == Some(&synced));
+
             // Reg a pw.
             let pce = PasswordChangeEvent::new_internal(&UUID_ADMIN, TEST_PASSWORD, None);
             assert!(idms_prox_write.set_account_password(au, &pce).is_ok());
@@ -8,6 +8,7 @@ use kanidm_proto::v1::OperationError;
 use kanidm_proto::v1::{UnixGroupToken, UnixUserToken};
 
 use crate::idm::delayed::{DelayedAction, UnixPasswordUpgrade};
+use crate::idm::missing_spn;
 
 // use crossbeam::channel::Sender;
 use std::time::Duration;
@@ -63,9 +64,7 @@ macro_rules! try_from_entry {
         let spn = $value
             .get_ava_single("spn")
             .map(|v| v.to_proto_string_clone())
-            .ok_or_else(|| {
-                OperationError::InvalidAccountState("Missing attribute: spn".to_string())
-            })?;
+            .ok_or_else(|| missing_spn($value))?;
 
         let uuid = *$value.get_uuid();
 
@@ -304,9 +303,7 @@ macro_rules! try_from_group_e {
         let spn = $value
             .get_ava_single("spn")
             .map(|v| v.to_proto_string_clone())
-            .ok_or_else(|| {
-                OperationError::InvalidAccountState("Missing attribute: spn".to_string())
-            })?;
+            .ok_or_else(|| missing_spn($value))?;
 
         let uuid = *$value.get_uuid();
 
@@ -350,9 +347,7 @@ macro_rules! try_from_account_group_e {
         let spn = $value
             .get_ava_single("spn")
             .map(|v| v.to_proto_string_clone())
-            .ok_or_else(|| {
-                OperationError::InvalidAccountState("Missing attribute: spn".to_string())
-            })?;
+            .ok_or_else(|| missing_spn($value))?;
 
         let uuid = *$value.get_uuid();
 
@@ -370,9 +365,12 @@ macro_rules! try_from_account_group_e {
 
         match $value.get_ava_as_refuuid("memberof") {
             Some(riter) => {
+                // Groups outside of the spn_scope have no spn, so they can't be
+                // given to the account as a unix group.
                 let f = filter!(f_and!([
                     f_eq("class", PartialValue::new_class("posixgroup")),
                     f_eq("class", PartialValue::new_class("group")),
+                    f_andnot(f_eq("class", PartialValue::new_class("spn_exempt"))),
                     f_or(
                         riter
                             .map(|u| f_eq("uuid", PartialValue::new_uuidr(u)))
@@ -1,8 +1,25 @@
+use crate::actors::v1_read::QueryServerReadV1;
 use crate::actors::v1_write::QueryServerWriteV1;
-use crate::constants::PURGE_FREQUENCY;
-use crate::event::{PurgeRecycledEvent, PurgeTombstoneEvent};
+use crate::constants::{
+    LOCKOUT_NOTIFY_FREQUENCY, ONLINE_BACKUP_FREQUENCY, PURGE_FREQUENCY, SPN_NOTIFY_FREQUENCY,
+    SPN_REGEN_FREQUENCY, STANDBY_PROBE_FREQUENCY,
+};
+use crate::event::{
+    DbMaintenanceEvent, OnlineBackupEvent, PurgeRecycledEvent, PurgeTombstoneEvent,
+};
+use crate::lockout_notify::LockoutNotifier;
+use crate::spn_notify::SpnNotifier;
+use crate::standby::StandbyMonitor;
+use crate::utils::duration_from_epoch_now;
 
-use tokio::time::{interval, Duration};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::time::{interval, interval_at, Duration, Instant, Interval};
+
+const ONLINE_BACKUP_PREFIX: &str = "kanidm-backup-";
+const ONLINE_BACKUP_SUFFIX: &str = ".json";
 
 pub struct IntervalActor;
 
@@ -21,4 +38,213 @@ impl IntervalActor {
             }
         });
     }
+
+    pub fn start_online_backup(
+        server: &'static QueryServerReadV1,
+        backup_path: String,
+        retention_count: usize,
+    ) {
+        tokio::spawn(async move {
+            let mut inter = interval(Duration::from_secs(ONLINE_BACKUP_FREQUENCY));
+            loop {
+                inter.tick().await;
+                let dst = Path::new(&backup_path)
+                    .join(online_backup_name(duration_from_epoch_now()))
+                    .to_string_lossy()
+                    .to_string();
+                if let Err(e) = server
+                    .handle_online_backup(OnlineBackupEvent::new(dst))
+                    .await
+                {
+                    // Keep the older backups, as they are all we have.
+                    error!("Online backup failed -> {:?}", e);
+                    continue;
+                }
+                match prune_online_backups(Path::new(&backup_path), retention_count) {
+                    Ok(removed) => removed
+                        .iter()
+                        .for_each(|p| info!("Removed old backup {}", p.display())),
+                    Err(e) => error!("Failed to prune old backups -> {:?}", e),
+                }
+            }
+        });
+    }
+
+    pub fn start_db_maintenance(server: &'static QueryServerWriteV1, period: u64, vacuum: bool) {
+        tokio::spawn(async move {
+            let mut inter = db_maintenance_interval(Duration::from_secs(period));
+            loop {
+                inter.tick().await;
+                server
+                    .handle_db_maintenance(DbMaintenanceEvent::new(vacuum))
+                    .await;
+            }
+        });
+    }
+
+    pub fn start_spn_notify(notifier: Arc<SpnNotifier>) {
+        tokio::spawn(async move {
+            let mut inter = interval(Duration::from_secs(SPN_NOTIFY_FREQUENCY));
+            loop {
+                inter.tick().await;
+                // The command may take some time, so keep it off the async workers.
+                let n = notifier.clone();
+                match tokio::task::spawn_blocking(move || n.process()).await {
+                    Ok(0) => {}
+                    Ok(sent) => info!("Sent {} spn changes to the kdc", sent),
+                    Err(e) => error!("spn notify task failed -> {:?}", e),
+                }
+            }
+        });
+    }
+
+    pub fn start_spn_regen(server: &'static QueryServerWriteV1) {
+        tokio::spawn(async move {
+            let mut inter = interval(Duration::from_secs(SPN_REGEN_FREQUENCY));
+            loop {
+                inter.tick().await;
+                server.handle_spn_regen().await;
+            }
+        });
+    }
+
+    pub fn start_lockout_notify(notifier: Arc<LockoutNotifier>) {
+        tokio::spawn(async move {
+            let mut inter = interval(Duration::from_secs(LOCKOUT_NOTIFY_FREQUENCY));
+            loop {
+                inter.tick().await;
+                let n = notifier.clone();
+                match tokio::task::spawn_blocking(move || n.process()).await {
+                    Ok(0) => {}
+                    Ok(sent) => info!("Sent {} lockout notifications", sent),
+                    Err(e) => error!("lockout notify task failed -> {:?}", e),
+                }
+            }
+        });
+    }
+
+    pub fn start_standby(monitor: Arc<StandbyMonitor>) {
+        tokio::spawn(async move {
+            let mut inter = interval(Duration::from_secs(STANDBY_PROBE_FREQUENCY));
+            // Once promoted there is nothing left to do.
+            while !monitor.is_promoted() {
+                inter.tick().await;
+                let m = monitor.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || m.process()).await {
+                    error!("standby probe task failed -> {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+// Unlike the other tasks, maintenance doesn't run at startup, as it blocks all
+// requests while it runs and a restart is a poor time for that.
+fn db_maintenance_interval(period: Duration) -> Interval {
+    interval_at(Instant::now() + period, period)
+}
+
+fn online_backup_name(now: Duration) -> String {
+    format!(
+        "{}{}{}",
+        ONLINE_BACKUP_PREFIX,
+        now.as_secs(),
+        ONLINE_BACKUP_SUFFIX
+    )
+}
+
+// Remove all but the `keep` most recent online backups in `dir`, returning the
+// paths that were removed. Files that were not written by the online backup are
+// never touched.
+fn prune_online_backups(dir: &Path, keep: usize) -> io::Result<Vec<PathBuf>> {
+    let mut backups: Vec<(u64, PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|ent| ent.ok())
+        .filter_map(|ent| {
+            let name = ent.file_name().into_string().ok()?;
+            let ts = name
+                .strip_prefix(ONLINE_BACKUP_PREFIX)?
+                .strip_suffix(ONLINE_BACKUP_SUFFIX)?
+                .parse::<u64>()
+                .ok()?;
+            Some((ts, ent.path()))
+        })
+        .collect();
+
+    // Newest first.
+    backups.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+    backups
+        .into_iter()
+        .skip(keep)
+        .map(|(_, p)| fs::remove_file(&p).map(|_| p))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{db_maintenance_interval, online_backup_name, prune_online_backups};
+    use std::fs::{self, File};
+    use std::time::Duration;
+    use tokio::time::{timeout, Instant};
+
+    #[tokio::test]
+    async fn test_db_maintenance_interval() {
+        let period = Duration::from_millis(200);
+        let start = Instant::now();
+        let mut inter = db_maintenance_interval(period);
+
+        // Nothing is run as soon as the server starts.
+        assert!(timeout(Duration::from_millis(100), inter.tick())
+            .await
+            .is_err());
+
+        // Then it runs once per period.
+        for n in 1..=3 {
+            let at = inter.tick().await;
+            assert!(at.duration_since(start) >= period * n);
+        }
+        assert!(start.elapsed() >= period * 3);
+    }
+
+    #[test]
+    fn test_prune_online_backups() {
+        let dir = std::env::temp_dir().join(format!("kanidm_prune_test_{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).expect("Failed to create test dir");
+
+        for ts in &[100, 400, 200, 300, 500] {
+            File::create(dir.join(online_backup_name(Duration::from_secs(*ts))))
+                .expect("Failed to create backup");
+        }
+        // Not ours, so it must be left alone.
+        File::create(dir.join("other.json")).expect("Failed to create file");
+
+        let removed = prune_online_backups(&dir, 2).expect("Failed to prune");
+        assert!(removed.len() == 3);
+
+        let mut remaining: Vec<String> = fs::read_dir(&dir)
+            .expect("Failed to read dir")
+            .map(|ent| {
+                ent.expect("Invalid entry")
+                    .file_name()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        remaining.sort();
+        assert!(
+            remaining
+                == vec![
+                    "kanidm-backup-400.json".to_string(),
+                    "kanidm-backup-500.json".to_string(),
+                    "other.json".to_string()
+                ]
+        );
+
+        // Fewer backups than the retention count removes nothing.
+        assert!(prune_online_backups(&dir, 5)
+            .expect("Failed to prune")
+            .is_empty());
+
+        fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
 }
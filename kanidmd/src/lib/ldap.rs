@@ -39,7 +39,13 @@ pub struct LdapServer {
 }
 
 impl LdapServer {
-    pub fn new(au: &mut AuditScope, idms: &IdmServer) -> Result<Self, OperationError> {
+    /// Create the ldap server. When `basedn` is not provided, it is derived from
+    /// the domain name.
+    pub fn new(
+        au: &mut AuditScope,
+        idms: &IdmServer,
+        basedn: Option<&str>,
+    ) -> Result<Self, OperationError> {
         let idms_prox_read = task::block_on(idms.proxy_read_async());
         // This is the rootdse path.
         // get the domain_info item
@@ -52,13 +58,18 @@ impl LdapServer {
             .map(|s| s.to_string())
             .ok_or(OperationError::InvalidEntryState)?;
 
-        let basedn = ldap_domain_to_dc(domain_name.as_str());
+        let basedn = basedn
+            .map(|b| b.to_string())
+            .unwrap_or_else(|| ldap_domain_to_dc(domain_name.as_str()));
+        let basedn_re = regex::escape(basedn.as_str());
 
-        let dnre = Regex::new(format!("^((?P<attr>[^=]+)=(?P<val>[^=]+),)?{}$", basedn).as_str())
-            .map_err(|_| OperationError::InvalidEntryState)?;
+        let dnre =
+            Regex::new(format!("^((?P<attr>[^=]+)=(?P<val>[^=]+),)?{}$", basedn_re).as_str())
+                .map_err(|_| OperationError::InvalidEntryState)?;
 
-        let binddnre = Regex::new(format!("^(([^=,]+)=)?(?P<val>[^=,]+)(,{})?$", basedn).as_str())
-            .map_err(|_| OperationError::InvalidEntryState)?;
+        let binddnre =
+            Regex::new(format!("^(([^=,]+)=)?(?P<val>[^=,]+)(,{})?$", basedn_re).as_str())
+                .map_err(|_| OperationError::InvalidEntryState)?;
 
         let rootdse = LdapSearchResultEntry {
             dn: "".to_string(),
@@ -480,6 +491,7 @@ mod tests {
     use crate::ldap::LdapServer;
     use crate::modify::{Modify, ModifyList};
     use async_std::task;
+    use ldap3_server::simple::*;
 
     const TEST_PASSWORD: &'static str = "ntaoeuntnaoeuhraohuercahu😍";
 
@@ -489,7 +501,7 @@ mod tests {
                        idms: &IdmServer,
                        _idms_delayed: &IdmServerDelayed,
                        au: &mut AuditScope| {
-            let ldaps = LdapServer::new(au, idms).expect("failed to start ldap");
+            let ldaps = LdapServer::new(au, idms, None).expect("failed to start ldap");
 
             let mut idms_prox_write = idms.proxy_write(duration_from_epoch_now());
             // make the admin a valid posix account
@@ -637,4 +649,50 @@ mod tests {
             assert!(task::block_on(ldaps.do_bind(au, idms, "claire", "test")).is_err());
         })
     }
+
+    #[test]
+    fn test_ldap_configured_basedn() {
+        run_idm_test!(|_qs: &QueryServer,
+                       idms: &IdmServer,
+                       _idms_delayed: &IdmServerDelayed,
+                       au: &mut AuditScope| {
+            let ldaps = LdapServer::new(au, idms, Some("o=kanidm,dc=example,dc=com"))
+                .expect("failed to start ldap");
+
+            let anon_t = task::block_on(ldaps.do_bind(au, idms, "", ""))
+                .unwrap()
+                .unwrap();
+
+            let search = |base: &str, scope: LdapSearchScope| SearchRequest {
+                msgid: 1,
+                base: base.to_string(),
+                scope,
+                filter: LdapFilter::Present("objectClass".to_string()),
+                attrs: vec![],
+            };
+
+            // Searches resolve under the configured basedn ...
+            let sr = search("o=kanidm,dc=example,dc=com", LdapSearchScope::Base);
+            assert!(task::block_on(ldaps.do_search(au, idms, &sr, &anon_t)).is_ok());
+            let sr = search("o=kanidm,dc=example,dc=com", LdapSearchScope::Subtree);
+            assert!(task::block_on(ldaps.do_search(au, idms, &sr, &anon_t)).is_ok());
+            let sr = search(
+                "name=admin,o=kanidm,dc=example,dc=com",
+                LdapSearchScope::Base,
+            );
+            assert!(task::block_on(ldaps.do_search(au, idms, &sr, &anon_t)).is_ok());
+
+            // ... but no longer under the domain derived one.
+            let sr = search("dc=example,dc=com", LdapSearchScope::Subtree);
+            assert!(task::block_on(ldaps.do_search(au, idms, &sr, &anon_t)).is_err());
+            let sr = search("name=admin,dc=example,dc=com", LdapSearchScope::Base);
+            assert!(task::block_on(ldaps.do_search(au, idms, &sr, &anon_t)).is_err());
+
+            // Binds accept the configured basedn.
+            assert!(ldaps
+                .binddnre
+                .is_match("name=admin,o=kanidm,dc=example,dc=com"));
+            assert!(!ldaps.binddnre.is_match("name=admin,dc=example,dc=com"));
+        })
+    }
 }
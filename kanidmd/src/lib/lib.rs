@@ -46,11 +46,19 @@ pub mod value;
 mod plugins;
 mod access;
 mod actors;
+mod auth_events;
+mod auth_trace;
+mod command_notify;
 pub mod idm;
+mod lockout_notify;
+pub mod preflight;
 mod repl;
 mod schema;
 pub mod server;
+mod spn_notify;
+pub mod standby;
 mod status;
+mod write_queue;
 
 pub mod config;
 pub mod core;
@@ -0,0 +1,105 @@
+// Notify an administrator when an account is locked out by repeated failed
+// authentications, so that a possible attack on it can be looked into.
+//
+// Notifications are queued by the authentication that caused the lockout, and
+// sent by the interval actor, so a notifier that is slow or failing never
+// delays or fails an authentication. A lockout is only of interest while it is
+// current, so a notification that can't be sent is logged and dropped rather
+// than retried. A command that runs past the timeout is killed.
+use crate::command_notify::{CommandArgs, CommandNotifier, OnFailure};
+use crate::constants::{LOCKOUT_NOTIFY_QUEUE_MAX, LOCKOUT_NOTIFY_TIMEOUT};
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockoutNotification {
+    pub account: String,
+    pub source: Option<IpAddr>,
+    pub attempts: usize,
+}
+
+// The command is given the account name, the source address of the final
+// failed attempt and the number of failed attempts. An unknown source is
+// given as an empty string.
+impl CommandArgs for LockoutNotification {
+    fn command_args(&self) -> Vec<String> {
+        vec![
+            self.account.clone(),
+            self.source.map(|ip| ip.to_string()).unwrap_or_default(),
+            self.attempts.to_string(),
+        ]
+    }
+}
+
+pub struct LockoutNotifier {
+    notifier: CommandNotifier<LockoutNotification>,
+}
+
+impl LockoutNotifier {
+    pub fn new(command: &str) -> Self {
+        LockoutNotifier {
+            notifier: CommandNotifier::new(
+                command,
+                Duration::from_secs(LOCKOUT_NOTIFY_TIMEOUT),
+                LOCKOUT_NOTIFY_QUEUE_MAX,
+                OnFailure::Drop,
+            ),
+        }
+    }
+
+    pub(crate) fn enqueue(&self, notification: LockoutNotification) {
+        let dropped = self.notifier.enqueue(Some(notification));
+        if dropped > 0 {
+            error!(
+                "lockout notify queue is full, dropped {} notifications",
+                dropped
+            );
+        }
+    }
+
+    pub(crate) fn pending(&self) -> Vec<LockoutNotification> {
+        self.notifier.pending()
+    }
+
+    /// Send the queued notifications in order. Returns the number that were
+    /// sent, those that failed are dropped.
+    pub(crate) fn process(&self) -> usize {
+        self.notifier.process(|notification, e| {
+            error!(
+                "Unable to send lockout notification for {}, dropping it -> {}",
+                notification.account, e
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LockoutNotification, LockoutNotifier};
+
+    fn notification(account: &str) -> LockoutNotification {
+        LockoutNotification {
+            account: account.to_string(),
+            source: Some("192.0.2.1".parse().expect("Invalid ip")),
+            attempts: 100,
+        }
+    }
+
+    #[test]
+    fn test_lockout_notify_failure_dropped() {
+        // A failing notifier doesn't hold on to the notifications.
+        let failing = LockoutNotifier::new("/nonexistent/kanidm_lockout_notify");
+        failing.enqueue(notification("a"));
+        failing.enqueue(notification("b"));
+        assert!(failing.process() == 0);
+        assert!(failing.pending().is_empty());
+
+        let working = LockoutNotifier::new("true");
+        working.enqueue(notification("a"));
+        working.enqueue(notification("b"));
+        assert!(working.pending() == vec![notification("a"), notification("b")]);
+        assert!(working.process() == 2);
+        assert!(working.pending().is_empty());
+    }
+}
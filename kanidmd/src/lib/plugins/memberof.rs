@@ -12,6 +12,7 @@
 
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntrySealed};
 use crate::event::{CreateEvent, DeleteEvent, ModifyEvent};
+use crate::filter::{Filter, FilterInvalid};
 use crate::prelude::*;
 // use crate::modify::{Modify, ModifyList};
 use crate::plugins::Plugin;
@@ -323,9 +324,28 @@ impl Plugin for MemberOf {
         au: &mut AuditScope,
         qs: &QueryServerReadTransaction,
     ) -> Vec<Result<(), ConsistencyError>> {
-        let mut r = Vec::new();
+        MemberOf::verify_entries(au, qs, filter!(f_pres("class")))
+    }
+
+    fn verify_scoped(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+        scope: &Filter<FilterInvalid>,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        // Each entry's memberof is checked against the groups that hold it, so
+        // this works for any subset of entries.
+        let filt_in = Filter::join_parts_and(filter!(f_pres("class")), scope.clone());
+        MemberOf::verify_entries(au, qs, filt_in)
+    }
+}
 
-        let filt_in = filter!(f_pres("class"));
+impl MemberOf {
+    fn verify_entries(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+        filt_in: Filter<FilterInvalid>,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let mut r = Vec::new();
 
         let all_cand = match qs
             .internal_search(au, filt_in)
@@ -1,7 +1,8 @@
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntrySealed};
 use crate::event::{CreateEvent, DeleteEvent, ModifyEvent};
+use crate::filter::{Filter, FilterInvalid};
 use crate::prelude::*;
-use kanidm_proto::v1::{ConsistencyError, OperationError};
+use kanidm_proto::v1::{ConsistencyError, OperationError, SpnBenchResult, SpnInconsistency};
 
 mod attrunique;
 mod base;
@@ -14,10 +15,18 @@ mod protected;
 mod recycle;
 mod refint;
 mod spn;
+mod spn_index;
 
 trait Plugin {
     fn id() -> &'static str;
 
+    // Does this plugin change the name of entries in pre_create_transform or
+    // pre_modify? Spns are generated from the name, so these must run no later
+    // than spn::Spn - see Plugins::check_name_order.
+    fn mutates_name() -> bool {
+        false
+    }
+
     fn pre_create_transform(
         au: &mut AuditScope,
         _qs: &QueryServerWriteTransaction,
@@ -116,10 +125,33 @@ trait Plugin {
         ladmin_error!(au, "plugin {} has an unimplemented verify!", Self::id());
         vec![Err(ConsistencyError::Unknown)]
     }
+
+    // As verify, but only checking the entries matching scope. Checks that are only
+    // meaningful over the whole directory (such as uniqueness) don't implement this,
+    // and are skipped.
+    fn verify_scoped(
+        au: &mut AuditScope,
+        _qs: &QueryServerReadTransaction,
+        _scope: &Filter<FilterInvalid>,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        ladmin_info!(
+            au,
+            "plugin {} can only verify the whole directory, skipping",
+            Self::id()
+        );
+        Vec::new()
+    }
 }
 
 pub struct Plugins {}
 
+// Plugins are timed, and the audit lines they log are tagged with their id.
+macro_rules! lplugin_segment {
+    ($au:ident, $id:expr, $fun:expr) => {{
+        lsubsystem_segment!($au, $id, || lperf_trace_segment!($au, $id, $fun))
+    }};
+}
+
 // Should this be a function instead, to allow inlining and better debug?
 // Probably not - I use this to generate the audit scope of the plugin from the type
 // and the ty can't really be "passed" to the fns with fn pointer stuff.
@@ -132,13 +164,21 @@ macro_rules! run_pre_create_transform_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let r = lperf_trace_segment!($au, <$target_plugin>::id(), || {
+        let r = lplugin_segment!($au, <$target_plugin>::id(), || {
             <$target_plugin>::pre_create_transform($au, $qs, $cand, $ce)
         });
         r
     }};
 }
 
+// The (id, mutates_name) of each plugin, in the order given.
+#[cfg(test)]
+macro_rules! plugin_order {
+    ($($target_plugin:ty),* $(,)?) => {{
+        vec![$((<$target_plugin>::id(), <$target_plugin>::mutates_name())),*]
+    }};
+}
+
 macro_rules! run_pre_create_plugin {
     (
         $au:ident,
@@ -147,7 +187,7 @@ macro_rules! run_pre_create_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let r = lperf_trace_segment!(
+        let r = lplugin_segment!(
             $au,
             <$target_plugin>::id(),
             || <$target_plugin>::pre_create($au, $qs, $cand, $ce,)
@@ -164,7 +204,7 @@ macro_rules! run_post_create_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let r = lperf_trace_segment!($au, <$target_plugin>::id(), || {
+        let r = lplugin_segment!($au, <$target_plugin>::id(), || {
             <$target_plugin>::post_create($au, $qs, $cand, $ce)
         });
         r
@@ -179,7 +219,7 @@ macro_rules! run_pre_modify_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let r = lperf_trace_segment!(
+        let r = lplugin_segment!(
             $au,
             <$target_plugin>::id(),
             || <$target_plugin>::pre_modify($au, $qs, $cand, $ce)
@@ -197,7 +237,7 @@ macro_rules! run_post_modify_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let r = lperf_trace_segment!($au, <$target_plugin>::id(), || {
+        let r = lplugin_segment!($au, <$target_plugin>::id(), || {
             <$target_plugin>::post_modify($au, $qs, $pre_cand, $cand, $ce)
         });
         r
@@ -212,7 +252,7 @@ macro_rules! run_pre_delete_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let r = lperf_trace_segment!(
+        let r = lplugin_segment!(
             $au,
             <$target_plugin>::id(),
             || <$target_plugin>::pre_delete($au, $qs, $cand, $ce,)
@@ -229,7 +269,7 @@ macro_rules! run_post_delete_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let r = lperf_trace_segment!($au, <$target_plugin>::id(), || {
+        let r = lplugin_segment!($au, <$target_plugin>::id(), || {
             <$target_plugin>::post_delete($au, $qs, $cand, $ce)
         });
         r
@@ -243,20 +283,101 @@ macro_rules! run_verify_plugin {
         $results:expr,
         $target_plugin:ty
     ) => {{
-        let mut r = lperf_trace_segment!($au, <$target_plugin>::id(), || <$target_plugin>::verify(
+        let mut r = lplugin_segment!($au, <$target_plugin>::id(), || <$target_plugin>::verify(
             $au, $qs,
         ));
         $results.append(&mut r);
     }};
 }
 
+macro_rules! run_verify_scoped_plugin {
+    (
+        $au:ident,
+        $qs:ident,
+        $scope:ident,
+        $results:expr,
+        $target_plugin:ty
+    ) => {{
+        let mut r = lplugin_segment!($au, <$target_plugin>::id(), || {
+            <$target_plugin>::verify_scoped($au, $qs, $scope)
+        });
+        $results.append(&mut r);
+    }};
+}
+
 impl Plugins {
+    /// The subsystems that the plugins tag their audit events with.
+    pub(crate) fn subsystems() -> Vec<&'static str> {
+        vec![
+            attrunique::AttrUnique::id(),
+            base::Base::id(),
+            domain::Domain::id(),
+            gidnumber::GidNumber::id(),
+            memberof::MemberOf::id(),
+            password_import::PasswordImport::id(),
+            protected::Protected::id(),
+            refint::ReferentialIntegrity::id(),
+            spn::Spn::id(),
+            spn_index::SpnIndex::id(),
+        ]
+    }
+
+    // The order of run_pre_create_transform and run_pre_modify. These must be
+    // kept in step with them, so that the name order can be checked.
+    #[cfg(test)]
+    fn pre_create_transform_order() -> Vec<(&'static str, bool)> {
+        plugin_order!(
+            base::Base,
+            password_import::PasswordImport,
+            gidnumber::GidNumber,
+            domain::Domain,
+            spn::Spn,
+            spn_index::SpnIndex,
+            attrunique::AttrUnique,
+        )
+    }
+
+    #[cfg(test)]
+    fn pre_modify_order() -> Vec<(&'static str, bool)> {
+        plugin_order!(
+            protected::Protected,
+            base::Base,
+            password_import::PasswordImport,
+            gidnumber::GidNumber,
+            spn::Spn,
+            spn_index::SpnIndex,
+            attrunique::AttrUnique,
+        )
+    }
+
+    /// Spns are generated from the final name of an entry, so no plugin that
+    /// changes the name may run after spn::Spn. Spn itself may, as it renames
+    /// entries before it generates their spns.
+    #[cfg(test)]
+    fn check_name_order(order: &[(&'static str, bool)]) -> Result<(), String> {
+        let spn = spn::Spn::id();
+        let after_spn = order
+            .iter()
+            .skip_while(|(id, _)| *id != spn)
+            .skip(1)
+            .find(|(_, mutates_name)| *mutates_name);
+        match (order.iter().any(|(id, _)| *id == spn), after_spn) {
+            (false, _) => Err(format!("{} is not in the plugin order", spn)),
+            (true, Some((id, _))) => Err(format!(
+                "{} changes the name of entries, but runs after {}",
+                id, spn
+            )),
+            (true, None) => Ok(()),
+        }
+    }
+
     pub fn run_pre_create_transform(
         au: &mut AuditScope,
         qs: &QueryServerWriteTransaction,
         cand: &mut Vec<Entry<EntryInvalid, EntryNew>>,
         ce: &CreateEvent,
     ) -> Result<(), OperationError> {
+        // Any change to this order must also be made to pre_create_transform_order.
         lperf_segment!(au, "plugins::run_pre_create_transform", || {
             run_pre_create_transform_plugin!(au, qs, cand, ce, base::Base)
                 .and_then(|_| {
@@ -273,6 +394,9 @@ impl Plugins {
                 })
                 .and_then(|_| run_pre_create_transform_plugin!(au, qs, cand, ce, domain::Domain))
                 .and_then(|_| run_pre_create_transform_plugin!(au, qs, cand, ce, spn::Spn))
+                .and_then(|_| {
+                    run_pre_create_transform_plugin!(au, qs, cand, ce, spn_index::SpnIndex)
+                })
                 .and_then(|_| {
                     // Should always be last
                     run_pre_create_transform_plugin!(au, qs, cand, ce, attrunique::AttrUnique)
@@ -308,13 +432,8 @@ impl Plugins {
             ce,
             refint::ReferentialIntegrity
         )
-        .and_then(|_| run_post_create_plugin!(
-            au,
-            qs,
-            cand,
-            ce,
-            memberof::MemberOf
-        )))
+        .and_then(|_| run_post_create_plugin!(au, qs, cand, ce, memberof::MemberOf))
+        .and_then(|_| run_post_create_plugin!(au, qs, cand, ce, spn::Spn)))
     }
 
     pub fn run_pre_modify(
@@ -323,6 +442,7 @@ impl Plugins {
         cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
         me: &ModifyEvent,
     ) -> Result<(), OperationError> {
+        // Any change to this order must also be made to pre_modify_order.
         lperf_segment!(au, "plugins::run_pre_modify", || {
             run_pre_modify_plugin!(au, qs, cand, me, protected::Protected)
                 .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, base::Base))
@@ -331,6 +451,7 @@ impl Plugins {
                 })
                 .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, gidnumber::GidNumber))
                 .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, spn::Spn))
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, spn_index::SpnIndex))
                 // attr unique should always be last
                 .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, attrunique::AttrUnique))
         })
@@ -410,7 +531,97 @@ impl Plugins {
             run_verify_plugin!(au, qs, &mut results, refint::ReferentialIntegrity);
             run_verify_plugin!(au, qs, &mut results, memberof::MemberOf);
             run_verify_plugin!(au, qs, &mut results, spn::Spn);
+            run_verify_plugin!(au, qs, &mut results, spn_index::SpnIndex);
+            results
+        })
+    }
+
+    /// As run_verify, but only for the entries matching scope.
+    pub fn run_verify_scoped(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+        scope: &Filter<FilterInvalid>,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        lperf_segment!(au, "plugins::run_verify_scoped", || {
+            let mut results = Vec::new();
+            run_verify_scoped_plugin!(au, qs, scope, &mut results, base::Base);
+            run_verify_scoped_plugin!(au, qs, scope, &mut results, attrunique::AttrUnique);
+            run_verify_scoped_plugin!(au, qs, scope, &mut results, refint::ReferentialIntegrity);
+            run_verify_scoped_plugin!(au, qs, scope, &mut results, memberof::MemberOf);
+            run_verify_scoped_plugin!(au, qs, scope, &mut results, spn::Spn);
+            run_verify_scoped_plugin!(au, qs, scope, &mut results, spn_index::SpnIndex);
             results
         })
     }
+
+    /// The accounts and groups whose spn verify would report as inconsistent, with
+    /// the spn each of them should have.
+    pub fn run_spn_fsck(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Vec<(EntrySealedCommitted, Option<Value>, SpnInconsistency)>, OperationError> {
+        lperf_segment!(au, "plugins::run_spn_fsck", || {
+            spn::Spn::find_inconsistent(au, qs, None)
+                .map_err(|ce| OperationError::ConsistencyError(vec![Err(ce)]))
+        })
+    }
+
+    /// Regenerate the spns of the accounts and groups, or only those with `class`.
+    pub fn run_spn_regenerate(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        class: Option<&PartialValue>,
+    ) -> Result<(), OperationError> {
+        lperf_segment!(au, "plugins::run_spn_regenerate", || {
+            spn::Spn::regenerate(au, qs, class)
+        })
+    }
+
+    /// Regenerate up to `chunk_size` of the spns left behind by a domain rename.
+    pub fn run_spn_regenerate_chunk(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        chunk_size: usize,
+    ) -> Result<usize, OperationError> {
+        lperf_segment!(au, "plugins::run_spn_regenerate_chunk", || {
+            spn::Spn::regenerate_chunk(au, qs, chunk_size)
+        })
+    }
+
+    /// Measure spn generation and verification over `count` synthetic entries.
+    pub fn run_spn_bench(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+        count: usize,
+    ) -> Result<SpnBenchResult, OperationError> {
+        lperf_segment!(au, "plugins::run_spn_bench", || spn::Spn::bench(
+            au, qs, count
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plugins::Plugins;
+
+    #[test]
+    fn test_plugins_name_order() {
+        assert!(Plugins::check_name_order(&Plugins::pre_create_transform_order()).is_ok());
+        assert!(Plugins::check_name_order(&Plugins::pre_modify_order()).is_ok());
+
+        // A plugin that renames entries after the spn is generated is caught.
+        let mut order = Plugins::pre_modify_order();
+        order.push(("plugin_test_rename", true));
+        assert!(Plugins::check_name_order(&order).is_err());
+        // But one that runs before it is fine.
+        order.pop();
+        order.insert(0, ("plugin_test_rename", true));
+        assert!(Plugins::check_name_order(&order).is_ok());
+        // As is one that doesn't change names.
+        order.push(("plugin_test_other", false));
+        assert!(Plugins::check_name_order(&order).is_ok());
+
+        order.retain(|(id, _)| *id != "plugin_spn");
+        assert!(Plugins::check_name_order(&order).is_err());
+    }
 }
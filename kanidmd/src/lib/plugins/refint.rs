@@ -16,7 +16,7 @@ use crate::plugins::Plugin;
 use crate::prelude::*;
 
 use crate::event::{CreateEvent, DeleteEvent, ModifyEvent};
-use crate::filter::f_eq;
+use crate::filter::{f_eq, Filter, FilterInvalid};
 use crate::modify::Modify;
 use crate::schema::SchemaTransaction;
 use kanidm_proto::v1::{ConsistencyError, PluginError};
@@ -79,6 +79,42 @@ impl ReferentialIntegrity {
             )))
         }
     }
+
+    // Check every reference of the candidates is to an entry in exists.
+    fn check_refs_exist(
+        qs: &QueryServerReadTransaction,
+        cand: &[Entry<EntrySealed, EntryCommitted>],
+        exists: &Set<&Uuid>,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let schema = qs.get_schema();
+        let ref_types = schema.get_reference_types();
+
+        let mut res = Vec::new();
+        // For all cands
+        for c in cand {
+            // For all reference in each cand.
+            for rtype in ref_types.values() {
+                // If the attribute is present
+                if let Some(vs) = c.get_ava(&rtype.name) {
+                    // For each value in the set.
+                    for v in vs {
+                        match v.to_ref_uuid() {
+                            Some(vu) => {
+                                if exists.get(vu).is_none() {
+                                    res.push(Err(ConsistencyError::RefintNotUpheld(c.get_id())))
+                                }
+                            }
+                            None => res.push(Err(ConsistencyError::InvalidAttributeType(
+                                "A non-value-ref type was found.".to_string(),
+                            ))),
+                        }
+                    }
+                }
+            }
+        }
+
+        res
+    }
 }
 
 impl Plugin for ReferentialIntegrity {
@@ -220,34 +256,56 @@ impl Plugin for ReferentialIntegrity {
 
         let acu_map: Set<&Uuid> = all_cand.iter().map(|e| e.get_uuid()).collect();
 
-        let schema = qs.get_schema();
-        let ref_types = schema.get_reference_types();
+        Self::check_refs_exist(qs, &all_cand, &acu_map)
+    }
 
-        let mut res = Vec::new();
-        // For all cands
-        for c in &all_cand {
-            // For all reference in each cand.
-            for rtype in ref_types.values() {
-                // If the attribute is present
-                if let Some(vs) = c.get_ava(&rtype.name) {
-                    // For each value in the set.
-                    for v in vs {
-                        match v.to_ref_uuid() {
-                            Some(vu) => {
-                                if acu_map.get(vu).is_none() {
-                                    res.push(Err(ConsistencyError::RefintNotUpheld(c.get_id())))
-                                }
-                            }
-                            None => res.push(Err(ConsistencyError::InvalidAttributeType(
-                                "A non-value-ref type was found.".to_string(),
-                            ))),
-                        }
-                    }
-                }
+    fn verify_scoped(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+        scope: &Filter<FilterInvalid>,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let filt_in = Filter::join_parts_and(filter_all!(f_pres("class")), scope.clone());
+
+        let cand = match qs
+            .internal_search(au, filt_in)
+            .map_err(|_| Err(ConsistencyError::QueryServerSearchFailure))
+        {
+            Ok(cand) => cand,
+            Err(e) => return vec![e],
+        };
+
+        // Rather than loading every entry, only look for those that are referenced.
+        let ref_types = qs.get_schema().get_reference_types();
+        let refs: BTreeSet<Uuid> = cand
+            .iter()
+            .flat_map(|c| {
+                ref_types
+                    .values()
+                    .filter_map(move |rtype| c.get_ava(&rtype.name))
+                    .flatten()
+                    .filter_map(|v| v.to_ref_uuid().copied())
+            })
+            .collect();
+
+        let found = if refs.is_empty() {
+            Vec::new()
+        } else {
+            let filt_refs = filter_all!(f_or(
+                refs.iter()
+                    .map(|u| f_eq("uuid", PartialValue::new_uuid(*u)))
+                    .collect()
+            ));
+            match qs
+                .internal_search(au, filt_refs)
+                .map_err(|_| Err(ConsistencyError::QueryServerSearchFailure))
+            {
+                Ok(found) => found,
+                Err(e) => return vec![e],
             }
-        }
+        };
+        let found_map: Set<&Uuid> = found.iter().map(|e| e.get_uuid()).collect();
 
-        res
+        Self::check_refs_exist(qs, &cand, &found_map)
     }
 }
 
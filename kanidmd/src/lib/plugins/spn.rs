@@ -3,19 +3,383 @@
 use crate::plugins::Plugin;
 use crate::prelude::*;
 
-use crate::constants::UUID_DOMAIN_INFO;
-use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntrySealed};
+use crate::config::{
+    AnonymousSpn, DuplicateNamePolicy, SpnCharsetPolicy, SpnDirectWrite, SpnRegenMode,
+};
+use crate::constants::{SPN_BENCH_COUNT_MAX, UUID_ANONYMOUS, UUID_DOMAIN_INFO, UUID_SYSTEM_CONFIG};
+use crate::entry::{
+    Entry, EntryCommitted, EntryInit, EntryInvalid, EntryNew, EntrySealed, SpnGenerator,
+};
 use crate::event::{CreateEvent, ModifyEvent};
-use crate::value::PartialValue;
-// use crate::value::{PartialValue, Value};
-use kanidm_proto::v1::{ConsistencyError, OperationError};
+use crate::filter::{f_eq, Filter, FilterInvalid, FilterValidResolved};
+use crate::modify::Modify;
+use crate::spn_notify::SpnChange;
+use crate::value::{PartialValue, Value};
+use kanidm_proto::v1::{
+    ConsistencyError, OperationError, PluginError, SpnBenchResult, SpnInconsistency, SpnOrphan,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
 
 pub struct Spn {}
 
 lazy_static! {
     static ref CLASS_GROUP: PartialValue = PartialValue::new_class("group");
     static ref CLASS_ACCOUNT: PartialValue = PartialValue::new_class("account");
+    static ref CLASS_TOMBSTONE: PartialValue = PartialValue::new_class("tombstone");
     static ref PV_UUID_DOMAIN_INFO: PartialValue = PartialValue::new_uuidr(&UUID_DOMAIN_INFO);
+    static ref PV_UUID_SYSTEM_CONFIG: PartialValue = PartialValue::new_uuidr(&UUID_SYSTEM_CONFIG);
+    static ref PV_UUID_ANONYMOUS: PartialValue = PartialValue::new_uuidr(&UUID_ANONYMOUS);
+    static ref CLASS_SPN_EXEMPT: PartialValue = PartialValue::new_class("spn_exempt");
+}
+
+// Remove the spn of an entry that should not have one. spn is a must attribute
+// of accounts and groups, so the entry is marked as exempt from it.
+fn exempt_spn<STATE>(e: &mut Entry<EntryInvalid, STATE>) {
+    e.purge_ava("spn");
+    e.add_ava("class", Value::new_class("spn_exempt"));
+}
+
+// Any entry that has been given an spn is no longer exempt from it.
+fn clear_spn_exemptions<STATE>(cand: &mut [Entry<EntryInvalid, STATE>]) {
+    cand.iter_mut()
+        .filter(|e| e.attribute_pres("spn"))
+        .for_each(|e| e.remove_avas("class", &btreeset![CLASS_SPN_EXEMPT.clone()]));
+}
+
+// Entries are in scope when no spn_scope is configured, or they match it. The
+// scope also excludes the members of the spn_optout_group.
+fn in_spn_scope<VALID, STATE>(
+    au: &mut AuditScope,
+    e: &Entry<VALID, STATE>,
+    spn_scope: Option<&Filter<FilterValidResolved>>,
+) -> bool {
+    match spn_scope {
+        Some(f) if !e.entry_match_no_index(f) => {
+            ltrace!(
+                au,
+                "plugin_spn: {:?} is outside of spn_scope or opted out, no spn",
+                e.get_ava_single("name")
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
+// Log the spn given to an entry. When spn_log_level is info, an spn that changed
+// is logged at info instead of trace.
+fn log_spn_set<STATE>(
+    au: &mut AuditScope,
+    qs: &QueryServerWriteTransaction,
+    e: &Entry<EntryInvalid, STATE>,
+    spn: &Value,
+) {
+    if e.get_ava_single("spn") != Some(spn) && qs.spn_log_at_info(au) {
+        ladmin_info!(
+            au,
+            "plugin_spn: set spn of {} to {}",
+            e.get_ava_single_str("name").unwrap_or("unnamed"),
+            spn.to_proto_string_clone()
+        );
+    } else {
+        ltrace!(au, "plugin_spn: set spn to {:?}", spn);
+    }
+}
+
+// An account past its account_expire can no longer be used. When spn_skip_expired
+// is set these keep their existing spn rather than being regenerated.
+fn is_expired<VALID, STATE>(e: &Entry<VALID, STATE>, ct: Duration) -> bool {
+    e.attribute_value_pres("class", &CLASS_ACCOUNT)
+        && e.get_ava_single_datetime("account_expire")
+            .map(|expire| expire <= OffsetDateTime::unix_epoch() + ct)
+            .unwrap_or(false)
+}
+
+// An account with spn_locked keeps the spn an administrator gave it, rather than
+// having one generated. Without an spn to keep, the lock has no effect.
+fn locked_spn<VALID, STATE>(e: &Entry<VALID, STATE>) -> Option<&Value> {
+    if e.get_ava_single_bool("spn_locked").unwrap_or(false) {
+        e.get_ava_single("spn")
+    } else {
+        None
+    }
+}
+
+// Does this modify, made by a user rather than by the server, set an spn itself?
+// That is only meaningful for an entry with spn_locked, any other is replaced by
+// the generated spn.
+fn sets_spn(me: &ModifyEvent) -> bool {
+    !me.event.is_internal()
+        && me
+            .modlist
+            .into_iter()
+            .any(|m| matches!(m, Modify::Present(a, _) if a == "spn"))
+}
+
+// Handle an spn that was set directly, and differs from the generated one,
+// according to spn_direct_write.
+fn check_direct_write(
+    au: &mut AuditScope,
+    qs: &QueryServerWriteTransaction,
+    e: &Entry<EntryInvalid, EntryCommitted>,
+    spn: &Value,
+) -> Result<(), OperationError> {
+    let name = e.get_ava_single_str("name").unwrap_or("unnamed");
+    match qs.get_spn_settings().direct_write {
+        SpnDirectWrite::Overwrite => {
+            ladmin_warning!(
+                au,
+                "plugin_spn: the spn of {} was set directly, replacing it with {}. Set spn_locked to keep an spn.",
+                name,
+                spn.to_proto_string_clone()
+            );
+            Ok(())
+        }
+        SpnDirectWrite::Reject => {
+            lrequest_error!(
+                au,
+                "plugin_spn: refusing to set the spn of {} directly, it is generated as {}",
+                name,
+                spn.to_proto_string_clone()
+            );
+            Err(OperationError::SystemProtectedAttribute)
+        }
+    }
+}
+
+// An entry replicated from a trusted domain keeps the spn that domain gave it, as
+// only the domain it originated from can generate it. Returns that domain.
+fn trusted_origin<'a, 'b, QS: QueryServerTransaction<'a>, VALID, STATE>(
+    qs: &QS,
+    e: &'b Entry<VALID, STATE>,
+) -> Option<&'b str> {
+    e.get_ava_single_str("origin_domain")
+        .filter(|d| qs.get_spn_settings().trusted_domains.contains(*d))
+}
+
+// The spn the server configuration requires the anonymous account to have, where
+// None means it has no spn. Other entries, and a generated anonymous spn, are not
+// overridden.
+fn anonymous_spn<'a, QS: QueryServerTransaction<'a>, VALID, STATE>(
+    qs: &QS,
+    e: &Entry<VALID, STATE>,
+) -> Option<Option<Value>> {
+    if !e.attribute_value_pres("uuid", &PV_UUID_ANONYMOUS) {
+        return None;
+    }
+    match &qs.get_spn_settings().anonymous_spn {
+        AnonymousSpn::Generated => None,
+        AnonymousSpn::Suppressed => Some(None),
+        AnonymousSpn::Fixed(name, domain) => Some(Some(Value::new_spn_str(name, domain))),
+    }
+}
+
+// Apply the configured spn of the anonymous account, returning true if it was
+// applied so that the spn is not generated.
+fn apply_anonymous_spn<STATE: Clone>(
+    au: &mut AuditScope,
+    qs: &QueryServerWriteTransaction,
+    e: &mut Entry<EntryInvalid, STATE>,
+) -> Result<bool, OperationError> {
+    match anonymous_spn(qs, e) {
+        Some(Some(spn)) => {
+            check_reserved(au, qs, &spn)?;
+            ltrace!(au, "plugin_spn: anonymous spn is configured as {:?}", spn);
+            e.set_ava("spn", btreeset![spn]);
+            Ok(true)
+        }
+        Some(None) => {
+            ltrace!(au, "plugin_spn: anonymous spn is suppressed");
+            exempt_spn(e);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Is this inconsistency an spn left behind by a domain rename, with the generated
+// name but in another realm? Those are regenerated in chunks in spn_regen_mode
+// available. A reserved spn, or one with the wrong name, is left for the fsck.
+fn awaiting_regen<STATE>(
+    e: &Entry<EntrySealed, STATE>,
+    g_spn: Option<&Value>,
+    kind: &SpnInconsistency,
+) -> bool {
+    if *kind != SpnInconsistency::Mismatch && *kind != SpnInconsistency::Untrusted {
+        return false;
+    }
+    match (
+        e.get_ava_single("spn").and_then(|v| v.to_spn()),
+        g_spn.and_then(|v| v.to_spn()),
+    ) {
+        (Some((name, realm)), Some((g_name, g_realm))) => {
+            name == g_name && !realm.eq_ignore_ascii_case(g_realm)
+        }
+        _ => false,
+    }
+}
+
+// An spn with a realm other than our own belongs to an entry replicated from
+// another domain, which generated it, so when trusted domains are configured
+// it is checked against them rather than against the spn we would generate.
+// Returns the realm of such an spn.
+fn foreign_realm<'a, 'b, QS: QueryServerTransaction<'a>>(
+    qs: &QS,
+    spngen: &SpnGenerator,
+    spn: &'b Value,
+) -> Option<&'b str> {
+    if qs.get_spn_settings().trusted_domains.is_empty() {
+        return None;
+    }
+    spn.to_spn()
+        .map(|(_, realm)| realm)
+        .filter(|realm| !spngen.is_local_realm(realm))
+}
+
+// Reserved spns are set aside by the server configuration, such as for service
+// principals managed outside of kanidm, and must never be given to an entry.
+fn is_reserved<'a, QS: QueryServerTransaction<'a>>(qs: &QS, spn: &Value) -> bool {
+    let reserved = &qs.get_spn_settings().reserved_spns;
+    !reserved.is_empty() && reserved.contains(&spn.to_proto_string_clone())
+}
+
+fn check_reserved(
+    au: &mut AuditScope,
+    qs: &QueryServerWriteTransaction,
+    spn: &Value,
+) -> Result<(), OperationError> {
+    if is_reserved(qs, spn) {
+        let spn = spn.to_proto_string_clone();
+        ladmin_error!(au, "plugin_spn: refusing to generate reserved spn {}", spn);
+        Err(OperationError::Plugin(PluginError::SpnReserved(spn)))
+    } else {
+        Ok(())
+    }
+}
+
+// Kerberos implementations refuse principals with characters outside of a small
+// set, so this is caught when the spn is generated rather than when it's used. In
+// sanitize the generator has already replaced them.
+fn check_kerberos_chars<STATE>(
+    au: &mut AuditScope,
+    qs: &QueryServerWriteTransaction,
+    spngen: &SpnGenerator,
+    e: &Entry<EntryInvalid, STATE>,
+    spn: &Value,
+) -> Result<(), OperationError> {
+    let invalid = spngen.invalid_kerberos_chars(e, spn);
+    if invalid.is_empty() {
+        return Ok(());
+    }
+    let spn = spn.to_proto_string_clone();
+    let invalid: String = invalid.into_iter().collect();
+    match qs.get_spn_settings().charset_policy {
+        SpnCharsetPolicy::Reject => {
+            ladmin_error!(
+                au,
+                "plugin_spn: refusing spn {} with characters kerberos doesn't allow -> {:?}",
+                spn,
+                invalid
+            );
+            Err(OperationError::Plugin(PluginError::SpnInvalidCharacters(
+                spn,
+            )))
+        }
+        SpnCharsetPolicy::Sanitize | SpnCharsetPolicy::Warn => {
+            ladmin_warning!(
+                au,
+                "plugin_spn: spn {} has characters kerberos doesn't allow -> {:?}",
+                spn,
+                invalid
+            );
+            Ok(())
+        }
+    }
+}
+
+// The spn prefixes of the domain must all be valid, so that each one is used by
+// the spn generator.
+fn check_spn_prefixes<STATE>(
+    au: &mut AuditScope,
+    e: &Entry<EntryInvalid, STATE>,
+) -> Result<(), OperationError> {
+    if !e.attribute_value_pres("uuid", &PV_UUID_DOMAIN_INFO) {
+        return Ok(());
+    }
+    match e
+        .get_ava_as_str("domain_spn_prefix")
+        .and_then(|mut i| i.find(|s| SpnGenerator::parse_prefix(s).is_none()))
+    {
+        Some(invalid) => {
+            ladmin_error!(au, "plugin_spn: invalid domain_spn_prefix {}", invalid);
+            Err(OperationError::InvalidAttribute(format!(
+                "domain_spn_prefix {} must be in the form class=prefix",
+                invalid
+            )))
+        }
+        None => Ok(()),
+    }
+}
+
+// Attrunique only sees the entries of a create after their spns are generated, so
+// entries in the same create sharing a name are dealt with first, before they are
+// given the same spn.
+fn check_duplicate_names(
+    au: &mut AuditScope,
+    qs: &QueryServerWriteTransaction,
+    cand: &mut [Entry<EntryInvalid, EntryNew>],
+) -> Result<(), OperationError> {
+    let mut names: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (i, e) in cand.iter().enumerate() {
+        if let Some(name) = e.get_ava_single_str("name") {
+            names.entry(name.to_string()).or_default().push(i);
+        }
+    }
+    let duplicates: Vec<(&String, &Vec<usize>)> =
+        names.iter().filter(|(_, idxs)| idxs.len() > 1).collect();
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+
+    match qs.get_spn_settings().duplicate_name_policy {
+        DuplicateNamePolicy::Reject => {
+            let conflicts = duplicates
+                .iter()
+                .map(|(name, idxs)| {
+                    let uuids: Vec<String> = idxs
+                        .iter()
+                        .filter_map(|i| cand[*i].get_ava_single("uuid"))
+                        .map(|v| v.to_proto_string_clone())
+                        .collect();
+                    format!("{} ({})", name, uuids.join(", "))
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            ladmin_error!(au, "plugin_spn: duplicate names in create -> {}", conflicts);
+            Err(OperationError::Plugin(PluginError::AttrUnique(format!(
+                "more than one entry in the create has the name {}",
+                conflicts
+            ))))
+        }
+        DuplicateNamePolicy::Disambiguate => {
+            let mut taken: BTreeSet<String> = names.keys().cloned().collect();
+            for (name, idxs) in duplicates {
+                // The first entry keeps the name.
+                for i in idxs.iter().skip(1) {
+                    let renamed = (2..)
+                        .map(|n| format!("{}_{}", name, n))
+                        .find(|n| !taken.contains(n))
+                        .ok_or(OperationError::InvalidEntryState)?;
+                    ladmin_info!(au, "plugin_spn: renaming duplicate {} to {}", name, renamed);
+                    cand[*i].set_ava("name", btreeset![Value::new_iname(&renamed)]);
+                    taken.insert(renamed);
+                }
+            }
+            Ok(())
+        }
+    }
 }
 
 impl Plugin for Spn {
@@ -23,6 +387,13 @@ impl Plugin for Spn {
         "plugin_spn"
     }
 
+    // Duplicate names in a create may be disambiguated. This is done before the
+    // spns are generated, and every other plugin that changes names must run
+    // before this one.
+    fn mutates_name() -> bool {
+        true
+    }
+
     // hook on pre-create and modify to generate / validate.
     fn pre_create_transform(
         au: &mut AuditScope,
@@ -35,33 +406,59 @@ impl Plugin for Spn {
         // just generate and set blindly when required.
 
         // Should we work out what classes dynamically from schema into a filter?
-        // No - types that are trust replicated are fixed.
-        let mut domain_name: Option<String> = None;
+        // No - types that are trust replicated are fixed. An operator may still
+        // narrow this further with the spn_scope in the system config.
+        check_duplicate_names(au, qs, cand)?;
+
+        let mut spngen: Option<SpnGenerator> = None;
+        let mut spn_scope = None;
 
         for e in cand.iter_mut() {
+            check_spn_prefixes(au, e)?;
             if e.attribute_value_pres("class", &CLASS_GROUP)
                 || e.attribute_value_pres("class", &CLASS_ACCOUNT)
             {
                 // We do this in the loop so that we don't get it unless required.
-                if domain_name.is_none() {
-                    domain_name = Some(qs.get_domain_name(au)?);
+                if spngen.is_none() {
+                    spngen = Some(qs.get_spn_generator(au)?);
+                    spn_scope = qs.get_spn_scope_filter(au)?;
+                }
+
+                if apply_anonymous_spn(au, qs, e)? {
+                    continue;
+                }
+
+                if let Some(spn) = locked_spn(e) {
+                    check_reserved(au, qs, spn)?;
+                    ltrace!(au, "plugin_spn: spn is locked to {:?}", spn);
+                    continue;
+                }
+
+                if let Some(domain) = trusted_origin(qs, e) {
+                    ltrace!(au, "plugin_spn: spn is from the trusted domain {}", domain);
+                    continue;
+                }
+
+                if !in_spn_scope(au, e, spn_scope.as_ref()) {
+                    exempt_spn(e);
+                    continue;
                 }
 
                 // It should be impossible to hit this expect as the is_none case should cause it to be replaced above.
-                let some_domain_name = domain_name
+                let some_spngen = spngen
                     .as_ref()
                     .ok_or(OperationError::InvalidEntryState)
                     .map_err(|e| {
                         ladmin_error!(
                             au,
-                            "Domain name option memory corruption may have occured. {:?}",
+                            "Spn generator option memory corruption may have occured. {:?}",
                             e
                         );
                         e
                     })?;
 
-                let spn = e
-                    .generate_spn(some_domain_name.as_str())
+                let spn = some_spngen
+                    .generate(e)
                     .ok_or(OperationError::InvalidEntryState)
                     .map_err(|e| {
                         ladmin_error!(
@@ -71,10 +468,13 @@ impl Plugin for Spn {
                         );
                         e
                     })?;
-                ltrace!(au, "plugin_spn: set spn to {:?}", spn);
+                check_reserved(au, qs, &spn)?;
+                check_kerberos_chars(au, qs, some_spngen, e, &spn)?;
+                log_spn_set(au, qs, e, &spn);
                 e.set_ava("spn", btreeset![spn]);
             }
         }
+        clear_spn_exemptions(cand);
         Ok(())
     }
 
@@ -82,35 +482,60 @@ impl Plugin for Spn {
         au: &mut AuditScope,
         qs: &QueryServerWriteTransaction,
         cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
-        _me: &ModifyEvent,
+        me: &ModifyEvent,
     ) -> Result<(), OperationError> {
         // Always generate and set *if* spn was an attribute on any of the mod
         // list events.
-        let mut domain_name: Option<String> = None;
+        let mut spngen: Option<SpnGenerator> = None;
+        let mut spn_scope = None;
+        let direct_write = sets_spn(me);
 
         for e in cand.iter_mut() {
+            check_spn_prefixes(au, e)?;
             if e.attribute_value_pres("class", &CLASS_GROUP)
                 || e.attribute_value_pres("class", &CLASS_ACCOUNT)
             {
-                if domain_name.is_none() {
-                    domain_name = Some(qs.get_domain_name(au)?);
+                if spngen.is_none() {
+                    spngen = Some(qs.get_spn_generator(au)?);
+                    spn_scope = qs.get_spn_scope_filter(au)?;
+                }
+
+                if apply_anonymous_spn(au, qs, e)? {
+                    continue;
+                }
+
+                if let Some(spn) = locked_spn(e) {
+                    check_reserved(au, qs, spn)?;
+                    ltrace!(au, "plugin_spn: spn is locked to {:?}", spn);
+                    continue;
+                }
+
+                if let Some(domain) = trusted_origin(qs, e) {
+                    ltrace!(au, "plugin_spn: spn is from the trusted domain {}", domain);
+                    continue;
+                }
+
+                // Entries that move out of scope lose their spn.
+                if !in_spn_scope(au, e, spn_scope.as_ref()) {
+                    exempt_spn(e);
+                    continue;
                 }
 
                 // It should be impossible to hit this expect as the is_none case should cause it to be replaced above.
-                let some_domain_name = domain_name
+                let some_spngen = spngen
                     .as_ref()
                     .ok_or(OperationError::InvalidEntryState)
                     .map_err(|e| {
                         ladmin_error!(
                             au,
-                            "Domain name option memory corruption may have occured. {:?}",
+                            "Spn generator option memory corruption may have occured. {:?}",
                             e
                         );
                         e
                     })?;
 
-                let spn = e
-                    .generate_spn(some_domain_name.as_str())
+                let spn = some_spngen
+                    .generate(e)
                     .ok_or(OperationError::InvalidEntryState)
                     .map_err(|e| {
                         ladmin_error!(
@@ -120,10 +545,32 @@ impl Plugin for Spn {
                         );
                         e
                     })?;
-                ltrace!(au, "plugin_spn: set spn to {:?}", spn);
+                check_reserved(au, qs, &spn)?;
+                check_kerberos_chars(au, qs, some_spngen, e, &spn)?;
+                if direct_write && e.get_ava_single("spn") != Some(&spn) {
+                    check_direct_write(au, qs, e, &spn)?;
+                }
+                log_spn_set(au, qs, e, &spn);
                 e.set_ava("spn", btreeset![spn]);
             }
         }
+        clear_spn_exemptions(cand);
+        Ok(())
+    }
+
+    fn post_create(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        cand: &[Entry<EntrySealed, EntryCommitted>],
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        // A new group may add its members to the spn_optout_group.
+        if cand
+            .iter()
+            .any(|e| e.attribute_value_pres("class", &CLASS_GROUP) && e.attribute_pres("member"))
+        {
+            Spn::regenerate_optout(au, qs)?;
+        }
         Ok(())
     }
 
@@ -135,9 +582,38 @@ impl Plugin for Spn {
         cand: &[Entry<EntrySealed, EntryCommitted>],
         _ce: &ModifyEvent,
     ) -> Result<(), OperationError> {
-        // On modify, if changing domain_name on UUID_DOMAIN_INFO
-        //    trigger the spn regen ... which is expensive. Future
-        // TODO #157: will be improvements to modify on large txns.
+        if qs.get_spn_settings().strict_verify {
+            Spn::verify_modified(au, qs, cand)?;
+        }
+
+        // Record the spns this modify changed for the kdc. A domain rename is
+        // recorded too, as the regeneration below modifies each entry in turn.
+        cand.iter().zip(pre_cand.iter()).for_each(|(post, pre)| {
+            let old = pre.get_ava_single("spn");
+            let new = post.get_ava_single("spn");
+            if old != new {
+                ltrace!(au, "plugin_spn: spn changed {:?} -> {:?}", old, new);
+                qs.record_spn_change(SpnChange {
+                    uuid: *post.get_uuid(),
+                    old: old.map(|v| v.to_proto_string_clone()),
+                    new: new.map(|v| v.to_proto_string_clone()),
+                });
+            }
+        });
+
+        // Memberof is updated without running the plugins, so an entry joining or
+        // leaving the spn_optout_group needs its spn updated here.
+        let member_changed = cand.iter().zip(pre_cand.iter()).any(|(post, pre)| {
+            post.attribute_value_pres("class", &CLASS_GROUP)
+                && post.get_ava_set("member") != pre.get_ava_set("member")
+        });
+        if member_changed {
+            Spn::regenerate_optout(au, qs)?;
+        }
+
+        // On modify, if changing domain_name or domain_spn_prefix on UUID_DOMAIN_INFO, or
+        //    spn_scope or spn_optout_group on UUID_SYSTEM_CONFIG, trigger the spn regen ... which
+        // is expensive. Future TODO #157: will be improvements to modify on large txns.
 
         let domain_name_changed =
             cand.iter()
@@ -154,105 +630,692 @@ impl Plugin for Spn {
                     }
                 });
 
-        let domain_name = match domain_name_changed {
-            Some(s) => s,
+        let spn_scope_changed = cand.iter().zip(pre_cand.iter()).any(|(post, pre)| {
+            post.attribute_value_pres("uuid", &PV_UUID_SYSTEM_CONFIG)
+                && (post.get_ava_single("spn_scope") != pre.get_ava_single("spn_scope")
+                    || post.get_ava_single("spn_optout_group")
+                        != pre.get_ava_single("spn_optout_group"))
+        });
+
+        let spn_prefix_changed = cand.iter().zip(pre_cand.iter()).any(|(post, pre)| {
+            post.attribute_value_pres("uuid", &PV_UUID_DOMAIN_INFO)
+                && post.get_ava_set("domain_spn_prefix") != pre.get_ava_set("domain_spn_prefix")
+        });
+
+        match domain_name_changed {
+            Some(domain_name) => ladmin_info!(
+                au,
+                "IMPORTANT!!! Changing domain name to \"{:?}\". THIS MAY TAKE A LONG TIME ...",
+                domain_name
+            ),
+            None if spn_scope_changed => ladmin_info!(
+                au,
+                "IMPORTANT!!! spn_scope or spn_optout_group changed, regenerating spns. THIS MAY TAKE A LONG TIME ..."
+            ),
+            None if spn_prefix_changed => ladmin_info!(
+                au,
+                "IMPORTANT!!! domain_spn_prefix changed, regenerating spns. THIS MAY TAKE A LONG TIME ..."
+            ),
             None => return Ok(()),
         };
 
-        ladmin_info!(
-            au,
-            "IMPORTANT!!! Changing domain name to \"{:?}\". THIS MAY TAKE A LONG TIME ...",
-            domain_name
-        );
+        let class = qs.take_spn_regen_class();
+        if domain_name_changed.is_some()
+            && class.is_none()
+            && qs.get_spn_settings().regen_mode == SpnRegenMode::Available
+        {
+            ladmin_info!(
+                au,
+                "spn_regen_mode is available, spns will be regenerated in chunks once the rename is committed"
+            );
+            qs.defer_spn_regen();
+            return Ok(());
+        }
+        Spn::regenerate(au, qs, class.as_ref())
+    }
+
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        Spn::verify_inconsistent(au, qs, None)
+    }
+
+    fn verify_scoped(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+        scope: &Filter<FilterInvalid>,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        Spn::verify_inconsistent(au, qs, Some(scope.clone()))
+    }
+}
 
+impl Spn {
+    /// Purge the spn of the accounts and groups, or only those with `class`, so that
+    /// pre_modify recreates it from the current domain name.
+    pub(crate) fn regenerate(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        class: Option<&PartialValue>,
+    ) -> Result<(), OperationError> {
+        let filt = Spn::regenerate_filter(au, qs, class)?;
         // All we do is purge spn, and allow the plugin to recreate. Neat! It's also all still
         // within the transaction, just incase!
-        qs.internal_modify(
-            au,
-            &filter!(f_or!([
+        qs.internal_modify(au, &filt, &modlist!([m_purge("spn")]))
+    }
+
+    /// As `regenerate`, but only purge the spns of up to `chunk_size` entries still
+    /// holding the spn of an earlier domain name. The entries are found from the
+    /// current domain name each time, so a rename between chunks is followed.
+    /// Returns the number of entries regenerated.
+    pub(crate) fn regenerate_chunk(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        chunk_size: usize,
+    ) -> Result<usize, OperationError> {
+        let chunk: Vec<Uuid> = Spn::find_awaiting_regen(au, qs)?
+            .into_iter()
+            .take(chunk_size)
+            .collect();
+        if chunk.is_empty() {
+            return Ok(0);
+        }
+        let filt = filter!(f_or(
+            chunk
+                .iter()
+                .map(|u| f_eq("uuid", PartialValue::new_uuidr(u)))
+                .collect()
+        ));
+        qs.internal_modify(au, &filt, &modlist!([m_purge("spn")]))?;
+
+        // Each chunk must make progress, or the next would find the same entries.
+        let stuck: Vec<_> = Spn::find_awaiting_regen(au, qs)?
+            .into_iter()
+            .filter(|u| chunk.contains(u))
+            .collect();
+        if !stuck.is_empty() {
+            ladmin_error!(
+                au,
+                "spn regeneration did not change the spns of {:?}, refusing to continue",
+                stuck
+            );
+            return Err(OperationError::InvalidEntryState);
+        }
+        Ok(chunk.len())
+    }
+
+    // The accounts and groups whose spn is in an earlier domain, which a
+    // regeneration in spn_regen_mode available has yet to reach.
+    fn find_awaiting_regen(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Vec<Uuid>, OperationError> {
+        let filt = Filter::join_parts_and(
+            Spn::regenerate_filter(au, qs, None)?,
+            filter!(f_pres("spn")),
+        );
+        let spngen = qs.get_spn_generator(au)?;
+        let skip_expired = qs.get_spn_skip_expired(au)?;
+        let ct = qs.get_curtime();
+        Ok(qs
+            .internal_search(au, filt)?
+            .into_iter()
+            .filter(|e| {
+                Spn::inconsistency(au, qs, &spngen, e, skip_expired, ct)
+                    .map(|(g_spn, kind)| awaiting_regen(e, g_spn.as_ref(), &kind))
+                    .unwrap_or(false)
+            })
+            .map(|e| *e.get_uuid())
+            .collect())
+    }
+
+    // The accounts and groups whose spns are regenerated, or only those with
+    // `class`, leaving out the spns that are never regenerated.
+    fn regenerate_filter(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        class: Option<&PartialValue>,
+    ) -> Result<Filter<FilterInvalid>, OperationError> {
+        let filt = match class {
+            Some(class) => {
+                ladmin_info!(au, "Only regenerating the spns of {:?}", class);
+                filter!(f_eq("class", class.clone()))
+            }
+            None => filter!(f_or!([
                 f_eq("class", PartialValue::new_class("group")),
                 f_eq("class", PartialValue::new_class("account"))
             ])),
-            &modlist!([m_purge("spn")]),
-        )
+        };
+
+        // Locked spns are kept as they are.
+        let filt = Filter::join_parts_and(
+            filt,
+            filter!(f_andnot(f_eq("spn_locked", PartialValue::new_bool(true)))),
+        );
+
+        // As are the spns of entries from trusted domains.
+        let trusted: Vec<_> = qs
+            .get_spn_settings()
+            .trusted_domains
+            .iter()
+            .map(|d| f_eq("origin_domain", PartialValue::new_iutf8(d)))
+            .collect();
+        let filt = if trusted.is_empty() {
+            filt
+        } else {
+            Filter::join_parts_and(filt, filter!(f_andnot(f_or(trusted))))
+        };
+
+        let filt = if qs.get_spn_skip_expired(au)? {
+            let ct = qs.get_curtime();
+            let expired: Vec<_> = qs
+                .internal_search(
+                    au,
+                    filter!(f_and!([
+                        f_eq("class", PartialValue::new_class("account")),
+                        f_pres("account_expire")
+                    ])),
+                )?
+                .into_iter()
+                .filter(|e| is_expired(e, ct))
+                .map(|e| f_eq("uuid", PartialValue::new_uuidr(e.get_uuid())))
+                .collect();
+            if expired.is_empty() {
+                filt
+            } else {
+                ladmin_info!(
+                    au,
+                    "Skipping spn regeneration of {} expired accounts",
+                    expired.len()
+                );
+                Filter::join_parts_and(filt, filter!(f_andnot(f_or(expired))))
+            }
+        } else {
+            filt
+        };
+        Ok(filt)
     }
 
-    fn verify(
+    /// Purge the spn of the accounts and groups that have joined the spn_optout_group
+    /// but still have an spn, or left it and have none, so that pre_modify removes or
+    /// recreates it.
+    fn regenerate_optout(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<(), OperationError> {
+        let group = match qs.get_spn_optout_group(au)? {
+            Some(group) => PartialValue::new_refer(group),
+            None => return Ok(()),
+        };
+        let filt = filter!(f_and!([
+            f_or!([
+                f_eq("class", PartialValue::new_class("group")),
+                f_eq("class", PartialValue::new_class("account"))
+            ]),
+            f_andnot(f_or!([
+                f_eq("spn_locked", PartialValue::new_bool(true)),
+                f_eq("uuid", PV_UUID_ANONYMOUS.clone())
+            ])),
+            f_or!([
+                f_and!([f_eq("memberof", group.clone()), f_pres("spn")]),
+                f_and!([f_andnot(f_eq("memberof", group)), f_andnot(f_pres("spn"))])
+            ])
+        ]));
+        // Entries without an spn may also be outside of the spn_scope, and have
+        // nothing to update.
+        let spn_scope = qs.get_spn_scope_filter(au)?;
+        let changed: Vec<_> = qs
+            .internal_search(au, filt)?
+            .into_iter()
+            .filter(|e| {
+                trusted_origin(qs, e.as_ref()).is_none()
+                    && in_spn_scope(au, e, spn_scope.as_ref()) != e.attribute_pres("spn")
+            })
+            .map(|e| f_eq("uuid", PartialValue::new_uuidr(e.get_uuid())))
+            .collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        ladmin_info!(
+            au,
+            "spn_optout_group membership changed, updating the spn of {} entries",
+            changed.len()
+        );
+        qs.internal_modify(au, &filter!(f_or(changed)), &modlist!([m_purge("spn")]))
+    }
+
+    fn verify_inconsistent(
         au: &mut AuditScope,
         qs: &QueryServerReadTransaction,
+        scope: Option<Filter<FilterInvalid>>,
     ) -> Vec<Result<(), ConsistencyError>> {
-        // Verify that all items with spn's have valid spns.
-        //   We need to consider the case that an item has a different origin domain too,
-        // so we should be able to verify that *those* spns validate to the trusted domain info
-        // we have been sent also. It's not up to use to generate those though ...
+        // While the spns of a domain rename are regenerated in chunks, those not yet
+        // regenerated are still in the old domain, which is expected. Any other
+        // inconsistency is still reported.
+        let regen_pending = qs.get_spn_regen_pending();
+        let mut r = match Spn::find_inconsistent(au, qs, scope.clone()) {
+            Ok(inconsistent) => inconsistent
+                .into_iter()
+                .filter(|(e, g_spn, kind)| {
+                    !(regen_pending && awaiting_regen(e, g_spn.as_ref(), kind))
+                })
+                .map(|(e, _, kind)| {
+                    // A missing spn is expected, as is a reserved one or one with
+                    // invalid characters when the configuration changed after the
+                    // entry existed, and an untrusted one from replication. The
+                    // others indicate a bug.
+                    debug_assert!(
+                        kind == SpnInconsistency::Missing
+                            || kind == SpnInconsistency::Reserved
+                            || kind == SpnInconsistency::InvalidCharacters
+                            || kind == SpnInconsistency::Untrusted
+                    );
+                    match (&kind, e.get_ava_single("spn").and_then(|v| v.to_spn())) {
+                        (SpnInconsistency::Untrusted, Some((_, realm))) => Err(
+                            ConsistencyError::UntrustedSpn(e.get_id(), realm.to_string()),
+                        ),
+                        _ => Err(ConsistencyError::InvalidSpn(e.get_id(), kind)),
+                    }
+                })
+                .collect(),
+            Err(e) => vec![Err(e)],
+        };
+        r.extend(Spn::find_orphaned(au, qs, scope));
+        r
+    }
 
-        let domain_name = match qs
-            .get_domain_name(au)
-            .map_err(|_| Err(ConsistencyError::QueryServerSearchFailure))
-        {
-            Ok(dn) => dn,
-            Err(e) => return vec![e],
+    // Find entries holding an spn they should not have at all, such as after a
+    // crash or replication leaves an spn behind on an entry that is no longer an
+    // account or group, or on a tombstone. find_inconsistent only checks accounts
+    // and groups, and regenerating won't remove these, so they are reported
+    // separately.
+    fn find_orphaned(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+        scope: Option<Filter<FilterInvalid>>,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        // Tombstones are hidden from a normal search.
+        let filt_in = filter_all!(f_pres("spn"));
+        let filt_in = match scope {
+            Some(scope) => Filter::join_parts_and(filt_in, scope),
+            None => filt_in,
+        };
+
+        let all_cand = match qs.internal_search(au, filt_in) {
+            Ok(c) => c,
+            Err(_) => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
         };
 
+        all_cand
+            .into_iter()
+            .filter_map(|e| {
+                let kind = if e.attribute_value_pres("class", &CLASS_TOMBSTONE) {
+                    SpnOrphan::Tombstone
+                } else if !e.attribute_value_pres("class", &CLASS_GROUP)
+                    && !e.attribute_value_pres("class", &CLASS_ACCOUNT)
+                {
+                    SpnOrphan::Ineligible
+                } else {
+                    return None;
+                };
+                ladmin_error!(
+                    au,
+                    "Entry {:?} holds an orphaned SPN {:?} ({:?})",
+                    e.get_uuid(),
+                    e.get_ava_single("spn"),
+                    kind
+                );
+                Some(Err(ConsistencyError::OrphanedSpn(e.get_id(), kind)))
+            })
+            .collect()
+    }
+
+    // Find the accounts and groups in scope whose spn is missing, doesn't match
+    // the current domain name or is reserved, along with the spn they should have. This is shared
+    // by verify and the spn fsck so they always agree. When scope is given, only the
+    // entries matching it are checked.
+    pub(crate) fn find_inconsistent(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+        scope: Option<Filter<FilterInvalid>>,
+    ) -> Result<
+        Vec<(
+            Entry<EntrySealed, EntryCommitted>,
+            Option<Value>,
+            SpnInconsistency,
+        )>,
+        ConsistencyError,
+    > {
+        // Verify that all items with spn's have valid spns.
+        //   An item may have a different origin domain too, in which case its spn is
+        // validated against the trusted domains instead. It's not up to us to generate
+        // those though ...
+
+        let spngen = qs
+            .get_spn_generator(au)
+            .map_err(|_| ConsistencyError::QueryServerSearchFailure)?;
+
         let filt_in = filter!(f_or!([
             f_eq("class", PartialValue::new_class("group")),
             f_eq("class", PartialValue::new_class("account"))
         ]));
 
-        let all_cand = match qs
-            .internal_search(au, filt_in)
-            .map_err(|_| Err(ConsistencyError::QueryServerSearchFailure))
-        {
-            Ok(all_cand) => all_cand,
-            Err(e) => return vec![e],
+        let filt_in = match qs.get_spn_scope_filter(au) {
+            Ok(Some(scope)) => Filter::join_parts_and(filt_in, scope),
+            Ok(None) => filt_in,
+            Err(_) => return Err(ConsistencyError::QueryServerSearchFailure),
+        };
+
+        let filt_in = match scope {
+            Some(scope) => Filter::join_parts_and(filt_in, scope),
+            None => filt_in,
         };
 
+        let all_cand = qs
+            .internal_search(au, filt_in)
+            .map_err(|_| ConsistencyError::QueryServerSearchFailure)?;
+
+        let skip_expired = qs
+            .get_spn_skip_expired(au)
+            .map_err(|_| ConsistencyError::QueryServerSearchFailure)?;
+        let ct = qs.get_curtime();
+
         let mut r = Vec::new();
 
         for e in all_cand {
-            let g_spn = match e.generate_spn(domain_name.as_str()) {
-                Some(s) => s,
-                None => {
+            if let Some((g_spn, kind)) = Spn::inconsistency(au, qs, &spngen, &e, skip_expired, ct) {
+                r.push((e, g_spn, kind));
+            }
+        }
+        Ok(r)
+    }
+
+    // Check a single account or group, returning the spn it should have if its
+    // current spn is inconsistent.
+    fn inconsistency<'a, QS: QueryServerTransaction<'a>, STATE>(
+        au: &mut AuditScope,
+        qs: &QS,
+        spngen: &SpnGenerator,
+        e: &Entry<EntrySealed, STATE>,
+        skip_expired: bool,
+        ct: Duration,
+    ) -> Option<(Option<Value>, SpnInconsistency)> {
+        if let Some(expected) = anonymous_spn(qs, e) {
+            let current = e.get_ava_single("spn");
+            if current == expected.as_ref() {
+                return None;
+            }
+            ladmin_error!(
+                au,
+                "Entry {:?} SPN does not match the configured anonymous_spn s {:?} != ex {:?}",
+                e.get_uuid(),
+                current,
+                expected,
+            );
+            let kind = if current.is_none() {
+                SpnInconsistency::Missing
+            } else {
+                SpnInconsistency::Mismatch
+            };
+            return Some((expected, kind));
+        }
+
+        if let Some(domain) = trusted_origin(qs, e) {
+            let current = e.get_ava_single("spn");
+            return match current.and_then(|v| v.to_spn()) {
+                Some((_, realm)) if realm.eq_ignore_ascii_case(domain) => {
+                    ltrace!(
+                        au,
+                        "Entry {:?} spn is from its trusted origin {}",
+                        e.get_uuid(),
+                        domain
+                    );
+                    None
+                }
+                _ => {
                     ladmin_error!(
                         au,
-                        "Entry {:?} SPN could not be generated (missing name!?)",
-                        e.get_uuid()
+                        "Entry {:?} SPN {:?} is not from its origin domain {}",
+                        e.get_uuid(),
+                        current,
+                        domain
                     );
-                    debug_assert!(false);
-                    r.push(Err(ConsistencyError::InvalidSpn(e.get_id())));
-                    continue;
+                    let kind = if current.is_none() {
+                        SpnInconsistency::Missing
+                    } else {
+                        SpnInconsistency::Untrusted
+                    };
+                    Some((None, kind))
                 }
             };
-            match e.get_ava_single("spn") {
-                Some(r_spn) => {
-                    ltrace!(au, "verify spn: s {:?} == ex {:?} ?", r_spn, g_spn);
-                    if *r_spn != g_spn {
+        }
+
+        let g_spn = match spngen.generate(e) {
+            Some(s) => s,
+            None => {
+                ladmin_error!(
+                    au,
+                    "Entry {:?} SPN could not be generated (missing name!?)",
+                    e.get_uuid()
+                );
+                return Some((None, SpnInconsistency::MissingName));
+            }
+        };
+        match e.get_ava_single("spn") {
+            Some(r_spn) => {
+                ltrace!(au, "verify spn: s {:?} == ex {:?} ?", r_spn, g_spn);
+                if locked_spn(e).is_some() {
+                    // A locked spn was chosen by an administrator, so it isn't
+                    // expected to match the generated one.
+                    ltrace!(au, "Entry {:?} spn is locked", e.get_uuid());
+                } else if let Some(realm) = foreign_realm(qs, spngen, r_spn) {
+                    if !qs
+                        .get_spn_settings()
+                        .trusted_domains
+                        .contains(&realm.to_lowercase())
+                    {
                         ladmin_error!(
                             au,
-                            "Entry {:?} SPN does not match expected s {:?} != ex {:?}",
+                            "Entry {:?} SPN {:?} is from the untrusted domain {}",
                             e.get_uuid(),
                             r_spn,
-                            g_spn,
+                            realm
                         );
-                        debug_assert!(false);
-                        r.push(Err(ConsistencyError::InvalidSpn(e.get_id())))
+                        return Some((Some(g_spn), SpnInconsistency::Untrusted));
+                    }
+                    ltrace!(
+                        au,
+                        "Entry {:?} spn is from the trusted domain {}",
+                        e.get_uuid(),
+                        realm
+                    );
+                } else if !spngen.validate(e, r_spn) {
+                    // Expired accounts are expected to retain an older spn.
+                    if skip_expired && is_expired(e, ct) {
+                        ltrace!(au, "Entry {:?} is expired, ignoring spn", e.get_uuid());
+                        return None;
                     }
+                    ladmin_error!(
+                        au,
+                        "Entry {:?} SPN does not match expected s {:?} != ex {:?}",
+                        e.get_uuid(),
+                        r_spn,
+                        g_spn,
+                    );
+                    return Some((Some(g_spn), SpnInconsistency::Mismatch));
                 }
-                None => {
-                    ladmin_error!(au, "Entry {:?} does not contain an SPN", e.get_uuid(),);
-                    r.push(Err(ConsistencyError::InvalidSpn(e.get_id())))
+
+                if is_reserved(qs, r_spn) {
+                    ladmin_error!(
+                        au,
+                        "Entry {:?} has the reserved SPN {:?}",
+                        e.get_uuid(),
+                        r_spn
+                    );
+                    Some((Some(g_spn), SpnInconsistency::Reserved))
+                } else if qs.get_spn_settings().charset_policy == SpnCharsetPolicy::Reject
+                    && !spngen.invalid_kerberos_chars(e, r_spn).is_empty()
+                {
+                    ladmin_error!(
+                        au,
+                        "Entry {:?} SPN {:?} has characters kerberos doesn't allow",
+                        e.get_uuid(),
+                        r_spn
+                    );
+                    Some((Some(g_spn), SpnInconsistency::InvalidCharacters))
+                } else {
+                    None
                 }
             }
+            None => {
+                ladmin_error!(au, "Entry {:?} does not contain an SPN", e.get_uuid(),);
+                Some((Some(g_spn), SpnInconsistency::Missing))
+            }
         }
-        r
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::prelude::*;
 
-    #[test]
+    // Measure how quickly spns can be generated and verified, so that an administrator
+    // can estimate how long a domain rename will take before starting one. The entries
+    // are synthetic and only exist in memory, so nothing is written.
+    pub(crate) fn bench(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+        count: usize,
+    ) -> Result<SpnBenchResult, OperationError> {
+        if count == 0 || count > SPN_BENCH_COUNT_MAX {
+            ladmin_error!(
+                au,
+                "spn bench count must be between 1 and {}",
+                SPN_BENCH_COUNT_MAX
+            );
+            return Err(OperationError::InvalidRequestState);
+        }
+
+        let spngen = qs.get_spn_generator(au)?;
+
+        let filt_in = filter!(f_or!([
+            f_eq("class", PartialValue::new_class("group")),
+            f_eq("class", PartialValue::new_class("account"))
+        ]));
+        let directory_size = qs.internal_search(au, filt_in)?.len();
+
+        let entries: Vec<Entry<EntryInit, EntryNew>> = (0..count)
+            .map(|i| {
+                let mut e = Entry::new();
+                e.add_ava("class", Value::new_class("object"));
+                e.add_ava("class", Value::new_class("account"));
+                e.add_ava("name", Value::new_iname(&format!("spn_bench_{}", i)));
+                e
+            })
+            .collect();
+
+        let start = Instant::now();
+        let spns: Vec<Option<Value>> = entries.iter().map(|e| spngen.generate(e)).collect();
+        let generate_time = start.elapsed();
+
+        let start = Instant::now();
+        let valid = entries
+            .iter()
+            .zip(spns.iter())
+            .filter(|(e, spn)| match spn {
+                Some(spn) => spngen.validate(*e, spn) && !is_reserved(qs, spn),
+                None => false,
+            })
+            .count();
+        let verify_time = start.elapsed();
+
+        if valid != count {
+            ladmin_error!(
+                au,
+                "spn bench: only {} of {} synthetic spns verified",
+                valid,
+                count
+            );
+            return Err(OperationError::InvalidState);
+        }
+
+        let per_sec = |d: Duration| count as f64 / d.as_secs_f64().max(f64::EPSILON);
+        ladmin_info!(
+            au,
+            "spn bench: {} entries, generated in {:?}, verified in {:?}",
+            count,
+            generate_time,
+            verify_time
+        );
+
+        Ok(SpnBenchResult {
+            count,
+            generate_per_sec: per_sec(generate_time),
+            verify_per_sec: per_sec(verify_time),
+            directory_size,
+        })
+    }
+
+    // The strict form of verify, limited to the accounts and groups in scope that
+    // a modify has just written. Any inconsistency fails the modify, so that a
+    // generation bug is found at write time rather than by a later verify.
+    fn verify_modified(
+        au: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        cand: &[Entry<EntrySealed, EntryCommitted>],
+    ) -> Result<(), OperationError> {
+        let cand: Vec<_> = cand
+            .iter()
+            .filter(|e| {
+                e.attribute_value_pres("class", &CLASS_GROUP)
+                    || e.attribute_value_pres("class", &CLASS_ACCOUNT)
+            })
+            .collect();
+        if cand.is_empty() {
+            return Ok(());
+        }
+
+        let spngen = qs.get_spn_generator(au)?;
+        let spn_scope = qs.get_spn_scope_filter(au)?;
+        let skip_expired = qs.get_spn_skip_expired(au)?;
+        let ct = qs.get_curtime();
+
+        let mut r = Vec::new();
+
+        for e in cand {
+            if !in_spn_scope(au, e, spn_scope.as_ref()) {
+                continue;
+            }
+            if let Some((_, kind)) = Spn::inconsistency(au, qs, &spngen, e, skip_expired, ct) {
+                r.push(Err(ConsistencyError::InvalidSpn(e.get_id(), kind)));
+            }
+        }
+
+        if r.is_empty() {
+            Ok(())
+        } else {
+            ladmin_error!(
+                au,
+                "plugin_spn: strict verify found {} inconsistent spns, refusing modify",
+                r.len()
+            );
+            Err(OperationError::ConsistencyError(r))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{
+        AnonymousSpn, DuplicateNamePolicy, SpnCharsetPolicy, SpnDirectWrite, SpnIdnMode,
+        SpnRegenMode,
+    };
+    use crate::event::ModifyEvent;
+    use crate::plugins::spn::Spn;
+    use crate::plugins::Plugin;
+    use crate::prelude::*;
+    use crate::server::SpnSettings;
+    use crate::spn_notify::{SpnChange, SpnNotifier};
+    use async_std::task;
+    use kanidm_proto::v1::{ConsistencyError, PluginError, SpnInconsistency, SpnOrphan};
+    use std::sync::Arc;
+
+    #[test]
     fn test_spn_generate_create() {
         // on create don't provide
         let e: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
@@ -397,4 +1460,1371 @@ mod tests {
             server_txn.commit(au).expect("Must not fail");
         });
     }
+
+    #[test]
+    fn test_spn_idn_punycode() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let mut punycode = server.clone();
+            punycode.set_spn_settings(SpnSettings {
+                idn_mode: SpnIdnMode::Punycode,
+                ..Default::default()
+            });
+            let admin_spn = |au: &mut AuditScope, txn: &QueryServerWriteTransaction| {
+                txn.internal_search_uuid(au, &UUID_ADMIN)
+                    .expect("must not fail")
+                    .get_ava_single("spn")
+                    .cloned()
+                    .expect("must not fail")
+            };
+
+            let server_txn = punycode.write(duration_from_epoch_now());
+            server_txn
+                .domain_rename(au, "bücher.example")
+                .expect("should not fail!");
+            assert!(
+                admin_spn(au, &server_txn) == Value::new_spn_str("admin", "xn--bcher-kva.example")
+            );
+            server_txn.commit(au).expect("Must not fail");
+            // Generation and verification agree in the same mode, but not in another.
+            assert!(Spn::verify(au, &punycode.read()).is_empty());
+            assert!(!Spn::verify(au, &server.read()).is_empty());
+
+            // Unicode mode is the domain name as it is.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                    &modlist!([m_purge("spn")]),
+                )
+                .expect("must not fail");
+            assert!(admin_spn(au, &server_txn) == Value::new_spn_str("admin", "bücher.example"));
+
+            // Leave an ascii domain, which is the same in both modes.
+            server_txn
+                .domain_rename(au, "new.example.com")
+                .expect("should not fail!");
+            server_txn.commit(au).expect("Must not fail");
+            assert!(Spn::verify(au, &punycode.read()).is_empty());
+        });
+    }
+
+    #[test]
+    fn test_spn_charset_policy() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let account = |name: &str| -> Entry<EntryInit, EntryNew> {
+                Entry::unsafe_from_entry_str(&format!(
+                    r#"{{
+                    "attrs": {{
+                        "class": ["account"],
+                        "name": ["{}"],
+                        "description": ["svc"],
+                        "displayname": ["svc"]
+                    }}
+                }}"#,
+                    name
+                ))
+            };
+            let spn_of = |au: &mut AuditScope, txn: &QueryServerWriteTransaction, name: &str| {
+                txn.internal_search(au, filter!(f_eq("name", PartialValue::new_iname(name))))
+                    .expect("must not fail")
+                    .pop()
+                    .and_then(|e| e.get_ava_single("spn").cloned())
+            };
+
+            // Warn is the default, and generates the spn as it is.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_create(au, vec![account("svc:warn")])
+                .expect("must not fail");
+            assert!(
+                spn_of(au, &server_txn, "svc:warn")
+                    == Some(Value::new_spn_str("svc:warn", "example.com"))
+            );
+            server_txn.commit(au).expect("Must not fail");
+            assert!(Spn::verify(au, &server.read()).is_empty());
+
+            // Reject refuses the entry, and reports the existing one.
+            let mut reject = server.clone();
+            reject.set_spn_settings(SpnSettings {
+                charset_policy: SpnCharsetPolicy::Reject,
+                ..Default::default()
+            });
+            let server_txn = reject.write(duration_from_epoch_now());
+            let r = server_txn.internal_create(au, vec![account("svc:reject")]);
+            match r {
+                Err(OperationError::Plugin(PluginError::SpnInvalidCharacters(spn))) => {
+                    assert!(spn == "svc:reject@example.com")
+                }
+                _ => panic!("create should have been rejected"),
+            }
+            server_txn
+                .internal_create(au, vec![account("svc-reject.ok")])
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+            let r = Spn::verify(au, &reject.read());
+            assert!(r.len() == 1);
+            assert!(matches!(
+                r[0],
+                Err(ConsistencyError::InvalidSpn(
+                    _,
+                    SpnInconsistency::InvalidCharacters
+                ))
+            ));
+
+            // Sanitize replaces the characters in the spn, but not the name.
+            let mut sanitize = server.clone();
+            sanitize.set_spn_settings(SpnSettings {
+                charset_policy: SpnCharsetPolicy::Sanitize,
+                ..Default::default()
+            });
+            let server_txn = sanitize.write(duration_from_epoch_now());
+            server_txn
+                .internal_create(au, vec![account("svc:sanitize")])
+                .expect("must not fail");
+            assert!(
+                spn_of(au, &server_txn, "svc:sanitize")
+                    == Some(Value::new_spn_str("svc_sanitize", "example.com"))
+            );
+            server_txn.commit(au).expect("Must not fail");
+            // An spn generated before is inconsistent until it's regenerated.
+            assert!(!Spn::verify(au, &sanitize.read()).is_empty());
+            let server_txn = sanitize.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("name", PartialValue::new_iname("svc:warn"))),
+                    &modlist!([m_purge("spn")]),
+                )
+                .expect("must not fail");
+            assert!(
+                spn_of(au, &server_txn, "svc:warn")
+                    == Some(Value::new_spn_str("svc_warn", "example.com"))
+            );
+            server_txn.commit(au).expect("Must not fail");
+            assert!(Spn::verify(au, &sanitize.read()).is_empty());
+        });
+    }
+
+    #[test]
+    fn test_spn_regen_domain_rename_scoped() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let spn_of = |au: &mut AuditScope, u: &Uuid| {
+                server_txn
+                    .internal_search_uuid(au, u)
+                    .expect("must not fail")
+                    .get_ava_single("spn")
+                    .cloned()
+                    .expect("must not fail")
+            };
+
+            assert!(server_txn
+                .domain_rename_scoped(au, "new.example.com", "recycled")
+                .is_err());
+
+            // Only the accounts are regenerated in the first pass.
+            server_txn
+                .domain_rename_scoped(au, "new.example.com", "account")
+                .expect("should not fail!");
+            assert!(spn_of(au, &UUID_ADMIN) == Value::new_spn_str("admin", "new.example.com"));
+            assert!(
+                spn_of(au, &UUID_SYSTEM_ADMINS)
+                    == Value::new_spn_str("system_admins", "example.com")
+            );
+
+            // The domain name is already changed, so the second pass only regenerates.
+            server_txn
+                .domain_rename_scoped(au, "new.example.com", "group")
+                .expect("should not fail!");
+            assert!(
+                spn_of(au, &UUID_SYSTEM_ADMINS)
+                    == Value::new_spn_str("system_admins", "new.example.com")
+            );
+
+            server_txn.commit(au).expect("Must not fail");
+        });
+    }
+
+    #[test]
+    fn test_spn_regen_mode() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let spn_of = |au: &mut AuditScope, qs: &QueryServer, u: &Uuid| {
+                qs.read()
+                    .internal_search_uuid(au, u)
+                    .expect("must not fail")
+                    .get_ava_single("spn")
+                    .cloned()
+                    .expect("must not fail")
+            };
+            let is_consistent = |au: &mut AuditScope, qs: &QueryServer| {
+                Spn::verify(au, &qs.read()).iter().all(|r| r.is_ok())
+            };
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_create(
+                    au,
+                    vec![entry_init!(
+                        ("class", Value::new_class("object")),
+                        ("class", Value::new_class("group")),
+                        ("name", Value::new_iname("testgroup"))
+                    )],
+                )
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+
+            // In strict mode, the spns are regenerated by the rename itself.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .domain_rename(au, "strict.example.com")
+                .expect("should not fail!");
+            server_txn.commit(au).expect("Must not fail");
+            assert!(
+                spn_of(au, server, &UUID_ADMIN)
+                    == Value::new_spn_str("admin", "strict.example.com")
+            );
+            assert!(!server.spn_regen_pending());
+            assert!(is_consistent(au, server));
+
+            // In available mode the rename commits first, leaving the old spns.
+            let mut available = server.clone();
+            available.set_spn_settings(SpnSettings {
+                regen_mode: SpnRegenMode::Available,
+                regen_chunk_size: 2,
+                ..Default::default()
+            });
+            let server_txn = available.write(duration_from_epoch_now());
+            server_txn
+                .domain_rename(au, "available.example.com")
+                .expect("should not fail!");
+            server_txn.commit(au).expect("Must not fail");
+            assert!(
+                spn_of(au, &available, &UUID_ADMIN)
+                    == Value::new_spn_str("admin", "strict.example.com")
+            );
+            assert!(available.spn_regen_pending());
+            // Which verify expects until they're regenerated.
+            assert!(is_consistent(au, &available));
+
+            // Then they're regenerated two at a time.
+            let regenerated =
+                task::block_on(available.spn_regenerate_chunked(au)).expect("must not fail");
+            assert!(regenerated > 2);
+            assert!(!available.spn_regen_pending());
+            assert!(
+                spn_of(au, &available, &UUID_ADMIN)
+                    == Value::new_spn_str("admin", "available.example.com")
+            );
+            let testgroup = available
+                .read()
+                .internal_search(
+                    au,
+                    filter!(f_eq("name", PartialValue::new_iname("testgroup"))),
+                )
+                .expect("must not fail");
+            assert!(
+                testgroup[0].get_ava_single("spn")
+                    == Some(&Value::new_spn_str("testgroup", "available.example.com"))
+            );
+            assert!(is_consistent(au, &available));
+            // Nothing is left to do.
+            assert!(
+                task::block_on(available.spn_regenerate_chunked(au)).expect("must not fail") == 0
+            );
+        });
+    }
+
+    #[test]
+    fn test_spn_verify_trusted_domains() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            // Only the accounts are regenerated, so the groups keep spns from the
+            // old domain, as though they were replicated from it.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .domain_rename_scoped(au, "new.example.com", "account")
+                .expect("should not fail!");
+            server_txn.commit(au).expect("Must not fail");
+
+            // Without trusted domains, they are compared to the local spn.
+            let r = Spn::verify(au, &server.read());
+            assert!(!r.is_empty());
+            assert!(r.iter().all(|r| matches!(
+                r,
+                Err(ConsistencyError::InvalidSpn(_, SpnInconsistency::Mismatch))
+            )));
+
+            let mut trusted = server.clone();
+            trusted.set_spn_settings(SpnSettings {
+                trusted_domains: std::iter::once("example.com".to_string()).collect(),
+                ..Default::default()
+            });
+            assert!(Spn::verify(au, &trusted.read()).is_empty());
+
+            let mut untrusted = server.clone();
+            untrusted.set_spn_settings(SpnSettings {
+                trusted_domains: std::iter::once("other.example.com".to_string()).collect(),
+                ..Default::default()
+            });
+            let r = Spn::verify(au, &untrusted.read());
+            assert!(!r.is_empty());
+            assert!(r.iter().all(|r| match r {
+                Err(ce @ ConsistencyError::UntrustedSpn(_, realm)) => {
+                    realm == "example.com"
+                        && ce.remediation() == Some(SpnInconsistency::Untrusted.remediation())
+                }
+                _ => false,
+            }));
+
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .domain_rename_scoped(au, "new.example.com", "group")
+                .expect("should not fail!");
+            server_txn.commit(au).expect("Must not fail");
+        });
+    }
+
+    #[test]
+    fn test_spn_locked_domain_rename() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let filt = filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN)));
+            let locked = Value::new_spn_str("host", "legacy.example.com");
+            let get_spn = |au: &mut AuditScope, txn: &QueryServerWriteTransaction| {
+                txn.internal_search_uuid(au, &UUID_ADMIN)
+                    .expect("must not fail")
+                    .get_ava_single("spn")
+                    .cloned()
+                    .expect("must not fail")
+            };
+
+            server_txn
+                .internal_modify(
+                    au,
+                    &filt,
+                    &modlist!([
+                        m_pres("spn_locked", &Value::new_bool(true)),
+                        m_purge("spn"),
+                        m_pres("spn", &locked)
+                    ]),
+                )
+                .expect("must not fail");
+            assert!(get_spn(au, &server_txn) == locked);
+
+            // The locked spn is kept through a domain rename, and is consistent.
+            server_txn
+                .domain_rename(au, "new.example.com")
+                .expect("must not fail");
+            assert!(get_spn(au, &server_txn) == locked);
+            let e = server_txn
+                .internal_search_uuid(au, &UUID_ADMIN)
+                .expect("must not fail");
+            assert!(Spn::verify_modified(au, &server_txn, &[e]).is_ok());
+
+            // Removing the lock generates the spn again, in the new domain.
+            server_txn
+                .internal_modify(
+                    au,
+                    &filt,
+                    &modlist!([m_purge("spn_locked"), m_purge("spn")]),
+                )
+                .expect("must not fail");
+            assert!(get_spn(au, &server_txn) == Value::new_spn_str("admin", "new.example.com"));
+
+            server_txn.commit(au).expect("Must not fail");
+        });
+    }
+
+    #[test]
+    fn test_spn_trusted_origin_kept() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let mut trusted = server.clone();
+            trusted.set_spn_settings(SpnSettings {
+                trusted_domains: std::iter::once("trusted.example.com".to_string()).collect(),
+                ..Default::default()
+            });
+            let server_txn = trusted.write(duration_from_epoch_now());
+
+            let e_trusted: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["account"],
+                    "name": ["replicated"],
+                    "origin_domain": ["trusted.example.com"],
+                    "spn": ["replicated@trusted.example.com"],
+                    "displayname": ["replicated"]
+                }
+            }"#,
+            );
+            let e_untrusted: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["account"],
+                    "name": ["unreplicated"],
+                    "origin_domain": ["other.example.com"],
+                    "spn": ["unreplicated@other.example.com"],
+                    "displayname": ["unreplicated"]
+                }
+            }"#,
+            );
+            server_txn
+                .internal_create(au, vec![e_trusted, e_untrusted])
+                .expect("must not fail");
+
+            let spn_of = |au: &mut AuditScope, name: &str| {
+                server_txn
+                    .internal_search(au, filter!(f_eq("name", PartialValue::new_iname(name))))
+                    .expect("must not fail")
+                    .pop()
+                    .expect("must not fail")
+                    .get_ava_single("spn")
+                    .cloned()
+            };
+            let trusted_spn = Value::new_spn_str("replicated", "trusted.example.com");
+            assert!(spn_of(au, "replicated") == Some(trusted_spn.clone()));
+            // An origin that isn't trusted gets a local spn as usual.
+            assert!(
+                spn_of(au, "unreplicated")
+                    == Some(Value::new_spn_str("unreplicated", "example.com"))
+            );
+
+            // The spn from the origin is kept when the entry is modified, or the
+            // local domain is renamed.
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("name", PartialValue::new_iname("replicated"))),
+                    &modlist!([
+                        m_purge("displayname"),
+                        m_pres("displayname", &Value::new_utf8s("renamed"))
+                    ]),
+                )
+                .expect("must not fail");
+            assert!(spn_of(au, "replicated") == Some(trusted_spn.clone()));
+            server_txn
+                .domain_rename(au, "new.example.com")
+                .expect("must not fail");
+            assert!(spn_of(au, "replicated") == Some(trusted_spn));
+            server_txn.commit(au).expect("Must not fail");
+            assert!(Spn::verify(au, &trusted.read()).is_empty());
+
+            // Once the origin is no longer trusted, the spn is checked as any other.
+            let r = Spn::verify(au, &server.read());
+            assert!(matches!(
+                r.as_slice(),
+                [Err(ConsistencyError::UntrustedSpn(_, realm))] if realm == "trusted.example.com"
+            ));
+
+            // Removing the origin has the spn generated locally.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("name", PartialValue::new_iname("replicated"))),
+                    &modlist!([m_purge("origin_domain"), m_purge("spn")]),
+                )
+                .expect("must not fail");
+            let e = server_txn
+                .internal_search(
+                    au,
+                    filter!(f_eq("name", PartialValue::new_iname("replicated"))),
+                )
+                .expect("must not fail")
+                .pop()
+                .expect("must not fail");
+            assert!(
+                e.get_ava_single("spn")
+                    == Some(&Value::new_spn_str("replicated", "new.example.com"))
+            );
+            server_txn.commit(au).expect("Must not fail");
+        });
+    }
+
+    #[test]
+    fn test_spn_verify_missing_remediation() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let e_pre = server_txn
+                .internal_search_uuid(au, &UUID_ADMIN)
+                .expect("must not fail");
+
+            // Bypass the plugins to remove the spn.
+            let mut e_broken = unsafe { e_pre.clone().into_invalid() };
+            e_broken.purge_ava("spn");
+            let e_broken = unsafe { e_broken.into_sealed_committed() };
+            server_txn
+                .get_be_txn()
+                .modify(au, &[e_pre], &[e_broken])
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+
+            let server_r_txn = server.read();
+            let r = Spn::verify(au, &server_r_txn);
+            assert!(r.len() == 1);
+            match &r[0] {
+                Err(ce) => {
+                    assert!(matches!(
+                        ce,
+                        ConsistencyError::InvalidSpn(_, SpnInconsistency::Missing)
+                    ));
+                    assert!(ce.remediation() == Some(SpnInconsistency::Missing.remediation()));
+                }
+                Ok(_) => panic!("verify should have failed"),
+            }
+            std::mem::drop(server_r_txn);
+
+            // Following the remediation resolves the inconsistency.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                    &modlist!([m_purge("spn")]),
+                )
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+        });
+    }
+
+    #[test]
+    fn test_spn_verify_orphaned() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let group = |name: &str| {
+                entry_init!(
+                    ("class", Value::new_class("object")),
+                    ("class", Value::new_class("group")),
+                    ("name", Value::new_iname(name))
+                )
+            };
+            server_txn
+                .internal_create(au, vec![group("testgroup_a"), group("testgroup_b")])
+                .expect("must not fail");
+
+            // Bypass the plugins to leave an spn on an entry that is no longer
+            // a group, and on a tombstone.
+            let mut orphan = |name: &str, classes: Vec<&str>| {
+                let e_pre = server_txn
+                    .internal_search(au, filter!(f_eq("name", PartialValue::new_iname(name))))
+                    .expect("must not fail")
+                    .pop()
+                    .expect("must not fail");
+                let mut e_broken = unsafe { e_pre.clone().into_invalid() };
+                e_broken.set_ava("class", classes.into_iter().map(Value::new_class).collect());
+                let e_broken = unsafe { e_broken.into_sealed_committed() };
+                server_txn
+                    .get_be_txn()
+                    .modify(au, &[e_pre], &[e_broken])
+                    .expect("must not fail");
+            };
+            orphan("testgroup_a", vec!["object"]);
+            orphan("testgroup_b", vec!["object", "tombstone"]);
+            server_txn.commit(au).expect("Must not fail");
+
+            let server_r_txn = server.read();
+            let r = Spn::verify(au, &server_r_txn);
+            assert!(r.len() == 2);
+            // These are distinct from a mismatch, and carry their own remediation.
+            assert!(r.iter().any(|r| matches!(
+                r,
+                Err(ConsistencyError::OrphanedSpn(_, SpnOrphan::Ineligible))
+            )));
+            assert!(r.iter().any(|r| matches!(
+                r,
+                Err(ConsistencyError::OrphanedSpn(_, SpnOrphan::Tombstone))
+            )));
+            assert!(r
+                .iter()
+                .all(|r| !matches!(r, Err(ConsistencyError::InvalidSpn(_, _)))));
+            assert!(r
+                .iter()
+                .all(|r| r.as_ref().err().and_then(|ce| ce.remediation()).is_some()));
+        });
+    }
+
+    #[test]
+    fn test_spn_scope_restricts_generation() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            // Only accounts described as kerberos are given an spn.
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_SYSTEM_CONFIG))),
+                    &modlist!([m_pres(
+                        "spn_scope",
+                        &Value::new_json_filter(r#"{"eq": ["description", "kerberos"]}"#)
+                            .expect("must not fail")
+                    )]),
+                )
+                .expect("must not fail");
+
+            let e_in: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["account"],
+                    "name": ["inscope"],
+                    "description": ["kerberos"],
+                    "displayname": ["inscope"]
+                }
+            }"#,
+            );
+            let e_out: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["account"],
+                    "name": ["outscope"],
+                    "spn": ["outscope@example.com"],
+                    "description": ["other"],
+                    "displayname": ["outscope"]
+                }
+            }"#,
+            );
+            server_txn
+                .internal_create(au, vec![e_in, e_out])
+                .expect("must not fail");
+
+            let e_in = server_txn
+                .internal_search(
+                    au,
+                    filter!(f_eq("name", PartialValue::new_iname("inscope"))),
+                )
+                .expect("must not fail")
+                .pop()
+                .expect("must not fail");
+            assert!(e_in.attribute_value_pres(
+                "spn",
+                &PartialValue::new_spn_s("inscope@example.com").expect("must not fail")
+            ));
+
+            // Even a provided spn is removed when outside of the scope.
+            let e_out = server_txn
+                .internal_search(
+                    au,
+                    filter!(f_eq("name", PartialValue::new_iname("outscope"))),
+                )
+                .expect("must not fail")
+                .pop()
+                .expect("must not fail");
+            assert!(!e_out.attribute_pres("spn"));
+            assert!(!e_out.attribute_pres("spn_index"));
+            assert!(e_out.attribute_value_pres("class", &PartialValue::new_class("spn_exempt")));
+
+            // Moving the entry into scope generates the spn.
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("name", PartialValue::new_iname("outscope"))),
+                    &modlist!([
+                        m_purge("description"),
+                        m_pres("description", &Value::new_utf8s("kerberos"))
+                    ]),
+                )
+                .expect("must not fail");
+            let e_out = server_txn
+                .internal_search(
+                    au,
+                    filter!(f_eq("name", PartialValue::new_iname("outscope"))),
+                )
+                .expect("must not fail")
+                .pop()
+                .expect("must not fail");
+            assert!(e_out.attribute_value_pres(
+                "spn",
+                &PartialValue::new_spn_s("outscope@example.com").expect("must not fail")
+            ));
+            assert!(!e_out.attribute_value_pres("class", &PartialValue::new_class("spn_exempt")));
+
+            // Claiming the exemption doesn't avoid the spn of an entry in scope.
+            let e_exempt: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["account", "spn_exempt"],
+                    "name": ["exempt"],
+                    "description": ["kerberos"],
+                    "displayname": ["exempt"]
+                }
+            }"#,
+            );
+            server_txn
+                .internal_create(au, vec![e_exempt])
+                .expect("must not fail");
+            let e_exempt = server_txn
+                .internal_search(au, filter!(f_eq("name", PartialValue::new_iname("exempt"))))
+                .expect("must not fail")
+                .pop()
+                .expect("must not fail");
+            assert!(e_exempt.attribute_pres("spn"));
+            assert!(!e_exempt.attribute_value_pres("class", &PartialValue::new_class("spn_exempt")));
+            server_txn.commit(au).expect("Must not fail");
+
+            // Verify only considers entries within the scope.
+            let server_r_txn = server.read();
+            assert!(Spn::verify(au, &server_r_txn).iter().all(|r| r.is_ok()));
+        });
+    }
+
+    #[test]
+    fn test_spn_scope_invalid_rejected() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let r = server_txn.internal_modify(
+                au,
+                &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_SYSTEM_CONFIG))),
+                &modlist!([m_pres(
+                    "spn_scope",
+                    &Value::new_json_filter(r#"{"eq": ["not_an_attribute", "kerberos"]}"#)
+                        .expect("must not fail")
+                )]),
+            );
+            assert!(r.is_err());
+        });
+    }
+
+    #[test]
+    fn test_spn_optout_group() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            // The group doesn't exist yet, so no one is opted out.
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_SYSTEM_CONFIG))),
+                    &modlist!([m_pres("spn_optout_group", &Value::new_iutf8("no_kerberos"))]),
+                )
+                .expect("must not fail");
+
+            let e_member: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["account"],
+                    "name": ["optout_member"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "displayname": ["optout_member"]
+                }
+            }"#,
+            );
+            let e_other: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["account"],
+                    "name": ["optout_other"],
+                    "displayname": ["optout_other"]
+                }
+            }"#,
+            );
+            server_txn
+                .internal_create(au, vec![e_member, e_other])
+                .expect("must not fail");
+
+            let spn_of = |au: &mut AuditScope, name: &str| {
+                server_txn
+                    .internal_search(au, filter!(f_eq("name", PartialValue::new_iname(name))))
+                    .expect("must not fail")
+                    .pop()
+                    .expect("must not fail")
+                    .get_ava_single("spn")
+                    .cloned()
+            };
+            let member_spn = Value::new_spn_str("optout_member", "example.com");
+            let other_spn = Value::new_spn_str("optout_other", "example.com");
+            assert!(spn_of(au, "optout_member") == Some(member_spn.clone()));
+            assert!(spn_of(au, "optout_other") == Some(other_spn.clone()));
+
+            // Creating the group removes the spn of its member only.
+            let e_group: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["group"],
+                    "name": ["no_kerberos"],
+                    "member": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"]
+                }
+            }"#,
+            );
+            server_txn
+                .internal_create(au, vec![e_group])
+                .expect("must not fail");
+            assert!(spn_of(au, "optout_member").is_none());
+            assert!(spn_of(au, "optout_other") == Some(other_spn.clone()));
+            server_txn.commit(au).expect("Must not fail");
+
+            // Verify skips the opted out member.
+            let server_r_txn = server.read();
+            assert!(Spn::verify(au, &server_r_txn).iter().all(|r| r.is_ok()));
+            drop(server_r_txn);
+
+            // Leaving the group restores the spn.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("name", PartialValue::new_iname("no_kerberos"))),
+                    &modlist!([m_purge("member")]),
+                )
+                .expect("must not fail");
+            let member = server_txn
+                .internal_search(
+                    au,
+                    filter!(f_eq("name", PartialValue::new_iname("optout_member"))),
+                )
+                .expect("must not fail")
+                .pop()
+                .expect("must not fail");
+            assert!(member.get_ava_single("spn") == Some(&member_spn));
+            server_txn.commit(au).expect("Must not fail");
+        });
+    }
+
+    #[test]
+    fn test_spn_prefix_by_class() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let e_posix: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["account", "posixaccount"],
+                    "name": ["testhost"],
+                    "displayname": ["testhost"]
+                }
+            }"#,
+            );
+            let e_group: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["group"],
+                    "name": ["testgroup"]
+                }
+            }"#,
+            );
+            server_txn
+                .internal_create(au, vec![e_posix, e_group])
+                .expect("must not fail");
+
+            // Setting the prefixes regenerates the existing spns.
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_DOMAIN_INFO))),
+                    &modlist!([
+                        m_pres("domain_spn_prefix", &Value::new_utf8s("posixaccount=host")),
+                        m_pres("domain_spn_prefix", &Value::new_utf8s("posixgroup=HTTP"))
+                    ]),
+                )
+                .expect("must not fail");
+
+            let spn_of = |au: &mut AuditScope, name: &str| {
+                server_txn
+                    .internal_search(au, filter!(f_eq("name", PartialValue::new_iname(name))))
+                    .expect("must not fail")
+                    .pop()
+                    .and_then(|e| e.get_ava_single("spn").cloned())
+                    .expect("must not fail")
+            };
+            assert!(spn_of(au, "testhost") == Value::new_spn_str("host/testhost", "example.com"));
+            assert!(spn_of(au, "testgroup") == Value::new_spn_str("testgroup", "example.com"));
+            assert!(spn_of(au, "admin") == Value::new_spn_str("admin", "example.com"));
+
+            // New entries are given the prefix too.
+            let e_posixgroup: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["group", "posixgroup"],
+                    "name": ["testweb"]
+                }
+            }"#,
+            );
+            server_txn
+                .internal_create(au, vec![e_posixgroup])
+                .expect("must not fail");
+            assert!(spn_of(au, "testweb") == Value::new_spn_str("HTTP/testweb", "example.com"));
+
+            // An invalid prefix is refused.
+            let r = server_txn.internal_modify(
+                au,
+                &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_DOMAIN_INFO))),
+                &modlist!([m_pres(
+                    "domain_spn_prefix",
+                    &Value::new_utf8s("posixaccount=host/x")
+                )]),
+            );
+            assert!(matches!(r, Err(OperationError::InvalidAttribute(_))));
+            server_txn.commit(au).expect("Must not fail");
+
+            let server_r_txn = server.read();
+            assert!(Spn::verify(au, &server_r_txn).iter().all(|r| r.is_ok()));
+        });
+    }
+
+    #[test]
+    fn test_spn_skip_expired_domain_rename() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_SYSTEM_CONFIG))),
+                    &modlist!([m_pres("spn_skip_expired", &Value::new_bool(true))]),
+                )
+                .expect("must not fail");
+
+            let e_expired: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["account"],
+                    "name": ["expired"],
+                    "displayname": ["expired"]
+                }
+            }"#,
+            );
+            server_txn
+                .internal_create(au, vec![e_expired])
+                .expect("must not fail");
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("name", PartialValue::new_iname("expired"))),
+                    &modlist!([m_pres(
+                        "account_expire",
+                        &Value::new_datetime_s("2020-01-01T00:00:00+00:00").expect("must not fail")
+                    )]),
+                )
+                .expect("must not fail");
+
+            server_txn
+                .domain_rename(au, "new.example.com")
+                .expect("should not fail!");
+
+            // The expired account keeps the spn of the old domain ...
+            let e_expired = server_txn
+                .internal_search(
+                    au,
+                    filter!(f_eq("name", PartialValue::new_iname("expired"))),
+                )
+                .expect("must not fail")
+                .pop()
+                .expect("must not fail");
+            assert!(e_expired.attribute_value_pres(
+                "spn",
+                &PartialValue::new_spn_s("expired@example.com").expect("must not fail")
+            ));
+            // ... while others are regenerated.
+            let e_admin = server_txn
+                .internal_search_uuid(au, &UUID_ADMIN)
+                .expect("must not fail");
+            assert!(
+                e_admin.get_ava_single("spn")
+                    == Some(&Value::new_spn_str("admin", "new.example.com"))
+            );
+            server_txn.commit(au).expect("Must not fail");
+
+            // Verify doesn't consider the retained spn a mismatch.
+            let server_r_txn = server.read();
+            assert!(Spn::verify(au, &server_r_txn).iter().all(|r| r.is_ok()));
+            std::mem::drop(server_r_txn);
+
+            // Without the option, it is.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_SYSTEM_CONFIG))),
+                    &modlist!([m_purge("spn_skip_expired")]),
+                )
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+
+            let server_r_txn = server.read();
+            let r = Spn::verify(au, &server_r_txn);
+            assert!(r.len() == 1);
+            assert!(matches!(
+                r[0],
+                Err(ConsistencyError::InvalidSpn(_, SpnInconsistency::Mismatch))
+            ));
+            std::mem::drop(server_r_txn);
+
+            // Regenerating the spn resolves it.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("name", PartialValue::new_iname("expired"))),
+                    &modlist!([m_purge("spn")]),
+                )
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+        });
+    }
+
+    #[test]
+    fn test_spn_reserved_rejected() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let mut server = server.clone();
+            server.set_spn_settings(SpnSettings {
+                reserved_spns: std::iter::once("svchost@example.com".to_string()).collect(),
+                ..Default::default()
+            });
+
+            let e: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["account"],
+                    "name": ["SvcHost"],
+                    "description": ["svchost"],
+                    "displayname": ["svchost"]
+                }
+            }"#,
+            );
+
+            let server_txn = server.write(duration_from_epoch_now());
+            let r = server_txn.internal_create(au, vec![e]);
+            match r {
+                Err(OperationError::Plugin(PluginError::SpnReserved(spn))) => {
+                    assert!(spn == "svchost@example.com")
+                }
+                _ => panic!("create should have been rejected"),
+            }
+
+            // Renaming an existing entry to a reserved spn is also rejected.
+            let r = server_txn.internal_modify(
+                au,
+                &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                &modlist!([
+                    m_purge("name"),
+                    m_pres("name", &Value::new_iname("svchost"))
+                ]),
+            );
+            assert!(matches!(
+                r,
+                Err(OperationError::Plugin(PluginError::SpnReserved(_)))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_spn_verify_reserved() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let e: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "attrs": {
+                    "class": ["account"],
+                    "name": ["svchost"],
+                    "description": ["svchost"],
+                    "displayname": ["svchost"]
+                }
+            }"#,
+            );
+
+            // The entry existed before the spn was reserved.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_create(au, vec![e])
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+
+            let mut server = server.clone();
+            server.set_spn_settings(SpnSettings {
+                reserved_spns: std::iter::once("svchost@example.com".to_string()).collect(),
+                ..Default::default()
+            });
+
+            let server_r_txn = server.read();
+            let r = Spn::verify(au, &server_r_txn);
+            assert!(r.len() == 1);
+            match &r[0] {
+                Err(ce) => {
+                    assert!(matches!(
+                        ce,
+                        ConsistencyError::InvalidSpn(_, SpnInconsistency::Reserved)
+                    ));
+                    assert!(ce.remediation() == Some(SpnInconsistency::Reserved.remediation()));
+                }
+                Ok(_) => panic!("verify should have failed"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_spn_anonymous_configured() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let filt = filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ANONYMOUS)));
+            let touch = modlist!([
+                m_purge("description"),
+                m_pres("description", &Value::new_utf8s("anonymous"))
+            ]);
+            let anon_spn = |server: &QueryServer, au: &mut AuditScope| {
+                server
+                    .read()
+                    .internal_search_uuid(au, &UUID_ANONYMOUS)
+                    .expect("must not fail")
+                    .get_ava_single("spn")
+                    .cloned()
+            };
+
+            // By default it is generated like any other account.
+            assert!(anon_spn(server, au) == Some(Value::new_spn_str("anonymous", "example.com")));
+
+            // Until the anonymous account is next modified, verify reports it.
+            let mut server = server.clone();
+            server.set_spn_settings(SpnSettings {
+                anonymous_spn: AnonymousSpn::Fixed("guest".to_string(), "example.com".to_string()),
+                ..Default::default()
+            });
+            let r = Spn::verify(au, &server.read());
+            assert!(r.len() == 1);
+            assert!(matches!(
+                r[0],
+                Err(ConsistencyError::InvalidSpn(_, SpnInconsistency::Mismatch))
+            ));
+
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(au, &filt, &touch)
+                .expect("must not fail");
+            // A domain rename doesn't change it either.
+            server_txn
+                .domain_rename(au, "new.example.com")
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+            assert!(anon_spn(&server, au) == Some(Value::new_spn_str("guest", "example.com")));
+            assert!(Spn::verify(au, &server.read()).is_empty());
+
+            server.set_spn_settings(SpnSettings {
+                anonymous_spn: AnonymousSpn::Suppressed,
+                ..Default::default()
+            });
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(au, &filt, &touch)
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+            assert!(anon_spn(&server, au).is_none());
+            assert!(Spn::verify(au, &server.read()).is_empty());
+        });
+    }
+
+    #[test]
+    fn test_spn_duplicate_names_in_create() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let person = |name: &str| {
+                entry_init!(
+                    ("class", Value::new_class("object")),
+                    ("class", Value::new_class("account")),
+                    ("name", Value::new_iname(name)),
+                    ("description", Value::new_utf8s(name)),
+                    ("displayname", Value::new_utf8s(name))
+                )
+            };
+            let batch = vec![
+                person("testperson"),
+                person("testperson"),
+                person("otherperson"),
+                person("testperson"),
+            ];
+
+            // By default the create is refused, naming the conflict.
+            let server_txn = server.write(duration_from_epoch_now());
+            match server_txn.internal_create(au, batch.clone()) {
+                Err(OperationError::Plugin(PluginError::AttrUnique(msg))) => {
+                    assert!(msg.contains("testperson"));
+                    assert!(!msg.contains("otherperson"));
+                }
+                r => panic!("duplicate names should be rejected, not {:?}", r),
+            }
+            std::mem::drop(server_txn);
+
+            let mut disambiguate = server.clone();
+            disambiguate.set_spn_settings(SpnSettings {
+                duplicate_name_policy: DuplicateNamePolicy::Disambiguate,
+                ..Default::default()
+            });
+            let server_txn = disambiguate.write(duration_from_epoch_now());
+            server_txn
+                .internal_create(au, batch)
+                .expect("must not fail");
+            for name in &["testperson", "testperson_2", "testperson_3", "otherperson"] {
+                let e = server_txn
+                    .internal_search(au, filter!(f_eq("name", PartialValue::new_iname(name))))
+                    .expect("must not fail");
+                assert!(e.len() == 1);
+                let spn = format!("{}@example.com", name);
+                assert!(
+                    e[0].get_ava_single("spn")
+                        .map(|v| v.to_proto_string_clone())
+                        == Some(spn)
+                );
+            }
+            server_txn.commit(au).expect("Must not fail");
+        });
+    }
+
+    #[test]
+    fn test_spn_strict_verify() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let mut strict_server = server.clone();
+            strict_server.set_spn_settings(SpnSettings {
+                strict_verify: true,
+                ..Default::default()
+            });
+
+            let server_txn = strict_server.write(duration_from_epoch_now());
+            // A normal modify regenerates a consistent spn, so it is allowed.
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                    &modlist!([m_purge("spn")]),
+                )
+                .expect("must not fail");
+
+            // Simulate a generation bug by presenting a write with the wrong spn.
+            let e_pre = server_txn
+                .internal_search_uuid(au, &UUID_ADMIN)
+                .expect("must not fail");
+            let mut e_corrupt = unsafe { e_pre.clone().into_invalid() };
+            e_corrupt.set_ava(
+                "spn",
+                btreeset![Value::new_spn_str("admin", "wrong.example.com")],
+            );
+            let e_corrupt = unsafe { e_corrupt.into_sealed_committed() };
+            let me = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                    modlist!([m_purge("spn")]),
+                )
+            };
+
+            let r = Spn::post_modify(au, &server_txn, &[e_pre.clone()], &[e_corrupt.clone()], &me);
+            match r {
+                Err(OperationError::ConsistencyError(errs)) => {
+                    assert!(errs.len() == 1);
+                    assert!(matches!(
+                        errs[0],
+                        Err(ConsistencyError::InvalidSpn(_, SpnInconsistency::Mismatch))
+                    ));
+                }
+                _ => panic!("strict verify should have rejected the write"),
+            }
+            server_txn.commit(au).expect("Must not fail");
+
+            // Without strict mode the write isn't checked.
+            let server_txn = server.write(duration_from_epoch_now());
+            assert!(Spn::post_modify(au, &server_txn, &[e_pre], &[e_corrupt], &me).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_spn_direct_write() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let filt = filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN)));
+            let tampered = Value::new_spn_str("admin", "tampered.example.com");
+            let mut reject_server = server.clone();
+            reject_server.set_spn_settings(SpnSettings {
+                direct_write: SpnDirectWrite::Reject,
+                ..Default::default()
+            });
+
+            let server_txn = server.write(duration_from_epoch_now());
+            let admin = server_txn
+                .internal_search_uuid(au, &UUID_ADMIN)
+                .expect("must not fail");
+            let generated = admin.get_ava_single("spn").cloned().expect("must not fail");
+            let mut tampered_cand = unsafe { admin.clone().into_invalid() };
+            tampered_cand.set_ava("spn", btreeset![tampered.clone()]);
+            let me_user = unsafe {
+                ModifyEvent::new_impersonate_entry(
+                    admin.clone(),
+                    filt.clone(),
+                    modlist!([m_purge("spn"), m_pres("spn", &tampered)]),
+                )
+            };
+            let me_internal = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filt.clone(),
+                    modlist!([m_purge("spn"), m_pres("spn", &tampered)]),
+                )
+            };
+
+            // By default the spn a user set is overwritten.
+            let mut cand = vec![tampered_cand.clone()];
+            assert!(Spn::pre_modify(au, &server_txn, &mut cand, &me_user).is_ok());
+            assert!(cand[0].get_ava_single("spn") == Some(&generated));
+            server_txn.commit(au).expect("Must not fail");
+
+            // Or the modify is rejected.
+            let server_txn = reject_server.write(duration_from_epoch_now());
+            let mut cand = vec![tampered_cand.clone()];
+            assert!(matches!(
+                Spn::pre_modify(au, &server_txn, &mut cand, &me_user),
+                Err(OperationError::SystemProtectedAttribute)
+            ));
+
+            // The server's own writes, and setting the spn that would be generated
+            // anyway, are not direct writes.
+            let mut cand = vec![tampered_cand];
+            assert!(Spn::pre_modify(au, &server_txn, &mut cand, &me_internal).is_ok());
+            assert!(cand[0].get_ava_single("spn") == Some(&generated));
+            let me_user_same = unsafe {
+                ModifyEvent::new_impersonate_entry(
+                    admin.clone(),
+                    filt,
+                    modlist!([m_purge("spn"), m_pres("spn", &generated)]),
+                )
+            };
+            let mut cand = vec![unsafe { admin.into_invalid() }];
+            assert!(Spn::pre_modify(au, &server_txn, &mut cand, &me_user_same).is_ok());
+            server_txn.commit(au).expect("Must not fail");
+        });
+    }
+
+    #[test]
+    fn test_spn_notify_enqueue() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let notifier = Arc::new(SpnNotifier::new("true"));
+            let mut notify_server = server.clone();
+            notify_server.set_spn_notifier(Some(notifier.clone()));
+
+            let rename = |txn: &QueryServerWriteTransaction, au: &mut AuditScope, name: &str| {
+                txn.internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                    &modlist!([m_purge("name"), m_pres("name", &Value::new_iname(name))]),
+                )
+                .expect("must not fail");
+            };
+
+            // Nothing is queued until the change commits.
+            let server_txn = notify_server.write(duration_from_epoch_now());
+            rename(&server_txn, au, "superadmin");
+            assert!(notifier.pending().is_empty());
+            server_txn.commit(au).expect("must not fail");
+            assert!(
+                notifier.pending()
+                    == vec![SpnChange {
+                        uuid: *UUID_ADMIN,
+                        old: Some("admin@example.com".to_string()),
+                        new: Some("superadmin@example.com".to_string()),
+                    }]
+            );
+
+            // An aborted change is never sent.
+            let server_txn = notify_server.write(duration_from_epoch_now());
+            rename(&server_txn, au, "otheradmin");
+            drop(server_txn);
+            assert!(notifier.pending().len() == 1);
+
+            // Modifies that don't change the spn aren't sent.
+            let server_txn = notify_server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                    &modlist!([m_purge("spn")]),
+                )
+                .expect("must not fail");
+            server_txn.commit(au).expect("must not fail");
+            assert!(notifier.pending().len() == 1);
+
+            assert!(notifier.process() == 1);
+            assert!(notifier.pending().is_empty());
+        });
+    }
 }
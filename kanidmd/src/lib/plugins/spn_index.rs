@@ -0,0 +1,235 @@
+// Maintain a normalised, plugin managed copy of the spn in spn_index. This
+// allows a direct equality lookup of an spn to the entry (and so it's uuid)
+// without needing to parse the spn syntax, and in environments where the
+// spn attribute index is not available.
+//
+// This must run *after* the spn plugin, as we derive our value from the
+// freshly generated spn.
+use crate::plugins::Plugin;
+use crate::prelude::*;
+
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew};
+use crate::event::{CreateEvent, ModifyEvent};
+use kanidm_proto::v1::{ConsistencyError, OperationError};
+
+pub struct SpnIndex {}
+
+lazy_static! {
+    static ref CLASS_GROUP: PartialValue = PartialValue::new_class("group");
+    static ref CLASS_ACCOUNT: PartialValue = PartialValue::new_class("account");
+}
+
+fn generate_spn_index<VALID, STATE>(e: &Entry<VALID, STATE>) -> Option<Value> {
+    e.get_ava_single("spn")
+        .map(|spn| Value::new_iutf8(spn.to_proto_string_clone().as_str()))
+}
+
+fn apply_spn_index<STATE>(
+    au: &mut AuditScope,
+    e: &mut Entry<EntryInvalid, STATE>,
+) -> Result<(), OperationError> {
+    if e.attribute_value_pres("class", &CLASS_GROUP)
+        || e.attribute_value_pres("class", &CLASS_ACCOUNT)
+    {
+        match generate_spn_index(e) {
+            Some(idx) => {
+                ltrace!(au, "plugin_spn_index: set spn_index to {:?}", idx);
+                e.set_ava("spn_index", btreeset![idx]);
+            }
+            None => {
+                // Outside of the spn_scope, so there is nothing to index.
+                ltrace!(au, "plugin_spn_index: no spn, purging spn_index");
+                e.purge_ava("spn_index");
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Plugin for SpnIndex {
+    fn id() -> &'static str {
+        "plugin_spn_index"
+    }
+
+    fn pre_create_transform(
+        au: &mut AuditScope,
+        _qs: &QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryNew>>,
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        cand.iter_mut().try_for_each(|e| apply_spn_index(au, e))
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        _qs: &QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        cand.iter_mut().try_for_each(|e| apply_spn_index(au, e))
+    }
+
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let filt_in = filter!(f_or!([
+            f_eq("class", PartialValue::new_class("group")),
+            f_eq("class", PartialValue::new_class("account"))
+        ]));
+
+        let all_cand = match qs
+            .internal_search(au, filt_in)
+            .map_err(|_| Err(ConsistencyError::QueryServerSearchFailure))
+        {
+            Ok(all_cand) => all_cand,
+            Err(e) => return vec![e],
+        };
+
+        let mut r = Vec::new();
+
+        for e in all_cand {
+            let ex_idx = generate_spn_index(&e);
+            let r_idx = e.get_ava_single("spn_index");
+            ltrace!(au, "verify spn_index: s {:?} == ex {:?} ?", r_idx, ex_idx);
+            if r_idx != ex_idx.as_ref() {
+                ladmin_error!(
+                    au,
+                    "Entry {:?} spn_index has drifted s {:?} != ex {:?}",
+                    e.get_uuid(),
+                    r_idx,
+                    ex_idx,
+                );
+                r.push(Err(ConsistencyError::InvalidSpnIndex(e.get_id())))
+            }
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plugins::spn_index::SpnIndex;
+    use crate::plugins::Plugin;
+    use crate::prelude::*;
+    use kanidm_proto::v1::ConsistencyError;
+
+    #[test]
+    fn test_spn_index_generate_create() {
+        let e: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "attrs": {
+                "class": ["account"],
+                "name": ["TestPerson"],
+                "description": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        );
+
+        let create = vec![e];
+        let preload = Vec::new();
+
+        run_create_test!(
+            Ok(()),
+            preload,
+            create,
+            None,
+            |au: &mut AuditScope, qs_write: &QueryServerWriteTransaction| {
+                let e = qs_write
+                    .internal_search(
+                        au,
+                        filter!(f_eq("name", PartialValue::new_iname("testperson"))),
+                    )
+                    .expect("must not fail")
+                    .pop()
+                    .expect("must not fail");
+                assert!(e.attribute_value_pres(
+                    "spn_index",
+                    &PartialValue::new_iutf8("testperson@example.com")
+                ));
+            }
+        );
+    }
+
+    #[test]
+    fn test_spn_index_regenerate_modify() {
+        let e: Entry<EntryInit, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "attrs": {
+                "class": ["account"],
+                "name": ["testperson"],
+                "description": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        );
+
+        let preload = vec![e];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iname("testperson"))),
+            modlist!([
+                m_purge("spn_index"),
+                m_pres("spn_index", &Value::new_iutf8("invalid@spn"))
+            ]),
+            None,
+            |au: &mut AuditScope, qs_write: &QueryServerWriteTransaction| {
+                let e = qs_write
+                    .internal_search(
+                        au,
+                        filter!(f_eq("name", PartialValue::new_iname("testperson"))),
+                    )
+                    .expect("must not fail")
+                    .pop()
+                    .expect("must not fail");
+                assert!(e.attribute_value_pres(
+                    "spn_index",
+                    &PartialValue::new_iutf8("testperson@example.com")
+                ));
+            }
+        );
+    }
+
+    #[test]
+    fn test_spn_index_verify_drift() {
+        run_test!(|server: &QueryServer, au: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let e_pre = server_txn
+                .internal_search_uuid(au, &UUID_ADMIN)
+                .expect("must not fail");
+
+            // Bypass the plugins to simulate the index drifting from the spn.
+            let mut e_drift = unsafe { e_pre.clone().into_invalid() };
+            e_drift.set_ava(
+                "spn_index",
+                btreeset![Value::new_iutf8("drift@example.com")],
+            );
+            let e_drift = unsafe { e_drift.into_sealed_committed() };
+            server_txn
+                .get_be_txn()
+                .modify(au, &[e_pre], &[e_drift])
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+
+            let server_r_txn = server.read();
+            let r = SpnIndex::verify(au, &server_r_txn);
+            assert!(r.len() == 1);
+            assert!(matches!(r[0], Err(ConsistencyError::InvalidSpnIndex(_))));
+            std::mem::drop(server_r_txn);
+
+            // A modify of the entry repairs the index.
+            let server_txn = server.write(duration_from_epoch_now());
+            server_txn
+                .internal_modify(
+                    au,
+                    &filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                    &modlist!([m_purge("spn_index")]),
+                )
+                .expect("must not fail");
+            server_txn.commit(au).expect("Must not fail");
+        });
+    }
+}
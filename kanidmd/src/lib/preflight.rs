@@ -0,0 +1,492 @@
+// Check that a server is ready to be deployed before it's started. Preflight
+// runs the same validation of the configuration that is done at startup, along
+// with the checks of the tls chain and key, the db folder, the cookie key and the
+// origin, and reports every problem found rather than stopping at the first.
+//
+// Errors will prevent the server starting or working. Warnings are problems that
+// the server will run with, but that an operator should look at before a deploy.
+use crate::config::Configuration;
+use crate::crypto::check_tls;
+
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use url::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PreflightSeverity {
+    Error,
+    Warning,
+    Info,
+}
+
+impl fmt::Display for PreflightSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreflightSeverity::Error => write!(f, "ERROR"),
+            PreflightSeverity::Warning => write!(f, "WARNING"),
+            PreflightSeverity::Info => write!(f, "INFO"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PreflightFinding {
+    pub severity: PreflightSeverity,
+    pub check: &'static str,
+    pub message: String,
+}
+
+/// The parts of the server configuration that preflight needs, but that are
+/// only used at startup and so aren't kept in the Configuration.
+#[derive(Debug, Clone)]
+pub struct PreflightOptions {
+    pub db_create_dir: bool,
+    pub db_arc_size_strict: bool,
+    pub system_memory: Option<usize>,
+    pub expiry_warn_days: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct PreflightReport {
+    checks: Vec<&'static str>,
+    findings: Vec<PreflightFinding>,
+}
+
+impl PreflightReport {
+    fn push(&mut self, severity: PreflightSeverity, check: &'static str, message: String) {
+        self.findings.push(PreflightFinding {
+            severity,
+            check,
+            message,
+        })
+    }
+
+    pub fn checks(&self) -> &[&'static str] {
+        self.checks.as_slice()
+    }
+
+    pub fn findings(&self) -> &[PreflightFinding] {
+        self.findings.as_slice()
+    }
+
+    pub fn count(&self, severity: PreflightSeverity) -> usize {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == severity)
+            .count()
+    }
+
+    /// A deploy can go ahead when there are no errors.
+    pub fn passed(&self) -> bool {
+        self.count(PreflightSeverity::Error) == 0
+    }
+
+    /// The percentage of checks that found no errors or warnings.
+    pub fn score(&self) -> usize {
+        if self.checks.is_empty() {
+            return 100;
+        }
+        let clean = self
+            .checks
+            .iter()
+            .filter(|check| {
+                !self
+                    .findings
+                    .iter()
+                    .any(|f| f.check == **check && f.severity != PreflightSeverity::Info)
+            })
+            .count();
+        clean * 100 / self.checks.len()
+    }
+}
+
+pub fn preflight(config: &Configuration, options: &PreflightOptions) -> PreflightReport {
+    let mut report = PreflightReport::default();
+    check_config(&mut report, config, options);
+    check_tls_config(&mut report, config, options);
+    check_db_path(&mut report, config, options);
+    check_cookie(&mut report, config);
+    check_origin(&mut report, config);
+    report
+}
+
+fn check_config(report: &mut PreflightReport, config: &Configuration, options: &PreflightOptions) {
+    let check = "config";
+    report.checks.push(check);
+    for msg in config.validate() {
+        report.push(PreflightSeverity::Error, check, msg);
+    }
+    match options.system_memory {
+        Some(mem) => {
+            if let Err(msg) = config.validate_db_arc_size(mem) {
+                let severity = if options.db_arc_size_strict {
+                    PreflightSeverity::Error
+                } else {
+                    PreflightSeverity::Warning
+                };
+                report.push(severity, check, msg);
+            }
+        }
+        None => report.push(
+            PreflightSeverity::Info,
+            check,
+            "Unable to determine system memory, db_arc_size can not be checked".to_string(),
+        ),
+    }
+}
+
+fn check_tls_config(
+    report: &mut PreflightReport,
+    config: &Configuration,
+    options: &PreflightOptions,
+) {
+    let check = "tls";
+    report.checks.push(check);
+    let tls_config = match &config.tls_config {
+        Some(tls_config) => tls_config,
+        None => {
+            report.push(
+                PreflightSeverity::Warning,
+                check,
+                "tls_chain and tls_key are not configured, clients must connect through a proxy that provides tls".to_string(),
+            );
+            return;
+        }
+    };
+
+    match check_tls(config, options.expiry_warn_days) {
+        Ok(tls) => {
+            report.push(
+                PreflightSeverity::Info,
+                check,
+                format!(
+                    "Certificate for {} expires {} ({} days remaining)",
+                    tls.subject, tls.not_after, tls.days_remaining
+                ),
+            );
+            for p in tls.problems {
+                report.push(PreflightSeverity::Error, check, p);
+            }
+        }
+        Err(e) => report.push(PreflightSeverity::Error, check, e),
+    }
+
+    if let Ok(meta) = fs::metadata(tls_config.key.as_str()) {
+        if meta.mode() & 0o007 != 0 {
+            report.push(
+                PreflightSeverity::Warning,
+                check,
+                format!(
+                    "{} has 'everyone' permission bits in the mode",
+                    tls_config.key
+                ),
+            );
+        }
+    }
+}
+
+fn check_db_path(report: &mut PreflightReport, config: &Configuration, options: &PreflightOptions) {
+    let check = "db_path";
+    report.checks.push(check);
+    let db_path = Path::new(config.db_path.as_str());
+    let db_parent_path = match db_path.parent() {
+        Some(p) => p,
+        None => {
+            report.push(
+                PreflightSeverity::Error,
+                check,
+                format!("db_path {} must be the path of a file", config.db_path),
+            );
+            return;
+        }
+    };
+    let db_parent_str = db_parent_path.to_str().unwrap_or("invalid file path");
+
+    if !db_parent_path.exists() {
+        if options.db_create_dir {
+            report.push(
+                PreflightSeverity::Info,
+                check,
+                format!(
+                    "DB folder {} does not exist, it will be created at startup",
+                    db_parent_str
+                ),
+            );
+        } else {
+            report.push(
+                PreflightSeverity::Error,
+                check,
+                format!(
+                    "DB folder {} does not exist. Create it, or set db_create_dir = true",
+                    db_parent_str
+                ),
+            );
+        }
+        return;
+    }
+
+    match fs::metadata(db_parent_path) {
+        Ok(meta) if !meta.is_dir() => report.push(
+            PreflightSeverity::Error,
+            check,
+            format!("DB folder {} is not a directory", db_parent_str),
+        ),
+        Ok(meta) => {
+            if meta.permissions().readonly() {
+                report.push(
+                    PreflightSeverity::Warning,
+                    check,
+                    format!(
+                        "DB folder permissions on {} indicate it may not be RW",
+                        db_parent_str
+                    ),
+                );
+            }
+            if meta.mode() & 0o007 != 0 {
+                report.push(
+                    PreflightSeverity::Warning,
+                    check,
+                    format!(
+                        "DB folder {} has 'everyone' permission bits in the mode",
+                        db_parent_str
+                    ),
+                );
+            }
+        }
+        Err(e) => report.push(
+            PreflightSeverity::Error,
+            check,
+            format!(
+                "Unable to read metadata for DB folder {} - {:?}",
+                db_parent_str, e
+            ),
+        ),
+    }
+
+    if !db_path.exists() {
+        report.push(
+            PreflightSeverity::Info,
+            check,
+            format!(
+                "DB {} does not exist, a new database will be created at startup",
+                config.db_path
+            ),
+        );
+    }
+}
+
+fn check_cookie(report: &mut PreflightReport, config: &Configuration) {
+    let check = "cookie_key";
+    report.checks.push(check);
+    // The key is generated at startup, so a key of a single repeated byte means
+    // the generator failed, and session cookies could be forged.
+    if config.cookie_key.iter().all(|b| *b == config.cookie_key[0]) {
+        report.push(
+            PreflightSeverity::Error,
+            check,
+            "cookie_key has not been randomly generated".to_string(),
+        );
+    }
+    if !config.secure_cookies {
+        report.push(
+            PreflightSeverity::Warning,
+            check,
+            "secure cookies are disabled, session cookies may be sent without tls".to_string(),
+        );
+    }
+    if let Some(domain) = &config.cookie_domain {
+        let host = Url::parse(config.origin.as_str())
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_lowercase()));
+        if let Some(host) = host {
+            if host != *domain && !host.ends_with(format!(".{}", domain).as_str()) {
+                report.push(
+                    PreflightSeverity::Error,
+                    check,
+                    format!(
+                        "cookie_domain {} does not contain the origin {}, browsers will reject the session cookie",
+                        domain, config.origin
+                    ),
+                );
+            }
+        }
+    }
+}
+
+fn check_origin(report: &mut PreflightReport, config: &Configuration) {
+    let check = "origin";
+    report.checks.push(check);
+    let bind = match config.address.parse::<SocketAddr>() {
+        Ok(addr) => Some(addr),
+        Err(e) => {
+            report.push(
+                PreflightSeverity::Error,
+                check,
+                format!(
+                    "bindaddress {} is not a valid address -> {:?}",
+                    config.address, e
+                ),
+            );
+            None
+        }
+    };
+    if let Some(ldapaddress) = &config.ldapaddress {
+        if let Err(e) = ldapaddress.parse::<SocketAddr>() {
+            report.push(
+                PreflightSeverity::Error,
+                check,
+                format!(
+                    "ldapbindaddress {} is not a valid address -> {:?}",
+                    ldapaddress, e
+                ),
+            );
+        }
+    }
+
+    let origin = match Url::parse(config.origin.as_str()) {
+        Ok(origin) => origin,
+        Err(e) => {
+            report.push(
+                PreflightSeverity::Error,
+                check,
+                format!("origin {} is not a valid url -> {:?}", config.origin, e),
+            );
+            return;
+        }
+    };
+    if origin.host_str().is_none() {
+        report.push(
+            PreflightSeverity::Error,
+            check,
+            format!("origin {} has no host", config.origin),
+        );
+    }
+    // Webauthn is only available to an https origin.
+    if origin.scheme() != "https" {
+        report.push(
+            PreflightSeverity::Warning,
+            check,
+            format!(
+                "origin {} is not https, webauthn will not be available",
+                config.origin
+            ),
+        );
+    }
+    if let (Some(bind), Some(port)) = (bind, origin.port_or_known_default()) {
+        if bind.port() != port {
+            report.push(
+                PreflightSeverity::Info,
+                check,
+                format!(
+                    "origin port {} differs from the bindaddress port {}, clients must reach the server through a proxy or port forward",
+                    port,
+                    bind.port()
+                ),
+            );
+        }
+        if bind.ip().is_loopback() {
+            report.push(
+                PreflightSeverity::Info,
+                check,
+                format!(
+                    "bindaddress {} only accepts connections from this host",
+                    config.address
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{preflight, PreflightOptions, PreflightSeverity};
+    use crate::config::{Configuration, TlsConfiguration};
+    use std::os::unix::fs::DirBuilderExt;
+
+    fn options() -> PreflightOptions {
+        PreflightOptions {
+            db_create_dir: false,
+            db_arc_size_strict: false,
+            system_memory: Some(1024 * 1024 * 1024),
+            expiry_warn_days: 30,
+        }
+    }
+
+    #[test]
+    fn test_preflight_clean() {
+        let dir =
+            std::env::temp_dir().join(format!("kanidm_preflight_test_{}", uuid::Uuid::new_v4()));
+        std::fs::DirBuilder::new()
+            .mode(0o750)
+            .create(&dir)
+            .expect("Unable to create test dir");
+
+        let mut config = Configuration::new();
+        config.secure_cookies = true;
+        config.update_db_path(dir.join("kanidm.db").to_str().expect("Invalid path"));
+        config.update_bind(&Some("0.0.0.0:443".to_string()));
+        config.update_origin("https://idm.example.com");
+        config.update_cookie(&Some("example.com".to_string()), None);
+
+        let report = preflight(&config, &options());
+        std::fs::remove_dir_all(&dir).expect("Unable to remove test dir");
+
+        assert!(report.passed());
+        assert!(report.count(PreflightSeverity::Error) == 0);
+        // Only a server without tls configured is noted.
+        assert!(report.count(PreflightSeverity::Warning) == 1);
+        assert!(report
+            .findings()
+            .iter()
+            .all(|f| f.severity != PreflightSeverity::Warning || f.check == "tls"));
+        assert!(report.score() == 80);
+    }
+
+    #[test]
+    fn test_preflight_multiple_issues() {
+        let mut config = Configuration::new();
+        config.secure_cookies = false;
+        config.cookie_key = [0; 32];
+        config.update_db_path("/nonexistent/kanidm_preflight/kanidm.db");
+        config.update_db_arc_size(Some(usize::MAX));
+        config.update_bind(&Some("not an address".to_string()));
+        config.update_origin("http://idm.example.com");
+        config.update_cookie(&Some("example.net".to_string()), None);
+        config.update_max_connections_per_ip(Some(0));
+        config.update_http_request_read_timeout(Some(0));
+        config.tls_config = Some(TlsConfiguration {
+            chain: "/nonexistent/kanidm_preflight/chain.pem".to_string(),
+            key: "/nonexistent/kanidm_preflight/key.pem".to_string(),
+        });
+
+        let report = preflight(&config, &options());
+        assert!(!report.passed());
+        assert!(report.score() == 0);
+
+        let errors_in = |check: &str| {
+            report
+                .findings()
+                .iter()
+                .filter(|f| f.check == check && f.severity == PreflightSeverity::Error)
+                .count()
+        };
+        // Both validation problems are reported, not only the first.
+        assert!(errors_in("config") == 2);
+        assert!(errors_in("tls") == 1);
+        assert!(errors_in("db_path") == 1);
+        // The cookie key and the cookie domain.
+        assert!(errors_in("cookie_key") == 2);
+        assert!(errors_in("origin") == 1);
+        // db_arc_size, secure cookies and the http origin.
+        assert!(report.count(PreflightSeverity::Warning) == 3);
+
+        // A strict db_arc_size makes that an error.
+        let mut strict = options();
+        strict.db_arc_size_strict = true;
+        let report = preflight(&config, &strict);
+        assert!(report.count(PreflightSeverity::Warning) == 2);
+    }
+}
@@ -6,10 +6,12 @@
 use async_std::task;
 use concread::arcache::{ARCache, ARCacheReadTxn};
 use hashbrown::{HashMap, HashSet};
-use std::cell::Cell;
-use std::collections::BTreeSet;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::{Semaphore, SemaphorePermit};
 
 use crate::access::{
@@ -17,7 +19,15 @@ use crate::access::{
     AccessControls, AccessControlsReadTransaction, AccessControlsTransaction,
     AccessControlsWriteTransaction,
 };
-use crate::be::{Backend, BackendReadTransaction, BackendTransaction, BackendWriteTransaction};
+use crate::be::{
+    Backend, BackendReadTransaction, BackendTransaction, BackendWriteTransaction,
+    DbMaintenanceStats,
+};
+use crate::config::{
+    AnonymousReadScope, AnonymousSpn, DuplicateNamePolicy, SpnCharsetPolicy, SpnDirectWrite,
+    SpnIdnMode, SpnLogLevel, SpnRegenMode,
+};
+use crate::entry::SpnGenerator;
 use crate::prelude::*;
 // We use so many, we just import them all ...
 use crate::event::{
@@ -32,7 +42,12 @@ use crate::schema::{
     Schema, SchemaAttribute, SchemaClass, SchemaReadTransaction, SchemaTransaction,
     SchemaWriteTransaction,
 };
-use kanidm_proto::v1::{ConsistencyError, SchemaError};
+use crate::spn_notify::{SpnChange, SpnNotifier};
+use crate::utils::{duration_from_epoch_now, pseudonym};
+use kanidm_proto::v1::{
+    ConsistencyError, EntrySize, Filter as ProtoFilter, SchemaError, SpnBenchResult, SpnConfig,
+    SpnFsckEntry, SpnRegenerateResult,
+};
 
 const RESOLVE_FILTER_CACHE_MAX: usize = 4096;
 const RESOLVE_FILTER_CACHE_LOCAL: usize = 0;
@@ -42,6 +57,8 @@ lazy_static! {
     static ref PVCLASS_CLASSTYPE: PartialValue = PartialValue::new_class("classtype");
     static ref PVCLASS_TOMBSTONE: PartialValue = PartialValue::new_class("tombstone");
     static ref PVCLASS_RECYCLED: PartialValue = PartialValue::new_class("recycled");
+    static ref PVCLASS_ACCOUNT: PartialValue = PartialValue::new_class("account");
+    static ref PVCLASS_GROUP: PartialValue = PartialValue::new_class("group");
     static ref PVCLASS_ACS: PartialValue = PartialValue::new_class("access_control_search");
     static ref PVCLASS_ACD: PartialValue = PartialValue::new_class("access_control_delete");
     static ref PVCLASS_ACM: PartialValue = PartialValue::new_class("access_control_modify");
@@ -50,6 +67,76 @@ lazy_static! {
     static ref PVACP_ENABLE_FALSE: PartialValue = PartialValue::new_bool(false);
 }
 
+// The name and domain are replaced separately, so entries sharing a domain still
+// do so in an anonymized rename plan.
+fn anonymize_spn(spn: &str) -> String {
+    match spn.rsplitn(2, '@').collect::<Vec<_>>().as_slice() {
+        [realm, name] => format!("n{}@d{}", pseudonym(name), pseudonym(realm)),
+        _ => format!("n{}", pseudonym(spn)),
+    }
+}
+
+/// How the spns of accounts and groups are generated and checked. This is set
+/// once when the server is configured, and shared by every transaction.
+#[derive(Debug, Clone)]
+pub struct SpnSettings {
+    /// The spns, in the lowercased name@domain form, that are never generated.
+    pub reserved_spns: BTreeSet<String>,
+    /// The domains, in lowercase, whose spns are accepted on entries replicated
+    /// from them, rather than being compared to the spn this server would generate.
+    pub trusted_domains: BTreeSet<String>,
+    /// Overrides the spn given to the anonymous account.
+    pub anonymous_spn: AnonymousSpn,
+    /// How an internationalised domain name is written in the realm of spns.
+    pub idn_mode: SpnIdnMode,
+    /// What is done with spns that have characters kerberos doesn't allow.
+    pub charset_policy: SpnCharsetPolicy,
+    /// When set, the spns of modified accounts and groups are checked as part of
+    /// each modify, and the modify is rejected if any are inconsistent.
+    pub strict_verify: bool,
+    /// How a create with more than one entry of the same name is handled.
+    pub duplicate_name_policy: DuplicateNamePolicy,
+    /// What is done when a modify sets an spn that would be generated.
+    pub direct_write: SpnDirectWrite,
+    /// The level that the spns set by the spn plugin are logged at.
+    pub log_level: SpnLogLevel,
+    /// How the spns are regenerated when the domain is renamed.
+    pub regen_mode: SpnRegenMode,
+    /// In spn_regen_mode available, how many spns are regenerated in each transaction.
+    pub regen_chunk_size: usize,
+}
+
+impl Default for SpnSettings {
+    fn default() -> Self {
+        SpnSettings {
+            reserved_spns: BTreeSet::new(),
+            trusted_domains: BTreeSet::new(),
+            anonymous_spn: AnonymousSpn::default(),
+            idn_mode: SpnIdnMode::default(),
+            charset_policy: SpnCharsetPolicy::default(),
+            strict_verify: false,
+            duplicate_name_policy: DuplicateNamePolicy::default(),
+            direct_write: SpnDirectWrite::default(),
+            log_level: SpnLogLevel::default(),
+            regen_mode: SpnRegenMode::default(),
+            regen_chunk_size: DEFAULT_SPN_REGEN_CHUNK_SIZE,
+        }
+    }
+}
+
+/// What a domain rename would do, measured by performing it and rolling back.
+#[derive(Debug)]
+pub struct DomainRenameSimulation {
+    /// The number of accounts and groups.
+    pub candidates: usize,
+    /// The number of those whose spn changed.
+    pub changed: usize,
+    /// How long the rename took, before it would be committed.
+    pub elapsed: Duration,
+    /// Some of the spns that changed, as old and new.
+    pub samples: Vec<(Option<String>, Option<String>)>,
+}
+
 #[derive(Clone)]
 pub struct QueryServer {
     s_uuid: Uuid,
@@ -61,6 +148,17 @@ pub struct QueryServer {
     write_ticket: Arc<Semaphore>,
     resolve_filter_cache:
         Arc<ARCache<(EventOriginId, Filter<FilterValid>), Filter<FilterValidResolved>>>,
+    anonymous_read_scope: AnonymousReadScope,
+    search_result_limit: usize,
+    default_search_attrs: Arc<Vec<String>>,
+    spn_settings: Arc<SpnSettings>,
+    spn_notifier: Option<Arc<SpnNotifier>>,
+    // Set when a domain rename committed in spn_regen_mode available, until the
+    // spns have all been regenerated.
+    spn_regen_pending: Arc<AtomicBool>,
+    missing_domain_name: Option<String>,
+    max_entries: Option<u64>,
+    max_modify_batch: usize,
 }
 
 pub struct QueryServerReadTransaction<'a> {
@@ -72,6 +170,12 @@ pub struct QueryServerReadTransaction<'a> {
     _db_ticket: SemaphorePermit<'a>,
     resolve_filter_cache:
         Cell<ARCacheReadTxn<'a, (EventOriginId, Filter<FilterValid>), Filter<FilterValidResolved>>>,
+    anonymous_read_scope: AnonymousReadScope,
+    search_result_limit: usize,
+    default_search_attrs: Arc<Vec<String>>,
+    spn_settings: Arc<SpnSettings>,
+    spn_regen_pending: bool,
+    curtime: Duration,
 }
 
 pub struct QueryServerWriteTransaction<'a> {
@@ -92,6 +196,22 @@ pub struct QueryServerWriteTransaction<'a> {
     _write_ticket: SemaphorePermit<'a>,
     resolve_filter_cache:
         Cell<ARCacheReadTxn<'a, (EventOriginId, Filter<FilterValid>), Filter<FilterValidResolved>>>,
+    anonymous_read_scope: AnonymousReadScope,
+    search_result_limit: usize,
+    spn_settings: Arc<SpnSettings>,
+    // The number of spn changes logged at info by this transaction.
+    spn_log_count: Cell<usize>,
+    spn_notifier: Option<Arc<SpnNotifier>>,
+    // Spn changes to give to the spn_notifier if this commits.
+    spn_changes: RefCell<Vec<SpnChange>>,
+    max_entries: Option<u64>,
+    max_modify_batch: usize,
+    // When set, a domain rename only regenerates the spns of entries with this class.
+    spn_regen_class: Cell<Option<PartialValue>>,
+    spn_regen_pending: Arc<AtomicBool>,
+    // Set when this transaction renamed the domain, and left the spns to be
+    // regenerated in chunks after it commits.
+    spn_regen_deferred: Cell<bool>,
 }
 
 pub(crate) struct ModifyPartial<'a> {
@@ -127,6 +247,15 @@ pub trait QueryServerTransaction<'a> {
         &self,
     ) -> &mut ARCacheReadTxn<'a, (EventOriginId, Filter<FilterValid>), Filter<FilterValidResolved>>;
 
+    fn get_anonymous_read_scope(&self) -> AnonymousReadScope;
+
+    fn get_search_result_limit(&self) -> usize;
+
+    fn get_spn_settings(&self) -> &SpnSettings;
+
+    /// The time this transaction was started at.
+    fn get_curtime(&self) -> Duration;
+
     /// Conduct a search and apply access controls to yield a set of entries that
     /// have been reduced to the set of user visible avas. Note that if you provide
     /// a `SearchEvent` for the internal user, this query will fail. It is invalid for
@@ -161,10 +290,71 @@ pub trait QueryServerTransaction<'a> {
         })
     }
 
+    // System administration operations, such as an spn fsck, don't act on entries
+    // the event names, so they're granted by idm_acp_system_admin_ops_priv, which
+    // allows reading the version of the system info entry. That search is
+    // evaluated like any other, so the access controls decide who may run them.
+    fn check_system_admin_access(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        op: &str,
+    ) -> Result<(), OperationError> {
+        if ev.is_internal() {
+            return Ok(());
+        }
+        let filter = filter!(f_and!([
+            f_eq("uuid", PartialValue::new_uuidr(&UUID_SYSTEM_INFO)),
+            f_pres("version")
+        ]));
+        let allowed = self.impersonate_search(audit, filter.clone(), filter, ev)?;
+        if allowed.is_empty() {
+            lsecurity!(audit, "{} denied to {}", op, ev);
+            Err(OperationError::AccessDenied)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// As search_ext, but refuse with SearchSizeLimitExceeded if more than
+    /// search_result_limit entries match, or `limit` if it's lower. The limit is
+    /// applied by the backend, so a search over it doesn't load every entry.
+    fn search_ext_limited(
+        &self,
+        audit: &mut AuditScope,
+        se: &SearchEvent,
+        limit: Option<usize>,
+    ) -> Result<Vec<Entry<EntryReduced, EntryCommitted>>, OperationError> {
+        lperf_segment!(audit, "server::search_ext_limited", || {
+            let max = self.get_search_result_limit();
+            let limit = limit.map(|l| l.min(max)).unwrap_or(max);
+            let entries = self.search_size_limited(audit, se, limit)?;
+
+            let access = self.get_accesscontrols();
+            access
+                .search_filter_entry_attributes(audit, se, entries)
+                .map_err(|e| {
+                    ladmin_error!(audit, "Failed to filter entry attributes {:?}", e);
+                    e
+                })
+        })
+    }
+
     fn search(
         &self,
         audit: &mut AuditScope,
         se: &SearchEvent,
+    ) -> Result<Vec<Entry<EntrySealed, EntryCommitted>>, OperationError> {
+        self.search_size_limited(audit, se, usize::MAX)
+    }
+
+    // As search, but refuse if more than `size_limit` entries match the filter.
+    // This is checked before access controls are applied.
+    fn search_size_limited(
+        &self,
+        audit: &mut AuditScope,
+        se: &SearchEvent,
+        size_limit: usize,
     ) -> Result<Vec<Entry<EntrySealed, EntryCommitted>>, OperationError> {
         lperf_segment!(audit, "server::search", || {
             if se.event.is_internal() {
@@ -203,10 +393,16 @@ pub trait QueryServerTransaction<'a> {
             // the QS wr/ro to the plugin trait. However, there shouldn't be a need for search
             // plugis, because all data transforms should be in the write path.
 
-            let res = self.get_be_txn().search(audit, lims, &vfr).map_err(|e| {
-                ladmin_error!(audit, "backend failure -> {:?}", e);
-                OperationError::Backend
-            })?;
+            let res = self
+                .get_be_txn()
+                .search_size_limited(audit, lims, &vfr, size_limit)
+                .map_err(|e| match e {
+                    OperationError::SearchSizeLimitExceeded(_) => e,
+                    e => {
+                        ladmin_error!(audit, "backend failure -> {:?}", e);
+                        OperationError::Backend
+                    }
+                })?;
 
             // Apply ACP before we let the plugins "have at it".
             // WARNING; for external searches this is NOT the only
@@ -214,10 +410,19 @@ pub trait QueryServerTransaction<'a> {
             // attribute set on the entries!
             //
             let access = self.get_accesscontrols();
-            access.search_filter_entries(audit, se, res).map_err(|e| {
+            let mut entries = access.search_filter_entries(audit, se, res).map_err(|e| {
                 ladmin_error!(audit, "Unable to access filter entries {:?}", e);
                 e
-            })
+            })?;
+
+            // The server configuration may further restrict what anonymous can read.
+            if let EventOrigin::User(u) = &se.event.origin {
+                if u.get_uuid() == &*UUID_ANONYMOUS {
+                    let scope = self.get_anonymous_read_scope();
+                    entries.retain(|e| scope.permits(e.get_uuid()));
+                }
+            }
+            Ok(entries)
         })
     }
 
@@ -693,6 +898,35 @@ pub trait QueryServerTransaction<'a> {
             })
     }
 
+    // This is a helper to get the table of class to spn prefix from the domain
+    // info. Invalid values are refused when they are written, so any that
+    // are found here are ignored.
+    fn get_spn_prefixes(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<Vec<(String, String)>, OperationError> {
+        self.internal_search_uuid(audit, &UUID_DOMAIN_INFO)
+            .map(|e| {
+                e.get_ava_as_str("domain_spn_prefix")
+                    .map(|i| i.filter_map(SpnGenerator::parse_prefix).collect())
+                    .unwrap_or_else(Vec::new)
+            })
+            .map_err(|e| {
+                ladmin_error!(audit, "Error getting domain spn prefixes -> {:?}", e);
+                e
+            })
+    }
+
+    /// The spn generator for the current domain name and spn prefixes.
+    fn get_spn_generator(&self, audit: &mut AuditScope) -> Result<SpnGenerator, OperationError> {
+        let domain_name = self.get_domain_name(audit)?;
+        let prefixes = self.get_spn_prefixes(audit)?;
+        Ok(SpnGenerator::new(domain_name.as_str())
+            .punycode(self.get_spn_settings().idn_mode == SpnIdnMode::Punycode)
+            .sanitize(self.get_spn_settings().charset_policy == SpnCharsetPolicy::Sanitize)
+            .prefixes(&prefixes))
+    }
+
     // This is a helper to get password badlist.
     fn get_password_badlist(
         &self,
@@ -718,6 +952,108 @@ pub trait QueryServerTransaction<'a> {
                 e
             })
     }
+
+    // This is a helper to get the optional filter that limits which accounts and
+    // groups are given an spn. None means no restriction beyond the class. During
+    // bootstrap the system config may not exist yet, which is also unrestricted.
+    fn get_spn_scope(&self, audit: &mut AuditScope) -> Result<Option<ProtoFilter>, OperationError> {
+        match self.internal_search_uuid(audit, &UUID_SYSTEM_CONFIG) {
+            Ok(e) => Ok(e.get_ava_single_protofilter("spn_scope").cloned()),
+            Err(OperationError::NoMatchingEntries) => Ok(None),
+            Err(e) => Err(e),
+        }
+        .map_err(|e| {
+            ladmin_error!(audit, "Failed to retrieve system configuration {:?}", e);
+            e
+        })
+    }
+
+    // This is a helper to get the uuid of the group whose members are not given
+    // an spn. A group that is configured but doesn't exist opts out no one, so
+    // that removing the group can't stop spns being generated.
+    fn get_spn_optout_group(&self, audit: &mut AuditScope) -> Result<Option<Uuid>, OperationError> {
+        let name = match self.internal_search_uuid(audit, &UUID_SYSTEM_CONFIG) {
+            Ok(e) => e.get_ava_single_str("spn_optout_group").map(str::to_string),
+            Err(OperationError::NoMatchingEntries) => None,
+            Err(e) => {
+                ladmin_error!(audit, "Failed to retrieve system configuration {:?}", e);
+                return Err(e);
+            }
+        };
+        match name {
+            Some(name) => match self.name_to_uuid(audit, &name) {
+                Ok(u) => Ok(Some(u)),
+                Err(OperationError::NoMatchingEntries) => {
+                    ladmin_warning!(
+                        audit,
+                        "spn_optout_group {} does not exist, no entries are opted out",
+                        name
+                    );
+                    Ok(None)
+                }
+                Err(e) => Err(e),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // The spn_scope, narrowed to exclude the members of the spn_optout_group.
+    fn get_spn_effective_scope(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<Option<ProtoFilter>, OperationError> {
+        let scope = self.get_spn_scope(audit)?;
+        let optout = self.get_spn_optout_group(audit)?.map(|u| {
+            ProtoFilter::And(vec![
+                ProtoFilter::Pres("class".to_string()),
+                ProtoFilter::AndNot(Box::new(ProtoFilter::Eq(
+                    "memberof".to_string(),
+                    u.to_hyphenated_ref().to_string(),
+                ))),
+            ])
+        });
+        Ok(match (scope, optout) {
+            (Some(scope), Some(optout)) => Some(ProtoFilter::And(vec![scope, optout])),
+            (scope, None) => scope,
+            (None, optout) => optout,
+        })
+    }
+
+    // This is a helper to get if expired accounts should keep their existing spn
+    // when spns are regenerated. Defaults to false.
+    fn get_spn_skip_expired(&self, audit: &mut AuditScope) -> Result<bool, OperationError> {
+        match self.internal_search_uuid(audit, &UUID_SYSTEM_CONFIG) {
+            Ok(e) => Ok(e.get_ava_single_bool("spn_skip_expired").unwrap_or(false)),
+            Err(OperationError::NoMatchingEntries) => Ok(false),
+            Err(e) => Err(e),
+        }
+        .map_err(|e| {
+            ladmin_error!(audit, "Failed to retrieve system configuration {:?}", e);
+            e
+        })
+    }
+
+    /// The spn settings of the domain, which are held in both the domain info and
+    /// the system config.
+    fn spn_config_export(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+    ) -> Result<SpnConfig, OperationError> {
+        self.check_system_admin_access(audit, ev, "spn config export")?;
+        let spn_prefixes = self
+            .internal_search_uuid(audit, &UUID_DOMAIN_INFO)
+            .map(|e| {
+                e.get_ava_as_str("domain_spn_prefix")
+                    .map(|i| i.map(str::to_string).collect())
+                    .unwrap_or_else(Vec::new)
+            })?;
+        Ok(SpnConfig {
+            spn_prefixes,
+            spn_scope: self.get_spn_scope(audit)?,
+            spn_skip_expired: self.get_spn_skip_expired(audit)?,
+        })
+    }
 }
 
 // Actually conduct a search request
@@ -756,9 +1092,41 @@ impl<'a> QueryServerTransaction<'a> for QueryServerReadTransaction<'a> {
                 >
         }
     }
+
+    fn get_anonymous_read_scope(&self) -> AnonymousReadScope {
+        self.anonymous_read_scope
+    }
+
+    fn get_search_result_limit(&self) -> usize {
+        self.search_result_limit
+    }
+
+    fn get_spn_settings(&self) -> &SpnSettings {
+        &self.spn_settings
+    }
+
+    fn get_curtime(&self) -> Duration {
+        self.curtime
+    }
 }
 
 impl<'a> QueryServerReadTransaction<'a> {
+    /// The attributes returned by an external search that doesn't request any,
+    /// or None to return all of them.
+    pub(crate) fn get_default_search_attrs(&self) -> Option<&[String]> {
+        if self.default_search_attrs.is_empty() {
+            None
+        } else {
+            Some(self.default_search_attrs.as_slice())
+        }
+    }
+
+    /// Were spns still waiting to be regenerated after a domain rename when this
+    /// transaction began?
+    pub(crate) fn get_spn_regen_pending(&self) -> bool {
+        self.spn_regen_pending
+    }
+
     // Verify the data content of the server is as expected. This will probably
     // call various functions for validation, including possibly plugin
     // verifications.
@@ -794,6 +1162,198 @@ impl<'a> QueryServerReadTransaction<'a> {
         Plugins::run_verify(audit, self)
         // Finished
     }
+
+    // As verify, but only the plugin checks, and only of the entries matching scope.
+    // The backend, schema and index checks cover the whole database so are not run.
+    fn verify_scoped(
+        &self,
+        audit: &mut AuditScope,
+        scope: &ProtoFilter,
+    ) -> Result<Vec<Result<(), ConsistencyError>>, OperationError> {
+        let scope = Filter::from_ro(audit, &Event::from_internal(), scope, self).map_err(|e| {
+            ladmin_error!(audit, "Invalid verify filter -> {:?}", e);
+            e
+        })?;
+        // Check it now, so that a mistake isn't reported as every plugin failing.
+        scope.validate(self.get_schema()).map_err(|e| {
+            ladmin_error!(audit, "Invalid verify filter -> {:?}", e);
+            OperationError::SchemaViolation(e)
+        })?;
+        Ok(Plugins::run_verify_scoped(audit, self, &scope))
+    }
+
+    /// Convert the configured spn_scope and spn_optout_group (if any) into a
+    /// filter that can be joined with an internal search.
+    pub(crate) fn get_spn_scope_filter(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<Option<Filter<FilterInvalid>>, OperationError> {
+        match self.get_spn_effective_scope(audit)? {
+            Some(pf) => Filter::from_ro(audit, &Event::from_internal(), &pf, self)
+                .map(Some)
+                .map_err(|e| {
+                    ladmin_error!(audit, "Invalid spn_scope filter -> {:?}", e);
+                    e
+                }),
+            None => Ok(None),
+        }
+    }
+
+    /// Compute, but do not apply, the spn each account and group would have
+    /// after a domain rename to `new_domain_name`. Each mapping is written to
+    /// `out` as "old_spn<TAB>new_spn" as it's generated, so that the plan for a
+    /// large directory is not held in memory as text. Returns the number of
+    /// mappings written.
+    ///
+    /// With `anonymize`, the name and domain of each spn are replaced by stable
+    /// pseudonyms so the plan can be shared, while spns that collide or drift
+    /// still do so in the output.
+    pub fn domain_rename_plan<W: std::io::Write>(
+        &self,
+        audit: &mut AuditScope,
+        new_domain_name: &str,
+        anonymize: bool,
+        out: &mut W,
+    ) -> Result<usize, OperationError> {
+        // Match the normalisation domain_rename applies to the new name.
+        let spngen = SpnGenerator::new(new_domain_name.to_lowercase().as_str())
+            .punycode(self.get_spn_settings().idn_mode == SpnIdnMode::Punycode)
+            .sanitize(self.get_spn_settings().charset_policy == SpnCharsetPolicy::Sanitize)
+            .prefixes(&self.get_spn_prefixes(audit)?);
+        let filt = filter!(f_or!([
+            f_eq("class", PartialValue::new_class("group")),
+            f_eq("class", PartialValue::new_class("account"))
+        ]));
+        // Entries outside of the spn scope have no spn to rename.
+        let filt = match self.get_spn_scope_filter(audit)? {
+            Some(scope) => Filter::join_parts_and(filt, scope),
+            None => filt,
+        };
+        let all_cand = self.internal_search(audit, filt)?;
+
+        let mut count = 0;
+        for e in all_cand.iter() {
+            let old_spn = e
+                .get_ava_single("spn")
+                .and_then(|v| spngen.to_spn_string(v));
+            let new_spn = spngen.generate(e).and_then(|v| spngen.to_spn_string(&v));
+            let (old_spn, new_spn) = if anonymize {
+                (
+                    old_spn.as_deref().map(anonymize_spn),
+                    new_spn.as_deref().map(anonymize_spn),
+                )
+            } else {
+                (old_spn, new_spn)
+            };
+            match (old_spn, new_spn) {
+                (Some(old_spn), Some(new_spn)) => {
+                    writeln!(out, "{}\t{}", old_spn, new_spn).map_err(|e| {
+                        ladmin_error!(audit, "Failed to write rename plan -> {:?}", e);
+                        OperationError::FsError
+                    })?;
+                    count += 1;
+                }
+                _ => {
+                    ladmin_warning!(
+                        audit,
+                        "Entry {:?} has no spn or name, skipping in rename plan",
+                        e.get_uuid()
+                    );
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Find the accounts and groups whose spn is missing or doesn't match the
+    /// domain name, exactly as verify would report them.
+    pub fn spn_fsck_report(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+    ) -> Result<Vec<SpnFsckEntry>, OperationError> {
+        self.check_system_admin_access(audit, ev, "spn fsck")?;
+        let spngen = self.get_spn_generator(audit)?;
+        Plugins::run_spn_fsck(audit, self).map(|inconsistent| {
+            inconsistent
+                .into_iter()
+                .map(|(e, expected, inconsistency)| SpnFsckEntry {
+                    uuid: e.get_uuid().to_hyphenated_ref().to_string(),
+                    name: e.get_ava_single_str("name").map(str::to_string),
+                    spn: e
+                        .get_ava_single("spn")
+                        .and_then(|v| spngen.to_spn_string(v)),
+                    expected_spn: expected.and_then(|v| spngen.to_spn_string(&v)),
+                    inconsistency,
+                })
+                .collect()
+        })
+    }
+
+    /// Measure spn generation and verification over `count` synthetic entries, to
+    /// estimate how long a domain rename will take. Nothing is written.
+    pub fn spn_bench(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        count: usize,
+    ) -> Result<SpnBenchResult, OperationError> {
+        self.check_system_admin_access(audit, ev, "spn fsck")?;
+        Plugins::run_spn_bench(audit, self, count)
+    }
+
+    /// The `top` entries with the most values, largest first. Pathological entries,
+    /// such as groups with very large memberships, slow down the transactions that
+    /// touch them. Only the largest entries seen so far are kept as the directory is
+    /// scanned, so the result is bounded by `top` rather than the directory size.
+    pub fn entry_sizes(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        top: usize,
+    ) -> Result<Vec<EntrySize>, OperationError> {
+        self.check_system_admin_access(audit, ev, "entry sizes")?;
+        if top == 0 || top > ENTRY_SIZES_TOP_MAX {
+            ladmin_error!(
+                audit,
+                "entry sizes top must be between 1 and {}",
+                ENTRY_SIZES_TOP_MAX
+            );
+            return Err(OperationError::InvalidRequestState);
+        }
+
+        let mut largest = BinaryHeap::with_capacity(top + 1);
+        for e in self.internal_search(audit, filter!(f_pres("class")))? {
+            let attrs = e.get_ava_names().count();
+            let values = e
+                .get_ava_names()
+                .filter_map(|a| e.get_ava_set(a))
+                .map(|vs| vs.len())
+                .sum::<usize>();
+            // The smallest is at the top of the heap, to be replaced first.
+            largest.push(Reverse((values, attrs, *e.get_uuid())));
+            if largest.len() > top {
+                largest.pop();
+            }
+        }
+
+        largest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((values, attrs, uuid))| {
+                let name = self
+                    .internal_search_uuid(audit, &uuid)?
+                    .get_ava_single_str("name")
+                    .map(str::to_string);
+                Ok(EntrySize {
+                    uuid: uuid.to_hyphenated_ref().to_string(),
+                    name,
+                    attrs,
+                    values,
+                })
+            })
+            .collect()
+    }
 }
 
 impl<'a> QueryServerTransaction<'a> for QueryServerWriteTransaction<'a> {
@@ -829,6 +1389,22 @@ impl<'a> QueryServerTransaction<'a> for QueryServerWriteTransaction<'a> {
                 >
         }
     }
+
+    fn get_anonymous_read_scope(&self) -> AnonymousReadScope {
+        self.anonymous_read_scope
+    }
+
+    fn get_search_result_limit(&self) -> usize {
+        self.search_result_limit
+    }
+
+    fn get_spn_settings(&self) -> &SpnSettings {
+        &self.spn_settings
+    }
+
+    fn get_curtime(&self) -> Duration {
+        self.cid.ts
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -860,9 +1436,177 @@ impl QueryServer {
                 RESOLVE_FILTER_CACHE_MAX,
                 RESOLVE_FILTER_CACHE_LOCAL,
             )),
+            anonymous_read_scope: AnonymousReadScope::default(),
+            search_result_limit: DEFAULT_SEARCH_RESULT_LIMIT,
+            default_search_attrs: Arc::new(Vec::new()),
+            spn_settings: Arc::new(SpnSettings::default()),
+            spn_notifier: None,
+            spn_regen_pending: Arc::new(AtomicBool::new(false)),
+            missing_domain_name: None,
+            max_entries: None,
+            max_modify_batch: DEFAULT_MAX_MODIFY_BATCH,
         }
     }
 
+    pub fn set_anonymous_read_scope(&mut self, scope: AnonymousReadScope) {
+        self.anonymous_read_scope = scope;
+    }
+
+    /// Set the most entries an external search returns.
+    pub fn set_search_result_limit(&mut self, limit: usize) {
+        self.search_result_limit = limit;
+    }
+
+    /// Set the attributes an external search returns when it doesn't request
+    /// any. When empty, all attributes are returned.
+    pub fn set_default_search_attrs(&mut self, attrs: &[String]) {
+        self.default_search_attrs = Arc::new(attrs.to_vec());
+    }
+
+    /// Check the default search attributes are all in the schema. This needs the
+    /// schema to be loaded, so it's checked after initialise_helper.
+    pub fn validate_default_search_attrs(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<(), OperationError> {
+        let qs_read = self.read();
+        match self
+            .default_search_attrs
+            .iter()
+            .find(|a| qs_read.get_schema().normalise_attr_if_exists(a).is_none())
+        {
+            Some(a) => {
+                ladmin_error!(
+                    audit,
+                    "default_search_attrs contains {}, which is not an attribute in the schema",
+                    a
+                );
+                Err(OperationError::InvalidAttributeName(a.to_string()))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Set how the spns of accounts and groups are generated and checked.
+    pub fn set_spn_settings(&mut self, settings: SpnSettings) {
+        self.spn_settings = Arc::new(settings);
+    }
+
+    /// When set, creates that would take the number of entries in the database
+    /// over this limit are refused. Internal creates are always allowed.
+    pub fn set_max_entries(&mut self, max: Option<u64>) {
+        self.max_entries = max;
+    }
+
+    /// Set the most entries a single modify may change. This applies to internal
+    /// modifies too, such as the spn regeneration of a domain rename.
+    pub fn set_max_modify_batch(&mut self, max: usize) {
+        self.max_modify_batch = max;
+    }
+
+    /// When set, committed spn changes are queued on the notifier to be sent to
+    /// an external kdc.
+    pub(crate) fn set_spn_notifier(&mut self, notifier: Option<Arc<SpnNotifier>>) {
+        self.spn_notifier = notifier;
+    }
+
+    pub(crate) fn get_spn_notifier(&self) -> Option<Arc<SpnNotifier>> {
+        self.spn_notifier.clone()
+    }
+
+    /// Check for spns left behind by an earlier domain rename, such as when the
+    /// server restarted before regenerating them all.
+    pub fn resume_spn_regen(&self) {
+        self.spn_regen_pending.store(true, Ordering::Release);
+    }
+
+    /// Are there spns waiting to be regenerated by `spn_regenerate_chunked`?
+    pub fn spn_regen_pending(&self) -> bool {
+        self.spn_regen_pending.load(Ordering::Acquire)
+    }
+
+    /// Regenerate the spns left behind by a domain rename in spn_regen_mode
+    /// available. Each chunk is committed in its own transaction, so other writes
+    /// can run between them. Returns the number of spns regenerated.
+    pub async fn spn_regenerate_chunked(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<usize, OperationError> {
+        if !self.spn_regen_pending() {
+            return Ok(0);
+        }
+        let mut domain_name = {
+            let qs_read = self.read_async().await;
+            qs_read.get_domain_name(audit)?
+        };
+        let mut count = 0;
+        loop {
+            let qs_write = self.write_async(duration_from_epoch_now()).await;
+            let n = Plugins::run_spn_regenerate_chunk(
+                audit,
+                &qs_write,
+                self.spn_settings.regen_chunk_size,
+            )?;
+            if n == 0 {
+                // Another rename may have committed between the chunks, and the
+                // chunks before it regenerated spns in the old domain. Check in
+                // this transaction, so that a rename can't commit before pending
+                // is cleared.
+                let current = qs_write.get_domain_name(audit)?;
+                if current != domain_name {
+                    ladmin_info!(
+                        audit,
+                        "domain renamed to {} during spn regeneration, restarting",
+                        current
+                    );
+                    domain_name = current;
+                    continue;
+                }
+                // Nothing was changed, so there is nothing to commit.
+                self.spn_regen_pending.store(false, Ordering::Release);
+                ladmin_info!(
+                    audit,
+                    "spn regeneration complete, {} spns regenerated",
+                    count
+                );
+                return Ok(count);
+            }
+            qs_write.commit(audit)?;
+            count += n;
+            ladmin_info!(audit, "Regenerated {} spns, {} so far", n, count);
+        }
+    }
+
+    /// The domain name to recreate the domain info with if an existing database
+    /// is found to be missing it. When `None`, the server refuses to start instead.
+    pub fn set_missing_domain_name(&mut self, name: Option<String>) {
+        self.missing_domain_name = name;
+    }
+
+    /// Vacuum and checkpoint the database. This waits for all open transactions
+    /// to finish, and holds off new ones until it is complete.
+    pub(crate) async fn db_maintenance_async(
+        &self,
+        audit: &mut AuditScope,
+        vacuum: bool,
+    ) -> Result<DbMaintenanceStats, OperationError> {
+        // Take the write ticket first, as a writer would, so we can't deadlock with one.
+        #[allow(clippy::expect_used)]
+        let _write_ticket = self
+            .write_ticket
+            .acquire()
+            .await
+            .expect("unable to aquire writer_ticket for db maintenance");
+        #[allow(clippy::expect_used)]
+        let _db_tickets = self
+            .db_tickets
+            .acquire_many(self.be.get_pool_size())
+            .await
+            .expect("unable to aquire db_tickets for db maintenance");
+
+        self.be.maintenance(audit, vacuum)
+    }
+
     #[cfg(test)]
     pub fn read(&self) -> QueryServerReadTransaction {
         task::block_on(self.read_async())
@@ -883,6 +1627,12 @@ impl QueryServer {
             accesscontrols: self.accesscontrols.read(),
             _db_ticket: db_ticket,
             resolve_filter_cache: Cell::new(self.resolve_filter_cache.read()),
+            anonymous_read_scope: self.anonymous_read_scope,
+            search_result_limit: self.search_result_limit,
+            default_search_attrs: self.default_search_attrs.clone(),
+            spn_settings: self.spn_settings.clone(),
+            spn_regen_pending: self.spn_regen_pending(),
+            curtime: duration_from_epoch_now(),
         }
     }
 
@@ -935,6 +1685,17 @@ impl QueryServer {
             _db_ticket: db_ticket,
             _write_ticket: write_ticket,
             resolve_filter_cache: Cell::new(self.resolve_filter_cache.read()),
+            anonymous_read_scope: self.anonymous_read_scope,
+            search_result_limit: self.search_result_limit,
+            spn_settings: self.spn_settings.clone(),
+            spn_log_count: Cell::new(0),
+            spn_notifier: self.spn_notifier.clone(),
+            spn_changes: RefCell::new(Vec::new()),
+            max_entries: self.max_entries,
+            max_modify_batch: self.max_modify_batch,
+            spn_regen_class: Cell::new(None),
+            spn_regen_pending: self.spn_regen_pending.clone(),
+            spn_regen_deferred: Cell::new(false),
         }
     }
 
@@ -1008,6 +1769,12 @@ impl QueryServer {
             migrate_txn.migrate_2_to_3(audit)?;
         }
 
+        // A new database is given its domain info by initialise_idm, but an existing
+        // one must already have it.
+        if system_info_version > 0 {
+            migrate_txn.check_domain_info(audit, self.missing_domain_name.as_deref())?;
+        }
+
         migrate_txn.commit(audit)?;
         // Migrations complete. Init idm will now set the version as needed.
 
@@ -1024,9 +1791,89 @@ impl QueryServer {
         let r_txn = task::block_on(self.read_async());
         r_txn.verify(audit)
     }
+
+    pub fn verify_scoped(
+        &self,
+        audit: &mut AuditScope,
+        scope: &ProtoFilter,
+    ) -> Result<Vec<Result<(), ConsistencyError>>, OperationError> {
+        let r_txn = task::block_on(self.read_async());
+        r_txn.verify_scoped(audit, scope)
+    }
 }
 
 impl<'a> QueryServerWriteTransaction<'a> {
+    /// Should a changed spn be logged at info, rather than trace? With
+    /// spn_log_level info, only the first SPN_LOG_INFO_MAX changes in this
+    /// transaction are, so that bulk operations such as a domain rename don't
+    /// flood the log.
+    pub(crate) fn spn_log_at_info(&self, audit: &mut AuditScope) -> bool {
+        if self.spn_settings.log_level != SpnLogLevel::Info {
+            return false;
+        }
+        let count = self.spn_log_count.get() + 1;
+        self.spn_log_count.set(count);
+        if count == SPN_LOG_INFO_MAX + 1 {
+            ladmin_info!(
+                audit,
+                "More than {} spns changed, further changes are logged at trace",
+                SPN_LOG_INFO_MAX
+            );
+        }
+        count <= SPN_LOG_INFO_MAX
+    }
+
+    /// The number of entries in the database, including those created by this
+    /// transaction.
+    pub(crate) fn get_entry_count(&self) -> u64 {
+        self.be_txn.get_entry_count()
+    }
+
+    /// The class a domain rename is limited to regenerating the spns of, if any. This
+    /// is only given out once, so that it applies to a single regeneration.
+    pub(crate) fn take_spn_regen_class(&self) -> Option<PartialValue> {
+        self.spn_regen_class.take()
+    }
+
+    /// Leave the spns of a domain rename to be regenerated in chunks once this
+    /// commits.
+    pub(crate) fn defer_spn_regen(&self) {
+        self.spn_regen_deferred.set(true);
+    }
+
+    /// Record an spn change for the kdc. This does nothing unless a notifier is
+    /// configured.
+    pub(crate) fn record_spn_change(&self, change: SpnChange) {
+        if self.spn_notifier.is_some() {
+            self.spn_changes.borrow_mut().push(change);
+        }
+    }
+
+    /// As `QueryServerReadTransaction::get_spn_scope_filter`, but validated and
+    /// resolved so that entries which are not yet committed can be matched
+    /// against it.
+    pub(crate) fn get_spn_scope_filter(
+        &self,
+        audit: &mut AuditScope,
+    ) -> Result<Option<Filter<FilterValidResolved>>, OperationError> {
+        let pf = match self.get_spn_effective_scope(audit)? {
+            Some(pf) => pf,
+            None => return Ok(None),
+        };
+        let ev = Event::from_internal();
+        Filter::from_rw(audit, &ev, &pf, self)
+            .and_then(|f| {
+                f.validate(self.get_schema())
+                    .map_err(OperationError::SchemaViolation)
+            })
+            .and_then(|f| f.resolve(&ev, None, None))
+            .map(Some)
+            .map_err(|e| {
+                ladmin_error!(audit, "Invalid spn_scope filter -> {:?}", e);
+                e
+            })
+    }
+
     pub fn create(&self, audit: &mut AuditScope, ce: &CreateEvent) -> Result<(), OperationError> {
         lperf_segment!(audit, "server::create", || {
             // The create event is a raw, read only representation of the request
@@ -1058,6 +1905,22 @@ impl<'a> QueryServerWriteTransaction<'a> {
                 return Err(OperationError::AccessDenied);
             }
 
+            // Check the limit before any plugins run, so that we don't do work
+            // for a create that can't succeed.
+            if let Some(max) = self.max_entries {
+                let count = self.get_entry_count();
+                if !ce.event.is_internal() && count + candidates.len() as u64 > max {
+                    ladmin_error!(
+                        audit,
+                        "Refusing to create {} entries, the database holds {} of max_entries {}",
+                        candidates.len(),
+                        count,
+                        max
+                    );
+                    return Err(OperationError::MaxEntriesExceeded(max));
+                }
+            }
+
             // Assign our replication metadata now, since we can proceed with this operation.
             let mut candidates: Vec<Entry<EntryInvalid, EntryNew>> = candidates
                 .into_iter()
@@ -1529,6 +2392,20 @@ impl<'a> QueryServerWriteTransaction<'a> {
                 }
             };
 
+            // Check the limit before the candidates are cloned, as that's what would
+            // exhaust memory.
+            if pre_candidates.len() > self.max_modify_batch {
+                ladmin_error!(
+                    audit,
+                    "Refusing to modify {} entries, more than max_modify_batch {}. Narrow the filter, or for a domain rename use domain_name_change --class to regenerate accounts and groups separately.",
+                    pre_candidates.len(),
+                    self.max_modify_batch
+                );
+                return Err(OperationError::MaxModifyBatchExceeded(
+                    self.max_modify_batch,
+                ));
+            }
+
             // Are we allowed to make the changes we want to?
             // modify_allow_operation
             let access = self.get_accesscontrols();
@@ -1801,6 +2678,55 @@ impl<'a> QueryServerWriteTransaction<'a> {
     }
 
     /// Migrate 2 to 3 changes the name, domain_name types from iutf8 to iname.
+    // If the domain info of an existing database is lost, initialise_idm would quietly
+    // create it again with the default domain name, leaving every spn inconsistent.
+    // Instead either refuse, or recreate it with the configured name and regenerate
+    // the spns to match.
+    pub(crate) fn check_domain_info(
+        &self,
+        audit: &mut AuditScope,
+        missing_domain_name: Option<&str>,
+    ) -> Result<(), OperationError> {
+        match self.internal_search_uuid(audit, &UUID_DOMAIN_INFO) {
+            Ok(_) => return Ok(()),
+            Err(OperationError::NoMatchingEntries) => {}
+            Err(e) => return Err(e),
+        };
+
+        let domain_name = match missing_domain_name {
+            Some(n) => n,
+            None => {
+                ladmin_error!(
+                    audit,
+                    "The domain info of this database is missing. Restore from a backup, or set missing_domain_name in the server configuration to recreate it."
+                );
+                return Err(OperationError::MissingDomainInfo);
+            }
+        };
+
+        ladmin_info!(
+            audit,
+            "The domain info of this database is missing, recreating it with domain name {}",
+            domain_name
+        );
+        let mut e = Entry::from_proto_entry_str(audit, JSON_DOMAIN_INFO_V1, self)?;
+        e.add_ava("domain_name", Value::new_iname(domain_name));
+        self.internal_create(audit, vec![e])?;
+
+        // As in a domain rename, the spn plugin regenerates the purged spns.
+        self.internal_modify(
+            audit,
+            &filter!(f_and!([
+                f_or!([
+                    f_eq("class", PartialValue::new_class("group")),
+                    f_eq("class", PartialValue::new_class("account"))
+                ]),
+                f_andnot(f_eq("spn_locked", PartialValue::new_bool(true)))
+            ])),
+            &ModifyList::new_purge("spn"),
+        )
+    }
+
     pub fn migrate_2_to_3(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
         lperf_segment!(audit, "server::migrate_2_to_3", || {
             ladmin_warning!(
@@ -2178,12 +3104,20 @@ impl<'a> QueryServerWriteTransaction<'a> {
             JSON_SCHEMA_ATTR_UNIX_PASSWORD,
             JSON_SCHEMA_ATTR_ACCOUNT_EXPIRE,
             JSON_SCHEMA_ATTR_ACCOUNT_VALID_FROM,
+            JSON_SCHEMA_ATTR_SPN_INDEX,
+            JSON_SCHEMA_ATTR_SPN_SCOPE,
+            JSON_SCHEMA_ATTR_SPN_SKIP_EXPIRED,
+            JSON_SCHEMA_ATTR_SPN_LOCKED,
+            JSON_SCHEMA_ATTR_DOMAIN_SPN_PREFIX,
+            JSON_SCHEMA_ATTR_SPN_OPTOUT_GROUP,
+            JSON_SCHEMA_ATTR_ORIGIN_DOMAIN,
             JSON_SCHEMA_CLASS_PERSON,
             JSON_SCHEMA_CLASS_GROUP,
             JSON_SCHEMA_CLASS_ACCOUNT,
             JSON_SCHEMA_CLASS_DOMAIN_INFO,
             JSON_SCHEMA_CLASS_POSIXACCOUNT,
             JSON_SCHEMA_CLASS_POSIXGROUP,
+            JSON_SCHEMA_CLASS_SPN_EXEMPT,
             JSON_SCHEMA_CLASS_SYSTEM_CONFIG,
             JSON_SCHEMA_ATTR_NSUNIQUEID,
         ];
@@ -2306,6 +3240,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
             JSON_IDM_ACP_PEOPLE_EXTEND_PRIV_V1,
             JSON_IDM_HP_ACP_ACCOUNT_UNIX_EXTEND_PRIV_V1,
             JSON_IDM_HP_ACP_GROUP_UNIX_EXTEND_PRIV_V1,
+            JSON_IDM_ACP_SYSTEM_ADMIN_OPS_PRIV_V1,
         ];
 
         let res: Result<(), _> = idm_entries
@@ -2539,6 +3474,265 @@ impl<'a> QueryServerWriteTransaction<'a> {
         self.internal_modify(audit, &filt, &modl)
     }
 
+    /// As `domain_rename`, but only regenerate the spns of entries with `class`, which
+    /// must be account or group. Other entries keep their spn in the old domain until
+    /// this is repeated for their class, which allows the principals users log in
+    /// with to be restored first. As the domain name is already changed by then,
+    /// later passes only regenerate spns.
+    pub fn domain_rename_scoped(
+        &self,
+        audit: &mut AuditScope,
+        new_domain_name: &str,
+        class: &str,
+    ) -> Result<(), OperationError> {
+        if class != "account" && class != "group" {
+            ladmin_error!(
+                audit,
+                "spn regeneration can only be scoped to account or group, not {}",
+                class
+            );
+            return Err(OperationError::InvalidRequestState);
+        }
+        self.spn_regen_class
+            .set(Some(PartialValue::new_class(class)));
+        self.domain_rename(audit, new_domain_name)?;
+        // If the name didn't change, nothing was regenerated by the rename.
+        match self.spn_regen_class.take() {
+            Some(class) => Plugins::run_spn_regenerate(audit, self, Some(&class)),
+            None => Ok(()),
+        }
+    }
+
+    /// Rename the domain exactly as `domain_rename` (or `domain_rename_scoped` with
+    /// `class`) would, measure the regeneration, and then abandon the transaction.
+    /// As the real modify path runs, this is the most accurate estimate of what a
+    /// rename will cost, but nothing is changed. The transaction is consumed so it
+    /// can't be committed afterwards.
+    pub fn domain_rename_simulate(
+        self,
+        audit: &mut AuditScope,
+        new_domain_name: &str,
+        class: Option<&str>,
+        samples: usize,
+    ) -> Result<DomainRenameSimulation, OperationError> {
+        let filt = filter!(f_or!([
+            f_eq("class", PartialValue::new_class("group")),
+            f_eq("class", PartialValue::new_class("account"))
+        ]));
+        let spn_of = |e: &Entry<EntrySealed, EntryCommitted>| {
+            e.get_ava_single("spn").map(|v| v.to_proto_string_clone())
+        };
+        let before: BTreeMap<Uuid, Option<String>> = self
+            .internal_search(audit, filt.clone())?
+            .iter()
+            .map(|e| (*e.get_uuid(), spn_of(e)))
+            .collect();
+
+        let start = Instant::now();
+        match class {
+            Some(class) => self.domain_rename_scoped(audit, new_domain_name, class),
+            None => self.domain_rename(audit, new_domain_name),
+        }?;
+        // In spn_regen_mode available the spns would be regenerated after the
+        // commit, so regenerate them here to measure the whole cost.
+        if self.spn_regen_deferred.get() {
+            Plugins::run_spn_regenerate(audit, &self, None)?;
+        }
+        let elapsed = start.elapsed();
+
+        let changed: Vec<(Option<String>, Option<String>)> = self
+            .internal_search(audit, filt)?
+            .iter()
+            .filter_map(|e| {
+                let old = before.get(e.get_uuid()).cloned().flatten();
+                let new = spn_of(e);
+                if old != new {
+                    Some((old, new))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        ladmin_info!(
+            audit,
+            "simulated domain rename regenerated {} of {} spns in {:?}, rolling back",
+            changed.len(),
+            before.len(),
+            elapsed
+        );
+
+        // self is dropped without a commit, so every change is rolled back.
+        Ok(DomainRenameSimulation {
+            candidates: before.len(),
+            changed: changed.len(),
+            elapsed,
+            samples: changed.into_iter().take(samples).collect(),
+        })
+    }
+
+    /// Regenerate the spn of these accounts and groups from the current domain name.
+    /// As with a domain rename, the spn is purged and the spn plugin recreates it.
+    pub fn spn_fsck_repair(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        uuids: &[Uuid],
+    ) -> Result<(), OperationError> {
+        self.check_system_admin_access(audit, ev, "spn fsck")?;
+        if uuids.is_empty() {
+            return Ok(());
+        }
+        let filt = filter!(f_or(
+            uuids
+                .iter()
+                .map(|u| f_eq("uuid", PartialValue::new_uuidr(u)))
+                .collect()
+        ));
+        self.internal_modify(audit, &filt, &ModifyList::new_purge("spn"))
+    }
+
+    /// Remove spn_locked from the entries matching filter, so that their spns are
+    /// generated again, returning how many were unlocked.
+    pub fn spn_unlock(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        filter: Filter<FilterInvalid>,
+    ) -> Result<usize, OperationError> {
+        self.check_system_admin_access(audit, ev, "spn unlock")?;
+        let filt = Filter::join_parts_and(
+            filter,
+            filter!(f_eq("spn_locked", PartialValue::new_bool(true))),
+        );
+        let locked = self.internal_search(audit, filt.clone())?;
+        if locked.is_empty() {
+            return Ok(0);
+        }
+        // As with a single unlock, purging the spn has the spn plugin generate it.
+        self.internal_modify(
+            audit,
+            &filt,
+            &modlist!([m_purge("spn_locked"), m_purge("spn")]),
+        )?;
+        ladmin_info!(audit, "unlocked the spns of {} entries", locked.len());
+        Ok(locked.len())
+    }
+
+    /// Regenerate the spn of a single account or group, without the directory wide
+    /// work of a domain rename or fsck. A locked spn is refused, as purging it would
+    /// silently discard the lock's spn.
+    pub fn spn_regenerate(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        uuid: &Uuid,
+    ) -> Result<SpnRegenerateResult, OperationError> {
+        self.check_system_admin_access(audit, ev, "spn regenerate")?;
+        let spngen = self.get_spn_generator(audit)?;
+        let spn_of = |e: &Entry<EntrySealed, EntryCommitted>| {
+            e.get_ava_single("spn")
+                .and_then(|v| spngen.to_spn_string(v))
+        };
+
+        let e = self.internal_search_uuid(audit, uuid)?;
+        if !e.attribute_value_pres("class", &PVCLASS_ACCOUNT)
+            && !e.attribute_value_pres("class", &PVCLASS_GROUP)
+        {
+            ladmin_error!(audit, "{} is not an account or group, it has no spn", uuid);
+            return Err(OperationError::InvalidRequestState);
+        }
+        if e.get_ava_single_bool("spn_locked").unwrap_or(false) {
+            ladmin_error!(
+                audit,
+                "{} has a locked spn, unset it to have the spn generated",
+                uuid
+            );
+            return Err(OperationError::InvalidRequestState);
+        }
+        if let Some(domain) = e
+            .get_ava_single_str("origin_domain")
+            .filter(|d| self.get_spn_settings().trusted_domains.contains(*d))
+        {
+            ladmin_error!(
+                audit,
+                "{} has its spn from the trusted domain {}, it can't be generated here",
+                uuid,
+                domain
+            );
+            return Err(OperationError::InvalidRequestState);
+        }
+        let spn_before = spn_of(&e);
+
+        let filt = filter!(f_eq("uuid", PartialValue::new_uuidr(uuid)));
+        self.internal_modify(audit, &filt, &ModifyList::new_purge("spn"))?;
+
+        let e = self.internal_search_uuid(audit, uuid)?;
+        Ok(SpnRegenerateResult {
+            uuid: uuid.to_hyphenated_ref().to_string(),
+            spn_before,
+            spn_after: spn_of(&e),
+        })
+    }
+
+    /// Replace the spn settings of the domain with `config`. It is checked as a
+    /// whole before anything is changed, and spns are regenerated if the prefixes
+    /// or scope differ from the current settings.
+    pub fn spn_config_import(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        config: &SpnConfig,
+    ) -> Result<(), OperationError> {
+        self.check_system_admin_access(audit, ev, "spn config import")?;
+
+        if let Some(invalid) = config
+            .spn_prefixes
+            .iter()
+            .find(|s| SpnGenerator::parse_prefix(s).is_none())
+        {
+            ladmin_error!(audit, "Refusing spn config with invalid prefix {}", invalid);
+            return Err(OperationError::InvalidAttribute(format!(
+                "spn prefix {} must be in the form class=prefix",
+                invalid
+            )));
+        }
+        if let Some(pf) = &config.spn_scope {
+            Filter::from_rw(audit, &Event::from_internal(), pf, self)
+                .and_then(|f| {
+                    f.validate(self.get_schema())
+                        .map_err(OperationError::SchemaViolation)
+                })
+                .map_err(|e| {
+                    ladmin_error!(audit, "Refusing spn config with invalid scope -> {:?}", e);
+                    e
+                })?;
+        }
+
+        let mut scope_mods = vec![Modify::Purged("spn_scope".to_string())];
+        if let Some(pf) = &config.spn_scope {
+            let pf = serde_json::to_string(pf).map_err(|_| OperationError::SerdeJsonError)?;
+            let v = Value::new_json_filter(&pf).ok_or(OperationError::InvalidValueState)?;
+            scope_mods.push(Modify::Present("spn_scope".to_string(), v));
+        }
+        scope_mods.push(Modify::Purged("spn_skip_expired".to_string()));
+        scope_mods.push(Modify::Present(
+            "spn_skip_expired".to_string(),
+            Value::new_bool(config.spn_skip_expired),
+        ));
+        let filt = filter_all!(f_eq("uuid", PartialValue::new_uuidr(&UUID_SYSTEM_CONFIG)));
+        self.internal_modify(audit, &filt, &ModifyList::new_list(scope_mods))?;
+
+        let mut prefix_mods = vec![Modify::Purged("domain_spn_prefix".to_string())];
+        prefix_mods.extend(
+            config
+                .spn_prefixes
+                .iter()
+                .map(|s| Modify::Present("domain_spn_prefix".to_string(), Value::new_utf8s(s))),
+        );
+        let filt = filter_all!(f_eq("uuid", PartialValue::new_uuidr(&UUID_DOMAIN_INFO)));
+        self.internal_modify(audit, &filt, &ModifyList::new_list(prefix_mods))
+    }
+
     pub fn reindex(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
         // initiate a be reindex here. This could have been from first run checking
         // the versions, or it could just be from the cli where an admin needs to do an
@@ -2586,10 +3780,20 @@ impl<'a> QueryServerWriteTransaction<'a> {
             schema,
             accesscontrols,
             cid,
+            spn_notifier,
+            spn_changes,
+            spn_regen_pending,
+            spn_regen_deferred,
             ..
         } = self;
         debug_assert!(!committed);
 
+        // Set before the rename is visible, so that no reader sees the new
+        // domain name without knowing the spns are still being regenerated.
+        if spn_regen_deferred.get() {
+            spn_regen_pending.store(true, Ordering::Release);
+        }
+
         // Write the cid to the db. If this fails, we can't assume replication
         // will be stable, so return if it fails.
         be_txn.set_db_ts_max(&cid.ts)?;
@@ -2602,6 +3806,12 @@ impl<'a> QueryServerWriteTransaction<'a> {
             schema
                 .commit()
                 .and_then(|_| accesscontrols.commit().and_then(|_| be_txn.commit(audit)))
+                .map(|_| {
+                    // Only now are the changes durable, so the kdc can be told.
+                    if let Some(notifier) = spn_notifier {
+                        notifier.enqueue(spn_changes.into_inner());
+                    }
+                })
         } else {
             Err(OperationError::ConsistencyError(r))
         }
@@ -2613,12 +3823,19 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
 #[cfg(test)]
 mod tests {
+    use crate::config::{AnonymousReadScope, SpnLogLevel};
     use crate::credential::policy::CryptoPolicy;
     use crate::credential::Credential;
-    use crate::event::{CreateEvent, DeleteEvent, ModifyEvent, ReviveRecycledEvent, SearchEvent};
+    use crate::event::{
+        CreateEvent, DeleteEvent, Event, ModifyEvent, ReviveRecycledEvent, SearchEvent,
+    };
     use crate::modify::{Modify, ModifyList};
     use crate::prelude::*;
-    use kanidm_proto::v1::SchemaError;
+    use crate::server::SpnSettings;
+    use crate::utils::pseudonym;
+    use kanidm_proto::v1::{
+        ConsistencyError, Filter as ProtoFilter, SchemaError, SpnConfig, SpnInconsistency,
+    };
     use std::time::Duration;
 
     #[test]
@@ -2665,6 +3882,202 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_qs_spn_log_level() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // By default, spns are only logged at trace.
+            let server_txn = server.write(duration_from_epoch_now());
+            assert!(!server_txn.spn_log_at_info(audit));
+            drop(server_txn);
+
+            let mut server = server.clone();
+            server.set_spn_settings(SpnSettings {
+                log_level: SpnLogLevel::Info,
+                ..Default::default()
+            });
+            let server_txn = server.write(duration_from_epoch_now());
+            assert!((0..SPN_LOG_INFO_MAX).all(|_| server_txn.spn_log_at_info(audit)));
+            // Past the limit, the rest of the transaction logs at trace.
+            assert!(!server_txn.spn_log_at_info(audit));
+            assert!(!server_txn.spn_log_at_info(audit));
+            drop(server_txn);
+
+            // The limit is per transaction.
+            let server_txn = server.write(duration_from_epoch_now());
+            assert!(server_txn.spn_log_at_info(audit));
+        });
+    }
+
+    #[test]
+    fn test_qs_default_search_attrs() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server = server.clone();
+            assert!(server.validate_default_search_attrs(audit).is_ok());
+            assert!(server.read().get_default_search_attrs().is_none());
+
+            let attrs = vec!["name".to_string(), "spn".to_string()];
+            server.set_default_search_attrs(&attrs);
+            assert!(server.validate_default_search_attrs(audit).is_ok());
+            assert!(server.read().get_default_search_attrs() == Some(attrs.as_slice()));
+
+            server.set_default_search_attrs(&["name".to_string(), "nonexistent".to_string()]);
+            assert!(
+                server.validate_default_search_attrs(audit)
+                    == Err(OperationError::InvalidAttributeName(
+                        "nonexistent".to_string()
+                    ))
+            );
+        });
+    }
+
+    #[test]
+    fn test_qs_search_result_limit() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server = server.clone();
+            server.set_search_result_limit(3);
+
+            let server_txn = server.read();
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let total = server_txn
+                .internal_search(audit, filter!(f_pres("class")))
+                .expect("failed")
+                .len();
+            assert!(total > 3);
+            let se = unsafe { SearchEvent::new_impersonate_entry(admin, filter!(f_pres("class"))) };
+
+            // A search matching more than the configured limit is refused.
+            assert!(matches!(
+                server_txn.search_ext_limited(audit, &se, None),
+                Err(OperationError::SearchSizeLimitExceeded(3))
+            ));
+
+            // A request can lower the limit, but not raise it.
+            assert!(matches!(
+                server_txn.search_ext_limited(audit, &se, Some(2)),
+                Err(OperationError::SearchSizeLimitExceeded(2))
+            ));
+            assert!(matches!(
+                server_txn.search_ext_limited(audit, &se, Some(total)),
+                Err(OperationError::SearchSizeLimitExceeded(3))
+            ));
+
+            // Results within the limit are returned.
+            let se = unsafe {
+                SearchEvent::new_impersonate_entry(
+                    server_txn
+                        .internal_search_uuid(audit, &UUID_ADMIN)
+                        .expect("failed"),
+                    filter!(f_eq("name", PartialValue::new_iname("admin"))),
+                )
+            };
+            let entries = server_txn
+                .search_ext_limited(audit, &se, None)
+                .expect("failed");
+            assert!(entries.len() == 1);
+        });
+    }
+
+    #[test]
+    fn test_qs_modify_max_modify_batch() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server = server.clone();
+            server.set_max_modify_batch(2);
+
+            let server_txn = server.write(duration_from_epoch_now());
+            let group = |name: &str| {
+                entry_init!(
+                    ("class", Value::new_class("object")),
+                    ("class", Value::new_class("group")),
+                    ("name", Value::new_iname(name)),
+                    ("description", Value::new_utf8s("batch"))
+                )
+            };
+            let ce = CreateEvent::new_internal(vec![
+                group("testgroup_a"),
+                group("testgroup_b"),
+                group("testgroup_c"),
+            ]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            let modl = ModifyList::new_purge_and_set("description", Value::new_utf8s("changed"));
+            let described = |s: &str| filter!(f_eq("description", PartialValue::new_utf8s(s)));
+
+            // Up to the limit is allowed.
+            let filt = filter!(f_or!([
+                f_eq("name", PartialValue::new_iname("testgroup_a")),
+                f_eq("name", PartialValue::new_iname("testgroup_b"))
+            ]));
+            assert!(server_txn.internal_modify(audit, &filt, &modl).is_ok());
+
+            // Beyond it is not, and nothing is changed.
+            let filt = filter!(f_eq("class", PartialValue::new_class("group")));
+            assert!(matches!(
+                server_txn.internal_modify(audit, &filt, &modl),
+                Err(OperationError::MaxModifyBatchExceeded(2))
+            ));
+            assert!(
+                server_txn
+                    .internal_search(audit, described("changed"))
+                    .expect("failed")
+                    .len()
+                    == 2
+            );
+            assert!(
+                server_txn
+                    .internal_search(audit, described("batch"))
+                    .expect("failed")
+                    .len()
+                    == 1
+            );
+            assert!(server_txn.commit(audit).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_qs_create_max_entries() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let count = server.write(duration_from_epoch_now()).get_entry_count();
+            let mut server = server.clone();
+            server.set_max_entries(Some(count + 1));
+
+            let server_txn = server.write(duration_from_epoch_now());
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let group = |name: &str| {
+                entry_init!(
+                    ("class", Value::new_class("object")),
+                    ("class", Value::new_class("group")),
+                    ("name", Value::new_iname(name))
+                )
+            };
+
+            // Up to the limit is allowed.
+            let ce = CreateEvent {
+                event: Event::from_impersonate_entry(admin.clone()),
+                entries: vec![group("testgroup_a")],
+            };
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            // Beyond it is not.
+            let ce = CreateEvent {
+                event: Event::from_impersonate_entry(admin),
+                entries: vec![group("testgroup_b")],
+            };
+            assert!(matches!(
+                server_txn.create(audit, &ce),
+                Err(OperationError::MaxEntriesExceeded(max)) if max == count + 1
+            ));
+
+            // Internal creates, such as migrations, are never limited.
+            let ce = CreateEvent::new_internal(vec![group("testgroup_c")]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+        });
+    }
+
     #[test]
     fn test_qs_init_idempotent_schema_core() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
@@ -3953,6 +5366,616 @@ mod tests {
     }
     */
 
+    #[test]
+    fn test_qs_domain_rename_plan() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_r_txn = server.read();
+            let mut out: Vec<u8> = Vec::new();
+            let count = server_r_txn
+                .domain_rename_plan(audit, "New.Example.COM", false, &mut out)
+                .expect("must not fail");
+            let plan = String::from_utf8(out).expect("Invalid utf8");
+            assert!(count == plan.lines().count());
+            assert!(plan
+                .lines()
+                .any(|l| l == "admin@example.com\tadmin@new.example.com"));
+            assert!(plan.lines().all(|l| l.ends_with("@new.example.com")));
+
+            // Nothing was changed by planning.
+            assert!(server_r_txn.get_domain_name(audit) == Ok("example.com".to_string()));
+            let e = server_r_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("must not fail");
+            assert!(
+                e.attribute_value_pres("spn", &PartialValue::new_spn_nrs("admin", "example.com"))
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_domain_rename_simulate() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let sim = server
+                .write(duration_from_epoch_now())
+                .domain_rename_simulate(audit, "new.example.com", None, 5)
+                .expect("must not fail");
+            assert!(sim.candidates > 0);
+            assert!(sim.changed == sim.candidates);
+            assert!(sim.samples.len() == 5.min(sim.changed));
+            assert!(sim.samples.iter().all(|(old, new)| {
+                old.as_deref().map(|s| s.ends_with("@example.com")) == Some(true)
+                    && new.as_deref().map(|s| s.ends_with("@new.example.com")) == Some(true)
+            }));
+
+            // Only the accounts are regenerated when the rename is scoped.
+            let scoped = server
+                .write(duration_from_epoch_now())
+                .domain_rename_simulate(audit, "new.example.com", Some("account"), 0)
+                .expect("must not fail");
+            assert!(scoped.changed > 0 && scoped.changed < sim.changed);
+            assert!(scoped.samples.is_empty());
+
+            // Nothing was changed by simulating.
+            let server_r_txn = server.read();
+            assert!(server_r_txn.get_domain_name(audit) == Ok("example.com".to_string()));
+            let e = server_r_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("must not fail");
+            assert!(
+                e.attribute_value_pres("spn", &PartialValue::new_spn_nrs("admin", "example.com"))
+            );
+            let e = server_r_txn
+                .internal_search_uuid(audit, &UUID_SYSTEM_ADMINS)
+                .expect("must not fail");
+            assert!(e.attribute_value_pres(
+                "spn",
+                &PartialValue::new_spn_nrs("system_admins", "example.com")
+            ));
+        })
+    }
+
+    #[test]
+    fn test_qs_domain_rename_plan_anonymize() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_r_txn = server.read();
+            let mut plan = |anonymize| {
+                let mut out: Vec<u8> = Vec::new();
+                server_r_txn
+                    .domain_rename_plan(audit, "new.example.com", anonymize, &mut out)
+                    .expect("must not fail");
+                String::from_utf8(out).expect("Invalid utf8")
+            };
+            let clear = plan(false);
+            let anon = plan(true);
+
+            // The same input always gives the same pseudonyms.
+            assert!(anon == plan(true));
+            assert!(clear.lines().count() == anon.lines().count());
+            assert!(!anon.contains("admin") && !anon.contains("example.com"));
+
+            // A rename keeps the name and changes the domain, which is still visible.
+            let old_domain = format!("@d{}\t", pseudonym("example.com"));
+            let new_domain = format!("@d{}", pseudonym("new.example.com"));
+            assert!(anon.lines().all(|l| {
+                let mut parts = l.split('@');
+                let name = parts.next();
+                l.contains(&old_domain)
+                    && l.ends_with(&new_domain)
+                    && name.map(|n| l.contains(&format!("\t{}@", n))) == Some(true)
+            }));
+        })
+    }
+
+    #[test]
+    fn test_qs_anonymous_read_scope() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let anon = server
+                .read()
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("must not fail");
+            let se = unsafe { SearchEvent::new_impersonate_entry(anon, filter!(f_pres("class"))) };
+
+            // By default anonymous can read whatever access controls allow.
+            let r = server.read().search(audit, &se).expect("must not fail");
+            assert!(r.len() > 2);
+
+            let mut server = server.clone();
+            server.set_anonymous_read_scope(AnonymousReadScope::DomainInfo);
+            let r = server.read().search(audit, &se).expect("must not fail");
+            assert!(r.len() == 2);
+            assert!(r.iter().any(|e| e.get_uuid() == &*UUID_DOMAIN_INFO));
+            assert!(r.iter().any(|e| e.get_uuid() == &*UUID_ANONYMOUS));
+
+            server.set_anonymous_read_scope(AnonymousReadScope::Nothing);
+            let r = server.read().search(audit, &se).expect("must not fail");
+            assert!(r.len() == 1);
+            assert!(r[0].get_uuid() == &*UUID_ANONYMOUS);
+
+            // Internal searches are never restricted.
+            let r = server
+                .read()
+                .internal_search(audit, filter!(f_pres("class")))
+                .expect("must not fail");
+            assert!(r.len() > 2);
+        })
+    }
+
+    #[test]
+    fn test_qs_spn_fsck() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let e_pre = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("must not fail");
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("must not fail");
+
+            // Bypass the plugins to remove the spn.
+            let mut e_broken = unsafe { e_pre.clone().into_invalid() };
+            e_broken.purge_ava("spn");
+            let e_broken = unsafe { e_broken.into_sealed_committed() };
+            server_txn
+                .get_be_txn()
+                .modify(audit, &[e_pre.clone()], &[e_broken])
+                .expect("must not fail");
+            server_txn.commit(audit).expect("must not fail");
+
+            // admin is a member of system_admins, anonymous is not.
+            let admin_ev = Event::from_impersonate_entry(e_pre);
+            let anon_ev = Event::from_impersonate_entry(anon);
+
+            let server_r_txn = server.read();
+            assert!(matches!(
+                server_r_txn.spn_fsck_report(audit, &anon_ev),
+                Err(OperationError::AccessDenied)
+            ));
+            let found = server_r_txn
+                .spn_fsck_report(audit, &admin_ev)
+                .expect("must not fail");
+            assert!(found.len() == 1);
+            assert!(found[0].inconsistency == SpnInconsistency::Missing);
+            assert!(found[0].spn.is_none());
+            assert!(found[0].expected_spn.is_some());
+            std::mem::drop(server_r_txn);
+
+            let server_txn = server.write(duration_from_epoch_now());
+            assert!(matches!(
+                server_txn.spn_fsck_repair(audit, &anon_ev, &[*UUID_ADMIN]),
+                Err(OperationError::AccessDenied)
+            ));
+            server_txn
+                .spn_fsck_repair(audit, &admin_ev, &[*UUID_ADMIN])
+                .expect("must not fail");
+            server_txn.commit(audit).expect("must not fail");
+
+            let found = server
+                .read()
+                .spn_fsck_report(audit, &Event::from_internal())
+                .expect("must not fail");
+            assert!(found.is_empty());
+        })
+    }
+
+    #[test]
+    fn test_qs_spn_unlock() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("must not fail");
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("must not fail");
+            let legacy = |name: &str| Value::new_spn_str(name, "legacy.example.com");
+            let locked = |name: &str, uuid: &str| {
+                entry_init!(
+                    ("class", Value::new_class("object")),
+                    ("class", Value::new_class("account")),
+                    ("name", Value::new_iname(name)),
+                    ("uuid", Value::new_uuids(uuid).expect("uuid")),
+                    ("displayname", Value::new_utf8s(name)),
+                    ("spn_locked", Value::new_bool(true)),
+                    ("spn", legacy(name))
+                )
+            };
+            server_txn
+                .internal_create(
+                    audit,
+                    vec![
+                        locked("migrated1", "cc8e95b4-c24f-4d68-ba54-8bed76f63931"),
+                        locked("migrated2", "cc8e95b4-c24f-4d68-ba54-8bed76f63932"),
+                        locked("keeplocked", "cc8e95b4-c24f-4d68-ba54-8bed76f63933"),
+                    ],
+                )
+                .expect("must not fail");
+
+            // admin is a member of system_admins, anonymous is not.
+            let admin_ev = Event::from_impersonate_entry(admin);
+            let anon_ev = Event::from_impersonate_entry(anon);
+            let filt = filter!(f_or!([
+                f_eq("name", PartialValue::new_iname("migrated1")),
+                f_eq("name", PartialValue::new_iname("migrated2")),
+                // Already unlocked, so it isn't counted.
+                f_eq("name", PartialValue::new_iname("admin"))
+            ]));
+
+            assert!(matches!(
+                server_txn.spn_unlock(audit, &anon_ev, filt.clone()),
+                Err(OperationError::AccessDenied)
+            ));
+            assert!(server_txn.spn_unlock(audit, &admin_ev, filt.clone()) == Ok(2));
+            // Nothing is left to unlock.
+            assert!(server_txn.spn_unlock(audit, &admin_ev, filt) == Ok(0));
+
+            let get = |audit: &mut AuditScope, name: &str| {
+                let uuid = server_txn.name_to_uuid(audit, name).expect("must not fail");
+                server_txn
+                    .internal_search_uuid(audit, &uuid)
+                    .expect("must not fail")
+            };
+            for name in &["migrated1", "migrated2"] {
+                let e = get(audit, name);
+                assert!(e.get_ava_single_bool("spn_locked").is_none());
+                let spn = e.get_ava_single("spn").expect("must not fail");
+                assert!(spn != &legacy(name));
+            }
+            let e = get(audit, "keeplocked");
+            assert!(e.get_ava_single_bool("spn_locked") == Some(true));
+            assert!(e.get_ava_single("spn") == Some(&legacy("keeplocked")));
+
+            server_txn.commit(audit).expect("must not fail");
+        })
+    }
+
+    #[test]
+    fn test_qs_spn_regenerate() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("must not fail");
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("must not fail");
+
+            // Bypass the plugins to leave both with an spn in an old domain.
+            let drift = |e: &Entry<EntrySealed, EntryCommitted>, name: &str| {
+                let mut e_drifted = unsafe { e.clone().into_invalid() };
+                e_drifted.set_ava(
+                    "spn",
+                    btreeset![Value::new_spn_str(name, "old.example.com")],
+                );
+                unsafe { e_drifted.into_sealed_committed() }
+            };
+            let group = server_txn
+                .internal_search_uuid(audit, &UUID_SYSTEM_ADMINS)
+                .expect("must not fail");
+            server_txn
+                .get_be_txn()
+                .modify(
+                    audit,
+                    &[admin.clone(), group.clone()],
+                    &[drift(&admin, "admin"), drift(&group, "system_admins")],
+                )
+                .expect("must not fail");
+
+            let admin_ev = Event::from_impersonate_entry(admin);
+            let anon_ev = Event::from_impersonate_entry(anon);
+            assert!(matches!(
+                server_txn.spn_regenerate(audit, &anon_ev, &UUID_ADMIN),
+                Err(OperationError::AccessDenied)
+            ));
+
+            let res = server_txn
+                .spn_regenerate(audit, &admin_ev, &UUID_ADMIN)
+                .expect("must not fail");
+            assert!(res.spn_before == Some("admin@old.example.com".to_string()));
+            assert!(res.spn_after == Some("admin@example.com".to_string()));
+
+            // Only the one entry was regenerated.
+            let group = server_txn
+                .internal_search_uuid(audit, &UUID_SYSTEM_ADMINS)
+                .expect("must not fail");
+            assert!(
+                group.get_ava_single("spn")
+                    == Some(&Value::new_spn_str("system_admins", "old.example.com"))
+            );
+
+            let res = server_txn
+                .spn_regenerate(audit, &admin_ev, &UUID_SYSTEM_ADMINS)
+                .expect("must not fail");
+            assert!(res.spn_after == Some("system_admins@example.com".to_string()));
+
+            // Entries without an spn to generate are refused.
+            assert!(matches!(
+                server_txn.spn_regenerate(audit, &admin_ev, &UUID_DOMAIN_INFO),
+                Err(OperationError::InvalidRequestState)
+            ));
+            server_txn.commit(audit).expect("must not fail");
+        })
+    }
+
+    #[test]
+    fn test_qs_spn_config_export_import() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("must not fail");
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("must not fail");
+            let admin_ev = Event::from_impersonate_entry(admin);
+            let anon_ev = Event::from_impersonate_entry(anon);
+
+            assert!(matches!(
+                server_txn.spn_config_export(audit, &anon_ev),
+                Err(OperationError::AccessDenied)
+            ));
+            let default = server_txn
+                .spn_config_export(audit, &admin_ev)
+                .expect("must not fail");
+            assert!(
+                default
+                    == SpnConfig {
+                        spn_prefixes: Vec::new(),
+                        spn_scope: None,
+                        spn_skip_expired: false,
+                    }
+            );
+
+            let config = SpnConfig {
+                spn_prefixes: vec!["group=HTTP".to_string()],
+                spn_scope: Some(ProtoFilter::And(vec![
+                    ProtoFilter::Pres("class".to_string()),
+                    ProtoFilter::AndNot(Box::new(ProtoFilter::Eq(
+                        "name".to_string(),
+                        "anonymous".to_string(),
+                    ))),
+                ])),
+                spn_skip_expired: true,
+            };
+            // A round trip through the serialised form restores the same config.
+            let exported = serde_json::to_string(&config).expect("must not fail");
+            let imported: SpnConfig = serde_json::from_str(&exported).expect("must not fail");
+            server_txn
+                .spn_config_import(audit, &admin_ev, &imported)
+                .expect("must not fail");
+            assert!(
+                server_txn
+                    .spn_config_export(audit, &admin_ev)
+                    .expect("must not fail")
+                    == config
+            );
+
+            // The spns were regenerated with the new settings.
+            let group = server_txn
+                .internal_search_uuid(audit, &UUID_SYSTEM_ADMINS)
+                .expect("must not fail");
+            assert!(
+                group.get_ava_single("spn")
+                    == Some(&Value::new_spn_str("HTTP/system_admins", "example.com"))
+            );
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("must not fail");
+            assert!(anon.get_ava_single("spn").is_none());
+
+            // An invalid config is refused without changing anything.
+            let invalid = SpnConfig {
+                spn_prefixes: vec!["account=host".to_string(), "nonsense".to_string()],
+                ..default.clone()
+            };
+            assert!(server_txn
+                .spn_config_import(audit, &admin_ev, &invalid)
+                .is_err());
+            let invalid = SpnConfig {
+                spn_scope: Some(ProtoFilter::Pres("not_an_attribute".to_string())),
+                ..default.clone()
+            };
+            assert!(server_txn
+                .spn_config_import(audit, &admin_ev, &invalid)
+                .is_err());
+            assert!(
+                server_txn
+                    .spn_config_export(audit, &admin_ev)
+                    .expect("must not fail")
+                    == config
+            );
+
+            server_txn
+                .spn_config_import(audit, &admin_ev, &default)
+                .expect("must not fail");
+            assert!(
+                server_txn
+                    .spn_config_export(audit, &admin_ev)
+                    .expect("must not fail")
+                    == default
+            );
+            server_txn.commit(audit).expect("must not fail");
+        })
+    }
+
+    #[test]
+    fn test_qs_verify_scoped() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let e_pre = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("must not fail");
+
+            // Bypass the plugins to remove the spn.
+            let mut e_broken = unsafe { e_pre.clone().into_invalid() };
+            e_broken.purge_ava("spn");
+            let e_broken = unsafe { e_broken.into_sealed_committed() };
+            server_txn
+                .get_be_txn()
+                .modify(audit, &[e_pre], &[e_broken])
+                .expect("must not fail");
+            server_txn.commit(audit).expect("must not fail");
+
+            let server_r_txn = server.read();
+            // Outside of the scope, the broken entry is ignored.
+            let r = server_r_txn
+                .verify_scoped(
+                    audit,
+                    &ProtoFilter::Eq("name".to_string(), "anonymous".to_string()),
+                )
+                .expect("must not fail");
+            assert!(r.is_empty());
+
+            let r = server_r_txn
+                .verify_scoped(
+                    audit,
+                    &ProtoFilter::Eq("name".to_string(), "admin".to_string()),
+                )
+                .expect("must not fail");
+            assert!(r.len() == 1);
+            assert!(matches!(
+                r[0],
+                Err(ConsistencyError::InvalidSpn(_, SpnInconsistency::Missing))
+            ));
+
+            // An invalid filter is refused.
+            assert!(server_r_txn
+                .verify_scoped(
+                    audit,
+                    &ProtoFilter::Eq("not_an_attr".to_string(), "x".to_string()),
+                )
+                .is_err());
+        })
+    }
+
+    #[test]
+    fn test_qs_spn_bench() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_r_txn = server.read();
+            let admin = server_r_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("must not fail");
+            let anon = server_r_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("must not fail");
+            let admin_ev = Event::from_impersonate_entry(admin);
+            let anon_ev = Event::from_impersonate_entry(anon);
+
+            assert!(matches!(
+                server_r_txn.spn_bench(audit, &anon_ev, 100),
+                Err(OperationError::AccessDenied)
+            ));
+            assert!(server_r_txn.spn_bench(audit, &admin_ev, 0).is_err());
+            assert!(server_r_txn
+                .spn_bench(audit, &admin_ev, SPN_BENCH_COUNT_MAX + 1)
+                .is_err());
+
+            let r = server_r_txn
+                .spn_bench(audit, &admin_ev, 100)
+                .expect("must not fail");
+            assert!(r.count == 100);
+            assert!(r.generate_per_sec > 0.0);
+            assert!(r.verify_per_sec > 0.0);
+            assert!(r.directory_size > 0);
+            std::mem::drop(server_r_txn);
+
+            // The synthetic entries are never written.
+            let found = server
+                .read()
+                .internal_search(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iname("spn_bench_0"))),
+                )
+                .expect("must not fail");
+            assert!(found.is_empty());
+        })
+    }
+
+    #[test]
+    fn test_qs_entry_sizes() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.write(duration_from_epoch_now());
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("must not fail");
+            // Groups with 0, 50 and 100 synthetic members.
+            let member_uuids: Vec<_> = (0..100).map(|_| Uuid::new_v4()).collect();
+            let members: Vec<_> = member_uuids
+                .iter()
+                .enumerate()
+                .map(|(i, u)| {
+                    let mut e: Entry<EntryInit, EntryNew> = Entry::new();
+                    e.add_ava("class", Value::new_class("object"));
+                    e.add_ava("class", Value::new_class("account"));
+                    e.add_ava("uuid", Value::new_uuid(*u));
+                    e.add_ava("name", Value::new_iname(&format!("entry_size_{}", i)));
+                    e.add_ava("displayname", Value::new_utf8s("entry size"));
+                    e
+                })
+                .collect();
+            server_txn
+                .internal_create(audit, members)
+                .expect("must not fail");
+            let groups: Vec<_> = [0, 50, 100]
+                .iter()
+                .map(|n| {
+                    let mut e: Entry<EntryInit, EntryNew> = Entry::new();
+                    e.add_ava("class", Value::new_class("object"));
+                    e.add_ava("class", Value::new_class("group"));
+                    e.add_ava("name", Value::new_iname(&format!("entry_size_group_{}", n)));
+                    member_uuids
+                        .iter()
+                        .take(*n)
+                        .for_each(|u| e.add_ava("member", Value::new_refer(*u)));
+                    e
+                })
+                .collect();
+            server_txn
+                .internal_create(audit, groups)
+                .expect("must not fail");
+            server_txn.commit(audit).expect("must not fail");
+
+            let server_r_txn = server.read();
+            let anon = server_r_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("must not fail");
+            let admin_ev = Event::from_impersonate_entry(admin);
+            let anon_ev = Event::from_impersonate_entry(anon);
+            assert!(matches!(
+                server_r_txn.entry_sizes(audit, &anon_ev, 2),
+                Err(OperationError::AccessDenied)
+            ));
+            assert!(server_r_txn.entry_sizes(audit, &admin_ev, 0).is_err());
+            assert!(server_r_txn
+                .entry_sizes(audit, &admin_ev, ENTRY_SIZES_TOP_MAX + 1)
+                .is_err());
+
+            // Largest first, and the size grows with the membership.
+            let r = server_r_txn
+                .entry_sizes(audit, &admin_ev, ENTRY_SIZES_TOP_MAX)
+                .expect("must not fail");
+            assert!(r.windows(2).all(|w| w[0].values >= w[1].values));
+            let position = |name: &str| {
+                r.iter()
+                    .position(|s| s.name.as_deref() == Some(name))
+                    .expect("must not fail")
+            };
+            let (g100, g50, g0) = (
+                position("entry_size_group_100"),
+                position("entry_size_group_50"),
+                position("entry_size_group_0"),
+            );
+            assert!(g100 < g50 && g50 < g0);
+            assert!(r[g100].values > 100);
+            assert!(r[g100].attrs == r[g50].attrs);
+
+            // Only the requested number are returned, and they are the largest.
+            let top = server_r_txn
+                .entry_sizes(audit, &admin_ev, 3)
+                .expect("must not fail");
+            assert!(top == r[..3].to_vec());
+        })
+    }
+
     #[test]
     fn test_qs_upgrade_entry_attrs() {
         run_test_no_init!(|server: &QueryServer, audit: &mut AuditScope| {
@@ -4064,4 +6087,44 @@ mod tests {
             assert!(server_txn.commit(audit).is_ok());
         })
     }
+
+    #[test]
+    fn test_qs_missing_domain_info() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // Simulate a damaged database by removing the domain info from the backend.
+            let server_txn = server.write(duration_from_epoch_now());
+            let domain_info = server_txn
+                .internal_search_uuid(audit, &UUID_DOMAIN_INFO)
+                .expect("failed");
+            server_txn
+                .be_txn
+                .delete(audit, &[domain_info])
+                .expect("failed");
+            assert!(server_txn.commit(audit).is_ok());
+
+            // By default the server refuses to start.
+            assert!(matches!(
+                server.initialise_helper(audit, duration_from_epoch_now()),
+                Err(OperationError::MissingDomainInfo)
+            ));
+
+            // When configured, it's recreated and the spns regenerated to match.
+            let mut recover_server = server.clone();
+            recover_server.set_missing_domain_name(Some("recovered.example.com".to_string()));
+            assert!(recover_server
+                .initialise_helper(audit, duration_from_epoch_now())
+                .is_ok());
+
+            let server_txn = server.write(duration_from_epoch_now());
+            assert!(server_txn.get_domain_name(audit).expect("failed") == "recovered.example.com");
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            assert!(
+                admin.get_ava_single("spn")
+                    == Some(&Value::new_spn_str("admin", "recovered.example.com"))
+            );
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
 }
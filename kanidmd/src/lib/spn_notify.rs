@@ -0,0 +1,110 @@
+// Notify an external kerberos kdc when the spn of an account or group changes,
+// so that it can keep its principals in sync with kanidm.
+//
+// Changes are only queued once the transaction that made them commits. The
+// queue is drained in order by the interval actor, and a change that can't be
+// delivered stays at the front of the queue to be retried, so that a kdc that
+// is down or unreachable doesn't cause changes to be lost or reordered. A
+// command that doesn't finish within the timeout is killed and retried, so a
+// hung kdc can't stop the interval actor.
+use crate::command_notify::{CommandArgs, CommandNotifier, OnFailure};
+use crate::constants::{SPN_NOTIFY_QUEUE_MAX, SPN_NOTIFY_TIMEOUT};
+
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpnChange {
+    pub uuid: Uuid,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+// The command is given the uuid, the old spn and the new spn. An spn that
+// was added or removed is given as an empty string.
+impl CommandArgs for SpnChange {
+    fn command_args(&self) -> Vec<String> {
+        vec![
+            self.uuid.to_hyphenated_ref().to_string(),
+            self.old.clone().unwrap_or_default(),
+            self.new.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+pub struct SpnNotifier {
+    notifier: CommandNotifier<SpnChange>,
+}
+
+impl SpnNotifier {
+    pub fn new(command: &str) -> Self {
+        SpnNotifier {
+            notifier: CommandNotifier::new(
+                command,
+                Duration::from_secs(SPN_NOTIFY_TIMEOUT),
+                SPN_NOTIFY_QUEUE_MAX,
+                OnFailure::Retry,
+            ),
+        }
+    }
+
+    pub(crate) fn enqueue(&self, changes: Vec<SpnChange>) {
+        let dropped = self.notifier.enqueue(changes);
+        if dropped > 0 {
+            error!(
+                "spn notify queue is full, dropped {} changes. The kdc must be resynchronised.",
+                dropped
+            );
+        }
+    }
+
+    pub(crate) fn pending(&self) -> Vec<SpnChange> {
+        self.notifier.pending()
+    }
+
+    /// Deliver the queued changes in order, stopping at the first that fails
+    /// so it can be retried later. Returns the number that were delivered.
+    pub(crate) fn process(&self) -> usize {
+        self.notifier.process(|change, e| {
+            error!(
+                "Unable to notify kdc of spn change for {}, will retry -> {}",
+                change.uuid, e
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SpnChange, SpnNotifier};
+    use uuid::Uuid;
+
+    fn change(new: &str) -> SpnChange {
+        SpnChange {
+            uuid: Uuid::new_v4(),
+            old: None,
+            new: Some(new.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_spn_notify_retry() {
+        // A kdc that is unavailable keeps the changes queued, in order.
+        let failing = SpnNotifier::new("false");
+        let changes = vec![change("a@example.com"), change("b@example.com")];
+        failing.enqueue(changes.clone());
+        assert!(failing.process() == 0);
+        assert!(failing.pending() == changes);
+
+        let missing = SpnNotifier::new("/nonexistent/kanidm_spn_notify");
+        missing.enqueue(changes.clone());
+        assert!(missing.process() == 0);
+        assert!(missing.pending() == changes);
+
+        // Once it is available, they are all delivered.
+        let working = SpnNotifier::new("true");
+        working.enqueue(changes);
+        assert!(working.process() == 2);
+        assert!(working.pending().is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+// A read only replica can be a warm standby for its primary. The standby serves
+// reads, and refuses writes until an operator promotes it with the promote
+// command. The primary is probed regularly so that its unavailability is logged,
+// but the standby never promotes itself.
+//
+// IMPORTANT: there is no replication. The standby only has the data it had when
+// it was last restored or copied from the primary, and writes made to the primary
+// since then are NOT on the standby. Promoting a standby while the primary is
+// still serving clients gives two servers accepting writes that diverge. Before
+// promoting, the operator must ensure the primary no longer accepts writes, and
+// bring the standby's data up to date if it is needed.
+//
+// Promotion lasts until the server is restarted. The operator should then make
+// this server's role write_replica, and ensure the old primary doesn't return as
+// a writer.
+use crate::constants::STANDBY_PROBE_TIMEOUT;
+
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+pub struct StandbyMonitor {
+    primary: String,
+    // Was the primary available at the last probe? Used to log each change once.
+    primary_available: AtomicBool,
+    promoted: AtomicBool,
+}
+
+impl StandbyMonitor {
+    pub fn new(primary: &str) -> Self {
+        StandbyMonitor {
+            primary: primary.to_string(),
+            primary_available: AtomicBool::new(true),
+            promoted: AtomicBool::new(false),
+        }
+    }
+
+    /// Has this standby been promoted, so that it accepts writes?
+    pub fn is_promoted(&self) -> bool {
+        self.promoted.load(Ordering::Acquire)
+    }
+
+    /// Promote the standby, returning false if it was already promoted.
+    pub fn promote(&self, reason: &str) -> bool {
+        let first = !self.promoted.swap(true, Ordering::AcqRel);
+        if first {
+            warn!(
+                "Standby promoted to accept writes -> {}. This server's data is not replicated from {}. Ensure {} no longer accepts writes, and make this server's role write_replica before it is restarted.",
+                reason, self.primary, self.primary
+            );
+        }
+        first
+    }
+
+    /// Record the result of a probe of the primary, returning true if its
+    /// availability changed since the last probe.
+    pub(crate) fn record_probe(&self, available: bool) -> bool {
+        self.primary_available.swap(available, Ordering::AcqRel) != available
+    }
+
+    /// Check that the primary accepts connections.
+    pub(crate) fn probe(&self) -> bool {
+        let timeout = Duration::from_secs(STANDBY_PROBE_TIMEOUT);
+        match self.primary.to_socket_addrs() {
+            Ok(mut addrs) => addrs.any(|addr| TcpStream::connect_timeout(&addr, timeout).is_ok()),
+            Err(e) => {
+                debug!("Unable to resolve primary {} -> {:?}", self.primary, e);
+                false
+            }
+        }
+    }
+
+    /// Probe the primary, and log when it becomes unavailable or returns. The
+    /// operator decides whether to promote.
+    pub(crate) fn process(&self) {
+        if self.is_promoted() {
+            return;
+        }
+        let available = self.probe();
+        if !self.record_probe(available) {
+            return;
+        }
+        if available {
+            info!("Primary {} is available again", self.primary);
+        } else {
+            warn!(
+                "Primary {} is unavailable. This standby will not promote itself, use the promote command if the primary has failed.",
+                self.primary
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StandbyMonitor;
+
+    #[test]
+    fn test_standby_probe_changes() {
+        let m = StandbyMonitor::new("primary.example.com:8443");
+        assert!(!m.record_probe(true));
+        // Only the change is reported, not every failed probe.
+        assert!(m.record_probe(false));
+        assert!(!m.record_probe(false));
+        assert!(m.record_probe(true));
+        // Probes never promote the standby.
+        assert!(!m.is_promoted());
+    }
+
+    #[test]
+    fn test_standby_manual_promotion() {
+        let m = StandbyMonitor::new("primary.example.com:8443");
+        for _ in 0..10 {
+            m.record_probe(false);
+        }
+        assert!(!m.is_promoted());
+        assert!(m.promote("manual"));
+        assert!(m.is_promoted());
+        assert!(!m.promote("manual"));
+    }
+}
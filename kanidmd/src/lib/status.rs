@@ -1,4 +1,4 @@
-use crate::audit::AuditScope;
+use crate::audit::{AuditScope, LogLevelHandle};
 use tokio::sync::mpsc::UnboundedSender as Sender;
 use uuid::Uuid;
 
@@ -8,11 +8,11 @@ pub struct StatusRequestEvent {
 
 pub struct StatusActor {
     log_tx: Sender<AuditScope>,
-    log_level: Option<u32>,
+    log_level: LogLevelHandle,
 }
 
 impl StatusActor {
-    pub fn start(log_tx: Sender<AuditScope>, log_level: Option<u32>) -> &'static Self {
+    pub fn start(log_tx: Sender<AuditScope>, log_level: LogLevelHandle) -> &'static Self {
         let x = Box::new(StatusActor { log_tx, log_level });
 
         let x_ptr = Box::into_raw(x);
@@ -20,9 +20,9 @@ impl StatusActor {
     }
 
     pub async fn handle_request(&self, event: StatusRequestEvent) -> bool {
-        let mut audit = AuditScope::new("status_handler", event.eventid, self.log_level);
+        let mut audit = AuditScope::new("status_handler", event.eventid, self.log_level.get());
         ladmin_info!(&mut audit, "status handler complete");
-        self.log_tx.send(audit).unwrap_or_else(|_| {
+        self.log_tx.send(audit.finish()).unwrap_or_else(|_| {
             error!("CRITICAL: UNABLE TO COMMIT LOGS");
         });
         true
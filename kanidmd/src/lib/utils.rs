@@ -1,6 +1,7 @@
 use std::time::{Duration, SystemTime};
 use uuid::{Builder, Uuid};
 
+use openssl::sha::sha256;
 use rand::distributions::Distribution;
 use rand::{thread_rng, Rng};
 
@@ -67,6 +68,14 @@ pub fn duration_from_epoch_now() -> Duration {
         .expect("invalid duration from epoch now")
 }
 
+/// A stable pseudonym for `s`, so that it can be shared without revealing the
+/// original value while equal values remain equal. There is no salt, so a
+/// guessable value can still be recovered by hashing guesses.
+pub fn pseudonym(s: &str) -> String {
+    let hash = sha256(s.as_bytes());
+    hash[..6].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 /*
 #[allow(dead_code)]
 pub fn uuid_from_now(sid: Sid) -> Uuid {
@@ -939,6 +939,13 @@ impl Value {
         matches!(&self.pv, PartialValue::Spn(_, _))
     }
 
+    pub fn to_spn(&self) -> Option<(&str, &str)> {
+        match &self.pv {
+            PartialValue::Spn(n, r) => Some((n.as_str(), r.as_str())),
+            _ => None,
+        }
+    }
+
     pub fn new_uint32(u: u32) -> Self {
         Value {
             pv: PartialValue::new_uint32(u),
@@ -0,0 +1,91 @@
+// Bound the number of client write requests in progress, most of which are
+// waiting for the write transaction. There is only ever one writer, so under a
+// burst of writes - such as a bulk import while spns are being regenerated -
+// requests would otherwise wait without limit, each holding its memory. Once
+// write_queue_depth requests are in progress, further requests are refused as
+// busy so that the client can retry later.
+//
+// Only client requests over https are queued. The server's own writes, such as
+// delayed actions and purges, always wait for the transaction.
+use kanidm_proto::v1::OperationError;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub(crate) struct WriteQueue {
+    depth: usize,
+    waiting: AtomicUsize,
+}
+
+/// A place in the write queue, which is released when dropped.
+pub(crate) struct WriteQueueTicket<'a> {
+    queue: &'a WriteQueue,
+}
+
+impl WriteQueue {
+    pub fn new(depth: usize) -> Self {
+        WriteQueue {
+            depth,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take a place in the queue, or ServerBusy if it's full.
+    pub fn enter(&self) -> Result<WriteQueueTicket<'_>, OperationError> {
+        let depth = self.depth;
+        self.waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |w| {
+                if w < depth {
+                    Some(w + 1)
+                } else {
+                    None
+                }
+            })
+            .map(|_| WriteQueueTicket { queue: self })
+            .map_err(|_| {
+                warn!(
+                    "Refusing a write request, {} write requests are already in progress",
+                    depth
+                );
+                OperationError::ServerBusy
+            })
+    }
+
+    /// The number of write requests in progress.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for WriteQueueTicket<'_> {
+    fn drop(&mut self) {
+        self.queue.waiting.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WriteQueue;
+    use kanidm_proto::v1::OperationError;
+
+    #[test]
+    fn test_write_queue_backpressure() {
+        let queue = WriteQueue::new(3);
+        let mut tickets: Vec<_> = (0..3)
+            .map(|_| queue.enter().expect("must not be full"))
+            .collect();
+        assert!(queue.waiting() == 3);
+
+        // Saturated, so further writes are refused rather than queued.
+        assert!(matches!(queue.enter(), Err(OperationError::ServerBusy)));
+        assert!(matches!(queue.enter(), Err(OperationError::ServerBusy)));
+        assert!(queue.waiting() == 3);
+
+        // A request that completes leaves the queue.
+        tickets.pop();
+        assert!(queue.waiting() == 2);
+        let _ticket = queue.enter().expect("must not be full");
+        assert!(matches!(queue.enter(), Err(OperationError::ServerBusy)));
+
+        tickets.clear();
+        assert!(queue.waiting() == 1);
+    }
+}
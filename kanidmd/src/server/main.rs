@@ -15,19 +15,28 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 use users::{get_current_gid, get_current_uid, get_effective_gid, get_effective_uid};
 
 use serde_derive::Deserialize;
-use std::fs::{metadata, File, Metadata};
+use std::fs::{metadata, DirBuilder, File, Metadata};
 use std::io::Read;
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use kanidm::audit::LogLevel;
-use kanidm::config::{Configuration, ServerRole};
+use kanidm::config::{
+    system_memory_bytes, AnonymousReadScope, Configuration, CookieSameSite, DuplicateNamePolicy,
+    ReauthOperation, ServerRole, SpnCharsetPolicy, SpnDirectWrite, SpnIdnMode, SpnLogLevel,
+    SpnRegenMode, VerifyFailureAction,
+};
+use kanidm::core::admin::{admin_promote, admin_recover_account};
 use kanidm::core::{
-    backup_server_core, create_server_core, domain_rename_core, recover_account_core,
-    reindex_server_core, restore_server_core, vacuum_server_core, verify_server_core,
+    backup_server_core, create_runtime, create_server_core, domain_rename_core,
+    domain_rename_plan_core, domain_rename_simulate_core, preflight_core, recover_account_core,
+    reindex_server_core, restore_server_core, tls_check_core, tls_key_check_core,
+    vacuum_server_core, verify_server_core,
 };
+use kanidm::preflight::PreflightOptions;
+use kanidm_proto::v1::AuthMech;
 
 use structopt::StructOpt;
 
@@ -37,16 +46,92 @@ include!("./opt.rs");
 struct ServerConfig {
     pub bindaddress: Option<String>,
     pub ldapbindaddress: Option<String>,
+    pub ldap_basedn: Option<String>,
     // pub threads: Option<usize>,
     pub db_path: String,
     pub db_fs_type: Option<String>,
     pub db_arc_size: Option<usize>,
+    #[serde(default)]
+    pub db_arc_size_strict: bool,
+    #[serde(default)]
+    pub db_create_dir: bool,
     pub tls_chain: Option<String>,
     pub tls_key: Option<String>,
+    pub tls_min_rsa_bits: Option<u32>,
+    pub tls_min_ec_bits: Option<u32>,
+    pub tls_allow_weak_keys: Option<bool>,
     pub log_level: Option<String>,
+    pub log_level_debug: Option<String>,
     pub origin: String,
     #[serde(default)]
     pub role: ServerRole,
+    pub no_ui_message: Option<String>,
+    pub read_only_message: Option<String>,
+    pub max_connections_per_ip: Option<usize>,
+    pub http_request_read_timeout: Option<u64>,
+    pub http_max_body_size: Option<usize>,
+    pub slow_operation_threshold: Option<u64>,
+    pub anonymous_auth_rate_limit: Option<u32>,
+    #[serde(default)]
+    pub anonymous_read_scope: AnonymousReadScope,
+    pub cookie_domain: Option<String>,
+    pub cookie_samesite: Option<CookieSameSite>,
+    pub backup_path: Option<String>,
+    pub backup_retention_count: Option<usize>,
+    #[serde(default)]
+    pub disabled_auth_mechs: Vec<AuthMech>,
+    pub max_password_size: Option<usize>,
+    pub max_credential_size: Option<usize>,
+    #[serde(default)]
+    pub reserved_spns: Vec<String>,
+    #[serde(default)]
+    pub trusted_domains: Vec<String>,
+    #[serde(default)]
+    pub spn_strict_verify: bool,
+    #[serde(default)]
+    pub duplicate_name_policy: DuplicateNamePolicy,
+    #[serde(default)]
+    pub spn_direct_write: SpnDirectWrite,
+    #[serde(default)]
+    pub spn_log_level: SpnLogLevel,
+    #[serde(default)]
+    pub spn_idn_mode: SpnIdnMode,
+    #[serde(default)]
+    pub spn_charset_policy: SpnCharsetPolicy,
+    #[serde(default)]
+    pub spn_regen_mode: SpnRegenMode,
+    pub spn_regen_chunk_size: Option<usize>,
+    #[serde(default)]
+    pub startup_verify: bool,
+    #[serde(default)]
+    pub on_verify_failure: VerifyFailureAction,
+    pub worker_stack_size: Option<usize>,
+    pub admin_socket_path: Option<String>,
+    pub spn_notify_command: Option<String>,
+    pub token_expiry_grace: Option<u64>,
+    pub missing_domain_name: Option<String>,
+    pub security_headers: Option<bool>,
+    pub hsts_max_age: Option<u64>,
+    pub content_security_policy: Option<String>,
+    pub max_entries: Option<u64>,
+    pub search_result_limit: Option<usize>,
+    #[serde(default)]
+    pub default_search_attrs: Vec<String>,
+    pub max_modify_batch: Option<usize>,
+    pub write_queue_depth: Option<usize>,
+    #[serde(default)]
+    pub reauth_operations: Vec<ReauthOperation>,
+    pub reauth_window: Option<u64>,
+    pub anonymous_spn: Option<String>,
+    pub db_maintenance_interval: Option<u64>,
+    pub db_maintenance_vacuum: Option<bool>,
+    pub auth_event_retention: Option<u64>,
+    pub auth_trace_max_duration: Option<u64>,
+    #[serde(default)]
+    pub detailed_auth_errors: bool,
+    pub lockout_notify_command: Option<String>,
+    pub standby_primary: Option<String>,
+    pub log_subsystems: Option<Vec<String>>,
 }
 
 impl ServerConfig {
@@ -67,13 +152,18 @@ impl KanidmdOpt {
     fn commonopt(&self) -> &CommonOpt {
         match self {
             KanidmdOpt::Server(sopt)
-            | KanidmdOpt::Verify(sopt)
             | KanidmdOpt::Reindex(sopt)
-            | KanidmdOpt::Vacuum(sopt) => &sopt,
+            | KanidmdOpt::Vacuum(sopt)
+            | KanidmdOpt::Promote(sopt)
+            | KanidmdOpt::ConfigTest(sopt) => &sopt,
             KanidmdOpt::Backup(bopt) => &bopt.commonopts,
+            KanidmdOpt::Verify(vopt) => &vopt.commonopts,
             KanidmdOpt::Restore(ropt) => &ropt.commonopts,
             KanidmdOpt::RecoverAccount(ropt) => &ropt.commonopts,
             KanidmdOpt::DomainChange(dopt) => &dopt.commonopts,
+            KanidmdOpt::DomainRenamePlan(dopt) => &dopt.commonopts,
+            KanidmdOpt::TlsCheck(topt) => &topt.commonopts,
+            KanidmdOpt::Preflight(popt) => &popt.commonopts,
         }
     }
 }
@@ -92,8 +182,7 @@ fn read_file_metadata(path: &PathBuf) -> Metadata {
     }
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
     // Get info about who we are.
     let cuid = get_current_uid();
     let ceuid = get_effective_uid();
@@ -114,6 +203,9 @@ async fn main() {
 
     // Read cli args, determine if we should backup/restore
     let opt = KanidmdOpt::from_args();
+    // Preflight reports every problem it finds rather than refusing to run at
+    // the first, and doesn't change anything.
+    let preflight = matches!(opt, KanidmdOpt::Preflight(_));
 
     let mut config = Configuration::new();
     // Check the permissions are sane.
@@ -154,9 +246,20 @@ async fn main() {
             }
         });
 
-    // Check the permissions of the files from the configuration.
+    let ll_debug = sconfig
+        .log_level_debug
+        .map(|ll| match LogLevel::from_str(ll.as_str()) {
+            Ok(v) => v as u32,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                std::process::exit(1);
+            }
+        });
+
+    // Check the permissions of the files from the configuration. Preflight reports
+    // missing files rather than exiting.
 
-    if let Some(i_str) = &(sconfig.tls_chain) {
+    if let Some(i_str) = sconfig.tls_chain.as_ref().filter(|_| !preflight) {
         let i_path = PathBuf::from(i_str.as_str());
         let i_meta = read_file_metadata(&i_path);
         if !i_meta.permissions().readonly() {
@@ -164,7 +267,7 @@ async fn main() {
         }
     }
 
-    if let Some(i_str) = &(sconfig.tls_key) {
+    if let Some(i_str) = sconfig.tls_key.as_ref().filter(|_| !preflight) {
         let i_path = PathBuf::from(i_str.as_str());
         let i_meta = read_file_metadata(&i_path);
         if !i_meta.permissions().readonly() {
@@ -179,13 +282,38 @@ async fn main() {
     let db_path = PathBuf::from(sconfig.db_path.as_str());
     // We can't check the db_path permissions because it may note exist yet!
     if let Some(db_parent_path) = db_path.parent() {
-        if !db_parent_path.exists() {
-            eprintln!(
-                "DB folder {} may not exist, server startup may FAIL!",
-                db_parent_path.to_str().unwrap_or("invalid file path")
-            );
+        if !db_parent_path.exists() && !preflight {
+            let db_parent_str = db_parent_path.to_str().unwrap_or("invalid file path");
+            if !sconfig.db_create_dir {
+                eprintln!(
+                    "ERROR: Refusing to run - DB folder {} does not exist. Create it, or set db_create_dir = true",
+                    db_parent_str
+                );
+                std::process::exit(1);
+            } else if let KanidmdOpt::ConfigTest(_) = opt {
+                // Don't change anything while testing the configuration.
+                eprintln!(
+                    "DB folder {} does not exist, it will be created at startup",
+                    db_parent_str
+                );
+            } else {
+                // Only the server's uid and group may access the db.
+                if let Err(e) = DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o750)
+                    .create(db_parent_path)
+                {
+                    eprintln!(
+                        "ERROR: Refusing to run - Unable to create DB folder {} - {:?}",
+                        db_parent_str, e
+                    );
+                    std::process::exit(1);
+                }
+                eprintln!("Created DB folder {}", db_parent_str);
+            }
         }
-
+    }
+    if let Some(db_parent_path) = db_path.parent().filter(|p| p.exists() && !preflight) {
         let db_par_path_buf = db_parent_path.to_path_buf();
         let i_meta = read_file_metadata(&db_par_path_buf);
         if !i_meta.is_dir() {
@@ -205,14 +333,99 @@ async fn main() {
     }
 
     config.update_log_level(ll);
+    config.update_log_level_debug(ll_debug);
     config.update_db_path(&sconfig.db_path.as_str());
     config.update_db_fs_type(&sconfig.db_fs_type);
     config.update_tls(&sconfig.tls_chain, &sconfig.tls_key);
+    config.update_tls_key_strength(
+        sconfig.tls_min_rsa_bits,
+        sconfig.tls_min_ec_bits,
+        sconfig.tls_allow_weak_keys,
+    );
     config.update_bind(&sconfig.bindaddress);
     config.update_ldapbind(&sconfig.ldapbindaddress);
+    config.update_ldap_basedn(&sconfig.ldap_basedn);
     config.update_origin(&sconfig.origin.as_str());
     config.update_db_arc_size(sconfig.db_arc_size);
     config.update_role(sconfig.role);
+    config.update_role_messages(&sconfig.no_ui_message, &sconfig.read_only_message);
+    config.update_max_connections_per_ip(sconfig.max_connections_per_ip);
+    config.update_http_request_read_timeout(sconfig.http_request_read_timeout);
+    config.update_http_max_body_size(sconfig.http_max_body_size);
+    config.update_slow_operation_threshold(sconfig.slow_operation_threshold);
+    config.update_anonymous_auth_rate_limit(sconfig.anonymous_auth_rate_limit);
+    config.update_anonymous_read_scope(sconfig.anonymous_read_scope);
+    config.update_cookie(&sconfig.cookie_domain, sconfig.cookie_samesite);
+    config.update_backup_path(&sconfig.backup_path);
+    config.update_backup_retention_count(sconfig.backup_retention_count);
+    config.update_disabled_auth_mechs(&sconfig.disabled_auth_mechs);
+    config.update_credential_size_limits(sconfig.max_password_size, sconfig.max_credential_size);
+    config.update_reserved_spns(&sconfig.reserved_spns);
+    config.update_trusted_domains(&sconfig.trusted_domains);
+    config.update_spn_strict_verify(sconfig.spn_strict_verify);
+    config.update_duplicate_name_policy(sconfig.duplicate_name_policy);
+    config.update_spn_direct_write(sconfig.spn_direct_write);
+    config.update_spn_log_level(sconfig.spn_log_level);
+    config.update_spn_idn_mode(sconfig.spn_idn_mode);
+    config.update_spn_charset_policy(sconfig.spn_charset_policy);
+    config.update_spn_regen_mode(sconfig.spn_regen_mode, sconfig.spn_regen_chunk_size);
+    config.update_startup_verify(sconfig.startup_verify, sconfig.on_verify_failure);
+    config.update_worker_stack_size(sconfig.worker_stack_size);
+    config.update_admin_socket_path(&sconfig.admin_socket_path);
+    config.update_spn_notify_command(&sconfig.spn_notify_command);
+    config.update_token_expiry_grace(sconfig.token_expiry_grace);
+    config.update_missing_domain_name(&sconfig.missing_domain_name);
+    config.update_security_headers(
+        sconfig.security_headers,
+        sconfig.hsts_max_age,
+        &sconfig.content_security_policy,
+    );
+    config.update_reauth(&sconfig.reauth_operations, sconfig.reauth_window);
+    config.update_anonymous_spn(&sconfig.anonymous_spn);
+    config.update_db_maintenance(
+        sconfig.db_maintenance_interval,
+        sconfig.db_maintenance_vacuum,
+    );
+    config.update_auth_event_retention(sconfig.auth_event_retention);
+    config.update_auth_trace_max_duration(sconfig.auth_trace_max_duration);
+    config.update_detailed_auth_errors(sconfig.detailed_auth_errors);
+    config.update_lockout_notify_command(&sconfig.lockout_notify_command);
+    config.update_standby(&sconfig.standby_primary);
+    config.update_log_subsystems(&sconfig.log_subsystems);
+    config.update_max_entries(sconfig.max_entries);
+    config.update_search_result_limit(sconfig.search_result_limit);
+    config.update_max_modify_batch(sconfig.max_modify_batch);
+    config.update_write_queue_depth(sconfig.write_queue_depth);
+    config.update_default_search_attrs(&sconfig.default_search_attrs);
+    if !preflight {
+        let errors = config.validate();
+        if !errors.is_empty() {
+            for msg in errors.iter() {
+                eprintln!("ERROR: Refusing to run - {}", msg);
+            }
+            std::process::exit(1);
+        }
+    }
+
+    // Problems with the configuration that are not fatal to startup. These are
+    // reported by configtest.
+    let mut config_warnings: Vec<String> = Vec::new();
+
+    match system_memory_bytes() {
+        Some(mem) => {
+            if let Err(msg) = config.validate_db_arc_size(mem) {
+                if sconfig.db_arc_size_strict && !preflight {
+                    eprintln!("ERROR: Refusing to run - {}", msg);
+                    std::process::exit(1);
+                }
+                eprintln!("WARNING: {}", msg);
+                config_warnings.push(msg);
+            }
+        }
+        None => {
+            eprintln!("WARNING: Unable to determine system memory, db_arc_size can not be checked");
+        }
+    }
 
     // Apply any cli overrides, normally debug level.
     if let Some(dll) = opt.commonopt().debug.as_ref() {
@@ -226,6 +439,24 @@ async fn main() {
         .format_level(false)
         .init();
 
+    // The runtime is built here rather than by tokio::main, as the worker threads
+    // need the stack size from the configuration.
+    let rt = match create_runtime(&config) {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("ERROR: Unable to start the runtime - {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    rt.block_on(run(opt, config, &sconfig, &config_warnings));
+}
+
+async fn run(
+    opt: KanidmdOpt,
+    config: Configuration,
+    sconfig: &ServerConfig,
+    config_warnings: &[String],
+) {
     match opt {
         KanidmdOpt::Server(_sopt) => {
             eprintln!("Running in server mode ...");
@@ -267,9 +498,9 @@ async fn main() {
             };
             restore_server_core(&config, p);
         }
-        KanidmdOpt::Verify(_vopt) => {
+        KanidmdOpt::Verify(vopt) => {
             eprintln!("Running in db verification mode ...");
-            verify_server_core(&config);
+            verify_server_core(&config, vopt.filter.as_deref());
         }
         KanidmdOpt::RecoverAccount(raopt) => {
             eprintln!("Running account recovery ...");
@@ -280,7 +511,37 @@ async fn main() {
                     std::process::exit(1);
                 }
             };
-            recover_account_core(&config, &raopt.name, &password);
+            // A running server holds the database, so ask it through its admin
+            // socket if it's listening. A socket left by a stopped server is ignored.
+            match &config.admin_socket_path {
+                Some(path) if std::os::unix::net::UnixStream::connect(path).is_ok() => {
+                    match admin_recover_account(path, &raopt.name, &password).await {
+                        Ok(()) => eprintln!("Password reset!"),
+                        Err(e) => {
+                            eprintln!("Error during password reset -> {}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                _ => recover_account_core(&config, &raopt.name, &password),
+            }
+        }
+        KanidmdOpt::Promote(_copt) => {
+            eprintln!("Promoting standby ...");
+            let path = match &config.admin_socket_path {
+                Some(path) => path,
+                None => {
+                    eprintln!("Promotion needs the admin socket, set admin_socket_path");
+                    std::process::exit(1);
+                }
+            };
+            match admin_promote(path).await {
+                Ok(()) => eprintln!("Promoted, this server now accepts writes"),
+                Err(e) => {
+                    eprintln!("Error during promotion -> {}", e);
+                    std::process::exit(1);
+                }
+            }
         }
         KanidmdOpt::Reindex(_copt) => {
             eprintln!("Running in reindex mode ...");
@@ -290,9 +551,54 @@ async fn main() {
             eprintln!("Running in vacuum mode ...");
             vacuum_server_core(&config);
         }
+        KanidmdOpt::DomainChange(dopt) if dopt.simulate => {
+            eprintln!(
+                "Running in domain name change simulation mode ... this may take a long time ..."
+            );
+            domain_rename_simulate_core(&config, &dopt.new_domain_name, dopt.class.as_deref());
+        }
         KanidmdOpt::DomainChange(dopt) => {
             eprintln!("Running in domain name change mode ... this may take a long time ...");
-            domain_rename_core(&config, &dopt.new_domain_name);
+            domain_rename_core(&config, &dopt.new_domain_name, dopt.class.as_deref());
+        }
+        KanidmdOpt::DomainRenamePlan(dopt) => {
+            eprintln!("Running in domain name change plan mode ...");
+            domain_rename_plan_core(
+                &config,
+                &dopt.new_domain_name,
+                dopt.anonymize,
+                dopt.output.as_deref(),
+            );
+        }
+        KanidmdOpt::TlsCheck(topt) => {
+            eprintln!("Running in TLS check mode ...");
+            tls_check_core(&config, topt.expiry_warn_days);
+        }
+        KanidmdOpt::ConfigTest(_copt) => {
+            eprintln!("Running in configuration test mode ...");
+            eprintln!("{}", config);
+            let mut config_warnings = config_warnings.to_vec();
+            if let Err(e) = tls_key_check_core(&config) {
+                config_warnings.push(e);
+            }
+            if config_warnings.is_empty() {
+                eprintln!("Configuration OK");
+            } else {
+                for w in config_warnings.iter() {
+                    eprintln!("FAIL: {}", w);
+                }
+                std::process::exit(1);
+            }
+        }
+        KanidmdOpt::Preflight(popt) => {
+            eprintln!("Running in preflight mode ...");
+            let options = PreflightOptions {
+                db_create_dir: sconfig.db_create_dir,
+                db_arc_size_strict: sconfig.db_arc_size_strict,
+                system_memory: system_memory_bytes(),
+                expiry_warn_days: popt.expiry_warn_days,
+            };
+            preflight_core(&config, &options);
         }
     }
 }
@@ -40,6 +40,57 @@ struct DomainOpt {
     #[structopt(short)]
     /// The new domain name.
     new_domain_name: String,
+    #[structopt(long, possible_values = &["account", "group"])]
+    /// Only regenerate the spns of accounts or groups. Run again with the same domain
+    /// name and the other class to regenerate the rest.
+    class: Option<String>,
+    #[structopt(long)]
+    /// Perform the rename and report what it changed and how long it took, then roll
+    /// it back. Nothing is written.
+    simulate: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct DomainRenamePlanOpt {
+    #[structopt(short)]
+    /// The planned new domain name.
+    new_domain_name: String,
+    #[structopt(parse(from_os_str), short, long)]
+    /// Write the old to new spn mapping to this file, rather than stdout.
+    output: Option<PathBuf>,
+    #[structopt(long)]
+    /// Replace names and domains with stable pseudonyms, so the plan can be shared.
+    anonymize: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct VerifyOpt {
+    #[structopt(short, long)]
+    /// Only check the entries matching this json filter, such as '{"eq": ["class", "account"]}'.
+    /// Checks that need the whole database, such as uniqueness, are skipped.
+    filter: Option<String>,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct PreflightOpt {
+    #[structopt(short, long, default_value = "30")]
+    /// Warn if the certificate expires within this many days.
+    expiry_warn_days: u32,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct TlsCheckOpt {
+    #[structopt(short, long, default_value = "30")]
+    /// Fail if the certificate expires within this many days.
+    expiry_warn_days: u32,
     #[structopt(flatten)]
     commonopts: CommonOpt,
 }
@@ -57,10 +108,13 @@ enum KanidmdOpt {
     Restore(RestoreOpt),
     #[structopt(name = "verify")]
     /// Verify database and entity consistency.
-    Verify(CommonOpt),
+    Verify(VerifyOpt),
     #[structopt(name = "recover_account")]
     /// Recover an account's password
     RecoverAccount(RecoverAccountOpt),
+    #[structopt(name = "promote")]
+    /// Promote a running standby so that it accepts writes. Its data is not replicated from the primary
+    Promote(CommonOpt),
     // #[structopt(name = "reset_server_id")]
     // ResetServerId(CommonOpt),
     #[structopt(name = "reindex")]
@@ -72,5 +126,18 @@ enum KanidmdOpt {
     #[structopt(name = "domain_name_change")]
     /// Change the IDM domain name
     DomainChange(DomainOpt),
+    #[structopt(name = "domain_rename_plan")]
+    /// Show the spn each account and group would have after a domain name change (offline)
+    DomainRenamePlan(DomainRenamePlanOpt),
+    #[structopt(name = "tls-check")]
+    /// Check the TLS chain, key and certificate validity for the origin, and exit
+    TlsCheck(TlsCheckOpt),
+    #[structopt(name = "configtest")]
+    /// Validate the server configuration and exit
+    ConfigTest(CommonOpt),
+    #[structopt(name = "preflight")]
+    /// Run all of the configuration, TLS, database and origin checks, report the
+    /// problems found and exit
+    Preflight(PreflightOpt),
 }
 